@@ -0,0 +1,9 @@
+//! webmetro's parser & chunker as a shared library: the C interface (see
+//! `include/webmetro.h`) with the `c` feature, or the JavaScript `Chunker`
+//! with `wasm`. Both live in webmetro itself, behind its `ffi` & `wasm`
+//! features; this crate just links them into a cdylib.
+
+#[cfg(feature = "c")]
+pub use webmetro::ffi::*;
+#[cfg(feature = "wasm")]
+pub use webmetro::wasm::*;
@@ -0,0 +1,26 @@
+#![no_main]
+use bytes::Bytes;
+use futures::{prelude::*, stream};
+use libfuzzer_sys::fuzz_target;
+
+use webmetro::chunk::{ChunkerOptions, WebmStream};
+use webmetro::error::WebmetroError;
+use webmetro::stream_parser::StreamEbml;
+
+// arbitrary input, fed through the parser & chunker in small pieces with
+// Cluster splitting on; any panic or hang is a bug, errors are fine
+fuzz_target!(|data: &[u8]| {
+    let pieces: Vec<Result<Bytes, WebmetroError>> = data.chunks(61)
+        .map(|piece| Ok(Bytes::copy_from_slice(piece)))
+        .collect();
+    let options = ChunkerOptions::new()
+        .soft_limit(1 << 20)
+        .max_cluster_duration(100);
+    let chunks = stream::iter(pieces)
+        .parse_ebml()
+        .with_soft_limit(1 << 20)
+        .chunk_webm_with(options)
+        .for_each(|_| future::ready(()))
+        .now_or_never();
+    assert!(chunks.is_some(), "chunker blocked on a finished input");
+});
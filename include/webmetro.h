@@ -0,0 +1,49 @@
+/* C interface to webmetro's WebM parser & chunker; link against libwebmetro_ffi, built by `cargo build -p webmetro-ffi`. */
+#ifndef WEBMETRO_H
+#define WEBMETRO_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define WEBMETRO_CHUNK_HEADERS 0
+#define WEBMETRO_CHUNK_CLUSTER 1
+
+typedef struct WebmetroChunker WebmetroChunker;
+
+typedef struct {
+    /* WEBMETRO_CHUNK_HEADERS or WEBMETRO_CHUNK_CLUSTER */
+    int kind;
+    /* nonzero if a Cluster starts with a keyframe */
+    int keyframe;
+    /* a Cluster's first & last timecodes; zero for headers */
+    uint64_t start;
+    uint64_t end;
+} WebmetroChunkInfo;
+
+/* Receives each chunk; data is only valid for the duration of the call. */
+typedef void (*WebmetroChunkCallback)(void *user_data, const WebmetroChunkInfo *info, const uint8_t *data, size_t len);
+
+/* Create a chunker. soft_limit bounds the size of a chunk (0 for no limit). */
+WebmetroChunker *webmetro_chunker_new(size_t soft_limit, WebmetroChunkCallback callback, void *user_data);
+
+/* Parse len bytes of WebM, calling the callback for any chunks completed.
+ * Returns 0 on success, or -1 once the stream is unusable. */
+int webmetro_chunker_feed(WebmetroChunker *chunker, const uint8_t *data, size_t len);
+
+/* Signal the end of input, passing the final Cluster to the callback. */
+int webmetro_chunker_finish(WebmetroChunker *chunker);
+
+/* The error that stopped the chunker, or NULL; owned by the chunker. */
+const char *webmetro_chunker_error(const WebmetroChunker *chunker);
+
+void webmetro_chunker_free(WebmetroChunker *chunker);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
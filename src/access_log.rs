@@ -0,0 +1,305 @@
+//! An access log, with one JSON line per finished session, for billing &
+//! abuse investigations rather than diagnostics. The file is rotated by size
+//! or age, with the old one renamed for the time it was started:
+//!
+//! ```json
+//! {"time":1600000000.25,"peer":"192.0.2.1:53211","role":"listen","channel":"main","bytes":1048576,"duration_ms":61250}
+//! ```
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::server::Action;
+
+/// One finished session
+#[derive(Clone, Debug, PartialEq)]
+pub struct AccessRecord {
+    /// when the session finished
+    pub time: SystemTime,
+    pub peer: Option<SocketAddr>,
+    pub action: Action,
+    pub channel: String,
+    /// bytes received from a source, or sent to a listener
+    pub bytes: u64,
+    pub duration: Duration,
+}
+
+/// A record as a line of the log describes it, with the time in Unix seconds
+#[derive(Serialize)]
+struct RecordJson<'a> {
+    time: f64,
+    peer: Option<SocketAddr>,
+    role: &'static str,
+    channel: &'a str,
+    bytes: u64,
+    duration_ms: u128,
+}
+
+impl AccessRecord {
+    /// The record as a one-line JSON object
+    pub fn to_json(&self) -> String {
+        let record = RecordJson {
+            time: self.time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as f64 / 1000.0,
+            peer: self.peer,
+            role: role(self.action),
+            channel: &self.channel,
+            bytes: self.bytes,
+            duration_ms: self.duration.as_millis(),
+        };
+        serde_json::to_string(&record).expect("Serializing an access record")
+    }
+}
+
+/// How an action is described to other systems, e.g. `listen`
+pub(crate) fn role(action: Action) -> &'static str {
+    match action {
+        Action::Probe => "probe",
+        Action::Listen => "listen",
+        Action::Publish => "publish",
+        Action::Record => "record",
+        Action::Mirror => "mirror",
+        Action::Monitor => "monitor",
+        Action::Configure => "configure",
+        Action::Pause => "pause",
+    }
+}
+
+struct LogFile {
+    file: File,
+    size: u64,
+    opened: SystemTime,
+}
+
+/// Appends records to a file, starting a new one once it reaches
+/// `max_size` bytes or `max_age` old
+pub struct AccessLog {
+    path: PathBuf,
+    max_size: Option<u64>,
+    max_age: Option<Duration>,
+    current: LogFile,
+}
+
+impl AccessLog {
+    /// Append to the log at `path`, creating it if needed
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<AccessLog> {
+        let path = path.into();
+        let current = open_log(&path)?;
+        Ok(AccessLog {
+            path,
+            max_size: None,
+            max_age: None,
+            current,
+        })
+    }
+
+    pub fn max_size(mut self, bytes: u64) -> Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    pub fn max_age(mut self, age: Duration) -> Self {
+        self.max_age = Some(age);
+        self
+    }
+
+    pub fn write(&mut self, record: &AccessRecord) -> io::Result<()> {
+        let mut line = record.to_json();
+        line.push('\n');
+
+        let too_big = self.max_size.map_or(false, |max_size| self.current.size > 0 && self.current.size + line.len() as u64 > max_size);
+        let too_old = self.max_age.map_or(false, |max_age| {
+            self.current.opened.elapsed().map_or(false, |age| age >= max_age)
+        });
+        if too_big || too_old {
+            self.rotate()?;
+        }
+
+        self.current.file.write_all(line.as_bytes())?;
+        self.current.size += line.len() as u64;
+        Ok(())
+    }
+
+    /// Rename the current file for when it was started, and start a new one
+    fn rotate(&mut self) -> io::Result<()> {
+        let started = self.current.opened.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        let mut rotated = rotated_path(&self.path, started, 0);
+        let mut index = 0;
+        while rotated.exists() {
+            index += 1;
+            rotated = rotated_path(&self.path, started, index);
+        }
+        fs::rename(&self.path, &rotated)?;
+        self.current = open_log(&self.path)?;
+        Ok(())
+    }
+
+    /// Hand the log to a thread of its own, so sessions are written without
+    /// holding up whoever finishes them
+    pub fn spawn(self) -> io::Result<Arc<AccessLogger>> {
+        let (sender, receiver) = mpsc::channel::<AccessRecord>();
+        let mut log = self;
+        let writer = thread::Builder::new()
+            .name("access-log".into())
+            .spawn(move || {
+                for record in receiver {
+                    if let Err(err) = log.write(&record) {
+                        warn!("Couldn't write to access log {}: {}", log.path.display(), err);
+                    }
+                }
+            })?;
+        Ok(Arc::new(AccessLogger {
+            records: Mutex::new(Some(sender)),
+            writer: Some(writer),
+        }))
+    }
+}
+
+/// Sends finished sessions to an `AccessLog`'s writer thread; dropping it
+/// waits for the records already sent to be written
+pub struct AccessLogger {
+    records: Mutex<Option<mpsc::Sender<AccessRecord>>>,
+    writer: Option<JoinHandle<()>>,
+}
+
+impl AccessLogger {
+    /// Queue a record for the log
+    pub fn log(&self, record: AccessRecord) {
+        if let Some(records) = self.records.lock().expect("Locking access log").as_ref() {
+            // the writer only stops once this is dropped
+            let _ = records.send(record);
+        }
+    }
+
+    /// Start timing a session, which is written to the log once the returned
+    /// guard is dropped
+    pub fn session(self: &Arc<Self>, action: Action, channel: &str, peer: Option<SocketAddr>) -> Session {
+        Session {
+            log: self.clone(),
+            action,
+            channel: channel.to_string(),
+            peer,
+            bytes: 0,
+            started: Instant::now(),
+        }
+    }
+}
+
+impl Drop for AccessLogger {
+    fn drop(&mut self) {
+        // hanging up ends the writer's loop, once it's caught up
+        self.records.get_mut().expect("Locking access log").take();
+        if let Some(writer) = self.writer.take() {
+            let _ = writer.join();
+        }
+    }
+}
+
+fn open_log(path: &Path) -> io::Result<LogFile> {
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    let size = file.metadata()?.len();
+    Ok(LogFile {
+        file,
+        size,
+        opened: SystemTime::now(),
+    })
+}
+
+/// e.g. access.log.1600000000, then access.log.1600000000-1 if that's taken
+fn rotated_path(path: &Path, started: u64, index: usize) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    if index == 0 {
+        name.push(format!(".{}", started));
+    } else {
+        name.push(format!(".{}-{}", started, index));
+    }
+    PathBuf::from(name)
+}
+
+/// A session in progress, logged when dropped
+pub struct Session {
+    log: Arc<AccessLogger>,
+    action: Action,
+    channel: String,
+    peer: Option<SocketAddr>,
+    bytes: u64,
+    started: Instant,
+}
+
+impl Session {
+    /// Count bytes received or sent
+    pub fn add(&mut self, bytes: usize) {
+        self.bytes += bytes as u64;
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.log.log(AccessRecord {
+            time: SystemTime::now(),
+            peer: self.peer,
+            action: self.action,
+            channel: std::mem::take(&mut self.channel),
+            bytes: self.bytes,
+            duration: self.started.elapsed(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::process;
+
+    use crate::access_log::*;
+
+    fn record(bytes: u64) -> AccessRecord {
+        AccessRecord {
+            time: UNIX_EPOCH + Duration::from_millis(1_600_000_000_250),
+            peer: "192.0.2.1:53211".parse().ok(),
+            action: Action::Listen,
+            channel: "main".into(),
+            bytes,
+            duration: Duration::from_millis(61_250),
+        }
+    }
+
+    #[test]
+    fn format_records() {
+        assert_eq!(record(1024).to_json(), r#"{"time":1600000000.25,"peer":"192.0.2.1:53211","role":"listen","channel":"main","bytes":1024,"duration_ms":61250}"#);
+    }
+
+    #[test]
+    fn rotate_by_size() {
+        let dir = temp_dir().join(format!("webmetro-access-log-{}", process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log");
+        let line_length = record(1).to_json().len() as u64 + 1;
+
+        let mut log = AccessLog::open(&path).unwrap().max_size(line_length * 2);
+        for bytes in 1..=3 {
+            log.write(&record(bytes)).unwrap();
+        }
+        // sessions are written by the log's own thread, done once it's dropped
+        let logger = log.spawn().unwrap();
+        drop(logger.session(Action::Publish, "main", None));
+        drop(logger);
+
+        let mut files: Vec<PathBuf> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().path()).collect();
+        files.sort();
+        assert_eq!(files.len(), 2);
+        let current = fs::read_to_string(&path).unwrap();
+        assert_eq!(current.lines().count(), 2);
+        assert!(current.lines().last().unwrap().contains(r#""role":"publish""#));
+        let rotated = files.iter().find(|file| **file != path).unwrap();
+        assert_eq!(fs::read_to_string(rotated).unwrap().lines().count(), 2);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
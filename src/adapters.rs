@@ -0,0 +1,224 @@
+use std::error::Error;
+use std::io::{Error as IoError, ErrorKind, Result as IoResult};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Buf, Bytes};
+use futures::{
+    channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    prelude::*,
+};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::channel::{Handle, Transmitter};
+use crate::chunk::{Chunk, ChunkerOptions, WebmChunker, WebmStream};
+use crate::error::WebmetroError;
+use crate::stream_parser::StreamEbml;
+
+/// Presents a stream of chunks as an `AsyncRead` of the concatenated WebM bytes,
+/// for APIs that want a reader rather than a stream.
+pub struct ChunkReader<S> {
+    stream: S,
+    chunk: Chunk,
+    buffer: Bytes,
+}
+
+impl<S> ChunkReader<S> {
+    pub fn new(stream: S) -> ChunkReader<S> {
+        ChunkReader {
+            stream,
+            chunk: Chunk::Empty,
+            buffer: Bytes::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.stream
+    }
+}
+
+impl<S: TryStream<Ok = Chunk> + Unpin> AsyncRead for ChunkReader<S>
+where
+    S::Error: Into<Box<dyn Error + Send + Sync>>,
+{
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8]) -> Poll<IoResult<usize>> {
+        let reader = self.get_mut();
+        loop {
+            if reader.buffer.has_remaining() {
+                let len = buf.len().min(reader.buffer.remaining());
+                reader.buffer.copy_to_slice(&mut buf[..len]);
+                return Poll::Ready(Ok(len));
+            }
+
+            if let Some(bytes) = reader.chunk.next() {
+                reader.buffer = bytes;
+                continue;
+            }
+
+            match reader.stream.try_poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(chunk))) => reader.chunk = chunk,
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Err(IoError::new(ErrorKind::Other, err))),
+                Poll::Ready(None) => return Poll::Ready(Ok(0)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+type IngestInput = UnboundedReceiver<Result<Bytes, WebmetroError>>;
+
+/// An `AsyncWrite` that parses the WebM bytes written to it and publishes
+/// the resulting chunks to a Channel, for sources arriving over transports
+/// that don't naturally produce a Stream.
+pub struct WebmIngest {
+    transmitter: Transmitter,
+    sender: UnboundedSender<Result<Bytes, WebmetroError>>,
+    receiver: Option<IngestInput>,
+    chunker: Option<WebmChunker<IngestInput>>,
+    buffer_size_limit: Option<usize>,
+    failed: bool,
+}
+
+impl WebmIngest {
+    pub fn new(channel: Handle) -> WebmIngest {
+        let (sender, receiver) = unbounded();
+        WebmIngest {
+            transmitter: Transmitter::new(channel),
+            sender,
+            receiver: Some(receiver),
+            chunker: None,
+            buffer_size_limit: None,
+            failed: false,
+        }
+    }
+
+    /// add a "soft" buffer size limit to the parser and chunker, as with
+    /// `EbmlStreamingParser::with_soft_limit` & `ChunkerOptions::soft_limit`
+    pub fn with_soft_limit(mut self, limit: usize) -> Self {
+        self.buffer_size_limit = Some(limit);
+        self
+    }
+
+    /// feed any chunks the chunker can now complete to the Transmitter
+    fn pump(&mut self, cx: &mut Context) -> IoResult<()> {
+        let receiver = &mut self.receiver;
+        let buffer_size_limit = self.buffer_size_limit;
+        let chunker = self.chunker.get_or_insert_with(|| {
+            let parser = receiver.take().expect("Ingest input taken once").parse_ebml();
+            match buffer_size_limit {
+                Some(limit) => parser.with_soft_limit(limit).chunk_webm_with(ChunkerOptions::new().soft_limit(limit)),
+                None => parser.chunk_webm(),
+            }
+        });
+
+        loop {
+            match chunker.poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(chunk))) => self.transmitter.send(chunk),
+                Poll::Ready(Some(Err(err))) => {
+                    self.failed = true;
+                    return Err(IoError::new(ErrorKind::InvalidData, err));
+                },
+                Poll::Ready(None) | Poll::Pending => return Ok(()),
+            }
+        }
+    }
+}
+
+impl AsyncWrite for WebmIngest {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context, buf: &[u8]) -> Poll<IoResult<usize>> {
+        let ingest = self.get_mut();
+        if ingest.failed || ingest.sender.unbounded_send(Ok(Bytes::copy_from_slice(buf))).is_err() {
+            return Poll::Ready(Err(ErrorKind::BrokenPipe.into()));
+        }
+        Poll::Ready(ingest.pump(cx).map(|()| buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<IoResult<()>> {
+        // everything written has already been handed to the parser
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context) -> Poll<IoResult<()>> {
+        let ingest = self.get_mut();
+        if ingest.failed {
+            return Poll::Ready(Ok(()));
+        }
+        // ending the input lets the chunker emit the final Cluster
+        ingest.sender.close_channel();
+        Poll::Ready(ingest.pump(cx))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::{executor::block_on, future::poll_fn, stream::iter};
+    use matches::assert_matches;
+    use std::pin::Pin;
+
+    use crate::adapters::*;
+    use crate::channel::{Channel, Listener};
+    use crate::chunk::ClusterHead;
+    use crate::error::WebmetroError;
+    use crate::tests::ENCODE_WEBM_TEST_FILE;
+
+    #[test]
+    fn read_chunks() {
+        let chunks = vec![
+            Chunk::Headers { bytes: Bytes::from_static(b"headers") },
+            Chunk::Cluster(ClusterHead::new(0), Bytes::from_static(b"body")),
+        ];
+        let expected: Vec<u8> = chunks.iter().cloned().flatten().flatten().collect();
+        let mut reader = ChunkReader::new(iter(chunks.into_iter().map(Result::<Chunk, WebmetroError>::Ok)));
+
+        let mut output = Vec::new();
+        let mut buf = [0; 4];
+        loop {
+            let len = block_on(poll_fn(|cx| Pin::new(&mut reader).poll_read(cx, &mut buf))).unwrap();
+            if len == 0 {
+                break;
+            }
+            output.extend_from_slice(&buf[..len]);
+        }
+
+        assert!(output.starts_with(b"headers"));
+        assert!(output.ends_with(b"body"));
+        assert_eq!(output, expected);
+    }
+
+    #[test]
+    fn read_error() {
+        let chunks = vec![Err(WebmetroError::ChannelClosed { name: "test".into() })];
+        let mut reader = ChunkReader::new(iter::<Vec<Result<Chunk, WebmetroError>>>(chunks));
+
+        let mut buf = [0; 4];
+        assert!(block_on(poll_fn(|cx| Pin::new(&mut reader).poll_read(cx, &mut buf))).is_err());
+    }
+
+    #[test]
+    fn ingest_written_bytes() {
+        let channel = Channel::new("test".into());
+        let listener = Listener::new(channel.clone()).into_chunks();
+        let mut ingest = WebmIngest::new(channel);
+
+        for piece in ENCODE_WEBM_TEST_FILE.chunks(7) {
+            let len = block_on(poll_fn(|cx| Pin::new(&mut ingest).poll_write(cx, piece))).unwrap();
+            assert_eq!(len, piece.len());
+        }
+        block_on(poll_fn(|cx| Pin::new(&mut ingest).poll_shutdown(cx))).unwrap();
+        drop(ingest);
+
+        let received: Vec<Chunk> = block_on(listener.take(3).collect());
+        assert_matches!(received[0], Chunk::Headers {..});
+        assert_matches!(received[1], Chunk::Cluster(..));
+        assert_matches!(received[2], Chunk::Cluster(..));
+    }
+
+    #[test]
+    fn ingest_rejects_garbage() {
+        let mut ingest = WebmIngest::new(Channel::new("test".into())).with_soft_limit(16);
+        let garbage = [0xFF; 64];
+        assert!(block_on(poll_fn(|cx| Pin::new(&mut ingest).poll_write(cx, &garbage))).is_err());
+        assert!(block_on(poll_fn(|cx| Pin::new(&mut ingest).poll_write(cx, &garbage))).is_err());
+    }
+}
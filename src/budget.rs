@@ -0,0 +1,153 @@
+//! Accounting for the memory a relay holds on to: the clusters channels keep
+//! for new listeners, listeners' queues, and clusters still being received.
+//!
+//! Everything charges a shared `MemoryBudget`; as it fills up, channels keep
+//! fewer clusters around and give up on listeners that are falling behind.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+/// How full a budget is
+#[derive(Clone, Copy, Debug, PartialEq, PartialOrd)]
+pub enum Pressure {
+    Normal,
+    /// past three quarters of the limit; stop retaining clusters for new listeners
+    High,
+    /// at or over the limit; also disconnect lagging listeners
+    Critical,
+}
+
+/// A byte count shared by everything buffering media, with an optional cap
+#[derive(Debug)]
+pub struct MemoryBudget {
+    limit: Option<usize>,
+    used: AtomicUsize,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: usize) -> Arc<MemoryBudget> {
+        Arc::new(MemoryBudget {
+            limit: Some(limit),
+            used: AtomicUsize::new(0),
+        })
+    }
+
+    /// A budget that only keeps count, and never applies pressure
+    pub fn unlimited() -> Arc<MemoryBudget> {
+        Arc::new(MemoryBudget {
+            limit: None,
+            used: AtomicUsize::new(0),
+        })
+    }
+
+    pub fn limit(&self) -> Option<usize> {
+        self.limit
+    }
+
+    /// How many bytes are currently charged
+    pub fn used(&self) -> usize {
+        self.used.load(Ordering::Relaxed)
+    }
+
+    pub fn pressure(&self) -> Pressure {
+        match self.limit {
+            Some(limit) if self.used() >= limit => Pressure::Critical,
+            Some(limit) if self.used() >= limit / 4 * 3 => Pressure::High,
+            _ => Pressure::Normal,
+        }
+    }
+
+    pub fn charge(&self, bytes: usize) {
+        self.used.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    pub fn release(&self, bytes: usize) {
+        self.used.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    /// Update a holder's charge from `from` to `to` bytes
+    pub fn adjust(&self, from: usize, to: usize) {
+        if to > from {
+            self.charge(to - from);
+        } else {
+            self.release(from - to);
+        }
+    }
+}
+
+/// A running charge against a budget, for buffers that grow & shrink
+/// piecemeal; whatever's left is released when it's dropped.
+#[derive(Debug)]
+pub struct Charge {
+    budget: Arc<MemoryBudget>,
+    bytes: AtomicUsize,
+}
+
+impl Charge {
+    pub fn new(budget: Arc<MemoryBudget>) -> Charge {
+        Charge {
+            budget,
+            bytes: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn add(&self, bytes: usize) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.budget.charge(bytes);
+    }
+
+    /// Release up to `bytes`, never more than has been added
+    pub fn remove(&self, bytes: usize) {
+        let mut current = self.bytes.load(Ordering::Relaxed);
+        loop {
+            let released = bytes.min(current);
+            match self.bytes.compare_exchange_weak(current, current - released, Ordering::Relaxed, Ordering::Relaxed) {
+                Ok(_) => {
+                    self.budget.release(released);
+                    return;
+                },
+                Err(actual) => current = actual,
+            }
+        }
+    }
+}
+
+impl Drop for Charge {
+    fn drop(&mut self) {
+        self.budget.release(*self.bytes.get_mut());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::budget::*;
+
+    #[test]
+    fn pressure_levels() {
+        let budget = MemoryBudget::new(100);
+        assert_eq!(budget.pressure(), Pressure::Normal);
+        budget.charge(80);
+        assert_eq!(budget.pressure(), Pressure::High);
+        budget.adjust(80, 100);
+        assert_eq!(budget.pressure(), Pressure::Critical);
+        budget.release(100);
+        assert_eq!(budget.used(), 0);
+
+        let unlimited = MemoryBudget::unlimited();
+        unlimited.charge(1 << 40);
+        assert_eq!(unlimited.pressure(), Pressure::Normal);
+    }
+
+    #[test]
+    fn release_charges_when_dropped() {
+        let budget = MemoryBudget::new(100);
+        let charge = Charge::new(budget.clone());
+        charge.add(30);
+        charge.remove(50);
+        assert_eq!(budget.used(), 0);
+
+        charge.add(40);
+        drop(charge);
+        assert_eq!(budget.used(), 0);
+    }
+}
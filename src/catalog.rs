@@ -0,0 +1,87 @@
+//! Finds the finished recordings in a directory, to serve as video-on-demand.
+//!
+//! Only finalized `.webm` files are listed; a recording that's still being
+//! written has no Cues or Duration yet, so players couldn't seek in it.
+
+use std::fs::{read_dir, File};
+use std::io::{Read, Result as IoResult};
+use std::path::Path;
+
+use crate::recorder::is_finalized;
+
+/// Enough of a file to cover its EBML header & the start of its Segment
+const HEADER_PEEK: u64 = 1024;
+
+/// A recording available for playback
+#[derive(Clone, Debug, PartialEq)]
+pub struct Recording {
+    /// the file name, relative to the catalog directory
+    pub name: String,
+    /// the file size in bytes
+    pub size: u64,
+}
+
+/// Whether a file name could be a recording; excludes anything that could
+/// reach outside the catalog directory
+fn is_recording_name(name: &str) -> bool {
+    name.ends_with(".webm") && !name.starts_with('.') && !name.contains(|c| c == '/' || c == '\\')
+}
+
+fn is_finalized_file(path: &Path) -> IoResult<bool> {
+    let mut start = Vec::new();
+    File::open(path)?.take(HEADER_PEEK).read_to_end(&mut start)?;
+    Ok(is_finalized(&start))
+}
+
+/// Whether `name` is a finalized recording in `dir`
+pub fn is_recording(dir: &Path, name: &str) -> bool {
+    is_recording_name(name) && is_finalized_file(&dir.join(name)).unwrap_or(false)
+}
+
+/// The finalized recordings in `dir`, sorted by name
+pub fn list_recordings(dir: &Path) -> IoResult<Vec<Recording>> {
+    let mut recordings = Vec::new();
+    for entry in read_dir(dir)? {
+        let entry = entry?;
+        let name = match entry.file_name().into_string() {
+            Ok(name) => name,
+            Err(_) => continue,
+        };
+        let metadata = entry.metadata()?;
+        if metadata.is_file() && is_recording_name(&name) && is_finalized_file(&entry.path())? {
+            recordings.push(Recording {
+                name,
+                size: metadata.len(),
+            });
+        }
+    }
+    recordings.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(recordings)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{create_dir_all, remove_dir_all, write};
+    use std::env::temp_dir;
+
+    use crate::catalog::*;
+    use crate::tests::{ENCODE_WEBM_TEST_FILE, TEST_FILE};
+
+    #[test]
+    fn list_finalized_recordings() {
+        let dir = temp_dir().join(format!("webmetro-catalog-{}", std::process::id()));
+        create_dir_all(&dir).unwrap();
+        write(dir.join("b.webm"), TEST_FILE).unwrap();
+        write(dir.join("a.webm"), TEST_FILE).unwrap();
+        write(dir.join("recording.webm"), ENCODE_WEBM_TEST_FILE).unwrap();
+        write(dir.join("notes.txt"), TEST_FILE).unwrap();
+
+        let names: Vec<String> = list_recordings(&dir).unwrap().into_iter().map(|recording| recording.name).collect();
+        assert_eq!(names, vec!["a.webm", "b.webm"]);
+        assert!(is_recording(&dir, "a.webm"));
+        assert!(!is_recording(&dir, "recording.webm"));
+        assert!(!is_recording(&dir, "../a.webm"));
+
+        remove_dir_all(&dir).unwrap();
+    }
+}
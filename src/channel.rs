@@ -1,113 +1,1590 @@
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
 use std::pin::Pin;
+use std::str::FromStr;
 use std::sync::{Arc, Mutex};
-use std::task::{Context, Poll};
+use std::task::{Context, Poll, Waker};
 
-use futures::{
-    channel::mpsc::{channel as mpsc_channel, Receiver, Sender},
-    Stream,
-};
-use odds::vec::VecExt;
+use bytes::Bytes;
+use futures::{ready, Sink, Stream};
+use tracing::Span;
 
-use crate::chunk::Chunk;
+use crate::budget::{Charge, MemoryBudget, Pressure};
+use crate::chunk::{Chunk, ClusterHead};
+use crate::error::WebmetroError;
+use crate::webm::{header_tracks, TrackEntry};
+
+/// How many chunks a listener may fall behind by default
+pub const DEFAULT_QUEUE_LIMIT: usize = 5;
+
+/// How many bytes a critical listener may have queued before it's taken to
+/// have stalled, and is disconnected after all
+pub const MAX_CRITICAL_QUEUE_BYTES: usize = 64 * 1024 * 1024;
+
+/// The most clusters kept from the latest keyframe onward for new listeners;
+/// past this, they just wait for the next keyframe.
+const SNAPSHOT_LIMIT: usize = 32;
+
+/// What to do when a listener's queue is full and another chunk arrives
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(rename_all = "lowercase"))]
+pub enum LagPolicy {
+    /// Discard queued clusters so the listener resumes at the latest keyframe
+    #[cfg_attr(feature = "serde", serde(rename = "drop"))]
+    DropToKeyframe,
+    /// Drop the listener; its stream ends
+    Disconnect,
+    /// Make the Transmitter wait until the listener catches up. This only
+    /// applies when sending through the Transmitter's `Sink` implementation;
+    /// `Transmitter::send` can't wait, so it disconnects the listener instead.
+    Block,
+}
+
+impl LagPolicy {
+    /// The name `from_str` accepts for this policy
+    pub fn name(&self) -> &'static str {
+        match self {
+            LagPolicy::DropToKeyframe => "drop",
+            LagPolicy::Disconnect => "disconnect",
+            LagPolicy::Block => "block",
+        }
+    }
+}
+
+impl FromStr for LagPolicy {
+    type Err = WebmetroError;
+
+    fn from_str(policy: &str) -> Result<LagPolicy, WebmetroError> {
+        match policy {
+            "drop" => Ok(LagPolicy::DropToKeyframe),
+            "disconnect" => Ok(LagPolicy::Disconnect),
+            "block" => Ok(LagPolicy::Block),
+            _ => Err(WebmetroError::ApplicationError {
+                message: format!("Unknown lag policy \"{}\" (expected drop, disconnect, or block)", policy),
+            })
+        }
+    }
+}
+
+/// Which listeners a channel keeps serving when it can't serve them all
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize), serde(rename_all = "kebab-case"))]
+pub enum ListenerPriority {
+    /// Subject to its LagPolicy, and disconnected if it's lagging when the
+    /// memory budget runs out
+    #[default]
+    BestEffort,
+    /// Not skipped ahead or disconnected by its LagPolicy or the memory
+    /// budget: its queue grows past its limit instead (still charged to the
+    /// budget), for consumers like recorders & mirrors that need every chunk.
+    /// With the Block policy, a full queue still holds the source back. One
+    /// that stalls is disconnected once it has `MAX_CRITICAL_QUEUE_BYTES` queued.
+    Critical,
+}
+
+impl ListenerPriority {
+    /// The name `from_str` accepts for this priority
+    pub fn name(&self) -> &'static str {
+        match self {
+            ListenerPriority::BestEffort => "best-effort",
+            ListenerPriority::Critical => "critical",
+        }
+    }
+}
+
+impl FromStr for ListenerPriority {
+    type Err = WebmetroError;
+
+    fn from_str(priority: &str) -> Result<ListenerPriority, WebmetroError> {
+        match priority {
+            "best-effort" => Ok(ListenerPriority::BestEffort),
+            "critical" => Ok(ListenerPriority::Critical),
+            _ => Err(WebmetroError::ApplicationError {
+                message: format!("Unknown listener priority \"{}\" (expected best-effort or critical)", priority),
+            })
+        }
+    }
+}
+
+/// How far one listener has fallen behind the live edge
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ListenerStats {
+    pub id: u64,
+    /// where the listener is connected from, if that's known; left out of
+    /// the JSON, which anyone allowed to monitor the channel can read
+    #[cfg_attr(feature = "serde", serde(skip))]
+    pub remote: Option<SocketAddr>,
+    pub policy: LagPolicy,
+    pub priority: ListenerPriority,
+    /// how many chunks are queued, and how many may be before the policy applies
+    pub queued_chunks: usize,
+    pub queue_limit: usize,
+    pub queued_bytes: usize,
+    /// the media time the queued clusters span, in milliseconds
+    pub queued_ms: u64,
+    /// how many times the listener has been skipped ahead to a keyframe
+    pub skips: u64,
+    /// halfway behind or more, so the first to be disconnected if the memory
+    /// budget runs out
+    pub lagging: bool,
+    /// the bytes of chunks taken from the queue so far
+    pub sent_bytes: u64,
+    /// the timecode of the first Cluster taken from the queue, in ms
+    pub start_timecode: Option<u64>,
+}
+
+impl ListenerStats {
+    /// The stats as a one-line JSON object
+    #[cfg(feature = "server")]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Serializing listener stats")
+    }
+}
+
+fn is_keyframe(chunk: &Chunk) -> bool {
+    match chunk {
+        Chunk::Cluster(head, _) => head.keyframe,
+        _ => false,
+    }
+}
+
+/// A chunk the channel holds on to, with its charge against the budget.
+/// The charge is shared by every buffer & queue holding the chunk, so its
+/// bytes are only counted once, and released when the last one lets go.
+#[derive(Clone)]
+struct Held {
+    chunk: Chunk,
+    charge: Arc<Charge>,
+}
+
+impl Held {
+    fn new(chunk: Chunk, budget: &Arc<MemoryBudget>) -> Held {
+        let charge = Charge::new(budget.clone());
+        charge.add(chunk.size());
+        Held {
+            chunk,
+            charge: Arc::new(charge),
+        }
+    }
+}
+
+/// What a Listener receives: the channel's chunks, then their end. A Cluster
+/// that doesn't follow on from the last one sent, because the listener
+/// skipped ahead or another source took over, has `ClusterHead::discontinuity`
+/// set, like any other break in the stream.
+#[derive(Clone, Debug)]
+pub enum ListenerEvent {
+    /// An initialization segment, which the Clusters after it decode with
+    Headers { bytes: Bytes },
+    Cluster(ClusterHead, Bytes),
+    /// Nothing follows: the channel's source ended, or the listener was
+    /// disconnected for falling behind
+    End,
+}
+
+impl ListenerEvent {
+    fn from_chunk(chunk: Chunk) -> Option<ListenerEvent> {
+        match chunk {
+            Chunk::Headers { bytes } => Some(ListenerEvent::Headers { bytes }),
+            Chunk::Cluster(head, body) => Some(ListenerEvent::Cluster(head, body)),
+            _ => None,
+        }
+    }
+
+    /// The chunk the event carries, if it's an initialization segment or Cluster
+    pub fn into_chunk(self) -> Option<Chunk> {
+        match self {
+            ListenerEvent::Headers { bytes } => Some(Chunk::Headers { bytes }),
+            ListenerEvent::Cluster(head, body) => Some(Chunk::Cluster(head, body)),
+            _ => None,
+        }
+    }
+
+    fn is_chunk(&self) -> bool {
+        matches!(self, ListenerEvent::Headers {..} | ListenerEvent::Cluster(..))
+    }
+
+    fn is_keyframe(&self) -> bool {
+        match self {
+            ListenerEvent::Cluster(head, _) => head.keyframe,
+            _ => false,
+        }
+    }
+
+    /// How many ms of media the event carries
+    fn duration(&self) -> u64 {
+        match self {
+            ListenerEvent::Cluster(head, _) => head.end.saturating_sub(head.start),
+            _ => 0,
+        }
+    }
+
+    /// How many bytes of WebM the event carries
+    fn size(&self) -> usize {
+        match self {
+            ListenerEvent::Headers { bytes } => bytes.len(),
+            ListenerEvent::Cluster(head, body) => head.size() + body.len(),
+            _ => 0,
+        }
+    }
+}
+
+/// An event waiting in a listener's queue, holding on to its chunk's charge
+#[derive(Clone)]
+struct Queued {
+    event: ListenerEvent,
+    /// only held, so the chunk stays charged until it's taken or dropped
+    _charge: Option<Arc<Charge>>,
+}
+
+impl Queued {
+    fn held(held: Held) -> Option<Queued> {
+        Some(Queued {
+            event: ListenerEvent::from_chunk(held.chunk)?,
+            _charge: Some(held.charge),
+        })
+    }
+}
+
+struct ListenerQueue {
+    /// the chunks, and the events between them, waiting to be taken
+    events: VecDeque<Queued>,
+    /// how many of the queued events are chunks; only they count towards the limit
+    chunks: usize,
+    /// the bytes of WebM the queued events carry
+    bytes: usize,
+    /// the media time the queued clusters span, in ms
+    media_ms: u64,
+    limit: usize,
+    policy: LagPolicy,
+    priority: ListenerPriority,
+    /// set after skipping ahead, until a keyframe arrives to resume at
+    awaiting_keyframe: bool,
+    /// the next Cluster queued doesn't follow on from the last one
+    discontinuous: bool,
+    waker: Option<Waker>,
+    remote: Option<SocketAddr>,
+    /// the token a viewer can look up its own stats with
+    session: Option<String>,
+    skips: u64,
+    sent_bytes: u64,
+    start_timecode: Option<u64>,
+    /// whether the stream ends when the channel's source disconnects
+    follows_source: bool,
+    /// the source has disconnected; the stream ends once the queue is drained
+    ended: bool,
+}
+
+impl ListenerQueue {
+    fn is_full(&self) -> bool {
+        self.chunks >= self.limit
+    }
+
+    /// at least half the queue's room is used up
+    fn is_lagging(&self) -> bool {
+        self.chunks * 2 >= self.limit
+    }
+
+    /// Add an event to the running totals
+    fn count_in(&mut self, event: &ListenerEvent) {
+        self.chunks += event.is_chunk() as usize;
+        self.bytes += event.size();
+        self.media_ms += event.duration();
+    }
+
+    /// Take an event out of the running totals
+    fn count_out(&mut self, event: &ListenerEvent) {
+        self.chunks -= event.is_chunk() as usize;
+        self.bytes -= event.size();
+        self.media_ms -= event.duration();
+    }
+
+    fn push_back(&mut self, queued: Queued) {
+        self.count_in(&queued.event);
+        self.events.push_back(queued);
+    }
+
+    fn push_front(&mut self, queued: Queued) {
+        self.count_in(&queued.event);
+        self.events.push_front(queued);
+    }
+
+    fn push(&mut self, held: Held) {
+        if self.ended {
+            return;
+        }
+        if self.awaiting_keyframe {
+            match held.chunk {
+                Chunk::Cluster(..) if !is_keyframe(&held.chunk) => return,
+                _ => self.awaiting_keyframe = false,
+            }
+        }
+        if let Some(mut queued) = Queued::held(held) {
+            if let ListenerEvent::Cluster(ref mut head, _) = queued.event {
+                if self.discontinuous {
+                    head.discontinuity = true;
+                    self.discontinuous = false;
+                }
+            }
+            self.push_back(queued);
+        }
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Wake the listener to find itself removed, and forget its session
+    fn disconnect(&mut self, sessions: &mut HashMap<String, u64>) {
+        if let Some(waker) = self.waker.take() {
+            waker.wake();
+        }
+        if let Some(ref session) = self.session {
+            sessions.remove(session);
+        }
+    }
+
+    /// Mark the next Cluster queued as a discontinuity, e.g. as another
+    /// source has taken over
+    fn mark_discontinuity(&mut self) {
+        self.discontinuous = true;
+    }
+
+    fn stats(&self, id: u64) -> ListenerStats {
+        ListenerStats {
+            id,
+            remote: self.remote,
+            policy: self.policy,
+            priority: self.priority,
+            queued_chunks: self.chunks,
+            queue_limit: self.limit,
+            queued_bytes: self.bytes,
+            queued_ms: self.media_ms,
+            skips: self.skips,
+            lagging: self.is_lagging(),
+            sent_bytes: self.sent_bytes,
+            start_timecode: self.start_timecode,
+        }
+    }
+
+    /// Drop queued clusters up to the most recent keyframe, keeping the
+    /// initialization segment that applies to it; if that wouldn't free up
+    /// room, drop everything and wait for the next keyframe. Either way, the
+    /// Cluster it resumes at is marked as a discontinuity.
+    fn skip_to_keyframe(&mut self) {
+        self.skips += 1;
+        let resume_at = match self.events.iter().rposition(|queued| queued.event.is_keyframe()) {
+            Some(position) if position > 0 => position,
+            _ => self.events.len(),
+        };
+        let is_headers = |queued: &Queued| matches!(queued.event, ListenerEvent::Headers {..});
+        let mut skipped: Vec<Queued> = self.events.drain(..resume_at).collect();
+        for queued in skipped.iter() {
+            self.count_out(&queued.event);
+        }
+
+        let resumed = !self.events.is_empty()
+            && self.chunks + (skipped.iter().any(is_headers) as usize) < self.limit;
+        if !resumed {
+            let rest = skipped.len();
+            skipped.extend(self.events.drain(..));
+            for queued in skipped[rest..].iter() {
+                self.count_out(&queued.event);
+            }
+            self.awaiting_keyframe = true;
+        }
+        let headers = skipped.iter().rev().find(|queued| is_headers(queued)).cloned();
+        if let Some(headers) = headers {
+            self.push_front(headers);
+        }
+        let resumed_at = self.events.iter_mut().find_map(|queued| match queued.event {
+            ListenerEvent::Cluster(ref mut head, _) => Some(head),
+            _ => None,
+        });
+        match resumed_at {
+            Some(head) => head.discontinuity = true,
+            None => self.discontinuous = true,
+        }
+    }
+
+    fn pop(&mut self) -> Option<ListenerEvent> {
+        let event = self.events.pop_front()?.event;
+        self.count_out(&event);
+        self.sent_bytes += event.size() as u64;
+        if let ListenerEvent::Cluster(ref head, _) = event {
+            self.start_timecode.get_or_insert(head.start);
+        }
+        Some(event)
+    }
+}
+
+/// The clusters kept for listeners that rewind, with where their keyframes
+/// are, so neither trimming nor rewinding has to look through every cluster
+#[derive(Default)]
+struct DvrBuffer {
+    clusters: VecDeque<Held>,
+    /// each keyframe's position (counting every cluster ever pushed) & start
+    keyframes: VecDeque<(usize, u64)>,
+    /// how many clusters have been dropped from the front
+    dropped: usize,
+}
+
+impl DvrBuffer {
+    fn is_empty(&self) -> bool {
+        self.clusters.is_empty()
+    }
+
+    fn clear(&mut self) {
+        self.dropped += self.clusters.len();
+        self.clusters.clear();
+        self.keyframes.clear();
+    }
+
+    fn push(&mut self, held: Held) {
+        if let Chunk::Cluster(ref head, _) = held.chunk {
+            if head.keyframe {
+                self.keyframes.push_back((self.dropped + self.clusters.len(), head.start));
+            }
+        }
+        self.clusters.push_back(held);
+    }
+
+    /// Drop the clusters before the latest keyframe at or before `oldest_needed`
+    fn trim(&mut self, oldest_needed: u64) {
+        while self.keyframes.get(1).map_or(false, |&(_, start)| start <= oldest_needed) {
+            self.keyframes.pop_front();
+        }
+        if let Some(&(position, start)) = self.keyframes.front() {
+            if start <= oldest_needed {
+                let drop = position - self.dropped;
+                self.clusters.drain(..drop);
+                self.dropped += drop;
+            }
+        }
+    }
+
+    /// The clusters from the latest keyframe at or before `target`, or from
+    /// the earliest keyframe if there's none that far back
+    fn from(&self, target: u64) -> Option<Vec<Held>> {
+        let &(position, _) = self.keyframes.iter().rev().find(|&&(_, start)| start <= target)
+            .or_else(|| self.keyframes.front())?;
+        Some(self.clusters.iter().skip(position - self.dropped).cloned().collect())
+    }
+}
 
 /// A collection of listeners to a stream of WebM chunks.
-/// Sending a chunk may fail due to a client being disconnected,
-/// or simply failing to keep up with the stream buffer. In either
-/// case, there's nothing practical the server can do to recover,
-/// so the failing client is just dropped from the listener list.
+/// Each listener has a bounded queue; what happens when a listener falls
+/// too far behind is decided by its LagPolicy.
+///
+/// The latest initialization segment and the clusters since the latest
+/// keyframe are retained, so a new listener can start decoding immediately.
+///
+/// Retained & queued chunks are charged to a MemoryBudget, once each however
+/// many listeners they're queued for. Under pressure,
+/// the channel stops retaining clusters, and when the budget is exhausted it
+/// disconnects listeners that have fallen halfway behind, whatever their
+/// policy. Critical listeners (see `ListenerPriority`) are spared both, up
+/// to a hard cap on what they have queued.
 pub struct Channel {
     pub name: String,
-    header_chunk: Option<Chunk>,
-    listeners: Vec<Sender<Chunk>>,
+    span: Span,
+    budget: Arc<MemoryBudget>,
+    header_chunk: Option<Held>,
+    /// the tracks the headers describe
+    tracks: Vec<TrackEntry>,
+    keyframe_snapshot: Vec<Held>,
+    /// how many ms of clusters to keep for listeners that rewind, if any
+    dvr_window: Option<u64>,
+    /// the clusters since the headers, from the latest keyframe at least
+    /// `dvr_window` behind the live edge
+    dvr: DvrBuffer,
+    listeners: HashMap<u64, ListenerQueue>,
+    /// the listeners given session tokens, by token
+    sessions: HashMap<String, u64>,
+    next_listener_id: u64,
+    /// the Transmitter whose chunks reach listeners, if any
+    source: Option<u64>,
+    /// the Transmitter whose chunks listeners got last, even if it's gone
+    last_source: Option<u64>,
+    /// hold listeners when the source disconnects, until the next one
+    paused: bool,
+    /// a Transmitter waiting to take over from the source, and the latest
+    /// initialization segment it's sent
+    successor: Option<(u64, Option<Chunk>)>,
+    next_transmitter_id: u64,
+    transmitter_waker: Option<Waker>,
 }
 
 pub type Handle = Arc<Mutex<Channel>>;
 
 impl Channel {
     pub fn new(name: String) -> Handle {
-        info!("Opening Channel {}", name);
+        Channel::with_budget(name, MemoryBudget::unlimited())
+    }
+
+    /// Open a channel charging its buffers to a (typically shared) budget
+    pub fn with_budget(name: String, budget: Arc<MemoryBudget>) -> Handle {
+        let span = info_span!("channel", name = %name);
+        span.in_scope(|| info!("Opening Channel {}", name));
         Arc::new(Mutex::new(Channel {
             name,
+            span,
+            budget,
             header_chunk: None,
-            listeners: Vec::new(),
+            tracks: Vec::new(),
+            keyframe_snapshot: Vec::new(),
+            dvr_window: None,
+            dvr: DvrBuffer::default(),
+            listeners: HashMap::new(),
+            sessions: HashMap::new(),
+            next_listener_id: 0,
+            source: None,
+            last_source: None,
+            paused: false,
+            successor: None,
+            next_transmitter_id: 0,
+            transmitter_waker: None,
         }))
     }
+
+    /// Whether a Transmitter is currently feeding the channel
+    pub fn has_source(&self) -> bool {
+        self.source.is_some()
+    }
+
+    /// The latest initialization segment, if the channel has had a source
+    pub fn headers(&self) -> Option<Bytes> {
+        match self.header_chunk {
+            Some(Held { chunk: Chunk::Headers { ref bytes }, .. }) => Some(bytes.clone()),
+            _ => None,
+        }
+    }
+
+    /// The tracks of the latest initialization segment; empty if the channel
+    /// hasn't had a source
+    pub fn tracks(&self) -> &[TrackEntry] {
+        &self.tracks
+    }
+
+    /// Retain this many ms of clusters (from a keyframe) for listeners that
+    /// join with `Listener::rewound`, or none with `None`. They're charged to
+    /// the budget, and dropped under memory pressure.
+    pub fn set_dvr_window(&mut self, window: Option<u64>) {
+        self.dvr_window = window;
+        if window.is_none() {
+            self.dvr.clear();
+        }
+    }
+
+    /// The clusters from the DVR buffer to start a listener `rewind` ms
+    /// behind the live edge with, starting at a keyframe
+    fn dvr_backlog(&self, rewind: u64) -> Option<Vec<Held>> {
+        let edge = match self.dvr.clusters.back()?.chunk {
+            Chunk::Cluster(ref head, _) => head.start,
+            _ => return None,
+        };
+        self.dvr.from(edge.saturating_sub(rewind))
+    }
+
+    /// The latest keyframe Cluster, while it's retained for new listeners
+    pub fn keyframe_cluster(&self) -> Option<Chunk> {
+        self.keyframe_snapshot.first().map(|held| held.chunk.clone())
+    }
+
+    /// How far behind each listener is, in the order they joined
+    pub fn listener_stats(&self) -> Vec<ListenerStats> {
+        let mut stats: Vec<ListenerStats> = self.listeners.iter()
+            .map(|(id, queue)| queue.stats(*id))
+            .collect();
+        stats.sort_by_key(|stats| stats.id);
+        stats
+    }
+
+    /// The stats of the listener given this session token, if it's still connected
+    pub fn session_stats(&self, session: &str) -> Option<ListenerStats> {
+        let id = self.sessions.get(session)?;
+        self.listeners.get(id).map(|queue| queue.stats(*id))
+    }
+
+    fn update_snapshot(&mut self, held: &Held, pressure: Pressure) {
+        match held.chunk {
+            Chunk::Headers { ref bytes } => {
+                self.header_chunk = Some(held.clone());
+                self.tracks = header_tracks(bytes);
+                self.keyframe_snapshot.clear();
+            },
+            Chunk::Cluster(ref head, _) if head.keyframe => {
+                self.keyframe_snapshot.clear();
+                if pressure < Pressure::Critical {
+                    self.keyframe_snapshot.push(held.clone());
+                }
+            },
+            Chunk::Cluster(..) => {
+                let room = !self.keyframe_snapshot.is_empty() && self.keyframe_snapshot.len() < SNAPSHOT_LIMIT;
+                if room && pressure == Pressure::Normal {
+                    self.keyframe_snapshot.push(held.clone());
+                } else {
+                    if !self.keyframe_snapshot.is_empty() && pressure > Pressure::Normal {
+                        debug!("Memory budget running low, dropping retained clusters on Channel {}", self.name);
+                    }
+                    self.keyframe_snapshot.clear();
+                }
+            },
+            _ => {}
+        }
+        self.update_dvr(held, pressure);
+    }
+
+    fn update_dvr(&mut self, held: &Held, pressure: Pressure) {
+        let window = match self.dvr_window {
+            Some(window) => window,
+            None => return,
+        };
+        let edge = match held.chunk {
+            Chunk::Headers {..} => {
+                // rewinding doesn't reach back past a change of source
+                self.dvr.clear();
+                return;
+            },
+            Chunk::Cluster(ref head, _) => head.start,
+            _ => return,
+        };
+        if pressure > Pressure::Normal {
+            if !self.dvr.is_empty() {
+                debug!("Memory budget running low, dropping DVR buffer on Channel {}", self.name);
+            }
+            self.dvr.clear();
+            return;
+        }
+        self.dvr.push(held.clone());
+
+        // drop clusters from before the latest keyframe that's far enough back
+        self.dvr.trim(edge.saturating_sub(window));
+    }
+
+    /// Forget a listener, and its session token
+    fn remove_listener(&mut self, id: u64) {
+        if let Some(queue) = self.listeners.remove(&id) {
+            if let Some(session) = queue.session {
+                self.sessions.remove(&session);
+            }
+        }
+    }
+
+    fn wake_transmitter(&mut self) {
+        if let Some(waker) = self.transmitter_waker.take() {
+            waker.wake();
+        }
+    }
+
+    /// Whether the channel is paused: its listeners are held when its source
+    /// disconnects, rather than ended, until the next source resumes it
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// Pause the channel (see `is_paused`), or resume it; resuming a channel
+    /// without a source ends its listeners' streams
+    pub fn set_paused(&mut self, paused: bool) {
+        if paused != self.paused {
+            info!("Channel {} {}", self.name, if paused { "paused" } else { "resumed" });
+        }
+        self.paused = paused;
+        if !paused {
+            self.end_listeners();
+        }
+    }
+
+    /// Make a Transmitter the source, which resumes a paused channel
+    fn set_source(&mut self, id: u64) {
+        self.source = Some(id);
+        if self.paused {
+            info!("Channel {} resumed", self.name);
+            self.paused = false;
+        }
+    }
+
+    /// With no source (or Transmitter waiting to take over), let the
+    /// listeners following the last one finish what's queued, then end their
+    /// streams. Transmitters do this as they disconnect, unless told to
+    /// `keep_listeners`. Paused channels keep their listeners.
+    pub fn end_listeners(&mut self) {
+        if self.source.is_some() || self.successor.is_some() || self.paused {
+            return;
+        }
+        for queue in self.listeners.values_mut().filter(|queue| queue.follows_source) {
+            queue.ended = true;
+            if let Some(waker) = queue.waker.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// A count that moves on whenever a Transmitter joins the channel, so a
+    /// caller can tell whether a new source has turned up since
+    pub fn source_generation(&self) -> u64 {
+        self.next_transmitter_id
+    }
+
+    /// Like `end_listeners`, unless a Transmitter has joined since
+    /// `generation`, e.g. for a reconnect grace that's no longer current
+    pub fn end_listeners_since(&mut self, generation: u64) {
+        if self.source_generation() == generation {
+            self.end_listeners();
+        }
+    }
+
+    fn next_transmitter(&mut self) -> u64 {
+        let id = self.next_transmitter_id;
+        self.next_transmitter_id += 1;
+        id
+    }
+
+    /// Queue a chunk for every listener, applying their lag policies
+    fn broadcast(&mut self, chunk: Chunk) {
+        let pressure = self.budget.pressure();
+        let held = Held::new(chunk, &self.budget);
+        self.update_snapshot(&held, pressure);
+        let changed = match (self.last_source, self.source) {
+            (Some(last), Some(source)) => last != source,
+            _ => false,
+        };
+        if self.source.is_some() {
+            self.last_source = self.source;
+        }
+
+        let name = self.name.clone();
+        let exhausted = pressure == Pressure::Critical;
+        let sessions = &mut self.sessions;
+        self.listeners.retain(|id, listener| {
+            // critical listeners just queue up, unless they've stalled outright
+            let sacrificial = listener.priority != ListenerPriority::Critical;
+            if !sacrificial && listener.bytes >= MAX_CRITICAL_QUEUE_BYTES {
+                warn!(listener = id, "Critical Listener {} on Channel {} stalled with {} bytes queued, disconnecting", id, name, listener.bytes);
+                listener.disconnect(sessions);
+                return false;
+            }
+            if sacrificial && exhausted && listener.is_lagging() {
+                warn!(listener = id, "Memory budget exhausted, disconnecting lagging Listener {} on Channel {}", id, name);
+                listener.disconnect(sessions);
+                return false;
+            }
+            if sacrificial && listener.is_full() {
+                match listener.policy {
+                    LagPolicy::DropToKeyframe => {
+                        debug!(listener = id, "Listener {} on Channel {} lagging, skipping to keyframe", id, name);
+                        listener.skip_to_keyframe();
+                    },
+                    LagPolicy::Disconnect | LagPolicy::Block => {
+                        info!(listener = id, "Listener {} on Channel {} lagging, disconnecting", id, name);
+                        listener.disconnect(sessions);
+                        return false;
+                    }
+                }
+            }
+            if changed {
+                listener.mark_discontinuity();
+            }
+            listener.push(held.clone());
+            true
+        });
+    }
 }
 
 impl Drop for Channel {
     fn drop(&mut self) {
+        let _enter = self.span.enter();
         info!("Closing Channel {}", self.name);
     }
 }
 
+/// Feeds a channel's listeners. A channel has one source at a time; other
+/// Transmitters either wait to take over from it, or have been replaced.
 pub struct Transmitter {
     channel: Handle,
+    span: Span,
+    id: u64,
+    /// leave listeners waiting when disconnecting, instead of ending them
+    keep_listeners: bool,
+    /// pause the channel when disconnecting as its source
+    pause_on_disconnect: bool,
 }
 
 impl Transmitter {
+    /// Become the channel's source right away, replacing any other
     pub fn new(channel_arc: Handle) -> Self {
+        let id = {
+            let mut channel = channel_arc.lock().expect("Locking channel");
+            let id = channel.next_transmitter();
+            channel.set_source(id);
+            id
+        };
+        Transmitter::with_id(channel_arc, id)
+    }
+
+    /// Become the channel's source, unless it already has one
+    pub fn claim(channel_arc: Handle) -> Result<Self, WebmetroError> {
+        let id = {
+            let mut channel = channel_arc.lock().expect("Locking channel");
+            if channel.source.is_some() {
+                return Err(WebmetroError::ChannelBusy { name: channel.name.clone() });
+            }
+            let id = channel.next_transmitter();
+            channel.set_source(id);
+            id
+        };
+        Ok(Transmitter::with_id(channel_arc, id))
+    }
+
+    /// Replace the channel's source once this Transmitter has sent an
+    /// initialization segment and a keyframe, so listeners switch over
+    /// cleanly; until then, the current source carries on and this one's
+    /// clusters are discarded. Supersedes any other Transmitter waiting to
+    /// take over.
+    pub fn take_over(channel_arc: Handle) -> Self {
+        let id = {
+            let mut channel = channel_arc.lock().expect("Locking channel");
+            let id = channel.next_transmitter();
+            if channel.source.is_some() {
+                channel.successor = Some((id, None));
+            } else {
+                channel.set_source(id);
+            }
+            id
+        };
+        Transmitter::with_id(channel_arc, id)
+    }
+
+    fn with_id(channel_arc: Handle, id: u64) -> Self {
+        let span = debug_span!("send", channel = %channel_arc.lock().expect("Locking channel").name);
         Transmitter {
             channel: channel_arc,
+            span,
+            id,
+            keep_listeners: false,
+            pause_on_disconnect: false,
         }
     }
 
+    /// Leave listeners waiting for another source when this one disconnects,
+    /// e.g. while it may reconnect, rather than ending their streams; they
+    /// can be ended later with `Channel::end_listeners`
+    pub fn keep_listeners(&mut self) {
+        self.keep_listeners = true;
+    }
+
+    /// Pause the channel (see `Channel::is_paused`) when this Transmitter
+    /// disconnects as its source, e.g. for a break between programmes
+    pub fn pause_on_disconnect(&mut self) {
+        self.pause_on_disconnect = true;
+    }
+
+    /// Send a chunk to the channel's listeners; chunks from a Transmitter
+    /// that's been replaced are discarded
     pub fn send(&self, chunk: Chunk) {
+        if let Err(err) = self.try_send(chunk) {
+            debug!("{}", err);
+        }
+    }
+
+    /// Send a chunk to the channel's listeners, failing if another
+    /// Transmitter has replaced this one
+    pub fn try_send(&self, chunk: Chunk) -> Result<(), WebmetroError> {
+        let _enter = self.span.enter();
         let mut channel = self.channel.lock().expect("Locking channel");
 
-        if let Chunk::Headers { .. } = chunk {
-            channel.header_chunk = Some(chunk.clone());
+        if channel.source != Some(self.id) {
+            let headers = match channel.successor {
+                Some((id, ref mut headers)) if id == self.id => headers,
+                _ => return Err(WebmetroError::SourceReplaced { name: channel.name.clone() }),
+            };
+            match chunk {
+                Chunk::Headers {..} => {
+                    *headers = Some(chunk);
+                    return Ok(());
+                },
+                Chunk::Cluster(ref head, _) if head.keyframe && headers.is_some() => {
+                    let headers = headers.take();
+                    channel.successor = None;
+                    channel.set_source(self.id);
+                    info!("Source replaced on Channel {}", channel.name);
+                    if let Some(headers) = headers {
+                        channel.broadcast(headers);
+                    }
+                },
+                // nothing listeners could start decoding from yet
+                _ => return Ok(()),
+            }
+        }
+
+        channel.broadcast(chunk);
+        Ok(())
+    }
+}
+
+/// Lets an ingest pipeline be driven with `chunk_stream.forward(transmitter)`.
+/// The Transmitter is ready for another chunk unless a listener with the
+/// Block policy has a full queue.
+impl Sink<Chunk> for Transmitter {
+    type Error = WebmetroError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), WebmetroError>> {
+        let mut channel = self.channel.lock().expect("Locking channel");
+        // only the source's chunks reach listeners, so only it waits for them
+        let blocked = channel.source == Some(self.id) && channel.listeners.values()
+            .any(|listener| listener.policy == LagPolicy::Block && listener.is_full());
+        if blocked {
+            channel.transmitter_waker = Some(cx.waker().clone());
+            Poll::Pending
+        } else {
+            Poll::Ready(Ok(()))
         }
+    }
+
+    fn start_send(self: Pin<&mut Self>, chunk: Chunk) -> Result<(), WebmetroError> {
+        self.try_send(chunk)
+    }
 
-        channel
-            .listeners
-            .retain_mut(|listener| listener.start_send(chunk.clone()).is_ok());
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), WebmetroError>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context) -> Poll<Result<(), WebmetroError>> {
+        Poll::Ready(Ok(()))
     }
 }
 
 impl Drop for Transmitter {
     fn drop(&mut self) {
         if let Ok(mut channel) = self.channel.lock() {
-            // when disconnecting, clean up the header chunk so subsequent
-            // clients don't get a potentially incorrect initialization segment
-            channel.header_chunk = None;
+            if channel.source == Some(self.id) {
+                if self.pause_on_disconnect {
+                    channel.set_paused(true);
+                }
+                // when disconnecting, clean up the header chunk so subsequent
+                // clients don't get a potentially incorrect initialization segment
+                channel.source = None;
+                channel.header_chunk = None;
+                channel.keyframe_snapshot.clear();
+                channel.dvr.clear();
+                // a Transmitter waiting to take over carries on instead
+                if !self.keep_listeners {
+                    channel.end_listeners();
+                }
+            }
+            if channel.successor.as_ref().map_or(false, |(id, _)| *id == self.id) {
+                channel.successor = None;
+                // it was meant to take over from a source that's already gone
+                if !self.keep_listeners {
+                    channel.end_listeners();
+                }
+            }
         }
     }
 }
 
+/// Receives a channel's chunks, as `ListenerEvent`s; `into_chunks` gives
+/// just the chunks, for consumers that only want the bytes
 pub struct Listener {
-    /// not used in operation, but its refcount keeps the channel alive when there's no Transmitter
-    _channel: Handle,
-    receiver: Receiver<Chunk>,
+    /// besides holding the queue, its refcount keeps the channel alive when there's no Transmitter
+    channel: Handle,
+    id: u64,
+    /// the End event has been sent
+    ended: bool,
 }
 
 impl Listener {
     pub fn new(channel_arc: Handle) -> Self {
-        let (mut sender, receiver) = mpsc_channel(5);
+        Listener::with_policy(channel_arc, DEFAULT_QUEUE_LIMIT, LagPolicy::Disconnect)
+    }
 
-        {
+    /// Join a channel, allowing up to `queue_limit` chunks to queue up
+    /// before applying the given LagPolicy.
+    pub fn with_policy(channel_arc: Handle, queue_limit: usize, policy: LagPolicy) -> Self {
+        Listener::join(channel_arc, queue_limit, policy, None)
+    }
+
+    /// Like `with_policy`, but start about `rewind` ms behind the live edge,
+    /// at the latest keyframe that far back in the channel's DVR buffer (see
+    /// `Channel::set_dvr_window`), or its earliest one. The queue has room
+    /// for that backlog on top of `queue_limit`. Without a DVR buffer, this
+    /// starts at the live edge like any other listener.
+    pub fn rewound(channel_arc: Handle, queue_limit: usize, policy: LagPolicy, rewind: u64) -> Self {
+        Listener::join(channel_arc, queue_limit, policy, Some(rewind))
+    }
+
+    fn join(channel_arc: Handle, queue_limit: usize, policy: LagPolicy, rewind: Option<u64>) -> Self {
+        let id = {
             let mut channel = channel_arc.lock().expect("Locking channel");
+            let id = channel.next_listener_id;
+            channel.next_listener_id += 1;
 
-            if let Some(ref chunk) = channel.header_chunk {
-                sender
-                    .start_send(chunk.clone())
-                    .expect("Queuing existing header chunk");
+            let mut queue = ListenerQueue {
+                events: VecDeque::new(),
+                chunks: 0,
+                bytes: 0,
+                media_ms: 0,
+                // room for at least an initialization segment & a cluster
+                limit: queue_limit.max(2),
+                policy,
+                priority: ListenerPriority::default(),
+                awaiting_keyframe: false,
+                discontinuous: false,
+                waker: None,
+                remote: None,
+                session: None,
+                skips: 0,
+                sent_bytes: 0,
+                start_timecode: None,
+                follows_source: false,
+                ended: false,
+            };
+            if let Some(ref held) = channel.header_chunk {
+                if let Some(queued) = Queued::held(held.clone()) {
+                    queue.push_back(queued);
+                }
+                let backlog = match rewind.and_then(|rewind| channel.dvr_backlog(rewind)) {
+                    Some(backlog) => {
+                        queue.limit += backlog.len();
+                        backlog
+                    },
+                    None if queue.events.len() + channel.keyframe_snapshot.len() <= queue.limit => {
+                        channel.keyframe_snapshot.clone()
+                    },
+                    None => {
+                        queue.awaiting_keyframe = true;
+                        Vec::new()
+                    },
+                };
+                for queued in backlog.into_iter().filter_map(Queued::held) {
+                    queue.push_back(queued);
+                }
             }
 
-            channel.listeners.push(sender);
-        }
+            channel.listeners.insert(id, queue);
+            id
+        };
 
         Listener {
-            _channel: channel_arc,
-            receiver: receiver,
+            channel: channel_arc,
+            id,
+            ended: false,
+        }
+    }
+
+    /// Note where the listener is connected from, for its stats
+    pub fn with_remote(self, remote: Option<SocketAddr>) -> Self {
+        if let Some(queue) = self.channel.lock().expect("Locking channel").listeners.get_mut(&self.id) {
+            queue.remote = remote;
+        }
+        self
+    }
+
+    /// Set how the listener fares when the channel can't keep up with
+    /// everyone; listeners are best-effort unless made critical
+    pub fn with_priority(self, priority: ListenerPriority) -> Self {
+        if let Some(queue) = self.channel.lock().expect("Locking channel").listeners.get_mut(&self.id) {
+            queue.priority = priority;
+        }
+        self
+    }
+
+    /// Give the listener a session token, so `Channel::session_stats` can find it
+    pub fn with_session(self, session: Option<String>) -> Self {
+        {
+            let mut channel = self.channel.lock().expect("Locking channel");
+            let Channel { listeners, sessions, .. } = &mut *channel;
+            if let Some(queue) = listeners.get_mut(&self.id) {
+                if let Some(ref token) = session {
+                    sessions.insert(token.clone(), self.id);
+                }
+                queue.session = session;
+            }
+        }
+        self
+    }
+
+    /// End the stream, after any chunks already queued, once the channel's
+    /// source disconnects (unless another Transmitter is waiting to take
+    /// over), rather than waiting for a new source
+    pub fn until_source_ends(self) -> Self {
+        if let Some(queue) = self.channel.lock().expect("Locking channel").listeners.get_mut(&self.id) {
+            queue.follows_source = true;
+        }
+        self
+    }
+
+    /// Just the chunks, leaving out the other events
+    pub fn into_chunks(self) -> ListenerChunks {
+        ListenerChunks {
+            listener: self,
         }
     }
 }
 
 impl Stream for Listener {
+    type Item = ListenerEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<ListenerEvent>> {
+        if self.ended {
+            return Poll::Ready(None);
+        }
+        let next_event = {
+            let mut channel = self.channel.lock().expect("Locking channel");
+            match channel.listeners.get_mut(&self.id) {
+                // dropped for lagging
+                None => None,
+                Some(queue) => match queue.pop() {
+                    Some(event) => {
+                        channel.wake_transmitter();
+                        Some(event)
+                    },
+                    None if queue.ended => {
+                        channel.remove_listener(self.id);
+                        channel.wake_transmitter();
+                        None
+                    },
+                    None => {
+                        queue.waker = Some(cx.waker().clone());
+                        return Poll::Pending;
+                    }
+                }
+            }
+        };
+        Poll::Ready(Some(next_event.unwrap_or_else(|| {
+            self.ended = true;
+            ListenerEvent::End
+        })))
+    }
+}
+
+/// A Listener's chunks alone; see `Listener::into_chunks`
+pub struct ListenerChunks {
+    listener: Listener,
+}
+
+impl Stream for ListenerChunks {
     type Item = Chunk;
 
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Chunk>> {
-        let receiver = &mut self.get_mut().receiver;
-        Pin::new(receiver).poll_next(cx)
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Chunk>> {
+        loop {
+            match ready!(Pin::new(&mut self.listener).poll_next(cx)) {
+                Some(event) => if let Some(chunk) = event.into_chunk() {
+                    return Poll::Ready(Some(chunk));
+                },
+                None => return Poll::Ready(None),
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        if let Ok(mut channel) = self.channel.lock() {
+            channel.remove_listener(self.id);
+            channel.wake_transmitter();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::{executor::block_on, future::poll_fn, sink::SinkExt, stream::{iter, StreamExt}};
+    use matches::assert_matches;
+
+    use crate::budget::MemoryBudget;
+    use crate::channel::*;
+    use crate::chunk::ClusterHead;
+    use crate::tests::{empty_cluster, starts};
+
+    #[test]
+    fn forward_into_transmitter() {
+        let channel = Channel::new("test".into());
+        let listener = Listener::new(channel.clone()).into_chunks();
+
+        let chunks = vec![
+            Chunk::Headers { bytes: Bytes::from_static(b"headers") },
+            Chunk::Cluster(ClusterHead::new(0), Bytes::from_static(b"cluster")),
+        ];
+        block_on(iter(chunks).map(Ok).forward(Transmitter::new(channel))).unwrap();
+
+        let received: Vec<Chunk> = block_on(listener.take(2).collect());
+        assert_matches!(received[0], Chunk::Headers {..});
+        assert_matches!(received[1], Chunk::Cluster(..));
+    }
+
+    #[test]
+    fn lagging_listener_skips_to_keyframe() {
+        let channel = Channel::new("test".into());
+        let listener = Listener::with_policy(channel.clone(), 3, LagPolicy::DropToKeyframe).into_chunks();
+        let transmitter = Transmitter::new(channel);
+
+        transmitter.send(Chunk::Headers { bytes: Bytes::from_static(b"headers") });
+        transmitter.send(empty_cluster(0, true));
+        transmitter.send(empty_cluster(1, false));
+        transmitter.send(empty_cluster(2, true));
+        transmitter.send(empty_cluster(3, false));
+        drop(transmitter);
+
+        let mut listener = listener;
+        let received: Vec<Chunk> = block_on(poll_fn(|cx| {
+            let mut received = Vec::new();
+            while let Poll::Ready(Some(chunk)) = Pin::new(&mut listener).poll_next(cx) {
+                received.push(chunk);
+            }
+            Poll::Ready(received)
+        }));
+        assert_eq!(starts(&received), vec![None, Some(2), Some(3)]);
+    }
+
+    #[test]
+    fn signal_stream_changes() {
+        let channel = Channel::new("test".into());
+        let mut listener = Listener::with_policy(channel.clone(), 3, LagPolicy::DropToKeyframe).until_source_ends();
+        let transmitter = Transmitter::new(channel.clone());
+        transmitter.send(Chunk::Headers { bytes: Bytes::from_static(b"headers") });
+        transmitter.send(empty_cluster(0, true));
+
+        let successor = Transmitter::take_over(channel.clone());
+        successor.send(Chunk::Headers { bytes: Bytes::from_static(b"other headers") });
+        // with room for three chunks, the listener falls behind & skips ahead
+        successor.send(empty_cluster(1, true));
+        successor.send(empty_cluster(2, false));
+        drop(transmitter);
+        successor.send(empty_cluster(3, true));
+        successor.send(empty_cluster(4, false));
+        drop(successor);
+
+        let events: Vec<String> = block_on(poll_fn(|cx| {
+            let mut events = Vec::new();
+            while let Poll::Ready(Some(event)) = Pin::new(&mut listener).poll_next(cx) {
+                events.push(match event {
+                    ListenerEvent::Headers { bytes } => String::from_utf8(bytes.to_vec()).unwrap(),
+                    // discontinuities are starred
+                    ListenerEvent::Cluster(head, _) if head.discontinuity => format!("{}*", head.start),
+                    ListenerEvent::Cluster(head, _) => head.start.to_string(),
+                    ListenerEvent::End => "End".to_string(),
+                });
+            }
+            Poll::Ready(events)
+        }));
+        assert_eq!(events, vec!["other headers", "3*", "4", "End"]);
+        assert!(block_on(listener.next()).is_none());
+    }
+
+    #[test]
+    fn lagging_listener_disconnects() {
+        let channel = Channel::new("test".into());
+        let listener = Listener::with_policy(channel.clone(), 2, LagPolicy::Disconnect).into_chunks();
+        let transmitter = Transmitter::new(channel);
+
+        for timecode in 0..3 {
+            transmitter.send(empty_cluster(timecode, true));
+        }
+
+        let received: Vec<Chunk> = block_on(listener.collect());
+        assert!(received.is_empty());
+    }
+
+    #[test]
+    fn end_with_source() {
+        let channel = Channel::new("test".into());
+        let following = Listener::new(channel.clone()).until_source_ends().into_chunks();
+        let mut waiting = Listener::new(channel.clone());
+        let transmitter = Transmitter::new(channel.clone());
+
+        transmitter.send(Chunk::Headers { bytes: Bytes::from_static(b"headers") });
+        transmitter.send(empty_cluster(0, true));
+        let successor = Transmitter::take_over(channel.clone());
+        drop(transmitter);
+        // a Transmitter waiting to take over keeps listeners going
+        assert!(!channel.lock().unwrap().listeners.values().any(|queue| queue.ended));
+
+        drop(successor);
+        let received: Vec<Chunk> = block_on(following.collect());
+        assert_eq!(starts(&received), vec![None, Some(0)]);
+
+        // listeners that didn't ask to end with the source wait for another one
+        let pending = block_on(poll_fn(|cx| {
+            while let Poll::Ready(Some(_)) = Pin::new(&mut waiting).poll_next(cx) {}
+            Poll::Ready(Pin::new(&mut waiting).poll_next(cx).is_pending())
+        }));
+        assert!(pending);
+    }
+
+    #[test]
+    fn keep_listeners_for_reconnect() {
+        let channel = Channel::new("test".into());
+        let mut listener = Listener::new(channel.clone()).until_source_ends();
+        let mut transmitter = Transmitter::new(channel.clone());
+        transmitter.keep_listeners();
+        transmitter.send(Chunk::Headers { bytes: Bytes::from_static(b"headers") });
+        drop(transmitter);
+
+        // still waiting for the source to come back
+        let pending = block_on(poll_fn(|cx| {
+            while let Poll::Ready(Some(_)) = Pin::new(&mut listener).poll_next(cx) {}
+            Poll::Ready(Pin::new(&mut listener).poll_next(cx).is_pending())
+        }));
+        assert!(pending);
+
+        // a grace period from before the latest source came & went is stale
+        let generation = channel.lock().unwrap().source_generation();
+        let mut transmitter = Transmitter::new(channel.clone());
+        transmitter.keep_listeners();
+        drop(transmitter);
+        channel.lock().unwrap().end_listeners_since(generation);
+        let pending = block_on(poll_fn(|cx| Poll::Ready(Pin::new(&mut listener).poll_next(cx).is_pending())));
+        assert!(pending);
+
+        channel.lock().unwrap().end_listeners();
+        assert_matches!(block_on(listener.next()), Some(ListenerEvent::End));
+        assert!(block_on(listener.next()).is_none());
+    }
+
+    #[test]
+    fn hold_listeners_while_paused() {
+        let channel = Channel::new("test".into());
+        let mut listener = Listener::new(channel.clone()).until_source_ends();
+        let mut transmitter = Transmitter::new(channel.clone());
+        transmitter.pause_on_disconnect();
+        transmitter.send(Chunk::Headers { bytes: Bytes::from_static(b"headers") });
+        drop(transmitter);
+        assert!(channel.lock().unwrap().is_paused());
+
+        let pending = block_on(poll_fn(|cx| {
+            while let Poll::Ready(Some(_)) = Pin::new(&mut listener).poll_next(cx) {}
+            Poll::Ready(Pin::new(&mut listener).poll_next(cx).is_pending())
+        }));
+        assert!(pending);
+
+        // the next source resumes the channel, so its end is the listener's too
+        let transmitter = Transmitter::new(channel.clone());
+        assert!(!channel.lock().unwrap().is_paused());
+        transmitter.send(Chunk::Headers { bytes: Bytes::from_static(b"headers") });
+        drop(transmitter);
+        assert_eq!(block_on(listener.into_chunks().collect::<Vec<_>>()).len(), 1);
+
+        // resuming without a source ends listeners at once
+        let mut listener = Listener::new(channel.clone()).until_source_ends();
+        channel.lock().unwrap().set_paused(true);
+        channel.lock().unwrap().set_paused(false);
+        assert_matches!(block_on(listener.next()), Some(ListenerEvent::End));
+    }
+
+    #[test]
+    fn lagging_listener_blocks_transmitter() {
+        let channel = Channel::new("test".into());
+        let mut listener = Listener::with_policy(channel.clone(), 2, LagPolicy::Block).into_chunks();
+        let mut transmitter = Transmitter::new(channel);
+
+        block_on(SinkExt::send(&mut transmitter, empty_cluster(0, true))).unwrap();
+        block_on(SinkExt::send(&mut transmitter, empty_cluster(1, false))).unwrap();
+        block_on(poll_fn(|cx| {
+            assert!(Pin::new(&mut transmitter).poll_ready(cx).is_pending());
+            match Pin::new(&mut listener).poll_next(cx) {
+                Poll::Ready(Some(chunk)) => assert_eq!(starts(&[chunk]), vec![Some(0)]),
+                _ => panic!("Listener should have a chunk queued"),
+            }
+            assert!(Pin::new(&mut transmitter).poll_ready(cx).is_ready());
+            Poll::Ready(())
+        }));
+    }
+
+    #[test]
+    fn new_listener_starts_at_keyframe() {
+        let channel = Channel::new("test".into());
+        let transmitter = Transmitter::new(channel.clone());
+
+        transmitter.send(Chunk::Headers { bytes: Bytes::from_static(b"headers") });
+        transmitter.send(empty_cluster(0, true));
+        transmitter.send(empty_cluster(1, false));
+        transmitter.send(empty_cluster(2, true));
+        transmitter.send(empty_cluster(3, false));
+
+        let listener = Listener::new(channel).into_chunks();
+        drop(transmitter);
+        let received: Vec<Chunk> = block_on(listener.take(3).collect());
+        assert_eq!(starts(&received), vec![None, Some(2), Some(3)]);
+    }
+
+    #[test]
+    fn report_listener_lag() {
+        let channel = Channel::new("test".into());
+        let remote = "192.0.2.1:5000".parse().ok();
+        let listener = Listener::with_policy(channel.clone(), 4, LagPolicy::DropToKeyframe).with_remote(remote);
+        let transmitter = Transmitter::new(channel.clone());
+
+        for timecode in 0..5 {
+            let mut head = ClusterHead::new(timecode * 100);
+            head.keyframe = timecode % 2 == 0;
+            head.end = timecode * 100 + 90;
+            transmitter.send(Chunk::Cluster(head, Bytes::from(vec![0; 10])));
+        }
+
+        let stats = channel.lock().unwrap().listener_stats();
+        assert_eq!(stats.len(), 1);
+        let stats = &stats[0];
+        assert_eq!(stats.remote, remote);
+        // skipped ahead to the keyframe at 200 once the queue filled up
+        assert_eq!(stats.skips, 1);
+        assert_eq!(stats.queued_chunks, 3);
+        assert_eq!(stats.queued_ms, 270);
+        assert!(stats.queued_bytes > 30);
+        assert!(stats.lagging);
+        #[cfg(feature = "server")]
+        assert!(stats.to_json().starts_with(r#"{"id":0,"policy":"drop","priority":"best-effort","queued_chunks":3,"queue_limit":4,"#));
+        drop(listener);
+    }
+
+    #[test]
+    fn rewind_from_dvr_buffer() {
+        let channel = Channel::new("test".into());
+        channel.lock().unwrap().set_dvr_window(Some(3000));
+        let transmitter = Transmitter::new(channel.clone());
+
+        transmitter.send(Chunk::Headers { bytes: Bytes::from_static(b"headers") });
+        for second in 0..10 {
+            transmitter.send(empty_cluster(second * 1000, second % 2 == 0));
+        }
+        // kept from the latest keyframe at least 3s back
+        assert_eq!(channel.lock().unwrap().dvr.clusters.len(), 4);
+
+        let rewound = Listener::rewound(channel.clone(), 2, LagPolicy::Disconnect, 2500).into_chunks();
+        let live = Listener::new(channel.clone()).into_chunks();
+        let far_back = Listener::rewound(channel.clone(), 2, LagPolicy::Disconnect, 60_000).into_chunks();
+        drop(transmitter);
+        let received: Vec<Chunk> = block_on(rewound.take(4).collect());
+        assert_eq!(starts(&received), vec![None, Some(6000), Some(7000), Some(8000)]);
+        let received: Vec<Chunk> = block_on(live.take(3).collect());
+        assert_eq!(starts(&received), vec![None, Some(8000), Some(9000)]);
+        let received: Vec<Chunk> = block_on(far_back.take(2).collect());
+        assert_eq!(starts(&received), vec![None, Some(6000)]);
+    }
+
+    #[test]
+    fn trim_dvr_buffer_by_keyframe() {
+        let budget = MemoryBudget::unlimited();
+        let mut dvr = DvrBuffer::default();
+        let starts = |held: Option<Vec<Held>>| -> Vec<Option<u64>> {
+            starts(&held.unwrap().into_iter().map(|held| held.chunk).collect::<Vec<_>>())
+        };
+        for second in 0..6 {
+            dvr.push(Held::new(empty_cluster(second * 1000, second % 2 == 0), &budget));
+            dvr.trim((second * 1000).saturating_sub(2000));
+        }
+        assert_eq!(dvr.clusters.len(), 4);
+        assert_eq!(starts(dvr.from(3500)), vec![Some(2000), Some(3000), Some(4000), Some(5000)]);
+        assert_eq!(starts(dvr.from(4000)), vec![Some(4000), Some(5000)]);
+        assert_eq!(starts(dvr.from(0)), vec![Some(2000), Some(3000), Some(4000), Some(5000)]);
+
+        // positions carry on past a clear
+        dvr.clear();
+        assert!(dvr.from(0).is_none());
+        for second in 6..9 {
+            dvr.push(Held::new(empty_cluster(second * 1000, second != 7), &budget));
+            dvr.trim((second * 1000).saturating_sub(1000));
+        }
+        assert_eq!(starts(dvr.from(8000)), vec![Some(8000)]);
+        assert_eq!(starts(dvr.from(7500)), vec![Some(6000), Some(7000), Some(8000)]);
+    }
+
+    fn sized_cluster(timecode: u64, keyframe: bool, size: usize) -> Chunk {
+        let mut head = ClusterHead::new(timecode);
+        head.keyframe = keyframe;
+        Chunk::Cluster(head, Bytes::from(vec![0; size]))
+    }
+
+    #[test]
+    fn shed_load_under_memory_pressure() {
+        let budget = MemoryBudget::new(1000);
+        let channel = Channel::with_budget("test".into(), budget.clone());
+        let transmitter = Transmitter::new(channel.clone());
+
+        transmitter.send(sized_cluster(0, true, 400));
+        transmitter.send(sized_cluster(1, false, 400));
+        assert!(budget.used() > 800);
+        // past three quarters of the budget, retained clusters are dropped
+        transmitter.send(sized_cluster(2, false, 400));
+        assert_eq!(budget.used(), 0);
+        transmitter.send(sized_cluster(3, true, 1000));
+        // with the budget exhausted, not even a keyframe is retained
+        transmitter.send(sized_cluster(4, true, 100));
+        assert_eq!(budget.used(), 0);
+        drop(transmitter);
+
+        let listener = Listener::with_policy(channel.clone(), 4, LagPolicy::DropToKeyframe).into_chunks();
+        let transmitter = Transmitter::new(channel.clone());
+        for timecode in 0..3 {
+            transmitter.send(sized_cluster(timecode, false, 400));
+        }
+        assert!(budget.used() > 1200);
+        // exhausted with half its queue used, the listener is dropped
+        transmitter.send(sized_cluster(3, false, 400));
+        assert_eq!(budget.used(), 0);
+        let received: Vec<Chunk> = block_on(listener.collect());
+        assert!(received.is_empty());
+    }
+
+    #[test]
+    fn charge_shared_chunks_once() {
+        let budget = MemoryBudget::unlimited();
+        let channel = Channel::with_budget("test".into(), budget.clone());
+        let transmitter = Transmitter::new(channel.clone());
+        let cluster = sized_cluster(0, true, 400);
+        let size = cluster.size();
+
+        let listeners: Vec<Listener> = (0..3).map(|_| Listener::new(channel.clone())).collect();
+        transmitter.send(cluster);
+        // retained for new listeners & queued for three, but only held once
+        assert_eq!(budget.used(), size);
+        for stats in channel.lock().unwrap().listener_stats() {
+            assert_eq!(stats.queued_bytes, size);
+        }
+        drop(listeners);
+        assert_eq!(budget.used(), size);
+        drop(transmitter);
+        assert_eq!(budget.used(), 0);
+    }
+
+    #[test]
+    fn spare_critical_listeners() {
+        let budget = MemoryBudget::new(1000);
+        let channel = Channel::with_budget("test".into(), budget.clone());
+        let viewer = Listener::with_policy(channel.clone(), 2, LagPolicy::Disconnect).into_chunks();
+        let recorder = Listener::with_policy(channel.clone(), 2, LagPolicy::Disconnect)
+            .with_priority(ListenerPriority::Critical);
+        let transmitter = Transmitter::new(channel.clone());
+
+        for timecode in 0..6 {
+            transmitter.send(sized_cluster(timecode, timecode == 0, 400));
+        }
+        let stats = channel.lock().unwrap().listener_stats();
+        assert_eq!(stats.len(), 1);
+        assert_eq!(stats[0].priority, ListenerPriority::Critical);
+        assert_eq!(stats[0].queued_chunks, 6);
+        drop(transmitter);
+
+        // the viewer was dropped for lagging, but the recorder got everything
+        assert!(block_on(viewer.collect::<Vec<Chunk>>()).is_empty());
+        let received: Vec<Chunk> = block_on(recorder.into_chunks().take(6).collect());
+        assert_eq!(starts(&received), (0..6).map(Some).collect::<Vec<_>>());
+        assert_eq!("best-effort".parse::<ListenerPriority>().unwrap(), ListenerPriority::BestEffort);
+    }
+
+    #[test]
+    fn disconnect_stalled_critical_listeners() {
+        let channel = Channel::new("test".into());
+        let recorder = Listener::with_policy(channel.clone(), 2, LagPolicy::Disconnect)
+            .with_priority(ListenerPriority::Critical);
+        let transmitter = Transmitter::new(channel.clone());
+
+        transmitter.send(sized_cluster(0, true, MAX_CRITICAL_QUEUE_BYTES));
+        assert_eq!(channel.lock().unwrap().listener_stats().len(), 1);
+        // nothing's been taken, so the next chunk finds it stalled
+        transmitter.send(sized_cluster(1, false, 1));
+        assert!(channel.lock().unwrap().listener_stats().is_empty());
+        assert!(block_on(recorder.into_chunks().collect::<Vec<Chunk>>()).is_empty());
     }
 }
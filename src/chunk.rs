@@ -1,55 +1,128 @@
-use bytes::{Buf, Bytes, BytesMut};
-use futures::prelude::*;
+use bytes::{Buf, Bytes};
+use futures::{
+    channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
+    prelude::*,
+    task::noop_waker,
+};
 use std::{
+    collections::VecDeque,
     io::Cursor,
     mem,
     pin::Pin,
+    str::FromStr,
     task::{Context, Poll, Poll::*},
 };
-use crate::stream_parser::EbmlStreamingParser;
-use crate::error::WebmetroError;
+use tracing::Span;
+
+use crate::stream_parser::{EbmlStreamingParser, StreamEbml};
+use crate::ebml::encode_integer;
+use crate::error::{Limit, WebmetroError};
 use crate::webm::*;
 
 #[derive(Clone, Debug)]
 pub struct ClusterHead {
     pub keyframe: bool,
+    /// in milliseconds, whatever TimestampScale the Cluster is written at
     pub start: u64,
     pub end: u64,
-    /// a Cluster tag and a Timecode tag together take at most 15 bytes;
-    /// fortuitously, 15 bytes can be inlined in a Bytes handle even on 32-bit systems
-    bytes: BytesMut,
+    /// the Cluster doesn't follow on from the one before it: its timecodes
+    /// jump (e.g. after an encoder stalls, or a source reconnects), so
+    /// formats that can signal that should (see `GapDetector`)
+    pub discontinuity: bool,
+    /// the Cluster's Timecode as written, in ticks of `scale` nanoseconds
+    timecode: u64,
+    scale: u64,
+    /// a Cluster tag and a Timecode tag together take at most 15 bytes.
+    /// Frozen, so every listener a Cluster is fanned out to shares one copy;
+    /// it's only re-encoded when the timecode actually changes.
+    bytes: Bytes,
 }
 
 impl ClusterHead {
     pub fn new(timecode: u64) -> ClusterHead {
+        ClusterHead::with_scale(timecode, DEFAULT_TIMESTAMP_SCALE)
+    }
+    /// A Cluster written at `scale` nanoseconds per tick, starting `timecode`
+    /// ticks in; its `start` & `end` are still in milliseconds
+    pub fn with_scale(timecode: u64, scale: u64) -> ClusterHead {
         let mut cluster_head = ClusterHead {
             keyframe: false,
             start: 0,
             end: 0,
-            bytes: BytesMut::with_capacity(15),
+            discontinuity: false,
+            timecode: 0,
+            scale,
+            bytes: Bytes::new(),
         };
-        cluster_head.update_timecode(timecode);
+        cluster_head.encode_timecode(timecode, cluster_head.milliseconds(timecode));
         cluster_head
     }
+    /// Move the Cluster to start `timecode` milliseconds in
     pub fn update_timecode(&mut self, timecode: u64) {
-        let delta = self.end - self.start;
-        self.start = timecode;
-        self.end = self.start + delta;
+        if timecode != self.start {
+            let ticks = rescale(timecode as i64, DEFAULT_TIMESTAMP_SCALE, self.scale) as u64;
+            self.encode_timecode(ticks, timecode);
+        }
+    }
+    /// Move the Cluster to start `timecode` ticks of its own TimestampScale in
+    fn update_ticks(&mut self, timecode: u64) {
+        if timecode != self.timecode {
+            self.encode_timecode(timecode, self.milliseconds(timecode));
+        }
+    }
+    fn encode_timecode(&mut self, timecode: u64, start: u64) {
+        let delta = self.end.saturating_sub(self.start);
+        self.timecode = timecode;
+        self.start = start;
+        self.end = self.start.saturating_add(delta);
         let mut buffer = [0;15];
         let mut cursor = Cursor::new(buffer.as_mut());
         // buffer is sized so these should never fail
         encode_webm_element(WebmElement::Cluster, &mut cursor).unwrap();
         encode_webm_element(WebmElement::Timecode(timecode), &mut cursor).unwrap();
-        self.bytes.clear();
         let len = cursor.position() as usize;
-        self.bytes.extend_from_slice(&buffer[..len]);
+        self.bytes = Bytes::copy_from_slice(&buffer[..len]);
+    }
+    fn milliseconds(&self, ticks: u64) -> u64 {
+        rescale(ticks as i64, self.scale, DEFAULT_TIMESTAMP_SCALE) as u64
     }
+    /// Note a block `timecode` ticks into the Cluster
     pub fn observe_simpleblock_timecode(&mut self, timecode: i16) {
-        let absolute_timecode = self.start + (timecode as u64);
-        if absolute_timecode > self.start {
-            self.end = absolute_timecode;
+        if timecode > 0 {
+            self.end = self.block_time(timecode);
         }
     }
+
+    /// When a block `timecode` ticks into the Cluster plays, in milliseconds
+    pub fn block_time(&self, timecode: i16) -> u64 {
+        rescale(self.timecode as i64 + timecode as i64, self.scale, DEFAULT_TIMESTAMP_SCALE).max(0) as u64
+    }
+
+    /// The timecode a block playing `time` milliseconds in would have in the
+    /// Cluster, in ticks; it's up to the caller whether it fits a block
+    pub fn block_timecode(&self, time: u64) -> i64 {
+        self.ticks(time as i64) - self.timecode as i64
+    }
+
+    /// A span of `milliseconds` in ticks of the Cluster's TimestampScale
+    pub fn ticks(&self, milliseconds: i64) -> i64 {
+        rescale(milliseconds, DEFAULT_TIMESTAMP_SCALE, self.scale)
+    }
+
+    /// The Cluster's Timecode as written, in ticks of its TimestampScale
+    pub fn timecode(&self) -> u64 {
+        self.timecode
+    }
+
+    /// The TimestampScale the Cluster is written at, in nanoseconds per tick
+    pub fn timestamp_scale(&self) -> u64 {
+        self.scale
+    }
+
+    /// How many bytes the Cluster & Timecode tags take
+    pub fn size(&self) -> usize {
+        self.bytes.len()
+    }
 }
 
 /// A chunk of WebM data
@@ -58,6 +131,10 @@ pub enum Chunk {
     Headers {
         bytes: Bytes
     },
+    /// A whole Cluster: its head, with the timing metadata, and its body,
+    /// the rest of its bytes. They always travel together, so recorders,
+    /// segmenters & the like never see half a Cluster; they're only kept as
+    /// separate `Bytes` so the head can be re-timed without copying the body.
     Cluster(ClusterHead, Bytes),
     // for iteration only
     #[doc(hidden)]
@@ -74,6 +151,15 @@ impl Chunk {
             _ => true,
         }
     }
+
+    /// How many bytes of WebM the chunk holds
+    pub fn size(&self) -> usize {
+        match self {
+            Chunk::Headers { bytes } | Chunk::RemainingBody(bytes) => bytes.len(),
+            Chunk::Cluster(head, body) => head.size() + body.len(),
+            Chunk::Empty => 0,
+        }
+    }
 }
 
 // TODO: make an external iterator type so we can remove Chunk::RemainingBody & Chunk::Empty
@@ -88,10 +174,10 @@ impl Iterator for Chunk {
                 Some(bytes)
             },
             Chunk::Cluster(ClusterHead {bytes, ..}, body) => {
-                let bytes = mem::replace(bytes, BytesMut::new());
+                let bytes = mem::replace(bytes, Bytes::new());
                 let body = mem::replace(body, Bytes::new());
                 *self = Chunk::RemainingBody(body);
-                Some(bytes.freeze())
+                Some(bytes)
             },
             Chunk::RemainingBody(bytes) => {
                 let bytes = mem::replace(bytes, Bytes::new());
@@ -103,6 +189,135 @@ impl Iterator for Chunk {
     }
 }
 
+/// How a chunker decides whether a Cluster starts with a keyframe
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(rename_all = "kebab-case"))]
+pub enum KeyframePolicy {
+    /// any keyframe block marks the Cluster
+    #[default]
+    AnyBlock,
+    /// only a keyframe as the Cluster's first block marks it, so a viewer
+    /// starting there doesn't receive undecodable frames first
+    FirstBlock,
+}
+
+impl FromStr for KeyframePolicy {
+    type Err = WebmetroError;
+
+    fn from_str(policy: &str) -> Result<KeyframePolicy, WebmetroError> {
+        match policy {
+            "any-block" => Ok(KeyframePolicy::AnyBlock),
+            "first-block" => Ok(KeyframePolicy::FirstBlock),
+            _ => Err(WebmetroError::ApplicationError {
+                message: format!("Unknown keyframe policy \"{}\" (expected any-block or first-block)", policy),
+            })
+        }
+    }
+}
+
+/// Configuration for a `WebmChunker`, built up like
+/// `ChunkerOptions::new().soft_limit(limit).max_cluster_duration(2000)`.
+/// With the `serde` feature it can also be deserialized, e.g. from a config file.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(default, rename_all = "kebab-case"))]
+pub struct ChunkerOptions {
+    soft_limit: Option<usize>,
+    max_cluster_size: Option<usize>,
+    max_cluster_duration: Option<u64>,
+    keyframe_policy: KeyframePolicy,
+    timestamp_scale: Option<u64>,
+    stats: bool,
+}
+
+impl ChunkerOptions {
+    pub fn new() -> ChunkerOptions {
+        ChunkerOptions::default()
+    }
+
+    /// add a "soft" buffer size limit; if a chunk buffer exceeds this size,
+    /// error the stream instead of resuming. It's still possible for a buffer
+    /// to exceed this size *after* a write, so ensure input sizes are reasonable.
+    pub fn soft_limit(mut self, limit: usize) -> Self {
+        self.soft_limit = Some(limit);
+        self
+    }
+
+    /// split Clusters whose body grows past this many bytes, continuing in a new Cluster
+    pub fn max_cluster_size(mut self, size: usize) -> Self {
+        self.max_cluster_size = Some(size);
+        self
+    }
+
+    /// split Clusters spanning at least this many milliseconds
+    pub fn max_cluster_duration(mut self, duration: u64) -> Self {
+        self.max_cluster_duration = Some(duration);
+        self
+    }
+
+    pub fn keyframe_policy(mut self, policy: KeyframePolicy) -> Self {
+        self.keyframe_policy = policy;
+        self
+    }
+
+    /// write the output at this TimestampScale (nanoseconds per timecode
+    /// tick, e.g. 1000 for microseconds) instead of the input's, rescaling
+    /// Cluster & block timecodes to match. Blocks that no longer fit their
+    /// Cluster's 16-bit range start a new one. Chunks' `start` & `end` stay
+    /// in milliseconds either way.
+    pub fn timestamp_scale(mut self, nanoseconds: u64) -> Self {
+        self.timestamp_scale = Some(nanoseconds);
+        self
+    }
+
+    /// the TimestampScale output is written at, in nanoseconds per tick
+    fn output_scale(&self) -> Option<u64> {
+        self.timestamp_scale.filter(|&scale| scale > 0)
+    }
+
+    /// keep running totals of the chunks produced, available from `WebmChunker::stats`
+    pub fn stats(mut self, enabled: bool) -> Self {
+        self.stats = enabled;
+        self
+    }
+}
+
+/// Running totals of a chunker's output
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct ChunkerStats {
+    pub headers: u64,
+    pub clusters: u64,
+    pub keyframe_clusters: u64,
+    /// Clusters started because the previous one hit the size or duration limit
+    pub split_clusters: u64,
+    pub bytes: u64,
+    pub largest_cluster: usize,
+}
+
+impl ChunkerStats {
+    fn record(&mut self, chunk: &Chunk) {
+        match chunk {
+            Chunk::Headers { bytes } => {
+                self.headers += 1;
+                self.bytes += bytes.len() as u64;
+            },
+            Chunk::Cluster(head, body) => {
+                let size = head.bytes.len() + body.len();
+                self.clusters += 1;
+                if head.keyframe {
+                    self.keyframe_clusters += 1;
+                }
+                self.bytes += size as u64;
+                self.largest_cluster = self.largest_cluster.max(size);
+            },
+            Chunk::RemainingBody(_) | Chunk::Empty => {}
+        }
+    }
+}
+
+/// The most completed chunks a chunker holds, when its input arrives faster than they're taken
+const BATCH_LIMIT: usize = 16;
+
 #[derive(Debug)]
 enum ChunkerState {
     BuildingHeader(Cursor<Vec<u8>>),
@@ -113,61 +328,161 @@ enum ChunkerState {
 
 pub struct WebmChunker<S> {
     source: EbmlStreamingParser<S>,
-    buffer_size_limit: Option<usize>,
+    options: ChunkerOptions,
+    stats: Option<ChunkerStats>,
+    /// blocks seen in the Cluster being built, for the keyframe policy & splitting
+    cluster_blocks: usize,
+    /// how far the current Cluster's timecode has been moved past the source's,
+    /// after splitting; block timecodes are rebased by this much
+    timecode_offset: i64,
+    /// the input's TimestampScale, from its Info
+    input_scale: u64,
+    /// the input's timecode for the current Cluster, before any rescaling
+    cluster_timecode: u64,
     state: ChunkerState,
+    /// completed chunks not yet taken
+    ready: VecDeque<Result<Chunk, WebmetroError>>,
+    span: Span,
 }
 
 impl<S> WebmChunker<S> {
-    /// add a "soft" buffer size limit; if a chunk buffer exceeds this size,
-    /// error the stream instead of resuming. It's still possible for a buffer
-    /// to exceed this size *after* a write, so ensure input sizes are reasonable.
+    #[deprecated(note = "use ChunkerOptions::soft_limit with WebmStream::chunk_webm_with")]
     pub fn with_soft_limit(mut self, limit: usize) -> Self {
-        self.buffer_size_limit = Some(limit);
+        self.options.soft_limit = Some(limit);
         self
     }
+
+    pub fn options(&self) -> &ChunkerOptions {
+        &self.options
+    }
+
+    /// the totals so far, if enabled with `ChunkerOptions::stats`
+    pub fn stats(&self) -> Option<&ChunkerStats> {
+        self.stats.as_ref()
+    }
 }
 
-fn encode(element: WebmElement, buffer: &mut Cursor<Vec<u8>>, limit: Option<usize>) -> Result<(), WebmetroError> {
-    if let Some(limit) = limit {
-        if limit <= buffer.get_ref().len() {
-            return Err(WebmetroError::ResourcesExceeded);
-        }
+fn check_limit(buffer: &Cursor<Vec<u8>>, limit: Option<usize>) -> Result<(), WebmetroError> {
+    match limit {
+        Some(limit) if limit <= buffer.get_ref().len() => Err(WebmetroError::LimitExceeded { limit: Limit::ChunkBuffer(limit) }),
+        _ => Ok(())
     }
+}
 
+fn encode(element: WebmElement, buffer: &mut Cursor<Vec<u8>>, limit: Option<usize>) -> Result<(), WebmetroError> {
+    check_limit(buffer, limit)?;
     encode_webm_element(element, buffer).map_err(|err| err.into())
 }
 
-impl<I: Buf, E, S: Stream<Item = Result<I, E>> + Unpin> Stream for WebmChunker<S>
+/// Encode a header element; a Segment is followed by an Info giving the
+/// output's TimestampScale, when that's been changed
+fn encode_header(element: WebmElement, buffer: &mut Cursor<Vec<u8>>, options: &ChunkerOptions) -> Result<(), WebmetroError> {
+    let segment = element == WebmElement::Segment;
+    encode(element, buffer, options.soft_limit)?;
+    match options.output_scale() {
+        Some(scale) if segment => encode_info(scale, buffer, options),
+        _ => Ok(())
+    }
+}
+
+/// Encode an Info giving just the TimestampScale
+fn encode_info(scale: u64, buffer: &mut Cursor<Vec<u8>>, options: &ChunkerOptions) -> Result<(), WebmetroError> {
+    let mut info = Vec::new();
+    encode_integer(TIMECODE_SCALE_ID, scale, &mut info)?;
+    encode(WebmElement::Info(&info), buffer, options.soft_limit)
+}
+
+fn add_block(element: WebmElement, timecode: i64, first: bool, options: &ChunkerOptions, cluster_head: &mut ClusterHead, buffer: &mut Cursor<Vec<u8>>) -> Result<(), WebmetroError> {
+    // a block this far before its Cluster was already unrepresentable
+    let timecode = timecode.max(i16::min_value() as i64).min(i16::max_value() as i64) as i16;
+    let keyframe = matches!(block_timing(&element), Some((_, true)));
+    if keyframe && (first || options.keyframe_policy == KeyframePolicy::AnyBlock) {
+        // TODO: this is incorrect, condition needs to also affirm this is a video block
+        cluster_head.keyframe = true;
+    }
+    cluster_head.observe_simpleblock_timecode(timecode);
+    check_limit(buffer, options.soft_limit)?;
+    encode_block(element, timecode, buffer).map_err(|err| err.into())
+}
+
+fn emit(stats: &mut Option<ChunkerStats>, chunk: Chunk) -> Poll<Option<Result<Chunk, WebmetroError>>> {
+    match chunk {
+        Chunk::Headers { ref bytes } => debug!(size = bytes.len(), "headers"),
+        Chunk::Cluster(ref head, ref body) => debug!(
+            start = head.start,
+            end = head.end,
+            keyframe = head.keyframe,
+            size = head.bytes.len() + body.len(),
+            "cluster"
+        ),
+        _ => {}
+    }
+    if let Some(stats) = stats {
+        stats.record(&chunk);
+    }
+    Ready(Some(Ok(chunk)))
+}
+
+impl<I: Buf, E, S: Stream<Item = Result<I, E>> + Unpin> WebmChunker<S>
 where
     WebmetroError: From<E>,
 {
-    type Item = Result<Chunk, WebmetroError>;
-
-    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Chunk, WebmetroError>>> {
-        let mut chunker = self.get_mut();
+    fn poll_chunk(&mut self, cx: &mut Context) -> Poll<Option<Result<Chunk, WebmetroError>>> {
+        let chunker = self;
+        let span = chunker.span.clone();
+        let _enter = span.enter();
         loop {
             match chunker.state {
                 ChunkerState::BuildingHeader(ref mut buffer) => {
                     match chunker.source.poll_event(cx) {
-                        Ready(Some(Err(passthru))) => return Ready(Some(Err(passthru))),
+                        Ready(Some(Err(passthru))) => {
+                            // the source may not be fused, so end here rather than poll it again
+                            chunker.state = ChunkerState::End;
+                            return Ready(Some(Err(passthru)));
+                        },
                         Pending => return Pending,
-                        Ready(None) => return Ready(None),
+                        Ready(None) => {
+                            // a stream can end before its first Cluster; its headers are still its headers
+                            let liberated_buffer = mem::replace(buffer, Cursor::new(Vec::new()));
+                            chunker.state = ChunkerState::End;
+                            if liberated_buffer.get_ref().is_empty() {
+                                return Ready(None);
+                            }
+                            return emit(&mut chunker.stats, Chunk::Headers {bytes: Bytes::from(liberated_buffer.into_inner())});
+                        },
                         Ready(Some(Ok(element))) => match element {
                             WebmElement::Cluster => {
                                 let liberated_buffer = mem::replace(buffer, Cursor::new(Vec::new()));
                                 let header_chunk = Chunk::Headers {bytes: Bytes::from(liberated_buffer.into_inner())};
 
+                                let scale = chunker.options.output_scale().unwrap_or(chunker.input_scale);
                                 chunker.state = ChunkerState::BuildingCluster(
-                                    ClusterHead::new(0),
+                                    ClusterHead::with_scale(0, scale),
                                     Cursor::new(Vec::new())
                                 );
-                                return Ready(Some(Ok(header_chunk)));
+                                chunker.cluster_blocks = 0;
+                                chunker.timecode_offset = 0;
+                                chunker.cluster_timecode = 0;
+                                return emit(&mut chunker.stats, header_chunk);
+                            },
+                            WebmElement::Info(info) => {
+                                chunker.input_scale = info_timestamp_scale(info);
+                                // left as they are, the Clusters stay in the input's ticks, so the headers have to say so
+                                if chunker.options.output_scale().is_none() && chunker.input_scale != DEFAULT_TIMESTAMP_SCALE {
+                                    if let Err(err) = encode_info(chunker.input_scale, buffer, &chunker.options) {
+                                        chunker.state = ChunkerState::End;
+                                        return Ready(Some(Err(err)));
+                                    }
+                                }
                             },
-                            WebmElement::Info => {},
                             WebmElement::Void => {},
                             WebmElement::Unknown(_) => {},
+                            WebmElement::Skipped {..} => {},
                             element => {
-                                if let Err(err) = encode(element, buffer, chunker.buffer_size_limit) {
+                                if matches!(element, WebmElement::EbmlHead | WebmElement::Segment) {
+                                    chunker.input_scale = DEFAULT_TIMESTAMP_SCALE;
+                                }
+                                if let Err(err) = encode_header(element, buffer, &chunker.options) {
                                     chunker.state = ChunkerState::End;
                                     return Ready(Some(Err(err)));
                                 }
@@ -177,18 +492,23 @@ where
                 },
                 ChunkerState::BuildingCluster(ref mut cluster_head, ref mut buffer) => {
                     match chunker.source.poll_event(cx) {
-                        Ready(Some(Err(passthru))) => return Ready(Some(Err(passthru))),
+                        Ready(Some(Err(passthru))) => {
+                            // the source may not be fused, so end here rather than poll it again
+                            chunker.state = ChunkerState::End;
+                            return Ready(Some(Err(passthru)));
+                        },
                         Pending => return Pending,
                         Ready(Some(Ok(element))) => match element {
                             WebmElement::EbmlHead | WebmElement::Segment => {
                                 let liberated_cluster_head = mem::replace(cluster_head, ClusterHead::new(0));
                                 let liberated_buffer = mem::replace(buffer, Cursor::new(Vec::new()));
 
+                                chunker.input_scale = DEFAULT_TIMESTAMP_SCALE;
                                 let mut new_header_cursor = Cursor::new(Vec::new());
-                                match encode(element, &mut new_header_cursor, chunker.buffer_size_limit) {
+                                match encode_header(element, &mut new_header_cursor, &chunker.options) {
                                     Ok(_) => {
                                         chunker.state = ChunkerState::BuildingHeader(new_header_cursor);
-                                        return Ready(Some(Ok(Chunk::Cluster(liberated_cluster_head, Bytes::from(liberated_buffer.into_inner())))));
+                                        return emit(&mut chunker.stats, Chunk::Cluster(liberated_cluster_head, Bytes::from(liberated_buffer.into_inner())));
                                     },
                                     Err(err) => {
                                         chunker.state = ChunkerState::End;
@@ -197,30 +517,84 @@ where
                                 }
                             },
                             WebmElement::Cluster => {
-                                let liberated_cluster_head = mem::replace(cluster_head, ClusterHead::new(0));
+                                let new_cluster_head = ClusterHead::with_scale(0, cluster_head.scale);
+                                let liberated_cluster_head = mem::replace(cluster_head, new_cluster_head);
                                 let liberated_buffer = mem::replace(buffer, Cursor::new(Vec::new()));
+                                chunker.cluster_blocks = 0;
+                                chunker.timecode_offset = 0;
+                                chunker.cluster_timecode = 0;
 
-                                return Ready(Some(Ok(Chunk::Cluster(liberated_cluster_head, Bytes::from(liberated_buffer.into_inner())))));
+                                return emit(&mut chunker.stats, Chunk::Cluster(liberated_cluster_head, Bytes::from(liberated_buffer.into_inner())));
                             },
                             WebmElement::Timecode(timecode) => {
-                                cluster_head.update_timecode(timecode);
+                                chunker.cluster_timecode = timecode;
+                                let timecode = match chunker.options.output_scale() {
+                                    Some(scale) => rescale(timecode as i64, chunker.input_scale, scale).max(0) as u64,
+                                    None => timecode,
+                                };
+                                cluster_head.update_ticks(timecode);
                             },
-                            WebmElement::SimpleBlock(ref block) => {
-                                if (block.flags & 0b10000000) != 0 {
-                                    // TODO: this is incorrect, condition needs to also affirm we're the first video block of the cluster
-                                    cluster_head.keyframe = true;
+                            mut block @ WebmElement::SimpleBlock(_) | mut block @ WebmElement::BlockGroup(_) => {
+                                let options = &chunker.options;
+                                let block_timecode = block_timing(&block).map_or(0, |(block, _)| block.timecode);
+                                let scaled = match options.output_scale() {
+                                    Some(scale) => {
+                                        if let WebmElement::BlockGroup(ref mut group) = block {
+                                            group.rescale(chunker.input_scale, scale);
+                                        }
+                                        let cluster_timecode = chunker.cluster_timecode as i64;
+                                        rescale(cluster_timecode + block_timecode as i64, chunker.input_scale, scale)
+                                            - rescale(cluster_timecode, chunker.input_scale, scale)
+                                    },
+                                    None => block_timecode as i64,
+                                };
+                                let mut relative = scaled - chunker.timecode_offset;
+                                let max_duration = options.max_cluster_duration
+                                    .map(|duration| rescale(duration as i64, DEFAULT_TIMESTAMP_SCALE, cluster_head.scale));
+                                if chunker.cluster_blocks == 0 && relative > i16::max_value() as i64 {
+                                    // out of reach of the Cluster's timecode, so move that up to the block
+                                    chunker.timecode_offset += relative;
+                                    cluster_head.update_ticks(cluster_head.timecode + relative as u64);
+                                    relative = 0;
+                                }
+                                let split = chunker.cluster_blocks > 0 && (
+                                    options.max_cluster_size.map_or(false, |size| buffer.get_ref().len() >= size)
+                                    || matches!(max_duration, Some(duration) if relative >= duration)
+                                    || relative > i16::max_value() as i64
+                                );
+
+                                if split {
+                                    // continue in a new Cluster starting at this block
+                                    let split_at = relative.max(0);
+                                    chunker.timecode_offset += split_at;
+                                    let mut new_cluster_head = ClusterHead::with_scale(cluster_head.timecode + split_at as u64, cluster_head.scale);
+                                    let mut new_buffer = Cursor::new(Vec::new());
+                                    if let Err(err) = add_block(block, relative - split_at, true, options, &mut new_cluster_head, &mut new_buffer) {
+                                        chunker.state = ChunkerState::End;
+                                        return Ready(Some(Err(err)));
+                                    }
+                                    chunker.cluster_blocks = 1;
+
+                                    let liberated_cluster_head = mem::replace(cluster_head, new_cluster_head);
+                                    let liberated_buffer = mem::replace(buffer, new_buffer);
+                                    if let Some(ref mut stats) = chunker.stats {
+                                        stats.split_clusters += 1;
+                                    }
+                                    return emit(&mut chunker.stats, Chunk::Cluster(liberated_cluster_head, Bytes::from(liberated_buffer.into_inner())));
                                 }
-                                cluster_head.observe_simpleblock_timecode(block.timecode);
-                                if let Err(err) = encode(WebmElement::SimpleBlock(*block), buffer, chunker.buffer_size_limit) {
+
+                                if let Err(err) = add_block(block, relative, chunker.cluster_blocks == 0, options, cluster_head, buffer) {
                                     chunker.state = ChunkerState::End;
                                     return Ready(Some(Err(err)));
                                 }
+                                chunker.cluster_blocks += 1;
                             },
-                            WebmElement::Info => {},
+                            WebmElement::Info(_) => {},
                             WebmElement::Void => {},
                             WebmElement::Unknown(_) => {},
+                            WebmElement::Skipped {..} => {},
                             element => {
-                                if let Err(err) = encode(element, buffer, chunker.buffer_size_limit) {
+                                if let Err(err) = encode(element, buffer, chunker.options.soft_limit) {
                                     chunker.state = ChunkerState::End;
                                     return Ready(Some(Err(err)));
                                 }
@@ -232,7 +606,7 @@ where
                             let liberated_buffer = mem::replace(buffer, Cursor::new(Vec::new()));
 
                             chunker.state = ChunkerState::End;
-                            return Ready(Some(Ok(Chunk::Cluster(liberated_cluster_head, Bytes::from(liberated_buffer.into_inner())))));
+                            return emit(&mut chunker.stats, Chunk::Cluster(liberated_cluster_head, Bytes::from(liberated_buffer.into_inner())));
                         }
                     }
                 },
@@ -242,29 +616,518 @@ where
     }
 }
 
+impl<I: Buf, E, S: Stream<Item = Result<I, E>> + Unpin> Stream for WebmChunker<S>
+where
+    WebmetroError: From<E>,
+{
+    type Item = Result<Chunk, WebmetroError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Chunk, WebmetroError>>> {
+        let chunker = self.get_mut();
+        // work through all the input that's already available, so a consumer
+        // can take several chunks for one wakeup instead of a wakeup apiece
+        let mut exhausted = false;
+        while chunker.ready.len() < BATCH_LIMIT {
+            match chunker.poll_chunk(cx) {
+                Ready(Some(Ok(chunk))) => chunker.ready.push_back(Ok(chunk)),
+                Ready(Some(Err(err))) => {
+                    chunker.ready.push_back(Err(err));
+                    break;
+                },
+                Ready(None) => {
+                    exhausted = true;
+                    break;
+                },
+                Pending => break,
+            }
+        }
+
+        match chunker.ready.pop_front() {
+            Some(result) => Ready(Some(result)),
+            None if exhausted => Ready(None),
+            None => Pending,
+        }
+    }
+}
+
 pub trait WebmStream {
     type Stream;
     fn chunk_webm(self) -> WebmChunker<Self::Stream>;
+    fn chunk_webm_with(self, options: ChunkerOptions) -> WebmChunker<Self::Stream>;
 }
 
 impl<S: Stream> WebmStream for EbmlStreamingParser<S> {
     type Stream = S;
     fn chunk_webm(self) -> WebmChunker<S> {
+        self.chunk_webm_with(ChunkerOptions::default())
+    }
+
+    fn chunk_webm_with(self, options: ChunkerOptions) -> WebmChunker<S> {
         WebmChunker {
             source: self,
-            buffer_size_limit: None,
-            state: ChunkerState::BuildingHeader(Cursor::new(Vec::new()))
+            stats: if options.stats { Some(ChunkerStats::default()) } else { None },
+            options,
+            cluster_blocks: 0,
+            timecode_offset: 0,
+            input_scale: DEFAULT_TIMESTAMP_SCALE,
+            cluster_timecode: 0,
+            state: ChunkerState::BuildingHeader(Cursor::new(Vec::new())),
+            ready: VecDeque::new(),
+            span: debug_span!("chunk"),
+        }
+    }
+}
+
+type PushInput = UnboundedReceiver<Result<Bytes, WebmetroError>>;
+
+/// A chunker for input that's handed over as it arrives instead of pulled from
+/// a Stream, for callers without an async runtime (like the C & WebAssembly
+/// interfaces): `push` some bytes, then take any completed chunks with `next_chunk`.
+pub struct PushChunker {
+    sender: UnboundedSender<Result<Bytes, WebmetroError>>,
+    chunker: WebmChunker<PushInput>,
+    failed: bool,
+}
+
+impl PushChunker {
+    /// The options' soft limit also applies to the parser
+    pub fn new(options: ChunkerOptions) -> PushChunker {
+        let (sender, receiver) = unbounded();
+        let parser = match options.soft_limit {
+            Some(limit) => receiver.parse_ebml().with_soft_limit(limit),
+            None => receiver.parse_ebml(),
+        };
+        PushChunker {
+            sender,
+            chunker: parser.chunk_webm_with(options),
+            failed: false,
+        }
+    }
+
+    pub fn push(&mut self, input: Bytes) {
+        // only fails after finish(), when more input has nowhere to go anyway
+        self.sender.unbounded_send(Ok(input)).ok();
+    }
+
+    /// Signal the end of input, so the final Cluster can be completed
+    pub fn finish(&mut self) {
+        self.sender.close_channel();
+    }
+
+    /// A chunk completed by the input so far, if there is one; after an
+    /// error, there won't be any more
+    pub fn next_chunk(&mut self) -> Option<Result<Chunk, WebmetroError>> {
+        if self.failed {
+            return None;
+        }
+        let waker = noop_waker();
+        let mut cx = Context::from_waker(&waker);
+        // input is pushed synchronously, so Pending just means more is needed
+        match self.chunker.poll_next_unpin(&mut cx) {
+            Ready(Some(Err(err))) => {
+                self.failed = true;
+                Some(Err(err))
+            },
+            Ready(next) => next,
+            Pending => None,
+        }
+    }
+
+    pub fn stats(&self) -> Option<&ChunkerStats> {
+        self.chunker.stats()
+    }
+}
+
+#[cfg(feature = "serde")]
+mod serialize {
+    use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+    use crate::chunk::{Chunk, ClusterHead};
+
+    impl Serialize for ClusterHead {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut head = serializer.serialize_struct("ClusterHead", 3)?;
+            head.serialize_field("keyframe", &self.keyframe)?;
+            head.serialize_field("start", &self.start)?;
+            head.serialize_field("end", &self.end)?;
+            head.end()
+        }
+    }
+
+    /// Chunks serialize as a summary tagged with a "chunk" field, giving sizes instead of contents
+    impl Serialize for Chunk {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let fields = match self {
+                Chunk::Headers {..} | Chunk::RemainingBody(_) => 2,
+                Chunk::Cluster(..) => 5,
+                Chunk::Empty => 1,
+            };
+            let mut chunk = serializer.serialize_struct("Chunk", fields)?;
+            match self {
+                Chunk::Headers { bytes } => {
+                    chunk.serialize_field("chunk", "Headers")?;
+                    chunk.serialize_field("size", &bytes.len())?;
+                },
+                Chunk::Cluster(head, body) => {
+                    chunk.serialize_field("chunk", "Cluster")?;
+                    chunk.serialize_field("keyframe", &head.keyframe)?;
+                    chunk.serialize_field("start", &head.start)?;
+                    chunk.serialize_field("end", &head.end)?;
+                    chunk.serialize_field("size", &(head.bytes.len() + body.len()))?;
+                },
+                Chunk::RemainingBody(body) => {
+                    chunk.serialize_field("chunk", "RemainingBody")?;
+                    chunk.serialize_field("size", &body.len())?;
+                },
+                Chunk::Empty => chunk.serialize_field("chunk", "Empty")?,
+            }
+            chunk.end()
         }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use futures::{executor::block_on, stream::iter};
+    use matches::assert_matches;
+    use proptest::{collection::vec, prelude::*};
+    use std::io::Cursor;
 
     use crate::chunk::*;
+    use crate::ebml::encode_bytes;
+    use crate::stream_parser::StreamEbml;
 
     #[test]
     fn enough_space_for_header() {
         ClusterHead::new(u64::max_value());
     }
+
+    /// one Cluster at 1000ms holding a block every 100ms, flagged keyframes at the given indices
+    fn single_cluster(blocks: i16, keyframes: &[i16]) -> Vec<u8> {
+        let mut output = Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::EbmlHead, &mut output).unwrap();
+        encode_webm_element(WebmElement::Segment, &mut output).unwrap();
+        encode_webm_element(WebmElement::Cluster, &mut output).unwrap();
+        encode_webm_element(WebmElement::Timecode(1000), &mut output).unwrap();
+        for index in 0..blocks {
+            encode_webm_element(WebmElement::SimpleBlock(SimpleBlock {
+                track: 1,
+                timecode: index * 100,
+                flags: if keyframes.contains(&index) { 0b10000000 } else { 0 },
+                data: &[0; 16],
+            }), &mut output).unwrap();
+        }
+        output.into_inner()
+    }
+
+    fn chunk(input: Vec<u8>, options: ChunkerOptions) -> (Vec<Chunk>, Option<ChunkerStats>) {
+        let mut chunker = iter(vec![Result::<_, WebmetroError>::Ok(Bytes::from(input))])
+            .parse_ebml()
+            .chunk_webm_with(options);
+        let chunks = block_on((&mut chunker).try_collect()).unwrap();
+        (chunks, chunker.stats().cloned())
+    }
+
+    #[test]
+    fn split_long_clusters() {
+        let options = ChunkerOptions::new().max_cluster_duration(300).stats(true);
+        let (chunks, stats) = chunk(single_cluster(10, &[0]), options);
+
+        let heads: Vec<(u64, u64, bool)> = chunks.iter().filter_map(|chunk| match chunk {
+            Chunk::Cluster(head, _) => Some((head.start, head.end, head.keyframe)),
+            _ => None
+        }).collect();
+        assert_eq!(heads, vec![
+            (1000, 1200, true),
+            (1300, 1500, false),
+            (1600, 1800, false),
+            (1900, 1900, false),
+        ]);
+
+        let stats = stats.unwrap();
+        assert_eq!(stats.headers, 1);
+        assert_eq!(stats.clusters, 4);
+        assert_eq!(stats.keyframe_clusters, 1);
+        assert_eq!(stats.split_clusters, 3);
+
+        // the pieces still parse, with block timecodes rebased onto the new Clusters
+        let bytes: Vec<u8> = chunks.into_iter().flatten().flatten().collect();
+        let mut parser = iter(vec![Result::<_, WebmetroError>::Ok(Bytes::from(bytes))]).parse_ebml();
+        let mut timecodes = Vec::new();
+        while let Some(element) = block_on(parser.next()).unwrap() {
+            match element {
+                WebmElement::Timecode(timecode) => timecodes.push(timecode as i64),
+                WebmElement::SimpleBlock(block) => timecodes.push(block.timecode as i64),
+                _ => {}
+            }
+        }
+        assert_eq!(timecodes, vec![1000, 0, 100, 200, 1300, 0, 100, 200, 1600, 0, 100, 200, 1900, 0]);
+    }
+
+    #[test]
+    fn rescale_timestamps() {
+        let heads = |chunks: &[Chunk]| chunks.iter().filter_map(|chunk| match chunk {
+            Chunk::Cluster(head, _) => Some((head.start, head.end)),
+            _ => None
+        }).collect::<Vec<_>>();
+
+        let (chunks, _) = chunk(single_cluster(10, &[0]), ChunkerOptions::new().timestamp_scale(100_000));
+        match &chunks[0] {
+            Chunk::Headers { bytes } => assert_eq!(timestamp_scale(bytes), 100_000),
+            _ => panic!("no Headers")
+        }
+        // the heads stay in milliseconds, while what's written is in ticks
+        assert_eq!(heads(&chunks), vec![(1000, 1900)]);
+        match &chunks[1] {
+            Chunk::Cluster(head, _) => assert_eq!(head.timecode, 10_000),
+            _ => panic!("no Cluster")
+        }
+        // as is the longest a Cluster can be
+        let options = ChunkerOptions::new().timestamp_scale(100_000).max_cluster_duration(300);
+        let (chunks, _) = chunk(single_cluster(10, &[0]), options);
+        assert_eq!(heads(&chunks), vec![(1000, 1200), (1300, 1500), (1600, 1800), (1900, 1900)]);
+
+        // 100ms apart is too far for a block's 16 bits of microseconds, so each gets its own Cluster
+        let (chunks, _) = chunk(single_cluster(10, &[0]), ChunkerOptions::new().timestamp_scale(1000));
+        let expected: Vec<(u64, u64)> = (0..10).map(|index| (1000 + index * 100, 1000 + index * 100)).collect();
+        assert_eq!(heads(&chunks), expected);
+
+        // and back again, going by the input's Info
+        let bytes: Vec<u8> = chunks.into_iter().flatten().flatten().collect();
+        let (chunks, _) = chunk(bytes, ChunkerOptions::new().timestamp_scale(DEFAULT_TIMESTAMP_SCALE));
+        let expected: Vec<(u64, u64)> = (0..10).map(|index| (1000 + index * 100, 1000 + index * 100)).collect();
+        assert_eq!(heads(&chunks), expected);
+    }
+
+    #[test]
+    fn keep_input_timestamp_scale() {
+        let (chunks, _) = chunk(single_cluster(10, &[0]), ChunkerOptions::new().timestamp_scale(100_000));
+        let bytes: Vec<u8> = chunks.into_iter().flatten().flatten().collect();
+
+        // without a scale of its own, the output keeps the input's
+        let (chunks, _) = chunk(bytes, ChunkerOptions::new());
+        match &chunks[0] {
+            Chunk::Headers { bytes } => assert_eq!(timestamp_scale(bytes), 100_000),
+            _ => panic!("no Headers")
+        }
+        match &chunks[1] {
+            Chunk::Cluster(head, _) => {
+                assert_eq!((head.start, head.end), (1000, 1900));
+                assert_eq!((head.timecode(), head.timestamp_scale()), (10_000, 100_000));
+            },
+            _ => panic!("no Cluster")
+        }
+    }
+
+    #[test]
+    fn keep_block_groups() {
+        let mut input = single_cluster(3, &[0]);
+        let mut payload = Vec::new();
+        encode_bytes(BLOCK_ID, &[0x81, 0x01, 0x2C, 0x00, 0xAA], &mut payload).unwrap();
+        encode_integer(REFERENCE_BLOCK_ID, 100, &mut payload).unwrap();
+        encode_bytes(BLOCK_ADDITIONS_ID, &[0xA6, 0x83, 0xA5, 0x81, 0xBB], &mut payload).unwrap();
+        encode_bytes(BLOCK_GROUP_ID, &payload, &mut input).unwrap();
+
+        // the BlockGroup, at 300ms, starts a new Cluster, & is rebased onto it
+        let (chunks, _) = chunk(input, ChunkerOptions::new().max_cluster_duration(300));
+        match chunks.last() {
+            Some(Chunk::Cluster(head, body)) => {
+                assert_eq!((head.start, head.keyframe), (1300, false));
+                match parse_webm(body).next() {
+                    Some(WebmElement::BlockGroup(group)) => {
+                        assert_eq!(group.block.timecode, 0);
+                        assert_eq!(group.additions(), vec![BlockAddition { id: 1, data: &[0xBB] }]);
+                    },
+                    other => panic!("not a BlockGroup: {:?}", other)
+                }
+            },
+            _ => panic!("no Cluster")
+        }
+    }
+
+    #[test]
+    fn split_large_clusters() {
+        let (chunks, _) = chunk(single_cluster(4, &[0]), ChunkerOptions::new().max_cluster_size(40));
+        let clusters = chunks.iter().filter(|chunk| match chunk {
+            Chunk::Cluster(..) => true,
+            _ => false
+        }).count();
+        assert_eq!(clusters, 2);
+    }
+
+    #[test]
+    fn drain_ready_input() {
+        use std::cell::Cell;
+
+        let source_polls = Cell::new(0);
+        let pieces: Vec<Result<Bytes, WebmetroError>> = crate::tests::ENCODE_WEBM_TEST_FILE.chunks(7)
+            .map(|piece| Ok(Bytes::copy_from_slice(piece)))
+            .collect();
+        let piece_count = pieces.len();
+        let source = iter(pieces).inspect(|_| source_polls.set(source_polls.get() + 1));
+        let mut chunker = source.parse_ebml().chunk_webm();
+
+        let first = block_on(chunker.next());
+        assert_matches!(first, Some(Ok(Chunk::Headers {..})));
+        // the whole input was already available, so it was all processed in one go
+        assert_eq!(source_polls.get(), piece_count);
+
+        let rest: Vec<_> = block_on((&mut chunker).collect());
+        assert_eq!(rest.len(), 2);
+        assert_eq!(source_polls.get(), piece_count);
+    }
+
+    #[test]
+    fn push_input() {
+        let mut chunker = PushChunker::new(ChunkerOptions::new());
+        let mut chunks = Vec::new();
+        for piece in crate::tests::ENCODE_WEBM_TEST_FILE.chunks(7) {
+            chunker.push(Bytes::copy_from_slice(piece));
+            while let Some(chunk) = chunker.next_chunk() {
+                chunks.push(chunk.unwrap());
+            }
+        }
+        // the last Cluster isn't known to be complete until the input ends
+        assert_eq!(chunks.len(), 2);
+        chunker.finish();
+        assert!(chunker.next_chunk().is_some());
+        assert!(chunker.next_chunk().is_none());
+    }
+
+    #[test]
+    fn keyframe_policies() {
+        let keyframe = |options| match chunk(single_cluster(3, &[1]), options).0.last() {
+            Some(Chunk::Cluster(head, _)) => head.keyframe,
+            _ => panic!("no Cluster")
+        };
+        assert!(keyframe(ChunkerOptions::new()));
+        assert!(!keyframe(ChunkerOptions::new().keyframe_policy(KeyframePolicy::FirstBlock)));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn deserialize_options() {
+        let options: ChunkerOptions = serde_json::from_str(r#"{"soft-limit":1024,"keyframe-policy":"first-block"}"#).unwrap();
+        assert_eq!(options, ChunkerOptions::new().soft_limit(1024).keyframe_policy(KeyframePolicy::FirstBlock));
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize_chunks() {
+        let mut head = ClusterHead::new(1000);
+        head.keyframe = true;
+        let json = serde_json::to_string(&Chunk::Cluster(head, Bytes::from_static(b"body"))).unwrap();
+        assert_eq!(json, r#"{"chunk":"Cluster","keyframe":true,"start":1000,"end":1000,"size":19}"#);
+    }
+
+    #[test]
+    fn survive_negative_block_timecodes() {
+        let mut input = single_cluster(1, &[0]);
+        let mut output = Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::SimpleBlock(SimpleBlock {
+            track: 1,
+            timecode: i16::min_value(),
+            flags: 0,
+            data: &[0; 16],
+        }), &mut output).unwrap();
+        input.extend(output.into_inner());
+        let (chunks, _) = chunk(input, ChunkerOptions::new().max_cluster_duration(300));
+        match chunks.last() {
+            Some(Chunk::Cluster(head, _)) => assert_eq!((head.start, head.end), (1000, 1000)),
+            _ => panic!("no Cluster")
+        }
+    }
+
+    #[test]
+    fn stop_after_errors() {
+        // a corrupt varint the parser would keep reporting if polled again
+        let input = Bytes::from_static(&[0, 0, 0, 0]);
+        let results: Vec<_> = block_on(iter(vec![Result::<_, WebmetroError>::Ok(input)])
+            .parse_ebml()
+            .chunk_webm()
+            .collect());
+        assert_eq!(results.len(), 1);
+        assert!(results[0].is_err());
+    }
+
+    /// (track, absolute timecode, flags, data) for each block in a stream
+    type Blocks = Vec<(u64, i64, u8, Vec<u8>)>;
+
+    /// what a stream means, regardless of how it's split into Clusters:
+    /// its Tracks payloads & its blocks
+    fn semantics(stream: &[u8]) -> (Vec<Vec<u8>>, Blocks) {
+        let mut tracks = Vec::new();
+        let mut blocks = Vec::new();
+        let mut cluster_timecode = 0;
+        for element in parse_webm(stream) {
+            match element {
+                WebmElement::Tracks(data) => tracks.push(data.to_vec()),
+                WebmElement::Timecode(timecode) => cluster_timecode = timecode as i64,
+                WebmElement::SimpleBlock(block) => blocks.push((
+                    block.track,
+                    cluster_timecode + block.timecode as i64,
+                    block.flags,
+                    block.data.to_vec(),
+                )),
+                _ => {}
+            }
+        }
+        (tracks, blocks)
+    }
+
+    proptest! {
+        #[test]
+        fn rechunk_same_stream(
+            tracks in vec(any::<u8>(), 0..64),
+            clusters in vec((
+                0..1u64 << 40,
+                vec((1..200u64, 0..=i16::max_value(), any::<u8>(), vec(any::<u8>(), 0..40)), 0..12),
+            ), 0..6),
+            max_duration in proptest::option::of(1..2000u64),
+            max_size in proptest::option::of(1..500usize),
+            piece_size in 1..64usize,
+        ) {
+            let mut input = Cursor::new(Vec::new());
+            encode_webm_element(WebmElement::EbmlHead, &mut input).unwrap();
+            encode_webm_element(WebmElement::Segment, &mut input).unwrap();
+            encode_webm_element(WebmElement::Tracks(&tracks), &mut input).unwrap();
+            for (timecode, blocks) in &clusters {
+                encode_webm_element(WebmElement::Cluster, &mut input).unwrap();
+                encode_webm_element(WebmElement::Timecode(*timecode), &mut input).unwrap();
+                for (track, timecode, flags, data) in blocks {
+                    encode_webm_element(WebmElement::SimpleBlock(SimpleBlock {
+                        track: *track,
+                        timecode: *timecode,
+                        flags: *flags,
+                        data,
+                    }), &mut input).unwrap();
+                }
+            }
+            let input = input.into_inner();
+
+            let mut options = ChunkerOptions::new();
+            if let Some(duration) = max_duration {
+                options = options.max_cluster_duration(duration);
+            }
+            if let Some(size) = max_size {
+                options = options.max_cluster_size(size);
+            }
+            let pieces: Vec<Result<Bytes, WebmetroError>> = input.chunks(piece_size)
+                .map(|piece| Ok(Bytes::copy_from_slice(piece)))
+                .collect();
+            let chunks: Vec<Chunk> = block_on(iter(pieces).parse_ebml().chunk_webm_with(options).try_collect()).unwrap();
+
+            for chunk in &chunks {
+                if let Chunk::Cluster(head, _) = chunk {
+                    let timecode = parse_webm(&head.bytes[..]).find_map(|element| match element {
+                        WebmElement::Timecode(timecode) => Some(timecode),
+                        _ => None
+                    });
+                    prop_assert_eq!(timecode, Some(head.start));
+                }
+            }
+            let output: Vec<u8> = chunks.into_iter().flatten().flatten().collect();
+            prop_assert_eq!(semantics(&output), semantics(&input));
+        }
+    }
 }
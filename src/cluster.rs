@@ -0,0 +1,624 @@
+//! Shares channels between relay instances over Redis pub/sub, so listeners
+//! behind a load balancer can be served by any instance, whichever one the
+//! source is publishing to.
+//!
+//! Each instance forwards its own sources' chunks to the Redis channel
+//! `webmetro:media:<channel>`, and announces them starting & stopping on
+//! `webmetro:events`. Every other instance publishes what it hears to its own
+//! Relay as if it came from a local source, so listeners, HLS, and events
+//! work the same everywhere. Headers are forwarded again before each keyframe,
+//! so an instance that joins mid-stream can start at the next one.
+//!
+//! Only the handful of Redis commands this needs are spoken, over plain TCP.
+
+use std::collections::{HashMap, HashSet};
+use std::hash::{BuildHasher, Hasher};
+use std::collections::hash_map::RandomState;
+use std::process;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{
+    channel::mpsc,
+    future::join,
+    prelude::*,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    time::{delay_for, timeout},
+};
+use tracing_futures::Instrument;
+
+use crate::chunk::Chunk;
+use crate::error::WebmetroError;
+use crate::events::Event;
+use crate::server::Relay;
+
+/// Where instances announce sources starting & stopping
+const EVENTS_TOPIC: &str = "webmetro:events";
+
+/// Prefix of the topics instances forward each channel's chunks to
+const MEDIA_TOPIC: &str = "webmetro:media:";
+
+/// How long to wait before reconnecting to Redis after losing it
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// How long a channel fed by another instance may go without chunks before
+/// its source is taken to be gone (e.g. that instance crashed)
+const FEED_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How many chunks may wait to be forwarded, or to be published locally,
+/// before more are dropped
+const FORWARD_QUEUE_LIMIT: usize = 64;
+
+/// The longest reply (or run of replies waiting to be read) taken from
+/// Redis; a bulk string claiming to be longer is a protocol error
+const MAX_REPLY_SIZE: usize = 64 * 1024 * 1024;
+
+/// A Redis server to meet other instances at, from a URL like
+/// `redis://:password@host:6379`
+#[derive(Clone, Debug, PartialEq)]
+pub struct RedisAddress {
+    /// host & port, to connect to
+    pub host: String,
+    pub password: Option<String>,
+}
+
+impl FromStr for RedisAddress {
+    type Err = WebmetroError;
+
+    fn from_str(url: &str) -> Result<RedisAddress, WebmetroError> {
+        let invalid = || WebmetroError::ApplicationError {
+            message: format!("Redis URL {} should look like redis://[:password@]host[:port]", url),
+        };
+        if !url.starts_with("redis://") {
+            return Err(invalid());
+        }
+        let rest = url["redis://".len()..].trim_end_matches('/');
+        let (password, host) = match rest.rfind('@') {
+            Some(at) => {
+                let user_info = &rest[..at];
+                let password = user_info.find(':').map_or(user_info, |colon| &user_info[colon + 1..]);
+                (Some(password.to_string()), &rest[at + 1..])
+            },
+            None => (None, rest),
+        };
+        if host.is_empty() || host.contains('/') {
+            return Err(invalid());
+        }
+        let host = if host.contains(':') {
+            host.to_string()
+        } else {
+            format!("{}:6379", host)
+        };
+        Ok(RedisAddress {
+            host,
+            password: password.filter(|password| !password.is_empty()),
+        })
+    }
+}
+
+/// A reply from Redis
+#[derive(Clone, Debug, PartialEq)]
+enum Reply {
+    Status(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Option<Bytes>),
+    Array(Option<Vec<Reply>>),
+}
+
+/// Encode a command as a RESP array of bulk strings
+fn command(args: &[&[u8]]) -> Vec<u8> {
+    let mut encoded = format!("*{}\r\n", args.len()).into_bytes();
+    for arg in args {
+        encoded.extend_from_slice(format!("${}\r\n", arg.len()).as_bytes());
+        encoded.extend_from_slice(arg);
+        encoded.extend_from_slice(b"\r\n");
+    }
+    encoded
+}
+
+fn protocol_error(message: &str) -> WebmetroError {
+    WebmetroError::ApplicationError {
+        message: format!("Redis protocol error: {}", message),
+    }
+}
+
+/// Parse the reply starting at `start`, returning it & where it ends, or
+/// `None` if it hasn't all arrived yet
+fn parse_reply_at(buffer: &[u8], start: usize) -> Result<Option<(Reply, usize)>, WebmetroError> {
+    let line_end = match buffer[start..].windows(2).position(|pair| pair == b"\r\n") {
+        Some(offset) => start + offset,
+        None => return Ok(None),
+    };
+    if line_end == start {
+        return Err(protocol_error("empty line"));
+    }
+    let line = std::str::from_utf8(&buffer[start + 1..line_end])
+        .map_err(|_| protocol_error("line isn't UTF-8"))?;
+    let next = line_end + 2;
+    let length = || line.parse::<i64>().map_err(|_| protocol_error("bad length"));
+    Ok(match buffer[start] {
+        b'+' => Some((Reply::Status(line.to_string()), next)),
+        b'-' => Some((Reply::Error(line.to_string()), next)),
+        b':' => Some((Reply::Integer(length()?), next)),
+        b'$' => match length()? {
+            length if length < 0 => Some((Reply::Bulk(None), next)),
+            length if length as u64 > MAX_REPLY_SIZE as u64 => return Err(protocol_error("bulk string too long")),
+            length => {
+                let end = next.checked_add(length as usize)
+                    .filter(|end| end.checked_add(2).is_some())
+                    .ok_or_else(|| protocol_error("bulk string too long"))?;
+                if buffer.len() < end + 2 {
+                    None
+                } else if &buffer[end..end + 2] != b"\r\n" {
+                    return Err(protocol_error("bulk string is longer than its length"));
+                } else {
+                    Some((Reply::Bulk(Some(Bytes::copy_from_slice(&buffer[next..end]))), end + 2))
+                }
+            }
+        },
+        b'*' => match length()? {
+            count if count < 0 => Some((Reply::Array(None), next)),
+            count => {
+                let mut items = Vec::new();
+                let mut position = next;
+                for _ in 0..count {
+                    match parse_reply_at(buffer, position)? {
+                        Some((item, end)) => {
+                            items.push(item);
+                            position = end;
+                        },
+                        None => return Ok(None),
+                    }
+                }
+                Some((Reply::Array(Some(items)), position))
+            }
+        },
+        _ => return Err(protocol_error("unknown reply type")),
+    })
+}
+
+/// Take a whole reply off the front of the buffer, if one's arrived
+fn parse_reply(buffer: &mut BytesMut) -> Result<Option<Reply>, WebmetroError> {
+    match parse_reply_at(buffer, 0)? {
+        Some((reply, end)) => {
+            buffer.advance(end);
+            Ok(Some(reply))
+        },
+        None => Ok(None),
+    }
+}
+
+/// A connection to Redis
+struct Connection {
+    stream: TcpStream,
+    buffer: BytesMut,
+}
+
+impl Connection {
+    async fn open(address: &RedisAddress) -> Result<Connection, WebmetroError> {
+        let stream = TcpStream::connect(&address.host[..]).await?;
+        stream.set_nodelay(true)?;
+        let mut connection = Connection {
+            stream,
+            buffer: BytesMut::new(),
+        };
+        if let Some(ref password) = address.password {
+            connection.call(&[b"AUTH", password.as_bytes()]).await?;
+        }
+        Ok(connection)
+    }
+
+    async fn send(&mut self, args: &[&[u8]]) -> Result<(), WebmetroError> {
+        self.stream.write_all(&command(args)).await?;
+        Ok(())
+    }
+
+    /// Read the next reply, or message if subscribed
+    async fn reply(&mut self) -> Result<Reply, WebmetroError> {
+        loop {
+            if let Some(reply) = parse_reply(&mut self.buffer)? {
+                return Ok(reply);
+            }
+            if self.buffer.len() >= MAX_REPLY_SIZE {
+                return Err(protocol_error("reply too long"));
+            }
+            self.buffer.reserve(8192);
+            if self.stream.read_buf(&mut self.buffer).await? == 0 {
+                return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+            }
+        }
+    }
+
+    /// Send a command & wait for its reply, failing if it's an error
+    async fn call(&mut self, args: &[&[u8]]) -> Result<Reply, WebmetroError> {
+        self.send(args).await?;
+        match self.reply().await? {
+            Reply::Error(message) => Err(WebmetroError::ApplicationError {
+                message: format!("Redis error: {}", message),
+            }),
+            reply => Ok(reply),
+        }
+    }
+
+    /// Send several commands at once, then read all their replies, failing
+    /// if any is an error
+    async fn pipeline(&mut self, commands: &[Vec<u8>]) -> Result<(), WebmetroError> {
+        self.stream.write_all(&commands.concat()).await?;
+        let mut error = None;
+        for _ in commands {
+            if let Reply::Error(message) = self.reply().await? {
+                error = Some(message);
+            }
+        }
+        match error {
+            Some(message) => Err(WebmetroError::ApplicationError {
+                message: format!("Redis error: {}", message),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// A forwarded chunk: the instance it came from, whether it's headers or a
+/// Cluster, then its WebM bytes
+fn encode_media(instance: &str, chunk: &Chunk) -> Option<Bytes> {
+    let kind = match chunk {
+        Chunk::Headers { .. } => b'H',
+        Chunk::Cluster(..) => b'C',
+        _ => return None,
+    };
+    let mut message = BytesMut::with_capacity(instance.len() + 3 + chunk.size());
+    message.put_slice(instance.as_bytes());
+    message.put_u8(b' ');
+    message.put_u8(kind);
+    message.put_u8(b'\n');
+    for bytes in chunk.clone() {
+        message.put_slice(&bytes);
+    }
+    Some(message.freeze())
+}
+
+/// Split a forwarded chunk into its instance, whether it's headers, and its bytes
+fn decode_media(message: &Bytes) -> Option<(&str, bool, Bytes)> {
+    let newline = message.iter().take(64).position(|&byte| byte == b'\n')?;
+    let head = std::str::from_utf8(&message[..newline]).ok()?;
+    let mut parts = head.splitn(2, ' ');
+    let instance = parts.next()?;
+    let headers = match parts.next()? {
+        "H" => true,
+        "C" => false,
+        _ => return None,
+    };
+    Some((instance, headers, message.slice(newline + 1..)))
+}
+
+/// A name for this instance that others are unlikely to share
+fn instance_id() -> String {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(process::id());
+    if let Ok(now) = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+        hasher.write_u128(now.as_nanos());
+    }
+    format!("{:016x}", hasher.finish())
+}
+
+/// Share the relay's channels with other instances meeting at the same Redis
+/// server, until the returned future is dropped; losing the connection just
+/// means reconnecting.
+pub fn cluster(relay: Arc<Relay>, address: RedisAddress) -> impl Future<Output = ()> {
+    let instance = instance_id();
+    info!("Joining cluster at {} as instance {}", address.host, instance);
+    // channels other instances are feeding, which aren't forwarded back
+    let fed = Arc::new(Mutex::new(HashSet::new()));
+    let forwarding = forward(relay.clone(), address.clone(), instance.clone(), fed.clone());
+    let following = follow(relay, address, instance, fed);
+    join(forwarding.instrument(info_span!("forward")), following.instrument(info_span!("follow")))
+        .map(|_| ())
+}
+
+/// Forward each local source's chunks as it starts publishing
+async fn forward(relay: Arc<Relay>, address: RedisAddress, instance: String, fed: Arc<Mutex<HashSet<String>>>) {
+    let (messages, mut outbox) = mpsc::channel::<(String, Bytes)>(FORWARD_QUEUE_LIMIT);
+    let mut events = relay.subscribe(None);
+    let publisher = async move {
+        let mut connection = None;
+        while let Some(first) = outbox.next().await {
+            // whatever else is waiting goes out with it, rather than a round
+            // trip to Redis apiece
+            let mut publishes = vec![first];
+            while publishes.len() < FORWARD_QUEUE_LIMIT {
+                match outbox.next().now_or_never() {
+                    Some(Some(next)) => publishes.push(next),
+                    _ => break,
+                }
+            }
+            if connection.is_none() {
+                connection = match Connection::open(&address).await {
+                    Ok(connection) => Some(connection),
+                    Err(err) => {
+                        warn!("Couldn't connect to Redis: {}", err);
+                        delay_for(RECONNECT_DELAY).await;
+                        continue;
+                    }
+                };
+            }
+            if let Some(ref mut open) = connection {
+                let commands: Vec<Vec<u8>> = publishes.iter()
+                    .map(|(topic, message)| command(&[b"PUBLISH", topic.as_bytes(), message]))
+                    .collect();
+                if let Err(err) = open.pipeline(&commands).await {
+                    warn!("Lost Redis connection: {}", err);
+                    connection = None;
+                }
+            }
+        }
+    };
+    let announcer = async move {
+        while let Some(event) = events.next().await {
+            let channel = match event {
+                Event::PublishStart { channel } => channel,
+                _ => continue,
+            };
+            // simulcast groups have no source of their own
+            if fed.lock().expect("Locking fed channels").contains(&channel) || !relay.has_source(&channel) {
+                continue;
+            }
+            info!("Forwarding channel {}", channel);
+            let mut messages = messages.clone();
+            let instance = instance.clone();
+            let chunks = relay.source_chunks(&channel);
+            tokio::spawn(async move {
+                let announce = |action: &str| (EVENTS_TOPIC.to_string(), Bytes::from(format!("{} {} {}", instance, action, channel)));
+                let topic = format!("{}{}", MEDIA_TOPIC, channel);
+                messages.send(announce("start")).await.ok();
+                let mut headers = None;
+                let mut chunks = Box::pin(chunks);
+                while let Some(Ok(chunk)) = chunks.next().await {
+                    match (&chunk, &headers) {
+                        (Chunk::Headers { .. }, _) => headers = Some(chunk.clone()),
+                        // so instances that missed them can join here
+                        (Chunk::Cluster(head, _), Some(headers)) if head.keyframe => {
+                            if let Some(message) = encode_media(&instance, headers) {
+                                messages.send((topic.clone(), message)).await.ok();
+                            }
+                        },
+                        _ => {}
+                    }
+                    if let Some(message) = encode_media(&instance, &chunk) {
+                        messages.send((topic.clone(), message)).await.ok();
+                    }
+                }
+                messages.send(announce("stop")).await.ok();
+                info!("Stopped forwarding channel {}", channel);
+            }.in_current_span());
+        }
+    };
+    join(publisher, announcer).await;
+}
+
+/// A channel being published locally from another instance's chunks
+struct Feed {
+    instance: String,
+    chunks: mpsc::Sender<Result<Bytes, WebmetroError>>,
+    headers: Bytes,
+    last_heard: Instant,
+}
+
+/// Publish other instances' sources to the local relay
+async fn follow(relay: Arc<Relay>, address: RedisAddress, instance: String, fed: Arc<Mutex<HashSet<String>>>) {
+    loop {
+        let mut feeds = HashMap::new();
+        if let Err(err) = follow_connection(&relay, &address, &instance, &fed, &mut feeds).await {
+            warn!("Lost Redis subscription: {}", err);
+        }
+        // their sources end with the connection
+        drop(feeds);
+        delay_for(RECONNECT_DELAY).await;
+    }
+}
+
+async fn follow_connection(
+    relay: &Arc<Relay>,
+    address: &RedisAddress,
+    instance: &str,
+    fed: &Arc<Mutex<HashSet<String>>>,
+    feeds: &mut HashMap<String, Feed>,
+) -> Result<(), WebmetroError> {
+    let mut connection = Connection::open(address).await?;
+    connection.send(&[b"SUBSCRIBE", EVENTS_TOPIC.as_bytes()]).await?;
+    connection.send(&[b"PSUBSCRIBE", format!("{}*", MEDIA_TOPIC).as_bytes()]).await?;
+    loop {
+        let reply = match timeout(FEED_TIMEOUT / 2, connection.reply()).await {
+            Ok(reply) => reply?,
+            Err(_) => {
+                expire_feeds(feeds);
+                continue;
+            }
+        };
+        let (topic, message) = match reply {
+            Reply::Array(Some(mut items)) => match (items.pop(), items.pop()) {
+                (Some(Reply::Bulk(Some(message))), Some(Reply::Bulk(Some(topic)))) => (topic, message),
+                _ => continue,
+            },
+            Reply::Error(message) => return Err(WebmetroError::ApplicationError {
+                message: format!("Redis error: {}", message),
+            }),
+            _ => continue,
+        };
+        if &topic[..] == EVENTS_TOPIC.as_bytes() {
+            let announcement = String::from_utf8_lossy(&message);
+            let mut words = announcement.splitn(3, ' ');
+            if let (Some(sender), Some("stop"), Some(channel)) = (words.next(), words.next(), words.next()) {
+                if feeds.get(channel).map_or(false, |feed| feed.instance == sender) {
+                    feeds.remove(channel);
+                }
+            }
+        } else if topic.starts_with(MEDIA_TOPIC.as_bytes()) {
+            let channel = String::from_utf8_lossy(&topic[MEDIA_TOPIC.len()..]).into_owned();
+            if let Some((sender, is_headers, bytes)) = decode_media(&message) {
+                if sender != instance {
+                    feed(relay, fed, feeds, channel, sender, is_headers, bytes);
+                }
+            }
+        }
+        expire_feeds(feeds);
+    }
+}
+
+/// Pass a forwarded chunk on to its channel's feed, starting one at headers
+fn feed(
+    relay: &Arc<Relay>,
+    fed: &Arc<Mutex<HashSet<String>>>,
+    feeds: &mut HashMap<String, Feed>,
+    channel: String,
+    sender: &str,
+    is_headers: bool,
+    bytes: Bytes,
+) {
+    if let Some(feed) = feeds.get_mut(&channel) {
+        if feed.chunks.is_closed() {
+            feeds.remove(&channel);
+        } else if feed.instance == sender {
+            feed.last_heard = Instant::now();
+            // repeated before each keyframe; only new headers are news
+            if is_headers && feed.headers == bytes {
+                return;
+            }
+            if is_headers {
+                feed.headers = bytes.clone();
+            }
+            if feed.chunks.try_send(Ok(bytes)).is_err() {
+                warn!("Dropped a chunk forwarded for channel {}", channel);
+            }
+            return;
+        } else {
+            // another instance's source, until this one's stops
+            return;
+        }
+    }
+    if !is_headers || relay.has_source(&channel) {
+        return;
+    }
+
+    info!("Following channel {} from instance {}", channel, sender);
+    let (mut chunks, body) = mpsc::channel(FORWARD_QUEUE_LIMIT);
+    chunks.try_send(Ok(bytes.clone())).ok();
+    fed.lock().expect("Locking fed channels").insert(channel.clone());
+    let publishing = relay.publish(&channel, body);
+    let fed = fed.clone();
+    let name = channel.clone();
+    tokio::spawn(async move {
+        if let Err(err) = publishing.await {
+            info!("Stopped following channel {}: {}", name, err);
+        }
+        fed.lock().expect("Locking fed channels").remove(&name);
+    }.in_current_span());
+    feeds.insert(channel, Feed {
+        instance: sender.to_string(),
+        chunks,
+        headers: bytes,
+        last_heard: Instant::now(),
+    });
+}
+
+/// Stop feeding channels whose instance has gone quiet
+fn expire_feeds(feeds: &mut HashMap<String, Feed>) {
+    feeds.retain(|channel, feed| {
+        let live = !feed.chunks.is_closed() && feed.last_heard.elapsed() < FEED_TIMEOUT;
+        if !live {
+            info!("Channel {} stopped arriving from instance {}", channel, feed.instance);
+        }
+        live
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use matches::assert_matches;
+
+    use crate::chunk::ClusterHead;
+    use crate::cluster::*;
+
+    #[test]
+    fn parse_redis_urls() {
+        assert_eq!("redis://cache".parse::<RedisAddress>().unwrap(), RedisAddress {
+            host: "cache:6379".into(),
+            password: None,
+        });
+        assert_eq!("redis://:s3cret@10.0.0.5:6380/".parse::<RedisAddress>().unwrap(), RedisAddress {
+            host: "10.0.0.5:6380".into(),
+            password: Some("s3cret".into()),
+        });
+        assert!("http://cache".parse::<RedisAddress>().is_err());
+        assert!("redis://cache/2".parse::<RedisAddress>().is_err());
+    }
+
+    #[test]
+    fn speak_resp() {
+        assert_eq!(command(&[b"PUBLISH", b"topic", b"a\r\nb"]), b"*3\r\n$7\r\nPUBLISH\r\n$5\r\ntopic\r\n$4\r\na\r\nb\r\n".to_vec());
+
+        let mut buffer = BytesMut::from(&b"+OK\r\n:2\r\n*3\r\n$7\r\nmessage\r\n$1\r\nt\r\n$-1\r\n*1\r\n$1\r\nx"[..]);
+        assert_eq!(parse_reply(&mut buffer).unwrap(), Some(Reply::Status("OK".into())));
+        assert_eq!(parse_reply(&mut buffer).unwrap(), Some(Reply::Integer(2)));
+        assert_eq!(parse_reply(&mut buffer).unwrap(), Some(Reply::Array(Some(vec![
+            Reply::Bulk(Some(Bytes::from("message"))),
+            Reply::Bulk(Some(Bytes::from("t"))),
+            Reply::Bulk(None),
+        ]))));
+        // an incomplete reply waits for the rest
+        assert_eq!(parse_reply(&mut buffer).unwrap(), None);
+        buffer.extend_from_slice(b"\r\n-ERR no\r\n\r\n");
+        assert_matches!(parse_reply(&mut buffer).unwrap(), Some(Reply::Array(Some(_))));
+        assert_eq!(parse_reply(&mut buffer).unwrap(), Some(Reply::Error("ERR no".into())));
+        assert!(parse_reply(&mut buffer).is_err());
+
+        for length in &["$9223372036854775807\r\n", "$67108865\r\n"] {
+            assert!(parse_reply(&mut BytesMut::from(length.as_bytes())).is_err());
+        }
+        assert!(parse_reply(&mut BytesMut::from(&b"$1\r\nab\r\n"[..])).is_err());
+    }
+
+    #[test]
+    fn parse_replies_in_pieces() {
+        let replies = b"+OK\r\n*3\r\n$7\r\nmessage\r\n$4\r\nt\r\nx\r\n$-1\r\n:12\r\n";
+        let mut buffer = BytesMut::new();
+        let mut parsed = Vec::new();
+        for &byte in replies.iter() {
+            buffer.extend_from_slice(&[byte]);
+            while let Some(reply) = parse_reply(&mut buffer).unwrap() {
+                parsed.push(reply);
+            }
+        }
+        assert!(buffer.is_empty());
+        assert_eq!(parsed, vec![
+            Reply::Status("OK".into()),
+            Reply::Array(Some(vec![
+                Reply::Bulk(Some(Bytes::from("message"))),
+                Reply::Bulk(Some(Bytes::from("t\r\nx"))),
+                Reply::Bulk(None),
+            ])),
+            Reply::Integer(12),
+        ]);
+    }
+
+    #[test]
+    fn frame_forwarded_chunks() {
+        let headers = Chunk::Headers { bytes: Bytes::from("EBML") };
+        let message = encode_media("abc", &headers).unwrap();
+        assert_eq!(decode_media(&message), Some(("abc", true, Bytes::from("EBML"))));
+
+        let cluster = Chunk::Cluster(ClusterHead::new(1000), Bytes::from("blocks"));
+        let message = encode_media("abc", &cluster).unwrap();
+        let (instance, is_headers, bytes) = decode_media(&message).unwrap();
+        assert_eq!((instance, is_headers, bytes.len()), ("abc", false, cluster.size()));
+        assert!(bytes.ends_with(b"blocks"));
+
+        assert_eq!(decode_media(&Bytes::from("no header line")), None);
+    }
+}
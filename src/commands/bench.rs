@@ -0,0 +1,207 @@
+use std::io::Cursor;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::{prelude::*, stream::iter};
+
+use self::counting::allocations;
+use webmetro::{
+    chunk::{Chunk, WebmStream},
+    ebml::{encode_bytes, encode_element, encode_integer},
+    error::WebmetroError,
+    fixers::ChunkTimecodeFixer,
+    stream_parser::StreamEbml,
+    webm::*,
+};
+
+/// Input is fed to the parser in pieces of this size, like reads off a socket
+const READ_SIZE: usize = 8 * 1024;
+
+const SYNTHETIC_FRAME_RATE: u64 = 30;
+const SYNTHETIC_KEYFRAME_INTERVAL: u64 = 60;
+const SYNTHETIC_FRAME_SIZE: usize = 5000;
+
+/// Counts allocations, so the bench can report them. Counting slows every
+/// allocation in the binary, relay included, so it's only built in with the
+/// count-allocations feature.
+#[cfg(feature = "count-allocations")]
+mod counting {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
+
+    /// Wraps the system allocator to count allocations
+    struct CountingAllocator;
+
+    static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+    static ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+    unsafe impl GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            ALLOCATIONS.fetch_add(1, Relaxed);
+            ALLOCATED_BYTES.fetch_add(layout.size(), Relaxed);
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            ALLOCATIONS.fetch_add(1, Relaxed);
+            ALLOCATED_BYTES.fetch_add(new_size, Relaxed);
+            System.realloc(ptr, layout, new_size)
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: CountingAllocator = CountingAllocator;
+
+    /// How many allocations have been made so far, and of how many bytes
+    pub fn allocations() -> Option<(usize, usize)> {
+        Some((ALLOCATIONS.load(Relaxed), ALLOCATED_BYTES.load(Relaxed)))
+    }
+}
+
+#[cfg(not(feature = "count-allocations"))]
+mod counting {
+    /// Allocations aren't counted in this build
+    pub fn allocations() -> Option<(usize, usize)> {
+        None
+    }
+}
+
+pub fn options() -> App<'static, 'static> {
+    SubCommand::with_name("bench")
+        .about("Measures the speed of the relay's parse, chunk, & timecode-fixing pipeline.")
+        .arg(Arg::with_name("input")
+            .help("The WebM file to process; a synthetic stream is generated if omitted"))
+        .arg(Arg::with_name("iterations")
+            .takes_value(true)
+            .short("n")
+            .long("iterations")
+            .default_value("10")
+            .help("How many times to run the file through the pipeline"))
+        .arg(Arg::with_name("synthetic_duration")
+            .takes_value(true)
+            .long("synthetic-duration")
+            .default_value("60")
+            .help("Length in seconds of the synthetic stream, when no input is given"))
+}
+
+/// Builds a single-track 30fps stream with a keyframe (and so a Cluster) every two seconds
+fn synthesize(seconds: u64) -> Result<Vec<u8>, WebmetroError> {
+    let mut tracks = Cursor::new(Vec::new());
+    encode_element(TRACK_ENTRY_ID, &mut tracks, |output| {
+        encode_integer(TRACK_NUMBER_ID, 1, output)?;
+        encode_integer(TRACK_TYPE_ID, TRACK_TYPE_VIDEO, output)?;
+        encode_bytes(CODEC_ID_ID, b"V_VP8", output)
+    })?;
+    let tracks = tracks.into_inner();
+
+    let frame: Vec<u8> = (0..SYNTHETIC_FRAME_SIZE).map(|i| i as u8).collect();
+    let frame_duration = 1000 / SYNTHETIC_FRAME_RATE;
+
+    let mut output = Cursor::new(Vec::new());
+    encode_webm_element(WebmElement::EbmlHead, &mut output)?;
+    encode_webm_element(WebmElement::Segment, &mut output)?;
+    encode_webm_element(WebmElement::Tracks(&tracks), &mut output)?;
+
+    for frame_index in 0..seconds * SYNTHETIC_FRAME_RATE {
+        let group_index = frame_index % SYNTHETIC_KEYFRAME_INTERVAL;
+        if group_index == 0 {
+            encode_webm_element(WebmElement::Cluster, &mut output)?;
+            encode_webm_element(WebmElement::Timecode(frame_index * frame_duration), &mut output)?;
+        }
+        encode_webm_element(WebmElement::SimpleBlock(SimpleBlock {
+            track: 1,
+            timecode: (group_index * frame_duration) as i16,
+            flags: if group_index == 0 { 0b10000000 } else { 0 },
+            data: &frame,
+        }), &mut output)?;
+    }
+
+    Ok(output.into_inner())
+}
+
+struct Measurement {
+    elapsed: Duration,
+    clusters: usize,
+    /// how many allocations were made, and of how many bytes, if counted
+    allocations: Option<(usize, usize)>,
+}
+
+async fn run_pipeline(input: &Bytes) -> Result<Measurement, WebmetroError> {
+    let reads: Vec<Bytes> = (0..input.len()).step_by(READ_SIZE)
+        .map(|start| input.slice(start..input.len().min(start + READ_SIZE)))
+        .collect();
+
+    let before = allocations();
+    let start = Instant::now();
+
+    let mut timecode_fixer = ChunkTimecodeFixer::new();
+    let mut chunk_stream = iter(reads.into_iter().map(Result::<Bytes, WebmetroError>::Ok))
+        .parse_ebml()
+        .chunk_webm()
+        .map_ok(move |chunk| timecode_fixer.process(chunk));
+
+    let mut clusters = 0;
+    while let Some(chunk) = chunk_stream.try_next().await? {
+        if let Chunk::Cluster(..) = chunk {
+            clusters += 1;
+        }
+    }
+
+    Ok(Measurement {
+        elapsed: start.elapsed(),
+        clusters,
+        allocations: before.zip(allocations()).map(|((count, bytes), (count_after, bytes_after))| {
+            (count_after - count, bytes_after - bytes)
+        }),
+    })
+}
+
+#[tokio::main]
+pub async fn run(args: &ArgMatches) -> Result<(), WebmetroError> {
+    let iterations: u32 = args.value_of("iterations").unwrap_or("10").parse()
+        .map_err(|_| WebmetroError::from("Iterations must be a number"))?;
+    if iterations == 0 {
+        return Err("Iterations must be at least 1".into());
+    }
+
+    let input = Bytes::from(match args.value_of("input") {
+        Some(path) => std::fs::read(path).map_err(|err| WebmetroError::ApplicationError {
+            message: format!("{}: {}", path, err),
+        })?,
+        None => {
+            let seconds: u64 = args.value_of("synthetic_duration").unwrap_or("60").parse()
+                .map_err(|_| WebmetroError::from("Synthetic duration must be a number of seconds"))?;
+            synthesize(seconds)?
+        }
+    });
+
+    let mut total = Duration::default();
+    let mut clusters = 0;
+    let mut allocated = Some((0, 0));
+    for iteration in 1..=iterations {
+        let measurement = run_pipeline(&input).await?;
+        debug!("Iteration {}: {:?}", iteration, measurement.elapsed);
+        total += measurement.elapsed;
+        clusters = measurement.clusters;
+        allocated = allocated.zip(measurement.allocations).map(|((count, bytes), (more, more_bytes))| (count + more, bytes + more_bytes));
+    }
+
+    let seconds = total.as_secs_f64();
+    let megabytes = (input.len() as f64 * iterations as f64) / (1024.0 * 1024.0);
+    println!("Input:        {} bytes, {} clusters", input.len(), clusters);
+    println!("Iterations:   {} in {:.3} s", iterations, seconds);
+    println!("Throughput:   {:.1} MB/s", megabytes / seconds);
+    println!("Clusters:     {:.0} clusters/s", (clusters as f64 * iterations as f64) / seconds);
+    match allocated {
+        Some((count, bytes)) => println!("Allocations:  {} per iteration ({} bytes)",
+            count / iterations as usize,
+            bytes / iterations as usize),
+        None => println!("Allocations:  not counted (build with --features count-allocations)"),
+    }
+    Ok(())
+}
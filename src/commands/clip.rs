@@ -0,0 +1,99 @@
+use std::fs;
+use std::io::{Seek, SeekFrom};
+use std::path::Path;
+
+use bytes::Bytes;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::{future, prelude::*, stream};
+use tokio::fs::File;
+use tokio_util::codec::{BytesCodec, FramedRead};
+
+use super::{create_file, seconds};
+use webmetro::{
+    chunk::{Chunk, ChunkerOptions, WebmStream},
+    cues::read_index,
+    error::WebmetroError,
+    fixers::ChunkStream,
+    recorder::WebmFileWriter,
+    stream_parser::StreamEbml,
+    webm::DEFAULT_TIMESTAMP_SCALE,
+};
+
+pub fn options() -> App<'static, 'static> {
+    SubCommand::with_name("clip")
+        .about("Copies a time range of a recorded WebM file into a new, standalone file, seeking with the file's Cues.")
+        .arg(Arg::with_name("input")
+            .help("The recorded file to read; it must be a seekable file, ideally with Cues (as record writes)")
+            .required(true))
+        .arg(Arg::with_name("output")
+            .help("The file to write")
+            .required(true))
+        .arg(Arg::with_name("start")
+            .takes_value(true)
+            .long("start")
+            .help("Where the clip starts, in seconds; it begins at the next keyframe. Defaults to the start of the file"))
+        .arg(Arg::with_name("end")
+            .takes_value(true)
+            .long("end")
+            .help("Where the clip ends, in seconds. Defaults to the end of the file"))
+}
+
+#[tokio::main]
+pub async fn run(args: &ArgMatches) -> Result<(), WebmetroError> {
+    let input = args.value_of("input").ok_or("Input file wasn't provided")?;
+    let output = Path::new(args.value_of("output").ok_or("Output file wasn't provided")?);
+    let start = seconds(args.value_of("start"))?.unwrap_or_default();
+    let end = seconds(args.value_of("end"))?;
+    if let Some(end) = end {
+        if end <= start {
+            return Err("--end must come after --start".into());
+        }
+    }
+
+    let mut file = fs::File::open(input).map_err(|err| WebmetroError::ApplicationError {
+        message: format!("{}: {}", input, err),
+    })?;
+    let index = read_index(&mut file)?;
+    if index.cues.is_empty() {
+        warn!("{} has no Cues, so it's read from the beginning", input);
+    }
+
+    // the headers, then the Clusters from the nearest cue before the start
+    let (position, cue) = index.seek(start);
+    file.seek(SeekFrom::Start(position))?;
+    let clusters = FramedRead::new(File::from_std(file), BytesCodec::new())
+        .map_ok(|bytes| bytes.freeze())
+        .map_err(WebmetroError::from);
+    let cue_time = cue.map(|cue| index.cue_time(&cue)).unwrap_or_default();
+    let bytes = stream::once(future::ok(Bytes::from(index.headers))).chain(clusters);
+
+    // durations count from the first block read, which starts the cued
+    // Cluster; the edges are cut between blocks by their ms timecodes, so
+    // the blocks' are read in ms too
+    let chunks = bytes.parse_ebml().chunk_webm_with(ChunkerOptions::new().timestamp_scale(DEFAULT_TIMESTAMP_SCALE));
+    let mut chunks = match end {
+        Some(end) => chunks.take_duration(end - cue_time).boxed(),
+        None => chunks.boxed(),
+    }.skip_duration(start - cue_time);
+
+    let mut writer = None;
+    let mut written = 0;
+    while let Some(chunk) = chunks.next().await {
+        let chunk = chunk?;
+        match (&mut writer, &chunk) {
+            (None, Chunk::Headers { .. }) => writer = Some(WebmFileWriter::new(create_file(output)?, &chunk)?),
+            (Some(writer), Chunk::Cluster(..)) => {
+                writer.write_cluster(&chunk)?;
+                written += 1;
+            },
+            _ => {}
+        }
+    }
+    let writer = writer.ok_or("Input has no headers")?;
+    if written == 0 {
+        warn!("Nothing was clipped; is --start past the end of {}?", input);
+    }
+    info!("Wrote {} ms to {}", writer.duration(), output.display());
+    writer.finish()?;
+    Ok(())
+}
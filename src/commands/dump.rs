@@ -1,33 +1,131 @@
-use clap::{App, AppSettings, ArgMatches, SubCommand};
+use std::io::Write;
+use std::time::Duration;
 
-use super::stdin_stream;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use tokio::time::delay_for;
+
+use super::{input_stream, output_writer, BoxedByteStream};
 use webmetro::{
+    ebml::{EbmlError, FromEbml},
     error::WebmetroError,
     stream_parser::StreamEbml,
     webm::{
         SimpleBlock,
+        WebmElement,
         WebmElement::*
     }
 };
 
+/// How long to wait before reconnecting to a URL
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
 pub fn options() -> App<'static, 'static> {
     SubCommand::with_name("dump")
         .setting(AppSettings::Hidden)
-        .about("Dumps WebM parsing events from parsing stdin")
+        .about("Dumps WebM parsing events from parsing stdin, a file, or a live http(s) stream")
+        .arg(Arg::with_name("input")
+            .help("The file or http(s) URL to dump; reads stdin if omitted or \"-\". URLs are followed live, reconnecting if the stream ends or drops"))
+        .arg(Arg::with_name("output")
+            .takes_value(true)
+            .short("o")
+            .long("output")
+            .help("The file to write; writes stdout if omitted"))
+        .arg(Arg::with_name("hex")
+            .long("hex")
+            .help("Also print a hexdump of each element's payload"))
+        .arg(Arg::with_name("max_bytes")
+            .takes_value(true)
+            .long("max-bytes")
+            .requires("hex")
+            .default_value("64")
+            .help("Hexdump at most this many bytes of each payload"))
 }
 
-#[tokio::main]
-pub async fn run(_args: &ArgMatches) -> Result<(), WebmetroError> {
+/// An element along with its raw payload, for hexdumps
+struct Dumped<'b> {
+    element: WebmElement<'b>,
+    payload: &'b [u8],
+}
+
+impl<'b> FromEbml<'b> for Dumped<'b> {
+    fn should_unwrap(element_id: u64) -> bool {
+        WebmElement::should_unwrap(element_id)
+    }
 
-    let mut events = stdin_stream().parse_ebml();
+    fn decode(element_id: u64, bytes: &'b[u8]) -> Result<Dumped<'b>, EbmlError> {
+        Ok(Dumped {
+            element: WebmElement::decode(element_id, bytes)?,
+            payload: bytes,
+        })
+    }
+
+    fn resync_id() -> Option<u64> {
+        WebmElement::resync_id()
+    }
+}
 
-    while let Some(element) = events.next().await? {
+/// Print up to `max_bytes` of `payload`, 16 bytes a line, with offsets & ASCII
+fn hexdump(output: &mut dyn Write, payload: &[u8], max_bytes: usize) -> Result<(), WebmetroError> {
+    let shown = &payload[..payload.len().min(max_bytes)];
+    for (line, bytes) in shown.chunks(16).enumerate() {
+        let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        let ascii: String = bytes.iter()
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' })
+            .collect();
+        writeln!(output, "  {:08x}  {:<47}  |{}|", line * 16, hex.join(" "), ascii)?;
+    }
+    if payload.len() > shown.len() {
+        writeln!(output, "  ... {} more bytes", payload.len() - shown.len())?;
+    }
+    Ok(())
+}
+
+/// Print each element of a stream as it's parsed; a `live` stream's are
+/// flushed as they arrive
+async fn dump(output: &mut dyn Write, stream: BoxedByteStream, hex: Option<usize>, live: bool) -> Result<(), WebmetroError> {
+    let mut events = stream.parse_ebml();
+
+    while let Some(Dumped { element, payload }) = events.next().await? {
         match element {
             // suppress printing byte arrays
-            Tracks(slice) => println!("Tracks[{}]", slice.len()),
-            SimpleBlock(SimpleBlock {timecode, ..}) => println!("SimpleBlock@{}", timecode),
-            other => println!("{:?}", other)
+            Tracks(slice) => writeln!(output, "Tracks[{}]", slice.len())?,
+            SimpleBlock(SimpleBlock {timecode, ..}) => writeln!(output, "SimpleBlock@{}", timecode)?,
+            other => writeln!(output, "{:?}", other)?
+        }
+        if let Some(max_bytes) = hex {
+            if !payload.is_empty() {
+                hexdump(output, payload, max_bytes)?;
+            }
+        }
+        if live {
+            output.flush()?;
         }
     }
+    output.flush()?;
     Ok(())
 }
+
+#[tokio::main]
+pub async fn run(args: &ArgMatches) -> Result<(), WebmetroError> {
+    let max_bytes: usize = args.value_of("max_bytes").unwrap_or("64").parse()
+        .map_err(|_| WebmetroError::from("--max-bytes must be a number"))?;
+    let hex = Some(max_bytes).filter(|_| args.is_present("hex"));
+    let input = args.value_of("input");
+    let mut output = output_writer(args.value_of("output"))?;
+
+    match input {
+        Some(url) if url.starts_with("http://") || url.starts_with("https://") => loop {
+            let result = match input_stream(input).await {
+                Ok(stream) => dump(&mut output, stream, hex, true).await,
+                Err(err) => Err(err),
+            };
+            match result {
+                Ok(()) => eprintln!("{} ended, reconnecting", url),
+                Err(err) if err.is_retryable() => eprintln!("{}: {}, reconnecting", url, err),
+                Err(err) => return Err(err),
+            }
+            delay_for(RECONNECT_DELAY).await;
+        },
+        _ => dump(&mut output, input_stream(input).await?, hex, false).await
+    }
+}
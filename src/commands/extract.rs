@@ -0,0 +1,225 @@
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use super::{create_file, input_stream};
+use webmetro::{
+    error::WebmetroError,
+    ogg::{opus_packet_samples, opus_tags, vorbis_headers, OggWriter},
+    stream_parser::StreamEbml,
+    webm::{block_timing, parse_tracks, SimpleBlock, TrackEntry, WebmElement},
+};
+
+const IVF_HEADER_LEN: u16 = 32;
+const IVF_FRAME_COUNT_OFFSET: u64 = 24;
+
+/// Any lacing flag set means the block holds several frames, which aren't split here
+const LACING_MASK: u8 = 0b00000110;
+
+pub fn options() -> App<'static, 'static> {
+    SubCommand::with_name("extract")
+        .about("Demuxes one track into an elementary stream: IVF for VP8/VP9/AV1 video, Ogg for Opus/Vorbis audio.")
+        .arg(Arg::with_name("output")
+            .help("The file to write")
+            .required(true))
+        .arg(Arg::with_name("input")
+            .help("The file or http(s) URL to read; reads stdin if omitted"))
+        .arg(Arg::with_name("track")
+            .takes_value(true)
+            .short("t")
+            .long("track")
+            .help("The track number to extract; defaults to the first video track, or else the first track"))
+}
+
+trait PacketWriter {
+    fn write_packet(&mut self, timecode: i64, data: &[u8]) -> Result<(), WebmetroError>;
+    fn finish(self: Box<Self>) -> Result<(), WebmetroError>;
+}
+
+struct IvfWriter {
+    output: BufWriter<File>,
+    frames: u32,
+}
+
+impl IvfWriter {
+    fn new(mut output: BufWriter<File>, fourcc: &[u8; 4], track: &TrackEntry) -> Result<IvfWriter, WebmetroError> {
+        output.write_all(b"DKIF")?;
+        output.write_u16::<LittleEndian>(0)?; // version
+        output.write_u16::<LittleEndian>(IVF_HEADER_LEN)?;
+        output.write_all(fourcc)?;
+        output.write_u16::<LittleEndian>(track.pixel_width as u16)?;
+        output.write_u16::<LittleEndian>(track.pixel_height as u16)?;
+        // timestamps are in milliseconds
+        output.write_u32::<LittleEndian>(1000)?;
+        output.write_u32::<LittleEndian>(1)?;
+        output.write_u32::<LittleEndian>(0)?; // frame count, patched in finish()
+        output.write_u32::<LittleEndian>(0)?; // unused
+        Ok(IvfWriter { output, frames: 0 })
+    }
+}
+
+impl PacketWriter for IvfWriter {
+    fn write_packet(&mut self, timecode: i64, data: &[u8]) -> Result<(), WebmetroError> {
+        self.output.write_u32::<LittleEndian>(data.len() as u32)?;
+        self.output.write_u64::<LittleEndian>(timecode as u64)?;
+        self.output.write_all(data)?;
+        self.frames += 1;
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), WebmetroError> {
+        self.output.seek(SeekFrom::Start(IVF_FRAME_COUNT_OFFSET))?;
+        self.output.write_u32::<LittleEndian>(self.frames)?;
+        self.output.flush()?;
+        Ok(())
+    }
+}
+
+enum Granule {
+    /// Opus granule positions count 48kHz samples, from the packets themselves
+    Opus(u64),
+    /// Vorbis granule positions are estimated from block timecodes
+    Vorbis(f64),
+}
+
+/// Holds back one packet, so the last one can be flagged as ending the stream
+struct OggPacketWriter {
+    ogg: OggWriter<BufWriter<File>>,
+    granule: Granule,
+    pending: Option<(Vec<u8>, u64)>,
+}
+
+impl OggPacketWriter {
+    fn new(output: BufWriter<File>, track: &TrackEntry) -> Result<OggPacketWriter, WebmetroError> {
+        let mut ogg = OggWriter::new(output, track.number as u32);
+        let granule = match track.codec_id.as_str() {
+            "A_OPUS" => {
+                ogg.write_packet(&track.codec_private, 0, false)?;
+                ogg.write_packet(&opus_tags(), 0, false)?;
+                Granule::Opus(0)
+            },
+            _ => {
+                let headers = vorbis_headers(&track.codec_private)
+                    .ok_or("Vorbis track has malformed CodecPrivate")?;
+                for header in headers {
+                    ogg.write_packet(header, 0, false)?;
+                }
+                Granule::Vorbis(track.sampling_frequency)
+            }
+        };
+        Ok(OggPacketWriter { ogg, granule, pending: None })
+    }
+}
+
+impl PacketWriter for OggPacketWriter {
+    fn write_packet(&mut self, timecode: i64, data: &[u8]) -> Result<(), WebmetroError> {
+        let granule_position = match self.granule {
+            Granule::Opus(ref mut samples) => {
+                *samples += opus_packet_samples(data);
+                *samples
+            },
+            Granule::Vorbis(rate) => (timecode.max(0) as f64 * rate / 1000.0) as u64,
+        };
+        if let Some((packet, granule_position)) = self.pending.take() {
+            self.ogg.write_packet(&packet, granule_position, false)?;
+        }
+        self.pending = Some((data.to_vec(), granule_position));
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<(), WebmetroError> {
+        if let Some((packet, granule_position)) = self.pending.take() {
+            self.ogg.write_packet(&packet, granule_position, true)?;
+        }
+        self.ogg.get_mut().flush()?;
+        Ok(())
+    }
+}
+
+fn packet_writer(output: &str, track: &TrackEntry) -> Result<Box<dyn PacketWriter>, WebmetroError> {
+    let fourcc = match track.codec_id.as_str() {
+        "V_VP8" => Some(b"VP80"),
+        "V_VP9" => Some(b"VP90"),
+        "V_AV1" => Some(b"AV01"),
+        "A_OPUS" | "A_VORBIS" => None,
+        codec => return Err(WebmetroError::ApplicationError {
+            message: format!("Track {} has codec {}, which can't be extracted", track.number, codec),
+        })
+    };
+
+    let output = create_file(Path::new(output))?;
+
+    Ok(match fourcc {
+        Some(fourcc) => Box::new(IvfWriter::new(output, fourcc, track)?),
+        None => Box::new(OggPacketWriter::new(output, track)?),
+    })
+}
+
+fn select_track(tracks: Vec<TrackEntry>, number: Option<u64>) -> Result<TrackEntry, WebmetroError> {
+    match number {
+        Some(number) => tracks.into_iter().find(|track| track.number == number)
+            .ok_or_else(|| WebmetroError::ApplicationError {
+                message: format!("Track {} not found", number),
+            }),
+        None => {
+            let video = tracks.iter().position(TrackEntry::is_video).unwrap_or(0);
+            tracks.into_iter().nth(video).ok_or_else(|| "Stream has no tracks".into())
+        }
+    }
+}
+
+#[tokio::main]
+pub async fn run(args: &ArgMatches) -> Result<(), WebmetroError> {
+    let output = args.value_of("output").ok_or("Output file wasn't provided")?;
+    let track_number = match args.value_of("track") {
+        Some(track) => Some(track.parse::<u64>()
+            .map_err(|_| WebmetroError::from("Track must be a number"))?),
+        None => None
+    };
+
+    let mut events = input_stream(args.value_of("input")).await?.parse_ebml();
+    let mut writer: Option<Box<dyn PacketWriter>> = None;
+    let mut track = 0;
+    let mut cluster_timecode = 0;
+    let mut skipped_laced = 0;
+
+    while let Some(element) = events.next::<WebmElement>().await? {
+        match element {
+            WebmElement::Tracks(tracks) => {
+                // only the first stream of a chained input is extracted
+                if writer.is_some() {
+                    break;
+                }
+                let entry = select_track(parse_tracks(tracks), track_number)?;
+                info!("Extracting track {} ({})", entry.number, entry.codec_id);
+                writer = Some(packet_writer(output, &entry)?);
+                track = entry.number;
+            },
+            WebmElement::Timecode(timecode) => cluster_timecode = timecode as i64,
+            element => if let Some((SimpleBlock { track: block_track, timecode, flags, data }, _)) = block_timing(&element) {
+                if block_track != track {
+                    continue;
+                }
+                if flags & LACING_MASK != 0 {
+                    skipped_laced += 1;
+                    continue;
+                }
+                if let Some(ref mut writer) = writer {
+                    writer.write_packet(cluster_timecode + timecode as i64, data)?;
+                }
+            },
+        }
+    }
+
+    if skipped_laced > 0 {
+        warn!("Skipped {} laced block(s)", skipped_laced);
+    }
+
+    match writer {
+        Some(writer) => writer.finish(),
+        None => Err("Stream has no Tracks".into())
+    }
+}
@@ -1,41 +1,88 @@
-use std::{io, io::prelude::*};
+use std::io::Write;
 
 use clap::{App, Arg, ArgMatches, SubCommand};
 use futures::prelude::*;
 
-use super::stdin_stream;
+use super::{input_stream, output_writer, seconds};
 use webmetro::{
-    chunk::{Chunk, WebmStream},
+    chunk::{Chunk, ChunkerOptions, WebmStream},
     error::WebmetroError,
-    fixers::{ChunkTimecodeFixer, Throttle},
+    fixers::{ChunkStream, ChunkTimecodeFixer, DriftCorrector, Pipeline, SilenceFiller, Throttle},
     stream_parser::StreamEbml,
 };
 
 pub fn options() -> App<'static, 'static> {
     SubCommand::with_name("filter")
         .about("Copies WebM from stdin to stdout, applying the same cleanup & stripping the relay server does.")
+        .arg(Arg::with_name("input")
+            .help("The file or http(s) URL to read; reads stdin if omitted"))
+        .arg(Arg::with_name("output")
+            .takes_value(true)
+            .short("o")
+            .long("output")
+            .help("The file to write; writes stdout if omitted"))
         .arg(Arg::with_name("throttle")
             .long("throttle")
             .help("Slow down output to \"real time\" speed as determined by the timestamps (useful for streaming static files)"))
+        .arg(Arg::with_name("start")
+            .takes_value(true)
+            .long("start")
+            .help("Skip this many seconds of the input, resuming at the next keyframe"))
+        .arg(Arg::with_name("duration")
+            .takes_value(true)
+            .long("duration")
+            .help("Stop after this many seconds of output"))
+        .arg(Arg::with_name("max_av_drift")
+            .takes_value(true)
+            .long("max-av-drift")
+            .help("When the audio drifts more than this many milliseconds from the video, shift its timestamps back in step, a little at a time"))
+        .arg(Arg::with_name("fill_silence")
+            .takes_value(true)
+            .long("fill-silence")
+            .help("Fill gaps of more than this many milliseconds in Opus audio with silence"))
+        .arg(Arg::with_name("timestamp_scale")
+            .takes_value(true)
+            .long("timestamp-scale")
+            .help("Write the output at this TimestampScale, in nanoseconds per tick (e.g. 1000 for microseconds), rescaling its timecodes"))
 }
 
 #[tokio::main]
 pub async fn run(args: &ArgMatches) -> Result<(), WebmetroError> {
-    let mut timecode_fixer = ChunkTimecodeFixer::new();
+    let mut pipeline = Pipeline::new();
+    if let Some(drift) = args.value_of("max_av_drift") {
+        pipeline.push(DriftCorrector::new(drift.parse().map_err(|_| WebmetroError::from("A/V drift must be a number of milliseconds"))?));
+    }
+    if let Some(threshold) = args.value_of("fill_silence") {
+        pipeline.push(SilenceFiller::new(threshold.parse().map_err(|_| WebmetroError::from("Silence threshold must be a number of milliseconds"))?));
+    }
+    pipeline.push(ChunkTimecodeFixer::new());
+    let mut chunker_options = ChunkerOptions::new();
+    if let Some(scale) = args.value_of("timestamp_scale") {
+        chunker_options = chunker_options.timestamp_scale(scale.parse().map_err(|_| WebmetroError::from("TimestampScale must be a number of nanoseconds"))?);
+    }
     let mut chunk_stream: Box<dyn Stream<Item = Result<Chunk, WebmetroError>> + Send + Unpin> =
         Box::new(
-            stdin_stream()
+            input_stream(args.value_of("input")).await?
                 .parse_ebml()
-                .chunk_webm()
-                .map_ok(move |chunk| timecode_fixer.process(chunk)),
+                .chunk_webm_with(chunker_options)
+                .process_with(pipeline),
         );
 
+    if let Some(start) = seconds(args.value_of("start"))? {
+        chunk_stream = Box::new(chunk_stream.skip_duration(start));
+    }
+    if let Some(duration) = seconds(args.value_of("duration"))? {
+        chunk_stream = Box::new(chunk_stream.take_duration(duration));
+    }
+
     if args.is_present("throttle") {
         chunk_stream = Box::new(Throttle::new(chunk_stream));
     }
 
+    let mut output = output_writer(args.value_of("output"))?;
     while let Some(chunk) = chunk_stream.next().await {
-        chunk?.try_for_each(|buffer| io::stdout().write_all(&buffer))?;
+        chunk?.try_for_each(|buffer| output.write_all(&buffer))?;
     }
+    output.flush()?;
     Ok(())
 }
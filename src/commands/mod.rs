@@ -1,14 +1,40 @@
+use std::fs;
+use std::io::{stdout, BufWriter, Write};
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 use bytes::Bytes;
-use futures::{Stream, TryStreamExt};
+use futures::{future, stream::repeat, Stream, StreamExt, TryStreamExt};
+use hyper::{Body, Client, Uri};
+use hyper_tls::HttpsConnector;
+use tokio::fs::File;
 use tokio_util::codec::{BytesCodec, FramedRead};
-use webmetro::error::WebmetroError;
+use webmetro::{
+    chunk::{Chunk, WebmStream},
+    error::WebmetroError,
+    fixers::ChunkTimecodeFixer,
+    stream_parser::StreamEbml,
+};
 
+pub mod bench;
+pub mod clip;
 pub mod dump;
+pub mod extract;
 pub mod filter;
+pub mod monitor;
+pub mod play;
+pub mod probe;
+pub mod record;
 pub mod relay;
 pub mod send;
+pub mod split;
+
+/// The buffer limit applied to relay ingest; neither a cluster nor the
+/// initialization segment may be larger than this.
+pub const BUFFER_LIMIT: usize = webmetro::server::DEFAULT_BUFFER_LIMIT;
+
+pub type BoxedByteStream = Box<dyn Stream<Item = Result<Bytes, WebmetroError>> + Send + Sync + Unpin>;
+pub type BoxedChunkStream = Box<dyn Stream<Item = Result<Chunk, WebmetroError>> + Send + Sync + Unpin>;
 
 /// An adapter that makes chunks of bytes from stdin available as a Stream;
 /// is NOT actually async, and just uses blocking read. Don't use more than
@@ -17,6 +43,88 @@ pub fn stdin_stream() -> impl Stream<Item = Result<Bytes, std::io::Error>> + Siz
     FramedRead::new(tokio::io::stdin(), BytesCodec::new()).map_ok(|bytes| bytes.freeze())
 }
 
+/// Opens an input to read WebM from: an http(s) URL is fetched as a client,
+/// "-" (or no source at all) reads stdin, and anything else is a file path.
+pub async fn input_stream(source: Option<&str>) -> Result<BoxedByteStream, WebmetroError> {
+    match source {
+        None | Some("-") => Ok(Box::new(stdin_stream().map_err(WebmetroError::from))),
+        Some(url) if url.starts_with("http://") || url.starts_with("https://") => {
+            Ok(Box::new(http_stream(url).await?))
+        },
+        Some(path) => {
+            let file = File::open(path).await.map_err(|err| WebmetroError::ApplicationError {
+                message: format!("{}: {}", path, err),
+            })?;
+            Ok(Box::new(
+                FramedRead::new(file, BytesCodec::new())
+                    .map_ok(|bytes| bytes.freeze())
+                    .map_err(WebmetroError::from),
+            ))
+        }
+    }
+}
+
+/// Creates (or truncates) a file to write to, naming it in any error
+pub fn create_file(path: &Path) -> Result<BufWriter<fs::File>, WebmetroError> {
+    let file = fs::File::create(path).map_err(|err| WebmetroError::ApplicationError {
+        message: format!("{}: {}", path.display(), err),
+    })?;
+    Ok(BufWriter::new(file))
+}
+
+/// Opens an output to write to: "-" (or no path at all) is stdout, and
+/// anything else is a file path. Flush it when done, so that write errors
+/// aren't lost.
+pub fn output_writer(path: Option<&str>) -> Result<Box<dyn Write + Send>, WebmetroError> {
+    match path {
+        None | Some("-") => Ok(Box::new(stdout())),
+        Some(path) => Ok(Box::new(create_file(Path::new(path))?)),
+    }
+}
+
+/// Reads a file over and over, end to end, as a Stream of byte chunks.
+pub fn looped_file_stream(path: PathBuf) -> impl Stream<Item = Result<Bytes, std::io::Error>> + Sized + Unpin {
+    repeat(path)
+        .then(|path| Box::pin(File::open(path)))
+        .map_ok(|file| FramedRead::new(file, BytesCodec::new()).map_ok(|bytes| bytes.freeze()))
+        .try_flatten()
+}
+
+/// Plays a file over and over as one continuous chunk stream: each pass's
+/// timecodes carry on from the previous pass's, and its (repeated)
+/// initialization segment is only sent the first time.
+pub fn looped_chunk_stream(path: PathBuf) -> impl Stream<Item = Result<Chunk, WebmetroError>> + Send + Sync + Unpin {
+    let mut timecode_fixer = ChunkTimecodeFixer::new();
+    let mut seen_headers = false;
+    looped_file_stream(path)
+        .parse_ebml()
+        .chunk_webm()
+        // each pass restarts from zero, so the fixer offsets it past the previous one
+        .map_ok(move |chunk| timecode_fixer.process(chunk))
+        // every pass repeats the same initialization segment; only send it once
+        .try_filter(move |chunk| {
+            let repeated = match chunk {
+                Chunk::Headers {..} => std::mem::replace(&mut seen_headers, true),
+                _ => false
+            };
+            future::ready(!repeated)
+        })
+}
+
+/// Fetches a WebM stream from an http(s) URL as a client, making the response
+/// body available as a Stream. Non-success statuses are reported as errors.
+pub async fn http_stream(url: &str) -> Result<impl Stream<Item = Result<Bytes, WebmetroError>> + Sized + Unpin, WebmetroError> {
+    let uri: Uri = url.parse().map_err(http::Error::from)?;
+    let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+
+    let response = client.get(uri).await?;
+    if !response.status().is_success() {
+        return Err(WebmetroError::from_response(&response));
+    }
+
+    Ok(response.into_body().map_err(WebmetroError::from))
+}
+
 pub fn parse_time(arg: Option<&str>) -> Result<Option<Duration>, WebmetroError> {
     match arg {
         Some(string) => match string.parse() {
@@ -28,3 +136,53 @@ pub fn parse_time(arg: Option<&str>) -> Result<Option<Duration>, WebmetroError>
         None => Ok(None),
     }
 }
+
+/// Parse a (possibly fractional) number of seconds
+pub fn seconds(arg: Option<&str>) -> Result<Option<Duration>, WebmetroError> {
+    match arg {
+        Some(string) => match string.parse::<f64>() {
+            Ok(seconds) if seconds >= 0.0 && seconds.is_finite() => Ok(Some(Duration::from_secs_f64(seconds))),
+            _ => Err(WebmetroError::ApplicationError {
+                message: format!("{} isn't a number of seconds", string),
+            }),
+        },
+        None => Ok(None),
+    }
+}
+
+/// Parse a byte count, allowing a K, M, or G suffix for binary multiples
+pub fn parse_size(arg: Option<&str>) -> Result<Option<u64>, WebmetroError> {
+    let string = match arg {
+        Some(string) => string.trim(),
+        None => return Ok(None),
+    };
+
+    let (digits, multiplier) = match string.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&string[..string.len() - 1], 1 << 10),
+        Some('M') => (&string[..string.len() - 1], 1 << 20),
+        Some('G') => (&string[..string.len() - 1], 1 << 30),
+        _ => (string, 1),
+    };
+
+    match digits.parse::<u64>() {
+        Ok(count) => Ok(Some(count * multiplier)),
+        Err(err) => Err(WebmetroError::ApplicationError {
+            message: format!("{}: {}", string, err),
+        }),
+    }
+}
+
+/// Number files after the first by inserting a counter before the extension,
+/// e.g. out.webm, out-1.webm, out-2.webm
+pub fn numbered_path(base: &Path, index: usize) -> PathBuf {
+    if index == 0 {
+        return base.to_path_buf();
+    }
+
+    let stem = base.file_stem().map_or_else(Default::default, |stem| stem.to_string_lossy());
+    let name = match base.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, index, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, index),
+    };
+    base.with_file_name(name)
+}
@@ -0,0 +1,264 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{stdout, Write};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::{Bytes, BytesMut};
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::{future::{select, Either}, prelude::*};
+use http::Request;
+use hyper::{client::HttpConnector, Body, Client};
+use hyper_tls::HttpsConnector;
+use tokio::time::{delay_for, interval};
+
+use super::seconds;
+use webmetro::{
+    error::WebmetroError,
+    events::Event,
+};
+
+/// How long to wait before reconnecting to the relay's event stream
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// How much of a channel's title fits in its row
+const TITLE_WIDTH: usize = 40;
+
+pub fn options() -> App<'static, 'static> {
+    SubCommand::with_name("monitor")
+        .about("Shows a live dashboard of a relay's channels in the terminal: whether each has a source, its listeners, bitrate & lag. Quit with Ctrl-C.")
+        .arg(Arg::with_name("url")
+            .help("The relay's base URL, e.g. http://localhost:8080")
+            .required(true))
+        .arg(Arg::with_name("token")
+            .takes_value(true)
+            .long("token")
+            .help("A bearer token to send, for relays that need one to view their channels"))
+        .arg(Arg::with_name("interval")
+            .takes_value(true)
+            .long("interval")
+            .default_value("1")
+            .help("How many seconds between refreshes"))
+}
+
+type HttpClient = Client<HttpsConnector<HttpConnector>>;
+
+/// What's known about one channel
+#[derive(Default)]
+struct ChannelRow {
+    live: bool,
+    listeners: usize,
+    bits_per_second: Option<u64>,
+    /// the furthest any listener has fallen behind, in ms of media
+    max_lag: u64,
+    /// how many listeners are halfway to their queue limit or more
+    lagging: usize,
+}
+
+/// Everything shown on the dashboard
+#[derive(Default)]
+struct Dashboard {
+    channels: BTreeMap<String, ChannelRow>,
+    /// kept apart from the rows, as channels are often described before
+    /// they go live
+    titles: BTreeMap<String, String>,
+    connected: bool,
+    error: Option<String>,
+}
+
+impl Dashboard {
+    fn apply(&mut self, event: Event) {
+        if let Event::Metadata { channel, metadata } = event {
+            match metadata.title {
+                Some(title) => self.titles.insert(channel, title),
+                None => self.titles.remove(&channel),
+            };
+            return;
+        }
+        let row = self.channels.entry(event.channel().to_string()).or_default();
+        match event {
+            Event::PublishStart { .. } => row.live = true,
+            Event::PublishStop { .. } => {
+                row.live = false;
+                row.bits_per_second = None;
+            },
+            Event::Listeners { count, .. } => row.listeners = count,
+            // bitrates only come while a source is publishing
+            Event::Bitrate { bits_per_second, .. } => {
+                row.live = true;
+                row.bits_per_second = Some(bits_per_second);
+            },
+            Event::Metadata { .. } => {},
+        }
+        // forget channels with nothing left to show
+        self.channels.retain(|_, row| row.live || row.listeners > 0);
+    }
+
+    fn render(&self, url: &str) -> String {
+        let mut screen = String::new();
+        // clear the screen & start from its top left
+        screen.push_str("\x1b[2J\x1b[H");
+        let status = match (self.connected, &self.error) {
+            (true, _) => "connected".to_string(),
+            (false, Some(error)) => format!("disconnected: {}", error),
+            (false, None) => "connecting".to_string(),
+        };
+        writeln!(screen, "webmetro monitor - {} ({})", url, status).unwrap();
+        let live = self.channels.values().filter(|row| row.live).count();
+        let listeners: usize = self.channels.values().map(|row| row.listeners).sum();
+        writeln!(screen, "{} channels, {} live, {} listeners\n", self.channels.len(), live, listeners).unwrap();
+        writeln!(screen, "{:<24} {:<6} {:>9} {:>12} {:>9} {:>7}  TITLE", "CHANNEL", "SOURCE", "LISTENERS", "BITRATE", "LAG", "LAGGING").unwrap();
+        for (name, row) in &self.channels {
+            let bitrate = row.bits_per_second.map_or("-".to_string(), |bps| format!("{} kbps", bps / 1000));
+            let title: String = self.titles.get(name).map_or("", String::as_str).chars().take(TITLE_WIDTH).collect();
+            writeln!(screen, "{:<24} {:<6} {:>9} {:>12} {:>9} {:>7}  {}",
+                name,
+                if row.live { "live" } else { "-" },
+                row.listeners,
+                bitrate,
+                format!("{} ms", row.max_lag),
+                row.lagging,
+                title).unwrap();
+        }
+        screen
+    }
+}
+
+fn get(url: &str, token: Option<&str>) -> Result<Request<Body>, WebmetroError> {
+    let mut request = Request::get(url);
+    if let Some(token) = token {
+        request = request.header("Authorization", format!("Bearer {}", token));
+    }
+    Ok(request.body(Body::empty())?)
+}
+
+/// Follow the relay's event stream, reconnecting whenever it's lost
+async fn follow_events(client: HttpClient, base: String, token: Option<String>, dashboard: Arc<Mutex<Dashboard>>) {
+    loop {
+        let result = read_events(&client, &base, token.as_deref(), &dashboard).await;
+        {
+            let mut dashboard = dashboard.lock().expect("Locking dashboard");
+            dashboard.connected = false;
+            dashboard.error = Some(match result {
+                Ok(()) => "the relay ended the event stream".to_string(),
+                Err(err) => err.to_string(),
+            });
+        }
+        delay_for(RECONNECT_DELAY).await;
+    }
+}
+
+/// Apply each event from the relay's event stream to the dashboard, until the stream ends
+async fn read_events(client: &HttpClient, base: &str, token: Option<&str>, dashboard: &Mutex<Dashboard>) -> Result<(), WebmetroError> {
+    let response = client.request(get(&format!("{}/events", base), token)?).await?;
+    if !response.status().is_success() {
+        return Err(WebmetroError::from_response(&response));
+    }
+    {
+        // the relay starts with a snapshot, so what was known is replaced
+        let mut dashboard = dashboard.lock().expect("Locking dashboard");
+        dashboard.channels.clear();
+        dashboard.titles.clear();
+        dashboard.connected = true;
+        dashboard.error = None;
+    }
+
+    let mut body = response.into_body();
+    let mut pending = BytesMut::new();
+    while let Some(bytes) = body.next().await {
+        pending.extend_from_slice(&bytes?);
+        while let Some(end) = pending.iter().position(|&byte| byte == b'\n') {
+            let line = pending.split_to(end + 1);
+            let line = String::from_utf8_lossy(&line);
+            // keepalives are comments, and messages are single data lines
+            if let Some(event) = line.trim_end().strip_prefix("data: ").and_then(Event::from_json) {
+                dashboard.lock().expect("Locking dashboard").apply(event);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Fetch a URL's whole body
+async fn fetch(client: &HttpClient, url: &str, token: Option<&str>) -> Result<Bytes, WebmetroError> {
+    let response = client.request(get(url, token)?).await?;
+    if !response.status().is_success() {
+        return Err(WebmetroError::from_response(&response));
+    }
+    Ok(hyper::body::to_bytes(response.into_body()).await?)
+}
+
+/// Ask the relay how far behind each channel's listeners have fallen; how
+/// many there are comes from the event stream
+async fn poll_listeners(client: &HttpClient, base: &str, token: Option<&str>, dashboard: &Mutex<Dashboard>) {
+    let channels: Vec<String> = dashboard.lock().expect("Locking dashboard").channels.keys().cloned().collect();
+    for channel in channels {
+        let listeners = match fetch(client, &format!("{}/live/{}/listeners", base, channel), token).await {
+            Ok(body) => match serde_json::from_slice::<Vec<serde_json::Value>>(&body) {
+                Ok(listeners) => listeners,
+                Err(err) => {
+                    debug!("Couldn't read channel {}'s listener stats: {}", channel, err);
+                    continue;
+                }
+            },
+            Err(err) => {
+                debug!("Couldn't fetch channel {}'s listener stats: {}", channel, err);
+                continue;
+            }
+        };
+        let max_lag = listeners.iter()
+            .filter_map(|listener| listener.get("queued_ms").and_then(serde_json::Value::as_u64))
+            .max()
+            .unwrap_or(0);
+        let lagging = listeners.iter()
+            .filter(|listener| listener.get("lagging").and_then(serde_json::Value::as_bool) == Some(true))
+            .count();
+        if let Some(row) = dashboard.lock().expect("Locking dashboard").channels.get_mut(&channel) {
+            row.max_lag = max_lag;
+            row.lagging = lagging;
+        }
+    }
+}
+
+/// Redraw the dashboard every `refresh`, until the terminal can't be written to
+async fn draw(client: &HttpClient, base: &str, token: Option<&str>, dashboard: &Mutex<Dashboard>, refresh: Duration) -> Result<(), WebmetroError> {
+    let mut ticks = interval(refresh);
+    loop {
+        ticks.tick().await;
+        poll_listeners(client, base, token, dashboard).await;
+        let screen = dashboard.lock().expect("Locking dashboard").render(base);
+        let mut out = stdout();
+        out.write_all(screen.as_bytes())?;
+        out.flush()?;
+    }
+}
+
+#[tokio::main]
+pub async fn run(args: &ArgMatches) -> Result<(), WebmetroError> {
+    let base = args.value_of("url").ok_or("Relay URL wasn't provided")?.trim_end_matches('/').to_string();
+    if !base.starts_with("http://") && !base.starts_with("https://") {
+        return Err("The relay URL should start with http:// or https://".into());
+    }
+    let token = args.value_of("token").map(str::to_string);
+    let refresh = match seconds(args.value_of("interval"))? {
+        Some(refresh) if refresh > Duration::from_millis(0) => refresh,
+        _ => return Err("The refresh interval must be more than 0 seconds".into()),
+    };
+
+    let client = Client::builder().build::<_, Body>(HttpsConnector::new());
+    let dashboard = Arc::new(Mutex::new(Dashboard::default()));
+    let events = follow_events(client.clone(), base.clone(), token.clone(), dashboard.clone());
+
+    let screen = draw(&client, &base, token.as_deref(), &dashboard, refresh);
+
+    // draw on the alternate screen, so quitting leaves the terminal as it was
+    print!("\x1b[?1049h");
+    let result = match select(select(Box::pin(events), Box::pin(screen)), Box::pin(tokio::signal::ctrl_c())).await {
+        Either::Left((Either::Right((result, _)), _)) => result,
+        Either::Left((Either::Left(((), _)), _)) => Ok(()),
+        Either::Right((result, _)) => result.map_err(WebmetroError::from),
+    };
+    print!("\x1b[?1049l");
+    stdout().flush()?;
+    result
+}
@@ -0,0 +1,32 @@
+use std::io::stdout;
+use std::path::PathBuf;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use tokio::fs::File;
+
+use super::{looped_chunk_stream, send::upload, BoxedChunkStream};
+use webmetro::{error::WebmetroError, fixers::ChunkStream};
+
+pub fn options() -> App<'static, 'static> {
+    SubCommand::with_name("play")
+        .about("Loops a WebM file forever, PUTting it to a relay server at real-time speed as a stand-in for a live source.")
+        .arg(Arg::with_name("file")
+            .help("The WebM file to play")
+            .required(true))
+        .arg(Arg::with_name("url")
+            .help("The location to upload to")
+            .required(true))
+}
+
+#[tokio::main]
+pub async fn run(args: &ArgMatches) -> Result<(), WebmetroError> {
+    let path = PathBuf::from(args.value_of("file").ok_or("File to play wasn't provided")?);
+    let url_str = args.value_of("url").ok_or("Upload location wasn't provided")?;
+
+    // fail early on a bad path, instead of on every pass
+    File::open(&path).await?;
+
+    let chunk_stream: BoxedChunkStream = Box::new(looped_chunk_stream(path).throttle());
+
+    upload(url_str, chunk_stream, None, std::u32::MAX, stdout()).await
+}
@@ -0,0 +1,242 @@
+use std::collections::{HashMap, HashSet};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+
+use super::{input_stream, BUFFER_LIMIT};
+use webmetro::{
+    error::WebmetroError,
+    stream_parser::StreamEbml,
+    webm::{parse_tracks, SimpleBlock, WebmElement},
+};
+
+pub fn options() -> App<'static, 'static> {
+    SubCommand::with_name("probe")
+        .about("Checks that a WebM file or stream is suitable for relaying, reporting each problem found with its byte offset.")
+        .arg(Arg::with_name("input")
+            .help("The file or http(s) URL to check; reads stdin if omitted"))
+        .arg(Arg::with_name("keyframe_interval")
+            .takes_value(true)
+            .long("max-keyframe-interval")
+            .default_value("10")
+            .help("Complain if video keyframes are more than n seconds apart, since new viewers must wait for one"))
+}
+
+#[derive(PartialEq)]
+enum Level {
+    Start,
+    EbmlHead,
+    Segment,
+    Cluster,
+}
+
+struct Probe {
+    max_keyframe_interval: i64,
+    violations: usize,
+    level: Level,
+    seen_tracks: bool,
+    seen_cluster: bool,
+    video_tracks: HashSet<u64>,
+    cluster_start: u64,
+    cluster_timecode: Option<u64>,
+    cluster_has_blocks: bool,
+    last_cluster_timecode: Option<u64>,
+    track_timecodes: HashMap<u64, i64>,
+    last_keyframe: Option<i64>,
+}
+
+impl Probe {
+    fn new(max_keyframe_interval: i64) -> Probe {
+        Probe {
+            max_keyframe_interval,
+            violations: 0,
+            level: Level::Start,
+            seen_tracks: false,
+            seen_cluster: false,
+            video_tracks: HashSet::new(),
+            cluster_start: 0,
+            cluster_timecode: None,
+            cluster_has_blocks: false,
+            last_cluster_timecode: None,
+            track_timecodes: HashMap::new(),
+            last_keyframe: None,
+        }
+    }
+
+    fn report(&mut self, offset: u64, message: String) {
+        println!("{:>10}: {}", offset, message);
+        self.violations += 1;
+    }
+
+    fn end_cluster(&mut self, offset: u64) {
+        if self.level != Level::Cluster {
+            return;
+        }
+        let size = offset - self.cluster_start;
+        if size > BUFFER_LIMIT as u64 {
+            self.report(self.cluster_start, format!("Cluster is {} bytes, over the relay's {} byte limit", size, BUFFER_LIMIT));
+        }
+        if self.cluster_timecode.is_none() {
+            self.report(self.cluster_start, "Cluster has no Timecode".into());
+        }
+        self.level = Level::Segment;
+    }
+
+    fn observe(&mut self, offset: u64, element: WebmElement) {
+        match element {
+            WebmElement::EbmlHead => {
+                self.end_cluster(offset);
+                if self.level != Level::Start && !self.seen_cluster {
+                    self.report(offset, "EBML header restarts a stream that never reached its first Cluster".into());
+                }
+                // a new (chained) stream starts here; its timecodes are judged on their own
+                let max_keyframe_interval = self.max_keyframe_interval;
+                let violations = self.violations;
+                *self = Probe::new(max_keyframe_interval);
+                self.violations = violations;
+                self.level = Level::EbmlHead;
+            },
+            WebmElement::Segment => {
+                if self.level != Level::EbmlHead {
+                    self.report(offset, "Segment not directly after an EBML header".into());
+                }
+                self.level = Level::Segment;
+            },
+            WebmElement::Cluster => {
+                self.end_cluster(offset);
+                if self.level != Level::Segment {
+                    self.report(offset, "Cluster outside of a Segment".into());
+                } else if !self.seen_tracks {
+                    self.report(offset, "Cluster before any Tracks".into());
+                }
+                self.seen_cluster = true;
+                self.level = Level::Cluster;
+                self.cluster_start = offset;
+                self.cluster_timecode = None;
+                self.cluster_has_blocks = false;
+            },
+            WebmElement::Timecode(timecode) => {
+                if self.level != Level::Cluster {
+                    self.report(offset, "Timecode outside of a Cluster".into());
+                    return;
+                }
+                if self.cluster_has_blocks || self.cluster_timecode.is_some() {
+                    self.report(offset, "Timecode is not the first element of its Cluster".into());
+                }
+                if let Some(last) = self.last_cluster_timecode {
+                    if timecode < last {
+                        self.report(offset, format!("Cluster timecode {} goes backwards from {}", timecode, last));
+                    }
+                }
+                self.cluster_timecode = Some(timecode);
+                self.last_cluster_timecode = Some(timecode);
+            },
+            WebmElement::SimpleBlock(_) | WebmElement::BlockGroup(_) => {
+                let (track, timecode, keyframe) = match element {
+                    WebmElement::SimpleBlock(SimpleBlock { track, timecode, flags, .. }) => (track, timecode, flags & 0b10000000 != 0),
+                    WebmElement::BlockGroup(group) => (group.block.track, group.block.timecode, group.keyframe),
+                    _ => return
+                };
+                let cluster_timecode = match (&self.level, self.cluster_timecode) {
+                    (Level::Cluster, Some(cluster_timecode)) => cluster_timecode,
+                    (Level::Cluster, None) => {
+                        self.report(offset, "Block before its Cluster's Timecode".into());
+                        return;
+                    },
+                    _ => {
+                        self.report(offset, "Block outside of a Cluster".into());
+                        return;
+                    }
+                };
+                self.cluster_has_blocks = true;
+
+                let absolute = cluster_timecode as i64 + timecode as i64;
+                if let Some(&last) = self.track_timecodes.get(&track) {
+                    if absolute < last {
+                        self.report(offset, format!("Track {} block timecode {} goes backwards from {}", track, absolute, last));
+                    }
+                }
+                self.track_timecodes.insert(track, absolute);
+
+                if self.video_tracks.contains(&track) {
+                    match (keyframe, self.last_keyframe) {
+                        (true, Some(last)) if absolute - last > self.max_keyframe_interval => {
+                            self.report(offset, format!("{} ms between video keyframes", absolute - last));
+                        },
+                        (false, None) => {
+                            self.report(offset, "Video starts without a keyframe".into());
+                            // only complain once
+                            self.last_keyframe = Some(absolute);
+                        },
+                        _ => {}
+                    }
+                    if keyframe {
+                        self.last_keyframe = Some(absolute);
+                    }
+                }
+            },
+            WebmElement::Tracks(tracks) => {
+                if self.level != Level::Segment {
+                    self.report(offset, "Tracks outside of a Segment's top level".into());
+                }
+                if self.seen_cluster {
+                    self.report(offset, "Tracks after the first Cluster will not reach viewers".into());
+                }
+                self.seen_tracks = true;
+                self.video_tracks = parse_tracks(tracks).into_iter()
+                    .filter(|track| track.is_video())
+                    .map(|track| track.number)
+                    .collect();
+            },
+            WebmElement::SeekHead | WebmElement::Info(_) | WebmElement::Cues => {
+                self.end_cluster(offset);
+                if self.level != Level::Segment {
+                    self.report(offset, format!("{:?} outside of a Segment", element));
+                }
+            },
+            WebmElement::Skipped { length, .. } => {
+                self.report(offset, format!("{} bytes of corrupt data skipped", length));
+            },
+            WebmElement::Void | WebmElement::Unknown(_) => {}
+        }
+    }
+}
+
+#[tokio::main]
+pub async fn run(args: &ArgMatches) -> Result<(), WebmetroError> {
+    let max_keyframe_interval: i64 = match args.value_of("keyframe_interval").unwrap_or("10").parse() {
+        Ok(seconds) if seconds > 0 => seconds,
+        _ => return Err("--max-keyframe-interval must be a positive whole number of seconds".into()),
+    };
+
+    let mut probe = Probe::new(max_keyframe_interval * 1000);
+    let mut events = input_stream(args.value_of("input")).await?.parse_ebml().with_resync();
+
+    loop {
+        let offset = events.offset();
+        match events.next().await {
+            Ok(Some(element)) => probe.observe(offset, element),
+            Ok(None) => {
+                probe.end_cluster(offset);
+                if probe.level == Level::Start {
+                    probe.report(offset, "No EBML header found".into());
+                }
+                break;
+            },
+            Err(err) => {
+                probe.report(offset, format!("Parsing stopped: {}", err));
+                break;
+            }
+        }
+    }
+
+    match probe.violations {
+        0 => {
+            println!("No problems found");
+            Ok(())
+        },
+        count => Err(WebmetroError::ApplicationError {
+            message: format!("{} isn't suitable for relaying: {} problem(s) found, as reported above",
+                args.value_of("input").unwrap_or("stdin"), count),
+        })
+    }
+}
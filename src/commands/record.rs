@@ -0,0 +1,120 @@
+use std::path::PathBuf;
+use std::time::Duration;
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+use tokio::{signal::ctrl_c, time::delay_for};
+
+use super::{create_file, http_stream, input_stream, numbered_path, parse_size, parse_time};
+use webmetro::{
+    chunk::WebmStream,
+    error::WebmetroError,
+    recorder::Archiver,
+    stream_parser::StreamEbml,
+};
+
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+pub fn options() -> App<'static, 'static> {
+    SubCommand::with_name("record")
+        .about("Records a relay channel to finalized WebM files, starting a new file whenever the stream restarts.")
+        .arg(Arg::with_name("url")
+            .help("The channel URL to record from, reconnecting whenever the stream ends; a file path (or \"-\" for stdin) is recorded once")
+            .required(true))
+        .arg(Arg::with_name("output")
+            .help("The file to write; later files are numbered, e.g. out-1.webm, out-2.webm")
+            .required_unless("output_file"))
+        .arg(Arg::with_name("output_file")
+            .takes_value(true)
+            .value_name("output")
+            .short("o")
+            .long("output")
+            .conflicts_with("output")
+            .help("The file to write, as an alternative to giving it after the URL"))
+        .arg(Arg::with_name("segment_duration")
+            .takes_value(true)
+            .long("segment-duration")
+            .help("Start a new file at the first keyframe after a file spans this many seconds (e.g. 3600 for hourly files)"))
+        .arg(Arg::with_name("segment_size")
+            .takes_value(true)
+            .long("segment-size")
+            .help("Start a new file at the first keyframe after a file reaches this size (e.g. 500M)"))
+}
+
+#[tokio::main]
+pub async fn run(args: &ArgMatches) -> Result<(), WebmetroError> {
+    let url_str = args.value_of("url").ok_or("Channel URL wasn't provided")?;
+    let output = args.value_of("output").or_else(|| args.value_of("output_file"));
+    let output = PathBuf::from(output.ok_or("Output file wasn't provided")?);
+
+    let segment_duration = parse_time(args.value_of("segment_duration"))?;
+    let segment_size = parse_size(args.value_of("segment_size"))?;
+
+    let mut stop = ctrl_c().boxed();
+    // a new initialization segment means the stream restarted, so the archiver rotates files
+    let mut archiver = Archiver::new(|index| {
+        let path = numbered_path(&output, index);
+        info!("Recording to {}", path.display());
+        create_file(&path)
+    });
+    if let Some(duration) = segment_duration {
+        archiver = archiver.max_duration(duration.as_millis() as u64);
+    }
+    if let Some(size) = segment_size {
+        archiver = archiver.max_size(size);
+    }
+
+    if !url_str.starts_with("http://") && !url_str.starts_with("https://") {
+        let mut chunk_stream = input_stream(Some(url_str)).await?.parse_ebml().chunk_webm();
+        loop {
+            let next_chunk = tokio::select! {
+                chunk = chunk_stream.next() => chunk,
+                _ = &mut stop => break,
+            };
+            match next_chunk {
+                Some(chunk) => archiver.write(&chunk?)?,
+                None => break,
+            }
+        }
+        return archiver.finish_file();
+    }
+
+    loop {
+        let mut chunk_stream = match http_stream(url_str).await {
+            Ok(stream) => stream.parse_ebml().chunk_webm(),
+            // e.g. refused credentials, which won't do any better next time
+            Err(err) if !err.is_retryable() => return Err(err),
+            Err(err) => {
+                // wait at least as long as the server asked
+                let wait = err.retry_after().map_or(RECONNECT_DELAY, |retry_after| retry_after.max(RECONNECT_DELAY));
+                warn!("{}, reconnecting in {:?}", err, wait);
+                tokio::select! {
+                    _ = delay_for(wait) => continue,
+                    _ = &mut stop => return archiver.finish_file(),
+                }
+            }
+        };
+
+        loop {
+            let next_chunk = tokio::select! {
+                chunk = chunk_stream.next() => chunk,
+                _ = &mut stop => {
+                    return archiver.finish_file();
+                }
+            };
+
+            match next_chunk {
+                Some(Ok(chunk)) => archiver.write(&chunk)?,
+                Some(Err(err)) => {
+                    warn!("{}", err);
+                    break;
+                },
+                None => break,
+            }
+        }
+
+        archiver.finish_file()?;
+        info!("Stream ended, reconnecting");
+        delay_for(RECONNECT_DELAY).await;
+    }
+}
@@ -1,136 +1,2533 @@
-use std::net::ToSocketAddrs;
-use std::sync::{
-    Arc,
-    Mutex,
-    Weak
-};
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fs::File;
+use std::io::BufWriter;
+use std::net::{IpAddr, SocketAddr, TcpListener, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
-use bytes::{Bytes, Buf};
+use bytes::{Buf, Bytes};
 use clap::{App, Arg, ArgMatches, SubCommand};
 use futures::{
+    future::Either,
     prelude::*,
-    Stream,
-    stream::FuturesUnordered,
+    stream::{self, FuturesUnordered},
 };
 use hyper::{
     Body,
+    Method,
     Response,
-    header::{
-        CACHE_CONTROL,
-        CONTENT_TYPE
-    }
+    Server,
+    StatusCode,
+    header::{HeaderMap, HeaderName, HeaderValue},
+    server::conn::AddrStream,
+    service::{make_service_fn, service_fn, Service},
+    Request,
+    Uri,
 };
-use stream::iter;
+use socket2::{Domain, Protocol, SockAddr, Socket, Type};
+use tokio::{runtime::Builder, task::spawn_blocking, time::{delay_for, timeout}};
+use tracing_futures::Instrument;
 use warp::{
     self,
+    filters::BoxedFilter,
+    ws::Ws,
     Filter,
-    path
-};
-use weak_table::{
-    WeakValueHashMap
+    Reply,
 };
+
+use super::{numbered_path, parse_size, parse_time, send::stream_to, BUFFER_LIMIT};
 use webmetro::{
+    access_log::{AccessLog, AccessLogger, Session},
+    catalog::{is_recording, list_recordings},
     channel::{
-        Channel,
-        Handle,
-        Listener,
-        Transmitter
+        LagPolicy,
+        DEFAULT_QUEUE_LIMIT,
     },
-    chunk::WebmStream,
+    chunk::Chunk,
+    cluster::{cluster, RedisAddress},
+    config_store::{ChannelConfig, ConfigStore, KeyAuthorizer},
+    edge::{Edge, EdgeOptions},
     error::WebmetroError,
-    fixers::{
-        ChunkStream,
-        ChunkTimecodeFixer,
+    fixers::{AudioChoice, IdlePadding, IdleTimeout},
+    history::{history_json, History, DEFAULT_SESSIONS_KEPT},
+    hls::{HlsOptions, HlsPackager},
+    hooks::{hook_runner, Hook, HookEvent, HookOptions, HookQueue},
+    jwt::JwtAuthorizer,
+    metadata::ChannelMetadata,
+    recorder::Archiver,
+    server::{
+        certificate_names,
+        check_source,
+        manifest_json,
+        redact_token,
+        rendition_channel,
+        simulcast_json,
+        AccessRequest,
+        Action,
+        AdminToken,
+        AllowAll,
+        Authorizer,
+        BitrateAction,
+        CertificateAuthorizer,
+        Holding,
+        MediaFormat,
+        Relay,
+        RelayOptions,
+        Route,
+        SourceOptions,
+        TokenBucket,
+        Viewer,
+        DEFAULT_GAP_THRESHOLD,
+        DEFAULT_MAX_PAUSE,
     },
-    stream_parser::StreamEbml
-, chunk::Chunk};
+    statsd::{statsd, StatsdOptions},
+    transcode::{transcoder, Transcode, TranscodeOptions},
+    upload::{upload_queue, Credentials, Retention, S3Target, UploadQueue},
+    webhook::{WebhookAuthorizer, WebhookOptions},
+};
+
+/// What a viewer's GET (or HEAD) asks for
+#[derive(Clone, Copy)]
+enum Egress {
+    /// the WebM stream, labeled as WebM or Matroska
+    Stream(MediaFormat),
+    /// fragmented MP4, at /live/<channel>/cmaf
+    Cmaf,
+    /// Ogg Opus, at /live/<channel>.ogg
+    Ogg,
+    /// WebM Clusters without the initialization segment, at /live/<channel>/media
+    Media,
+}
+
+impl Egress {
+    /// The Content-Type to send instead of the configured one, if any
+    fn content_type(&self) -> Option<&'static str> {
+        match self {
+            Egress::Stream(MediaFormat::WebM) | Egress::Media => None,
+            Egress::Stream(format) => Some(format.content_type()),
+            Egress::Cmaf => Some("video/mp4"),
+            Egress::Ogg => Some("audio/ogg"),
+        }
+    }
+}
+
+fn media_response(relay: &Relay, egress: Egress, body: Body) -> Response<Body> {
+    let mut response = Response::builder()
+        // the Content-Type depends on the Accept header
+        .header("Vary", "Accept");
+    for (name, value) in relay.options().media_headers.iter() {
+        let value = match egress.content_type() {
+            Some(content_type) if name.eq_ignore_ascii_case("Content-Type") => content_type,
+            _ => value.as_str(),
+        };
+        response = response.header(name.as_str(), value);
+    }
+    response.body(body).unwrap()
+}
+
+fn forbidden() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::FORBIDDEN)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Refuse a request: with 401 if it didn't present any credentials, so a
+/// client knows to supply some, or 403 if the ones it did were refused
+fn denied(request: &AccessRequest) -> Response<Body> {
+    if request.credentials.is_some() || !request.client_names.is_empty() {
+        return forbidden();
+    }
+    Response::builder()
+        .status(StatusCode::UNAUTHORIZED)
+        .header("WWW-Authenticate", "Bearer")
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Refuse a source whose stream failed before its response began
+fn refused_source(err: &WebmetroError) -> Response<Body> {
+    Response::builder()
+        .status(err.status_code())
+        .body(Body::from(format!("{}\n", err)))
+        .unwrap()
+}
+
+fn too_many_requests() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::TOO_MANY_REQUESTS)
+        .body(Body::empty())
+        .unwrap()
+}
+
+fn redirect(channel: &str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::TEMPORARY_REDIRECT)
+        .header("Location", format!("/live/{}", channel))
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Point a request at the channel its name is an alias for, or answer with a redirect
+fn resolve_alias(relay: &Relay, mut request: AccessRequest) -> Result<AccessRequest, Response<Body>> {
+    match relay.resolve(&request.channel) {
+        Route::Channel(channel) => {
+            request.channel = channel;
+            Ok(request)
+        },
+        Route::Redirect(channel) => Err(redirect(&channel)),
+    }
+}
+
+fn server_error() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::INTERNAL_SERVER_ERROR)
+        .body(Body::empty())
+        .unwrap()
+}
+
+/// Serves finalized recordings from `dir` under /vod/, with a plain-text
+/// listing (one "name<TAB>size" line per file) at /vod/ itself. Files are
+/// served with Content-Length, and Range requests for seeking are honored.
+fn vod_routes(dir: PathBuf) -> BoxedFilter<(Response<Body>,)> {
+    let list_dir = dir.clone();
+    let index = warp::path!("vod")
+        .and(warp::get().or(warp::head()).unify())
+        .and_then(move || {
+            let dir = list_dir.clone();
+            async move {
+                match spawn_blocking(move || list_recordings(&dir)).await {
+                    Ok(Ok(recordings)) => {
+                        let listing: String = recordings.iter()
+                            .map(|recording| format!("{}\t{}\n", recording.name, recording.size))
+                            .collect();
+                        Ok::<_, Infallible>(Response::builder()
+                            .header("Content-Type", "text/plain; charset=utf-8")
+                            .body(Body::from(listing))
+                            .unwrap())
+                    },
+                    Ok(Err(err)) => {
+                        warn!("Couldn't list recordings: {}", err);
+                        Ok(server_error())
+                    },
+                    Err(err) => {
+                        warn!("Couldn't list recordings: {}", err);
+                        Ok(server_error())
+                    },
+                }
+            }
+        });
+
+    let check_dir = dir.clone();
+    let file = warp::path("vod")
+        .and(warp::get().or(warp::head()).unify())
+        .and(warp::path::peek())
+        // only finalized recordings may be fetched, not ones still being written
+        .and_then(move |tail: warp::path::Peek| {
+            let dir = check_dir.clone();
+            let name = tail.as_str().to_string();
+            async move {
+                match spawn_blocking(move || is_recording(&dir, &name)).await {
+                    Ok(true) => Ok(()),
+                    _ => Err(warp::reject::not_found()),
+                }
+            }
+        })
+        .untuple_one()
+        .and(warp::fs::dir(dir));
+
+    index
+        .or(file.map(Reply::into_response))
+        .unify()
+        .boxed()
+}
+
+/// Where & how the relay records channels
+#[derive(Clone)]
+struct RecordSettings {
+    dir: PathBuf,
+    segment_duration: Option<u64>,
+    segment_size: Option<u64>,
+    /// where to send finished files for uploading, if anywhere
+    upload: Option<UploadQueue>,
+    /// hooks to run as files are finished
+    hooks: Option<HookQueue>,
+}
+
+/// Write a channel's recording to files in the record directory, named for
+/// the channel and when recording started, noting each in the channel's
+/// session history
+async fn record_channel(settings: RecordSettings, history: Arc<History>, channel: String, chunks: impl Stream<Item = Result<Chunk, WebmetroError>>) {
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+    // channel names come from URLs, so keep only characters that are safe in a file name
+    let safe_name: String = channel.chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '_' { c } else { '_' })
+        .collect();
+    let base = settings.dir.join(format!("{}-{}.webm", safe_name, started));
+
+    let mut archiver = Archiver::new(|index| {
+        let path = numbered_path(&base, index);
+        info!("Recording to {}", path.display());
+        history.add_recording(&channel, &path.display().to_string());
+        Ok(BufWriter::new(File::create(&path)?))
+    });
+    if let Some(duration) = settings.segment_duration {
+        archiver = archiver.max_duration(duration);
+    }
+    if let Some(size) = settings.segment_size {
+        archiver = archiver.max_size(size);
+    }
+    if settings.upload.is_some() || settings.hooks.is_some() {
+        let (upload, hooks) = (settings.upload, settings.hooks);
+        let (base, channel) = (base.clone(), channel.clone());
+        archiver = archiver.on_finish(move |index| {
+            let path = numbered_path(&base, index);
+            if let Some(ref hooks) = hooks {
+                hooks.run(&HookEvent::recording_finished(&channel, path.clone()));
+            }
+            if let Some(ref upload) = upload {
+                upload.push(path);
+            }
+        });
+    }
+    let mut chunks = Box::pin(chunks);
+    while let Some(chunk) = chunks.next().await {
+        if let Err(err) = chunk.and_then(|chunk| archiver.write(&chunk)) {
+            warn!("Recording failed: {}", err);
+            break;
+        }
+    }
+    if let Err(err) = archiver.finish_file() {
+        warn!("Recording failed: {}", err);
+    }
+    info!("Recording stopped");
+}
+
+/// Switch recording a channel on or off, spawning a task to write the files
+/// if that starts a new recording. Returns whether it did.
+fn set_recording(relay: &Relay, settings: &RecordSettings, channel: &str, enabled: bool) -> bool {
+    match relay.set_recording(channel, enabled) {
+        Some(chunks) => {
+            let span = info_span!("recorder", channel = %channel);
+            tokio::spawn(record_channel(settings.clone(), relay.history().clone(), channel.to_string(), chunks).instrument(span));
+            true
+        },
+        None => false
+    }
+}
+
+fn is_switched_on(value: &str) -> bool {
+    matches!(value, "1" | "true" | "on" | "yes")
+}
+
+/// Start logging a request's session, if there's an access log
+fn log_session(access_log: &Option<Arc<AccessLogger>>, request: &AccessRequest) -> Option<Session> {
+    access_log.as_ref().map(|log| log.session(request.action, &request.channel, request.remote))
+}
+
+/// How a source asked to publish, from its query parameters
+#[derive(Clone, Copy)]
+struct PublishOptions {
+    /// record the channel while this source publishes (if the relay records at all)
+    record: bool,
+    /// replace the channel's current source, instead of being refused
+    takeover: bool,
+    /// pause the channel when this source disconnects, holding its listeners
+    /// until the next source
+    pause: bool,
+}
+
+/// A publisher's metadata for its channel, from its ?meta=<json> parameter
+fn query_metadata(query: &HashMap<String, String>) -> Result<Option<ChannelMetadata>, WebmetroError> {
+    query.get("meta").map(|json| ChannelMetadata::from_json(json)).transpose()
+}
+
+impl PublishOptions {
+    fn from_query(query: &HashMap<String, String>, record_all: bool) -> PublishOptions {
+        PublishOptions {
+            record: query.get("record").map(|value| is_switched_on(value)).unwrap_or(record_all),
+            takeover: query.get("takeover").map_or(false, |value| is_switched_on(value)),
+            pause: query.get("pause").map_or(false, |value| is_switched_on(value)),
+        }
+    }
+}
+
+/// How long the relay waits on a quiet or disconnected source
+#[derive(Clone, Copy, Default)]
+struct SourceTimeouts {
+    /// fail a source that sends nothing for this long
+    idle: Option<Duration>,
+    /// how long a failed source's listeners wait for it to reconnect
+    reconnect_grace: Option<Duration>,
+}
+
+fn channel_busy() -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::CONFLICT)
+        .body(Body::from("The channel already has a source; publish with ?takeover=1 to replace it\n"))
+        .unwrap()
+}
+
+/// Publish a source's stream to a channel, recording it too if asked & the
+/// relay records at all; a recording started for this source ends along with
+/// it. If the source fails, its listeners are ended once the reconnect grace
+/// is up, unless it's reconnected by then. Should be called in the
+/// publisher's span.
+fn publish_source<I: Buf, E, S>(relay: &Arc<Relay>, record_settings: Option<&RecordSettings>, options: PublishOptions, timeouts: SourceTimeouts, channel: &str, body: S) -> impl Future<Output = Result<(), WebmetroError>>
+where
+    S: Stream<Item = Result<I, E>> + Unpin,
+    WebmetroError: From<E>,
+{
+    let body = match timeouts.idle {
+        Some(timeout) => Either::Left(IdleTimeout::new(body, timeout)),
+        None => Either::Right(body.map_err(WebmetroError::from)),
+    };
+    let recording = match record_settings {
+        Some(settings) if options.record => set_recording(relay, settings, channel, true),
+        _ => false
+    };
+    let stop_relay = relay.clone();
+    let channel = channel.to_string();
+    if options.takeover {
+        info!("Taking over Channel {}", channel);
+    }
+    let source_options = SourceOptions {
+        takeover: options.takeover,
+        pause_on_end: options.pause,
+    };
+    relay.publish_with(&channel, body, source_options)
+        .inspect(move |result| {
+            if recording {
+                stop_relay.set_recording(&channel, false);
+            }
+            match (result, timeouts.reconnect_grace) {
+                (Err(_), Some(grace)) => {
+                    info!("Holding listeners on Channel {} for {}s in case the source reconnects", channel, grace.as_secs());
+                    // a source that arrives meanwhile keeps them, even if it's gone again by then
+                    let generation = stop_relay.source_generation(&channel);
+                    let relay = stop_relay.clone();
+                    let channel = channel.clone();
+                    tokio::spawn(async move {
+                        delay_for(grace).await;
+                        relay.end_listeners_since(&channel, generation);
+                    });
+                },
+                (Ok(()), Some(_)) => stop_relay.end_listeners(&channel),
+                (_, None) => {}
+            }
+        })
+}
+
+/// Lets recording be switched on (PUT) or off (DELETE) for each channel under
+/// /record/, while GET reports whether it's on
+fn record_routes(relay: Arc<Relay>, settings: RecordSettings) -> BoxedFilter<(Response<Body>,)> {
+    let method = warp::get().map(|| None::<bool>)
+        .or(warp::put().map(|| Some(true))).unify()
+        .or(warp::delete().map(|| Some(false))).unify();
+
+    method.and(access_request(Action::Record))
+        .and_then(move |switch: Option<bool>, request: AccessRequest| {
+            let relay = relay.clone();
+            let settings = settings.clone();
+            async move {
+                if !relay.authorize(&request).await {
+                    return Ok::<_, Infallible>(forbidden());
+                }
+                if let Some(enabled) = switch {
+                    info!("Recording switched {} for Channel {}", if enabled { "on" } else { "off" }, request.channel);
+                    set_recording(&relay, &settings, &request.channel, enabled);
+                }
+                let state = if relay.is_recording(&request.channel) { "on\n" } else { "off\n" };
+                Ok(Response::builder()
+                    .header("Content-Type", "text/plain; charset=utf-8")
+                    .body(Body::from(state))
+                    .unwrap())
+            }
+        })
+        .boxed()
+}
+
+/// Start mirroring a channel to `target` (or stop, with None), spawning a task
+/// that POSTs its stream there until the mirror is switched off or retargeted.
+/// Dropped connections are retried indefinitely, resuming at a keyframe.
+fn set_mirror(relay: &Relay, channel: &str, target: Option<&str>) {
+    let chunks = match relay.set_mirror(channel, target) {
+        Some(chunks) => chunks,
+        None => return,
+    };
+    let target = target.unwrap_or_default().to_string();
+    let span = info_span!("mirror", channel = %channel, target = %redact_token(&target));
+    tokio::spawn(async move {
+        info!("Mirroring started");
+        match stream_to(Method::POST, &target, Box::pin(chunks), std::u32::MAX).await {
+            Ok(_) => info!("Mirroring stopped"),
+            Err(err) => warn!("Mirroring failed: {}", err),
+        }
+    }.instrument(span));
+}
 
-const BUFFER_LIMIT: usize = 2 * 1024 * 1024;
+/// Only absolute http(s) URLs make sense as mirror targets
+fn is_mirror_target(target: &str) -> bool {
+    match target.parse::<Uri>() {
+        Ok(uri) => matches!(uri.scheme_str(), Some("http") | Some("https")) && uri.host().is_some(),
+        Err(_) => false,
+    }
+}
+
+/// Lets each channel under /mirror/ be mirrored to another server: a PUT with
+/// the target URL as its body starts (or retargets) the mirror, a DELETE
+/// stops it, and GET reports the current target
+fn mirror_routes(relay: Arc<Relay>) -> BoxedFilter<(Response<Body>,)> {
+    let method = warp::get().map(|| None::<Option<String>>)
+        .or(warp::put()
+            .and(warp::body::content_length_limit(4096))
+            .and(warp::body::bytes())
+            .map(|body: Bytes| Some(Some(String::from_utf8_lossy(&body).trim().to_string())))).unify()
+        .or(warp::delete().map(|| Some(None))).unify();
 
-fn get_stream(channel: Handle) -> impl Stream<Item = Result<Bytes, WebmetroError>> {
-    let mut timecode_fixer = ChunkTimecodeFixer::new();
-    Listener::new(channel).map(|c| Result::<Chunk, WebmetroError>::Ok(c))
-    .map_ok(move |chunk| timecode_fixer.process(chunk))
-    .find_starting_point()
-    .map_ok(|webm_chunk| iter(webm_chunk).map(Result::<Bytes, WebmetroError>::Ok))
-    .try_flatten()
+    access_request(Action::Mirror).and(method)
+        .and_then(move |request: AccessRequest, change: Option<Option<String>>| {
+            let relay = relay.clone();
+            async move {
+                if !relay.authorize(&request).await {
+                    return Ok::<_, Infallible>(forbidden());
+                }
+                if let Some(target) = change {
+                    if let Some(ref target) = target {
+                        if !is_mirror_target(target) {
+                            return Ok(Response::builder()
+                                .status(StatusCode::BAD_REQUEST)
+                                .body(Body::from("Mirror target must be an http(s) URL\n"))
+                                .unwrap());
+                        }
+                    }
+                    info!("Mirror for Channel {} set to {}", request.channel, target.as_deref().map_or(Cow::Borrowed("off"), redact_token));
+                    set_mirror(&relay, &request.channel, target.as_deref());
+                }
+                let state = match relay.mirror_target(&request.channel) {
+                    Some(target) => format!("{}\n", target),
+                    None => "off\n".to_string(),
+                };
+                Ok(Response::builder()
+                    .header("Content-Type", "text/plain; charset=utf-8")
+                    .body(Body::from(state))
+                    .unwrap())
+            }
+        })
+        .boxed()
 }
 
-fn post_stream(channel: Handle, stream: impl Stream<Item = Result<impl Buf, warp::Error>> + Unpin) -> impl Stream<Item = Result<Bytes, WebmetroError>> {
-    let channel = Transmitter::new(channel);
-    stream
-        .map_err(WebmetroError::from)
-        .parse_ebml().with_soft_limit(BUFFER_LIMIT)
-        .chunk_webm().with_soft_limit(BUFFER_LIMIT)
-        .map_ok(move |chunk| {
-            channel.send(chunk);
-            Bytes::new()
+/// How often a paused channel's WebM listeners are sent a Void element, so
+/// proxies & players don't give up on them during the break
+const PAUSE_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// How often channels are checked for pauses that have gone on too long
+const PAUSE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often closed channels' metadata is forgotten
+const METADATA_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Lets channels be paused (PUT) or resumed (DELETE) under /pause/, while GET
+/// reports whether they are; a paused channel's listeners wait through the
+/// break after its source disconnects, until the next source resumes it or
+/// `--max-pause` is up. Pausing is an admin action.
+fn pause_routes(relay: Arc<Relay>) -> BoxedFilter<(Response<Body>,)> {
+    let method = warp::get().map(|| None::<bool>)
+        .or(warp::put().map(|| Some(true))).unify()
+        .or(warp::delete().map(|| Some(false))).unify();
+
+    access_request(Action::Pause).and(method)
+        .and_then(move |request: AccessRequest, switch: Option<bool>| {
+            let relay = relay.clone();
+            async move {
+                if !relay.authorize(&request).await {
+                    return Ok::<_, Infallible>(forbidden());
+                }
+                if let Some(paused) = switch {
+                    relay.set_paused(&request.channel, paused);
+                }
+                let state = if relay.is_paused(&request.channel) { "on\n" } else { "off\n" };
+                Ok(Response::builder()
+                    .header("Content-Type", "text/plain; charset=utf-8")
+                    .body(Body::from(state))
+                    .unwrap())
+            }
         })
-        .inspect_err(|err| {
-            warn!("{}", err)
+        .boxed()
+}
+
+/// Bring a channel in line with its stored configuration, undoing what its
+/// `old` configuration set up
+fn apply_config(relay: &Relay, record_settings: Option<&RecordSettings>, channel: &str, old: &ChannelConfig, new: &ChannelConfig) {
+    for (alias, _) in &old.aliases {
+        relay.remove_alias(alias);
+    }
+    for (alias, redirect) in &new.aliases {
+        relay.set_alias(alias, channel, *redirect);
+    }
+    if old.dvr_window != new.dvr_window {
+        relay.set_dvr_window(channel, new.dvr_window);
+    }
+    if old.max_publish_duration != new.max_publish_duration {
+        relay.set_max_publish_duration(channel, new.max_publish_duration);
+    }
+    if old.max_bitrate != new.max_bitrate {
+        relay.set_max_bitrate(channel, new.max_bitrate);
+    }
+    if old.record != new.record {
+        match record_settings {
+            Some(settings) => {
+                set_recording(relay, settings, channel, new.record);
+            },
+            None if new.record => warn!("Channel {} is set to record, but the relay has no --record-dir", channel),
+            None => {}
+        }
+    }
+    if old.mirror != new.mirror {
+        set_mirror(relay, channel, new.mirror.as_deref());
+    }
+}
+
+fn bad_config(status: StatusCode, message: String) -> Response<Body> {
+    Response::builder()
+        .status(status)
+        .body(Body::from(format!("{}\n", message)))
+        .unwrap()
+}
+
+/// Lets each channel's stored configuration be read (GET), replaced with
+/// the settings in the body (PUT), or forgotten (DELETE) under /config/;
+/// changes take effect at once. These are admin requests, and publishing
+/// keys are never sent back.
+fn config_routes(relay: Arc<Relay>, store: Arc<ConfigStore>, record_settings: Option<RecordSettings>) -> BoxedFilter<(Response<Body>,)> {
+    let method = warp::get().map(|| None::<String>)
+        .or(warp::put()
+            .and(warp::body::content_length_limit(65536))
+            .and(warp::body::bytes())
+            .map(|body: Bytes| Some(String::from_utf8_lossy(&body).into_owned()))).unify()
+        .or(warp::delete().map(|| Some(String::new()))).unify();
+
+    access_request(Action::Configure).and(method)
+        .and_then(move |request: AccessRequest, change: Option<String>| {
+            let relay = relay.clone();
+            let store = store.clone();
+            let record_settings = record_settings.clone();
+            async move {
+                if !relay.authorize(&request).await {
+                    return Ok::<_, Infallible>(forbidden());
+                }
+                if let Some(text) = change {
+                    let config: ChannelConfig = match text.parse() {
+                        Ok(config) => config,
+                        Err(err) => return Ok(bad_config(StatusCode::BAD_REQUEST, err.to_string())),
+                    };
+                    if config.mirror.as_ref().map_or(false, |target| !is_mirror_target(target)) {
+                        return Ok(bad_config(StatusCode::BAD_REQUEST, "Mirror target must be an http(s) URL".to_string()));
+                    }
+                    if config.record && record_settings.is_none() {
+                        return Ok(bad_config(StatusCode::BAD_REQUEST, "This relay doesn't record".to_string()));
+                    }
+                    let (saving, channel, new) = (store.clone(), request.channel.clone(), config.clone());
+                    let old = match spawn_blocking(move || saving.set_channel(&channel, new)).await {
+                        Ok(Ok(old)) => old,
+                        Ok(Err(err @ WebmetroError::IoError {..})) => {
+                            error!("Couldn't save channel configuration: {}", err);
+                            return Ok(server_error());
+                        },
+                        Ok(Err(err)) => return Ok(bad_config(StatusCode::BAD_REQUEST, err.to_string())),
+                        Err(err) => {
+                            error!("Couldn't save channel configuration: {}", err);
+                            return Ok(server_error());
+                        },
+                    };
+                    info!("Configuration for Channel {} changed", request.channel);
+                    apply_config(&relay, record_settings.as_ref(), &request.channel, &old, &config);
+                }
+                let mut shown = store.channel(&request.channel);
+                let mut text = String::new();
+                if shown.publish_key.take().is_some() {
+                    text.push_str("# key set, not shown\n");
+                }
+                text.push_str(&shown.to_string());
+                Ok(Response::builder()
+                    .header("Content-Type", "text/plain; charset=utf-8")
+                    .body(Body::from(text))
+                    .unwrap())
+            }
+        })
+        .boxed()
+}
+
+/// How often an idle event stream sends a comment, so proxies don't time it out
+const EVENT_KEEPALIVE: Duration = Duration::from_secs(15);
+
+/// Streams channel events as server-sent events, one JSON object per message:
+/// every channel's at /events, and a single channel's at /live/<channel>/events
+fn event_routes(relay: Arc<Relay>) -> BoxedFilter<(Response<Body>,)> {
+    let all = warp::path!("events").map(|| None::<String>);
+    let one = warp::path!("live" / String / "events").map(Some);
+
+    warp::get()
+        .and(all.or(one).unify())
+        .and(credentials())
+        .and(warp::addr::remote())
+        .and(client_names())
+        .and_then(move |channel: Option<String>, credentials, remote, client_names| {
+            let relay = relay.clone();
+            async move {
+                let channel = channel.map(|name| match relay.resolve(&name) {
+                    Route::Channel(channel) | Route::Redirect(channel) => channel,
+                });
+                let request = AccessRequest {
+                    action: Action::Monitor,
+                    channel: channel.clone().unwrap_or_default(),
+                    credentials,
+                    remote,
+                    client_names,
+                };
+                if !relay.authorize(&request).await {
+                    return Ok::<_, Infallible>(forbidden());
+                }
+
+                // subscribed first, so nothing's missed between the two
+                let events = relay.subscribe(channel.as_deref());
+                let snapshot = stream::iter(relay.snapshot(channel.as_deref()));
+                let events = snapshot.chain(events)
+                    .map(|event| format!("data: {}\n\n", event.to_json()));
+                let keepalive = stream::unfold((), |()| delay_for(EVENT_KEEPALIVE).map(|()| Some((": keepalive\n\n".to_string(), ()))));
+                let body = stream::select(events, keepalive).map(Ok::<_, WebmetroError>);
+                Ok(Response::builder()
+                    .header("Content-Type", "text/event-stream")
+                    .header("Cache-Control", "no-cache")
+                    .header("X-Accel-Buffering", "no")
+                    .body(Body::wrap_stream(body))
+                    .unwrap())
+            }
+        })
+        .boxed()
+}
+
+/// Reports how far behind each of a channel's listeners is, as a JSON array
+/// at /live/<channel>/listeners
+fn listener_stats_route(relay: Arc<Relay>) -> BoxedFilter<(Response<Body>,)> {
+    warp::get()
+        .and(warp::path!("live" / String / "listeners"))
+        .and(credentials())
+        .and(warp::addr::remote())
+        .and(client_names())
+        .and_then(move |name: String, credentials, remote, client_names| {
+            let relay = relay.clone();
+            async move {
+                let channel = match relay.resolve(&name) {
+                    Route::Channel(channel) | Route::Redirect(channel) => channel,
+                };
+                let request = AccessRequest {
+                    action: Action::Monitor,
+                    channel,
+                    credentials,
+                    remote,
+                    client_names,
+                };
+                if !relay.authorize(&request).await {
+                    return Ok::<_, Infallible>(forbidden());
+                }
+
+                let stats: Vec<String> = relay.listener_stats(&request.channel).iter()
+                    .map(|stats| stats.to_json())
+                    .collect();
+                Ok(Response::builder()
+                    .header("Content-Type", "application/json")
+                    .header("Cache-Control", "no-cache")
+                    .body(Body::from(format!("[{}]\n", stats.join(","))))
+                    .unwrap())
+            }
+        })
+        .boxed()
+}
+
+/// Reports a listener's own stats, as a JSON object at
+/// /live/<channel>/session/<session>, given the session token it was sent
+/// in its response's X-Webmetro-Session header
+fn session_stats_route(relay: Arc<Relay>) -> BoxedFilter<(Response<Body>,)> {
+    warp::get()
+        .and(warp::path!("live" / String / "session" / String))
+        .and(credentials())
+        .and(warp::addr::remote())
+        .and(client_names())
+        .and_then(move |name: String, session: String, credentials, remote, client_names| {
+            let relay = relay.clone();
+            async move {
+                let channel = match relay.resolve(&name) {
+                    Route::Channel(channel) | Route::Redirect(channel) => channel,
+                };
+                let request = AccessRequest {
+                    action: Action::Listen,
+                    channel,
+                    credentials,
+                    remote,
+                    client_names,
+                };
+                if !relay.authorize(&request).await {
+                    return Ok::<_, Infallible>(forbidden());
+                }
+
+                match relay.session_stats(&request.channel, &session) {
+                    Some(stats) => Ok(Response::builder()
+                        .header("Content-Type", "application/json")
+                        .header("Cache-Control", "no-cache")
+                        .body(Body::from(format!("{}\n", stats.to_json())))
+                        .unwrap()),
+                    None => Ok(not_found()),
+                }
+            }
+        })
+        .boxed()
+}
+
+/// Reports a simulcast channel's combined status, and each of its
+/// renditions', as a JSON object at /live/<channel>/renditions
+fn rendition_status_route(relay: Arc<Relay>) -> BoxedFilter<(Response<Body>,)> {
+    warp::get()
+        .and(warp::path!("live" / String / "renditions"))
+        .and(credentials())
+        .and(warp::addr::remote())
+        .and(client_names())
+        .and_then(move |name: String, credentials, remote, client_names| {
+            let relay = relay.clone();
+            async move {
+                let channel = match relay.resolve(&name) {
+                    Route::Channel(channel) | Route::Redirect(channel) => channel,
+                };
+                let request = AccessRequest {
+                    action: Action::Monitor,
+                    channel,
+                    credentials,
+                    remote,
+                    client_names,
+                };
+                if !relay.authorize(&request).await {
+                    return Ok::<_, Infallible>(forbidden());
+                }
+
+                let status = simulcast_json(&request.channel, relay.metadata(&request.channel).as_ref(), &relay.rendition_status(&request.channel));
+                Ok(Response::builder()
+                    .header("Content-Type", "application/json")
+                    .header("Cache-Control", "no-cache")
+                    .body(Body::from(format!("{}\n", status)))
+                    .unwrap())
+            }
+        })
+        .boxed()
+}
+
+/// Describes a channel's stream (its MIME type, and each track's codec &
+/// format) as a JSON object at /live/<channel>/manifest.json, so players can
+/// set up Media Source Extensions before fetching it
+fn manifest_route(relay: Arc<Relay>) -> BoxedFilter<(Response<Body>,)> {
+    warp::get()
+        .and(warp::path!("live" / String / "manifest.json"))
+        .and(credentials())
+        .and(warp::addr::remote())
+        .and(client_names())
+        .and_then(move |name: String, credentials, remote, client_names| {
+            let relay = relay.clone();
+            async move {
+                let channel = match relay.resolve(&name) {
+                    Route::Channel(channel) | Route::Redirect(channel) => channel,
+                };
+                let request = AccessRequest {
+                    action: Action::Listen,
+                    channel,
+                    credentials,
+                    remote,
+                    client_names,
+                };
+                if !relay.authorize(&request).await {
+                    return Ok::<_, Infallible>(forbidden());
+                }
+
+                let tracks = match relay.tracks(&request.channel) {
+                    Some(tracks) => tracks,
+                    None => return Ok(not_found()),
+                };
+                let manifest = manifest_json(&request.channel, relay.has_source(&request.channel), relay.metadata(&request.channel).as_ref(), &tracks);
+                Ok(Response::builder()
+                    .header("Content-Type", "application/json")
+                    .header("Cache-Control", "no-cache")
+                    .body(Body::from(format!("{}\n", manifest)))
+                    .unwrap())
+            }
+        })
+        .boxed()
+}
+
+/// The most a metadata update's body may hold
+const METADATA_BODY_LIMIT: u64 = 16384;
+
+/// Lets a channel's metadata be read (GET), set by its publisher from the
+/// JSON object in the body (POST or PUT), or forgotten (DELETE) at
+/// /live/<channel>/meta
+fn metadata_routes(relay: Arc<Relay>) -> BoxedFilter<(Response<Body>,)> {
+    let method = warp::get().map(|| None::<Option<Bytes>>)
+        .or(warp::post().or(warp::put()).unify()
+            .and(warp::body::content_length_limit(METADATA_BODY_LIMIT))
+            .and(warp::body::bytes())
+            .map(|body: Bytes| Some(Some(body)))).unify()
+        .or(warp::delete().map(|| Some(None))).unify();
+
+    warp::path!("live" / String / "meta")
+        .and(method)
+        .and(credentials())
+        .and(warp::addr::remote())
+        .and(client_names())
+        .and_then(move |name: String, change: Option<Option<Bytes>>, credentials, remote, client_names| {
+            let relay = relay.clone();
+            async move {
+                let channel = match relay.resolve(&name) {
+                    Route::Channel(channel) | Route::Redirect(channel) => channel,
+                };
+                let request = AccessRequest {
+                    action: if change.is_some() { Action::Publish } else { Action::Listen },
+                    channel,
+                    credentials,
+                    remote,
+                    client_names,
+                };
+                if !relay.authorize(&request).await {
+                    return Ok::<_, Infallible>(denied(&request));
+                }
+                let changed = change.is_some();
+                // only a live channel's kept, so a change can't leave one behind
+                if let Some(Some(_)) = change {
+                    if !relay.is_live(&request.channel) {
+                        return Ok(bad_config(StatusCode::CONFLICT, format!("Channel {} isn't live", request.channel)));
+                    }
+                }
+                if let Some(body) = change {
+                    let metadata = match body.map(|body| ChannelMetadata::from_json(&String::from_utf8_lossy(&body))).transpose() {
+                        Ok(metadata) => metadata,
+                        Err(err) => return Ok(bad_config(StatusCode::BAD_REQUEST, err.to_string())),
+                    };
+                    info!("Metadata for Channel {} changed", request.channel);
+                    relay.set_metadata(&request.channel, metadata);
+                }
+                match relay.metadata(&request.channel) {
+                    Some(metadata) => Ok(Response::builder()
+                        .header("Content-Type", "application/json")
+                        .header("Cache-Control", "no-cache")
+                        .body(Body::from(format!("{}\n", metadata.to_json())))
+                        .unwrap()),
+                    None if changed => Ok(Response::builder()
+                        .status(StatusCode::NO_CONTENT)
+                        .body(Body::empty())
+                        .unwrap()),
+                    None => Ok(not_found()),
+                }
+            }
+        })
+        .boxed()
+}
+
+/// Reports a channel's broadcast sessions as a JSON object at
+/// /channels/<channel>/history (or /channels/<channel>/<rendition>/history)
+fn history_route(relay: Arc<Relay>) -> BoxedFilter<(Response<Body>,)> {
+    let channel = warp::path!("channels" / String / "history");
+    let rendition = warp::path!("channels" / String / String / "history")
+        .map(|channel: String, rendition: String| rendition_channel(&channel, &rendition));
+    warp::get()
+        .and(channel.or(rendition).unify())
+        .and(credentials())
+        .and(warp::addr::remote())
+        .and(client_names())
+        .and_then(move |name: String, credentials, remote, client_names| {
+            let relay = relay.clone();
+            async move {
+                let channel = match relay.resolve(&name) {
+                    Route::Channel(channel) | Route::Redirect(channel) => channel,
+                };
+                let request = AccessRequest {
+                    action: Action::Monitor,
+                    channel,
+                    credentials,
+                    remote,
+                    client_names,
+                };
+                if !relay.authorize(&request).await {
+                    return Ok::<_, Infallible>(forbidden());
+                }
+
+                let history = history_json(&request.channel, &relay.history().sessions(&request.channel));
+                Ok(Response::builder()
+                    .header("Content-Type", "application/json")
+                    .header("Cache-Control", "no-cache")
+                    .body(Body::from(format!("{}\n", history)))
+                    .unwrap())
+            }
         })
+        .boxed()
+}
+
+/// How long a channel goes without HLS requests before it stops being packaged
+const HLS_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// What a request under /live/<channel>/hls/ asks for
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum HlsFile {
+    /// index.m3u8
+    Playlist,
+    /// master.m3u8, for a simulcast channel
+    Master,
+    /// init-<n>.mp4
+    Init(u64),
+    /// <msn>.m4s
+    Segment(u64),
+    /// <msn>.<part>.m4s
+    Part(u64, usize),
+}
+
+impl HlsFile {
+    fn parse(name: &str) -> Option<HlsFile> {
+        if name == "index.m3u8" {
+            return Some(HlsFile::Playlist);
+        }
+        if name == "master.m3u8" {
+            return Some(HlsFile::Master);
+        }
+        if name.starts_with("init-") && name.ends_with(".mp4") {
+            return name[5..name.len() - 4].parse().ok().map(HlsFile::Init);
+        }
+        if !name.ends_with(".m4s") {
+            return None;
+        }
+        let mut numbers = name[..name.len() - 4].splitn(2, '.');
+        let sequence = numbers.next()?.parse().ok()?;
+        match numbers.next() {
+            Some(part) => part.parse().ok().map(|part| HlsFile::Part(sequence, part)),
+            None => Some(HlsFile::Segment(sequence)),
+        }
+    }
+}
+
+/// Wait up to `limit` for a segment (or part) to be ready, returning
+/// whether it is
+async fn hls_ready(packager: &HlsPackager, sequence: u64, part: Option<usize>, limit: Duration) -> bool {
+    let deadline = Instant::now() + limit;
+    loop {
+        // listen for changes before checking, so none are missed in between
+        let changed = packager.changed();
+        if packager.is_ready(sequence, part) {
+            return true;
+        }
+        let now = Instant::now();
+        if now >= deadline || timeout(deadline - now, changed).await.is_err() {
+            return packager.is_ready(sequence, part);
+        }
+    }
 }
 
-fn media_response(body: Body) -> Response<Body> {
+fn not_found() -> Response<Body> {
     Response::builder()
-        .header(CONTENT_TYPE, "video/webm")
-        .header("X-Accel-Buffering", "no")
-        .header(CACHE_CONTROL, "no-cache, no-store")
-        .body(body)
+        .status(StatusCode::NOT_FOUND)
+        .body(Body::empty())
         .unwrap()
 }
 
+/// Serves channels as low-latency HLS under /live/<channel>/hls/, starting
+/// to package a channel on its first request. Playlist requests with
+/// `_HLS_msn` (and `_HLS_part`), and requests for parts that haven't arrived
+/// yet, wait for them for up to three target durations. Renditions are
+/// served under /live/<channel>/<rendition>/hls/, and a master playlist
+/// offering them all at /live/<channel>/hls/master.m3u8.
+fn hls_routes(relay: Arc<Relay>) -> BoxedFilter<(Response<Body>,)> {
+    let options = relay.options().hls.clone();
+    let limit = Duration::from_millis(options.segment_duration * 3);
+    let channel = warp::path!("live" / String / "hls" / String);
+    let rendition = warp::path!("live" / String / String / "hls" / String)
+        .map(|channel: String, rendition: String, file: String| (rendition_channel(&channel, &rendition), file));
+    warp::get()
+        .and(channel.map(|name: String, file: String| (name, file)).or(rendition).unify())
+        .and(warp::query::<HashMap<String, String>>().or(warp::any().map(HashMap::new)).unify())
+        .and(credentials())
+        .and(warp::addr::remote())
+        .and(client_names())
+        .and_then(move |(name, file): (String, String), query: HashMap<String, String>, credentials, remote, client_names| {
+            let relay = relay.clone();
+            async move {
+                let file = match HlsFile::parse(&file) {
+                    Some(file) => file,
+                    None => return Ok::<_, Infallible>(not_found()),
+                };
+                let channel = match relay.resolve(&name) {
+                    Route::Channel(channel) | Route::Redirect(channel) => channel,
+                };
+                let request = AccessRequest {
+                    action: Action::Listen,
+                    channel,
+                    credentials,
+                    remote,
+                    client_names,
+                };
+                if !relay.authorize(&request).await {
+                    return Ok(forbidden());
+                }
+
+                // the group itself isn't packaged, only its renditions
+                if file == HlsFile::Master {
+                    return Ok(match relay.master_playlist(&request.channel) {
+                        Some(playlist) => Response::builder()
+                            .header("Content-Type", "application/vnd.apple.mpegurl")
+                            .header("Cache-Control", "no-cache")
+                            .body(Body::from(playlist))
+                            .unwrap(),
+                        None => not_found(),
+                    });
+                }
+
+                let (packager, packaging) = relay.hls(&request.channel);
+                if let Some(packaging) = packaging {
+                    info!("Packaging Channel {} For HLS", request.channel);
+                    tokio::spawn(packaging.instrument(info_span!("hls", channel = %request.channel)));
+                }
+
+                let (content_type, cache_control, body) = match file {
+                    HlsFile::Playlist => {
+                        let sequence = query.get("_HLS_msn").and_then(|msn| msn.parse().ok());
+                        let part = query.get("_HLS_part").and_then(|part| part.parse().ok());
+                        if let Some(sequence) = sequence {
+                            if !hls_ready(&packager, sequence, part, limit).await {
+                                return Ok(Response::builder()
+                                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                                    .body(Body::empty())
+                                    .unwrap());
+                            }
+                        }
+                        ("application/vnd.apple.mpegurl", "no-cache", packager.playlist().map(Bytes::from))
+                    },
+                    HlsFile::Master => unreachable!("master playlists are answered before packaging"),
+                    HlsFile::Init(number) => ("video/mp4", "max-age=60", packager.init_segment(number)),
+                    HlsFile::Segment(sequence) => ("video/mp4", "max-age=60", packager.segment(sequence)),
+                    HlsFile::Part(sequence, part) => {
+                        // a preload hint names a part before it's arrived
+                        hls_ready(&packager, sequence, Some(part), limit).await;
+                        ("video/mp4", "max-age=60", packager.part(sequence, part))
+                    },
+                };
+                Ok(match body {
+                    Some(body) => Response::builder()
+                        .header("Content-Type", content_type)
+                        .header("Cache-Control", cache_control)
+                        .body(Body::from(body))
+                        .unwrap(),
+                    None => not_found(),
+                })
+            }
+        })
+        .boxed()
+}
+
+/// A finite file made from what a channel has retained
+#[derive(Clone, Copy)]
+enum Snapshot {
+    /// the initialization segment, at /live/<channel>/init, for players
+    /// that append it separately from /live/<channel>/media
+    Init,
+    /// the headers & latest keyframe Cluster as a complete WebM file, at
+    /// /live/<channel>/preview.webm
+    Preview,
+}
+
+fn snapshot_routes(relay: Arc<Relay>) -> BoxedFilter<(Response<Body>,)> {
+    let init = warp::path!("live" / String / "init").map(|channel| (channel, Snapshot::Init));
+    let preview = warp::path!("live" / String / "preview.webm").map(|channel| (channel, Snapshot::Preview));
+    warp::get()
+        .and(init.or(preview).unify())
+        .and(credentials())
+        .and(warp::addr::remote())
+        .and(client_names())
+        .and_then(move |(name, snapshot): (String, Snapshot), credentials, remote, client_names| {
+            let relay = relay.clone();
+            async move {
+                let channel = match relay.resolve(&name) {
+                    Route::Channel(channel) | Route::Redirect(channel) => channel,
+                };
+                let request = AccessRequest {
+                    action: Action::Listen,
+                    channel,
+                    credentials,
+                    remote,
+                    client_names,
+                };
+                if !relay.authorize(&request).await {
+                    return Ok::<_, Infallible>(forbidden());
+                }
+
+                let file = match snapshot {
+                    Snapshot::Init => relay.init_segment(&request.channel),
+                    Snapshot::Preview => match relay.preview(&request.channel) {
+                        Ok(preview) => preview,
+                        Err(err) => {
+                            warn!("Couldn't make a preview of Channel {}: {}", request.channel, err);
+                            return Ok(server_error());
+                        }
+                    },
+                };
+                Ok(match file {
+                    Some(file) => media_response(&relay, Egress::Media, Body::from(file)),
+                    None => not_found(),
+                })
+            }
+        })
+        .boxed()
+}
+
+#[derive(Debug)]
+struct RateLimited;
+
+impl warp::reject::Reject for RateLimited {}
+
+/// Admits requests while `bucket` has tokens for them, rejecting the rest
+/// with `RateLimited` (answered with 429); without a bucket, admits everything.
+/// Place it after a route's path & method, so only that route's requests count.
+fn rate_limit(bucket: Option<Arc<TokenBucket>>) -> impl Filter<Extract = (), Error = warp::Rejection> + Clone {
+    warp::any()
+        .and_then(move || {
+            let admitted = bucket.as_ref().map_or(true, |bucket| bucket.take());
+            async move {
+                if admitted {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(RateLimited))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+/// Parse a pair of --<prefix>-rate & --<prefix>-burst options into a bucket;
+/// the burst defaults to a second's worth of requests
+fn token_bucket(args: &ArgMatches, prefix: &str) -> Result<Option<Arc<TokenBucket>>, WebmetroError> {
+    let parse = |option: String| -> Result<Option<f64>, WebmetroError> {
+        match args.value_of(&option) {
+            Some(value) => match value.parse::<f64>() {
+                Ok(number) if number > 0.0 => Ok(Some(number)),
+                _ => Err(WebmetroError::ApplicationError {
+                    message: format!("--{} must be a positive number", option.replace('_', "-")),
+                }),
+            },
+            None => Ok(None),
+        }
+    };
+    let rate = match parse(format!("{}_rate", prefix))? {
+        Some(rate) => rate,
+        None => return Ok(None),
+    };
+    let burst = parse(format!("{}_burst", prefix))?.unwrap_or(rate).max(1.0);
+    Ok(Some(Arc::new(TokenBucket::new(rate, burst))))
+}
+
+/// The headers a TLS-terminating proxy describes a client certificate with
+const CERTIFICATE_HEADERS: [&str; 2] = ["x-ssl-client-s-dn", "x-ssl-client-san"];
+
+/// Remove the certificate headers from requests that didn't come through a
+/// trusted proxy, since any client could have set them
+fn strip_certificate_headers(headers: &mut HeaderMap, remote: IpAddr, trusted_proxies: &[IpAddr]) {
+    if !trusted_proxies.contains(&remote) {
+        for name in CERTIFICATE_HEADERS.iter() {
+            headers.remove(*name);
+        }
+    }
+}
+
+/// The names on the client's certificate, as reported by a TLS-terminating
+/// proxy that verified it (empty headers mean there wasn't one); see
+/// `strip_certificate_headers`
+fn client_names() -> impl Filter<Extract = (Vec<String>,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>(CERTIFICATE_HEADERS[0])
+        .and(warp::header::optional::<String>(CERTIFICATE_HEADERS[1]))
+        .map(|subject: Option<String>, alt_names: Option<String>| {
+            certificate_names(subject.as_deref(), alt_names.as_deref())
+        })
+}
+
+/// The request's Authorization header, or else a bearer token passed as
+/// ?token= (for clients like EventSource & WebSocket that can't set headers)
+fn credentials() -> impl Filter<Extract = (Option<String>,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and(warp::query::<HashMap<String, String>>().or(warp::any().map(HashMap::new)).unify())
+        .map(|header: Option<String>, query: HashMap<String, String>| {
+            header.or_else(|| query.get("token").map(|token| format!("Bearer {}", token)))
+        })
+}
+
+/// The format a viewer asked for, with a `?format=` parameter or its Accept header
+fn media_format() -> impl Filter<Extract = (MediaFormat,), Error = warp::Rejection> + Clone {
+    warp::header::optional::<String>("accept")
+        .and(warp::query::<HashMap<String, String>>().or(warp::any().map(HashMap::new)).unify())
+        .map(|accept: Option<String>, query: HashMap<String, String>| {
+            MediaFormat::negotiate(query.get("format").map(String::as_str), accept.as_deref())
+        })
+}
+
+/// Matches a viewer's request for a channel, in whichever form it asks for;
+/// with `ogg`, a name ending in .ogg asks for Ogg Opus
+fn listen_request(ogg: bool) -> impl Filter<Extract = (AccessRequest, Egress), Error = warp::Rejection> + Clone {
+    let stream = access_request(Action::Listen)
+        .and(media_format())
+        .map(move |mut request: AccessRequest, format: MediaFormat| {
+            if ogg && request.channel.ends_with(".ogg") {
+                let length = request.channel.len() - ".ogg".len();
+                request.channel.truncate(length);
+                (request, Egress::Ogg)
+            } else {
+                (request, Egress::Stream(format))
+            }
+        });
+    let cmaf = warp::path!("live" / String / "cmaf").map(|channel| (channel, Egress::Cmaf));
+    let media = warp::path!("live" / String / "media").map(|channel| (channel, Egress::Media));
+    let other = cmaf.or(media).unify()
+        .and(credentials())
+        .and(warp::addr::remote())
+        .and(client_names())
+        .map(|(channel, egress), credentials, remote, client_names| {
+            (AccessRequest { action: Action::Listen, channel, credentials, remote, client_names }, egress)
+        });
+    stream.or(other).unify().untuple_one()
+}
+
+/// Parse a "Name: value" header option; an empty value means None
+fn parse_header(header: &str) -> Result<(&str, Option<&str>), WebmetroError> {
+    let mut parts = header.splitn(2, ':');
+    let name = parts.next().unwrap_or("").trim();
+    let value = parts.next().map(str::trim);
+    let valid = HeaderName::from_bytes(name.as_bytes()).is_ok()
+        && value.map_or(false, |value| HeaderValue::from_str(value).is_ok());
+    if !valid {
+        return Err(WebmetroError::ApplicationError {
+            message: format!("--header {} should look like \"Name: value\"", header),
+        });
+    }
+    Ok((name, value.filter(|value| !value.is_empty())))
+}
+
+/// What follows a channel's name in the URLs that serve it in other ways,
+/// so can't name one of its renditions
+const RESERVED_RENDITIONS: &[&str] = &["cmaf", "events", "hls", "init", "listeners", "manifest.json", "media", "meta", "preview.webm", "renditions"];
+
+/// Matches the rest of a channel URL: a channel's own name, or a rendition's
+/// (e.g. main/720p, for the rendition 720p of the simulcast channel main)
+fn channel_name() -> impl Filter<Extract = (String,), Error = warp::Rejection> + Clone {
+    let channel = warp::path::param::<String>()
+        .and(warp::path::end());
+    let rendition = warp::path::param::<String>()
+        .and(warp::path::param::<String>())
+        .and(warp::path::end())
+        .and_then(|channel: String, rendition: String| async move {
+            if RESERVED_RENDITIONS.contains(&rendition.as_str()) {
+                Err(warp::reject::not_found())
+            } else {
+                Ok(rendition_channel(&channel, &rendition))
+            }
+        });
+    channel.or(rendition).unify()
+}
+
+/// Matches a channel URL, collecting what an Authorizer needs to know about the request
+fn access_request(action: Action) -> impl Filter<Extract = (AccessRequest,), Error = warp::Rejection> + Clone {
+    let prefix = match action {
+        Action::Record => "record",
+        Action::Mirror => "mirror",
+        Action::Configure => "config",
+        Action::Pause => "pause",
+        _ => "live",
+    };
+    warp::path(prefix)
+        .and(channel_name())
+        .and(credentials())
+        .and(warp::addr::remote())
+        .and(client_names())
+        .map(move |channel, credentials, remote, client_names| AccessRequest { action, channel, credentials, remote, client_names })
+}
+
 pub fn options() -> App<'static, 'static> {
     SubCommand::with_name("relay")
         .about("Hosts an HTTP-based relay server")
         .arg(Arg::with_name("listen")
             .help("The address:port to listen to")
             .required(true))
+        .arg(Arg::with_name("require_all_binds")
+            .long("require-all-binds")
+            .help("Refuse to start unless every address the listen address resolves to can be bound, instead of serving on those that can"))
+        .arg(Arg::with_name("user")
+            .takes_value(true)
+            .long("user")
+            .help("Switch to this user after binding the listen address (e.g. to bind port 80 as root)"))
+        .arg(Arg::with_name("group")
+            .takes_value(true)
+            .long("group")
+            .help("Switch to this group after binding the listen address; defaults to the --user's group"))
+        .arg(Arg::with_name("nodelay")
+            .long("nodelay")
+            .help("Set TCP_NODELAY on connections, so small writes aren't held back to be batched"))
+        .arg(Arg::with_name("keepalive")
+            .takes_value(true)
+            .long("keepalive")
+            .help("Enable TCP keepalive on connections, probing idle peers after n seconds"))
+        .arg(Arg::with_name("backlog")
+            .takes_value(true)
+            .long("backlog")
+            .default_value("128")
+            .help("How many not-yet-accepted connections may queue up on each listening socket"))
+        .arg(Arg::with_name("workers")
+            .takes_value(true)
+            .long("workers")
+            .conflicts_with("single_thread")
+            .help("How many worker threads serve connections (defaults to one per CPU core)"))
+        .arg(Arg::with_name("single_thread")
+            .long("single-thread")
+            .help("Serve everything from the main thread, e.g. on small embedded devices"))
+        .arg(Arg::with_name("listener_queue")
+            .takes_value(true)
+            .long("listener-queue")
+            .help("How many chunks a viewer may fall behind before its lag policy applies [default: 5]"))
+        .arg(Arg::with_name("lag_policy")
+            .takes_value(true)
+            .long("lag-policy")
+            .possible_values(&["drop", "disconnect", "block"])
+            .default_value("disconnect")
+            .help("What to do with a viewer that falls behind: skip it ahead to the latest keyframe, disconnect it, or hold back the source until it catches up"))
+        .arg(Arg::with_name("resync")
+            .long("resync")
+            .help("Skip corrupt data from a source to the next Cluster, instead of disconnecting it"))
+        .arg(Arg::with_name("memory_limit")
+            .takes_value(true)
+            .long("memory-limit")
+            .help("Cap the memory used to buffer media across all channels (e.g. 64M); nearing it, new viewers wait for a keyframe, and past it, lagging viewers are disconnected"))
+        .arg(Arg::with_name("dvr_window")
+            .takes_value(true)
+            .long("dvr-window")
+            .help("Keep this many seconds of each channel, so viewers can start behind the live edge with ?rewind=<seconds>"))
+        .arg(Arg::with_name("source_timeout")
+            .takes_value(true)
+            .long("source-timeout")
+            .help("Disconnect a source that sends nothing for this many seconds"))
+        .arg(Arg::with_name("max_publish_duration")
+            .takes_value(true)
+            .long("max-publish-duration")
+            .help("End a source cleanly once it's published for this many seconds, finishing its last Cluster & any recording (a channel's stored max-duration setting overrides this)"))
+        .arg(Arg::with_name("max_bitrate")
+            .takes_value(true)
+            .long("max-bitrate")
+            .help("The most bits per second a source may publish, averaged over its last 3 seconds of media (a channel's stored max-bitrate setting overrides this)"))
+        .arg(Arg::with_name("max_bitrate_action")
+            .takes_value(true)
+            .long("max-bitrate-action")
+            .possible_values(&["warn", "end"])
+            .default_value("end")
+            .help("What to do with a source over its bitrate cap: log a warning, or end it"))
+        .arg(Arg::with_name("end_with_source")
+            .long("end-with-source")
+            .help("End viewers' streams when their channel's source disconnects, instead of leaving them waiting for the next source"))
+        .arg(Arg::with_name("reconnect_grace")
+            .takes_value(true)
+            .long("reconnect-grace")
+            .requires("end_with_source")
+            .help("When a source fails (e.g. by timing out), keep its viewers waiting this many seconds for it to reconnect, instead of ending their streams"))
+        .arg(Arg::with_name("gap_threshold")
+            .takes_value(true)
+            .long("gap-threshold")
+            .help("Mark a discontinuity (e.g. in HLS playlists) where a source's timecodes jump ahead by more than this many milliseconds [default: 1000]"))
+        .arg(Arg::with_name("max_av_drift")
+            .takes_value(true)
+            .long("max-av-drift")
+            .help("When a source's audio drifts more than this many milliseconds from its video, shift the audio's timestamps back in step, a little at a time"))
+        .arg(Arg::with_name("fill_silence")
+            .takes_value(true)
+            .long("fill-silence")
+            .help("Fill gaps of more than this many milliseconds in a source's Opus audio with silence, so players don't stall waiting for it"))
+        .arg(Arg::with_name("idle_padding")
+            .takes_value(true)
+            .long("idle-padding")
+            .help("While a channel's source stalls, send its WebM viewers a small EBML Void element every n seconds, so proxies & players don't time out the idle response"))
+        .arg(Arg::with_name("max_listeners_per_ip")
+            .takes_value(true)
+            .long("max-listeners-per-ip")
+            .help("Refuse (with 429 Too Many Requests) a viewer from an address that already has this many streams open"))
+        .arg(Arg::with_name("header")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .long("header")
+            .help("Send this header with viewers' streams, given as \"Name: value\"; replaces a default header of the same name (Content-Type, X-Accel-Buffering, Cache-Control), or removes it if the value is empty; may be repeated"))
+        .arg(Arg::with_name("access_log")
+            .takes_value(true)
+            .long("access-log")
+            .help("Append a JSON line to this file for each finished source & viewer session: channel, role, bytes, duration, and peer address"))
+        .arg(Arg::with_name("access_log_max_size")
+            .takes_value(true)
+            .long("access-log-max-size")
+            .requires("access_log")
+            .help("Start a new access log once it would grow past this size (e.g. 100M), renaming the old one for when it started"))
+        .arg(Arg::with_name("access_log_max_age")
+            .takes_value(true)
+            .long("access-log-max-age")
+            .requires("access_log")
+            .help("Start a new access log once it's this many seconds old, renaming the old one for when it started"))
+        .arg(Arg::with_name("ogg")
+            .long("ogg")
+            .help("Serve channels whose only track is Opus as Ogg Opus at /live/<channel>.ogg, for audio players that don't speak WebM"))
+        .arg(Arg::with_name("hls")
+            .long("hls")
+            .help("Serve channels as low-latency HLS at /live/<channel>/hls/index.m3u8, splitting sources' Clusters into parts"))
+        .arg(Arg::with_name("hls_segment_duration")
+            .takes_value(true)
+            .long("hls-segment-duration")
+            .requires("hls")
+            .help("Start a new HLS segment at the first keyframe after this many seconds [default: 2]"))
+        .arg(Arg::with_name("hls_part_duration")
+            .takes_value(true)
+            .long("hls-part-duration")
+            .requires("hls")
+            .help("Split sources' Clusters into HLS parts of about this many milliseconds [default: 500]"))
+        .arg(Arg::with_name("hls_window")
+            .takes_value(true)
+            .long("hls-window")
+            .requires("hls")
+            .help("How many complete segments HLS playlists list [default: 6]"))
+        .arg(Arg::with_name("ingest_rate")
+            .takes_value(true)
+            .long("ingest-rate")
+            .help("Accept at most this many new sources per second, answering the rest with 429"))
+        .arg(Arg::with_name("ingest_burst")
+            .takes_value(true)
+            .long("ingest-burst")
+            .requires("ingest_rate")
+            .help("How many new sources may connect at once within --ingest-rate (defaults to the rate)"))
+        .arg(Arg::with_name("egress_rate")
+            .takes_value(true)
+            .long("egress-rate")
+            .help("Accept at most this many new viewers per second, answering the rest with 429"))
+        .arg(Arg::with_name("egress_burst")
+            .takes_value(true)
+            .long("egress-burst")
+            .requires("egress_rate")
+            .help("How many new viewers may connect at once within --egress-rate (defaults to the rate)"))
+        .arg(Arg::with_name("jwt_secret_file")
+            .takes_value(true)
+            .long("jwt-secret-file")
+            .conflicts_with("jwt_public_key")
+            .help("Require requests to carry a JWT signed with HS256 and the secret in this file, as a bearer token or ?token= parameter"))
+        .arg(Arg::with_name("jwt_public_key")
+            .takes_value(true)
+            .long("jwt-public-key")
+            .help("Require requests to carry a JWT signed with RS256 by the holder of this PEM public key, as a bearer token or ?token= parameter"))
+        .arg(Arg::with_name("jwt_issuer")
+            .takes_value(true)
+            .long("jwt-issuer")
+            .help("Only accept JWTs from this issuer (iss)"))
+        .arg(Arg::with_name("jwt_audience")
+            .takes_value(true)
+            .long("jwt-audience")
+            .help("Only accept JWTs meant for this audience (aud)"))
+        .arg(Arg::with_name("auth_webhook")
+            .takes_value(true)
+            .long("auth-webhook")
+            .conflicts_with_all(&["jwt_secret_file", "jwt_public_key"])
+            .help("Ask this URL whether each request may proceed, POSTing its channel, role, token & IP as JSON; a 2xx answer allows it and a 4xx denies it"))
+        .arg(Arg::with_name("auth_webhook_cache")
+            .takes_value(true)
+            .long("auth-webhook-cache")
+            .requires("auth_webhook")
+            .help("How many seconds to reuse the webhook's answer for the same request (default 30)"))
+        .arg(Arg::with_name("auth_webhook_timeout")
+            .takes_value(true)
+            .long("auth-webhook-timeout")
+            .requires("auth_webhook")
+            .help("How many seconds to wait for the webhook's answer (default 5)"))
+        .arg(Arg::with_name("auth_webhook_failure")
+            .takes_value(true)
+            .long("auth-webhook-failure")
+            .requires("auth_webhook")
+            .possible_values(&["open", "closed"])
+            .help("Whether to allow (open) or deny (closed, the default) requests when the webhook fails or doesn't answer in time"))
+        .arg(Arg::with_name("admin_token_file")
+            .takes_value(true)
+            .long("admin-token-file")
+            .help("Allow admin requests, like switching recording on & off, only with the token in this file, as a bearer token or ?token= parameter; without it, they're all denied"))
+        .arg(Arg::with_name("publisher_cert")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .long("publisher-cert")
+            .requires("trusted_proxy")
+            .help("Only accept sources with a client certificate (verified by a TLS-terminating proxy) whose CN or SAN is allowed to publish to the channel, given as name=channel-prefix; may be repeated"))
+        .arg(Arg::with_name("trusted_proxy")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .long("trusted-proxy")
+            .help("The address of a TLS-terminating proxy whose X-SSL-Client-S-DN & X-SSL-Client-SAN headers describe verified client certificates; they're ignored from anyone else. May be repeated"))
+        .arg(Arg::with_name("alias")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .long("alias")
+            .help("Serve a channel under another name as well, given as alias=channel; may be repeated"))
+        .arg(Arg::with_name("redirect")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .long("redirect")
+            .help("Redirect requests for one channel name to another (307), given as alias=channel; may be repeated"))
+        .arg(Arg::with_name("vod_dir")
+            .takes_value(true)
+            .long("vod-dir")
+            .help("Serve the finished recordings in this directory (e.g. from the record subcommand) under /vod/"))
+        .arg(Arg::with_name("record_dir")
+            .takes_value(true)
+            .long("record-dir")
+            .help("Record channels to this directory; recording is switched on & off per channel with PUT & DELETE to /record/<channel>, or a ?record=on|off parameter when publishing"))
+        .arg(Arg::with_name("record_all")
+            .long("record-all")
+            .requires("record_dir")
+            .help("Record each channel while it has a source, unless it publishes with ?record=off"))
+        .arg(Arg::with_name("record_segment_duration")
+            .takes_value(true)
+            .long("record-segment-duration")
+            .requires("record_dir")
+            .help("Start a new recording file at the first keyframe after a file spans this many seconds"))
+        .arg(Arg::with_name("record_segment_size")
+            .takes_value(true)
+            .long("record-segment-size")
+            .requires("record_dir")
+            .help("Start a new recording file at the first keyframe after a file reaches this size (e.g. 500M)"))
+        .arg(Arg::with_name("upload_url")
+            .takes_value(true)
+            .long("upload-url")
+            .requires("record_dir")
+            .help("Upload finished recordings to S3-compatible storage, e.g. https://s3.us-east-1.amazonaws.com/bucket/prefix; credentials are read from AWS_ACCESS_KEY_ID & AWS_SECRET_ACCESS_KEY"))
+        .arg(Arg::with_name("upload_region")
+            .takes_value(true)
+            .long("upload-region")
+            .default_value("us-east-1")
+            .help("The storage region to sign uploads for"))
+        .arg(Arg::with_name("upload_retention")
+            .takes_value(true)
+            .long("upload-retention")
+            .default_value("keep")
+            .help("What to do with local recordings once uploaded: keep, delete, or delete after n seconds"))
+        .arg(Arg::with_name("hook")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .long("hook")
+            .help("Run a command (directly, not with a shell) when something happens, given as trigger=command; the trigger is publish-start, publish-stop, or recording-finished, and the command gets WEBMETRO_EVENT, WEBMETRO_CHANNEL, WEBMETRO_TIME & WEBMETRO_FILE (for recordings) in its environment; may be repeated"))
+        .arg(Arg::with_name("hook_timeout")
+            .takes_value(true)
+            .long("hook-timeout")
+            .requires("hook")
+            .help("Kill a hook's command if it runs for longer than this many seconds [default: 30]"))
+        .arg(Arg::with_name("max_running_hooks")
+            .takes_value(true)
+            .long("max-running-hooks")
+            .requires("hook")
+            .help("How many hooks' commands may run at once; the rest wait their turn [default: 4]"))
+        .arg(Arg::with_name("transcode")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .long("transcode")
+            .help("Pipe every channel's source through a command, given as suffix=command and run like a hook's, and publish its stdout as <channel>_<suffix>; the command reads WebM on stdin, gets WEBMETRO_CHANNEL in its environment, and is restarted if it exits while the source is live; may be repeated"))
+        .arg(Arg::with_name("transcode_restart_delay")
+            .takes_value(true)
+            .long("transcode-restart-delay")
+            .requires("transcode")
+            .help("The longest to wait before restarting a transcode's command, in seconds; the wait starts at a second and doubles each time it exits soon after starting [default: 30]"))
+        .arg(Arg::with_name("statsd")
+            .takes_value(true)
+            .long("statsd")
+            .help("Send the relay's metrics (channels, listeners, bitrates & memory) to the StatsD collector at this host:port"))
+        .arg(Arg::with_name("statsd_prefix")
+            .takes_value(true)
+            .long("statsd-prefix")
+            .requires("statsd")
+            .help("Start StatsD metrics' names with this [default: webmetro]"))
+        .arg(Arg::with_name("statsd_interval")
+            .takes_value(true)
+            .long("statsd-interval")
+            .requires("statsd")
+            .help("Send StatsD metrics every n seconds [default: 10]"))
+        .arg(Arg::with_name("dogstatsd")
+            .long("dogstatsd")
+            .requires("statsd")
+            .help("Tag StatsD metrics with their channel, as DogStatsD (Datadog) understands, instead of naming the channel in the metric"))
+        .arg(Arg::with_name("statsd_tag")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .long("statsd-tag")
+            .requires("statsd")
+            .help("Add this DogStatsD tag (e.g. env:prod) to every metric, implying --dogstatsd; may be repeated"))
+        .arg(Arg::with_name("mirror")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .long("mirror")
+            .help("POST a channel's stream to another server, given as channel=url; may be repeated"))
+        .arg(Arg::with_name("mirror_control")
+            .long("mirror-control")
+            .help("Let mirrors be started & stopped at runtime with PUT & DELETE to /mirror/<channel>, with the --admin-token-file token"))
+        .arg(Arg::with_name("pause_control")
+            .long("pause-control")
+            .help("Let channels be paused & resumed at runtime with PUT & DELETE to /pause/<channel>, holding their listeners between sources, with the --admin-token-file token"))
+        .arg(Arg::with_name("max_pause")
+            .takes_value(true)
+            .long("max-pause")
+            .help("Resume a channel once it's been paused this many seconds, ending its listeners if no source has come back [default: 1800]"))
+        .arg(Arg::with_name("config_store")
+            .takes_value(true)
+            .long("config-store")
+            .help("Keep channels' publishing keys, recording, mirrors, DVR windows & aliases in this file, so they survive restarts"))
+        .arg(Arg::with_name("config_control")
+            .long("config-control")
+            .requires("config_store")
+            .help("Let stored channel configuration be read & changed at runtime with GET, PUT & DELETE to /config/<channel>, with the --admin-token-file token"))
+        .arg(Arg::with_name("session_history")
+            .takes_value(true)
+            .long("session-history")
+            .help("Keep channels' broadcast sessions (reported at /channels/<channel>/history) in this file, so they survive restarts"))
+        .arg(Arg::with_name("cluster")
+            .takes_value(true)
+            .long("cluster")
+            .help("Share channels with other relays using this Redis server, so any of them can serve listeners for a source publishing to one"))
+        .arg(Arg::with_name("origin")
+            .takes_value(true)
+            .long("origin")
+            .help("Run as an edge of the relay at this base URL (e.g. http://origin:8080): a channel without a source here is pulled from <origin>/live/<channel> when a listener asks for it"))
+        .arg(Arg::with_name("origin_linger")
+            .takes_value(true)
+            .long("origin-linger")
+            .requires("origin")
+            .help("How many seconds to keep pulling a channel from the origin after its last listener leaves [default: 10]"))
+}
+
+fn bind_listener(addr: SocketAddr, backlog: i32) -> std::io::Result<TcpListener> {
+    let domain = match addr {
+        SocketAddr::V4(_) => Domain::ipv4(),
+        SocketAddr::V6(_) => Domain::ipv6(),
+    };
+    let socket = Socket::new(domain, Type::stream(), Some(Protocol::tcp()))?;
+    // match the behavior of std::net::TcpListener::bind on Unix
+    #[cfg(unix)]
+    socket.set_reuse_address(true)?;
+    socket.bind(&SockAddr::from(addr))?;
+    socket.listen(backlog)?;
+    Ok(socket.into_tcp_listener())
+}
+
+/// The user & group ids to switch to once listening, looked up by name
+#[cfg(unix)]
+#[derive(Clone, Copy, Debug, Default)]
+struct Identity {
+    uid: Option<libc::uid_t>,
+    gid: Option<libc::gid_t>,
+}
+
+#[cfg(not(unix))]
+#[derive(Clone, Copy, Debug)]
+struct Identity;
+
+/// Look up the --user & --group names. This happens before the runtime starts
+/// any threads, since getpwnam & getgrnam return pointers into static storage
+/// that a concurrent call would overwrite. The group defaults to the user's own.
+#[cfg(unix)]
+fn lookup_identity(user: Option<&str>, group: Option<&str>) -> Result<Identity, WebmetroError> {
+    use std::ffi::CString;
+
+    let name = |name: &str| CString::new(name).map_err(|_| WebmetroError::from("User & group names can't contain NUL"));
+
+    let mut identity = Identity::default();
+    if let Some(user) = user {
+        let entry = unsafe { libc::getpwnam(name(user)?.as_ptr()) };
+        if entry.is_null() {
+            return Err(WebmetroError::ApplicationError { message: format!("Unknown user {}", user) });
+        }
+        unsafe {
+            identity.uid = Some((*entry).pw_uid);
+            identity.gid = Some((*entry).pw_gid);
+        }
+    }
+    if let Some(group) = group {
+        let entry = unsafe { libc::getgrnam(name(group)?.as_ptr()) };
+        if entry.is_null() {
+            return Err(WebmetroError::ApplicationError { message: format!("Unknown group {}", group) });
+        }
+        identity.gid = Some(unsafe { (*entry).gr_gid });
+    }
+    Ok(identity)
+}
+
+#[cfg(not(unix))]
+fn lookup_identity(_user: Option<&str>, _group: Option<&str>) -> Result<Identity, WebmetroError> {
+    Err("--user & --group are only supported on Unix".into())
+}
+
+/// Switch to an unprivileged user and/or group, e.g. once ports below 1024
+/// have been bound as root. Supplementary groups are cleared.
+#[cfg(unix)]
+fn drop_privileges(identity: Identity) -> Result<(), WebmetroError> {
+    let last_error = |action: &str| WebmetroError::ApplicationError {
+        message: format!("Couldn't {}: {}", action, std::io::Error::last_os_error()),
+    };
+
+    // the group has to change first, while we're still allowed to
+    if let Some(gid) = identity.gid {
+        if unsafe { libc::setgroups(1, &gid) } != 0 {
+            return Err(last_error("clear supplementary groups"));
+        }
+        if unsafe { libc::setgid(gid) } != 0 {
+            return Err(last_error("change group"));
+        }
+    }
+    if let Some(uid) = identity.uid {
+        if unsafe { libc::setuid(uid) } != 0 {
+            return Err(last_error("change user"));
+        }
+    }
+    info!("Dropped privileges to user {} & group {}", unsafe { libc::getuid() }, unsafe { libc::getgid() });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn drop_privileges(_identity: Identity) -> Result<(), WebmetroError> {
+    Err("--user & --group are only supported on Unix".into())
+}
+
+pub fn run(args: &ArgMatches) -> Result<(), WebmetroError> {
+    let identity = if args.is_present("user") || args.is_present("group") {
+        Some(lookup_identity(args.value_of("user"), args.value_of("group"))?)
+    } else {
+        None
+    };
+
+    let mut builder = Builder::new();
+    if args.is_present("single_thread") {
+        builder.basic_scheduler();
+    } else {
+        builder.threaded_scheduler();
+        if let Some(workers) = args.value_of("workers") {
+            match workers.parse() {
+                Ok(workers) if workers > 0 => builder.core_threads(workers),
+                _ => return Err("Worker count must be a positive number".into()),
+            };
+        }
+    }
+    let mut runtime = builder.enable_all().build()?;
+    runtime.block_on(serve(args, identity))
 }
 
-#[tokio::main]
-pub async fn run(args: &ArgMatches) -> Result<(), WebmetroError> {
-    let channel_map = Arc::new(Mutex::new(WeakValueHashMap::<String, Weak<Mutex<Channel>>>::new()));
+async fn serve(args: &ArgMatches, identity: Option<Identity>) -> Result<(), WebmetroError> {
     let addr_str = args.value_of("listen").ok_or("Listen address wasn't provided")?;
 
+    let nodelay = args.is_present("nodelay");
+    let keepalive = parse_time(args.value_of("keepalive"))?;
+    let backlog: i32 = args.value_of("backlog").unwrap_or("128").parse()
+        .map_err(|_| WebmetroError::from("Backlog must be a number"))?;
+    let queue_limit = match args.value_of("listener_queue") {
+        Some(limit) => limit.parse().map_err(|_| WebmetroError::from("Listener queue must be a number"))?,
+        None => DEFAULT_QUEUE_LIMIT
+    };
+    let lag_policy: LagPolicy = args.value_of("lag_policy").unwrap_or("disconnect").parse()?;
+    let memory_limit = parse_size(args.value_of("memory_limit"))?.map(|limit| limit as usize);
+    let max_listeners_per_ip = match args.value_of("max_listeners_per_ip") {
+        Some(limit) => Some(limit.parse().map_err(|_| WebmetroError::from("Listener limit must be a number"))?),
+        None => None
+    };
+    let gap_threshold = match args.value_of("gap_threshold") {
+        Some(threshold) => threshold.parse().map_err(|_| WebmetroError::from("Gap threshold must be a number of milliseconds"))?,
+        None => DEFAULT_GAP_THRESHOLD
+    };
+    let max_av_drift = match args.value_of("max_av_drift") {
+        Some(drift) => Some(drift.parse().map_err(|_| WebmetroError::from("A/V drift must be a number of milliseconds"))?),
+        None => None
+    };
+    let fill_silence = match args.value_of("fill_silence") {
+        Some(threshold) => Some(threshold.parse().map_err(|_| WebmetroError::from("Silence threshold must be a number of milliseconds"))?),
+        None => None
+    };
+
+    let jwt = match (args.value_of("jwt_secret_file"), args.value_of("jwt_public_key")) {
+        (Some(path), _) => {
+            let secret = std::fs::read(path)?;
+            // a trailing newline in the file isn't part of the secret
+            let length = secret.iter().rposition(|byte| !byte.is_ascii_whitespace()).map_or(0, |last| last + 1);
+            Some(JwtAuthorizer::hs256(&secret[..length]))
+        },
+        (None, Some(path)) => Some(JwtAuthorizer::rs256(&std::fs::read(path)?)?),
+        (None, None) => None
+    }.map(|mut jwt| {
+        if let Some(issuer) = args.value_of("jwt_issuer") {
+            jwt = jwt.issuer(issuer);
+        }
+        if let Some(audience) = args.value_of("jwt_audience") {
+            jwt = jwt.audience(audience);
+        }
+        jwt
+    });
+
+    let webhook = match args.value_of("auth_webhook") {
+        Some(url) => {
+            let mut webhook_options = WebhookOptions::default();
+            if let Some(ttl) = parse_time(args.value_of("auth_webhook_cache"))? {
+                webhook_options.cache_ttl = ttl;
+            }
+            if let Some(limit) = parse_time(args.value_of("auth_webhook_timeout"))? {
+                webhook_options.timeout = limit;
+            }
+            if let Some(policy) = args.value_of("auth_webhook_failure") {
+                webhook_options.on_failure = policy.parse()?;
+            }
+            Some(WebhookAuthorizer::new(url, webhook_options)?)
+        },
+        None => None
+    };
+    // tokens are checked by the webhook, if there is one, instead of as JWTs
+    let tokens: Arc<dyn Authorizer> = match (jwt, webhook) {
+        (Some(jwt), _) => Arc::new(jwt),
+        (None, Some(webhook)) => Arc::new(webhook),
+        (None, None) => Arc::new(AllowAll),
+    };
+
+    let mut trusted_proxies = Vec::new();
+    for address in args.values_of("trusted_proxy").into_iter().flatten() {
+        match address.parse::<IpAddr>() {
+            Ok(address) => trusted_proxies.push(address),
+            Err(_) => return Err(WebmetroError::ApplicationError {
+                message: format!("--trusted-proxy {} should be an IP address", address),
+            }),
+        }
+    }
+    let trusted_proxies = Arc::new(trusted_proxies);
+
+    let mut certificate_rules = Vec::new();
+    for rule in args.values_of("publisher_cert").into_iter().flatten() {
+        let mut parts = rule.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(name), Some(prefix)) if !name.is_empty() => {
+                certificate_rules.push((name, prefix));
+            },
+            _ => return Err(WebmetroError::ApplicationError {
+                message: format!("--publisher-cert {} should look like name=channel-prefix", rule),
+            })
+        }
+    }
+
+    let hls = args.is_present("hls");
+    let mut hls_options = HlsOptions::default();
+    if let Some(duration) = parse_time(args.value_of("hls_segment_duration"))? {
+        hls_options.segment_duration = duration.as_millis() as u64;
+    }
+    if let Some(duration) = args.value_of("hls_part_duration") {
+        hls_options.part_duration = match duration.parse() {
+            Ok(duration) if duration > 0 => duration,
+            _ => return Err("HLS part duration must be a positive number of milliseconds".into()),
+        };
+    }
+    if let Some(window) = args.value_of("hls_window") {
+        hls_options.window = window.parse().map_err(|_| WebmetroError::from("HLS window must be a number"))?;
+    }
+
+    let timeouts = SourceTimeouts {
+        idle: parse_time(args.value_of("source_timeout"))?,
+        reconnect_grace: parse_time(args.value_of("reconnect_grace"))?,
+    };
+
+    let max_bitrate = match args.value_of("max_bitrate") {
+        Some(bitrate) => Some(bitrate.parse().map_err(|_| WebmetroError::from("Maximum bitrate must be a number of bits per second"))?),
+        None => None,
+    };
+    let bitrate_action: BitrateAction = args.value_of("max_bitrate_action").unwrap_or("end").parse()?;
+
+    let mut options = RelayOptions {
+        queue_limit,
+        lag_policy,
+        buffer_limit: BUFFER_LIMIT,
+        resync: args.is_present("resync"),
+        memory_limit,
+        max_listeners_per_ip,
+        max_cluster_duration: if hls { Some(hls_options.part_duration) } else { None },
+        gap_threshold,
+        max_av_drift,
+        fill_silence,
+        hls: hls_options,
+        dvr_window: parse_time(args.value_of("dvr_window"))?.map(|window| window.as_millis() as u64),
+        max_publish_duration: parse_time(args.value_of("max_publish_duration"))?.map(|duration| duration.as_millis() as u64),
+        end_with_source: args.is_present("end_with_source"),
+        reconnect_grace: timeouts.reconnect_grace.is_some(),
+        max_bitrate,
+        bitrate_action,
+        max_pause: parse_time(args.value_of("max_pause"))?.unwrap_or(DEFAULT_MAX_PAUSE),
+        ..RelayOptions::default()
+    };
+    for header in args.values_of("header").into_iter().flatten() {
+        let (name, value) = parse_header(header)?;
+        options.set_media_header(name, value);
+    }
+
+    let config_store = match args.value_of("config_store") {
+        Some(path) => Some(Arc::new(ConfigStore::open(path)?)),
+        None => None
+    };
+
+    // client certificates are checked first, then tokens
+    let authorizer: Arc<dyn Authorizer> = if !certificate_rules.is_empty() {
+        Arc::new(certificate_rules.into_iter()
+            .fold(CertificateAuthorizer::new(tokens), |authorizer, (name, prefix)| authorizer.allow(name, prefix)))
+    } else {
+        tokens
+    };
+    // but a channel's own publishing key, if it has one, comes before either
+    let authorizer: Arc<dyn Authorizer> = match config_store {
+        Some(ref store) => Arc::new(KeyAuthorizer::new(store.clone(), authorizer)),
+        None => authorizer,
+    };
+    let history = match args.value_of("session_history") {
+        Some(path) => History::open(path, DEFAULT_SESSIONS_KEPT)?,
+        None => History::default(),
+    };
+    let mut relay = Relay::new(options).with_authorizer(authorizer).with_history(history);
+    if let Some(path) = args.value_of("admin_token_file") {
+        let token = std::fs::read_to_string(path)?;
+        let token = token.trim();
+        if token.is_empty() {
+            return Err(WebmetroError::ApplicationError {
+                message: format!("{} should hold the admin token", path),
+            });
+        }
+        relay = relay.with_admin_authorizer(AdminToken::new(token));
+    }
+    let relay = Arc::new(relay);
+
+    let segment_duration = parse_time(args.value_of("record_segment_duration"))?;
+    let segment_size = parse_size(args.value_of("record_segment_size"))?;
+    let upload = match args.value_of("upload_url") {
+        Some(url) => {
+            let credentials = Credentials {
+                access_key: std::env::var("AWS_ACCESS_KEY_ID").map_err(|_| "AWS_ACCESS_KEY_ID must be set to upload recordings")?,
+                secret_key: std::env::var("AWS_SECRET_ACCESS_KEY").map_err(|_| "AWS_SECRET_ACCESS_KEY must be set to upload recordings")?,
+            };
+            let target = S3Target::from_url(url, args.value_of("upload_region").unwrap_or("us-east-1"), credentials)?;
+            let retention: Retention = args.value_of("upload_retention").unwrap_or("keep").parse()?;
+            let (queue, uploader) = upload_queue(target, retention);
+            tokio::spawn(uploader.instrument(info_span!("upload")));
+            Some(queue)
+        },
+        None => None
+    };
+    for (option, redirect) in &[("alias", false), ("redirect", true)] {
+        for mapping in args.values_of(option).into_iter().flatten() {
+            let mut names = mapping.splitn(2, '=');
+            match (names.next(), names.next()) {
+                (Some(alias), Some(channel)) if !alias.is_empty() && !channel.is_empty() => {
+                    relay.set_alias(alias, channel, *redirect);
+                },
+                _ => return Err(WebmetroError::ApplicationError {
+                    message: format!("--{} {} should look like alias=channel", option, mapping),
+                })
+            }
+        }
+    }
+
+    for mapping in args.values_of("mirror").into_iter().flatten() {
+        let mut parts = mapping.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(channel), Some(target)) if !channel.is_empty() && is_mirror_target(target) => {
+                set_mirror(&relay, channel, Some(target));
+            },
+            _ => return Err(WebmetroError::ApplicationError {
+                message: format!("--mirror {} should look like channel=http://host/path", mapping),
+            })
+        }
+    }
+
+    let hooks = args.values_of("hook").into_iter().flatten()
+        .map(str::parse)
+        .collect::<Result<Vec<Hook>, _>>()?;
+    let hooks = if hooks.is_empty() {
+        None
+    } else {
+        let mut hook_options = HookOptions::default();
+        if let Some(limit) = parse_time(args.value_of("hook_timeout"))? {
+            hook_options.timeout = limit;
+        }
+        if let Some(running) = args.value_of("max_running_hooks") {
+            hook_options.max_running = match running.parse() {
+                Ok(running) if running > 0 => running,
+                _ => return Err("The number of running hooks must be a positive number".into()),
+            };
+        }
+        let (queue, runner) = hook_runner(hooks, hook_options);
+        tokio::spawn(runner.instrument(info_span!("hooks")));
+        let described = relay.clone();
+        tokio::spawn(queue.follow(relay.subscribe_lossless(None), move |channel| described.metadata(channel)));
+        Some(queue)
+    };
+
+    let transcodes = args.values_of("transcode").into_iter().flatten()
+        .map(str::parse)
+        .collect::<Result<Vec<Transcode>, _>>()?;
+    if !transcodes.is_empty() {
+        let mut transcode_options = TranscodeOptions::default();
+        if let Some(delay) = parse_time(args.value_of("transcode_restart_delay"))? {
+            transcode_options.max_restart_delay = delay.max(transcode_options.restart_delay);
+        }
+        tokio::spawn(transcoder(relay.clone(), transcodes, transcode_options).instrument(info_span!("transcoder")));
+    }
+
+    let record_settings = args.value_of("record_dir").map(|dir| RecordSettings {
+        dir: PathBuf::from(dir),
+        segment_duration: segment_duration.map(|duration| duration.as_millis() as u64),
+        segment_size,
+        upload,
+        hooks,
+    });
+    let record_all = args.is_present("record_all");
+
+    if let Some(address) = args.value_of("statsd") {
+        let address = address.to_socket_addrs()?.next()
+            .ok_or_else(|| WebmetroError::ApplicationError {
+                message: format!("Can't resolve StatsD address {}", address),
+            })?;
+        let mut statsd_options = StatsdOptions::default();
+        if let Some(prefix) = args.value_of("statsd_prefix") {
+            statsd_options.prefix = prefix.to_string();
+        }
+        if let Some(interval) = parse_time(args.value_of("statsd_interval"))? {
+            statsd_options.interval = interval.max(Duration::from_secs(1));
+        }
+        let tags: Vec<String> = args.values_of("statsd_tag").into_iter().flatten().map(str::to_string).collect();
+        if args.is_present("dogstatsd") || !tags.is_empty() {
+            statsd_options.tags = Some(tags);
+        }
+        tokio::spawn(statsd(relay.clone(), address, statsd_options)?.instrument(info_span!("statsd")));
+    }
+
+    if let Some(url) = args.value_of("cluster") {
+        let address: RedisAddress = url.parse()?;
+        tokio::spawn(cluster(relay.clone(), address).instrument(info_span!("cluster")));
+    }
+
+    // kept until the server stops, since the relay only holds it weakly
+    let _edge = match args.value_of("origin") {
+        Some(origin) => {
+            let mut edge_options = EdgeOptions::new(origin);
+            if let Some(linger) = parse_time(args.value_of("origin_linger"))? {
+                edge_options.linger = linger;
+            }
+            let edge = Arc::new(Edge::new(relay.clone(), edge_options)?);
+            edge.serve_demand();
+            Some(edge)
+        },
+        None => None
+    };
+
+    if let Some(ref store) = config_store {
+        for (channel, config) in store.channels() {
+            apply_config(&relay, record_settings.as_ref(), &channel, &ChannelConfig::default(), &config);
+        }
+    }
+
+    let access_log = match args.value_of("access_log") {
+        Some(path) => {
+            let mut log = AccessLog::open(path).map_err(|err| WebmetroError::ApplicationError {
+                message: format!("{}: {}", path, err),
+            })?;
+            if let Some(max_size) = parse_size(args.value_of("access_log_max_size"))? {
+                log = log.max_size(max_size);
+            }
+            if let Some(max_age) = parse_time(args.value_of("access_log_max_age"))? {
+                log = log.max_age(max_age);
+            }
+            Some(log.spawn()?)
+        },
+        None => None
+    };
+    let ingest_limit = token_bucket(args, "ingest")?;
+    let egress_limit = token_bucket(args, "egress")?;
+    let idle_padding = parse_time(args.value_of("idle_padding"))?;
+
     let addrs = addr_str.to_socket_addrs()?;
     info!("Binding to {:?}", addrs);
     if addrs.len() == 0 {
         return Err("Listen address didn't resolve".into());
     }
 
-    let channel = path!("live" / String).map(move |name: String| {
-        let channel = channel_map.lock().unwrap()
-            .entry(name.clone())
-            .or_insert_with(|| Channel::new(name.clone()));
-        (channel, name)
-    });
+    let head_relay = relay.clone();
+    let head = warp::head().and(access_request(Action::Probe))
+        .and(media_format())
+        .and_then(move |request: AccessRequest, format: MediaFormat| {
+            let relay = head_relay.clone();
+            async move {
+                let request = match resolve_alias(&relay, request) {
+                    Ok(request) => request,
+                    Err(response) => return Ok::<_, Infallible>(response),
+                };
+                if !relay.authorize(&request).await {
+                    return Ok::<_, Infallible>(forbidden());
+                }
+                info!("HEAD Request For Channel {}", request.channel);
+                Ok(media_response(&relay, Egress::Stream(format), Body::empty()))
+            }
+        });
 
-    let head = channel.clone().and(warp::head())
-        .map(|(_, name)| {
-            info!("HEAD Request For Channel {}", name);
-            media_response(Body::empty())
+    let get_relay = relay.clone();
+    let get_access_log = access_log.clone();
+    let get = warp::get().and(listen_request(args.is_present("ogg")))
+        .and(rate_limit(egress_limit))
+        .and(warp::query::<HashMap<String, String>>().or(warp::any().map(HashMap::new)).unify())
+        .and_then(move |request: AccessRequest, egress: Egress, query: HashMap<String, String>| {
+            let relay = get_relay.clone();
+            let access_log = get_access_log.clone();
+            async move {
+                let request = match resolve_alias(&relay, request) {
+                    Ok(request) => request,
+                    Err(response) => return Ok::<_, Infallible>(response),
+                };
+                if !relay.authorize(&request).await {
+                    return Ok::<_, Infallible>(forbidden());
+                }
+                let slot = match request.remote {
+                    Some(remote) => match relay.reserve_listener(remote.ip()) {
+                        Some(slot) => Some(slot),
+                        None => {
+                            info!("Too many listeners from {}", remote.ip());
+                            return Ok(too_many_requests());
+                        }
+                    },
+                    None => None
+                };
+                let span = info_span!("listener", channel = %request.channel, remote = ?request.remote);
+                span.in_scope(|| info!("Listener Connected On Channel {}", request.channel));
+                let mut session = log_session(&access_log, &request);
+                // ?rewind=<seconds> starts that far behind the live edge
+                let rewind = query.get("rewind")
+                    .and_then(|seconds| seconds.parse::<f64>().ok())
+                    .filter(|seconds| *seconds > 0.0)
+                    .map(|seconds| (seconds * 1000.0) as u64);
+                // ?audio_track=<number> or ?audio_lang=<language> picks one audio track of several
+                let audio = query.get("audio_track")
+                    .and_then(|number| number.parse().ok())
+                    .map(AudioChoice::Track)
+                    .or_else(|| query.get("audio_lang").filter(|language| !language.is_empty()).cloned().map(AudioChoice::Language));
+                // the listener can look up its own stats with this, at /live/<channel>/session/<token>
+                let token = relay.new_session();
+                let viewer = Viewer {
+                    remote: request.remote,
+                    session: Some(token.clone()),
+                    audio,
+                };
+                // the slot is given back (and the session logged) once the stream is dropped
+                let stream = span.in_scope(|| match egress {
+                    Egress::Stream(format) => Either::Left(match rewind {
+                        Some(rewind) => Either::Left(relay.listen_rewound(&request.channel, viewer, format, rewind)),
+                        None => Either::Right(relay.listen_from(&request.channel, viewer, format)),
+                    }),
+                    Egress::Cmaf => Either::Right(Either::Left(relay.listen_cmaf(&request.channel, viewer))),
+                    Egress::Ogg => Either::Right(Either::Right(Either::Left(relay.listen_ogg(&request.channel, viewer)))),
+                    Egress::Media => Either::Right(Either::Right(Either::Right(relay.listen_media(&request.channel, viewer)))),
+                });
+                // Void elements only make sense in WebM; they're sent during
+                // pauses regardless, so held listeners aren't timed out
+                let stream = match (egress, idle_padding) {
+                    (Egress::Stream(_), Some(interval)) | (Egress::Media, Some(interval)) => Either::Left(IdlePadding::new(Box::pin(stream), interval)),
+                    (Egress::Stream(_), None) | (Egress::Media, None) => {
+                        let relay = relay.clone();
+                        let channel = request.channel.clone();
+                        Either::Left(IdlePadding::new(Box::pin(stream), PAUSE_KEEPALIVE)
+                            .only_while(move || relay.is_paused(&channel)))
+                    },
+                    _ => Either::Right(stream),
+                };
+                let stream = stream
+                    .inspect(move |item| {
+                        if let (Some(session), Ok(bytes)) = (session.as_mut(), item) {
+                            session.add(bytes.len());
+                        }
+                    });
+                let stream = Holding::new(stream, slot);
+                let mut response = media_response(&relay, egress, Body::wrap_stream(stream.instrument(span)));
+                response.headers_mut().insert("X-Webmetro-Session", HeaderValue::from_str(&token).unwrap());
+                Ok(response)
+            }
         });
 
-    let get = channel.clone().and(warp::get())
-        .map(|(channel, name)| {
-            info!("Listener Connected On Channel {}", name);
-            media_response(Body::wrap_stream(get_stream(channel)))
+    let post_relay = relay.clone();
+    let post_record_settings = record_settings.clone();
+    let post_access_log = access_log.clone();
+    let post_put = warp::post().or(warp::put()).unify()
+        .and(access_request(Action::Publish))
+        .and(rate_limit(ingest_limit.clone()))
+        .and(warp::query::<HashMap<String, String>>())
+        .and(warp::body::stream())
+        .and_then(move |request: AccessRequest, query: HashMap<String, String>, body| {
+            let relay = post_relay.clone();
+            let record_settings = post_record_settings.clone();
+            let access_log = post_access_log.clone();
+            async move {
+                let request = match resolve_alias(&relay, request) {
+                    Ok(request) => request,
+                    Err(response) => return Ok::<_, Infallible>(response),
+                };
+                if !relay.authorize(&request).await {
+                    return Ok::<_, Infallible>(denied(&request));
+                }
+                let options = PublishOptions::from_query(&query, record_all);
+                let metadata = match query_metadata(&query) {
+                    Ok(metadata) => metadata,
+                    Err(err) => return Ok(bad_config(StatusCode::BAD_REQUEST, err.to_string())),
+                };
+                if !options.takeover && relay.has_source(&request.channel) {
+                    info!("Channel {} already has a source", request.channel);
+                    return Ok(channel_busy());
+                }
+                let span = info_span!("publisher", channel = %request.channel, remote = ?request.remote);
+                span.in_scope(|| info!("Source Connected On Channel {}", request.channel));
+
+                // the session is logged once the body is dropped
+                let mut session = log_session(&access_log, &request);
+                let body = body.inspect(move |item: &Result<_, warp::Error>| {
+                    if let (Some(session), Ok(buf)) = (session.as_mut(), item) {
+                        session.add(buf.remaining());
+                    }
+                });
+                // read up to the first Cluster before answering, so a bad
+                // source gets a status explaining why instead of a cut-off body
+                let buffer_limit = relay.options().buffer_limit;
+                let checked = match timeouts.idle {
+                    Some(idle) => timeout(idle, check_source(body, buffer_limit)).await
+                        .unwrap_or_else(|elapsed| Err(elapsed.into())),
+                    None => check_source(body, buffer_limit).await,
+                };
+                let body = match checked {
+                    Ok(body) => body,
+                    Err(err) => {
+                        span.in_scope(|| warn!("Refusing source: {}", err));
+                        return Ok(refused_source(&err));
+                    }
+                };
+                // set before publishing, so publish-start hooks see it
+                if metadata.is_some() {
+                    relay.set_metadata(&request.channel, metadata);
+                }
+                // create the pipeline in the span, so its stages' spans are children of it
+                let ingest = span.in_scope(|| publish_source(&relay, record_settings.as_ref(), options, timeouts, &request.channel, body))
+                    .map_ok(|()| Bytes::new())
+                    .inspect_err(|err| {
+                        warn!("{}", err)
+                    })
+                    .into_stream();
+                Ok(Response::new(Body::wrap_stream(ingest.instrument(span))))
+            }
         });
 
-    let post_put = channel.clone().and(warp::post().or(warp::put()).unify())
-        .and(warp::body::stream()).map(|(channel, name), stream| {
-            info!("Source Connected On Channel {}", name);
-            Response::new(Body::wrap_stream(post_stream(channel, stream)))
+    let ws_relay = relay.clone();
+    let ws_record_settings = record_settings.clone();
+    let ws_access_log = access_log.clone();
+    let websocket = warp::ws()
+        .and(access_request(Action::Publish))
+        .and(rate_limit(ingest_limit))
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(move |ws: Ws, request: AccessRequest, query: HashMap<String, String>| {
+            let relay = ws_relay.clone();
+            let record_settings = ws_record_settings.clone();
+            let access_log = ws_access_log.clone();
+            async move {
+                let request = match resolve_alias(&relay, request) {
+                    Ok(request) => request,
+                    Err(response) => return Ok::<_, Infallible>(response),
+                };
+                if !relay.authorize(&request).await {
+                    return Ok::<_, Infallible>(denied(&request));
+                }
+                let options = PublishOptions::from_query(&query, record_all);
+                let metadata = match query_metadata(&query) {
+                    Ok(metadata) => metadata,
+                    Err(err) => return Ok(bad_config(StatusCode::BAD_REQUEST, err.to_string())),
+                };
+                if !options.takeover && relay.has_source(&request.channel) {
+                    info!("Channel {} already has a source", request.channel);
+                    return Ok(channel_busy());
+                }
+                let span = info_span!("publisher", channel = %request.channel, remote = ?request.remote);
+                span.in_scope(|| info!("WebSocket Source Connected On Channel {}", request.channel));
+
+                Ok(ws.on_upgrade(move |socket| {
+                    // set just before publishing, so it isn't forgotten as
+                    // a closed channel's in the meantime
+                    if metadata.is_some() {
+                        relay.set_metadata(&request.channel, metadata);
+                    }
+                    // the session is logged once the socket is dropped
+                    let mut session = log_session(&access_log, &request);
+                    // binary messages carry the WebM bytes; anything else is ignored
+                    let body = socket
+                        .try_filter(|message| future::ready(message.is_binary()))
+                        .map_ok(move |message| {
+                            if let Some(session) = session.as_mut() {
+                                session.add(message.as_bytes().len());
+                            }
+                            Bytes::copy_from_slice(message.as_bytes())
+                        });
+                    let ingest = span.in_scope(|| publish_source(&relay, record_settings.as_ref(), options, timeouts, &request.channel, Box::pin(body)));
+                    ingest
+                        .map(|result| if let Err(err) = result {
+                            warn!("{}", err)
+                        })
+                        .instrument(span)
+                }).into_response())
+            }
         });
 
-    let routes = head
+    let live = head
+        .or(websocket)
         .or(get)
-        .or(post_put);
+        .or(post_put)
+        .map(Reply::into_response);
+    let mut routes = event_routes(relay.clone())
+        .or(listener_stats_route(relay.clone())).unify()
+        .or(rendition_status_route(relay.clone())).unify()
+        .or(manifest_route(relay.clone())).unify()
+        .or(metadata_routes(relay.clone())).unify()
+        .or(session_stats_route(relay.clone())).unify()
+        .or(history_route(relay.clone())).unify()
+        .or(snapshot_routes(relay.clone())).unify()
+        .or(live).unify()
+        .boxed();
+    if let Some(dir) = args.value_of("vod_dir") {
+        info!("Serving recordings from {}", dir);
+        routes = vod_routes(PathBuf::from(dir)).or(routes).unify().boxed();
+    }
+    let config_record_settings = record_settings.clone();
+    if let Some(settings) = record_settings {
+        info!("Recording to {}", settings.dir.display());
+        routes = record_routes(relay.clone(), settings).or(routes).unify().boxed();
+    }
+    if args.is_present("mirror_control") {
+        routes = mirror_routes(relay.clone()).or(routes).unify().boxed();
+    }
+    if args.is_present("pause_control") {
+        routes = pause_routes(relay.clone()).or(routes).unify().boxed();
+    }
+    // sources can pause their channels too (with ?pause=on), so this runs regardless
+    let pause_relay = relay.clone();
+    tokio::spawn(async move {
+        loop {
+            delay_for(PAUSE_CHECK_INTERVAL).await;
+            pause_relay.expire_pauses();
+        }
+    });
+    let metadata_relay = relay.clone();
+    tokio::spawn(async move {
+        loop {
+            delay_for(METADATA_CHECK_INTERVAL).await;
+            metadata_relay.expire_metadata();
+        }
+    });
+    match config_store {
+        Some(store) if args.is_present("config_control") => {
+            routes = config_routes(relay.clone(), store, config_record_settings).or(routes).unify().boxed();
+        },
+        _ => {}
+    }
+    if hls {
+        routes = hls_routes(relay.clone()).or(routes).unify().boxed();
+        // stop packaging channels nobody's watching over HLS
+        let hls_relay = relay.clone();
+        tokio::spawn(async move {
+            loop {
+                delay_for(HLS_IDLE_TIMEOUT).await;
+                hls_relay.expire_hls(HLS_IDLE_TIMEOUT);
+            }
+        });
+    }
+    let routes = routes
+        .recover(|rejection: warp::Rejection| async move {
+            if rejection.find::<RateLimited>().is_some() {
+                Ok(too_many_requests())
+            } else {
+                Err(rejection)
+            }
+        })
+        .unify()
+        .boxed();
 
-    let mut server_futures: FuturesUnordered<_> = addrs.map(|addr| warp::serve(routes.clone()).try_bind(addr)).collect();
+    let require_all_binds = args.is_present("require_all_binds");
+    let mut server_futures = FuturesUnordered::new();
+    let mut failed_binds = 0;
+    for addr in addrs {
+        let listener = match bind_listener(addr, backlog) {
+            Ok(listener) => listener,
+            Err(err) => {
+                let err = std::io::Error::new(err.kind(), format!("Couldn't bind to {}: {}", addr, err));
+                if require_all_binds {
+                    return Err(err.into());
+                }
+                error!("{}", err);
+                failed_binds += 1;
+                continue;
+            }
+        };
+
+        let routes = routes.clone();
+        let trusted_proxies = trusted_proxies.clone();
+        let make_service = make_service_fn(move |conn: &AddrStream| {
+            let remote = conn.remote_addr();
+            debug!(remote = %remote, "Connection accepted");
+            let mut service = warp::service(routes.clone());
+            let trusted_proxies = trusted_proxies.clone();
+            let service = service_fn(move |mut request: Request<Body>| {
+                strip_certificate_headers(request.headers_mut(), remote.ip(), &trusted_proxies);
+                service.call(request)
+            });
+            async move { Ok::<_, Infallible>(service) }
+        });
 
-    while let Some(_) = server_futures.next().await {};
+        info!("Listening on {}", addr);
+        server_futures.push(
+            Server::from_tcp(listener)?
+                // listener streams send each Cluster's head & body as separate
+                // frames; queue them for writev instead of copying them together
+                .http1_writev(true)
+                .tcp_nodelay(nodelay)
+                .tcp_keepalive(keepalive)
+                .serve(make_service)
+                .map(move |result| (addr, result))
+        );
+    }
+
+    if server_futures.is_empty() {
+        return Err("Couldn't bind to any listen address".into());
+    }
+    if failed_binds > 0 {
+        warn!("Serving on {} of {} addresses (pass --require-all-binds to refuse to start instead)", server_futures.len(), server_futures.len() + failed_binds);
+    }
+
+    // every address is bound (or failed to), so root isn't needed any more
+    if let Some(identity) = identity {
+        drop_privileges(identity)?;
+    }
+
+    while let Some((addr, result)) = server_futures.next().await {
+        match result {
+            Ok(()) => warn!("Stopped serving on {}", addr),
+            Err(err) => {
+                error!("Serving on {} failed: {}", addr, err);
+                return Err(err.into());
+            }
+        }
+    }
 
     Ok(())
 }
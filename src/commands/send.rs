@@ -1,26 +1,52 @@
-use bytes::Bytes;
 use clap::{App, Arg, ArgMatches, SubCommand};
-use futures::prelude::*;
-use hyper::{client::HttpConnector, Body, Client, Request};
-use std::io::{stdout, Write};
-use stream::iter;
+use futures::{poll, prelude::*, stream::iter};
+use hyper::{body::Sender, header::{CONTENT_LENGTH, EXPECT}, Body, Client, Method, Request, Response};
+use hyper_tls::HttpsConnector;
+use std::io::Write;
+use std::path::PathBuf;
+use std::task::Poll;
+use std::time::Duration;
+use tokio::{fs::File, task::JoinHandle, time::delay_for};
 
-use super::{parse_time, stdin_stream};
+use super::{http_stream, input_stream, looped_chunk_stream, output_writer, parse_time, BoxedChunkStream};
 use webmetro::{
     chunk::{Chunk, WebmStream},
     error::WebmetroError,
     fixers::{ChunkTimecodeFixer, Throttle},
+    server::redact_token,
     stream_parser::StreamEbml,
 };
 
+const INITIAL_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
 pub fn options() -> App<'static, 'static> {
     SubCommand::with_name("send")
-        .about("PUTs WebM from stdin to a relay server.")
+        .about("PUTs WebM from stdin or a file (or a URL, or a looped file) to a relay server.")
         .arg(Arg::with_name("url")
             .help("The location to upload to")
             .required(true))
+        .arg(Arg::with_name("input")
+            .help("The file to upload; reads stdin if omitted"))
+        .arg(Arg::with_name("output")
+            .takes_value(true)
+            .short("o")
+            .long("output")
+            .help("The file to write the relay's response to; writes stdout if omitted"))
+        .arg(Arg::with_name("source")
+            .takes_value(true)
+            .long("source")
+            .conflicts_with("input")
+            .help("Fetch WebM from an http(s) URL instead of reading stdin, republishing another server's stream"))
+        .arg(Arg::with_name("loop")
+            .takes_value(true)
+            .value_name("FILE")
+            .long("loop")
+            .conflicts_with_all(&["source", "input"])
+            .help("Read WebM from a file instead of stdin, repeating it forever with timecodes carrying on from each pass to the next"))
         .arg(Arg::with_name("throttle")
             .long("throttle")
+            .visible_alias("realtime")
             .help("Slow down upload to \"real time\" speed as determined by the timestamps (useful for streaming static files)"))
         .arg(Arg::with_name("skip")
             .takes_value(true)
@@ -32,9 +58,57 @@ pub fn options() -> App<'static, 'static> {
             .short("t")
             .long("take")
             .help("Stop uploading after approximately n seconds of content"))
+        .arg(Arg::with_name("takeover")
+            .long("takeover")
+            .help("Replace the channel's current source, if it has one, instead of being refused; this includes a stale connection of this upload's own when reconnecting"))
+        .arg(Arg::with_name("retries")
+            .takes_value(true)
+            .long("retries")
+            .default_value("5")
+            .help("Reconnect up to n times in a row if the connection to the relay drops"))
+        .arg(Arg::with_name("content_length")
+            .long("content-length")
+            .conflicts_with_all(&["source", "loop"])
+            .help("Read all the input before uploading, and send it with a Content-Length rather than chunked, for servers & proxies that need one; such an upload can't resume after a dropped connection"))
 }
 
-type BoxedChunkStream = Box<dyn Stream<Item = Result<Chunk, WebmetroError>> + Send + Sync + Unpin>;
+/// Remembers the most recent initialization segment & the clusters since the
+/// last keyframe, so a new connection can resume with a decodable stream.
+#[derive(Default)]
+struct ResumePoint {
+    headers: Option<Chunk>,
+    clusters: Vec<Chunk>,
+}
+
+impl ResumePoint {
+    fn observe(&mut self, chunk: &Chunk) {
+        match chunk {
+            Chunk::Headers {..} => {
+                self.headers = Some(chunk.clone());
+                self.clusters.clear();
+            },
+            Chunk::Cluster(head, _) if head.keyframe => {
+                self.clusters.clear();
+                self.clusters.push(chunk.clone());
+            },
+            Chunk::Cluster(..) if !self.clusters.is_empty() => {
+                self.clusters.push(chunk.clone());
+            },
+            _ => {}
+        }
+    }
+
+    fn replay(&self) -> Vec<Chunk> {
+        self.headers.iter().chain(self.clusters.iter()).cloned().collect()
+    }
+}
+
+async fn send_chunk(sender: &mut Sender, chunk: Chunk) -> Result<(), hyper::Error> {
+    for buffer in chunk {
+        sender.send_data(buffer).await?;
+    }
+    Ok(())
+}
 
 #[tokio::main]
 pub async fn run(args: &ArgMatches) -> Result<(), WebmetroError> {
@@ -47,37 +121,210 @@ pub async fn run(args: &ArgMatches) -> Result<(), WebmetroError> {
     let start_time = parse_time(args.value_of("skip"))?.map_or(0, |s| s.as_millis());
     let stop_time = parse_time(args.value_of("take"))?.map_or(std::u128::MAX, |t| t.as_millis() + start_time);
 
+    let max_retries: u32 = args.value_of("retries").unwrap_or("5").parse()
+        .map_err(|_| WebmetroError::from("Retry count must be a number"))?;
+
+    let input: BoxedChunkStream = match (args.value_of("source"), args.value_of("loop")) {
+        (Some(source_url), _) => Box::new(http_stream(source_url).await?.parse_ebml().chunk_webm()),
+        (None, Some(path)) => {
+            let path = PathBuf::from(path);
+            // fail early on a bad path, instead of on every pass
+            File::open(&path).await?;
+            // a loop never ends by itself, so stop it once past --take
+            Box::new(looped_chunk_stream(path).try_take_while(move |chunk| future::ready(Ok(match chunk {
+                Chunk::Cluster(head, _) => (head.start as u128) <= stop_time,
+                _ => true,
+            }))))
+        },
+        (None, None) => Box::new(input_stream(args.value_of("input")).await?.parse_ebml().chunk_webm()),
+    };
+
     // build pipeline
     let mut timecode_fixer = ChunkTimecodeFixer::new();
     let mut chunk_stream: BoxedChunkStream = Box::new(
-        stdin_stream()
-            .parse_ebml()
-            .chunk_webm()
+        input
             .map_ok(move |chunk| timecode_fixer.process(chunk))
             .try_filter(move |chunk| future::ready(chunk.overlaps(start_time, stop_time))),
     );
 
+    // the whole body has to be read to know its length
+    let content_length = if args.is_present("content_length") {
+        let chunks: Vec<Chunk> = chunk_stream.try_collect().await?;
+        let length = chunks.iter().map(|chunk| chunk.size() as u64).sum();
+        chunk_stream = Box::new(iter(chunks).map(Ok));
+        Some(length)
+    } else {
+        None
+    };
+
     if args.is_present("throttle") {
         chunk_stream = Box::new(Throttle::new(chunk_stream));
     }
 
-    let chunk_stream = chunk_stream
-        .map_ok(|webm_chunk| iter(webm_chunk).map(Result::<Bytes, WebmetroError>::Ok))
-        .try_flatten()
-        .map_err(|err| {
-            warn!("{}", &err);
-            err
-        });
-
-    let request_payload = Body::wrap_stream(chunk_stream);
+    let url_str = if args.is_present("takeover") { takeover_url(&url_str) } else { url_str };
 
-    let request = Request::put(url_str).body(request_payload)?;
-    let client = Client::builder().build(HttpConnector::new());
+    let output = output_writer(args.value_of("output"))?;
+    upload(&url_str, chunk_stream, content_length, max_retries, output).await
+}
 
-    let response = client.request(request).await?;
-    let mut response_stream = response.into_body();
+/// PUTs a chunk stream to a relay, reconnecting with exponential backoff
+/// (up to `max_retries` times in a row) if the connection drops, and copies
+/// the response to `output`. With a `content_length`, the body is sent with
+/// that length rather than chunked (see `send_body`).
+pub async fn upload(url_str: &str, chunk_stream: BoxedChunkStream, content_length: Option<u64>, max_retries: u32, mut output: impl Write) -> Result<(), WebmetroError> {
+    let mut response_stream = send_body(Method::PUT, url_str, chunk_stream, content_length, max_retries).await?;
     while let Some(response_chunk) = response_stream.try_next().await? {
-        stdout().write_all(&response_chunk)?;
+        output.write_all(&response_chunk)?;
     }
+    output.flush()?;
     Ok(())
 }
+
+/// Sends a chunk stream as the body of a request, reconnecting with
+/// exponential backoff (up to `max_retries` times in a row) if the connection
+/// drops; each new connection resumes with the headers & latest keyframe.
+/// Reconnections ask to take the channel over, in case the relay hasn't
+/// noticed the old connection dropping yet. A refusal that retrying won't fix
+/// (see `WebmetroError::is_retryable`) fails at once, and a server's
+/// `Retry-After` is respected. Each request asks with `Expect: 100-continue`,
+/// and the relay's answer is watched for as the stream is sent: a refusal
+/// (e.g. of credentials or limits) stops the upload with its status &
+/// explanation, while a 2xx is the relay taking it. Returns the response body
+/// once the stream ends.
+pub async fn stream_to<S>(method: Method, url_str: &str, chunk_stream: S, max_retries: u32) -> Result<Body, WebmetroError>
+where
+    S: Stream<Item = Result<Chunk, WebmetroError>> + Unpin,
+{
+    send_body(method, url_str, chunk_stream, None, max_retries).await
+}
+
+/// Like `stream_to`, but a body of `content_length` bytes is sent with a
+/// Content-Length header rather than chunked. Such a body can't be resumed
+/// over a new connection, as the rest wouldn't add up to the length given,
+/// so a dropped connection fails it.
+pub async fn send_body<S>(method: Method, url_str: &str, mut chunk_stream: S, content_length: Option<u64>, max_retries: u32) -> Result<Body, WebmetroError>
+where
+    S: Stream<Item = Result<Chunk, WebmetroError>> + Unpin,
+{
+    let client = Client::builder().build(HttpsConnector::new());
+    let mut resume_point = ResumePoint::default();
+    let mut replay = Vec::new();
+    let mut retries = 0;
+    let mut backoff = INITIAL_BACKOFF;
+    let shown_url = redact_token(url_str);
+
+    loop {
+        let (mut sender, request_payload) = Body::channel();
+        let mut request = Request::builder()
+            .method(method.clone())
+            .uri(url_str)
+            .header(EXPECT, "100-continue");
+        if let Some(length) = content_length {
+            request = request.header(CONTENT_LENGTH, length);
+        }
+        let request = request.body(request_payload)?;
+        let mut response = tokio::spawn(client.request(request));
+        let mut answer = None;
+
+        let mut connection_lost = false;
+        for chunk in replay.drain(..) {
+            if send_chunk(&mut sender, chunk).await.is_err() || refused(&mut response, &mut answer).await? {
+                connection_lost = true;
+                break;
+            }
+        }
+
+        if !connection_lost {
+            while let Some(chunk) = chunk_stream.try_next().await.map_err(|err| {
+                warn!("{}", &err);
+                err
+            })? {
+                resume_point.observe(&chunk);
+                if send_chunk(&mut sender, chunk).await.is_err() || refused(&mut response, &mut answer).await? {
+                    connection_lost = true;
+                    break;
+                }
+                // fresh data made it through, so this connection counts as healthy
+                retries = 0;
+                backoff = INITIAL_BACKOFF;
+            }
+        }
+
+        // dropping the sender ends the request body
+        drop(sender);
+        let response = match answer {
+            Some(response) => response,
+            None => response.await.map_err(|err| WebmetroError::from(err.to_string().as_str()))?,
+        };
+
+        // a refusal once everything's sent may still be worth another try, below
+        let response = match response {
+            Ok(response) if !connection_lost && response.status().is_success() => return Ok(response.into_body()),
+            Err(err) if !connection_lost => return Err(err.into()),
+            response => response,
+        };
+
+        let retry_after = match response {
+            Err(err) => {
+                warn!("Connection to {} lost: {}", shown_url, err);
+                None
+            },
+            Ok(response) if response.status().is_success() => {
+                warn!("Connection to {} lost ({})", shown_url, response.status());
+                None
+            },
+            Ok(response) => {
+                let err = WebmetroError::from_response_body(response).await;
+                // e.g. refused credentials, which won't do any better next time
+                if !err.is_retryable() {
+                    warn!("Connection to {} refused: {}", shown_url, err);
+                    return Err(err);
+                }
+                warn!("Connection to {} lost: {}", shown_url, err);
+                err.retry_after()
+            },
+        };
+
+        if content_length.is_some() {
+            return Err("The connection was lost, and a body with a Content-Length can't be resumed".into());
+        }
+        if retries >= max_retries {
+            return Err("Gave up reconnecting".into());
+        }
+        retries += 1;
+
+        // wait at least as long as the server asked
+        let wait = retry_after.map_or(backoff, |retry_after| std::cmp::max(retry_after, backoff));
+        info!("Reconnecting in {:?} (attempt {} of {})", wait, retries, max_retries);
+        delay_for(wait).await;
+        backoff = std::cmp::min(backoff * 2, MAX_BACKOFF);
+
+        replay = resume_point.replay();
+    }
+}
+
+/// Take the relay's answer if it's come while the request is still being
+/// sent, and say whether it turned the request down; a 2xx means it's
+/// taking the stream, so sending carries on
+async fn refused(response: &mut JoinHandle<Result<Response<Body>, hyper::Error>>, answer: &mut Option<Result<Response<Body>, hyper::Error>>) -> Result<bool, WebmetroError> {
+    if answer.is_none() {
+        if let Poll::Ready(result) = poll!(response) {
+            *answer = Some(result.map_err(|err| WebmetroError::from(err.to_string().as_str()))?);
+        }
+    }
+    Ok(match answer {
+        Some(Ok(response)) => !response.status().is_success(),
+        Some(Err(_)) => true,
+        None => false,
+    })
+}
+
+/// The URL with a `takeover=1` query parameter added, unless it has one
+fn takeover_url(url_str: &str) -> String {
+    let query = url_str.splitn(2, '?').nth(1).unwrap_or("");
+    if query.split('&').any(|parameter| parameter.starts_with("takeover=")) {
+        return url_str.to_string();
+    }
+    let separator = if url_str.contains('?') { '&' } else { '?' };
+    format!("{}{}takeover=1", url_str, separator)
+}
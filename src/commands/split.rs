@@ -0,0 +1,86 @@
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use clap::{App, Arg, ArgMatches, SubCommand};
+use futures::prelude::*;
+
+use super::{create_file, input_stream, numbered_path, parse_size, parse_time};
+use webmetro::{
+    chunk::{Chunk, WebmStream},
+    error::WebmetroError,
+    recorder::WebmFileWriter,
+    segmenter::Segmenter,
+    stream_parser::StreamEbml,
+};
+
+type FileWriter = WebmFileWriter<BufWriter<File>>;
+
+pub fn options() -> App<'static, 'static> {
+    SubCommand::with_name("split")
+        .about("Cuts WebM into independently playable files at keyframe clusters.")
+        .arg(Arg::with_name("output")
+            .help("Base name for the output files, which are numbered, e.g. out-1.webm, out-2.webm")
+            .required(true))
+        .arg(Arg::with_name("input")
+            .help("The file or http(s) URL to split; reads stdin if omitted"))
+        .arg(Arg::with_name("duration")
+            .takes_value(true)
+            .short("d")
+            .long("duration")
+            .required_unless("size")
+            .help("Start a new file at the first keyframe after approximately n seconds"))
+        .arg(Arg::with_name("size")
+            .takes_value(true)
+            .long("size")
+            .help("Start a new file at the first keyframe after approximately n bytes (K, M, and G suffixes are allowed)"))
+}
+
+fn create(output: &Path, index: usize, headers: &Chunk) -> Result<FileWriter, WebmetroError> {
+    let path = numbered_path(output, index);
+    info!("Writing {}", path.display());
+    WebmFileWriter::new(create_file(&path)?, headers)
+}
+
+#[tokio::main]
+pub async fn run(args: &ArgMatches) -> Result<(), WebmetroError> {
+    let output = PathBuf::from(args.value_of("output").ok_or("Output file wasn't provided")?);
+    let max_duration = parse_time(args.value_of("duration"))?.map_or(std::u64::MAX, |d| d.as_millis() as u64);
+    let mut segmenter = Segmenter::new(max_duration);
+    if let Some(size) = parse_size(args.value_of("size"))? {
+        segmenter = segmenter.max_size(size as usize);
+    }
+
+    let mut chunks = input_stream(args.value_of("input")).await?
+        .parse_ebml()
+        .chunk_webm();
+
+    // each piece is written as it comes, rather than holding whole segments;
+    // a chained stream may have different tracks, so its segments are never mixed into one file
+    let mut file_count = 0;
+    let mut writer: Option<FileWriter> = None;
+    while let Some(chunk) = chunks.try_next().await? {
+        for (starts_segment, cluster) in segmenter.cut(chunk) {
+            if starts_segment {
+                if let Some(finished) = writer.take() {
+                    finished.finish()?;
+                }
+                file_count += 1;
+                let headers = segmenter.headers().cloned().unwrap_or_default();
+                writer = Some(create(&output, file_count, &Chunk::Headers { bytes: headers })?);
+            }
+            if let Some(ref mut writer) = writer {
+                writer.write_cluster(&cluster)?;
+            }
+        }
+    }
+    if let Some(finished) = writer {
+        finished.finish()?;
+    }
+
+    if file_count == 0 {
+        return Err("Stream has no keyframes to start a file at".into());
+    }
+    info!("Wrote {} file(s)", file_count);
+    Ok(())
+}
@@ -0,0 +1,380 @@
+//! Channel configuration that survives relay restarts: publishing keys,
+//! recording, mirrors, DVR depth, publishing limits, and aliases. It's kept in a flat file with
+//! one setting per line, which is rewritten whenever a channel changes.
+//! Keys are only kept as their SHA-256 digests; one given in plain text is
+//! replaced by its digest when the file is next written:
+//!
+//! ```text
+//! main key sha256:<64 hex digits>
+//! main record
+//! main mirror https://backup.example/live/main
+//! main dvr 30000
+//! main max-duration 3600000
+//! main max-bitrate 6000000
+//! main alias tv
+//! main redirect old-main
+//! ```
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+
+use futures::future::{ready, BoxFuture, FutureExt};
+use sha2::{Digest, Sha256};
+
+use crate::error::WebmetroError;
+use crate::server::{split_rendition, AccessRequest, Action, Authorizer};
+
+/// One channel's stored settings
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ChannelConfig {
+    /// the digest (see `key_digest`) of a key sources must present, as a
+    /// bearer token, to publish
+    pub publish_key: Option<String>,
+    pub record: bool,
+    /// where to mirror the channel to
+    pub mirror: Option<String>,
+    /// how many ms of the channel to keep for listeners that rewind,
+    /// instead of the relay's default
+    pub dvr_window: Option<u64>,
+    /// how many ms sources may publish for before they're ended, instead of
+    /// the relay's default
+    pub max_publish_duration: Option<u64>,
+    /// how many bits per second sources may publish, instead of the relay's
+    /// default
+    pub max_bitrate: Option<u64>,
+    /// other names the channel goes by, and whether each redirects to it
+    /// rather than serving it directly
+    pub aliases: Vec<(String, bool)>,
+}
+
+impl ChannelConfig {
+    /// Apply one "setting [value]" line
+    fn set(&mut self, line: &str) -> Result<(), WebmetroError> {
+        let mut words = line.split_whitespace();
+        let setting = words.next().unwrap_or("");
+        let value = words.next();
+        let extra = words.next();
+        match (setting, value, extra) {
+            ("key", Some(key), None) => self.publish_key = Some(if is_key_digest(key) {
+                key.to_ascii_lowercase()
+            } else {
+                key_digest(key)
+            }),
+            ("record", None, None) => self.record = true,
+            ("mirror", Some(target), None) => self.mirror = Some(target.to_string()),
+            ("dvr", Some(window), None) => match window.parse() {
+                Ok(window) => self.dvr_window = Some(window),
+                Err(_) => return Err(WebmetroError::ApplicationError {
+                    message: format!("DVR window \"{}\" should be a number of ms", window),
+                }),
+            },
+            ("max-duration", Some(duration), None) => match duration.parse() {
+                Ok(duration) => self.max_publish_duration = Some(duration),
+                Err(_) => return Err(WebmetroError::ApplicationError {
+                    message: format!("Maximum publishing duration \"{}\" should be a number of ms", duration),
+                }),
+            },
+            ("max-bitrate", Some(bitrate), None) => match bitrate.parse() {
+                Ok(bitrate) => self.max_bitrate = Some(bitrate),
+                Err(_) => return Err(WebmetroError::ApplicationError {
+                    message: format!("Maximum bitrate \"{}\" should be a number of bits per second", bitrate),
+                }),
+            },
+            ("alias", Some(alias), None) => self.aliases.push((alias.to_string(), false)),
+            ("redirect", Some(alias), None) => self.aliases.push((alias.to_string(), true)),
+            _ => return Err(WebmetroError::ApplicationError {
+                message: format!("Unknown channel setting \"{}\"", line.trim()),
+            }),
+        }
+        Ok(())
+    }
+}
+
+/// Reads settings as written by `Display`, one per line; blank lines &
+/// `#` comments are skipped
+impl FromStr for ChannelConfig {
+    type Err = WebmetroError;
+
+    fn from_str(text: &str) -> Result<ChannelConfig, WebmetroError> {
+        let mut config = ChannelConfig::default();
+        for line in text.lines().map(str::trim) {
+            if !line.is_empty() && !line.starts_with('#') {
+                config.set(line)?;
+            }
+        }
+        Ok(config)
+    }
+}
+
+impl fmt::Display for ChannelConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if let Some(ref key) = self.publish_key {
+            writeln!(f, "key {}", key)?;
+        }
+        if self.record {
+            writeln!(f, "record")?;
+        }
+        if let Some(ref target) = self.mirror {
+            writeln!(f, "mirror {}", target)?;
+        }
+        if let Some(window) = self.dvr_window {
+            writeln!(f, "dvr {}", window)?;
+        }
+        if let Some(duration) = self.max_publish_duration {
+            writeln!(f, "max-duration {}", duration)?;
+        }
+        if let Some(bitrate) = self.max_bitrate {
+            writeln!(f, "max-bitrate {}", bitrate)?;
+        }
+        for (alias, redirect) in &self.aliases {
+            writeln!(f, "{} {}", if *redirect { "redirect" } else { "alias" }, alias)?;
+        }
+        Ok(())
+    }
+}
+
+/// Every channel's stored settings, backed by a file
+pub struct ConfigStore {
+    path: PathBuf,
+    channels: Mutex<BTreeMap<String, ChannelConfig>>,
+}
+
+impl ConfigStore {
+    /// Load the store at `path`, starting out empty if there's no file yet
+    pub fn open(path: impl Into<PathBuf>) -> Result<ConfigStore, WebmetroError> {
+        let path = path.into();
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut channels = BTreeMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut parts = line.splitn(2, char::is_whitespace);
+            let channel = parts.next().unwrap_or("");
+            let setting = parts.next().unwrap_or("");
+            channels.entry(channel.to_string())
+                .or_insert_with(ChannelConfig::default)
+                .set(setting)
+                .map_err(|err| WebmetroError::ApplicationError {
+                    message: format!("{} line {}: {}", path.display(), number + 1, err),
+                })?;
+        }
+
+        Ok(ConfigStore {
+            path,
+            channels: Mutex::new(channels),
+        })
+    }
+
+    /// A channel's settings, which are all defaults if none were stored
+    pub fn channel(&self, name: &str) -> ChannelConfig {
+        let channels = self.channels.lock().expect("Locking config store");
+        channels.get(name).cloned().unwrap_or_default()
+    }
+
+    /// Every channel with stored settings
+    pub fn channels(&self) -> Vec<(String, ChannelConfig)> {
+        let channels = self.channels.lock().expect("Locking config store");
+        channels.iter().map(|(name, config)| (name.clone(), config.clone())).collect()
+    }
+
+    /// Replace a channel's settings & save the store, returning the old ones;
+    /// the defaults forget the channel. Fails without changing anything if
+    /// the file can't be written, or one of the aliases is another channel's.
+    pub fn set_channel(&self, name: &str, config: ChannelConfig) -> Result<ChannelConfig, WebmetroError> {
+        if name.is_empty() || name.contains(char::is_whitespace) {
+            return Err(WebmetroError::ApplicationError {
+                message: format!("Channel name \"{}\" can't be stored", name),
+            });
+        }
+        let mut channels = self.channels.lock().expect("Locking config store");
+        for (alias, _) in &config.aliases {
+            let owner = channels.iter().find(|(channel, other)| {
+                channel.as_str() != name && other.aliases.iter().any(|(other_alias, _)| other_alias == alias)
+            });
+            if let Some((owner, _)) = owner {
+                return Err(WebmetroError::ApplicationError {
+                    message: format!("{} is already an alias for channel {}", alias, owner),
+                });
+            }
+        }
+
+        let mut updated = channels.clone();
+        let old = if config == ChannelConfig::default() {
+            updated.remove(name)
+        } else {
+            updated.insert(name.to_string(), config)
+        };
+        self.save(&updated)?;
+        *channels = updated;
+        Ok(old.unwrap_or_default())
+    }
+
+    fn save(&self, channels: &BTreeMap<String, ChannelConfig>) -> Result<(), WebmetroError> {
+        let mut text = String::from("# webmetro channel configuration; rewritten by the relay\n");
+        for (name, config) in channels {
+            for line in config.to_string().lines() {
+                text.push_str(&format!("{} {}\n", name, line));
+            }
+        }
+        save_file(&self.path, &text)?;
+        Ok(())
+    }
+}
+
+/// Write a file in full, then move it into place, so a crash can't leave it
+/// half-written
+pub(crate) fn save_file(path: &Path, text: &str) -> io::Result<()> {
+    let mut temporary = path.to_path_buf().into_os_string();
+    temporary.push(".tmp");
+    let mut file = File::create(&temporary)?;
+    file.write_all(text.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temporary, path)
+}
+
+/// How a publishing key is stored, as `sha256:` and its hex SHA-256 digest
+pub fn key_digest(key: &str) -> String {
+    format!("sha256:{}", hex::encode(Sha256::digest(key.as_bytes())))
+}
+
+fn is_key_digest(key: &str) -> bool {
+    match key.strip_prefix("sha256:") {
+        Some(digest) => digest.len() == 64 && digest.bytes().all(|byte| byte.is_ascii_hexdigit()),
+        None => false,
+    }
+}
+
+/// Compare keys in time that doesn't depend on where they differ
+pub(crate) fn same_key(given: &str, key: &str) -> bool {
+    given.len() == key.len()
+        && given.bytes().zip(key.bytes()).fold(0, |difference, (a, b)| difference | (a ^ b)) == 0
+}
+
+/// Lets sources publish to a channel with a stored key only if they present
+/// it as a bearer token (e.g. with `?token=<key>`), admitting those that do;
+/// every other request is left to the next Authorizer
+pub struct KeyAuthorizer {
+    store: Arc<ConfigStore>,
+    next: Arc<dyn Authorizer>,
+}
+
+impl KeyAuthorizer {
+    pub fn new(store: Arc<ConfigStore>, next: impl Authorizer + 'static) -> Self {
+        KeyAuthorizer {
+            store,
+            next: Arc::new(next),
+        }
+    }
+}
+
+impl Authorizer for KeyAuthorizer {
+    fn authorize(&self, request: &AccessRequest) -> BoxFuture<'static, bool> {
+        if request.action == Action::Publish {
+            // a simulcast channel's key covers its renditions
+            let channel = split_rendition(&request.channel).map_or(request.channel.as_str(), |(group, _)| group);
+            if let Some(key) = self.store.channel(channel).publish_key {
+                let presented = request.credentials.as_ref()
+                    .filter(|credentials| credentials.starts_with("Bearer "))
+                    .map_or(false, |credentials| same_key(&key_digest(&credentials["Bearer ".len()..]), &key));
+                return ready(presented).boxed();
+            }
+        }
+        self.next.authorize(request)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::process;
+
+    use futures::executor::block_on;
+
+    use crate::config_store::*;
+
+    fn main_config() -> ChannelConfig {
+        ChannelConfig {
+            publish_key: Some(key_digest("s3cret")),
+            record: true,
+            mirror: Some("https://backup.example/live/main".into()),
+            dvr_window: Some(30_000),
+            max_publish_duration: Some(3_600_000),
+            max_bitrate: Some(6_000_000),
+            aliases: vec![("tv".into(), false), ("old-main".into(), true)],
+        }
+    }
+
+    #[test]
+    fn parse_channel_configs() {
+        let config = main_config();
+        assert_eq!(config.to_string().parse::<ChannelConfig>().unwrap(), config);
+        assert_eq!("# nothing yet\n\n".parse::<ChannelConfig>().unwrap(), ChannelConfig::default());
+        // keys are only kept as digests
+        assert_eq!("key s3cret".parse::<ChannelConfig>().unwrap().publish_key, Some(key_digest("s3cret")));
+        assert!(!config.to_string().contains("s3cret"));
+        assert!("dvr soon".parse::<ChannelConfig>().is_err());
+        assert!("max-duration 1h".parse::<ChannelConfig>().is_err());
+        assert!("max-bitrate 6M".parse::<ChannelConfig>().is_err());
+        assert!("record always".parse::<ChannelConfig>().is_err());
+        assert!("colour blue".parse::<ChannelConfig>().is_err());
+    }
+
+    #[test]
+    fn persist_channels() {
+        let path = temp_dir().join(format!("webmetro-config-{}", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let store = ConfigStore::open(&path).unwrap();
+        assert_eq!(store.set_channel("main", main_config()).unwrap(), ChannelConfig::default());
+        let alias_taken = ChannelConfig {
+            aliases: vec![("tv".into(), false)],
+            ..ChannelConfig::default()
+        };
+        assert!(store.set_channel("other", alias_taken).is_err());
+        assert!(store.set_channel("two words", main_config()).is_err());
+
+        let reopened = ConfigStore::open(&path).unwrap();
+        assert_eq!(reopened.channels(), vec![("main".to_string(), main_config())]);
+        assert_eq!(reopened.set_channel("main", ChannelConfig::default()).unwrap(), main_config());
+        assert_eq!(ConfigStore::open(&path).unwrap().channels(), vec![]);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn require_publish_keys() {
+        let path = temp_dir().join(format!("webmetro-config-keys-{}", process::id()));
+        let store = Arc::new(ConfigStore::open(&path).unwrap());
+        store.set_channel("main", main_config()).unwrap();
+        let authorizer = KeyAuthorizer::new(store, crate::server::AllowAll);
+
+        let request = |action, channel: &str, credentials: Option<&str>| AccessRequest {
+            action,
+            channel: channel.into(),
+            credentials: credentials.map(String::from),
+            remote: None,
+            client_names: Vec::new(),
+        };
+        assert!(block_on(authorizer.authorize(&request(Action::Publish, "main", Some("Bearer s3cret")))));
+        assert!(!block_on(authorizer.authorize(&request(Action::Publish, "main/720p", None))));
+        assert!(!block_on(authorizer.authorize(&request(Action::Publish, "main", Some("Bearer guess")))));
+        assert!(!block_on(authorizer.authorize(&request(Action::Publish, "main", None))));
+        // listeners, and channels without keys, are left to the next authorizer
+        assert!(block_on(authorizer.authorize(&request(Action::Listen, "main", None))));
+        assert!(block_on(authorizer.authorize(&request(Action::Publish, "other", None))));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
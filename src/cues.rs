@@ -0,0 +1,285 @@
+//! Reads the index of a finalized WebM file (such as `WebmFileWriter`
+//! writes), so a tool can seek to a time without scanning every Cluster
+//! before it.
+
+use std::io::{Read, Seek, SeekFrom};
+use std::time::Duration;
+
+use crate::ebml::*;
+use crate::error::WebmetroError;
+use crate::iterator::ebml_iter;
+use crate::webm::*;
+
+/// A Cluster listed in a file's Cues, which starts with a keyframe
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Cue {
+    /// the Cluster's timecode, in the file's ticks (see
+    /// `FileIndex::timestamp_scale`)
+    pub time: u64,
+    pub track: u64,
+    /// from the start of the Segment's payload
+    pub cluster_position: u64,
+}
+
+enum CueElement<'b> {
+    Point(&'b[u8]),
+    Time(u64),
+    TrackPositions(&'b[u8]),
+    Track(u64),
+    ClusterPosition(u64),
+    Seek(&'b[u8]),
+    SeekId(&'b[u8]),
+    SeekPosition(u64),
+    Other
+}
+
+impl<'b> FromEbml<'b> for CueElement<'b> {
+    fn should_unwrap(_element_id: u64) -> bool {
+        false
+    }
+
+    fn decode(element_id: u64, bytes: &'b[u8]) -> Result<CueElement<'b>, EbmlError> {
+        match element_id {
+            CUE_POINT_ID => Ok(CueElement::Point(bytes)),
+            CUE_TIME_ID => decode_uint(bytes).map(CueElement::Time),
+            CUE_TRACK_POSITIONS_ID => Ok(CueElement::TrackPositions(bytes)),
+            CUE_TRACK_ID => decode_uint(bytes).map(CueElement::Track),
+            CUE_CLUSTER_POSITION_ID => decode_uint(bytes).map(CueElement::ClusterPosition),
+            SEEK_ID => Ok(CueElement::Seek(bytes)),
+            SEEK_ID_ID => Ok(CueElement::SeekId(bytes)),
+            SEEK_POSITION_ID => decode_uint(bytes).map(CueElement::SeekPosition),
+            _ => Ok(CueElement::Other)
+        }
+    }
+}
+
+/// Extract the cue points from the payload of a Cues element, in time order
+pub fn parse_cues(cues: &[u8]) -> Vec<Cue> {
+    let mut parsed = Vec::new();
+    for point in ebml_iter::<CueElement>(cues) {
+        let point = match point {
+            CueElement::Point(point) => point,
+            _ => continue,
+        };
+        let mut time = None;
+        let mut positions = Vec::new();
+        for element in ebml_iter::<CueElement>(point) {
+            match element {
+                CueElement::Time(cue_time) => time = Some(cue_time),
+                CueElement::TrackPositions(track_positions) => {
+                    let mut track = None;
+                    let mut cluster_position = None;
+                    for element in ebml_iter::<CueElement>(track_positions) {
+                        match element {
+                            CueElement::Track(number) => track = Some(number),
+                            CueElement::ClusterPosition(position) => cluster_position = Some(position),
+                            _ => {}
+                        }
+                    }
+                    if let (Some(track), Some(cluster_position)) = (track, cluster_position) {
+                        positions.push((track, cluster_position));
+                    }
+                },
+                _ => {}
+            }
+        }
+        if let Some(time) = time {
+            parsed.extend(positions.into_iter().map(|(track, cluster_position)| Cue { time, track, cluster_position }));
+        }
+    }
+    parsed.sort_by_key(|cue| cue.time);
+    parsed
+}
+
+/// Where the SeekHead says an element is, from the start of the Segment's payload
+fn seek_position(seek_head: &[u8], element_id: u64) -> Option<u64> {
+    ebml_iter::<CueElement>(seek_head).find_map(|seek| match seek {
+        CueElement::Seek(seek) => {
+            let mut id = None;
+            let mut position = None;
+            for element in ebml_iter::<CueElement>(seek) {
+                match element {
+                    CueElement::SeekId(bytes) => id = match decode_varint(bytes) {
+                        Ok(Some((Varint::Value(id), _))) => Some(id),
+                        _ => None,
+                    },
+                    CueElement::SeekPosition(seek_position) => position = Some(seek_position),
+                    _ => {}
+                }
+            }
+            position.filter(|_| id == Some(element_id))
+        },
+        _ => None,
+    })
+}
+
+/// What's needed to seek in a file
+#[derive(Clone, Debug, PartialEq)]
+pub struct FileIndex {
+    /// where the Segment's payload starts, which Cue & SeekHead positions count from
+    pub segment_start: u64,
+    /// where the first Cluster starts, and so where the headers end
+    pub first_cluster: u64,
+    /// nanoseconds per timecode tick, from the Segment's Info
+    pub timestamp_scale: u64,
+    /// the file's headers, rebuilt to stream from: its EBML header, a Segment
+    /// of unknown size, and the elements before the first Cluster, without
+    /// the SeekHead, Cues & Voids, whose positions no longer hold
+    pub headers: Vec<u8>,
+    /// empty if the file has no Cues
+    pub cues: Vec<Cue>,
+}
+
+impl FileIndex {
+    /// Where to start reading Clusters to play from `time`: the latest cued
+    /// Cluster at or before it, or else the first Cluster
+    pub fn seek(&self, time: Duration) -> (u64, Option<Cue>) {
+        let ticks = (time.as_nanos() / self.timestamp_scale as u128) as u64;
+        match self.cues.iter().rev().find(|cue| cue.time <= ticks) {
+            Some(cue) => (self.segment_start + cue.cluster_position, Some(*cue)),
+            None => (self.first_cluster, None),
+        }
+    }
+
+    /// When a cued Cluster starts
+    pub fn cue_time(&self, cue: &Cue) -> Duration {
+        Duration::from_nanos(cue.time.saturating_mul(self.timestamp_scale))
+    }
+}
+
+/// Read an element's ID, size, & header length at `position`, leaving the
+/// input at its payload
+fn read_tag<R: Read + Seek>(input: &mut R, position: u64) -> Result<(u64, Varint, u64), WebmetroError> {
+    input.seek(SeekFrom::Start(position))?;
+    // an ID takes at most 4 bytes, and a size at most 8
+    let mut buffer = Vec::new();
+    input.by_ref().take(12).read_to_end(&mut buffer)?;
+    match decode_tag(&buffer)? {
+        Some((id, size, header_length)) => {
+            input.seek(SeekFrom::Start(position + header_length as u64))?;
+            Ok((id, size, header_length as u64))
+        },
+        None => Err("File ended before its first Cluster".into()),
+    }
+}
+
+/// Read the payload of an element of `size`, which mustn't be unknown
+fn read_payload<R: Read>(input: &mut R, size: &Varint) -> Result<Vec<u8>, WebmetroError> {
+    let size = match *size {
+        Varint::Value(size) => size,
+        Varint::Unknown => return Err(EbmlError::UnknownElementLength.into()),
+    };
+    let mut payload = Vec::new();
+    input.take(size).read_to_end(&mut payload)?;
+    if (payload.len() as u64) < size {
+        return Err("File ended partway through an element".into());
+    }
+    Ok(payload)
+}
+
+/// Read the top-level elements of a file up to its first Cluster, and its
+/// Cues, wherever the SeekHead says they are
+pub fn read_index<R: Read + Seek>(input: &mut R) -> Result<FileIndex, WebmetroError> {
+    let mut position = 0;
+    let mut segment_start = None;
+    let mut timestamp_scale = DEFAULT_TIMESTAMP_SCALE;
+    let mut headers = Vec::new();
+    let mut cues = None;
+    let mut cues_position = None;
+    let first_cluster = loop {
+        let (id, size, header_length) = read_tag(input, position)?;
+        match id {
+            SEGMENT_ID => {
+                // its children follow
+                encode_tag_header(SEGMENT_ID, Varint::Unknown, &mut headers)?;
+                position += header_length;
+                segment_start = Some(position);
+                continue;
+            },
+            CLUSTER_ID => break position,
+            SEEK_HEAD_ID => cues_position = seek_position(&read_payload(input, &size)?, CUES_ID),
+            CUES_ID => cues = Some(parse_cues(&read_payload(input, &size)?)),
+            VOID_ID => {},
+            _ => {
+                let payload = read_payload(input, &size)?;
+                if id == SEGMENT_INFO_ID {
+                    timestamp_scale = info_timestamp_scale(&payload);
+                }
+                encode_tag_header(id, Varint::Value(payload.len() as u64), &mut headers)?;
+                headers.extend_from_slice(&payload);
+            }
+        }
+        match size {
+            Varint::Value(size) => position += header_length + size,
+            Varint::Unknown => return Err(EbmlError::UnknownElementLength.into()),
+        }
+    };
+    let segment_start = segment_start.ok_or("Not a WebM file")?;
+
+    if let (None, Some(cues_position)) = (&cues, cues_position) {
+        let (id, size, _) = read_tag(input, segment_start + cues_position)?;
+        if id == CUES_ID {
+            cues = Some(parse_cues(&read_payload(input, &size)?));
+        }
+    }
+
+    Ok(FileIndex {
+        segment_start,
+        first_cluster,
+        timestamp_scale,
+        headers,
+        cues: cues.unwrap_or_default(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use futures::{executor::block_on, stream, TryStreamExt};
+
+    use crate::chunk::{Chunk, WebmStream};
+    use crate::cues::*;
+    use crate::recorder::WebmFileWriter;
+    use crate::stream_parser::StreamEbml;
+    use crate::tests::TEST_FILE;
+
+    #[test]
+    fn read_written_index() {
+        let chunks: Vec<Chunk> = block_on(stream::iter(vec![Ok::<&[u8], WebmetroError>(TEST_FILE)])
+            .parse_ebml()
+            .chunk_webm()
+            .try_collect())
+            .unwrap();
+        let mut writer = WebmFileWriter::new(Cursor::new(Vec::new()), &chunks[0]).unwrap();
+        for chunk in &chunks[1..] {
+            writer.write_cluster(chunk).unwrap();
+        }
+        let mut file = writer.finish().unwrap();
+
+        let index = read_index(&mut file).unwrap();
+        assert!(index.cues.len() > 1);
+        let file = file.into_inner();
+        assert_eq!(&file[index.first_cluster as usize..][..4], &[0x1F, 0x43, 0xB6, 0x75]);
+
+        // every cue points at a Cluster
+        for cue in &index.cues {
+            let position = (index.segment_start + cue.cluster_position) as usize;
+            assert_eq!(&file[position..][..4], &[0x1F, 0x43, 0xB6, 0x75]);
+        }
+        assert_eq!(index.timestamp_scale, DEFAULT_TIMESTAMP_SCALE);
+        let last = *index.cues.last().unwrap();
+        let after = index.cue_time(&last) + Duration::from_millis(1);
+        assert_eq!(index.seek(after), (index.segment_start + last.cluster_position, Some(last)));
+        assert_eq!(index.seek(Duration::from_secs(0)).1, Some(index.cues[0]));
+
+        // the headers keep the Tracks, but not the SeekHead pointing into the old file
+        let elements: Vec<WebmElement> = parse_webm(&index.headers).collect();
+        assert_eq!(elements[0], WebmElement::EbmlHead);
+        assert!(elements.iter().any(|element| matches!(element, WebmElement::Tracks(_))));
+        assert!(!elements.iter().any(|element| matches!(element, WebmElement::SeekHead | WebmElement::Cues)));
+
+        // without a Segment, it's not a WebM file
+        assert!(read_index(&mut Cursor::new(&file[index.segment_start as usize..])).is_err());
+    }
+}
@@ -102,6 +102,16 @@ pub fn decode_tag(bytes: &[u8]) -> Result<Option<(u64, Varint, usize)>, EbmlErro
     }
 }
 
+/// The total length of an element, given its header length & payload size;
+/// a corrupt size can be too big to address, so this is checked rather than
+/// left to overflow.
+pub fn element_length(header_length: usize, payload_size: u64) -> Result<usize, EbmlError> {
+    if payload_size > usize::max_value() as u64 {
+        return Err(EbmlError::CorruptPayload);
+    }
+    (payload_size as usize).checked_add(header_length).ok_or(EbmlError::CorruptPayload)
+}
+
 pub fn decode_uint(bytes: &[u8]) -> Result<u64, EbmlError> {
     if bytes.len() < 1 || bytes.len() > 8 {
         return Err(EbmlError::CorruptPayload);
@@ -110,6 +120,24 @@ pub fn decode_uint(bytes: &[u8]) -> Result<u64, EbmlError> {
     Ok(BigEndian::read_uint(bytes, bytes.len()))
 }
 
+/// Decode a signed integer, e.g. a ReferenceBlock's relative timecode
+pub fn decode_int(bytes: &[u8]) -> Result<i64, EbmlError> {
+    if bytes.is_empty() || bytes.len() > 8 {
+        return Err(EbmlError::CorruptPayload);
+    }
+
+    Ok(BigEndian::read_int(bytes, bytes.len()))
+}
+
+pub fn decode_float(bytes: &[u8]) -> Result<f64, EbmlError> {
+    match bytes.len() {
+        0 => Ok(0.0),
+        4 => Ok(BigEndian::read_f32(bytes) as f64),
+        8 => Ok(BigEndian::read_f64(bytes)),
+        _ => Err(EbmlError::CorruptPayload)
+    }
+}
+
 const SMALL_FLAG: u64 = 0x80;
 const EIGHT_FLAG: u64 = 0x01 << (8*7);
 const EIGHT_MAX: u64 = EIGHT_FLAG - 2;
@@ -161,6 +189,22 @@ pub fn encode_varint_4<T: Write>(varint: Varint, output: &mut T) -> IoResult<()>
     output.write_all(&buffer)
 }
 
+// tries to write a varint with a fixed 8-byte representation
+pub fn encode_varint_8<T: Write>(varint: Varint, output: &mut T) -> IoResult<()> {
+    let number = match varint {
+        Varint::Unknown => EIGHT_FLAG | (EIGHT_FLAG - 1),
+        Varint::Value(too_big) if too_big > EIGHT_MAX => {
+            return Err(IoError::new(ErrorKind::InvalidInput, WriteError::OutOfRange))
+        },
+        Varint::Value(value) => EIGHT_FLAG | value
+    };
+
+    let mut buffer = [0; 8];
+    buffer.as_mut().put_u64(number);
+
+    output.write_all(&buffer)
+}
+
 pub fn encode_element<T: Write + Seek, F: Fn(&mut T) -> IoResult<X>, X>(tag: u64, output: &mut T, content: F) -> IoResult<()> {
     encode_varint(Varint::Value(tag), output)?;
     encode_varint_4(Varint::Unknown, output)?;
@@ -197,6 +241,16 @@ pub fn encode_integer<T: Write>(tag: u64, value: u64, output: &mut T) -> IoResul
     output.write_all(&buffer[..])
 }
 
+/// Tries to write a simple EBML tag with a floating-point value
+pub fn encode_float<T: Write>(tag: u64, value: f64, output: &mut T) -> IoResult<()> {
+    encode_tag_header(tag, Varint::Value(8), output)?;
+
+    let mut buffer = [0; 8];
+    buffer.as_mut().put_f64(value);
+
+    output.write_all(&buffer[..])
+}
+
 pub struct EbmlLayout {
     pub element_id: u64,
     pub body_offset: usize,
@@ -216,37 +270,51 @@ pub trait FromEbml<'a>: Sized {
     /// references into the given buffer.
     fn decode(element_id: u64, bytes: &'a[u8]) -> Result<Self, EbmlError>;
 
-    /// Check if enough space exists in the given buffer to decode an element;
-    /// it will not actually call `decode` or try to construct an instance,
-    /// but EBML errors with the next tag header will be returned eagerly.
-    fn check_space(bytes: &[u8]) -> Result<Option<EbmlLayout>, EbmlError> {
+    /// Decode the next tag header in the given buffer, reporting the element's
+    /// layout even if its body hasn't fully arrived yet; `Ok(None)` means the
+    /// header itself is incomplete.
+    fn peek_layout(bytes: &[u8]) -> Result<Option<EbmlLayout>, EbmlError> {
         match decode_tag(bytes) {
             Ok(None) => Ok(None),
             Err(err) => Err(err),
             Ok(Some((element_id, payload_size_tag, body_offset))) => {
                 let should_unwrap = Self::should_unwrap(element_id);
 
-                let payload_size = match (should_unwrap, payload_size_tag) {
-                    (true, _) => 0,
+                let element_len = match (should_unwrap, payload_size_tag) {
+                    (true, _) => body_offset,
                     (false, Varint::Unknown) => return Err(EbmlError::UnknownElementLength),
-                    (false, Varint::Value(size)) => size as usize
+                    (false, Varint::Value(size)) => element_length(body_offset, size)?
                 };
 
-                let element_len = body_offset + payload_size;
-                if element_len > bytes.len() {
-                    // need to read more still
-                    Ok(None)
-                } else {
-                    Ok(Some(EbmlLayout {
-                        element_id,
-                        body_offset,
-                        element_len
-                    }))
-                }
+                Ok(Some(EbmlLayout {
+                    element_id,
+                    body_offset,
+                    element_len
+                }))
             }
         }
     }
 
+    /// Check if enough space exists in the given buffer to decode an element;
+    /// it will not actually call `decode` or try to construct an instance,
+    /// but EBML errors with the next tag header will be returned eagerly.
+    fn check_space(bytes: &[u8]) -> Result<Option<EbmlLayout>, EbmlError> {
+        // need to read more still if the element's not all there
+        Ok(Self::peek_layout(bytes)?.filter(|info| info.element_len <= bytes.len()))
+    }
+
+    /// The ID of an element marking a safe place to resume parsing after corrupt
+    /// input, if there is one; see `EbmlStreamingParser::with_resync`
+    fn resync_id() -> Option<u64> {
+        None
+    }
+
+    /// Construct an event reporting that `length` bytes of corrupt input
+    /// starting at `offset` were skipped, if this type can represent that
+    fn skipped(_offset: u64, _length: u64) -> Option<Self> {
+        None
+    }
+
     /// Attempt to construct an instance of this type from the given byte slice
     fn decode_element(bytes: &'a[u8]) -> Result<Option<(Self, usize)>, EbmlError> {
         match Self::check_space(bytes)? {
@@ -365,6 +433,22 @@ mod tests {
         assert_eq!(encode_varint(Varint::Value(u64::max_value()), &mut buffer).unwrap_err().kind(), ErrorKind::InvalidInput);
     }
 
+    #[test]
+    fn encode_fixed_width_varints() {
+        let mut buffer = BytesMut::with_capacity(10).writer();
+
+        encode_varint_8(Varint::Value(0), &mut buffer).unwrap();
+        assert_eq!(&buffer.get_mut().split_to(8), &[0x01, 0, 0, 0, 0, 0, 0, 0].as_ref());
+
+        encode_varint_8(Varint::Value(0x1234), &mut buffer).unwrap();
+        assert_eq!(&buffer.get_mut().split_to(8), &[0x01, 0, 0, 0, 0, 0, 0x12, 0x34].as_ref());
+
+        encode_varint_8(Varint::Unknown, &mut buffer).unwrap();
+        assert_eq!(&buffer.get_mut().split_to(8), &[0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF].as_ref());
+
+        assert_eq!(encode_varint_8(Varint::Value(0xFFFFFFFFFFFFFF), &mut buffer).unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
     #[test]
     fn fail_corrupted_tags() {
         if let Err(CorruptVarint) = decode_tag(&[0]) {} else {assert!(false)}
@@ -408,6 +492,23 @@ mod tests {
         assert_eq!(decode_uint(&[0x80,0,0,0,0,0,0,1]).unwrap(), 9223372036854775809);
     }
 
+    #[test]
+    fn parse_ints() {
+        assert_eq!(decode_int(&[1]).unwrap(), 1);
+        assert_eq!(decode_int(&[0xFF]).unwrap(), -1);
+        assert_eq!(decode_int(&[0xFF, 0x9C]).unwrap(), -100);
+        assert_eq!(decode_int(&[0xFF,0xFF,0xFF,0xFF,0xFF,0xFF,0xFF,0x9C]).unwrap(), -100);
+        if let Err(EbmlError::CorruptPayload) = decode_int(&[]) {} else {assert!(false)}
+    }
+
+    #[test]
+    fn parse_floats() {
+        assert_eq!(decode_float(&[]).unwrap(), 0.0);
+        assert_eq!(decode_float(&[0x47, 0x3B, 0x80, 0x00]).unwrap(), 48000.0);
+        assert_eq!(decode_float(&[0x40, 0xE7, 0x70, 0x00, 0x00, 0x00, 0x00, 0x00]).unwrap(), 48000.0);
+        if let Err(EbmlError::CorruptPayload) = decode_float(&[0; 3]) {} else {assert!(false)}
+    }
+
     #[derive(Debug, PartialEq)]
     struct GenericElement(u64, usize);
 
@@ -430,4 +531,13 @@ mod tests {
         let decoded = GenericElement::decode_element(TEST_FILE);
         if let Ok(Some((GenericElement(0x0A45DFA3, 31), 43))) = decoded {} else {assert!(false)}
     }
+
+    #[test]
+    fn refuse_oversized_elements() {
+        assert_eq!(element_length(4, 12).unwrap(), 16);
+        if let Err(EbmlError::CorruptPayload) = element_length(4, u64::max_value()) {} else {assert!(false)}
+        // an 8-byte ID, then the largest size an 8-byte varint can give
+        let header = [0x01, 1, 2, 3, 4, 5, 6, 7, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFE];
+        assert!(GenericElement::check_space(&header).unwrap().is_none());
+    }
 }
@@ -0,0 +1,262 @@
+//! Edge mode: rather than taking sources itself, a relay pulls each channel
+//! from an origin relay the first time a listener asks for it, and serves
+//! it locally from then on. The channel's headers, keyframe, and DVR window
+//! are kept here like any source's, so later listeners start without asking
+//! the origin again; once a channel has had no listeners for a while, the
+//! connection to the origin is closed. Edges can pull from other edges, so
+//! a tree of them fans a channel out to as many listeners as need it.
+//!
+//! Channels are pulled whenever the relay sees demand for them, i.e. a
+//! listener of any kind or an HLS packager starting (see `Relay::on_demand`).
+
+use std::collections::HashSet;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::future::{select, Either};
+use hyper::{client::HttpConnector, Body, Client, Uri};
+use hyper_tls::HttpsConnector;
+use tokio::time::{delay_for, interval};
+use tracing_futures::Instrument;
+
+use crate::error::WebmetroError;
+use crate::server::{Relay, SourceOptions};
+
+/// How long to wait before reconnecting to the origin after losing it; the
+/// wait doubles each time reconnecting fails, up to `MAX_RECONNECT_DELAY`
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+
+/// How often a pulled channel is checked for listeners
+const IDLE_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Where & how long channels are pulled
+#[derive(Clone, Debug, PartialEq)]
+pub struct EdgeOptions {
+    /// the origin's base URL, e.g. `http://origin.example:8080`; a channel
+    /// is pulled from `<origin>/live/<channel>`
+    pub origin: String,
+    /// how long a channel is kept pulled without listeners
+    pub linger: Duration,
+}
+
+impl EdgeOptions {
+    pub fn new(origin: &str) -> EdgeOptions {
+        EdgeOptions {
+            origin: origin.trim_end_matches('/').to_string(),
+            linger: Duration::from_secs(10),
+        }
+    }
+
+    /// Where a channel is pulled from
+    pub fn channel_url(&self, channel: &str) -> String {
+        format!("{}/live/{}", self.origin, channel)
+    }
+}
+
+/// Pulls channels from an origin into a Relay as listeners ask for them
+pub struct Edge {
+    relay: Arc<Relay>,
+    options: EdgeOptions,
+    client: Client<HttpsConnector<HttpConnector>>,
+    /// the channels being pulled
+    pulling: Arc<Mutex<HashSet<String>>>,
+}
+
+impl Edge {
+    pub fn new(relay: Arc<Relay>, options: EdgeOptions) -> Result<Edge, WebmetroError> {
+        options.origin.parse::<Uri>().map_err(|_| WebmetroError::ApplicationError {
+            message: format!("Invalid origin URL {}", options.origin),
+        })?;
+        Ok(Edge {
+            relay,
+            options,
+            client: Client::builder().build::<_, Body>(HttpsConnector::new()),
+            pulling: Arc::new(Mutex::new(HashSet::new())),
+        })
+    }
+
+    /// Whether a channel is being pulled from the origin
+    pub fn is_pulling(&self, channel: &str) -> bool {
+        self.pulling.lock().expect("Locking pulled channels").contains(channel)
+    }
+
+    /// Pull channels as the relay sees demand for them. The relay only holds
+    /// on to the edge weakly, so it has to be kept alive elsewhere.
+    pub fn serve_demand(self: &Arc<Self>) {
+        let edge = Arc::downgrade(self);
+        self.relay.on_demand(move |channel| {
+            if let Some(edge) = edge.upgrade() {
+                edge.demand(channel);
+            }
+        });
+    }
+
+    /// A listener wants a channel: start pulling it from the origin, unless
+    /// it's already being pulled or has a local source
+    pub fn demand(&self, channel: &str) {
+        if self.relay.has_source(channel) {
+            return;
+        }
+        if !self.pulling.lock().expect("Locking pulled channels").insert(channel.to_string()) {
+            return;
+        }
+
+        info!("Pulling channel {} from {}", channel, self.options.origin);
+        let pull = pull(self.relay.clone(), self.client.clone(), self.options.clone(), channel.to_string());
+        let idle = until_idle(self.relay.clone(), channel.to_string(), self.options.linger);
+        let relay = self.relay.clone();
+        let pulling = self.pulling.clone();
+        let span = info_span!("edge", channel = %channel);
+        let channel = channel.to_string();
+        tokio::spawn(async move {
+            if let Either::Right(_) = select(Box::pin(pull), Box::pin(idle)).await {
+                info!("Channel {} has no listeners, disconnecting from the origin", channel);
+            }
+            pulling.lock().expect("Locking pulled channels").remove(&channel);
+            // nobody's waiting for the origin to come back
+            relay.set_paused(&channel, false);
+        }.instrument(span));
+    }
+}
+
+/// Publish a channel from the origin, reconnecting with exponential backoff
+/// as need be, until the channel turns out to have a source of its own here
+/// or the origin refuses it
+async fn pull(relay: Arc<Relay>, client: Client<HttpsConnector<HttpConnector>>, options: EdgeOptions, channel: String) {
+    let url = options.channel_url(&channel);
+    // listeners wait through reconnections, rather than being ended
+    let source = SourceOptions { pause_on_end: true, ..SourceOptions::default() };
+    let mut delay = RECONNECT_DELAY;
+    loop {
+        let started = Instant::now();
+        let result = match fetch(&client, &url).await {
+            Ok(body) => relay.publish_with(&channel, body, source).await,
+            Err(err) => Err(err),
+        };
+        // a connection that lasted a while is lost afresh, not failing again
+        if started.elapsed() >= MAX_RECONNECT_DELAY {
+            delay = RECONNECT_DELAY;
+        }
+        let wait = match result {
+            Ok(()) => {
+                debug!("Origin ended channel {}, reconnecting", channel);
+                delay
+            },
+            Err(WebmetroError::ChannelBusy { .. }) | Err(WebmetroError::SourceReplaced { .. }) => {
+                info!("Channel {} has a local source, no longer pulling it", channel);
+                return;
+            },
+            // e.g. refused credentials, which won't do any better next time
+            Err(err) if !err.is_retryable() => {
+                warn!("Pulling channel {} failed ({}), giving up", channel, err);
+                return;
+            },
+            Err(err) => {
+                // wait at least as long as the origin asked
+                let wait = err.retry_after().map_or(delay, |retry_after| retry_after.max(delay));
+                warn!("Pulling channel {} failed ({}), reconnecting in {:?}", channel, err, wait);
+                wait
+            },
+        };
+        delay_for(wait).await;
+        delay = (delay * 2).min(MAX_RECONNECT_DELAY);
+    }
+}
+
+/// Ask the origin for a channel's stream
+async fn fetch(client: &Client<HttpsConnector<HttpConnector>>, url: &str) -> Result<Body, WebmetroError> {
+    let uri: Uri = url.parse().map_err(|_| WebmetroError::ApplicationError {
+        message: format!("Invalid origin URL {}", url),
+    })?;
+    let response = client.get(uri).await?;
+    if !response.status().is_success() {
+        return Err(WebmetroError::from_response(&response));
+    }
+    Ok(response.into_body())
+}
+
+/// Finishes once a channel has gone `linger` without listeners
+async fn until_idle(relay: Arc<Relay>, channel: String, linger: Duration) {
+    let mut checks = interval(IDLE_CHECK_INTERVAL);
+    let mut idle_since = None;
+    loop {
+        checks.tick().await;
+        if relay.listener_count(&channel) > 0 {
+            idle_since = None;
+            continue;
+        }
+        let since = *idle_since.get_or_insert_with(Instant::now);
+        if since.elapsed() >= linger {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    use bytes::Bytes;
+    use futures::{channel::oneshot, prelude::*, stream};
+    use hyper::{service::{make_service_fn, service_fn}, Request, Response, Server};
+    use tokio::time::timeout;
+
+    use crate::edge::*;
+    use crate::server::RelayOptions;
+    use crate::tests::TEST_FILE;
+
+    #[test]
+    fn name_origin_channels() {
+        let options = EdgeOptions::new("http://origin.example:8080/");
+        assert_eq!(options.channel_url("main"), "http://origin.example:8080/live/main");
+        let relay = Arc::new(Relay::default());
+        assert!(Edge::new(relay, EdgeOptions::new("not a url")).is_err());
+    }
+
+    #[test]
+    fn pull_on_demand() {
+        let mut runtime = tokio::runtime::Builder::new().basic_scheduler().enable_all().build().unwrap();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let address = runtime.block_on(async move {
+            let service = make_service_fn(|_| async {
+                Ok::<_, Infallible>(service_fn(|request: Request<Body>| async move {
+                    assert_eq!(request.uri().path(), "/live/main");
+                    // the origin's channel stays live, without sending more
+                    let body = stream::once(future::ok::<_, Infallible>(Bytes::from_static(TEST_FILE)))
+                        .chain(stream::pending());
+                    Ok::<_, Infallible>(Response::new(Body::wrap_stream(body)))
+                }))
+            });
+            let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(service);
+            let address = server.local_addr();
+            tokio::spawn(server.with_graceful_shutdown(stopped.map(|_| ())));
+            address
+        });
+
+        let relay = Arc::new(Relay::new(RelayOptions::default()));
+        let options = EdgeOptions { linger: Duration::from_millis(100), ..EdgeOptions::new(&format!("http://{}", address)) };
+        let edge = Arc::new(Edge::new(relay.clone(), options).unwrap());
+        edge.serve_demand();
+        runtime.block_on(async {
+            // listening is enough to start pulling
+            let listener = relay.listen("main");
+            assert!(edge.is_pulling("main"));
+            let received = timeout(Duration::from_secs(5), listener.take(1).try_collect::<Vec<Bytes>>()).await
+                .expect("Nothing was pulled").unwrap();
+            assert_eq!(&received[0][0..4], &[0x1A, 0x45, 0xDF, 0xA3]);
+            assert!(relay.has_source("main"));
+
+            // with the listener gone, the origin is let go
+            timeout(Duration::from_secs(5), async {
+                while edge.is_pulling("main") {
+                    delay_for(Duration::from_millis(50)).await;
+                }
+            }).await.expect("Still pulling without listeners");
+            assert!(!relay.has_source("main"));
+            stop.send(()).unwrap();
+        });
+    }
+}
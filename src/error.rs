@@ -1,18 +1,265 @@
+use std::fmt;
+use std::time::Duration;
 
 use custom_error::custom_error;
 
+use crate::ebml::EbmlError;
+
+/// The buffer limits that can cut off a stream
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Limit {
+    /// the parser's input buffer, holding one element not yet fully received
+    ParserBuffer(usize),
+    /// a chunk being assembled by the chunker
+    ChunkBuffer(usize),
+    /// a channel's cap on its sources' bitrate, in bits per second
+    Bitrate(u64),
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::ParserBuffer(size) => write!(f, "parser buffer limit of {} bytes", size),
+            Limit::ChunkBuffer(size) => write!(f, "chunk buffer limit of {} bytes", size),
+            Limit::Bitrate(bitrate) => write!(f, "bitrate limit of {} bps", bitrate),
+        }
+    }
+}
+
+/// How much of an error response's body is quoted in its error
+#[cfg(feature = "server")]
+const MAX_ERROR_BODY: usize = 512;
+
+#[cfg(feature = "server")]
 custom_error!{pub WebmetroError
-    ResourcesExceeded = "resources exceeded",
-    EbmlError{source: crate::ebml::EbmlError} = "EBML error: {source}",
+    LimitExceeded{limit: Limit} = "{limit} exceeded",
+    ParseError{offset: u64, source: EbmlError} = "EBML error at byte {offset}: {source}",
+    EbmlError{source: EbmlError} = "EBML error: {source}",
+    UnsupportedFormat = "not a WebM stream",
+    Timeout = "timed out",
+    ChannelClosed{name: String} = "channel {name} closed",
+    ChannelBusy{name: String} = "channel {name} already has a source",
+    SourceReplaced{name: String} = "another source took over channel {name}",
     HttpError{source: http::Error} = "HTTP error: {source}",
+    HttpStatus{status: http::StatusCode, retry_after: Option<Duration>, detail: String} = "server responded with {status}{detail}",
     HyperError{source: hyper::Error} = "Hyper error: {source}",
     IoError{source: std::io::Error} = "IO error: {source}",
     WarpError{source: warp::Error} = "Warp error: {source}",
     ApplicationError{message: String} = "{message}"
 }
 
+// without the server feature, there's no web stack to report errors from
+#[cfg(not(feature = "server"))]
+custom_error!{pub WebmetroError
+    LimitExceeded{limit: Limit} = "{limit} exceeded",
+    ParseError{offset: u64, source: EbmlError} = "EBML error at byte {offset}: {source}",
+    EbmlError{source: EbmlError} = "EBML error: {source}",
+    UnsupportedFormat = "not a WebM stream",
+    Timeout = "timed out",
+    ChannelClosed{name: String} = "channel {name} closed",
+    ChannelBusy{name: String} = "channel {name} already has a source",
+    SourceReplaced{name: String} = "another source took over channel {name}",
+    IoError{source: std::io::Error} = "IO error: {source}",
+    ApplicationError{message: String} = "{message}"
+}
+
+impl WebmetroError {
+    /// A process exit status distinguishing broad classes of failure,
+    /// so scripts can tell bad input from network trouble.
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            WebmetroError::ApplicationError {..}
+                | WebmetroError::ChannelClosed {..}
+                | WebmetroError::ChannelBusy {..}
+                | WebmetroError::SourceReplaced {..} => 1,
+            WebmetroError::ParseError {..}
+                | WebmetroError::EbmlError {..}
+                | WebmetroError::UnsupportedFormat => 3,
+            WebmetroError::LimitExceeded {..} => 4,
+            #[cfg(feature = "server")]
+            WebmetroError::HttpError {..}
+                | WebmetroError::HttpStatus {..}
+                | WebmetroError::HyperError {..}
+                | WebmetroError::WarpError {..} => 5,
+            WebmetroError::Timeout {..} => 5,
+            WebmetroError::IoError {..} => 6,
+        }
+    }
+
+    /// Whether the failure may be transient, e.g. a dropped connection or an
+    /// overloaded server, so the operation is worth retrying; bad input and
+    /// refused credentials will only fail the same way again.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            WebmetroError::Timeout {..} | WebmetroError::IoError {..} => true,
+            #[cfg(feature = "server")]
+            WebmetroError::HyperError {..} | WebmetroError::WarpError {..} => true,
+            #[cfg(feature = "server")]
+            WebmetroError::HttpStatus { status, .. } => status.is_server_error()
+                || *status == http::StatusCode::REQUEST_TIMEOUT
+                || *status == http::StatusCode::TOO_MANY_REQUESTS,
+            _ => false,
+        }
+    }
+
+    /// How long a server asked to be left alone before a retry, if it did
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            #[cfg(feature = "server")]
+            WebmetroError::HttpStatus { retry_after, .. } => *retry_after,
+            _ => None,
+        }
+    }
+
+    /// The error for an unsuccessful response, noting its `Retry-After`
+    /// if that's given in seconds (rather than as a date)
+    #[cfg(feature = "server")]
+    pub fn from_response<T>(response: &http::Response<T>) -> WebmetroError {
+        let retry_after = response.headers().get(http::header::RETRY_AFTER)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.trim().parse().ok())
+            .map(Duration::from_secs);
+        WebmetroError::HttpStatus { status: response.status(), retry_after, detail: String::new() }
+    }
+
+    /// Like `from_response`, also quoting the start of the response's body,
+    /// where a relay explains why it turned a request down
+    #[cfg(feature = "server")]
+    pub async fn from_response_body(response: http::Response<hyper::Body>) -> WebmetroError {
+        use futures::StreamExt;
+
+        let mut error = WebmetroError::from_response(&response);
+        let mut body = response.into_body();
+        let mut text = Vec::new();
+        while text.len() < MAX_ERROR_BODY {
+            match body.next().await {
+                Some(Ok(bytes)) => text.extend_from_slice(&bytes),
+                _ => break,
+            }
+        }
+        text.truncate(MAX_ERROR_BODY);
+        let text = String::from_utf8_lossy(&text);
+        let text = text.split_whitespace().collect::<Vec<_>>().join(" ");
+        if let WebmetroError::HttpStatus { ref mut detail, .. } = error {
+            if !text.is_empty() {
+                *detail = format!(": {}", text);
+            }
+        }
+        error
+    }
+
+    /// The HTTP status a relay should answer with when this error ends a request,
+    /// if the response hasn't already begun.
+    #[cfg(feature = "server")]
+    pub fn status_code(&self) -> http::StatusCode {
+        use http::StatusCode;
+        match self {
+            WebmetroError::ParseError {..} | WebmetroError::EbmlError {..} => StatusCode::BAD_REQUEST,
+            WebmetroError::UnsupportedFormat => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            WebmetroError::LimitExceeded {..} => StatusCode::PAYLOAD_TOO_LARGE,
+            WebmetroError::Timeout {..} => StatusCode::REQUEST_TIMEOUT,
+            WebmetroError::ChannelClosed {..} => StatusCode::GONE,
+            WebmetroError::ChannelBusy {..} | WebmetroError::SourceReplaced {..} => StatusCode::CONFLICT,
+            // these arise reading a request body, so the client's connection is at fault
+            WebmetroError::HyperError {..} | WebmetroError::WarpError {..} => StatusCode::BAD_REQUEST,
+            // another server's answer, so it's at fault
+            WebmetroError::HttpStatus {..} => StatusCode::BAD_GATEWAY,
+            WebmetroError::HttpError {..}
+                | WebmetroError::IoError {..}
+                | WebmetroError::ApplicationError {..} => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl From<tokio::time::Elapsed> for WebmetroError {
+    fn from(_: tokio::time::Elapsed) -> WebmetroError {
+        WebmetroError::Timeout
+    }
+}
+
 impl From<&str> for WebmetroError {
     fn from(message: &str) -> WebmetroError {
         WebmetroError::ApplicationError{message: message.into()}
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::error::Error;
+
+    use crate::ebml::EbmlError;
+    use crate::error::*;
+
+    #[test]
+    fn describe_errors() {
+        let error = WebmetroError::ParseError { offset: 42, source: EbmlError::CorruptVarint };
+        assert_eq!(error.to_string(), format!("EBML error at byte 42: {}", EbmlError::CorruptVarint));
+        assert!(error.source().is_some());
+        assert_eq!(error.exit_code(), 3);
+
+        let error = WebmetroError::LimitExceeded { limit: Limit::ChunkBuffer(1024) };
+        assert_eq!(error.to_string(), "chunk buffer limit of 1024 bytes exceeded");
+        assert_eq!(error.exit_code(), 4);
+    }
+
+    #[test]
+    fn classify_retryable_errors() {
+        assert!(WebmetroError::Timeout.is_retryable());
+        assert!(!WebmetroError::ParseError { offset: 0, source: EbmlError::CorruptVarint }.is_retryable());
+        assert!(!WebmetroError::UnsupportedFormat.is_retryable());
+        assert_eq!(WebmetroError::Timeout.retry_after(), None);
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn classify_responses() {
+        let response = http::Response::builder()
+            .status(http::StatusCode::TOO_MANY_REQUESTS)
+            .header("Retry-After", "120")
+            .body(())
+            .unwrap();
+        let error = WebmetroError::from_response(&response);
+        assert!(error.is_retryable());
+        assert_eq!(error.retry_after(), Some(Duration::from_secs(120)));
+
+        let response = http::Response::builder()
+            .status(http::StatusCode::FORBIDDEN)
+            .body(())
+            .unwrap();
+        let error = WebmetroError::from_response(&response);
+        assert!(!error.is_retryable());
+        assert_eq!(error.to_string(), "server responded with 403 Forbidden");
+
+        // the relay's explanation is passed on
+        let response = http::Response::builder()
+            .status(http::StatusCode::CONFLICT)
+            .body(hyper::Body::from("Channel main\nalready has a source\n"))
+            .unwrap();
+        let error = futures::executor::block_on(WebmetroError::from_response_body(response));
+        assert_eq!(error.to_string(), "server responded with 409 Conflict: Channel main already has a source");
+        let response = http::Response::builder()
+            .status(http::StatusCode::FORBIDDEN)
+            .body(hyper::Body::empty())
+            .unwrap();
+        let error = futures::executor::block_on(WebmetroError::from_response_body(response));
+        assert_eq!(error.to_string(), "server responded with 403 Forbidden");
+    }
+
+    #[cfg(feature = "server")]
+    #[test]
+    fn fail_fast_on_client_errors() {
+        let retryable = |status: u16| {
+            let status = http::StatusCode::from_u16(status).unwrap();
+            WebmetroError::HttpStatus { status, retry_after: None, detail: String::new() }.is_retryable()
+        };
+        // a client error means the request itself is wrong, except for these two
+        for status in (400..500).filter(|status| *status != 408 && *status != 429) {
+            assert!(!retryable(status), "{} shouldn't be retried", status);
+        }
+        assert!(retryable(408));
+        assert!(retryable(429));
+        assert!(retryable(500));
+        assert!(retryable(503));
+    }
+}
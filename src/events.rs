@@ -0,0 +1,391 @@
+//! Channel lifecycle events (sources coming & going, listener counts,
+//! bitrates, and publishers' metadata), for dashboards to follow as they happen instead of polling.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use futures::{
+    channel::mpsc,
+    future::ready,
+    prelude::*,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::chunk::Chunk;
+use crate::metadata::ChannelMetadata;
+use crate::server::split_rendition;
+
+/// How many events a subscriber may fall behind before it misses some
+const EVENT_QUEUE_LIMIT: usize = 64;
+
+/// The least media time a bitrate is measured over, in milliseconds
+const BITRATE_WINDOW: u64 = 1000;
+
+/// Something that happened to a channel
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "kebab-case")]
+pub enum Event {
+    /// a source started publishing to the channel
+    PublishStart { channel: String },
+    /// the channel's source stopped publishing, or was disconnected
+    PublishStop { channel: String },
+    /// a listener joined or left
+    Listeners { channel: String, count: usize },
+    /// the source's bitrate over about the last second of media
+    Bitrate {
+        channel: String,
+        #[serde(rename = "bps")]
+        bits_per_second: u64,
+    },
+    /// the channel's metadata was changed (or cleared, leaving it empty)
+    Metadata {
+        channel: String,
+        #[serde(rename = "meta")]
+        metadata: ChannelMetadata,
+    },
+}
+
+impl Event {
+    pub fn channel(&self) -> &str {
+        match self {
+            Event::PublishStart { channel }
+            | Event::PublishStop { channel }
+            | Event::Listeners { channel, .. }
+            | Event::Bitrate { channel, .. }
+            | Event::Metadata { channel, .. } => channel,
+        }
+    }
+
+    /// The event as a one-line JSON object, e.g.
+    /// `{"event":"listeners","channel":"main","count":3}`
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Serializing an event")
+    }
+
+    /// Read an event back from `to_json`'s form, e.g. as a relay's event
+    /// stream sends it; `None` if it isn't one
+    pub fn from_json(json: &str) -> Option<Event> {
+        serde_json::from_str(json).ok()
+    }
+}
+
+/// Whether an event is for a channel (or one of its renditions), or any
+/// channel's are wanted
+fn for_channel(channel: Option<&str>) -> impl FnMut(&Event) -> future::Ready<bool> {
+    let channel = channel.map(str::to_string);
+    move |event| ready(channel.as_ref().map_or(true, |channel| {
+        event.channel() == channel
+            || split_rendition(event.channel()).map_or(false, |(group, _)| group == channel)
+    }))
+}
+
+/// Fans events out to subscribers, and keeps each channel's listener count
+/// & latest bitrate. A simulcast channel starts publishing when the first of
+/// its renditions does, and stops when the last one does.
+#[derive(Default)]
+pub struct EventHub {
+    subscribers: Mutex<Vec<mpsc::Sender<Event>>>,
+    /// subscribers that are sent every event, however far behind they fall
+    lossless_subscribers: Mutex<Vec<mpsc::UnboundedSender<Event>>>,
+    listeners: Mutex<HashMap<String, usize>>,
+    bitrates: Mutex<HashMap<String, u64>>,
+    /// how many of each simulcast channel's renditions are publishing
+    live_renditions: Mutex<HashMap<String, usize>>,
+}
+
+impl EventHub {
+    pub fn new() -> Arc<EventHub> {
+        Arc::new(EventHub::default())
+    }
+
+    /// Follow events for one channel (including its renditions'), or every
+    /// channel with `None`. A subscriber that falls too far behind misses
+    /// events until it catches up.
+    pub fn subscribe(&self, channel: Option<&str>) -> impl Stream<Item = Event> + Send + Unpin {
+        let (sender, receiver) = mpsc::channel(EVENT_QUEUE_LIMIT);
+        self.subscribers.lock().expect("Locking event subscribers").push(sender);
+        receiver.filter(for_channel(channel))
+    }
+
+    /// Follow events like `subscribe`, but without missing any, for
+    /// subscribers that act on each one (like hooks) rather than showing
+    /// the latest; events queue up without limit while they're behind
+    pub fn subscribe_lossless(&self, channel: Option<&str>) -> impl Stream<Item = Event> + Send + Unpin {
+        let (sender, receiver) = mpsc::unbounded();
+        self.lossless_subscribers.lock().expect("Locking event subscribers").push(sender);
+        receiver.filter(for_channel(channel))
+    }
+
+    pub fn emit(&self, event: Event) {
+        {
+            let mut subscribers = self.lossless_subscribers.lock().expect("Locking event subscribers");
+            subscribers.retain(|subscriber| subscriber.unbounded_send(event.clone()).is_ok());
+        }
+        let mut subscribers = self.subscribers.lock().expect("Locking event subscribers");
+        for subscriber in subscribers.iter_mut() {
+            // a full queue just means this event is skipped for that subscriber
+            subscriber.try_send(event.clone()).ok();
+        }
+        subscribers.retain(|subscriber| !subscriber.is_closed());
+    }
+
+    /// How many listeners a channel has
+    pub fn listener_count(&self, channel: &str) -> usize {
+        let listeners = self.listeners.lock().expect("Locking listener counts");
+        listeners.get(channel).cloned().unwrap_or(0)
+    }
+
+    /// A channel's source's latest bitrate, while it's publishing
+    pub fn bitrate(&self, channel: &str) -> Option<u64> {
+        let bitrates = self.bitrates.lock().expect("Locking bitrates");
+        bitrates.get(channel).cloned()
+    }
+
+    fn count_listener(&self, channel: &str, joined: bool) {
+        let count = {
+            let mut listeners = self.listeners.lock().expect("Locking listener counts");
+            let count = listeners.entry(channel.to_string()).or_insert(0);
+            if joined {
+                *count += 1;
+            } else {
+                *count -= 1;
+            }
+            let current = *count;
+            if current == 0 {
+                listeners.remove(channel);
+            }
+            current
+        };
+        self.emit(Event::Listeners { channel: channel.to_string(), count });
+    }
+
+    /// Announce a source publishing to a channel; the returned session
+    /// measures its bitrate, and announces the source stopping when dropped.
+    pub fn publishing(self: &Arc<Self>, channel: &str) -> Publishing {
+        self.emit(Event::PublishStart { channel: channel.to_string() });
+        self.count_rendition(channel, true);
+        Publishing {
+            hub: self.clone(),
+            channel: channel.to_string(),
+            window_start: None,
+            window_bytes: 0,
+        }
+    }
+
+    /// Keep count of a simulcast channel's live renditions, announcing it
+    /// starting with the first & stopping with the last
+    fn count_rendition(&self, channel: &str, started: bool) {
+        let group = match split_rendition(channel) {
+            Some((group, _)) => group,
+            None => return,
+        };
+        let event = {
+            let mut live = self.live_renditions.lock().expect("Locking live renditions");
+            let count = live.entry(group.to_string()).or_insert(0);
+            if started {
+                *count += 1;
+                if *count > 1 {
+                    return;
+                }
+                Event::PublishStart { channel: group.to_string() }
+            } else {
+                *count -= 1;
+                if *count > 0 {
+                    return;
+                }
+                live.remove(group);
+                Event::PublishStop { channel: group.to_string() }
+            }
+        };
+        self.emit(event);
+    }
+
+    /// Count a listener joining a channel, until the returned guard is dropped
+    pub fn viewing(self: &Arc<Self>, channel: &str) -> Viewing {
+        self.count_listener(channel, true);
+        Viewing {
+            hub: self.clone(),
+            channel: channel.to_string(),
+        }
+    }
+}
+
+/// A source publishing to a channel
+pub struct Publishing {
+    hub: Arc<EventHub>,
+    channel: String,
+    /// the timecode the current bitrate window started at
+    window_start: Option<u64>,
+    window_bytes: u64,
+}
+
+impl Publishing {
+    /// Measure a chunk the source sent, announcing the bitrate each time a
+    /// second or more of media has arrived
+    pub fn observe(&mut self, chunk: &Chunk) {
+        let head = match chunk {
+            Chunk::Cluster(head, _) => head,
+            // a new initialization segment means a new stream, with new timecodes
+            Chunk::Headers { .. } => {
+                self.window_start = None;
+                self.window_bytes = 0;
+                return;
+            },
+            _ => return,
+        };
+        let start = match self.window_start {
+            Some(start) if start <= head.start => start,
+            _ => {
+                self.window_start = Some(head.start);
+                self.window_bytes = 0;
+                head.start
+            }
+        };
+        self.window_bytes += chunk.size() as u64;
+        let elapsed = head.end.saturating_sub(start);
+        if elapsed >= BITRATE_WINDOW {
+            let bits_per_second = self.window_bytes * 8 * 1000 / elapsed;
+            self.hub.bitrates.lock().expect("Locking bitrates").insert(self.channel.clone(), bits_per_second);
+            self.hub.emit(Event::Bitrate {
+                channel: self.channel.clone(),
+                bits_per_second,
+            });
+            self.window_start = Some(head.end);
+            self.window_bytes = 0;
+        }
+    }
+}
+
+impl Drop for Publishing {
+    fn drop(&mut self) {
+        self.hub.bitrates.lock().expect("Locking bitrates").remove(&self.channel);
+        self.hub.emit(Event::PublishStop { channel: self.channel.clone() });
+        self.hub.count_rendition(&self.channel, false);
+    }
+}
+
+/// A listener watching a channel
+pub struct Viewing {
+    hub: Arc<EventHub>,
+    channel: String,
+}
+
+impl Drop for Viewing {
+    fn drop(&mut self) {
+        self.hub.count_listener(&self.channel, false);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use crate::events::*;
+    use crate::tests::cluster;
+
+    #[test]
+    fn format_events() {
+        let event = Event::Listeners { channel: "main".into(), count: 3 };
+        assert_eq!(event.to_json(), r#"{"event":"listeners","channel":"main","count":3}"#);
+        let event = Event::PublishStart { channel: "a \"quoted\"\n\\name".into() };
+        assert_eq!(event.to_json(), r#"{"event":"publish-start","channel":"a \"quoted\"\n\\name"}"#);
+        let event = Event::Metadata { channel: "main".into(), metadata: ChannelMetadata { title: Some("Main".into()), ..ChannelMetadata::default() } };
+        assert_eq!(event.to_json(), r#"{"event":"metadata","channel":"main","meta":{"title":"Main","description":null,"tags":[]}}"#);
+    }
+
+    #[test]
+    fn read_events() {
+        let events = vec![
+            Event::PublishStart { channel: "main".into() },
+            Event::Listeners { channel: "main".into(), count: 3 },
+            Event::Bitrate { channel: "main/720p".into(), bits_per_second: 2_500_000 },
+            Event::Metadata { channel: "main".into(), metadata: ChannelMetadata { tags: vec!["live".into()], ..ChannelMetadata::default() } },
+            Event::PublishStop { channel: "a \"quoted\" name".into() },
+        ];
+        for event in events {
+            assert_eq!(Event::from_json(&event.to_json()), Some(event));
+        }
+        assert_eq!(Event::from_json(r#"{"event":"publish-pause","channel":"main"}"#), None);
+        assert_eq!(Event::from_json(r#"{"event":"listeners","channel":"main"}"#), None);
+        assert_eq!(Event::from_json("not json"), None);
+    }
+
+    #[test]
+    fn count_listeners() {
+        let hub = EventHub::new();
+        let events = hub.subscribe(Some("main"));
+        let first = hub.viewing("main");
+        let _second = hub.viewing("main");
+        let _elsewhere = hub.viewing("other");
+        assert_eq!(hub.listener_count("main"), 2);
+        drop(first);
+        assert_eq!(hub.listener_count("main"), 1);
+        drop(hub);
+
+        let counts: Vec<Event> = block_on(events.take(3).collect());
+        assert_eq!(counts, vec![
+            Event::Listeners { channel: "main".into(), count: 1 },
+            Event::Listeners { channel: "main".into(), count: 2 },
+            Event::Listeners { channel: "main".into(), count: 1 },
+        ]);
+    }
+
+    #[test]
+    fn subscribe_without_losing_events() {
+        let hub = EventHub::new();
+        let lossy = hub.subscribe(None);
+        let lossless = hub.subscribe_lossless(Some("main"));
+        for count in 0..EVENT_QUEUE_LIMIT * 2 {
+            hub.emit(Event::Listeners { channel: "main".into(), count });
+        }
+        hub.emit(Event::Listeners { channel: "other".into(), count: 1 });
+        drop(hub);
+
+        assert!(block_on(lossy.count()) < EVENT_QUEUE_LIMIT * 2);
+        assert_eq!(block_on(lossless.count()), EVENT_QUEUE_LIMIT * 2);
+    }
+
+    #[test]
+    fn measure_bitrate() {
+        let hub = EventHub::new();
+        let events = hub.subscribe(None);
+        let mut publishing = hub.publishing("main");
+        let clusters = [cluster(0, &[(1, 0), (1, 400)]), cluster(500, &[(1, 0), (1, 400)]), cluster(1000, &[(1, 0)])];
+        for chunk in clusters.iter() {
+            publishing.observe(chunk);
+        }
+        let bytes: usize = clusters.iter().map(Chunk::size).sum();
+        assert_eq!(hub.bitrate("main"), Some(bytes as u64 * 8));
+        drop(publishing);
+        assert_eq!(hub.bitrate("main"), None);
+
+        let events: Vec<Event> = block_on(events.take(3).collect());
+        assert_eq!(events, vec![
+            Event::PublishStart { channel: "main".into() },
+            Event::Bitrate { channel: "main".into(), bits_per_second: bytes as u64 * 8 },
+            Event::PublishStop { channel: "main".into() },
+        ]);
+    }
+
+    #[test]
+    fn group_renditions() {
+        let hub = EventHub::new();
+        let events = hub.subscribe(Some("main"));
+        let high = hub.publishing("main/720p");
+        let low = hub.publishing("main/360p");
+        let _elsewhere = hub.publishing("other/720p");
+        drop(high);
+        drop(low);
+        drop(hub);
+
+        let events: Vec<Event> = block_on(events.take(6).collect());
+        assert_eq!(events, vec![
+            Event::PublishStart { channel: "main/720p".into() },
+            Event::PublishStart { channel: "main".into() },
+            Event::PublishStart { channel: "main/360p".into() },
+            Event::PublishStop { channel: "main/720p".into() },
+            Event::PublishStop { channel: "main/360p".into() },
+            Event::PublishStop { channel: "main".into() },
+        ]);
+    }
+}
@@ -0,0 +1,206 @@
+//! A C interface to the parser & chunker, for media servers not written in Rust.
+//! See `include/webmetro.h` for the C declarations.
+//!
+//! Bytes are pushed in with `webmetro_chunker_feed`; whenever that completes
+//! a chunk, the callback given to `webmetro_chunker_new` is called with it
+//! before `webmetro_chunker_feed` returns.
+
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int, c_void};
+use std::ptr;
+use std::slice;
+
+use bytes::Bytes;
+
+use crate::chunk::{Chunk, ChunkerOptions, PushChunker};
+
+pub const WEBMETRO_CHUNK_HEADERS: c_int = 0;
+pub const WEBMETRO_CHUNK_CLUSTER: c_int = 1;
+
+/// Describes a chunk passed to a `WebmetroChunkCallback`
+#[repr(C)]
+pub struct WebmetroChunkInfo {
+    /// `WEBMETRO_CHUNK_HEADERS` or `WEBMETRO_CHUNK_CLUSTER`
+    pub kind: c_int,
+    /// nonzero if a Cluster starts with a keyframe
+    pub keyframe: c_int,
+    /// a Cluster's first & last timecodes; zero for headers
+    pub start: u64,
+    pub end: u64,
+}
+
+/// Receives each chunk; `data` is only valid for the duration of the call
+pub type WebmetroChunkCallback = extern "C" fn(user_data: *mut c_void, info: *const WebmetroChunkInfo, data: *const u8, len: usize);
+
+pub struct WebmetroChunker {
+    chunker: PushChunker,
+    callback: WebmetroChunkCallback,
+    user_data: *mut c_void,
+    /// reused to present a Cluster's head & body contiguously
+    buffer: Vec<u8>,
+    error: Option<CString>,
+}
+
+impl WebmetroChunker {
+    /// pass along any chunks the chunker can now complete
+    fn pump(&mut self) -> c_int {
+        while let Some(result) = self.chunker.next_chunk() {
+            match result {
+                Ok(chunk) => self.deliver(chunk),
+                Err(err) => self.error = CString::new(err.to_string()).ok(),
+            }
+        }
+        if self.error.is_some() { -1 } else { 0 }
+    }
+
+    fn deliver(&mut self, chunk: Chunk) {
+        let info = match chunk {
+            Chunk::Headers {..} => WebmetroChunkInfo {
+                kind: WEBMETRO_CHUNK_HEADERS,
+                keyframe: 0,
+                start: 0,
+                end: 0,
+            },
+            Chunk::Cluster(ref head, _) => WebmetroChunkInfo {
+                kind: WEBMETRO_CHUNK_CLUSTER,
+                keyframe: head.keyframe as c_int,
+                start: head.start,
+                end: head.end,
+            },
+            _ => return,
+        };
+        self.buffer.clear();
+        for bytes in chunk {
+            self.buffer.extend_from_slice(&bytes);
+        }
+        (self.callback)(self.user_data, &info, self.buffer.as_ptr(), self.buffer.len());
+    }
+}
+
+/// Create a chunker. `soft_limit` bounds the size of a chunk (0 for no limit);
+/// `user_data` is passed back to `callback` untouched.
+#[no_mangle]
+pub extern "C" fn webmetro_chunker_new(soft_limit: usize, callback: WebmetroChunkCallback, user_data: *mut c_void) -> *mut WebmetroChunker {
+    let mut options = ChunkerOptions::new();
+    if soft_limit > 0 {
+        options = options.soft_limit(soft_limit);
+    }
+
+    Box::into_raw(Box::new(WebmetroChunker {
+        chunker: PushChunker::new(options),
+        callback,
+        user_data,
+        buffer: Vec::new(),
+        error: None,
+    }))
+}
+
+/// Parse `len` bytes of WebM, calling the callback for any chunks completed.
+/// Returns 0 on success, or -1 if the stream is unusable; after an error,
+/// `webmetro_chunker_error` describes it and further input is refused.
+///
+/// # Safety
+/// `chunker` must come from `webmetro_chunker_new`, and `data` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn webmetro_chunker_feed(chunker: *mut WebmetroChunker, data: *const u8, len: usize) -> c_int {
+    let chunker = match chunker.as_mut() {
+        Some(chunker) => chunker,
+        None => return -1,
+    };
+    if chunker.error.is_some() {
+        return -1;
+    }
+    if len > 0 {
+        if data.is_null() {
+            return -1;
+        }
+        chunker.chunker.push(Bytes::copy_from_slice(slice::from_raw_parts(data, len)));
+    }
+    chunker.pump()
+}
+
+/// Signal the end of input, so the final Cluster is passed to the callback.
+/// Returns 0 on success, or -1 on error.
+///
+/// # Safety
+/// `chunker` must come from `webmetro_chunker_new`.
+#[no_mangle]
+pub unsafe extern "C" fn webmetro_chunker_finish(chunker: *mut WebmetroChunker) -> c_int {
+    match chunker.as_mut() {
+        Some(chunker) => {
+            chunker.chunker.finish();
+            chunker.pump()
+        },
+        None => -1
+    }
+}
+
+/// The error that stopped the chunker, or NULL if there hasn't been one.
+/// The string is owned by the chunker.
+///
+/// # Safety
+/// `chunker` must come from `webmetro_chunker_new`.
+#[no_mangle]
+pub unsafe extern "C" fn webmetro_chunker_error(chunker: *const WebmetroChunker) -> *const c_char {
+    match chunker.as_ref().and_then(|chunker| chunker.error.as_ref()) {
+        Some(message) => message.as_ptr(),
+        None => ptr::null(),
+    }
+}
+
+/// # Safety
+/// `chunker` must come from `webmetro_chunker_new`, and not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn webmetro_chunker_free(chunker: *mut WebmetroChunker) {
+    if !chunker.is_null() {
+        drop(Box::from_raw(chunker));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::CStr;
+
+    use crate::ffi::*;
+    use crate::tests::ENCODE_WEBM_TEST_FILE;
+
+    extern "C" fn collect(user_data: *mut c_void, info: *const WebmetroChunkInfo, data: *const u8, len: usize) {
+        let chunks = unsafe { &mut *(user_data as *mut Vec<(c_int, u64, Vec<u8>)>) };
+        let info = unsafe { &*info };
+        chunks.push((info.kind, info.start, unsafe { slice::from_raw_parts(data, len) }.to_vec()));
+    }
+
+    #[test]
+    fn chunk_through_ffi() {
+        let mut chunks: Vec<(c_int, u64, Vec<u8>)> = Vec::new();
+        unsafe {
+            let chunker = webmetro_chunker_new(0, collect, &mut chunks as *mut _ as *mut c_void);
+            for piece in ENCODE_WEBM_TEST_FILE.chunks(7) {
+                assert_eq!(webmetro_chunker_feed(chunker, piece.as_ptr(), piece.len()), 0);
+            }
+            assert_eq!(webmetro_chunker_finish(chunker), 0);
+            assert!(webmetro_chunker_error(chunker).is_null());
+            webmetro_chunker_free(chunker);
+        }
+
+        let kinds: Vec<c_int> = chunks.iter().map(|(kind, _, _)| *kind).collect();
+        assert_eq!(kinds, vec![WEBMETRO_CHUNK_HEADERS, WEBMETRO_CHUNK_CLUSTER, WEBMETRO_CHUNK_CLUSTER]);
+        assert_eq!(chunks[2].1, 1000);
+        assert!(chunks[0].2.starts_with(&[0x1A, 0x45, 0xDF, 0xA3]));
+    }
+
+    #[test]
+    fn report_errors() {
+        let mut chunks: Vec<(c_int, u64, Vec<u8>)> = Vec::new();
+        let garbage = [0xFF; 64];
+        unsafe {
+            let chunker = webmetro_chunker_new(16, collect, &mut chunks as *mut _ as *mut c_void);
+            assert_eq!(webmetro_chunker_feed(chunker, garbage.as_ptr(), garbage.len()), -1);
+            let message = CStr::from_ptr(webmetro_chunker_error(chunker));
+            assert!(!message.to_bytes().is_empty());
+            assert_eq!(webmetro_chunker_feed(chunker, garbage.as_ptr(), garbage.len()), -1);
+            webmetro_chunker_free(chunker);
+        }
+        assert!(chunks.is_empty());
+    }
+}
@@ -1,23 +1,98 @@
+use std::collections::VecDeque;
+use std::mem;
+use std::ops::Range;
 use std::pin::Pin;
 use std::task::{
     Context,
     Poll
 };
+use std::time::Duration;
 
+use bytes::Bytes;
 use futures::prelude::*;
+#[cfg(feature = "tokio")]
 use tokio::time::{
     delay_until,
     Delay,
-    Duration,
     Instant,
 };
 
-use crate::chunk::Chunk;
+use tracing::Span;
+
+#[cfg(feature = "tokio")]
+use crate::adapters::ChunkReader;
+use crate::chunk::{Chunk, ClusterHead};
+#[cfg(feature = "tokio")]
+use crate::error::WebmetroError;
+use crate::segmenter::{Segmenter, Segments};
+use crate::webm::{block_timing, cluster_blocks, element_offsets, encode_block, encode_simple_block, header_tracks, parse_webm, remove_tracks, video_tracks, SimpleBlock, WebmElement, KEYFRAME};
+
+/// One stage of a chunk pipeline, like the fixers here: it takes in each
+/// chunk in turn and passes on what should follow downstream in its place,
+/// which is usually the same chunk, maybe changed, but can be nothing (to
+/// drop it) or several chunks (to add some). Run a stream through one with
+/// `ChunkStream::process_with`, or chain them into a `Pipeline`.
+pub trait ChunkProcessor: Send {
+    /// Take in a chunk, pushing whatever replaces it onto `output`
+    fn process_chunk(&mut self, chunk: Chunk, output: &mut Vec<Chunk>);
+}
+
+impl<P: ChunkProcessor + ?Sized> ChunkProcessor for Box<P> {
+    fn process_chunk(&mut self, chunk: Chunk, output: &mut Vec<Chunk>) {
+        (**self).process_chunk(chunk, output)
+    }
+}
+
+/// Stages run one after another, each on what the last passed on
+#[derive(Default)]
+pub struct Pipeline {
+    stages: Vec<Box<dyn ChunkProcessor>>,
+    /// reused between chunks, to pass chunks from one stage to the next
+    scratch: Vec<Chunk>,
+}
+
+impl Pipeline {
+    pub fn new() -> Pipeline {
+        Pipeline::default()
+    }
+
+    /// Add a stage after the others
+    pub fn push(&mut self, stage: impl ChunkProcessor + 'static) {
+        self.stages.push(Box::new(stage));
+    }
+
+    /// Add a stage after the others, builder-style
+    pub fn with(mut self, stage: impl ChunkProcessor + 'static) -> Self {
+        self.push(stage);
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.stages.is_empty()
+    }
+}
+
+impl ChunkProcessor for Pipeline {
+    fn process_chunk(&mut self, chunk: Chunk, output: &mut Vec<Chunk>) {
+        let mut current = mem::take(&mut self.scratch);
+        current.push(chunk);
+        let mut next = Vec::new();
+        for stage in &mut self.stages {
+            for chunk in current.drain(..) {
+                stage.process_chunk(chunk, &mut next);
+            }
+            mem::swap(&mut current, &mut next);
+        }
+        output.append(&mut current);
+        self.scratch = current;
+    }
+}
 
 pub struct ChunkTimecodeFixer {
     current_offset: u64,
     last_observed_timecode: u64,
-    assumed_duration: u64
+    assumed_duration: u64,
+    span: Span,
 }
 
 impl ChunkTimecodeFixer {
@@ -25,16 +100,20 @@ impl ChunkTimecodeFixer {
         ChunkTimecodeFixer {
             current_offset: 0,
             last_observed_timecode: 0,
-            assumed_duration: 33
+            assumed_duration: 33,
+            span: debug_span!("fix"),
         }
     }
     pub fn process(&mut self, mut chunk: Chunk) -> Chunk {
+        let _enter = self.span.enter();
         match chunk {
             Chunk::Cluster(ref mut cluster_head, _) => {
                 let start = cluster_head.start;
                 if start < self.last_observed_timecode {
                     let next_timecode = self.last_observed_timecode + self.assumed_duration;
                     self.current_offset = next_timecode - start;
+                    debug!(offset = self.current_offset, "timecodes went backwards, offsetting");
+                    cluster_head.discontinuity = true;
                 }
 
                 cluster_head.update_timecode(start + self.current_offset);
@@ -46,6 +125,434 @@ impl ChunkTimecodeFixer {
     }
 }
 
+/// Marks Clusters that start more than `threshold` ms after the one before
+/// ended as discontinuities, so gaps (an encoder stalling, say) can be
+/// signaled downstream instead of just making timecodes jump
+pub struct GapDetector {
+    threshold: u64,
+    last_end: Option<u64>,
+    span: Span,
+}
+
+impl GapDetector {
+    pub fn new(threshold: u64) -> GapDetector {
+        GapDetector {
+            threshold,
+            last_end: None,
+            span: debug_span!("gaps"),
+        }
+    }
+    pub fn process(&mut self, mut chunk: Chunk) -> Chunk {
+        let _enter = self.span.enter();
+        if let Chunk::Cluster(ref mut cluster_head, _) = chunk {
+            if let Some(last_end) = self.last_end {
+                let gap = cluster_head.start.saturating_sub(last_end);
+                if gap > self.threshold {
+                    info!(gap, "timecodes jumped ahead, marking a discontinuity");
+                    cluster_head.discontinuity = true;
+                }
+            }
+            self.last_end = Some(cluster_head.end);
+        }
+        chunk
+    }
+}
+
+/// How many ms a `DriftCorrector` shifts audio by per Cluster, by default
+pub const DEFAULT_DRIFT_STEP: u64 = 10;
+
+/// Keeps a stream's audio in step with its video, as encoders' clocks drift
+/// apart over hours. Where a Cluster's last audio block is more than
+/// `threshold` ms ahead of (or behind) its last video block, audio timecodes
+/// from there on are shifted back toward the video's, by at most `max_step`
+/// ms per Cluster so playback doesn't jump. Only timecodes change; streams
+/// without both audio & video pass through as they are.
+pub struct DriftCorrector {
+    threshold: u64,
+    max_step: u64,
+    audio_tracks: Vec<u64>,
+    video_tracks: Vec<u64>,
+    /// in ms, added to audio timecodes
+    correction: i64,
+    span: Span,
+}
+
+impl DriftCorrector {
+    pub fn new(threshold: u64) -> DriftCorrector {
+        DriftCorrector {
+            threshold,
+            max_step: DEFAULT_DRIFT_STEP,
+            audio_tracks: Vec::new(),
+            video_tracks: Vec::new(),
+            correction: 0,
+            span: debug_span!("drift"),
+        }
+    }
+
+    /// Shift audio by at most this many ms per Cluster
+    pub fn max_step(mut self, step: u64) -> Self {
+        self.max_step = step.max(1);
+        self
+    }
+
+    /// How many ms audio timecodes are currently being shifted by
+    pub fn correction(&self) -> i64 {
+        self.correction
+    }
+
+    pub fn process(&mut self, chunk: Chunk) -> Chunk {
+        let span = self.span.clone();
+        let _enter = span.enter();
+        match chunk {
+            Chunk::Headers { ref bytes } => {
+                let tracks = header_tracks(bytes);
+                self.audio_tracks = tracks.iter().filter(|track| track.is_audio()).map(|track| track.number).collect();
+                self.video_tracks = tracks.iter().filter(|track| track.is_video()).map(|track| track.number).collect();
+                // a new source brings its own clocks
+                self.correction = 0;
+                chunk
+            },
+            Chunk::Cluster(head, body) if !self.audio_tracks.is_empty() && !self.video_tracks.is_empty() => {
+                self.measure(&head, &body);
+                if self.correction == 0 {
+                    Chunk::Cluster(head, body)
+                } else {
+                    self.shift_audio(head, &body)
+                }
+            },
+            chunk => chunk,
+        }
+    }
+
+    /// Compare the Cluster's last audio & video timecodes, adjusting the
+    /// correction if they've drifted too far apart
+    fn measure(&mut self, head: &ClusterHead, body: &[u8]) {
+        let mut last_audio = None;
+        let mut last_video = None;
+        for element in parse_webm(body) {
+            if let Some((block, _)) = block_timing(&element) {
+                if self.audio_tracks.contains(&block.track) {
+                    last_audio = Some(head.block_time(block.timecode) as i64);
+                } else if self.video_tracks.contains(&block.track) {
+                    last_video = Some(head.block_time(block.timecode) as i64);
+                }
+            }
+        }
+        if let (Some(audio), Some(video)) = (last_audio, last_video) {
+            let drift = audio + self.correction - video;
+            if drift.abs() > self.threshold as i64 {
+                let step = drift.signum() * drift.abs().min(self.max_step as i64);
+                self.correction -= step;
+                info!(drift, correction = self.correction, "audio drifted from video, shifting its timecodes");
+            }
+        }
+    }
+
+    /// Rewrite the Cluster with the correction added to its audio blocks'
+    /// timecodes; other elements are kept as they are
+    fn shift_audio(&self, mut head: ClusterHead, body: &Bytes) -> Chunk {
+        head.end = head.start;
+        // the correction's in ms, but the blocks are written in ticks
+        let correction = head.ticks(self.correction);
+        let body = rewrite_cluster(body, |element, _, output| {
+            let block = match block_timing(&element) {
+                Some((block, _)) => block,
+                None => return false,
+            };
+            let shifted = self.audio_tracks.contains(&block.track);
+            let timecode = if shifted {
+                (block.timecode as i64 + correction)
+                    .max(i16::min_value() as i64)
+                    .min(i16::max_value() as i64) as i16
+            } else {
+                block.timecode
+            };
+            head.end = head.end.max(head.block_time(timecode));
+            if shifted {
+                // writing to memory can't fail
+                encode_block(element, timecode, output).unwrap();
+            }
+            shifted
+        });
+        Chunk::Cluster(head, body)
+    }
+}
+
+/// A 20ms Opus packet of silence (a CELT frame with no energy), which
+/// decodes for any channel count
+const OPUS_SILENCE: [u8; 3] = [0xf8, 0xff, 0xfe];
+const OPUS_SILENCE_DURATION: u64 = 20;
+/// The longest an Opus packet can play for, in ms
+const MAX_OPUS_PACKET_DURATION: u64 = 120;
+
+/// How many ms of silence a `SilenceFiller` puts in one gap, by default
+pub const DEFAULT_MAX_SILENCE: u64 = 10_000;
+
+struct OpusTrack {
+    number: u64,
+    /// the timecode of its last block, in ms
+    last: Option<u64>,
+    /// how long its blocks have been lasting, in ms
+    frame: u64,
+}
+
+/// Fills gaps in a stream's Opus tracks with silent packets, so the audio
+/// timeline stays continuous where a publisher dropped some: where a block
+/// arrives more than `threshold` ms after the last one should have ended,
+/// 20ms packets of silence are put in front of it to cover the gap. Gaps
+/// longer than `max_silence` ms are left alone, as are other tracks.
+pub struct SilenceFiller {
+    threshold: u64,
+    max_silence: u64,
+    tracks: Vec<OpusTrack>,
+    span: Span,
+}
+
+impl SilenceFiller {
+    pub fn new(threshold: u64) -> SilenceFiller {
+        SilenceFiller {
+            threshold,
+            max_silence: DEFAULT_MAX_SILENCE,
+            tracks: Vec::new(),
+            span: debug_span!("silence"),
+        }
+    }
+
+    /// Leave gaps longer than this many ms as they are
+    pub fn max_silence(mut self, duration: u64) -> Self {
+        self.max_silence = duration;
+        self
+    }
+
+    pub fn process(&mut self, chunk: Chunk) -> Chunk {
+        let span = self.span.clone();
+        let _enter = span.enter();
+        match chunk {
+            Chunk::Headers { ref bytes } => {
+                self.tracks = header_tracks(bytes)
+                    .iter()
+                    .filter(|track| track.codec_id == "A_OPUS")
+                    .map(|track| OpusTrack {
+                        number: track.number,
+                        last: None,
+                        frame: OPUS_SILENCE_DURATION,
+                    })
+                    .collect();
+                chunk
+            },
+            Chunk::Cluster(head, body) if !self.tracks.is_empty() => self.fill(head, body),
+            chunk => chunk,
+        }
+    }
+
+    /// Copy the Cluster, putting silence in front of Opus blocks that follow
+    /// a gap; returns it as it is if there aren't any
+    fn fill(&mut self, head: ClusterHead, body: Bytes) -> Chunk {
+        let body = rewrite_cluster(&body, |element, bytes, output| {
+            let block = match block_timing(&element) {
+                Some((block, _)) => block,
+                None => return false,
+            };
+            let time = head.block_time(block.timecode);
+            let track = match self.tracks.iter_mut().find(|track| track.number == block.track) {
+                Some(track) => track,
+                None => return false,
+            };
+            let mut filled = false;
+            if let Some(last) = track.last {
+                let expected = last + track.frame;
+                let gap = time.saturating_sub(expected);
+                if gap > self.threshold && gap <= self.max_silence {
+                    let mut silence = expected;
+                    let mut frames = 0;
+                    while silence + OPUS_SILENCE_DURATION <= time {
+                        let timecode = head.block_timecode(silence);
+                        // Clusters can only hold blocks within 32s of their start
+                        if timecode >= i16::min_value() as i64 {
+                            let block = SimpleBlock {
+                                track: track.number,
+                                timecode: timecode as i16,
+                                // silent frames decode on their own, whatever the next block's lacing & such
+                                flags: KEYFRAME,
+                                data: &OPUS_SILENCE,
+                            };
+                            // writing to memory can't fail
+                            encode_simple_block(block, output).unwrap();
+                            frames += 1;
+                        }
+                        silence += OPUS_SILENCE_DURATION;
+                    }
+                    info!(track = track.number, gap, frames, "filled a gap in the audio with silence");
+                    // followed by the block itself
+                    output.extend_from_slice(bytes);
+                    filled = true;
+                } else if time > last && time - last <= MAX_OPUS_PACKET_DURATION {
+                    track.frame = time - last;
+                }
+            }
+            track.last = Some(time);
+            filled
+        });
+        Chunk::Cluster(head, body)
+    }
+}
+
+/// Which of a stream's audio tracks a listener wants
+#[derive(Clone, Debug, PartialEq)]
+pub enum AudioChoice {
+    /// the audio track with this number
+    Track(u64),
+    /// the first audio track in this language (see `TrackEntry::in_language`)
+    Language(String),
+}
+
+/// Passes on just one of a stream's audio tracks, leaving the others' blocks
+/// out of Clusters and their TrackEntries out of the headers; other tracks
+/// are untouched. If no audio track is the one chosen, the stream passes
+/// through with all of them.
+pub struct AudioTrackSelector {
+    choice: AudioChoice,
+    /// the audio tracks being left out of the current stream
+    dropped: Vec<u64>,
+    span: Span,
+}
+
+impl AudioTrackSelector {
+    pub fn new(choice: AudioChoice) -> AudioTrackSelector {
+        AudioTrackSelector {
+            choice,
+            dropped: Vec::new(),
+            span: debug_span!("audio_track"),
+        }
+    }
+
+    pub fn process(&mut self, chunk: Chunk) -> Chunk {
+        let span = self.span.clone();
+        let _enter = span.enter();
+        match chunk {
+            Chunk::Headers { ref bytes } => {
+                let tracks = header_tracks(bytes);
+                let audio: Vec<_> = tracks.iter().filter(|track| track.is_audio()).collect();
+                let chosen = audio.iter().find(|track| match self.choice {
+                    AudioChoice::Track(number) => track.number == number,
+                    AudioChoice::Language(ref language) => track.in_language(language),
+                });
+                self.dropped = match chosen {
+                    Some(chosen) => audio.iter().map(|track| track.number).filter(|&number| number != chosen.number).collect(),
+                    None => {
+                        debug!("No audio track is {:?}, passing on all of them", self.choice);
+                        Vec::new()
+                    }
+                };
+                if self.dropped.is_empty() {
+                    return chunk;
+                }
+                match remove_tracks(bytes, &self.dropped) {
+                    Ok(headers) => Chunk::Headers { bytes: Bytes::from(headers) },
+                    Err(err) => {
+                        warn!("Couldn't remove audio tracks from headers: {}", err);
+                        self.dropped.clear();
+                        chunk
+                    }
+                }
+            },
+            Chunk::Cluster(head, body) if !self.dropped.is_empty() => self.filter(head, &body),
+            chunk => chunk,
+        }
+    }
+
+    /// Copy the Cluster without the dropped tracks' blocks
+    fn filter(&self, head: ClusterHead, body: &Bytes) -> Chunk {
+        // their blocks are replaced with nothing
+        let body = rewrite_cluster(body, |element, _, _| {
+            matches!(block_timing(&element), Some((block, _)) if self.dropped.contains(&block.track))
+        });
+        Chunk::Cluster(head, body)
+    }
+}
+
+/// Rewrite a Cluster's body element by element: `rewrite` is given each
+/// element & its bytes, and returns whether it's written something to take
+/// the element's place (which may be nothing at all). The body is only
+/// copied once an element has been replaced.
+fn rewrite_cluster(body: &Bytes, mut rewrite: impl FnMut(WebmElement, &[u8], &mut Vec<u8>) -> bool) -> Bytes {
+    let mut output: Option<Vec<u8>> = None;
+    let mut replacement = Vec::new();
+    for (offset, length, element) in element_offsets(body) {
+        let bytes = &body[offset..offset + length];
+        replacement.clear();
+        if rewrite(element, bytes, &mut replacement) {
+            output.get_or_insert_with(|| {
+                let mut output = Vec::with_capacity(body.len());
+                output.extend_from_slice(&body[..offset]);
+                output
+            }).extend_from_slice(&replacement);
+        } else if let Some(ref mut output) = output {
+            output.extend_from_slice(bytes);
+        }
+    }
+    output.map_or_else(|| body.clone(), Bytes::from)
+}
+
+impl ChunkProcessor for ChunkTimecodeFixer {
+    fn process_chunk(&mut self, chunk: Chunk, output: &mut Vec<Chunk>) {
+        output.push(self.process(chunk));
+    }
+}
+
+impl ChunkProcessor for GapDetector {
+    fn process_chunk(&mut self, chunk: Chunk, output: &mut Vec<Chunk>) {
+        output.push(self.process(chunk));
+    }
+}
+
+impl ChunkProcessor for DriftCorrector {
+    fn process_chunk(&mut self, chunk: Chunk, output: &mut Vec<Chunk>) {
+        output.push(self.process(chunk));
+    }
+}
+
+impl ChunkProcessor for SilenceFiller {
+    fn process_chunk(&mut self, chunk: Chunk, output: &mut Vec<Chunk>) {
+        output.push(self.process(chunk));
+    }
+}
+
+impl ChunkProcessor for AudioTrackSelector {
+    fn process_chunk(&mut self, chunk: Chunk, output: &mut Vec<Chunk>) {
+        output.push(self.process(chunk));
+    }
+}
+
+/// A chunk stream run through a `ChunkProcessor`; see `ChunkStream::process_with`
+pub struct Processed<S, P> {
+    stream: S,
+    processor: P,
+    ready: VecDeque<Chunk>,
+    buffer: Vec<Chunk>,
+}
+
+impl<S: TryStream<Ok = Chunk> + Unpin, P: ChunkProcessor + Unpin> Stream for Processed<S, P>
+{
+    type Item = Result<Chunk, S::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Chunk, S::Error>>> {
+        let this = &mut *self;
+        loop {
+            if let Some(chunk) = this.ready.pop_front() {
+                return Poll::Ready(Some(Ok(chunk)));
+            }
+            match this.stream.try_poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    this.processor.process_chunk(chunk, &mut this.buffer);
+                    this.ready.extend(this.buffer.drain(..));
+                },
+                other => return other,
+            }
+        }
+    }
+}
+
 pub struct StartingPointFinder<S> {
     stream: S,
     seen_header: bool,
@@ -86,12 +593,169 @@ impl<S: TryStream<Ok = Chunk> + Unpin> Stream for StartingPointFinder<S>
     }
 }
 
+pub struct FillHeaders<S, F> {
+    stream: S,
+    headers: Option<Pin<Box<F>>>,
+    /// the last headers passed on, to stand in for any more missing ones
+    last: Option<Bytes>,
+    waiting: bool,
+}
+
+impl<S: TryStream<Ok = Chunk> + Unpin, F: Future<Output = Result<Bytes, S::Error>>> Stream for FillHeaders<S, F>
+{
+    type Item = Result<Chunk, S::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Chunk, S::Error>>> {
+        let this = &mut *self;
+        loop {
+            if this.waiting {
+                let headers = match this.headers.as_mut() {
+                    Some(headers) => futures::ready!(headers.as_mut().poll(cx)),
+                    None => unreachable!("waiting without headers to wait for"),
+                };
+                this.headers = None;
+                this.waiting = false;
+                return Poll::Ready(Some(headers.map(|bytes| {
+                    this.last = Some(bytes.clone());
+                    Chunk::Headers { bytes }
+                })));
+            }
+            return match this.stream.try_poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(Chunk::Headers { bytes }))) if bytes.is_empty() => {
+                    if let Some(ref last) = this.last {
+                        Poll::Ready(Some(Ok(Chunk::Headers { bytes: last.clone() })))
+                    } else if this.headers.is_some() {
+                        this.waiting = true;
+                        continue;
+                    } else {
+                        Poll::Ready(Some(Ok(Chunk::Headers { bytes })))
+                    }
+                },
+                Poll::Ready(Some(Ok(Chunk::Headers { bytes }))) => {
+                    // the stream brought its own, so there's no need to fetch any
+                    this.headers = None;
+                    this.last = Some(bytes.clone());
+                    Poll::Ready(Some(Ok(Chunk::Headers { bytes })))
+                },
+                other => other,
+            };
+        }
+    }
+}
+
+/// When a Cluster's first block plays
+fn first_time(head: &ClusterHead, blocks: &[(usize, SimpleBlock, bool)]) -> u64 {
+    blocks.first().map_or(head.start, |(_, block, _)| head.block_time(block.timecode))
+}
+
+/// Part of a Cluster, keeping its timecode so the blocks' stay valid
+fn slice_cluster(head: &ClusterHead, body: &Bytes, range: Range<usize>, blocks: &[(usize, SimpleBlock, bool)]) -> Chunk {
+    // a copy of the head, so it's written at the same TimestampScale
+    let mut sliced = head.clone();
+    sliced.end = sliced.start;
+    let mut kept = blocks.iter().filter(|(offset, _, _)| range.contains(offset)).peekable();
+    sliced.keyframe = kept.peek().map_or(false, |(_, _, keyframe)| *keyframe);
+    for (_, block, _) in kept {
+        sliced.observe_simpleblock_timecode(block.timecode);
+    }
+    Chunk::Cluster(sliced, body.slice(range))
+}
+
+/// Drops the start of a chunk stream; see `ChunkStream::skip_duration`
+pub struct SkipDuration<S> {
+    stream: S,
+    skip: u64,
+    /// where the edge is, once the first Cluster is seen
+    edge: Option<u64>,
+    video_tracks: Vec<u64>,
+    started: bool,
+}
+
+impl<S: TryStream<Ok = Chunk> + Unpin> Stream for SkipDuration<S>
+{
+    type Item = Result<Chunk, S::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Chunk, S::Error>>> {
+        let this = &mut *self;
+        loop {
+            return match this.stream.try_poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(Chunk::Cluster(head, body)))) if !this.started => {
+                    let blocks = cluster_blocks(&body);
+                    let edge = *this.edge.get_or_insert(first_time(&head, &blocks).saturating_add(this.skip));
+                    let resume_at = blocks.iter().find(|(_, block, keyframe)| {
+                        *keyframe
+                            && (this.video_tracks.is_empty() || this.video_tracks.contains(&block.track))
+                            && head.block_time(block.timecode) >= edge
+                    }).map(|(offset, _, _)| *offset);
+                    match resume_at {
+                        Some(offset) => {
+                            this.started = true;
+                            Poll::Ready(Some(Ok(slice_cluster(&head, &body, offset..body.len(), &blocks))))
+                        },
+                        None => continue,
+                    }
+                },
+                Poll::Ready(Some(Ok(Chunk::Headers { bytes }))) => {
+                    this.video_tracks = video_tracks(&bytes);
+                    Poll::Ready(Some(Ok(Chunk::Headers { bytes })))
+                },
+                other => other
+            };
+        }
+    }
+}
+
+/// Ends a chunk stream partway; see `ChunkStream::take_duration`
+pub struct TakeDuration<S> {
+    stream: S,
+    take: u64,
+    /// where the edge is, once the first Cluster is seen
+    edge: Option<u64>,
+    ended: bool,
+}
+
+impl<S: TryStream<Ok = Chunk> + Unpin> Stream for TakeDuration<S>
+{
+    type Item = Result<Chunk, S::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Chunk, S::Error>>> {
+        let this = &mut *self;
+        if this.ended {
+            return Poll::Ready(None);
+        }
+        match this.stream.try_poll_next_unpin(cx) {
+            Poll::Ready(Some(Ok(Chunk::Cluster(head, body)))) => {
+                let blocks = cluster_blocks(&body);
+                let edge = *this.edge.get_or_insert(first_time(&head, &blocks).saturating_add(this.take));
+                let end_at = blocks.iter()
+                    .find(|(_, block, _)| head.block_time(block.timecode) >= edge)
+                    .map(|(offset, _, _)| *offset);
+                match end_at {
+                    None => Poll::Ready(Some(Ok(Chunk::Cluster(head, body)))),
+                    Some(0) => {
+                        this.ended = true;
+                        Poll::Ready(None)
+                    },
+                    Some(offset) => {
+                        this.ended = true;
+                        Poll::Ready(Some(Ok(slice_cluster(&head, &body, 0..offset, &blocks))))
+                    }
+                }
+            },
+            other => other
+        }
+    }
+}
+
+/// Delays chunks so they're yielded in real time, according to their timecodes
+#[cfg(feature = "tokio")]
 pub struct Throttle<S> {
     stream: S,
     start_time: Option<Instant>,
     sleep: Delay
 }
 
+#[cfg(feature = "tokio")]
 impl<S> Throttle<S> {
     pub fn new(wrap: S) -> Throttle<S> {
         let now = Instant::now();
@@ -103,6 +767,7 @@ impl<S> Throttle<S> {
     }
 }
 
+#[cfg(feature = "tokio")]
 impl<S: TryStream<Ok = Chunk> + Unpin> Stream for Throttle<S>
 {
     type Item = Result<Chunk, S::Error>;
@@ -127,6 +792,138 @@ impl<S: TryStream<Ok = Chunk> + Unpin> Stream for Throttle<S>
     }
 }
 
+/// An empty EBML Void element
+#[cfg(feature = "tokio")]
+const VOID_PADDING: [u8; 2] = [0xEC, 0x80];
+
+/// Fills silences in a WebM byte stream with EBML Void elements, so proxies
+/// & players with idle timeouts don't give up on a stalled source. Nothing is
+/// added before the stream's first bytes, since a Void ahead of the EBML
+/// header would hide what kind of file it is.
+#[cfg(feature = "tokio")]
+pub struct IdlePadding<S> {
+    stream: S,
+    interval: Duration,
+    started: bool,
+    sleep: Delay,
+    condition: Option<Box<dyn Fn() -> bool + Send>>
+}
+
+#[cfg(feature = "tokio")]
+impl<S> IdlePadding<S> {
+    /// Pad `wrap` whenever it's been idle for `interval`; its items must each
+    /// be whole elements, as the relay's listener streams are
+    pub fn new(wrap: S, interval: Duration) -> IdlePadding<S> {
+        IdlePadding {
+            stream: wrap,
+            interval,
+            started: false,
+            sleep: delay_until(Instant::now() + interval),
+            condition: None
+        }
+    }
+
+    /// Only pad while `condition` holds, e.g. while a channel is paused;
+    /// it's checked each time the stream has been idle for the interval
+    pub fn only_while(mut self, condition: impl Fn() -> bool + Send + 'static) -> IdlePadding<S> {
+        self.condition = Some(Box::new(condition));
+        self
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<S: TryStream<Ok = Bytes> + Unpin> Stream for IdlePadding<S>
+{
+    type Item = Result<Bytes, S::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Bytes, S::Error>>> {
+        match self.stream.try_poll_next_unpin(cx) {
+            Poll::Pending => {},
+            next => {
+                if let Poll::Ready(Some(Ok(_))) = next {
+                    self.started = true;
+                }
+                let idle_until = Instant::now() + self.interval;
+                self.sleep.reset(idle_until);
+                return next;
+            }
+        }
+
+        if !self.started {
+            return Poll::Pending;
+        }
+        loop {
+            match self.sleep.poll_unpin(cx) {
+                Poll::Pending => return Poll::Pending,
+                Poll::Ready(()) => {
+                    let idle_until = Instant::now() + self.interval;
+                    self.sleep.reset(idle_until);
+                    let pad = match self.condition {
+                        Some(ref condition) => condition(),
+                        None => true
+                    };
+                    if pad {
+                        return Poll::Ready(Some(Ok(Bytes::from_static(&VOID_PADDING))));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Fails a stream with `WebmetroError::Timeout`, then ends it, if it goes
+/// `timeout` without yielding anything; e.g. for a publisher that's stopped
+/// sending without closing its connection
+#[cfg(feature = "tokio")]
+pub struct IdleTimeout<S> {
+    stream: S,
+    timeout: Duration,
+    timed_out: bool,
+    sleep: Delay
+}
+
+#[cfg(feature = "tokio")]
+impl<S> IdleTimeout<S> {
+    pub fn new(wrap: S, timeout: Duration) -> IdleTimeout<S> {
+        IdleTimeout {
+            stream: wrap,
+            timeout,
+            timed_out: false,
+            sleep: delay_until(Instant::now() + timeout)
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<S: TryStream + Unpin> Stream for IdleTimeout<S>
+where
+    WebmetroError: From<S::Error>,
+{
+    type Item = Result<S::Ok, WebmetroError>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<S::Ok, WebmetroError>>> {
+        if self.timed_out {
+            return Poll::Ready(None);
+        }
+        match self.stream.try_poll_next_unpin(cx) {
+            Poll::Pending => {},
+            next => {
+                let deadline = Instant::now() + self.timeout;
+                self.sleep.reset(deadline);
+                return next.map(|item| item.map(|result| result.map_err(WebmetroError::from)));
+            }
+        }
+
+        match self.sleep.poll_unpin(cx) {
+            Poll::Pending => Poll::Pending,
+            Poll::Ready(()) => {
+                self.timed_out = true;
+                Poll::Ready(Some(Err(WebmetroError::Timeout)))
+            }
+        }
+    }
+}
+
 pub trait ChunkStream where Self : Sized + TryStream<Ok = Chunk> {
     /*fn fix_timecodes(self) -> Map<_> {
         let fixer = ;
@@ -144,9 +941,331 @@ pub trait ChunkStream where Self : Sized + TryStream<Ok = Chunk> {
         }
     }
 
+    /// Fill in the empty Headers chunk a stream joined partway through starts
+    /// with (see `EbmlStreamingParser::joining_mid_stream`) with headers from
+    /// elsewhere, such as a relay's `/live/<channel>/init`; `headers` is only
+    /// awaited if they're needed, and its error ends the stream. Follow this
+    /// with `find_starting_point` to hold off until a keyframe.
+    fn fill_headers<F: Future<Output = Result<Bytes, Self::Error>>>(self, headers: F) -> FillHeaders<Self, F> {
+        FillHeaders {
+            stream: self,
+            headers: Some(Box::pin(headers)),
+            last: None,
+            waiting: false,
+        }
+    }
+
+    /// Run the chunks through a processing stage (or a `Pipeline` of them)
+    fn process_with<P: ChunkProcessor>(self, processor: P) -> Processed<Self, P> {
+        Processed {
+            stream: self,
+            processor,
+            ready: VecDeque::new(),
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Drop about the first `duration` of the stream, measured from its
+    /// first block's timecode, resuming at the first video keyframe (or
+    /// any keyframe, without video) at or after that, partway through its
+    /// Cluster if need be. Headers are passed along, and timecodes are left
+    /// as they are.
+    fn skip_duration(self, duration: Duration) -> SkipDuration<Self> {
+        SkipDuration {
+            stream: self,
+            skip: duration.as_millis() as u64,
+            edge: None,
+            video_tracks: Vec::new(),
+            started: false,
+        }
+    }
+
+    /// End the stream after `duration`, measured from its first block's
+    /// timecode, partway through the Cluster that crosses it if need be
+    fn take_duration(self, duration: Duration) -> TakeDuration<Self> {
+        TakeDuration {
+            stream: self,
+            take: duration.as_millis() as u64,
+            edge: None,
+            ended: false,
+        }
+    }
+
+    /// Collect the chunks into keyframe-aligned segments
+    fn segment(self, segmenter: Segmenter) -> Segments<Self> {
+        Segments::new(self, segmenter)
+    }
+
+    #[cfg(feature = "tokio")]
     fn throttle(self) -> Throttle<Self> {
         Throttle::new(self)
     }
+
+    #[cfg(feature = "tokio")]
+    fn into_reader(self) -> ChunkReader<Self> {
+        ChunkReader::new(self)
+    }
 }
 
 impl<T: TryStream<Ok = Chunk>> ChunkStream for T {}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, stream};
+    use matches::assert_matches;
+
+    use crate::ebml::{encode_bytes, encode_integer};
+    use crate::fixers::*;
+    use crate::tests::{block, block_group, cluster, timecodes};
+    use crate::webm::{encode_webm_element, CODEC_ID_ID, LANGUAGE_ID, TRACK_ENTRY_ID, TRACK_NUMBER_ID, TRACK_TYPE_ID};
+
+    /// Drops Headers & sends each Cluster twice
+    struct Doubler;
+
+    impl ChunkProcessor for Doubler {
+        fn process_chunk(&mut self, chunk: Chunk, output: &mut Vec<Chunk>) {
+            if let Chunk::Cluster(..) = chunk {
+                output.push(chunk.clone());
+                output.push(chunk);
+            }
+        }
+    }
+
+    #[test]
+    fn run_pipelines() {
+        let pipeline = Pipeline::new().with(Doubler).with(Doubler);
+        let chunks = vec![Chunk::Headers { bytes: Bytes::new() }, cluster(0, &[(1, 0)]), cluster(1000, &[(1, 0)])];
+        let processed: Vec<Chunk> = block_on(stream::iter(chunks.into_iter().map(Ok::<_, ()>))
+            .process_with(pipeline)
+            .try_collect())
+            .unwrap();
+        let starts: Vec<u64> = processed.iter().map(|chunk| match chunk {
+            Chunk::Cluster(head, _) => head.start,
+            _ => panic!("Headers weren't dropped"),
+        }).collect();
+        assert_eq!(starts, vec![0, 0, 0, 0, 1000, 1000, 1000, 1000]);
+    }
+
+    #[test]
+    fn fill_missing_headers() {
+        let headers = Bytes::from_static(b"headers");
+        let chunks = vec![
+            Chunk::Headers { bytes: Bytes::new() },
+            cluster(0, &[(1, 0)]),
+            Chunk::Headers { bytes: Bytes::new() },
+        ];
+        let filled: Vec<Chunk> = block_on(stream::iter(chunks.clone().into_iter().map(Ok::<_, ()>))
+            .fill_headers(future::ready(Ok(headers.clone())))
+            .try_collect())
+            .unwrap();
+        assert_matches!(filled[0], Chunk::Headers { ref bytes } if bytes == &headers);
+        assert_matches!(filled[1], Chunk::Cluster(..));
+        assert_matches!(filled[2], Chunk::Headers { ref bytes } if bytes == &headers);
+
+        // a stream with its own headers keeps them, without waiting on others
+        let own = vec![Chunk::Headers { bytes: Bytes::from_static(b"own") }, cluster(0, &[(1, 0)])];
+        let filled: Vec<Chunk> = block_on(stream::iter(own.into_iter().map(Ok::<_, ()>))
+            .fill_headers(future::pending())
+            .try_collect())
+            .unwrap();
+        assert_matches!(filled[0], Chunk::Headers { ref bytes } if &bytes[..] == b"own");
+
+        // and failing to get them fails the stream
+        let result: Result<Vec<Chunk>, ()> = block_on(stream::iter(chunks.into_iter().map(Ok::<_, ()>))
+            .fill_headers(future::ready(Err(())))
+            .try_collect());
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn clip_durations() {
+        let keyframes = |start: u64, blocks: &[(i16, bool)]| {
+            let mut body = Vec::new();
+            for &(timecode, keyframe) in blocks {
+                let flags = if keyframe { KEYFRAME } else { 0 };
+                encode_simple_block(SimpleBlock { track: 1, timecode, flags, data: &[0; 4] }, &mut body).unwrap();
+            }
+            Chunk::Cluster(ClusterHead::new(start), Bytes::from(body))
+        };
+        let chunks = vec![
+            Chunk::Headers { bytes: Bytes::new() },
+            keyframes(0, &[(0, true), (400, false), (800, true)]),
+            keyframes(1000, &[(0, false), (500, true), (900, false)]),
+            keyframes(2000, &[(0, true), (500, false)]),
+        ];
+        let clipped: Vec<Chunk> = block_on(stream::iter(chunks.into_iter().map(Ok::<_, ()>))
+            .skip_duration(Duration::from_millis(700))
+            .take_duration(Duration::from_millis(1500))
+            .try_collect())
+            .unwrap();
+
+        assert_matches!(clipped[0], Chunk::Headers { .. });
+        // resumed at the keyframe after 700ms, and ended before 2300ms
+        let clusters: Vec<(u64, Vec<(u64, i16)>)> = clipped[1..].iter().map(|chunk| match chunk {
+            Chunk::Cluster(head, _) => (head.start, timecodes(chunk)),
+            _ => panic!("not a Cluster"),
+        }).collect();
+        assert_eq!(clusters, vec![(0, vec![(1, 800)]), (1000, vec![(1, 0), (1, 500), (1, 900)]), (2000, vec![(1, 0)])]);
+        match clipped[1] {
+            Chunk::Cluster(ref head, _) => assert!(head.keyframe && head.end == 800),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn clip_rescaled_durations() {
+        // as in clip_durations, but with blocks in 100µs ticks
+        let keyframes = |start: u64, blocks: &[(i16, bool)]| {
+            let mut body = Vec::new();
+            for &(timecode, keyframe) in blocks {
+                let flags = if keyframe { KEYFRAME } else { 0 };
+                encode_simple_block(SimpleBlock { track: 1, timecode, flags, data: &[0; 4] }, &mut body).unwrap();
+            }
+            Chunk::Cluster(ClusterHead::with_scale(start, 100_000), Bytes::from(body))
+        };
+        let chunks = vec![
+            Chunk::Headers { bytes: Bytes::new() },
+            keyframes(0, &[(0, true), (4000, false), (8000, true)]),
+            keyframes(10_000, &[(0, false), (5000, true), (9000, false)]),
+            keyframes(20_000, &[(0, true), (5000, false)]),
+        ];
+        let clipped: Vec<Chunk> = block_on(stream::iter(chunks.into_iter().map(Ok::<_, ()>))
+            .skip_duration(Duration::from_millis(700))
+            .take_duration(Duration::from_millis(1500))
+            .try_collect())
+            .unwrap();
+
+        let clusters: Vec<(u64, Vec<(u64, i16)>)> = clipped[1..].iter().map(|chunk| match chunk {
+            Chunk::Cluster(head, _) => (head.start, timecodes(chunk)),
+            _ => panic!("not a Cluster"),
+        }).collect();
+        assert_eq!(clusters, vec![(0, vec![(1, 8000)]), (1000, vec![(1, 0), (1, 5000), (1, 9000)]), (2000, vec![(1, 0)])]);
+        match clipped[1] {
+            Chunk::Cluster(ref head, _) => assert!(head.keyframe && head.end == 800 && head.timestamp_scale() == 100_000),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn fill_silence() {
+        let mut filler = SilenceFiller::new(50);
+        filler.tracks = vec![OpusTrack { number: 2, last: None, frame: 20 }];
+
+        let chunk = filler.process(cluster(0, &[(1, 0), (2, 0), (2, 20), (1, 33), (2, 40)]));
+        assert_eq!(timecodes(&chunk), vec![(1, 0), (2, 0), (2, 20), (1, 33), (2, 40)]);
+
+        // the audio stops for 100ms, across a Cluster boundary
+        let chunk = filler.process(cluster(150, &[(1, 0), (2, 10)]));
+        assert_eq!(timecodes(&chunk), vec![(1, 0), (2, -90), (2, -70), (2, -50), (2, -30), (2, -10), (2, 10)]);
+        match chunk {
+            Chunk::Cluster(_, body) => assert!(body.windows(3).any(|window| window == OPUS_SILENCE)),
+            _ => unreachable!(),
+        }
+
+        // short jitter, other tracks, and very long gaps are left alone
+        let chunk = filler.process(cluster(200, &[(2, 0), (1, 500), (2, 30_000)]));
+        assert_eq!(timecodes(&chunk), vec![(2, 0), (1, 500), (2, 30_000)]);
+
+        // the silence doesn't take on a laced, discardable block's flags
+        let mut body = Vec::new();
+        encode_simple_block(SimpleBlock { track: 2, timecode: 0, flags: 0x07, data: &[0; 4] }, &mut body).unwrap();
+        let chunk = filler.process(Chunk::Cluster(ClusterHead::new(30_340), Bytes::from(body)));
+        assert_eq!(timecodes(&chunk), vec![(2, -100), (2, -80), (2, -60), (2, -40), (2, -20), (2, 0)]);
+        let flags: Vec<u8> = match chunk {
+            Chunk::Cluster(_, ref body) => parse_webm(body).filter_map(|element| block_timing(&element)).map(|(block, _)| block.flags).collect(),
+            _ => unreachable!(),
+        };
+        assert_eq!(flags, vec![KEYFRAME, KEYFRAME, KEYFRAME, KEYFRAME, KEYFRAME, 0x07]);
+    }
+
+    #[test]
+    fn correct_drift() {
+        let mut corrector = DriftCorrector::new(50).max_step(20);
+        corrector.video_tracks = vec![1];
+        corrector.audio_tracks = vec![2];
+
+        // within the threshold, nothing changes
+        let chunk = corrector.process(cluster(0, &[(1, 0), (2, 10), (1, 33), (2, 40)]));
+        assert_eq!(timecodes(&chunk), vec![(1, 0), (2, 10), (1, 33), (2, 40)]);
+
+        // audio running 100ms ahead is pulled back a step at a time
+        let chunk = corrector.process(cluster(1000, &[(1, 0), (2, 100)]));
+        assert_eq!(corrector.correction(), -20);
+        assert_eq!(timecodes(&chunk), vec![(1, 0), (2, 80)]);
+        let chunk = corrector.process(cluster(2000, &[(1, 0), (2, 100)]));
+        assert_eq!(timecodes(&chunk), vec![(1, 0), (2, 60)]);
+        match chunk {
+            Chunk::Cluster(head, _) => assert_eq!((head.start, head.end), (2000, 2060)),
+            _ => unreachable!(),
+        }
+        // until it's back within the threshold
+        corrector.process(cluster(3000, &[(1, 0), (2, 100)]));
+        corrector.process(cluster(4000, &[(1, 0), (2, 100)]));
+        assert_eq!(corrector.correction(), -60);
+
+        // other elements are kept in place
+        let mut body = block(1, 0, false);
+        body.extend_from_slice(&[0xec, 0x81, 0x00]);
+        body.extend(block(2, 100, false));
+        let chunk = corrector.process(Chunk::Cluster(ClusterHead::new(5000), Bytes::from(body)));
+        match chunk {
+            Chunk::Cluster(_, ref body) => assert!(body.windows(3).any(|window| window == [0xec, 0x81, 0x00])),
+            _ => unreachable!(),
+        }
+        assert_eq!(timecodes(&chunk), vec![(1, 0), (2, 40)]);
+
+        // as are BlockGroups, whose audio is shifted all the same
+        let mut body = block(1, 0, false);
+        body.extend(block_group(2, 100));
+        let chunk = corrector.process(Chunk::Cluster(ClusterHead::new(6000), Bytes::from(body)));
+        assert_eq!(timecodes(&chunk), vec![(1, 0), (2, 40)]);
+        match chunk {
+            Chunk::Cluster(_, ref body) => assert!(matches!(parse_webm(body).nth(1), Some(WebmElement::BlockGroup(_)))),
+            _ => unreachable!(),
+        }
+
+        // new headers start over
+        corrector.process(Chunk::Headers { bytes: Bytes::new() });
+        assert_eq!(corrector.correction(), 0);
+        let chunk = cluster(5000, &[(1, 0), (2, 100)]);
+        assert_eq!(timecodes(&corrector.process(chunk)), vec![(1, 0), (2, 100)]);
+    }
+
+    #[test]
+    fn select_audio_track() {
+        // a video track, then audio in English (by default) & German
+        let mut tracks = Vec::new();
+        for &(number, codec, language) in &[(1, "V_VP9", None), (2, "A_OPUS", None), (3, "A_OPUS", Some("ger"))] {
+            let mut entry = Vec::new();
+            encode_integer(TRACK_NUMBER_ID, number, &mut entry).unwrap();
+            encode_integer(TRACK_TYPE_ID, if number == 1 { 1 } else { 2 }, &mut entry).unwrap();
+            encode_bytes(CODEC_ID_ID, codec.as_bytes(), &mut entry).unwrap();
+            if let Some(language) = language {
+                encode_bytes(LANGUAGE_ID, language.as_bytes(), &mut entry).unwrap();
+            }
+            encode_bytes(TRACK_ENTRY_ID, &entry, &mut tracks).unwrap();
+        }
+        let mut headers = std::io::Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::EbmlHead, &mut headers).unwrap();
+        encode_webm_element(WebmElement::Segment, &mut headers).unwrap();
+        encode_webm_element(WebmElement::Tracks(&tracks), &mut headers).unwrap();
+        let headers = Chunk::Headers { bytes: Bytes::from(headers.into_inner()) };
+        let blocks = [(1, 0), (2, 0), (3, 0), (1, 33)];
+
+        let select = |choice| {
+            let mut selector = AudioTrackSelector::new(choice);
+            let tracks = match selector.process(headers.clone()) {
+                Chunk::Headers { bytes } => header_tracks(&bytes).iter().map(|track| track.number).collect::<Vec<_>>(),
+                _ => panic!("not Headers"),
+            };
+            (tracks, timecodes(&selector.process(cluster(0, &blocks))))
+        };
+
+        assert_eq!(select(AudioChoice::Language("de".into())), (vec![1, 3], vec![(1, 0), (3, 0), (1, 33)]));
+        assert_eq!(select(AudioChoice::Language("en".into())), (vec![1, 2], vec![(1, 0), (2, 0), (1, 33)]));
+        assert_eq!(select(AudioChoice::Track(3)), (vec![1, 3], vec![(1, 0), (3, 0), (1, 33)]));
+        // asking for something that isn't there gets everything
+        assert_eq!(select(AudioChoice::Language("fr".into())), (vec![1, 2, 3], blocks.to_vec()));
+        assert_eq!(select(AudioChoice::Track(1)), (vec![1, 2, 3], blocks.to_vec()));
+    }
+}
@@ -0,0 +1,664 @@
+//! Transmuxes a WebM chunk stream into fragmented MP4 (CMAF), for players
+//! that only take ISO BMFF, like Safari's Media Source Extensions.
+//!
+//! Each Headers chunk becomes an initialization segment (ftyp + moov), and
+//! each Cluster a fragment (moof + mdat) holding its blocks. VP8, VP9, AV1,
+//! and Opus tracks are carried; other tracks are left out. Video is timed in
+//! milliseconds, like WebM, and Opus in 48kHz samples.
+
+use bytes::Bytes;
+
+use crate::chunk::{Chunk, ClusterHead};
+use crate::error::WebmetroError;
+use crate::muxer::Muxer;
+use crate::ogg::opus_packet_samples;
+use crate::webm::{block_timing, header_tracks, parse_webm, SimpleBlock, TrackEntry};
+
+const MOVIE_TIMESCALE: u32 = 1000;
+const OPUS_TIMESCALE: u32 = 48000;
+
+const LACING_MASK: u8 = 0b0000_0110;
+
+/// sample_depends_on = 2: decodable on its own
+const SYNC_SAMPLE: u32 = 0x0200_0000;
+/// sample_depends_on = 1, sample_is_non_sync_sample = 1
+const NON_SYNC_SAMPLE: u32 = 0x0101_0000;
+
+/// How long a video frame is assumed to last when there's no later frame in
+/// its Cluster to measure it against, and no earlier one to copy, in ms
+const DEFAULT_FRAME_DURATION: u32 = 33;
+
+const UNITY_MATRIX: [u32; 9] = [0x0001_0000, 0, 0, 0, 0x0001_0000, 0, 0, 0, 0x4000_0000];
+
+/// Builds ISO BMFF boxes in one buffer, filling in each box's size once it's finished
+#[derive(Default)]
+struct BoxWriter {
+    buffer: Vec<u8>,
+}
+
+impl BoxWriter {
+    fn begin(&mut self, kind: &[u8; 4]) -> usize {
+        let start = self.buffer.len();
+        self.u32(0);
+        self.bytes(kind);
+        start
+    }
+
+    fn begin_full(&mut self, kind: &[u8; 4], version: u8, flags: u32) -> usize {
+        let start = self.begin(kind);
+        self.u32((version as u32) << 24 | flags);
+        start
+    }
+
+    fn end(&mut self, start: usize) {
+        let size = (self.buffer.len() - start) as u32;
+        self.buffer[start..start + 4].copy_from_slice(&size.to_be_bytes());
+    }
+
+    fn u8(&mut self, value: u8) {
+        self.buffer.push(value);
+    }
+
+    fn u16(&mut self, value: u16) {
+        self.bytes(&value.to_be_bytes());
+    }
+
+    fn u32(&mut self, value: u32) {
+        self.bytes(&value.to_be_bytes());
+    }
+
+    fn u64(&mut self, value: u64) {
+        self.bytes(&value.to_be_bytes());
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    fn zeros(&mut self, count: usize) {
+        self.buffer.resize(self.buffer.len() + count, 0);
+    }
+
+    fn matrix(&mut self) {
+        for value in UNITY_MATRIX.iter() {
+            self.u32(*value);
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Codec {
+    Vp8,
+    Vp9,
+    Av1,
+    Opus,
+}
+
+/// A WebM track carried in the MP4 output
+#[derive(Clone, Debug)]
+struct Track {
+    /// the WebM track number
+    number: u64,
+    /// the MP4 track_ID
+    id: u32,
+    codec: Codec,
+    entry: TrackEntry,
+    /// the duration of the last sample sent, for video frames that can't be measured
+    last_duration: u32,
+}
+
+impl Track {
+    fn timescale(&self) -> u32 {
+        match self.codec {
+            Codec::Opus => OPUS_TIMESCALE,
+            _ => MOVIE_TIMESCALE,
+        }
+    }
+
+    fn is_audio(&self) -> bool {
+        self.codec == Codec::Opus
+    }
+
+    /// The track's codec, as given in an RFC 6381 `codecs` parameter
+    fn codec_string(&self) -> String {
+        match self.codec {
+            Codec::Vp8 => "vp08.00.10.08".to_string(),
+            Codec::Vp9 => {
+                let config = vp9_config(&self.entry.codec_private);
+                format!("vp09.{:02}.{:02}.{:02}", config.profile, config.level, config.bit_depth)
+            },
+            Codec::Av1 => self.entry.av1_codec().unwrap_or_else(|| "av01".to_string()),
+            Codec::Opus => "opus".to_string(),
+        }
+    }
+}
+
+/// What a VP9 CodecPrivate says about the stream, with defaults for what it leaves out
+struct Vp9Config {
+    profile: u8,
+    level: u8,
+    bit_depth: u8,
+    chroma_subsampling: u8,
+}
+
+/// Read the feature list in a VP9 CodecPrivate: a series of ID, length, & value
+fn vp9_config(codec_private: &[u8]) -> Vp9Config {
+    let mut config = Vp9Config { profile: 0, level: 31, bit_depth: 8, chroma_subsampling: 1 };
+    let mut features = codec_private;
+    while features.len() >= 2 {
+        let (id, length) = (features[0], features[1] as usize);
+        let value = match features.get(2..2 + length) {
+            Some(value) => value,
+            None => break,
+        };
+        if length == 1 {
+            match id {
+                1 => config.profile = value[0],
+                2 => config.level = value[0],
+                3 => config.bit_depth = value[0],
+                4 => config.chroma_subsampling = value[0],
+                _ => {}
+            }
+        }
+        features = &features[2 + length..];
+    }
+    config
+}
+
+/// Turn an OpusHead (as in WebM's CodecPrivate) into the body of a dOps box
+fn opus_specific_box(entry: &TrackEntry, output: &mut BoxWriter) {
+    let head = &entry.codec_private;
+    let start = output.begin(b"dOps");
+    output.u8(0);
+    if head.len() >= 19 && head.starts_with(b"OpusHead") {
+        // OpusHead is little-endian, and dOps big-endian
+        output.u8(head[9]);
+        output.u16(u16::from_le_bytes([head[10], head[11]]));
+        output.u32(u32::from_le_bytes([head[12], head[13], head[14], head[15]]));
+        output.u16(u16::from_le_bytes([head[16], head[17]]));
+        output.u8(head[18]);
+        if head[18] != 0 {
+            // stream count, coupled count, and channel mapping
+            output.bytes(head.get(19..21 + head[9] as usize).unwrap_or(&[]));
+        }
+    } else {
+        output.u8(entry.channels.max(1) as u8);
+        output.u16(0);
+        output.u32(OPUS_TIMESCALE);
+        output.u16(0);
+        output.u8(0);
+    }
+    output.end(start);
+}
+
+fn sample_entry(track: &Track, output: &mut BoxWriter) {
+    let entry = &track.entry;
+    let kind = match track.codec {
+        Codec::Vp8 => b"vp08",
+        Codec::Vp9 => b"vp09",
+        Codec::Av1 => b"av01",
+        Codec::Opus => b"Opus",
+    };
+    let start = output.begin(kind);
+    output.zeros(6);
+    // data_reference_index
+    output.u16(1);
+
+    if track.is_audio() {
+        output.zeros(8);
+        output.u16(entry.channels.max(1) as u16);
+        // sample size
+        output.u16(16);
+        output.zeros(4);
+        output.u32(OPUS_TIMESCALE << 16);
+        opus_specific_box(entry, output);
+    } else {
+        output.zeros(16);
+        output.u16(entry.pixel_width as u16);
+        output.u16(entry.pixel_height as u16);
+        // 72 dpi
+        output.u32(0x0048_0000);
+        output.u32(0x0048_0000);
+        output.u32(0);
+        // frame count
+        output.u16(1);
+        // compressor name
+        output.zeros(32);
+        // depth
+        output.u16(0x0018);
+        output.u16(0xFFFF);
+        match track.codec {
+            Codec::Av1 => {
+                // WebM's AV1 CodecPrivate is the AV1CodecConfigurationRecord
+                let config = output.begin(b"av1C");
+                output.bytes(&entry.codec_private);
+                output.end(config);
+            },
+            _ => {
+                let config = if track.codec == Codec::Vp9 {
+                    vp9_config(&entry.codec_private)
+                } else {
+                    Vp9Config { profile: 0, level: 10, bit_depth: 8, chroma_subsampling: 1 }
+                };
+                let start = output.begin_full(b"vpcC", 1, 0);
+                output.u8(config.profile);
+                output.u8(config.level);
+                output.u8(config.bit_depth << 4 | config.chroma_subsampling << 1);
+                // colour primaries, transfer characteristics, and matrix coefficients, unspecified
+                output.u8(2);
+                output.u8(2);
+                output.u8(2);
+                // no codec initialization data
+                output.u16(0);
+                output.end(start);
+            }
+        }
+    }
+    output.end(start);
+}
+
+fn track_box(track: &Track, output: &mut BoxWriter) {
+    let trak = output.begin(b"trak");
+
+    // enabled & in the movie
+    let tkhd = output.begin_full(b"tkhd", 0, 3);
+    output.zeros(8);
+    output.u32(track.id);
+    output.zeros(4 + 4 + 8);
+    // layer & alternate group
+    output.zeros(4);
+    output.u16(if track.is_audio() { 0x0100 } else { 0 });
+    output.zeros(2);
+    output.matrix();
+    output.u32((track.entry.pixel_width as u32) << 16);
+    output.u32((track.entry.pixel_height as u32) << 16);
+    output.end(tkhd);
+
+    let mdia = output.begin(b"mdia");
+    let mdhd = output.begin_full(b"mdhd", 0, 0);
+    output.zeros(8);
+    output.u32(track.timescale());
+    output.u32(0);
+    // "und"
+    output.u16(0x55C4);
+    output.u16(0);
+    output.end(mdhd);
+
+    let hdlr = output.begin_full(b"hdlr", 0, 0);
+    output.u32(0);
+    output.bytes(if track.is_audio() { b"soun" } else { b"vide" });
+    output.zeros(12);
+    output.bytes(if track.is_audio() { b"SoundHandler\0" } else { b"VideoHandler\0" });
+    output.end(hdlr);
+
+    let minf = output.begin(b"minf");
+    if track.is_audio() {
+        let smhd = output.begin_full(b"smhd", 0, 0);
+        output.zeros(4);
+        output.end(smhd);
+    } else {
+        let vmhd = output.begin_full(b"vmhd", 0, 1);
+        output.zeros(8);
+        output.end(vmhd);
+    }
+    let dinf = output.begin(b"dinf");
+    let dref = output.begin_full(b"dref", 0, 0);
+    output.u32(1);
+    // the media is in this file
+    let url = output.begin_full(b"url ", 0, 1);
+    output.end(url);
+    output.end(dref);
+    output.end(dinf);
+
+    let stbl = output.begin(b"stbl");
+    let stsd = output.begin_full(b"stsd", 0, 0);
+    output.u32(1);
+    sample_entry(track, output);
+    output.end(stsd);
+    // fragments hold all the samples, so these tables are empty
+    for kind in [b"stts", b"stsc", b"stco"].iter() {
+        let table = output.begin_full(kind, 0, 0);
+        output.u32(0);
+        output.end(table);
+    }
+    let stsz = output.begin_full(b"stsz", 0, 0);
+    output.u32(0);
+    output.u32(0);
+    output.end(stsz);
+    output.end(stbl);
+
+    output.end(minf);
+    output.end(mdia);
+    output.end(trak);
+}
+
+fn init_segment(tracks: &[Track]) -> Vec<u8> {
+    let mut output = BoxWriter::default();
+
+    let ftyp = output.begin(b"ftyp");
+    output.bytes(b"iso6");
+    output.u32(0);
+    output.bytes(b"iso6cmfcmp41");
+    output.end(ftyp);
+
+    let moov = output.begin(b"moov");
+    let mvhd = output.begin_full(b"mvhd", 0, 0);
+    output.zeros(8);
+    output.u32(MOVIE_TIMESCALE);
+    output.u32(0);
+    // rate & volume
+    output.u32(0x0001_0000);
+    output.u16(0x0100);
+    output.zeros(10);
+    output.matrix();
+    output.zeros(24);
+    output.u32(tracks.len() as u32 + 1);
+    output.end(mvhd);
+
+    for track in tracks {
+        track_box(track, &mut output);
+    }
+
+    let mvex = output.begin(b"mvex");
+    for track in tracks {
+        let trex = output.begin_full(b"trex", 0, 0);
+        output.u32(track.id);
+        // default sample description index, duration, size, & flags
+        output.u32(1);
+        output.zeros(12);
+        output.end(trex);
+    }
+    output.end(mvex);
+    output.end(moov);
+
+    output.buffer
+}
+
+struct Sample<'a> {
+    /// in ms
+    time: u64,
+    keyframe: bool,
+    data: &'a [u8],
+}
+
+/// Converts WebM chunks to fMP4 segments; see the module documentation.
+#[derive(Default)]
+pub struct Fmp4Muxer {
+    tracks: Vec<Track>,
+    sequence_number: u32,
+    /// in ms
+    fragment_duration: u64,
+}
+
+impl Fmp4Muxer {
+    pub fn new() -> Fmp4Muxer {
+        Fmp4Muxer::default()
+    }
+
+    /// The carried tracks' codecs, as given in an RFC 6381 `codecs`
+    /// parameter, e.g. `["vp09.00.31.08", "opus"]`
+    pub fn codecs(&self) -> Vec<String> {
+        self.tracks.iter().map(Track::codec_string).collect()
+    }
+
+    /// The width & height of the first carried video track, if there is one
+    pub fn resolution(&self) -> Option<(u64, u64)> {
+        self.tracks.iter()
+            .find(|track| !track.is_audio() && track.entry.pixel_width > 0 && track.entry.pixel_height > 0)
+            .map(|track| (track.entry.pixel_width, track.entry.pixel_height))
+    }
+
+    /// How much media time the last fragment covered, in ms, going by its
+    /// longest track
+    pub fn fragment_duration(&self) -> u64 {
+        self.fragment_duration
+    }
+
+    /// Transmux a chunk: Headers become an initialization segment, and
+    /// Clusters a fragment. Clusters before the first Headers, or without any
+    /// blocks for the carried tracks, produce nothing. Fails if a stream has
+    /// no tracks MP4 can carry.
+    pub fn process(&mut self, chunk: &Chunk) -> Result<Option<Bytes>, WebmetroError> {
+        match chunk {
+            Chunk::Headers { bytes } => self.start(bytes).map(Some),
+            Chunk::Cluster(head, body) => Ok(self.fragment(head, body)),
+            _ => Ok(None),
+        }
+    }
+
+    fn start(&mut self, headers: &[u8]) -> Result<Bytes, WebmetroError> {
+        let entries = header_tracks(headers);
+
+        self.tracks.clear();
+        for entry in entries {
+            let codec = match entry.codec_id.as_str() {
+                "V_VP8" => Codec::Vp8,
+                "V_VP9" => Codec::Vp9,
+                "V_AV1" => Codec::Av1,
+                "A_OPUS" => Codec::Opus,
+                codec => {
+                    warn!("Track {} has codec {}, which can't be carried in MP4; leaving it out", entry.number, codec);
+                    continue;
+                }
+            };
+            self.tracks.push(Track {
+                number: entry.number,
+                id: self.tracks.len() as u32 + 1,
+                codec,
+                entry,
+                last_duration: DEFAULT_FRAME_DURATION,
+            });
+        }
+        if self.tracks.is_empty() {
+            return Err("Stream has no tracks that can be carried in MP4".into());
+        }
+        Ok(Bytes::from(init_segment(&self.tracks)))
+    }
+
+    fn fragment(&mut self, head: &ClusterHead, body: &[u8]) -> Option<Bytes> {
+        let mut samples: Vec<Vec<Sample>> = self.tracks.iter().map(|_| Vec::new()).collect();
+        for element in parse_webm(body) {
+            if let Some((SimpleBlock { track, timecode, flags, data }, keyframe)) = block_timing(&element) {
+                // lacing isn't worth supporting for the codecs carried
+                if flags & LACING_MASK != 0 {
+                    continue;
+                }
+                if let Some(index) = self.tracks.iter().position(|carried| carried.number == track) {
+                    samples[index].push(Sample {
+                        time: head.block_time(timecode),
+                        keyframe,
+                        data,
+                    });
+                }
+            }
+        }
+        if samples.iter().all(Vec::is_empty) {
+            return None;
+        }
+
+        self.sequence_number += 1;
+        let mut output = BoxWriter::default();
+        let moof = output.begin(b"moof");
+        let mfhd = output.begin_full(b"mfhd", 0, 0);
+        output.u32(self.sequence_number);
+        output.end(mfhd);
+
+        // where each track's data_offset goes, to fill in once the moof's size is known
+        let mut data_offsets = Vec::new();
+        self.fragment_duration = 0;
+        for (track, samples) in self.tracks.iter_mut().zip(samples.iter()) {
+            if samples.is_empty() {
+                continue;
+            }
+            let timescale = track.timescale() as u64;
+            let traf = output.begin(b"traf");
+            // default-base-is-moof
+            let tfhd = output.begin_full(b"tfhd", 0, 0x02_0000);
+            output.u32(track.id);
+            output.end(tfhd);
+            let tfdt = output.begin_full(b"tfdt", 1, 0);
+            output.u64(samples[0].time * timescale / 1000);
+            output.end(tfdt);
+
+            // data offset, and each sample's duration, size, & flags
+            let trun = output.begin_full(b"trun", 0, 0x00_0701);
+            output.u32(samples.len() as u32);
+            data_offsets.push(output.buffer.len());
+            output.u32(0);
+            let mut track_duration = 0;
+            for (index, sample) in samples.iter().enumerate() {
+                let duration = match (track.codec, samples.get(index + 1)) {
+                    (Codec::Opus, _) if opus_packet_samples(sample.data) > 0 => opus_packet_samples(sample.data) as u32,
+                    (_, Some(next)) => ((next.time - sample.time.min(next.time)) * timescale / 1000) as u32,
+                    (_, None) => track.last_duration,
+                };
+                track.last_duration = duration;
+                track_duration += duration as u64;
+                output.u32(duration);
+                output.u32(sample.data.len() as u32);
+                output.u32(if sample.keyframe || track.is_audio() { SYNC_SAMPLE } else { NON_SYNC_SAMPLE });
+            }
+            output.end(trun);
+            output.end(traf);
+            self.fragment_duration = self.fragment_duration.max(track_duration * 1000 / timescale);
+        }
+        output.end(moof);
+
+        let mdat_header = 8;
+        let mut offset = output.buffer.len() - moof + mdat_header;
+        for (position, samples) in data_offsets.into_iter().zip(samples.iter().filter(|samples| !samples.is_empty())) {
+            output.buffer[position..position + 4].copy_from_slice(&(offset as u32).to_be_bytes());
+            offset += samples.iter().map(|sample| sample.data.len()).sum::<usize>();
+        }
+
+        let mdat = output.begin(b"mdat");
+        for sample in samples.iter().flatten() {
+            output.bytes(sample.data);
+        }
+        output.end(mdat);
+        Some(Bytes::from(output.buffer))
+    }
+}
+
+impl Muxer for Fmp4Muxer {
+    fn mux(&mut self, chunk: &Chunk, output: &mut Vec<Bytes>) -> Result<(), WebmetroError> {
+        output.extend(self.process(chunk)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::prelude::*;
+
+    use crate::chunk::{ChunkerOptions, WebmStream};
+    use crate::fmp4::*;
+    use crate::stream_parser::StreamEbml;
+    use crate::tests::TEST_FILE;
+
+    /// Split a buffer into its top-level boxes' types & bodies
+    fn boxes(mut buffer: &[u8]) -> Vec<([u8; 4], &[u8])> {
+        let mut boxes = Vec::new();
+        while buffer.len() >= 8 {
+            let size = u32::from_be_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]) as usize;
+            let mut kind = [0; 4];
+            kind.copy_from_slice(&buffer[4..8]);
+            boxes.push((kind, &buffer[8..size]));
+            buffer = &buffer[size..];
+        }
+        assert!(buffer.is_empty());
+        boxes
+    }
+
+    #[test]
+    fn transmux_vp9() {
+        let chunks: Vec<Chunk> = stream::iter(vec![Ok::<&[u8], WebmetroError>(TEST_FILE)])
+            .parse_ebml()
+            .chunk_webm()
+            .try_collect()
+            .now_or_never()
+            .expect("Test tried to block on I/O")
+            .expect("Parse failed");
+
+        let mut muxer = Fmp4Muxer::new();
+        let init = muxer.process(&chunks[0]).unwrap().unwrap();
+        let init_boxes = boxes(&init);
+        assert_eq!(init_boxes.iter().map(|(kind, _)| kind).collect::<Vec<_>>(), vec![b"ftyp", b"moov"]);
+        let moov = init_boxes[1].1;
+        assert!(moov.windows(4).any(|window| window == b"vp09"));
+        assert!(muxer.codecs()[0].starts_with("vp09."));
+
+        let mut fragments = 0;
+        for chunk in &chunks[1..] {
+            let fragment = match muxer.process(chunk).unwrap() {
+                Some(fragment) => fragment,
+                None => continue,
+            };
+            let fragment_boxes = boxes(&fragment);
+            assert_eq!(fragment_boxes.iter().map(|(kind, _)| kind).collect::<Vec<_>>(), vec![b"moof", b"mdat"]);
+
+            // the samples' sizes add up to the mdat, which starts where the trun says
+            let moof = fragment_boxes[0].1;
+            let trun = moof.windows(4).position(|window| window == b"trun").unwrap() + 4;
+            let count = u32::from_be_bytes([moof[trun + 4], moof[trun + 5], moof[trun + 6], moof[trun + 7]]) as usize;
+            let data_offset = u32::from_be_bytes([moof[trun + 8], moof[trun + 9], moof[trun + 10], moof[trun + 11]]) as usize;
+            assert_eq!(data_offset, moof.len() + 16);
+            let sizes: usize = (0..count)
+                .map(|index| trun + 12 + index * 12 + 4)
+                .map(|at| u32::from_be_bytes([moof[at], moof[at + 1], moof[at + 2], moof[at + 3]]) as usize)
+                .sum();
+            assert_eq!(sizes, fragment_boxes[1].1.len());
+            fragments += 1;
+        }
+        assert!(fragments > 0);
+    }
+
+    /// Each fragment's tfdt base media decode times, transmuxing the test
+    /// file chunked with these options
+    fn decode_times(options: ChunkerOptions) -> Vec<u64> {
+        let chunks: Vec<Chunk> = stream::iter(vec![Ok::<&[u8], WebmetroError>(TEST_FILE)])
+            .parse_ebml()
+            .chunk_webm_with(options)
+            .try_collect()
+            .now_or_never()
+            .expect("Test tried to block on I/O")
+            .expect("Parse failed");
+
+        let mut muxer = Fmp4Muxer::new();
+        muxer.process(&chunks[0]).unwrap().unwrap();
+        let mut times = Vec::new();
+        for chunk in &chunks[1..] {
+            if let Some(fragment) = muxer.process(chunk).unwrap() {
+                let moof = boxes(&fragment)[0].1;
+                let tfdt = moof.windows(4).position(|window| window == b"tfdt").unwrap() + 4;
+                let mut time = [0; 8];
+                // after the version & flags
+                time.copy_from_slice(&moof[tfdt + 4..tfdt + 12]);
+                times.push(u64::from_be_bytes(time));
+            }
+        }
+        times
+    }
+
+    #[test]
+    fn transmux_rescaled() {
+        let times = decode_times(ChunkerOptions::new());
+        // 100µs ticks split some Clusters, but the fragments start at the same times
+        let rescaled = decode_times(ChunkerOptions::new().timestamp_scale(100_000));
+        assert!(times.len() > 1);
+        assert!(times.iter().all(|time| rescaled.contains(time)), "{:?} vs {:?}", times, rescaled);
+    }
+
+    #[test]
+    fn read_opus_head() {
+        let mut head = b"OpusHead".to_vec();
+        head.extend_from_slice(&[1, 2, 0x38, 0x01, 0x80, 0xBB, 0, 0, 0, 0, 0]);
+        let entry = TrackEntry { codec_private: head, channels: 2, ..TrackEntry::default() };
+        let mut output = BoxWriter::default();
+        opus_specific_box(&entry, &mut output);
+        assert_eq!(output.buffer, vec![
+            0, 0, 0, 19, b'd', b'O', b'p', b's',
+            0, 2, 0x01, 0x38, 0, 0, 0xBB, 0x80, 0, 0, 0,
+        ]);
+    }
+}
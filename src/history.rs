@@ -0,0 +1,427 @@
+//! A record of each channel's broadcast sessions: when each source started
+//! & stopped, how much it sent, its largest audience, and the files it was
+//! recorded to. Finished sessions can be kept in a file, one per line as
+//! `/channels/<channel>/history` reports them, so they survive restarts:
+//!
+//! ```json
+//! {"channel":"main","start":1600000000.25,"end":1600000061.5,"duration_ms":61250,"bytes":1048576,"peak_listeners":12,"recordings":["/recordings/main-1600000000.webm"]}
+//! ```
+//!
+//! Like the config store's, the file is rewritten in full when sessions
+//! finish, so it only ever holds the ones kept in memory; that's done on a
+//! thread of its own, so sources finishing aren't held up by it.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fs;
+use std::io::ErrorKind;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::config_store::save_file;
+use crate::error::WebmetroError;
+
+/// How many finished sessions are kept for each channel by default
+pub const DEFAULT_SESSIONS_KEPT: usize = 100;
+
+/// One source's broadcast on a channel
+#[derive(Clone, Debug, PartialEq)]
+pub struct SessionRecord {
+    pub channel: String,
+    pub started: SystemTime,
+    /// `None` while the source is still publishing
+    pub ended: Option<SystemTime>,
+    /// bytes of media received
+    pub bytes: u64,
+    /// the most listeners the channel had at once
+    pub peak_listeners: usize,
+    /// files the channel was recorded to during the session
+    pub recordings: Vec<String>,
+}
+
+fn unix_millis(time: SystemTime) -> u128 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_millis()
+}
+
+/// A session as JSON describes it, with times in Unix seconds
+#[derive(Serialize)]
+struct SessionJson<'a> {
+    channel: &'a str,
+    start: f64,
+    end: Option<f64>,
+    duration_ms: u128,
+    bytes: u64,
+    peak_listeners: usize,
+    recordings: &'a [String],
+}
+
+/// A session as read back from the history file
+#[derive(Deserialize)]
+struct SavedSession {
+    channel: String,
+    start: f64,
+    end: Option<f64>,
+    bytes: u64,
+    peak_listeners: usize,
+    recordings: Vec<String>,
+}
+
+/// A channel's sessions as JSON describes them
+#[derive(Serialize)]
+struct HistoryJson<'a> {
+    channel: &'a str,
+    sessions: Vec<SessionJson<'a>>,
+}
+
+impl SessionRecord {
+    fn new(channel: &str) -> SessionRecord {
+        SessionRecord {
+            channel: channel.to_string(),
+            started: SystemTime::now(),
+            ended: None,
+            bytes: 0,
+            peak_listeners: 0,
+            recordings: Vec::new(),
+        }
+    }
+
+    /// How long the session lasted, or has lasted so far
+    pub fn duration(&self) -> Duration {
+        self.ended.unwrap_or_else(SystemTime::now)
+            .duration_since(self.started)
+            .unwrap_or_default()
+    }
+
+    fn json(&self) -> SessionJson<'_> {
+        let seconds = |time: SystemTime| unix_millis(time) as f64 / 1000.0;
+        SessionJson {
+            channel: &self.channel,
+            start: seconds(self.started),
+            end: self.ended.map(seconds),
+            duration_ms: self.duration().as_millis(),
+            bytes: self.bytes,
+            peak_listeners: self.peak_listeners,
+            recordings: &self.recordings,
+        }
+    }
+
+    /// The session as a one-line JSON object, with times in Unix seconds
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.json()).expect("Serializing a session")
+    }
+}
+
+/// Reads a session as `to_json` writes it
+impl FromStr for SessionRecord {
+    type Err = WebmetroError;
+
+    fn from_str(line: &str) -> Result<SessionRecord, WebmetroError> {
+        let saved: SavedSession = serde_json::from_str(line).map_err(|err| WebmetroError::ApplicationError {
+            message: format!("Invalid session record: {}", err),
+        })?;
+        let time = |seconds: f64| UNIX_EPOCH + Duration::from_millis((seconds * 1000.0).round() as u64);
+        if saved.channel.is_empty() || !saved.start.is_finite() || saved.start < 0.0 {
+            return Err(WebmetroError::ApplicationError {
+                message: "Invalid session record: no channel or start time".into(),
+            });
+        }
+        Ok(SessionRecord {
+            channel: saved.channel,
+            started: time(saved.start),
+            ended: saved.end.filter(|end| end.is_finite() && *end >= 0.0).map(time),
+            bytes: saved.bytes,
+            peak_listeners: saved.peak_listeners,
+            recordings: saved.recordings,
+        })
+    }
+}
+
+type Sessions = Arc<Mutex<HashMap<String, VecDeque<SessionRecord>>>>;
+
+/// Rewrites the history file whenever it's woken, from a thread of its own
+struct Saver {
+    changed: Mutex<Option<mpsc::Sender<()>>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Saver {
+    fn spawn(path: PathBuf, finished: Sessions) -> Result<Saver, WebmetroError> {
+        let (changed, wakeups) = mpsc::channel::<()>();
+        let thread = thread::Builder::new()
+            .name("session-history".into())
+            .spawn(move || {
+                while wakeups.recv().is_ok() {
+                    // sessions that finished meanwhile are saved together
+                    wakeups.try_iter().count();
+                    let text = {
+                        let finished = finished.lock().expect("Locking session history");
+                        let channels: BTreeMap<_, _> = finished.iter().collect();
+                        let mut text = String::new();
+                        for session in channels.values().flat_map(|sessions| sessions.iter()) {
+                            text.push_str(&session.to_json());
+                            text.push('\n');
+                        }
+                        text
+                    };
+                    if let Err(err) = save_file(&path, &text) {
+                        warn!("Couldn't save session history to {}: {}", path.display(), err);
+                    }
+                }
+            })?;
+        Ok(Saver {
+            changed: Mutex::new(Some(changed)),
+            thread: Some(thread),
+        })
+    }
+
+    fn wake(&self) {
+        if let Some(changed) = self.changed.lock().expect("Locking session history saver").as_ref() {
+            // the thread only stops once this is dropped
+            let _ = changed.send(());
+        }
+    }
+}
+
+impl Drop for Saver {
+    fn drop(&mut self) {
+        // hanging up ends the thread's loop, once it's saved the last changes
+        self.changed.get_mut().expect("Locking session history saver").take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Each channel's live session & latest finished ones, optionally appended
+/// to a file as they finish
+pub struct History {
+    keep: usize,
+    next_id: AtomicU64,
+    /// each channel's live session, with the id of the guard that finishes it
+    live: Mutex<HashMap<String, (u64, SessionRecord)>>,
+    finished: Sessions,
+    saver: Option<Saver>,
+}
+
+impl Default for History {
+    fn default() -> History {
+        History::new(DEFAULT_SESSIONS_KEPT)
+    }
+}
+
+impl History {
+    /// Keep the last `keep` finished sessions of each channel in memory only
+    pub fn new(keep: usize) -> History {
+        History {
+            keep,
+            next_id: AtomicU64::new(0),
+            live: Mutex::new(HashMap::new()),
+            finished: Arc::new(Mutex::new(HashMap::new())),
+            saver: None,
+        }
+    }
+
+    /// Load the history file at `path` (if there is one yet), skipping lines
+    /// that can't be read, and save sessions to it as they finish
+    pub fn open(path: impl Into<PathBuf>, keep: usize) -> Result<History, WebmetroError> {
+        let path = path.into();
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(err) if err.kind() == ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let mut finished = HashMap::new();
+        for (number, line) in text.lines().enumerate().filter(|(_, line)| !line.is_empty()) {
+            let session: SessionRecord = match line.parse() {
+                Ok(session) => session,
+                Err(err) => {
+                    warn!("{} line {}: {}, skipping it", path.display(), number + 1, err);
+                    continue;
+                }
+            };
+            let sessions: &mut VecDeque<SessionRecord> = finished.entry(session.channel.clone()).or_default();
+            sessions.push_back(session);
+            if sessions.len() > keep {
+                sessions.pop_front();
+            }
+        }
+        let finished = Arc::new(Mutex::new(finished));
+        Ok(History {
+            saver: Some(Saver::spawn(path, finished.clone())?),
+            finished,
+            ..History::new(keep)
+        })
+    }
+
+    /// Start a channel's session, which finishes when the returned guard is
+    /// dropped, or when another source takes the channel over
+    pub fn start(self: &Arc<Self>, channel: &str) -> LiveSession {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let replaced = self.live.lock().expect("Locking live sessions")
+            .insert(channel.to_string(), (id, SessionRecord::new(channel)));
+        if let Some((_, session)) = replaced {
+            self.finish(session);
+        }
+        LiveSession {
+            history: self.clone(),
+            channel: channel.to_string(),
+            id,
+        }
+    }
+
+    /// Note a file the channel's live session is being recorded to
+    pub fn add_recording(&self, channel: &str, path: &str) {
+        if let Some((_, session)) = self.live.lock().expect("Locking live sessions").get_mut(channel) {
+            session.recordings.push(path.to_string());
+        }
+    }
+
+    /// A channel's sessions, oldest first, ending with the live one if
+    /// there's a source publishing
+    pub fn sessions(&self, channel: &str) -> Vec<SessionRecord> {
+        let mut sessions: Vec<SessionRecord> = self.finished.lock().expect("Locking session history")
+            .get(channel)
+            .map(|sessions| sessions.iter().cloned().collect())
+            .unwrap_or_default();
+        if let Some((_, live)) = self.live.lock().expect("Locking live sessions").get(channel) {
+            sessions.push(live.clone());
+        }
+        sessions
+    }
+
+    fn finish(&self, mut session: SessionRecord) {
+        session.ended = Some(SystemTime::now());
+        {
+            let mut finished = self.finished.lock().expect("Locking session history");
+            let sessions = finished.entry(session.channel.clone()).or_default();
+            sessions.push_back(session);
+            if sessions.len() > self.keep {
+                sessions.pop_front();
+            }
+        }
+        if let Some(ref saver) = self.saver {
+            saver.wake();
+        }
+    }
+}
+
+/// A session in progress, finished when dropped
+pub struct LiveSession {
+    history: Arc<History>,
+    channel: String,
+    id: u64,
+}
+
+impl LiveSession {
+    /// Count media received, and how many listeners there are now
+    pub fn observe(&mut self, bytes: usize, listeners: usize) {
+        let mut live = self.history.live.lock().expect("Locking live sessions");
+        if let Some((_, session)) = live.get_mut(&self.channel).filter(|(id, _)| *id == self.id) {
+            session.bytes += bytes as u64;
+            session.peak_listeners = session.peak_listeners.max(listeners);
+        }
+    }
+}
+
+impl Drop for LiveSession {
+    fn drop(&mut self) {
+        let session = {
+            let mut live = self.history.live.lock().expect("Locking live sessions");
+            match live.get(&self.channel) {
+                Some((id, _)) if *id == self.id => live.remove(&self.channel).map(|(_, session)| session),
+                _ => None,
+            }
+        };
+        if let Some(session) = session {
+            self.history.finish(session);
+        }
+    }
+}
+
+/// A channel's sessions as a JSON object, e.g.
+/// `{"channel":"main","sessions":[{"channel":"main","start":1600000000.25,...}]}`
+pub fn history_json(channel: &str, sessions: &[SessionRecord]) -> String {
+    let history = HistoryJson {
+        channel,
+        sessions: sessions.iter().map(SessionRecord::json).collect(),
+    };
+    serde_json::to_string(&history).expect("Serializing session history")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::process;
+
+    use crate::history::*;
+
+    #[test]
+    fn record_sessions() {
+        let path = temp_dir().join(format!("webmetro-history-{}", process::id()));
+        let _ = fs::remove_file(&path);
+
+        let history = Arc::new(History::open(&path, 2).unwrap());
+        for bytes in 1..=3 {
+            let mut session = history.start("main");
+            history.add_recording("main", &format!("/recordings/main-{}.webm", bytes));
+            session.observe(bytes, bytes);
+            session.observe(bytes, 0);
+        }
+        let replaced = history.start("main");
+        // taking a channel over starts a new session
+        let live = history.start("main");
+        history.add_recording("main", "/recordings/main-live.webm");
+
+        let sessions = history.sessions("main");
+        assert_eq!(sessions.len(), 3);
+        assert_eq!((sessions[0].bytes, sessions[0].peak_listeners), (6, 3));
+        assert_eq!(sessions[0].recordings, vec!["/recordings/main-3.webm".to_string()]);
+        assert_eq!(sessions[1].bytes, 0);
+        assert_eq!(sessions[2].ended, None);
+        assert!(history.sessions("other").is_empty());
+
+        // the file only keeps the sessions kept in memory, and is all
+        // written once the history is dropped
+        drop((replaced, live, history));
+        let text = fs::read_to_string(&path).unwrap();
+        assert_eq!(text.lines().count(), 2);
+
+        // a restart keeps the finished sessions, to the millisecond, past
+        // lines that can't be read
+        fs::write(&path, format!("not a session\n{}", text)).unwrap();
+        let reopened = History::open(&path, 2).unwrap();
+        let kept = reopened.sessions("main");
+        let millis = |time: Option<SystemTime>| time.map(unix_millis);
+        assert_eq!(kept.len(), 2);
+        assert_eq!(millis(Some(kept[0].started)), millis(Some(sessions[1].started)));
+        assert_eq!(millis(kept[0].ended), millis(sessions[1].ended));
+        assert_eq!(kept[1].recordings, sessions[2].recordings);
+        drop(reopened);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn format_sessions() {
+        let session = SessionRecord {
+            channel: "main".into(),
+            started: UNIX_EPOCH + Duration::from_millis(1_600_000_000_250),
+            ended: Some(UNIX_EPOCH + Duration::from_millis(1_600_000_061_500)),
+            bytes: 1048576,
+            peak_listeners: 12,
+            recordings: vec!["/recordings/main-1600000000.webm".into()],
+        };
+        assert_eq!(session.to_json().parse::<SessionRecord>().unwrap(), session);
+        assert!("main\tyesterday".parse::<SessionRecord>().is_err());
+        assert!(r#"{"channel":"","start":1,"end":null,"bytes":0,"peak_listeners":0,"recordings":[]}"#.parse::<SessionRecord>().is_err());
+        assert_eq!(history_json("main", &[session]), concat!(
+            r#"{"channel":"main","sessions":[{"channel":"main","start":1600000000.25,"end":1600000061.5,"#,
+            r#""duration_ms":61250,"bytes":1048576,"peak_listeners":12,"recordings":["/recordings/main-1600000000.webm"]}]}"#,
+        ));
+    }
+}
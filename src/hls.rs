@@ -0,0 +1,488 @@
+//! Low-latency HLS packaging, built on the fMP4 transmuxer.
+//!
+//! An `HlsPackager` turns a channel's chunks into a rolling window of media
+//! segments, each made of partial segments: one per Cluster, published as
+//! soon as the Cluster arrives. Splitting Clusters at ingest (see
+//! `RelayOptions::max_cluster_duration`) keeps parts short, so players can
+//! stay a second or so behind the live edge instead of several segments.
+//!
+//! Alongside each init segment, the playlist lists:
+//!
+//! * `<msn>.m4s`, a complete segment (its parts, back to back)
+//! * `<msn>.<part>.m4s`, one part of a segment
+//! * a preload hint for the part that hasn't arrived yet
+//!
+//! Clients doing blocking playlist reloads (`_HLS_msn` & `_HLS_part`) wait
+//! for the part they asked for with `is_ready` & `changed`.
+//!
+//! A simulcast channel's renditions can be offered together in a master
+//! playlist (see `master_playlist`), so players switch between them as
+//! their bandwidth allows.
+
+use std::collections::VecDeque;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use bytes::{Bytes, BytesMut};
+use futures::{
+    channel::oneshot,
+    prelude::*,
+};
+
+use crate::chunk::Chunk;
+use crate::error::WebmetroError;
+use crate::fmp4::Fmp4Muxer;
+
+/// How many of the most recent segments have their parts listed
+const PART_SEGMENTS: usize = 3;
+
+/// How a channel is split into segments & parts
+#[derive(Clone, Debug, PartialEq)]
+pub struct HlsOptions {
+    /// start a new segment at the first keyframe after this many ms
+    pub segment_duration: u64,
+    /// the duration parts are expected to keep to, in ms
+    pub part_duration: u64,
+    /// how many complete segments the playlist keeps
+    pub window: usize,
+}
+
+impl Default for HlsOptions {
+    fn default() -> HlsOptions {
+        HlsOptions {
+            segment_duration: 2000,
+            part_duration: 500,
+            window: 6,
+        }
+    }
+}
+
+struct Part {
+    data: Bytes,
+    /// in ms
+    duration: u64,
+    independent: bool,
+}
+
+struct Segment {
+    sequence: u64,
+    /// which init segment it follows
+    init: u64,
+    /// whether it follows a new init segment, rather than the last segment
+    discontinuity: bool,
+    parts: Vec<Part>,
+    complete: bool,
+}
+
+impl Segment {
+    fn duration(&self) -> u64 {
+        self.parts.iter().map(|part| part.duration).sum()
+    }
+}
+
+struct State {
+    muxer: Fmp4Muxer,
+    /// each init segment still referred to, by number
+    inits: VecDeque<(u64, Bytes)>,
+    next_init: u64,
+    segments: VecDeque<Segment>,
+    next_sequence: u64,
+    /// how many discontinuities have scrolled out of the window
+    discontinuity_sequence: u64,
+    /// whether the next segment follows a new init segment
+    discontinuity: bool,
+    waiters: Vec<oneshot::Sender<()>>,
+    last_request: Instant,
+}
+
+/// Packages one channel for HLS; see the module documentation.
+pub struct HlsPackager {
+    options: HlsOptions,
+    state: Mutex<State>,
+}
+
+impl HlsPackager {
+    pub fn new(options: HlsOptions) -> HlsPackager {
+        HlsPackager {
+            options,
+            state: Mutex::new(State {
+                muxer: Fmp4Muxer::new(),
+                inits: VecDeque::new(),
+                next_init: 0,
+                segments: VecDeque::new(),
+                next_sequence: 0,
+                discontinuity_sequence: 0,
+                discontinuity: false,
+                waiters: Vec::new(),
+                last_request: Instant::now(),
+            }),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<State> {
+        self.state.lock().expect("Locking HLS packager")
+    }
+
+    /// Add a chunk from the channel: Headers start a new init segment (and
+    /// a discontinuity), and Clusters become parts, starting a new segment
+    /// at the first keyframe once the current one is long enough. A Cluster
+    /// marked as a discontinuity ends the current segment, and the next one
+    /// is listed after an `EXT-X-DISCONTINUITY` tag.
+    pub fn push(&self, chunk: &Chunk) {
+        let mut state = self.state();
+        let state = &mut *state;
+        match (chunk, state.muxer.process(chunk)) {
+            (Chunk::Headers { .. }, Ok(Some(init))) => {
+                if let Some(open) = state.segments.back_mut() {
+                    open.complete = true;
+                }
+                state.discontinuity = !state.segments.is_empty();
+                state.inits.push_back((state.next_init, init));
+                state.next_init += 1;
+            },
+            (Chunk::Cluster(head, _), Ok(Some(data))) => {
+                // a jump in the timeline ends the segment, as new headers do
+                let mut ended = false;
+                if head.discontinuity && !state.segments.is_empty() {
+                    if let Some(open) = state.segments.back_mut().filter(|segment| !segment.complete) {
+                        open.complete = true;
+                        ended = true;
+                    }
+                    state.discontinuity = true;
+                }
+                let independent = head.keyframe;
+                let part = Part {
+                    data,
+                    duration: state.muxer.fragment_duration(),
+                    independent,
+                };
+                let open = state.segments.back().filter(|segment| !segment.complete);
+                let segment_done = open.map_or(true, |open| open.duration() >= self.options.segment_duration);
+                if independent && segment_done {
+                    if let Some(open) = state.segments.back_mut() {
+                        open.complete = true;
+                    }
+                    state.segments.push_back(Segment {
+                        sequence: state.next_sequence,
+                        init: state.next_init - 1,
+                        discontinuity: state.discontinuity,
+                        parts: vec![part],
+                        complete: false,
+                    });
+                    state.next_sequence += 1;
+                    state.discontinuity = false;
+                    self.trim(state);
+                } else if let Some(open) = state.segments.back_mut().filter(|segment| !segment.complete) {
+                    open.parts.push(part);
+                } else if !ended {
+                    // a segment has to start with a keyframe, so the part is
+                    // dropped; the playlist only changed if a segment just ended
+                    return;
+                }
+            },
+            (_, Ok(_)) => return,
+            (_, Err(err)) => {
+                warn!("Can't package stream for HLS: {}", err);
+                return;
+            }
+        }
+
+        for waiter in state.waiters.drain(..) {
+            waiter.send(()).ok();
+        }
+    }
+
+    /// Forget segments (and init segments) that have scrolled out of the window
+    fn trim(&self, state: &mut State) {
+        while state.segments.iter().filter(|segment| segment.complete).count() > self.options.window {
+            state.segments.pop_front();
+            if state.segments.front().map_or(false, |segment| segment.discontinuity) {
+                state.discontinuity_sequence += 1;
+            }
+        }
+        if let Some(oldest) = state.segments.front().map(|segment| segment.init) {
+            while state.inits.front().map_or(false, |(init, _)| *init < oldest) {
+                state.inits.pop_front();
+            }
+        }
+    }
+
+    /// Note that a client asked for something, for `idle_time`
+    pub fn touch(&self) {
+        self.state().last_request = Instant::now();
+    }
+
+    /// How long since a client last asked for something
+    pub fn idle_time(&self) -> Duration {
+        self.state().last_request.elapsed()
+    }
+
+    /// Whether the segment numbered `sequence` is complete, or with a part
+    /// number, has that part; true of segments that have already expired.
+    /// Also true if a segment completes without ever reaching that part.
+    pub fn is_ready(&self, sequence: u64, part: Option<usize>) -> bool {
+        let state = self.state();
+        if state.segments.front().map_or(false, |oldest| sequence < oldest.sequence) {
+            return true;
+        }
+        match state.segments.iter().find(|segment| segment.sequence == sequence) {
+            Some(segment) => segment.complete || part.map_or(false, |part| part < segment.parts.len()),
+            None => false,
+        }
+    }
+
+    /// Resolves once there's a new part or init segment, or the packager is dropped
+    pub fn changed(&self) -> impl Future<Output = ()> + Send {
+        let (waiter, changed) = oneshot::channel();
+        self.state().waiters.push(waiter);
+        changed.map(|_| ())
+    }
+
+    /// The init segment with this number, if it's still referred to
+    pub fn init_segment(&self, number: u64) -> Option<Bytes> {
+        self.state().inits.iter()
+            .find(|(init, _)| *init == number)
+            .map(|(_, data)| data.clone())
+    }
+
+    /// A complete segment, as one fMP4 fragment per part
+    pub fn segment(&self, sequence: u64) -> Option<Bytes> {
+        let state = self.state();
+        let segment = state.segments.iter().find(|segment| segment.sequence == sequence && segment.complete)?;
+        let mut data = BytesMut::with_capacity(segment.parts.iter().map(|part| part.data.len()).sum());
+        for part in &segment.parts {
+            data.extend_from_slice(&part.data);
+        }
+        Some(data.freeze())
+    }
+
+    pub fn part(&self, sequence: u64, part: usize) -> Option<Bytes> {
+        let state = self.state();
+        let segment = state.segments.iter().find(|segment| segment.sequence == sequence)?;
+        segment.parts.get(part).map(|part| part.data.clone())
+    }
+
+    /// The media playlist, or `None` until the first segment has started
+    pub fn playlist(&self) -> Option<String> {
+        let state = self.state();
+        let first = state.segments.front()?;
+
+        let longest_segment = state.segments.iter()
+            .filter(|segment| segment.complete)
+            .map(Segment::duration)
+            .fold(self.options.segment_duration, u64::max);
+        let longest_part = state.segments.iter()
+            .flat_map(|segment| segment.parts.iter())
+            .map(|part| part.duration)
+            .fold(self.options.part_duration, u64::max);
+
+        let mut playlist = String::new();
+        writeln!(playlist, "#EXTM3U").unwrap();
+        writeln!(playlist, "#EXT-X-VERSION:9").unwrap();
+        writeln!(playlist, "#EXT-X-TARGETDURATION:{}", (longest_segment + 999) / 1000).unwrap();
+        writeln!(playlist, "#EXT-X-SERVER-CONTROL:CAN-BLOCK-RELOAD=YES,PART-HOLD-BACK={}", seconds(longest_part * 3)).unwrap();
+        writeln!(playlist, "#EXT-X-PART-INF:PART-TARGET={}", seconds(longest_part)).unwrap();
+        writeln!(playlist, "#EXT-X-MEDIA-SEQUENCE:{}", first.sequence).unwrap();
+        writeln!(playlist, "#EXT-X-DISCONTINUITY-SEQUENCE:{}", state.discontinuity_sequence).unwrap();
+        writeln!(playlist, "#EXT-X-INDEPENDENT-SEGMENTS").unwrap();
+
+        let listed_parts = state.segments.len().saturating_sub(PART_SEGMENTS);
+        let mut init = None;
+        for (index, segment) in state.segments.iter().enumerate() {
+            if segment.discontinuity && index > 0 {
+                writeln!(playlist, "#EXT-X-DISCONTINUITY").unwrap();
+            }
+            if init != Some(segment.init) {
+                writeln!(playlist, "#EXT-X-MAP:URI=\"init-{}.mp4\"", segment.init).unwrap();
+                init = Some(segment.init);
+            }
+            if index >= listed_parts {
+                for (number, part) in segment.parts.iter().enumerate() {
+                    write!(playlist, "#EXT-X-PART:DURATION={},URI=\"{}.{}.m4s\"", seconds(part.duration), segment.sequence, number).unwrap();
+                    if part.independent {
+                        write!(playlist, ",INDEPENDENT=YES").unwrap();
+                    }
+                    writeln!(playlist).unwrap();
+                }
+            }
+            if segment.complete {
+                writeln!(playlist, "#EXTINF:{},", seconds(segment.duration())).unwrap();
+                writeln!(playlist, "{}.m4s", segment.sequence).unwrap();
+            }
+        }
+
+        let (sequence, part) = match state.segments.back() {
+            Some(open) if !open.complete => (open.sequence, open.parts.len()),
+            _ => (state.next_sequence, 0),
+        };
+        writeln!(playlist, "#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\"{}.{}.m4s\"", sequence, part).unwrap();
+        Some(playlist)
+    }
+}
+
+/// One rendition offered by a master playlist
+#[derive(Clone, Debug, PartialEq)]
+pub struct Variant {
+    /// the rendition's media playlist, relative to the master playlist
+    pub uri: String,
+    /// the peak bits per second
+    pub bandwidth: u64,
+    /// the video's width & height, if it has video
+    pub resolution: Option<(u64, u64)>,
+    /// the codecs of the tracks carried in MP4, as in an RFC 6381 `codecs` parameter
+    pub codecs: Vec<String>,
+}
+
+impl Variant {
+    /// Describe a rendition from its WebM headers & its source's bitrate.
+    /// The bitrate is only measured over a second or so, so a quarter more
+    /// is allowed for peaks. Fails if it has no tracks MP4 can carry.
+    pub fn from_headers(uri: String, headers: Bytes, bits_per_second: u64) -> Result<Variant, WebmetroError> {
+        let mut muxer = Fmp4Muxer::new();
+        muxer.process(&Chunk::Headers { bytes: headers })?;
+        Ok(Variant {
+            uri,
+            bandwidth: bits_per_second + bits_per_second / 4,
+            resolution: muxer.resolution(),
+            codecs: muxer.codecs(),
+        })
+    }
+}
+
+/// A master playlist offering each variant, in the order given; players
+/// usually start with the first
+pub fn master_playlist(variants: &[Variant]) -> String {
+    let mut playlist = String::new();
+    writeln!(playlist, "#EXTM3U").unwrap();
+    writeln!(playlist, "#EXT-X-VERSION:9").unwrap();
+    writeln!(playlist, "#EXT-X-INDEPENDENT-SEGMENTS").unwrap();
+    for variant in variants {
+        write!(playlist, "#EXT-X-STREAM-INF:BANDWIDTH={},CODECS=\"{}\"", variant.bandwidth, variant.codecs.join(",")).unwrap();
+        if let Some((width, height)) = variant.resolution {
+            write!(playlist, ",RESOLUTION={}x{}", width, height).unwrap();
+        }
+        writeln!(playlist).unwrap();
+        writeln!(playlist, "{}", variant.uri).unwrap();
+    }
+    playlist
+}
+
+/// ms as decimal seconds, e.g. "0.500"
+fn seconds(ms: u64) -> String {
+    format!("{}.{:03}", ms / 1000, ms % 1000)
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, stream};
+
+    use crate::chunk::{ChunkerOptions, WebmStream};
+    use crate::error::WebmetroError;
+    use crate::fixers::GapDetector;
+    use crate::hls::*;
+    use crate::stream_parser::StreamEbml;
+    use crate::tests::TEST_FILE;
+
+    fn chunks(options: ChunkerOptions) -> Vec<Chunk> {
+        stream::iter(vec![Ok::<&[u8], WebmetroError>(TEST_FILE)])
+            .parse_ebml()
+            .chunk_webm_with(options)
+            .try_collect()
+            .now_or_never()
+            .expect("Test tried to block on I/O")
+            .expect("Parse failed")
+    }
+
+    #[test]
+    fn publish_parts() {
+        let packager = HlsPackager::new(HlsOptions { segment_duration: 0, part_duration: 100, window: 2 });
+        assert_eq!(packager.playlist(), None);
+        assert!(!packager.is_ready(0, Some(0)));
+
+        let chunks = chunks(ChunkerOptions::new().max_cluster_duration(100));
+        let changed = packager.changed();
+        for chunk in &chunks {
+            packager.push(chunk);
+        }
+        block_on(changed);
+
+        let playlist = packager.playlist().unwrap();
+        assert!(playlist.starts_with("#EXTM3U\n"));
+        assert!(playlist.contains("#EXT-X-MAP:URI=\"init-0.mp4\"\n"));
+        assert!(playlist.contains("#EXT-X-PART:DURATION="));
+        assert!(playlist.contains(",INDEPENDENT=YES\n"));
+        assert!(playlist.contains("#EXT-X-PRELOAD-HINT:TYPE=PART,URI=\""));
+        assert!(packager.init_segment(0).is_some());
+        assert!(packager.part(0, 0).is_some());
+        assert!(packager.is_ready(0, Some(0)));
+    }
+
+    #[test]
+    fn start_segments_at_keyframes() {
+        let packager = HlsPackager::new(HlsOptions { segment_duration: 0, part_duration: 100, window: 6 });
+        for chunk in &chunks(ChunkerOptions::new()) {
+            packager.push(chunk);
+        }
+        let state = packager.state();
+        assert!(!state.segments.is_empty());
+        for segment in &state.segments {
+            assert!(segment.parts[0].independent);
+        }
+        // each new Headers starts a new segment, but there's only one here
+        assert_eq!(state.inits.len(), 1);
+    }
+
+    #[test]
+    fn mark_discontinuities() {
+        let packager = HlsPackager::new(HlsOptions::default());
+        let chunks = chunks(ChunkerOptions::new());
+        for chunk in chunks.iter().chain(chunks.iter()) {
+            packager.push(chunk);
+        }
+        let playlist = packager.playlist().unwrap();
+        assert!(playlist.contains("#EXT-X-DISCONTINUITY\n#EXT-X-MAP:URI=\"init-1.mp4\"\n"));
+        assert!(packager.segment(0).is_some());
+    }
+
+    #[test]
+    fn mark_gaps() {
+        let packager = HlsPackager::new(HlsOptions { window: 100, ..HlsOptions::default() });
+        let mut gap_detector = GapDetector::new(1000);
+        let chunks = chunks(ChunkerOptions::new());
+        let resumed = chunks.iter().skip(1).cloned().map(|mut chunk| {
+            if let Chunk::Cluster(ref mut head, _) = chunk {
+                head.update_timecode(head.start + 60_000);
+            }
+            chunk
+        });
+        for chunk in chunks.iter().cloned().chain(resumed) {
+            packager.push(&gap_detector.process(chunk));
+        }
+        let playlist = packager.playlist().unwrap();
+        assert_eq!(playlist.matches("#EXT-X-DISCONTINUITY\n").count(), 1);
+        assert!(!playlist.contains("init-1.mp4"));
+    }
+
+    #[test]
+    fn offer_variants() {
+        let headers = match &chunks(ChunkerOptions::new())[0] {
+            Chunk::Headers { bytes } => bytes.clone(),
+            _ => panic!("Stream didn't start with headers"),
+        };
+        let variant = Variant::from_headers("720p/hls/index.m3u8".into(), headers, 2_000_000).unwrap();
+        assert_eq!(variant.bandwidth, 2_500_000);
+        assert!(!variant.codecs.is_empty());
+
+        let low = Variant {
+            uri: "360p/hls/index.m3u8".into(),
+            bandwidth: 800_000,
+            resolution: Some((640, 360)),
+            codecs: vec!["vp09.00.31.08".into(), "opus".into()],
+        };
+        let playlist = master_playlist(&[variant, low]);
+        assert!(playlist.starts_with("#EXTM3U\n"));
+        assert!(playlist.contains("#EXT-X-STREAM-INF:BANDWIDTH=2500000,CODECS=\""));
+        assert!(playlist.ends_with("#EXT-X-STREAM-INF:BANDWIDTH=800000,CODECS=\"vp09.00.31.08,opus\",RESOLUTION=640x360\n360p/hls/index.m3u8\n"));
+    }
+}
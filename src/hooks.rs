@@ -0,0 +1,378 @@
+//! Commands run when things happen to channels: a source starting or
+//! stopping, or a recording file being finished. Each is given as
+//! `<trigger>=<command>`, and run directly rather than by a shell (see
+//! `command_words`), with the details in its environment:
+//!
+//! * `WEBMETRO_EVENT`, the trigger, e.g. `publish-start`
+//! * `WEBMETRO_CHANNEL`, the channel's name
+//! * `WEBMETRO_TIME`, when it happened, in seconds since the Unix epoch
+//! * `WEBMETRO_FILE`, the finished file, for `recording-finished`
+//! * `WEBMETRO_TITLE`, `WEBMETRO_DESCRIPTION`, and `WEBMETRO_TAGS` (joined
+//!   with commas), for `publish-start` & `publish-stop`, from whatever
+//!   metadata the channel's publisher gave
+//!
+//! Commands run in the background, a few at a time, and are killed if they
+//! take too long; failures are only logged.
+
+use std::fmt;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use futures::{
+    channel::mpsc::{unbounded, UnboundedSender},
+    prelude::*,
+};
+use tokio::{process::Command, time::timeout};
+
+use crate::error::WebmetroError;
+use crate::events::Event;
+use crate::metadata::ChannelMetadata;
+
+/// What a hook runs on
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HookTrigger {
+    PublishStart,
+    PublishStop,
+    RecordingFinished,
+}
+
+impl fmt::Display for HookTrigger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            HookTrigger::PublishStart => "publish-start",
+            HookTrigger::PublishStop => "publish-stop",
+            HookTrigger::RecordingFinished => "recording-finished",
+        })
+    }
+}
+
+impl FromStr for HookTrigger {
+    type Err = WebmetroError;
+
+    fn from_str(trigger: &str) -> Result<HookTrigger, WebmetroError> {
+        match trigger {
+            "publish-start" => Ok(HookTrigger::PublishStart),
+            "publish-stop" => Ok(HookTrigger::PublishStop),
+            "recording-finished" => Ok(HookTrigger::RecordingFinished),
+            _ => Err(WebmetroError::ApplicationError {
+                message: format!("Unknown hook trigger \"{}\" (expected publish-start, publish-stop, or recording-finished)", trigger),
+            })
+        }
+    }
+}
+
+/// A command to run on a trigger
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hook {
+    pub trigger: HookTrigger,
+    pub command: String,
+}
+
+/// Reads a hook as `<trigger>=<command>`
+impl FromStr for Hook {
+    type Err = WebmetroError;
+
+    fn from_str(spec: &str) -> Result<Hook, WebmetroError> {
+        let mut parts = spec.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(trigger), Some(command)) if !command.trim().is_empty() => {
+                command_words(command, &[])?;
+                Ok(Hook {
+                    trigger: trigger.parse()?,
+                    command: command.to_string(),
+                })
+            },
+            _ => Err(WebmetroError::ApplicationError {
+                message: format!("Hook {} should look like trigger=command", spec),
+            })
+        }
+    }
+}
+
+/// Something that happened, which hooks are told about
+#[derive(Clone, Debug, PartialEq)]
+pub struct HookEvent {
+    pub trigger: HookTrigger,
+    pub channel: String,
+    /// the file a recording was finished in
+    pub file: Option<PathBuf>,
+    /// what the channel's publisher said about it
+    pub metadata: Option<ChannelMetadata>,
+    pub time: SystemTime,
+}
+
+impl HookEvent {
+    pub fn new(trigger: HookTrigger, channel: &str) -> HookEvent {
+        HookEvent {
+            trigger,
+            channel: channel.to_string(),
+            file: None,
+            metadata: None,
+            time: SystemTime::now(),
+        }
+    }
+
+    pub fn recording_finished(channel: &str, file: PathBuf) -> HookEvent {
+        HookEvent {
+            file: Some(file),
+            ..HookEvent::new(HookTrigger::RecordingFinished, channel)
+        }
+    }
+
+    pub fn with_metadata(self, metadata: Option<ChannelMetadata>) -> HookEvent {
+        HookEvent { metadata, ..self }
+    }
+
+    /// The hooks' triggers for a channel event, if any
+    pub fn from_event(event: &Event) -> Option<HookEvent> {
+        match event {
+            Event::PublishStart { channel } => Some(HookEvent::new(HookTrigger::PublishStart, channel)),
+            Event::PublishStop { channel } => Some(HookEvent::new(HookTrigger::PublishStop, channel)),
+            _ => None,
+        }
+    }
+
+    /// The environment variables describing it to a command
+    pub fn environment(&self) -> Vec<(&'static str, String)> {
+        let time = self.time.duration_since(UNIX_EPOCH).map(|time| time.as_secs()).unwrap_or(0);
+        let mut environment = vec![
+            ("WEBMETRO_EVENT", self.trigger.to_string()),
+            ("WEBMETRO_CHANNEL", self.channel.clone()),
+            ("WEBMETRO_TIME", time.to_string()),
+        ];
+        if let Some(ref file) = self.file {
+            environment.push(("WEBMETRO_FILE", file.display().to_string()));
+        }
+        if let Some(ref metadata) = self.metadata {
+            environment.push(("WEBMETRO_TITLE", metadata.title.clone().unwrap_or_default()));
+            environment.push(("WEBMETRO_DESCRIPTION", metadata.description.clone().unwrap_or_default()));
+            environment.push(("WEBMETRO_TAGS", metadata.tags.join(",")));
+        }
+        environment
+    }
+}
+
+/// How hooks are run
+#[derive(Clone, Debug, PartialEq)]
+pub struct HookOptions {
+    /// the most commands running at once; more wait their turn
+    pub max_running: usize,
+    /// how long a command may run before it's killed
+    pub timeout: Duration,
+}
+
+impl Default for HookOptions {
+    fn default() -> HookOptions {
+        HookOptions {
+            max_running: 4,
+            timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// A handle for running hooks; see `hook_runner`
+#[derive(Clone)]
+pub struct HookQueue {
+    hooks: Arc<Vec<Hook>>,
+    sender: UnboundedSender<(String, Vec<(&'static str, String)>)>,
+}
+
+impl HookQueue {
+    /// Queue up the commands hooked to this event
+    pub fn run(&self, event: &HookEvent) {
+        for hook in self.hooks.iter().filter(|hook| hook.trigger == event.trigger) {
+            self.sender.unbounded_send((hook.command.clone(), event.environment())).ok();
+        }
+    }
+
+    /// Run hooks for the publishing events in a stream (e.g. from
+    /// `Relay::subscribe`), until it ends, describing each channel with
+    /// what `metadata` finds for it (e.g. `Relay::metadata`)
+    pub fn follow(&self, events: impl Stream<Item = Event>, metadata: impl Fn(&str) -> Option<ChannelMetadata>) -> impl Future<Output = ()> {
+        let queue = self.clone();
+        events.for_each(move |event| {
+            if let Some(event) = HookEvent::from_event(&event) {
+                let described = metadata(&event.channel);
+                queue.run(&event.with_metadata(described));
+            }
+            future::ready(())
+        })
+    }
+}
+
+/// Split a command into its program & arguments as a shell would, without
+/// running one: words are separated by whitespace, quoted with `''` (taken
+/// literally) or `""`, or escaped with `\`, and `$NAME` or `${NAME}` outside
+/// single quotes is replaced with the variable's value from `environment`,
+/// or else the relay's own. Pipes, redirections & the like aren't supported;
+/// a command that needs them can run `sh -c` itself.
+pub fn command_words(command: &str, environment: &[(&str, String)]) -> Result<Vec<String>, WebmetroError> {
+    let invalid = |problem: &str| WebmetroError::ApplicationError {
+        message: format!("Command \"{}\" {}", command, problem),
+    };
+    let mut words = Vec::new();
+    let mut word: Option<String> = None;
+    let mut quote = None;
+    let mut chars = command.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (None, '\'') | (None, '"') => {
+                quote = Some(c);
+                word.get_or_insert_with(String::new);
+            },
+            (Some(open), c) if c == open => quote = None,
+            (Some('\''), c) => word.get_or_insert_with(String::new).push(c),
+            (_, '\\') => {
+                let escaped = chars.next().ok_or_else(|| invalid("ends with a \\"))?;
+                let word = word.get_or_insert_with(String::new);
+                // within double quotes, only these are escaped
+                if quote.is_some() && !matches!(escaped, '"' | '\\' | '$') {
+                    word.push('\\');
+                }
+                word.push(escaped);
+            },
+            (_, '$') => {
+                let mut name = String::new();
+                if chars.peek() == Some(&'{') {
+                    chars.next();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) if c.is_ascii_alphanumeric() || c == '_' => name.push(c),
+                            _ => return Err(invalid("has a bad ${...} variable")),
+                        }
+                    }
+                } else {
+                    while let Some(&c) = chars.peek().filter(|c| c.is_ascii_alphanumeric() || **c == '_') {
+                        name.push(c);
+                        chars.next();
+                    }
+                }
+                let word = word.get_or_insert_with(String::new);
+                if name.is_empty() {
+                    word.push('$');
+                } else if let Some((_, value)) = environment.iter().find(|(variable, _)| *variable == name) {
+                    word.push_str(value);
+                } else {
+                    word.push_str(&std::env::var(&name).unwrap_or_default());
+                }
+            },
+            (_, c) => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    if quote.is_some() {
+        return Err(invalid("has an unterminated quote"));
+    }
+    words.extend(word);
+    if words.is_empty() {
+        return Err(invalid("is empty"));
+    }
+    Ok(words)
+}
+
+/// Start running hooks: returns a queue to send events to, and the task that
+/// runs their commands, which ends once every copy of the queue is dropped
+pub fn hook_runner(hooks: Vec<Hook>, options: HookOptions) -> (HookQueue, impl Future<Output = ()>) {
+    let (sender, receiver) = unbounded();
+    let queue = HookQueue {
+        hooks: Arc::new(hooks),
+        sender,
+    };
+    let HookOptions { max_running, timeout: limit } = options;
+    let task = receiver.for_each_concurrent(max_running.max(1), move |(command, environment): (String, Vec<(&str, String)>)| async move {
+        let words = match command_words(&command, &environment) {
+            Ok(words) => words,
+            Err(err) => {
+                warn!("Couldn't run hook: {}", err);
+                return;
+            },
+        };
+        let mut process = Command::new(&words[0]);
+        process.args(&words[1..])
+            .envs(environment)
+            .stdin(Stdio::null())
+            .kill_on_drop(true);
+        match timeout(limit, process.status()).await {
+            Ok(Ok(status)) if status.success() => debug!("Hook \"{}\" finished", command),
+            Ok(Ok(status)) => warn!("Hook \"{}\" failed: {}", command, status),
+            Ok(Err(err)) => warn!("Couldn't run hook \"{}\": {}", command, err),
+            Err(_) => warn!("Hook \"{}\" took over {}s, killed it", command, limit.as_secs()),
+        }
+    });
+    (queue, task)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::temp_dir;
+    use std::fs;
+    use std::process;
+
+    use crate::hooks::*;
+
+    #[test]
+    fn parse_hooks() {
+        let hook: Hook = "publish-start=notify-send \"$WEBMETRO_CHANNEL is live\"".parse().unwrap();
+        assert_eq!(hook.trigger, HookTrigger::PublishStart);
+        assert_eq!(hook.command, "notify-send \"$WEBMETRO_CHANNEL is live\"");
+        assert_eq!("recording-finished=a=b".parse::<Hook>().unwrap().command, "a=b");
+        assert!("publish-start=".parse::<Hook>().is_err());
+        assert!("publish-start".parse::<Hook>().is_err());
+        assert!("publish-pause=true".parse::<Hook>().is_err());
+        assert!("publish-start=echo 'unterminated".parse::<Hook>().is_err());
+    }
+
+    #[test]
+    fn split_commands() {
+        let environment = [("WEBMETRO_CHANNEL", "main; rm -rf /".to_string()), ("WEBMETRO_FILE", "a b.webm".to_string())];
+        let words = |command| command_words(command, &environment).unwrap();
+        assert_eq!(words("notify-send \"$WEBMETRO_CHANNEL is live\""), vec!["notify-send", "main; rm -rf / is live"]);
+        // variables are only ever part of one argument, quoted or not
+        assert_eq!(words("ffmpeg -i $WEBMETRO_FILE ${WEBMETRO_FILE}.mp4"), vec!["ffmpeg", "-i", "a b.webm", "a b.webm.mp4"]);
+        assert_eq!(words(r#"sh -c 'echo "$WEBMETRO_CHANNEL" > out' \$HOME "a\"b\c" ''"#), vec!["sh", "-c", r#"echo "$WEBMETRO_CHANNEL" > out"#, "$HOME", r#"a"b\c"#, ""]);
+        assert!(command_words("echo ${WEBMETRO_FILE%.webm}", &environment).is_err());
+        assert!(command_words("echo \\", &environment).is_err());
+        assert!(command_words("  ", &environment).is_err());
+    }
+
+    #[test]
+    fn describe_events() {
+        let event = HookEvent::recording_finished("main", PathBuf::from("/recordings/main-1.webm"));
+        let environment = event.environment();
+        assert!(environment.contains(&("WEBMETRO_EVENT", "recording-finished".to_string())));
+        assert!(environment.contains(&("WEBMETRO_CHANNEL", "main".to_string())));
+        assert!(environment.contains(&("WEBMETRO_FILE", "/recordings/main-1.webm".to_string())));
+        assert_eq!(HookEvent::from_event(&Event::PublishStop { channel: "main".into() }).unwrap().trigger, HookTrigger::PublishStop);
+        assert_eq!(HookEvent::from_event(&Event::Listeners { channel: "main".into(), count: 1 }), None);
+
+        let metadata = ChannelMetadata { title: Some("Launch".into()), tags: vec!["space".into(), "live".into()], ..ChannelMetadata::default() };
+        let event = HookEvent::new(HookTrigger::PublishStart, "main").with_metadata(Some(metadata));
+        let environment = event.environment();
+        assert!(environment.contains(&("WEBMETRO_TITLE", "Launch".to_string())));
+        assert!(environment.contains(&("WEBMETRO_DESCRIPTION", String::new())));
+        assert!(environment.contains(&("WEBMETRO_TAGS", "space,live".to_string())));
+    }
+
+    #[test]
+    fn run_commands() {
+        let path = temp_dir().join(format!("webmetro-hook-{}", process::id()));
+        let hooks = vec![
+            format!("publish-start=sh -c 'echo \"$WEBMETRO_EVENT $WEBMETRO_CHANNEL\" > {}'", path.display()).parse().unwrap(),
+            "publish-stop=sleep 10".parse().unwrap(),
+        ];
+        let (queue, task) = hook_runner(hooks, HookOptions { max_running: 1, timeout: Duration::from_millis(200) });
+        queue.run(&HookEvent::new(HookTrigger::PublishStop, "main"));
+        queue.run(&HookEvent::new(HookTrigger::PublishStart, "main"));
+        drop(queue);
+
+        let mut runtime = tokio::runtime::Builder::new().basic_scheduler().enable_all().build().unwrap();
+        // the sleep is killed, so the other command gets its turn
+        runtime.block_on(async { timeout(Duration::from_secs(5), task).await }).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "publish-start main\n");
+        fs::remove_file(&path).unwrap();
+    }
+}
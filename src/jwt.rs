@@ -0,0 +1,215 @@
+//! Authorizes requests with JSON Web Tokens issued by an existing auth system.
+//!
+//! Tokens are signed with HS256 (a shared secret) or RS256 (the issuer's
+//! public key), must carry an `exp`, and grant access through two claims,
+//! each a list of channel name patterns where `*` matches any run of
+//! characters: `publish` for sending streams to channels, and `view` for
+//! watching them. Admin actions (recording, mirroring, configuring & pausing)
+//! are left to the relay's admin Authorizer, so they need the admin token
+//! whatever a JWT grants.
+//!
+//! ```json
+//! {"exp": 1600000000, "publish": ["studio-*"], "view": ["*"]}
+//! ```
+
+use futures::{
+    future::{ready, BoxFuture},
+    prelude::*,
+};
+use jsonwebtoken::{decode, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+
+use crate::error::WebmetroError;
+use crate::server::{split_rendition, AccessRequest, Action, Authorizer};
+
+/// What a token allows its bearer to do
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct Grants {
+    pub publish: Vec<String>,
+    pub view: Vec<String>,
+}
+
+impl Grants {
+    pub fn permits(&self, action: Action, channel: &str) -> bool {
+        let patterns = match action {
+            Action::Publish => &self.publish,
+            Action::Probe | Action::Listen | Action::Monitor => &self.view,
+            // the relay never asks about these, as they're admin actions
+            Action::Record | Action::Mirror | Action::Configure | Action::Pause => return false,
+        };
+        // a grant for a simulcast channel covers its renditions
+        let group = split_rendition(channel).map_or(channel, |(group, _)| group);
+        patterns.iter().any(|pattern| matches_pattern(pattern, channel) || matches_pattern(pattern, group))
+    }
+}
+
+/// Whether `name` matches a pattern in which `*` stands for any run of characters
+pub fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let mut pieces = pattern.split('*');
+    // split always yields at least one piece
+    let first = pieces.next().unwrap_or("");
+    if !name.starts_with(first) {
+        return false;
+    }
+    let mut rest = &name[first.len()..];
+    let pieces: Vec<&str> = pieces.collect();
+    match pieces.split_last() {
+        // no wildcard at all
+        None => rest.is_empty(),
+        Some((last, middle)) => {
+            for piece in middle {
+                match rest.find(piece) {
+                    Some(index) => rest = &rest[index + piece.len()..],
+                    None => return false,
+                }
+            }
+            rest.len() >= last.len() && rest.ends_with(last)
+        }
+    }
+}
+
+/// The token in a request's credentials, without any "Bearer " prefix
+pub fn bearer_token(credentials: &str) -> &str {
+    let token = credentials.trim();
+    if token.len() > 7 && token.get(..7).map_or(false, |scheme| scheme.eq_ignore_ascii_case("Bearer ")) {
+        token[7..].trim_start()
+    } else {
+        token
+    }
+}
+
+/// Checks a bearer token, from the request's credentials, against the
+/// channel & action being requested
+pub struct JwtAuthorizer {
+    key: DecodingKey<'static>,
+    validation: Validation,
+}
+
+impl JwtAuthorizer {
+    /// Accept tokens signed with HS256 and this shared secret
+    pub fn hs256(secret: &[u8]) -> JwtAuthorizer {
+        JwtAuthorizer {
+            key: DecodingKey::from_secret(secret).into_static(),
+            validation: Validation::new(Algorithm::HS256),
+        }
+    }
+
+    /// Accept tokens signed with RS256, given the issuer's PEM-encoded public key
+    pub fn rs256(public_key_pem: &[u8]) -> Result<JwtAuthorizer, WebmetroError> {
+        let key = DecodingKey::from_rsa_pem(public_key_pem).map_err(|err| WebmetroError::ApplicationError {
+            message: format!("Couldn't read RSA public key: {}", err),
+        })?;
+        Ok(JwtAuthorizer {
+            key: key.into_static(),
+            validation: Validation::new(Algorithm::RS256),
+        })
+    }
+
+    /// Only accept tokens with this `iss` claim
+    pub fn issuer(mut self, issuer: &str) -> Self {
+        self.validation.iss = Some(issuer.to_string());
+        self
+    }
+
+    /// Only accept tokens whose `aud` claim includes this audience
+    pub fn audience(mut self, audience: &str) -> Self {
+        self.validation.set_audience(&[audience]);
+        self
+    }
+
+    /// The grants in a token (optionally prefixed with "Bearer "), if it's
+    /// valid, unexpired, and meant for us
+    pub fn grants(&self, credentials: &str) -> Option<Grants> {
+        match decode::<Grants>(bearer_token(credentials), &self.key, &self.validation) {
+            Ok(data) => Some(data.claims),
+            Err(err) => {
+                debug!("Rejected token: {}", err);
+                None
+            }
+        }
+    }
+}
+
+impl Authorizer for JwtAuthorizer {
+    fn authorize(&self, request: &AccessRequest) -> BoxFuture<'static, bool> {
+        let permitted = request.credentials.as_ref()
+            .and_then(|credentials| self.grants(credentials))
+            .map_or(false, |grants| grants.permits(request.action, &request.channel));
+        ready(permitted).boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde::Serialize;
+
+    use crate::jwt::*;
+
+    #[derive(Serialize)]
+    struct TestClaims<'a> {
+        exp: u64,
+        iss: &'a str,
+        aud: &'a str,
+        publish: Vec<&'a str>,
+        view: Vec<&'a str>,
+    }
+
+    fn token(exp: u64, iss: &str) -> String {
+        let claims = TestClaims { exp, iss, aud: "webmetro", publish: vec!["studio-*"], view: vec!["*"] };
+        encode(&Header::default(), &claims, &EncodingKey::from_secret(b"secret")).unwrap()
+    }
+
+    fn request(action: Action, channel: &str, credentials: String) -> AccessRequest {
+        AccessRequest {
+            action,
+            channel: channel.into(),
+            credentials: Some(credentials),
+            remote: None,
+            client_names: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn match_patterns() {
+        assert!(matches_pattern("*", ""));
+        assert!(matches_pattern("main", "main"));
+        assert!(!matches_pattern("main", "main2"));
+        assert!(matches_pattern("studio-*", "studio-a"));
+        assert!(!matches_pattern("studio-*", "main"));
+        assert!(matches_pattern("*-hd", "main-hd"));
+        assert!(matches_pattern("a*b*c", "a-b-b-c"));
+        assert!(!matches_pattern("a*b*c", "a-c"));
+        assert!(!matches_pattern("ab*ba", "aba"));
+    }
+
+    #[test]
+    fn grant_renditions() {
+        let grants = Grants { publish: vec!["main".into()], view: Vec::new() };
+        assert!(grants.permits(Action::Publish, "main/720p"));
+        assert!(!grants.permits(Action::Publish, "mainly/720p"));
+        assert!(!grants.permits(Action::Listen, "main/720p"));
+        // admin actions aren't granted by tokens at all
+        assert!(!grants.permits(Action::Record, "main"));
+    }
+
+    #[test]
+    fn authorize_tokens() {
+        let authorizer = JwtAuthorizer::hs256(b"secret").issuer("auth.example").audience("webmetro");
+        let valid = format!("Bearer {}", token(4_000_000_000, "auth.example"));
+
+        assert!(block_on(authorizer.authorize(&request(Action::Publish, "studio-a", valid.clone()))));
+        assert!(!block_on(authorizer.authorize(&request(Action::Publish, "main", valid.clone()))));
+        assert!(block_on(authorizer.authorize(&request(Action::Listen, "main", valid))));
+
+        let expired = token(1_000_000_000, "auth.example");
+        assert!(!block_on(authorizer.authorize(&request(Action::Listen, "main", expired))));
+        let foreign = token(4_000_000_000, "elsewhere.example");
+        assert!(!block_on(authorizer.authorize(&request(Action::Listen, "main", foreign))));
+
+        let forged = JwtAuthorizer::hs256(b"another secret");
+        assert!(!block_on(forged.authorize(&request(Action::Listen, "main", token(4_000_000_000, "auth.example")))));
+    }
+}
@@ -1,4 +1,4 @@
-#[macro_use] extern crate log;
+#[macro_use] extern crate tracing;
 
 pub mod ebml;
 pub mod error;
@@ -10,12 +10,52 @@ pub mod chunk;
 pub mod fixers;
 pub mod webm;
 
+#[cfg(feature = "server")]
+pub mod access_log;
+#[cfg(feature = "tokio")]
+pub mod adapters;
+pub mod budget;
+pub mod catalog;
 pub mod channel;
+#[cfg(feature = "server")]
+pub mod cluster;
+#[cfg(feature = "server")]
+pub mod config_store;
+pub mod cues;
+#[cfg(feature = "server")]
+pub mod edge;
+#[cfg(feature = "server")]
+pub mod events;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+pub mod fmp4;
+#[cfg(feature = "server")]
+pub mod history;
+pub mod hls;
+#[cfg(feature = "server")]
+pub mod hooks;
+#[cfg(feature = "server")]
+pub mod jwt;
+#[cfg(feature = "server")]
+pub mod metadata;
+pub mod muxer;
+pub mod ogg;
+pub mod recorder;
+pub mod segmenter;
+#[cfg(feature = "server")]
+pub mod server;
+#[cfg(feature = "server")]
+pub mod statsd;
+#[cfg(feature = "server")]
+pub mod transcode;
+#[cfg(feature = "server")]
+pub mod upload;
+#[cfg(feature = "wasm")]
+pub mod wasm;
+#[cfg(feature = "server")]
+pub mod webhook;
 
 pub use crate::ebml::{EbmlError, FromEbml};
 
 #[cfg(test)]
-mod tests {
-    pub const TEST_FILE: &'static [u8] = include_bytes!("data/test1.webm");
-    pub const ENCODE_WEBM_TEST_FILE: &'static [u8] = include_bytes!("data/encode_webm_test.webm");
-}
+mod tests;
@@ -1,15 +1,24 @@
 
-#[macro_use] extern crate log;
+#[macro_use] extern crate tracing;
 
 mod commands;
 
-use clap::{App, AppSettings, crate_version};
+use clap::{App, AppSettings, Arg, crate_version};
+use tracing_subscriber::EnvFilter;
 
 use crate::commands::{
     relay,
+    bench,
     filter,
     send,
-    dump
+    play,
+    probe,
+    record,
+    split,
+    dump,
+    extract,
+    clip,
+    monitor
 };
 
 fn options() -> App<'static, 'static> {
@@ -18,21 +27,76 @@ fn options() -> App<'static, 'static> {
         .about("Utilities for broadcasting & relaying live WebM video/audio streams")
         .setting(AppSettings::DisableHelpSubcommand)
         .setting(AppSettings::VersionlessSubcommands)
+        .arg(Arg::with_name("verbose")
+            .short("v")
+            .multiple(true)
+            .global(true)
+            .help("Log more detail; repeat for even more (overrides RUST_LOG)"))
+        .arg(Arg::with_name("quiet")
+            .short("q")
+            .global(true)
+            .conflicts_with("verbose")
+            .help("Only log errors (overrides RUST_LOG)"))
         .subcommand(relay::options())
         .subcommand(filter::options())
         .subcommand(send::options())
+        .subcommand(play::options())
+        .subcommand(record::options())
+        .subcommand(probe::options())
+        .subcommand(split::options())
         .subcommand(dump::options())
+        .subcommand(extract::options())
+        .subcommand(clip::options())
+        .subcommand(monitor::options())
+        .subcommand(bench::options())
+}
+
+/// Exit status for invalid command-line arguments
+const EXIT_USAGE: i32 = 2;
+
+fn init_logging(verbosity: u64, quiet: bool) {
+    let filter = match (quiet, verbosity) {
+        (true, _) => EnvFilter::new("error"),
+        (false, 0) => EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("error")),
+        (false, 1) => EnvFilter::new("info"),
+        (false, 2) => EnvFilter::new("debug"),
+        (false, _) => EnvFilter::new("trace"),
+    };
+    tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .init();
 }
 
 fn main() {
-    env_logger::init();
-    let args = options().get_matches();
+    let args = options().get_matches_safe().unwrap_or_else(|err| {
+        if err.use_stderr() {
+            eprintln!("{}", err.message);
+            std::process::exit(EXIT_USAGE);
+        }
+        // --help and --version also "fail", but print to stdout & exit successfully
+        err.exit()
+    });
+
+    let (verbosity, quiet) = match args.subcommand() {
+        (_, Some(sub_args)) => (sub_args.occurrences_of("verbose"), sub_args.is_present("quiet")),
+        _ => (args.occurrences_of("verbose"), args.is_present("quiet")),
+    };
+    init_logging(verbosity, quiet);
 
     match args.subcommand() {
         ("filter", Some(sub_args)) => filter::run(sub_args),
         ("relay", Some(sub_args)) => relay::run(sub_args),
         ("send", Some(sub_args)) => send::run(sub_args),
+        ("play", Some(sub_args)) => play::run(sub_args),
+        ("record", Some(sub_args)) => record::run(sub_args),
+        ("probe", Some(sub_args)) => probe::run(sub_args),
+        ("split", Some(sub_args)) => split::run(sub_args),
         ("dump", Some(sub_args)) => dump::run(sub_args),
+        ("extract", Some(sub_args)) => extract::run(sub_args),
+        ("clip", Some(sub_args)) => clip::run(sub_args),
+        ("monitor", Some(sub_args)) => monitor::run(sub_args),
+        ("bench", Some(sub_args)) => bench::run(sub_args),
         _ => {
             options().print_help().unwrap();
             println!("");
@@ -40,5 +104,6 @@ fn main() {
         }
     }.unwrap_or_else(|err| {
         error!("{}", err);
+        std::process::exit(err.exit_code());
     });
 }
@@ -0,0 +1,90 @@
+//! What a publisher says about its channel, for directory pages & the like:
+//! a title, a description, and tags. Publishers send it as JSON, e.g.
+//!
+//! ```json
+//! {"title":"Launch stream","description":"Live from the pad","tags":["space","live"]}
+//! ```
+//!
+//! with any of the fields left out.
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::WebmetroError;
+
+/// The longest title allowed, in characters
+const MAX_TITLE: usize = 200;
+
+/// The longest description allowed, in characters
+const MAX_DESCRIPTION: usize = 2000;
+
+/// The most tags allowed, and the longest each may be, in characters
+const MAX_TAGS: usize = 16;
+const MAX_TAG: usize = 50;
+
+/// A channel's metadata
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChannelMetadata {
+    pub title: Option<String>,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+}
+
+impl ChannelMetadata {
+    /// Read metadata sent as JSON, checking it isn't too big
+    pub fn from_json(json: &str) -> Result<ChannelMetadata, WebmetroError> {
+        let metadata: ChannelMetadata = serde_json::from_str(json).map_err(|err| WebmetroError::ApplicationError {
+            message: format!("Invalid metadata: {}", err),
+        })?;
+        metadata.check()?;
+        Ok(metadata)
+    }
+
+    /// Fail if any field is too long, or there are too many tags
+    pub fn check(&self) -> Result<(), WebmetroError> {
+        let too_long = |field: &Option<String>, limit: usize| field.as_ref().map_or(false, |text| text.chars().count() > limit);
+        if too_long(&self.title, MAX_TITLE) {
+            return Err(WebmetroError::ApplicationError {
+                message: format!("Titles may be at most {} characters", MAX_TITLE),
+            });
+        }
+        if too_long(&self.description, MAX_DESCRIPTION) {
+            return Err(WebmetroError::ApplicationError {
+                message: format!("Descriptions may be at most {} characters", MAX_DESCRIPTION),
+            });
+        }
+        if self.tags.len() > MAX_TAGS || self.tags.iter().any(|tag| tag.chars().count() > MAX_TAG) {
+            return Err(WebmetroError::ApplicationError {
+                message: format!("There may be at most {} tags, of at most {} characters each", MAX_TAGS, MAX_TAG),
+            });
+        }
+        Ok(())
+    }
+
+    /// The metadata as a JSON object, with every field, e.g.
+    /// `{"title":"Launch stream","description":null,"tags":["space"]}`
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Serializing metadata")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::metadata::*;
+
+    #[test]
+    fn read_metadata() {
+        let metadata = ChannelMetadata::from_json(r#"{"title":"Launch \"stream\"","tags":["space","live"]}"#).unwrap();
+        assert_eq!(metadata.title.as_deref(), Some("Launch \"stream\""));
+        assert_eq!(metadata.description, None);
+        assert_eq!(metadata.to_json(), r#"{"title":"Launch \"stream\"","description":null,"tags":["space","live"]}"#);
+        assert_eq!(ChannelMetadata::from_json("{}").unwrap(), ChannelMetadata::default());
+
+        assert!(ChannelMetadata::from_json(r#"{"title":1}"#).is_err());
+        assert!(ChannelMetadata::from_json(r#"{"rating":5}"#).is_err());
+        let long_title = format!(r#"{{"title":"{}"}}"#, "a".repeat(MAX_TITLE + 1));
+        assert!(ChannelMetadata::from_json(&long_title).is_err());
+        let many_tags = ChannelMetadata { tags: vec!["tag".into(); MAX_TAGS + 1], ..ChannelMetadata::default() };
+        assert!(many_tags.check().is_err());
+    }
+}
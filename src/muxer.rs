@@ -0,0 +1,201 @@
+//! The common shape of egress formats: a `Muxer` turns a live chunk stream
+//! (initialization segments describing the tracks, then Clusters of their
+//! blocks) into some container's bytes. `WebmMuxer` passes WebM through,
+//! `fmp4::Fmp4Muxer` transmuxes to fragmented MP4, and
+//! `ogg::OggOpusMuxer` remuxes Opus audio to Ogg; a new format only needs
+//! another implementation, which `mux` & `Relay::listen_muxed` can serve.
+
+use std::mem;
+use std::task::Poll;
+
+use bytes::Bytes;
+use futures::{prelude::*, ready, stream::poll_fn};
+
+use crate::chunk::Chunk;
+use crate::error::WebmetroError;
+use crate::webm::set_doc_type;
+
+/// Turns chunks into a container format's bytes
+pub trait Muxer {
+    /// Mux a chunk, appending the bytes to send for it to `output`, in
+    /// order; nothing if it produces nothing (yet). Fails if the stream
+    /// can't be carried.
+    fn mux(&mut self, chunk: &Chunk, output: &mut Vec<Bytes>) -> Result<(), WebmetroError>;
+}
+
+/// Mux a chunk stream into a stream of a container's bytes, reusing one
+/// buffer for every chunk's
+pub fn mux<M: Muxer>(chunks: impl Stream<Item = Result<Chunk, WebmetroError>>, mut muxer: M) -> impl Stream<Item = Result<Bytes, WebmetroError>> {
+    let mut chunks = Box::pin(chunks);
+    let mut pending = Vec::new();
+    let mut sent = 0;
+    poll_fn(move |cx| loop {
+        if sent < pending.len() {
+            sent += 1;
+            return Poll::Ready(Some(Ok(mem::take(&mut pending[sent - 1]))));
+        }
+        pending.clear();
+        sent = 0;
+        match ready!(chunks.as_mut().poll_next(cx)) {
+            Some(Ok(chunk)) => if let Err(err) = muxer.mux(&chunk, &mut pending) {
+                return Poll::Ready(Some(Err(err)));
+            },
+            Some(Err(err)) => return Poll::Ready(Some(Err(err))),
+            None => return Poll::Ready(None),
+        }
+    })
+}
+
+/// The container a listener's stream is labeled as. The media is the same
+/// either way; Matroska just changes the DocType & Content-Type, for players
+/// and recorders that check them strictly.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MediaFormat {
+    WebM,
+    Matroska,
+}
+
+impl MediaFormat {
+    /// Choose a format from a `?format=` parameter if there is one, or else
+    /// by whether the Accept header prefers Matroska over WebM
+    pub fn negotiate(format: Option<&str>, accept: Option<&str>) -> MediaFormat {
+        match format {
+            Some("mkv") | Some("matroska") => return MediaFormat::Matroska,
+            Some(_) => return MediaFormat::WebM,
+            None => {}
+        }
+        let mut webm = 0.0;
+        let mut matroska = 0.0;
+        for range in accept.unwrap_or("").split(',') {
+            let mut params = range.split(';');
+            let media_type = params.next().unwrap_or("").trim().to_ascii_lowercase();
+            let quality = params
+                .find_map(|param| {
+                    let mut parts = param.splitn(2, '=');
+                    match (parts.next(), parts.next()) {
+                        (Some(name), Some(value)) if name.trim().eq_ignore_ascii_case("q") => value.trim().parse::<f32>().ok(),
+                        _ => None,
+                    }
+                })
+                .unwrap_or(1.0);
+            match media_type.as_str() {
+                "video/webm" | "audio/webm" => webm = quality.max(webm),
+                "video/x-matroska" | "audio/x-matroska" => matroska = quality.max(matroska),
+                _ => {}
+            }
+        }
+        if matroska > webm {
+            MediaFormat::Matroska
+        } else {
+            MediaFormat::WebM
+        }
+    }
+
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            MediaFormat::WebM => "video/webm",
+            MediaFormat::Matroska => "video/x-matroska",
+        }
+    }
+
+    pub fn doc_type(&self) -> &'static str {
+        match self {
+            MediaFormat::WebM => "webm",
+            MediaFormat::Matroska => "matroska",
+        }
+    }
+
+    /// A muxer labeling the stream as this format
+    pub fn muxer(&self) -> WebmMuxer {
+        WebmMuxer::with_format(*self)
+    }
+}
+
+/// Sends chunks on as the WebM (or Matroska) they already are, sharing their
+/// bytes rather than copying them: each Cluster's head & body are separate
+/// `Bytes`, so an HTTP server that supports vectored writes can send them as
+/// they are.
+#[derive(Clone, Debug)]
+pub struct WebmMuxer {
+    /// what to label initialization segments as
+    format: MediaFormat,
+}
+
+impl WebmMuxer {
+    pub fn new() -> WebmMuxer {
+        WebmMuxer::with_format(MediaFormat::WebM)
+    }
+
+    /// Relabel initialization segments as `format`, e.g. Matroska for
+    /// players that only take that
+    pub fn with_format(format: MediaFormat) -> WebmMuxer {
+        WebmMuxer { format }
+    }
+}
+
+impl Default for WebmMuxer {
+    fn default() -> WebmMuxer {
+        WebmMuxer::new()
+    }
+}
+
+impl Muxer for WebmMuxer {
+    fn mux(&mut self, chunk: &Chunk, output: &mut Vec<Bytes>) -> Result<(), WebmetroError> {
+        match (self.format, chunk) {
+            (MediaFormat::Matroska, Chunk::Headers { bytes }) => {
+                let doc_type = self.format.doc_type();
+                match set_doc_type(bytes, doc_type) {
+                    Ok(headers) => output.push(Bytes::from(headers)),
+                    Err(err) => {
+                        warn!("Couldn't relabel headers as {}: {}", doc_type, err);
+                        output.push(bytes.clone());
+                    }
+                }
+            },
+            (_, chunk) => output.extend(chunk.clone()),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use crate::chunk::WebmStream;
+    use crate::muxer::*;
+    use crate::stream_parser::StreamEbml;
+    use crate::tests::TEST_FILE;
+    use crate::webm::doc_type;
+
+    #[test]
+    fn negotiate_media_format() {
+        assert_eq!(MediaFormat::negotiate(None, None), MediaFormat::WebM);
+        assert_eq!(MediaFormat::negotiate(None, Some("*/*")), MediaFormat::WebM);
+        assert_eq!(MediaFormat::negotiate(None, Some("video/x-matroska, */*;q=0.8")), MediaFormat::Matroska);
+        assert_eq!(MediaFormat::negotiate(None, Some("video/webm, video/x-matroska;q=0.9")), MediaFormat::WebM);
+        assert_eq!(MediaFormat::negotiate(None, Some("video/webm;q=0.5, video/x-matroska")), MediaFormat::Matroska);
+        assert_eq!(MediaFormat::negotiate(Some("mkv"), Some("video/webm")), MediaFormat::Matroska);
+        assert_eq!(MediaFormat::negotiate(Some("webm"), Some("video/x-matroska")), MediaFormat::WebM);
+    }
+
+    #[test]
+    fn pass_webm_through() {
+        let chunks: Vec<Chunk> = block_on(stream::iter(vec![Ok::<_, WebmetroError>(Bytes::from_static(TEST_FILE))])
+            .parse_ebml()
+            .chunk_webm()
+            .try_collect()).unwrap();
+        let webm: Vec<Bytes> = chunks.iter().cloned().flatten().collect();
+        let muxed: Vec<Bytes> = block_on(mux(stream::iter(chunks.clone()).map(Ok), WebmMuxer::new()).try_collect()).unwrap();
+        assert_eq!(muxed, webm);
+
+        let mut muxer = MediaFormat::Matroska.muxer();
+        let mut headers = Vec::new();
+        muxer.mux(&chunks[0], &mut headers).unwrap();
+        assert_eq!(doc_type(&headers[0]).unwrap(), Some("matroska"));
+        // Clusters are passed on as their head & body
+        let mut cluster = Vec::new();
+        muxer.mux(&chunks[1], &mut cluster).unwrap();
+        assert_eq!(cluster.len(), 2);
+    }
+}
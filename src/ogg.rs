@@ -0,0 +1,358 @@
+use std::io::{Result as IoResult, Write};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use bytes::Bytes;
+
+use crate::chunk::Chunk;
+use crate::error::WebmetroError;
+use crate::muxer::Muxer;
+use crate::webm::{block_timing, header_tracks, parse_webm, SimpleBlock, TrackEntry};
+
+const CONTINUED_PACKET: u8 = 0x01;
+const BEGINNING_OF_STREAM: u8 = 0x02;
+const END_OF_STREAM: u8 = 0x04;
+
+/// Granule position marking a page on which no packet ends
+const NO_GRANULE: u64 = !0;
+
+const MAX_SEGMENTS: usize = 255;
+
+/// Any lacing flag set means the block holds several frames, which aren't split here
+const LACING_MASK: u8 = 0b0000_0110;
+
+fn crc_table() -> Vec<u32> {
+    (0..256).map(|index| {
+        let mut remainder = (index as u32) << 24;
+        for _ in 0..8 {
+            remainder = if remainder & 0x80000000 != 0 {
+                (remainder << 1) ^ 0x04C11DB7
+            } else {
+                remainder << 1
+            };
+        }
+        remainder
+    }).collect()
+}
+
+fn crc(table: &[u32], bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |crc, byte| {
+        (crc << 8) ^ table[((crc >> 24) as u8 ^ byte) as usize]
+    })
+}
+
+/// Wraps packets of a single logical bitstream into Ogg pages.
+///
+/// Each packet starts a fresh page, which is what Ogg Opus & Ogg Vorbis
+/// require for their header packets and harmless for everything after.
+pub struct OggWriter<W: Write> {
+    output: W,
+    serial: u32,
+    sequence: u32,
+    crc_table: Vec<u32>,
+}
+
+impl<W: Write> OggWriter<W> {
+    pub fn new(output: W, serial: u32) -> OggWriter<W> {
+        OggWriter {
+            output,
+            serial,
+            sequence: 0,
+            crc_table: crc_table(),
+        }
+    }
+
+    /// Write a packet, ending at the given granule position; packets too large
+    /// for one page are continued across several.
+    pub fn write_packet(&mut self, packet: &[u8], granule_position: u64, end_of_stream: bool) -> IoResult<()> {
+        let mut lacing = vec![255u8; packet.len() / 255];
+        lacing.push((packet.len() % 255) as u8);
+
+        let mut data = packet;
+        let page_count = (lacing.len() + MAX_SEGMENTS - 1) / MAX_SEGMENTS;
+        for (page_index, segments) in lacing.chunks(MAX_SEGMENTS).enumerate() {
+            let last_page = page_index + 1 == page_count;
+            let page_len = segments.iter().map(|&segment| segment as usize).sum();
+            let (page_data, rest) = data.split_at(page_len);
+            data = rest;
+
+            let mut header_type = 0;
+            if page_index > 0 {
+                header_type |= CONTINUED_PACKET;
+            }
+            if self.sequence == 0 {
+                header_type |= BEGINNING_OF_STREAM;
+            }
+            if last_page && end_of_stream {
+                header_type |= END_OF_STREAM;
+            }
+
+            let granule = if last_page { granule_position } else { NO_GRANULE };
+            self.write_page(header_type, granule, segments, page_data)?;
+        }
+        Ok(())
+    }
+
+    fn write_page(&mut self, header_type: u8, granule_position: u64, segments: &[u8], data: &[u8]) -> IoResult<()> {
+        let mut page = Vec::with_capacity(27 + segments.len() + data.len());
+        page.extend_from_slice(b"OggS");
+        page.push(0); // version
+        page.push(header_type);
+        page.write_u64::<LittleEndian>(granule_position)?;
+        page.write_u32::<LittleEndian>(self.serial)?;
+        page.write_u32::<LittleEndian>(self.sequence)?;
+        page.write_u32::<LittleEndian>(0)?; // CRC placeholder
+        page.push(segments.len() as u8);
+        page.extend_from_slice(segments);
+        page.extend_from_slice(data);
+
+        let checksum = crc(&self.crc_table, &page);
+        (&mut page[22..26]).write_u32::<LittleEndian>(checksum)?;
+
+        self.sequence += 1;
+        self.output.write_all(&page)
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.output
+    }
+
+    pub fn into_inner(self) -> W {
+        self.output
+    }
+}
+
+/// A minimal OpusTags header packet, which Ogg Opus requires after OpusHead
+pub fn opus_tags() -> Vec<u8> {
+    let vendor = concat!("webmetro ", env!("CARGO_PKG_VERSION"));
+    let mut tags = Vec::new();
+    tags.extend_from_slice(b"OpusTags");
+    tags.extend_from_slice(&(vendor.len() as u32).to_le_bytes());
+    tags.extend_from_slice(vendor.as_bytes());
+    tags.extend_from_slice(&0u32.to_le_bytes()); // no user comments
+    tags
+}
+
+/// Count the 48kHz samples in an Opus packet, per its TOC byte (RFC 6716 section 3.1)
+pub fn opus_packet_samples(packet: &[u8]) -> u64 {
+    let toc = match packet.first() {
+        Some(&toc) => toc,
+        None => return 0
+    };
+
+    let config = toc >> 3;
+    let frame_samples = match config {
+        0..=11 => [480, 960, 1920, 2880][(config % 4) as usize],
+        12..=15 => [480, 960][(config % 2) as usize],
+        _ => [120, 240, 480, 960][(config % 4) as usize],
+    };
+    let frames = match toc & 0x03 {
+        0 => 1,
+        1 | 2 => 2,
+        _ => packet.get(1).map_or(0, |count| count & 0x3F) as u64,
+    };
+
+    frame_samples * frames
+}
+
+/// Remuxes a live stream whose only track is Opus into Ogg Opus, for audio
+/// players that don't speak WebM. Each Headers chunk starts a new logical
+/// bitstream, chained after the last, so a change of source is still valid Ogg.
+#[derive(Default)]
+pub struct OggOpusMuxer {
+    ogg: Option<OggWriter<Vec<u8>>>,
+    track: u64,
+    serial: u32,
+    /// 48kHz samples so far in the current bitstream
+    granule_position: u64,
+}
+
+impl OggOpusMuxer {
+    pub fn new() -> OggOpusMuxer {
+        OggOpusMuxer::default()
+    }
+
+    /// Remux a chunk: Headers become the OpusHead & OpusTags pages, and each
+    /// Cluster's blocks a page apiece. Clusters before the first Headers
+    /// produce nothing. Fails if the stream has anything besides one Opus track.
+    pub fn process(&mut self, chunk: &Chunk) -> Result<Option<Bytes>, WebmetroError> {
+        match chunk {
+            Chunk::Headers { bytes } => {
+                let tracks = header_tracks(bytes);
+                match tracks.as_slice() {
+                    [track] if track.codec_id == "A_OPUS" => self.start(track).map(Some),
+                    _ => Err("Ogg output needs a stream whose only track is Opus".into()),
+                }
+            },
+            Chunk::Cluster(_, body) => self.packets(body),
+            _ => Ok(None),
+        }
+    }
+
+    fn start(&mut self, track: &TrackEntry) -> Result<Bytes, WebmetroError> {
+        self.serial += 1;
+        self.track = track.number;
+        self.granule_position = 0;
+        let mut ogg = OggWriter::new(Vec::new(), self.serial);
+        ogg.write_packet(&track.codec_private, 0, false)?;
+        ogg.write_packet(&opus_tags(), 0, false)?;
+        let pages = std::mem::replace(ogg.get_mut(), Vec::new());
+        self.ogg = Some(ogg);
+        Ok(Bytes::from(pages))
+    }
+
+    fn packets(&mut self, body: &[u8]) -> Result<Option<Bytes>, WebmetroError> {
+        let ogg = match self.ogg {
+            Some(ref mut ogg) => ogg,
+            None => return Ok(None),
+        };
+        for element in parse_webm(body) {
+            if let Some((SimpleBlock { track, flags, data, .. }, _)) = block_timing(&element) {
+                if track != self.track || flags & LACING_MASK != 0 {
+                    continue;
+                }
+                self.granule_position += opus_packet_samples(data);
+                ogg.write_packet(data, self.granule_position, false)?;
+            }
+        }
+        let pages = std::mem::replace(ogg.get_mut(), Vec::new());
+        Ok(if pages.is_empty() { None } else { Some(Bytes::from(pages)) })
+    }
+}
+
+impl Muxer for OggOpusMuxer {
+    fn mux(&mut self, chunk: &Chunk, output: &mut Vec<Bytes>) -> Result<(), WebmetroError> {
+        output.extend(self.process(chunk)?);
+        Ok(())
+    }
+}
+
+/// Split Matroska's Vorbis CodecPrivate (Xiph-laced identification, comment,
+/// and setup headers) into its three packets.
+pub fn vorbis_headers(codec_private: &[u8]) -> Option<Vec<&[u8]>> {
+    let (&count, mut rest) = codec_private.split_first()?;
+    if count != 2 {
+        return None;
+    }
+
+    let mut sizes = Vec::new();
+    for _ in 0..2 {
+        let mut size = 0;
+        loop {
+            let (&lace, remaining) = rest.split_first()?;
+            rest = remaining;
+            size += lace as usize;
+            if lace < 255 {
+                break;
+            }
+        }
+        sizes.push(size);
+    }
+
+    if sizes[0] + sizes[1] > rest.len() {
+        return None;
+    }
+    let (identification, rest) = rest.split_at(sizes[0]);
+    let (comment, setup) = rest.split_at(sizes[1]);
+    Some(vec![identification, comment, setup])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ogg::*;
+
+    #[test]
+    fn ogg_crc() {
+        assert_eq!(crc(&crc_table(), b"123456789"), 0x89A1897F);
+    }
+
+    #[test]
+    fn write_pages() {
+        let mut writer = OggWriter::new(Vec::new(), 0x1234);
+        writer.write_packet(&[1, 2, 3], 0, false).unwrap();
+        writer.write_packet(&[0; 600], 960, true).unwrap();
+        let output = writer.into_inner();
+
+        assert_eq!(&output[0..4], b"OggS");
+        assert_eq!(output[5], BEGINNING_OF_STREAM);
+        assert_eq!(&output[14..18], &[0x34, 0x12, 0, 0]);
+        assert_eq!(&output[26..28], &[1, 3]);
+
+        let second_page = &output[27 + 1 + 3..];
+        assert_eq!(&second_page[0..4], b"OggS");
+        assert_eq!(second_page[5], END_OF_STREAM);
+        assert_eq!(&second_page[6..14], &960u64.to_le_bytes());
+        // sequence number
+        assert_eq!(&second_page[18..22], &[1, 0, 0, 0]);
+        assert_eq!(&second_page[26..30], &[3, 255, 255, 90]);
+        assert_eq!(second_page.len(), 27 + 3 + 600);
+    }
+
+    #[test]
+    fn split_large_packets() {
+        let mut writer = OggWriter::new(Vec::new(), 0);
+        writer.write_packet(&[0; 255 * 255], 100, false).unwrap();
+        let output = writer.into_inner();
+
+        // first page is full & has no packet ending on it
+        assert_eq!(output[26], 255);
+        assert_eq!(&output[6..14], &NO_GRANULE.to_le_bytes());
+
+        let second_page = &output[27 + 255 + 255 * 255..];
+        assert_eq!(second_page[5], CONTINUED_PACKET);
+        assert_eq!(&second_page[6..14], &100u64.to_le_bytes());
+        assert_eq!(&second_page[26..28], &[1, 0]);
+    }
+
+    #[test]
+    fn count_opus_samples() {
+        // CELT 20ms, one frame
+        assert_eq!(opus_packet_samples(&[0xF8]), 960);
+        // SILK 60ms, two frames
+        assert_eq!(opus_packet_samples(&[0x19]), 5760);
+        // CELT 2.5ms, code 3 with 4 frames
+        assert_eq!(opus_packet_samples(&[0x83, 0x04]), 480);
+        assert_eq!(opus_packet_samples(&[]), 0);
+    }
+
+    #[test]
+    fn remux_opus() {
+        use std::io::Cursor;
+
+        use crate::webm::encode_simple_block;
+
+        let mut muxer = OggOpusMuxer::new();
+        let track = TrackEntry { number: 2, codec_id: "A_OPUS".into(), codec_private: b"OpusHead".to_vec(), ..TrackEntry::default() };
+        let headers = muxer.start(&track).unwrap();
+        assert_eq!(&headers[0..4], b"OggS");
+        assert_eq!(headers[5], BEGINNING_OF_STREAM);
+        assert_eq!(&headers[28..36], b"OpusHead");
+
+        let mut body = Cursor::new(Vec::new());
+        for (track, packet) in &[(2, [0xF8, 1]), (1, [0, 0]), (2, [0xF8, 2])] {
+            encode_simple_block(SimpleBlock { track: *track, timecode: 0, flags: 0x80, data: packet }, &mut body).unwrap();
+        }
+        let pages = muxer.packets(body.get_ref()).unwrap().unwrap();
+        // only track 2's packets, 960 samples apiece
+        assert_eq!(pages.len(), 2 * (27 + 1 + 2));
+        assert_eq!(&pages[6..14], &960u64.to_le_bytes());
+        assert_eq!(&pages[30 + 6..30 + 14], &1920u64.to_le_bytes());
+        assert_eq!(&pages[30 + 28..30 + 30], &[0xF8, 2]);
+
+        assert!(muxer.packets(&[]).unwrap().is_none());
+    }
+
+    #[test]
+    fn split_vorbis_headers() {
+        let mut codec_private = vec![2, 3, 255, 1];
+        codec_private.extend_from_slice(&[1; 3]);
+        codec_private.extend_from_slice(&[2; 256]);
+        codec_private.extend_from_slice(&[3; 10]);
+
+        let headers = vorbis_headers(&codec_private).unwrap();
+        assert_eq!(headers[0], &[1; 3][..]);
+        assert_eq!(headers[1].len(), 256);
+        assert_eq!(headers[2], &[3; 10][..]);
+
+        assert_eq!(vorbis_headers(&[2, 3]), None);
+    }
+}
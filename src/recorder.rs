@@ -0,0 +1,539 @@
+use std::io::{Cursor, Result as IoResult, Seek, SeekFrom, Write};
+use std::mem;
+
+use bytes::BufMut;
+
+use crate::chunk::Chunk;
+use crate::ebml::*;
+use crate::error::WebmetroError;
+use crate::webm::*;
+
+const APP_NAME: &str = concat!("webmetro ", env!("CARGO_PKG_VERSION"));
+
+struct CuePoint {
+    time: u64,
+    track: u64,
+    cluster_position: u64,
+}
+
+/// Writes a chunk stream out as a finalized, seekable WebM file.
+///
+/// Live streams have unknown-size Segments & Clusters and no index; this writer
+/// instead emits sized elements, reserves space for a SeekHead & Duration,
+/// and patches them in (along with Cues for each keyframe cluster) when
+/// `finish()` is called. Cluster timecodes are rebased so the file starts at zero.
+pub struct WebmFileWriter<W: Write + Seek> {
+    output: W,
+    segment_size_offset: u64,
+    segment_data_start: u64,
+    cues_seek_range: (u64, u64),
+    duration_offset: u64,
+    /// the file's TimestampScale, in nanoseconds per tick
+    scale: u64,
+    /// the first Cluster's timecode, in ticks, & its start in milliseconds
+    first_timecode: Option<(u64, u64)>,
+    duration: u64,
+    cue_points: Vec<CuePoint>,
+}
+
+fn position<W: Seek>(output: &mut W) -> Result<u64, WebmetroError> {
+    Ok(output.seek(SeekFrom::Current(0))?)
+}
+
+fn encode_id(element_id: u64) -> IoResult<Vec<u8>> {
+    let mut id_bytes = Vec::new();
+    encode_varint(Varint::Value(element_id), &mut id_bytes)?;
+    Ok(id_bytes)
+}
+
+/// Find the track of the first keyframe block in a cluster body, for indexing
+fn keyframe_track(body: &[u8]) -> Option<u64> {
+    parse_webm(body).find_map(|element| match block_timing(&element) {
+        Some((block, true)) => Some(block.track),
+        _ => None
+    })
+}
+
+impl<W: Write + Seek> WebmFileWriter<W> {
+    /// Begin a new file using the initialization segment from a Headers chunk
+    pub fn new(mut output: W, headers: &Chunk) -> Result<Self, WebmetroError> {
+        let headers = match headers {
+            Chunk::Headers { bytes } => bytes,
+            _ => return Err("Headers chunk has no Tracks element".into())
+        };
+        let tracks = parse_webm(headers).find_map(|element| match element {
+            WebmElement::Tracks(tracks) => Some(tracks),
+            _ => None
+        }).ok_or("Headers chunk has no Tracks element")?;
+
+        encode_webm_element(WebmElement::EbmlHead, &mut output)?;
+
+        encode_varint(Varint::Value(SEGMENT_ID), &mut output)?;
+        let segment_size_offset = position(&mut output)?;
+        encode_varint_8(Varint::Unknown, &mut output)?;
+        let segment_data_start = position(&mut output)?;
+
+        // SeekHead positions are written fixed-width, so they can be filled in later;
+        // note where each entry (which ends with its position) finishes
+        let mut seek_entries = Cursor::new(Vec::new());
+        let mut seek_entry_ends = Vec::new();
+        for element_id in &[SEGMENT_INFO_ID, TRACKS_ID, CUES_ID] {
+            let id_bytes = encode_id(*element_id)?;
+            encode_element(SEEK_ID, &mut seek_entries, |output| {
+                encode_bytes(SEEK_ID_ID, &id_bytes, output)?;
+                encode_integer(SEEK_POSITION_ID, 0, output)
+            })?;
+            seek_entry_ends.push(seek_entries.position());
+        }
+        let seek_entries = seek_entries.into_inner();
+        encode_bytes(SEEK_HEAD_ID, &seek_entries, &mut output)?;
+        let seek_entries_start = position(&mut output)? - seek_entries.len() as u64;
+
+        let scale = timestamp_scale(headers);
+        let info_position = position(&mut output)? - segment_data_start;
+        encode_element(SEGMENT_INFO_ID, &mut output, |output| {
+            encode_integer(TIMECODE_SCALE_ID, scale, output)?;
+            encode_bytes(MUXING_APP_ID, APP_NAME.as_bytes(), output)?;
+            encode_bytes(WRITING_APP_ID, APP_NAME.as_bytes(), output)?;
+            encode_float(DURATION_ID, 0.0, output)
+        })?;
+        // Duration was written last, so its payload is the final 8 bytes of Info
+        let duration_offset = position(&mut output)? - 8;
+
+        let tracks_position = position(&mut output)? - segment_data_start;
+        encode_webm_element(WebmElement::Tracks(tracks), &mut output)?;
+
+        patch_u64(&mut output, seek_entries_start + seek_entry_ends[0] - 8, info_position)?;
+        patch_u64(&mut output, seek_entries_start + seek_entry_ends[1] - 8, tracks_position)?;
+        let cues_seek_range = (seek_entries_start + seek_entry_ends[1], seek_entries_start + seek_entry_ends[2]);
+        output.seek(SeekFrom::End(0))?;
+
+        Ok(WebmFileWriter {
+            output,
+            segment_size_offset,
+            segment_data_start,
+            cues_seek_range,
+            duration_offset,
+            scale,
+            first_timecode: None,
+            duration: 0,
+            cue_points: Vec::new(),
+        })
+    }
+
+    /// Append a Cluster chunk to the file; other chunk types are ignored.
+    pub fn write_cluster(&mut self, chunk: &Chunk) -> Result<(), WebmetroError> {
+        let (head, body) = match chunk {
+            Chunk::Cluster(head, body) => (head, body),
+            _ => return Ok(())
+        };
+
+        let (first_timecode, first_start) = *self.first_timecode.get_or_insert((head.timecode(), head.start));
+        let timecode = head.timecode().saturating_sub(first_timecode);
+        let cluster_position = position(&mut self.output)? - self.segment_data_start;
+
+        let mut timecode_bytes = Vec::new();
+        encode_integer(TIMECODE_ID, timecode, &mut timecode_bytes)?;
+        encode_tag_header(CLUSTER_ID, Varint::Value((timecode_bytes.len() + body.len()) as u64), &mut self.output)?;
+        self.output.write_all(&timecode_bytes)?;
+        self.output.write_all(body)?;
+
+        if head.keyframe {
+            if let Some(track) = keyframe_track(body) {
+                self.cue_points.push(CuePoint {
+                    time: timecode,
+                    track,
+                    cluster_position,
+                });
+            }
+        }
+
+        self.duration = self.duration.max(head.end.saturating_sub(first_start));
+        Ok(())
+    }
+
+    /// Milliseconds of media written so far
+    pub fn duration(&self) -> u64 {
+        self.duration
+    }
+
+    /// Write the Cues index & patch the Segment size, Duration, and SeekHead,
+    /// returning the underlying output.
+    pub fn finish(mut self) -> Result<W, WebmetroError> {
+        let cue_points = mem::replace(&mut self.cue_points, Vec::new());
+        let (cues_seek_start, cues_seek_end) = self.cues_seek_range;
+
+        if cue_points.is_empty() {
+            // Cues may not be empty, so blank out the SeekHead entry pointing at them
+            let void_len = cues_seek_end - cues_seek_start;
+            self.output.seek(SeekFrom::Start(cues_seek_start))?;
+            encode_tag_header(VOID_ID, Varint::Value(void_len - 2), &mut self.output)?;
+            self.output.write_all(&vec![0; (void_len - 2) as usize])?;
+            self.output.seek(SeekFrom::End(0))?;
+        } else {
+            let cues_position = position(&mut self.output)? - self.segment_data_start;
+            encode_element(CUES_ID, &mut self.output, |output| {
+                for cue in &cue_points {
+                    encode_element(CUE_POINT_ID, output, |output| {
+                        encode_integer(CUE_TIME_ID, cue.time, output)?;
+                        encode_element(CUE_TRACK_POSITIONS_ID, output, |output| {
+                            encode_integer(CUE_TRACK_ID, cue.track, output)?;
+                            encode_integer(CUE_CLUSTER_POSITION_ID, cue.cluster_position, output)
+                        })
+                    })?;
+                }
+                Ok(())
+            })?;
+            patch_u64(&mut self.output, cues_seek_end - 8, cues_position)?;
+            self.output.seek(SeekFrom::End(0))?;
+        }
+
+        let end = position(&mut self.output)?;
+
+        self.output.seek(SeekFrom::Start(self.segment_size_offset))?;
+        encode_varint_8(Varint::Value(end - self.segment_data_start), &mut self.output)?;
+
+        let mut duration_bytes = [0; 8];
+        // in ticks, like the file's other times
+        duration_bytes.as_mut().put_f64(self.duration as f64 * DEFAULT_TIMESTAMP_SCALE as f64 / self.scale as f64);
+        self.output.seek(SeekFrom::Start(self.duration_offset))?;
+        self.output.write_all(&duration_bytes)?;
+
+        self.output.seek(SeekFrom::Start(end))?;
+        self.output.flush()?;
+        Ok(self.output)
+    }
+}
+
+/// Records a chunk stream into a series of finalized files, starting a new
+/// one whenever the stream restarts with fresh headers. `open` is called with
+/// the index of each file to create.
+///
+/// Files can also be rotated by duration or size: once a file reaches either
+/// limit, the next keyframe Cluster starts a new file with the same headers.
+pub struct Archiver<W: Write + Seek, F> {
+    open: F,
+    writer: Option<WebmFileWriter<W>>,
+    headers: Option<Chunk>,
+    /// bytes of media in the current file
+    written: u64,
+    /// the timecode the current file's media starts at
+    file_start: Option<u64>,
+    max_duration: Option<u64>,
+    max_size: Option<u64>,
+    files: usize,
+    on_finish: Option<Box<dyn FnMut(usize) + Send>>,
+}
+
+impl<W: Write + Seek, F: FnMut(usize) -> Result<W, WebmetroError>> Archiver<W, F> {
+    pub fn new(open: F) -> Self {
+        Archiver {
+            open,
+            writer: None,
+            headers: None,
+            written: 0,
+            file_start: None,
+            max_duration: None,
+            max_size: None,
+            files: 0,
+            on_finish: None,
+        }
+    }
+
+    /// Call `callback` with a file's index once it's been finalized
+    pub fn on_finish(mut self, callback: impl FnMut(usize) + Send + 'static) -> Self {
+        self.on_finish = Some(Box::new(callback));
+        self
+    }
+
+    /// Start a new file at the first keyframe after a file spans this many milliseconds
+    pub fn max_duration(mut self, duration: u64) -> Self {
+        self.max_duration = Some(duration);
+        self
+    }
+
+    /// Start a new file at the first keyframe after a file reaches this many bytes
+    pub fn max_size(mut self, size: u64) -> Self {
+        self.max_size = Some(size);
+        self
+    }
+
+    fn start_file(&mut self, headers: &Chunk) -> Result<(), WebmetroError> {
+        self.finish_file()?;
+        let output = (self.open)(self.files)?;
+        self.files += 1;
+        self.writer = Some(WebmFileWriter::new(output, headers)?);
+        self.written = headers.size() as u64;
+        Ok(())
+    }
+
+    /// whether a Cluster starting at `timecode` is past the current file's limits
+    fn is_full(&self, timecode: u64) -> bool {
+        let elapsed = match self.file_start {
+            Some(start) => timecode.saturating_sub(start),
+            // no media written yet
+            None => return false
+        };
+        self.max_duration.map_or(false, |limit| elapsed >= limit)
+            || self.max_size.map_or(false, |limit| self.written >= limit)
+    }
+
+    /// Write a chunk; Clusters before the first headers are skipped
+    pub fn write(&mut self, chunk: &Chunk) -> Result<(), WebmetroError> {
+        match chunk {
+            Chunk::Headers {..} => {
+                self.start_file(chunk)?;
+                self.headers = Some(chunk.clone());
+            },
+            Chunk::Cluster(head, _) => {
+                if self.writer.is_none() {
+                    return Ok(());
+                }
+                let rotate = head.keyframe && self.is_full(head.start);
+                if rotate {
+                    if let Some(headers) = self.headers.clone() {
+                        debug!("Rotating recording");
+                        self.start_file(&headers)?;
+                    }
+                }
+                if let Some(ref mut writer) = self.writer {
+                    writer.write_cluster(chunk)?;
+                    self.written += chunk.size() as u64;
+                    self.file_start.get_or_insert(head.start);
+                }
+            },
+            _ => {}
+        }
+        Ok(())
+    }
+
+    /// Finalize the file being written, if any; Clusters are skipped until
+    /// the next headers start a new file.
+    pub fn finish_file(&mut self) -> Result<(), WebmetroError> {
+        self.written = 0;
+        self.file_start = None;
+        if let Some(writer) = self.writer.take() {
+            info!("Finalizing recording ({} ms)", writer.duration());
+            writer.finish()?;
+            if let Some(ref mut callback) = self.on_finish {
+                callback(self.files - 1);
+            }
+        }
+        Ok(())
+    }
+
+    /// How many files have been started
+    pub fn files(&self) -> usize {
+        self.files
+    }
+}
+
+/// Whether the start of a WebM file shows it was finalized. Live streams, and
+/// recordings still being written, have a Segment of unknown size.
+pub fn is_finalized(start: &[u8]) -> bool {
+    match decode_tag(start) {
+        Ok(Some((EBML_HEAD_ID, Varint::Value(size), header_len))) => {
+            let segment = start.get(header_len + size as usize..).map(decode_tag);
+            matches!(segment, Some(Ok(Some((SEGMENT_ID, Varint::Value(_), _)))))
+        },
+        _ => false
+    }
+}
+
+fn patch_u64<W: Write + Seek>(output: &mut W, offset: u64, value: u64) -> Result<(), WebmetroError> {
+    let mut buffer = [0; 8];
+    buffer.as_mut().put_u64(value);
+    output.seek(SeekFrom::Start(offset))?;
+    output.write_all(&buffer)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{stream::iter, FutureExt, TryStreamExt};
+    use std::io::Cursor;
+
+    use crate::chunk::{Chunk, ChunkerOptions, ClusterHead, WebmStream};
+    use crate::error::WebmetroError;
+    use crate::recorder::*;
+    use crate::stream_parser::StreamEbml;
+    use crate::tests::{ENCODE_WEBM_TEST_FILE, TEST_FILE};
+
+    #[test]
+    fn write_finalized_file() {
+        let chunks: Vec<Chunk> = iter(vec![Ok::<&[u8], WebmetroError>(TEST_FILE)])
+            .parse_ebml()
+            .chunk_webm()
+            .try_collect()
+            .now_or_never()
+            .expect("Test tried to block on I/O")
+            .expect("Parse failed");
+
+        let mut writer = WebmFileWriter::new(Cursor::new(Vec::new()), &chunks[0]).unwrap();
+        for chunk in &chunks[1..] {
+            writer.write_cluster(chunk).unwrap();
+        }
+        let output = writer.finish().unwrap().into_inner();
+
+        let mut iter = parse_webm(&output);
+        assert_eq!(iter.next(), Some(WebmElement::EbmlHead));
+        assert_eq!(iter.next(), Some(WebmElement::Segment));
+        assert_eq!(iter.next(), Some(WebmElement::SeekHead));
+        assert!(matches!(iter.next(), Some(WebmElement::Info(_))));
+        assert!(matches!(iter.next(), Some(WebmElement::Tracks(_))));
+        assert_eq!(iter.next(), Some(WebmElement::Cluster));
+        assert_eq!(iter.next(), Some(WebmElement::Timecode(0)));
+
+        let clusters = iter.filter(|element| *element == WebmElement::Cluster).count();
+        assert_eq!(clusters, 2);
+
+        let last = parse_webm(&output).last();
+        assert_eq!(last, Some(WebmElement::Cues));
+        assert!(is_finalized(&output));
+    }
+
+    #[test]
+    fn write_rescaled_file() {
+        let write = |options: ChunkerOptions| {
+            let chunks: Vec<Chunk> = iter(vec![Ok::<&[u8], WebmetroError>(TEST_FILE)])
+                .parse_ebml()
+                .chunk_webm_with(options)
+                .try_collect()
+                .now_or_never()
+                .expect("Test tried to block on I/O")
+                .expect("Parse failed");
+            let mut writer = WebmFileWriter::new(Cursor::new(Vec::new()), &chunks[0]).unwrap();
+            for chunk in &chunks[1..] {
+                writer.write_cluster(chunk).unwrap();
+            }
+            let duration = writer.duration();
+            let output = writer.finish().unwrap().into_inner();
+            let timecodes: Vec<u64> = parse_webm(&output).filter_map(|element| match element {
+                WebmElement::Timecode(timecode) => Some(timecode),
+                _ => None,
+            }).collect();
+            (duration, timecodes)
+        };
+
+        // the duration is in milliseconds either way, while Clusters are written in microseconds
+        let (duration, timecodes) = write(ChunkerOptions::new());
+        let (rescaled_duration, rescaled_timecodes) = write(ChunkerOptions::new().timestamp_scale(1000));
+        assert_eq!(rescaled_duration, duration);
+        assert_eq!(rescaled_timecodes[0], 0);
+        // (Clusters too long for 16 bits of microseconds are split)
+        let last = *rescaled_timecodes.last().unwrap();
+        assert!(last >= timecodes.last().unwrap() * 1000 && last <= duration * 1000);
+    }
+
+    #[test]
+    fn archive_each_stream() {
+        let chunks: Vec<Chunk> = iter(vec![Ok::<&[u8], WebmetroError>(TEST_FILE)])
+            .parse_ebml()
+            .chunk_webm()
+            .try_collect()
+            .now_or_never()
+            .expect("Test tried to block on I/O")
+            .expect("Parse failed");
+
+        let mut opened = Vec::new();
+        let (finished, finished_indexes) = std::sync::mpsc::channel();
+        let mut archiver = Archiver::new(|index| {
+            opened.push(index);
+            Ok(Cursor::new(Vec::new()))
+        }).on_finish(move |index| finished.send(index).unwrap());
+        // a stray Cluster before any headers is skipped
+        archiver.write(&chunks[1]).unwrap();
+        for chunk in chunks.iter().chain(&chunks) {
+            archiver.write(chunk).unwrap();
+        }
+        archiver.finish_file().unwrap();
+        assert_eq!(archiver.files(), 2);
+        drop(archiver);
+        assert_eq!(opened, vec![0, 1]);
+        assert_eq!(finished_indexes.try_iter().collect::<Vec<_>>(), vec![0, 1]);
+    }
+
+    #[test]
+    fn rotate_by_duration() {
+        let mut headers = Vec::new();
+        encode_bytes(TRACKS_ID, &[], &mut headers).unwrap();
+        let headers = Chunk::Headers { bytes: headers.into() };
+        let cluster = |timecode, keyframe| {
+            let mut head = ClusterHead::new(timecode);
+            head.keyframe = keyframe;
+            Chunk::Cluster(head, Default::default())
+        };
+
+        let mut outputs = 0;
+        let mut archiver = Archiver::new(|_| {
+            outputs += 1;
+            Ok(Cursor::new(Vec::new()))
+        }).max_duration(1000);
+        archiver.write(&headers).unwrap();
+        for timecode in (0..4000).step_by(500) {
+            // only every other Cluster is a keyframe to cut at
+            archiver.write(&cluster(timecode, timecode % 1000 == 0)).unwrap();
+        }
+        archiver.finish_file().unwrap();
+        // each file starts on a keyframe, a second after the last
+        assert_eq!(archiver.files(), 4);
+        drop(archiver);
+        assert_eq!(outputs, 4);
+    }
+
+    #[test]
+    fn rotate_by_size() {
+        let mut headers = Vec::new();
+        encode_bytes(TRACKS_ID, &[], &mut headers).unwrap();
+        let headers = Chunk::Headers { bytes: headers.into() };
+        let cluster = |timecode, keyframe| {
+            let mut head = ClusterHead::new(timecode);
+            head.keyframe = keyframe;
+            let mut body = Vec::new();
+            encode_bytes(VOID_ID, &[0; 1000], &mut body).unwrap();
+            Chunk::Cluster(head, body.into())
+        };
+        // room for the headers & exactly two Clusters
+        let cluster_size = cluster(0, true).size() as u64;
+        let limit = headers.size() as u64 + 2 * cluster_size;
+
+        let mut files = vec![Vec::new(); 3];
+        let mut outputs = files.iter_mut();
+        let mut archiver = Archiver::new(|_| Ok(Cursor::new(outputs.next().unwrap()))).max_size(limit);
+        archiver.write(&headers).unwrap();
+        archiver.write(&cluster(0, true)).unwrap();
+        archiver.write(&cluster(100, true)).unwrap();
+        assert_eq!(archiver.written, limit);
+        // a full file carries on until there's a keyframe to cut at
+        archiver.write(&cluster(200, false)).unwrap();
+        assert_eq!(archiver.files(), 1);
+        assert_eq!(archiver.written, limit + cluster_size);
+        archiver.write(&cluster(300, true)).unwrap();
+        assert_eq!(archiver.files(), 2);
+        assert_eq!(archiver.written, headers.size() as u64 + cluster_size);
+        // just under the limit, the next keyframe stays in the same file
+        archiver.write(&cluster(400, true)).unwrap();
+        assert_eq!(archiver.files(), 2);
+        archiver.finish_file().unwrap();
+        drop(archiver);
+
+        let timecodes = |file: &[u8]| -> Vec<u64> {
+            parse_webm(file).filter_map(|element| match element {
+                WebmElement::Timecode(timecode) => Some(timecode),
+                _ => None,
+            }).collect()
+        };
+        assert_eq!(timecodes(&files[0]), vec![0, 100, 200]);
+        // rebased to start at zero
+        assert_eq!(timecodes(&files[1]), vec![0, 100]);
+        assert!(files[2].is_empty());
+    }
+
+    #[test]
+    fn detect_unfinalized_files() {
+        assert!(is_finalized(TEST_FILE));
+        assert!(!is_finalized(ENCODE_WEBM_TEST_FILE));
+        assert!(!is_finalized(&TEST_FILE[..20]));
+        assert!(!is_finalized(b"not webm"));
+    }
+}
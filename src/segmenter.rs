@@ -0,0 +1,369 @@
+//! Cuts a chunk stream into segments of about a target duration, each
+//! starting at a video keyframe, as HLS & the split command need. A keyframe
+//! partway through a Cluster splits the Cluster there, so segments don't run
+//! long waiting for the next Cluster to start with one.
+
+use std::collections::VecDeque;
+use std::io::{Cursor, Write};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures::prelude::*;
+
+use crate::chunk::{Chunk, ClusterHead};
+use crate::webm::{block_timing, element_offsets, encode_block, video_tracks};
+
+/// A run of Clusters that starts with a keyframe & plays on its own, given
+/// its headers
+#[derive(Clone, Debug)]
+pub struct Segment {
+    /// counting from 0 for the stream's first segment
+    pub sequence: u64,
+    /// the initialization segment it plays with
+    pub headers: Bytes,
+    /// in ms
+    pub start: u64,
+    /// in ms; exactly until the next segment's start, or for the last segment
+    /// before new headers (or the end), until its last block's timecode
+    pub duration: u64,
+    pub clusters: Vec<Chunk>,
+}
+
+impl Segment {
+    /// How many bytes of Clusters it holds
+    pub fn size(&self) -> usize {
+        self.clusters.iter().map(Chunk::size).sum()
+    }
+}
+
+/// The segment being filled
+struct Building {
+    start: u64,
+    end: u64,
+    clusters: Vec<Chunk>,
+    size: usize,
+}
+
+/// Collects chunks into segments: a new one starts at the first keyframe
+/// once the current one is `target_duration` ms long (or `max_size` bytes,
+/// if set), and at new headers. Anything before the first keyframe is dropped.
+pub struct Segmenter {
+    target_duration: u64,
+    max_size: Option<usize>,
+    headers: Option<Bytes>,
+    /// tracks whose keyframes can start a segment; any track's, if there's no video
+    video_tracks: Vec<u64>,
+    current: Option<Building>,
+    sequence: u64,
+}
+
+impl Segmenter {
+    pub fn new(target_duration: u64) -> Segmenter {
+        Segmenter {
+            target_duration,
+            max_size: None,
+            headers: None,
+            video_tracks: Vec::new(),
+            current: None,
+            sequence: 0,
+        }
+    }
+
+    /// Also start a new segment at the first keyframe after this many bytes
+    pub fn max_size(mut self, size: usize) -> Self {
+        self.max_size = Some(size);
+        self
+    }
+
+    /// Take in a chunk, returning any segments it completes
+    pub fn push(&mut self, chunk: Chunk) -> Vec<Segment> {
+        self.take(chunk, true).0
+    }
+
+    /// Take in a chunk without keeping its Clusters, returning them (cut at
+    /// keyframes as need be) each marked with whether it starts a segment,
+    /// for callers that write segments out as they go rather than holding
+    /// them whole; Clusters before the first keyframe are dropped
+    pub fn cut(&mut self, chunk: Chunk) -> Vec<(bool, Chunk)> {
+        self.take(chunk, false).1
+    }
+
+    /// The headers the current segment plays with
+    pub fn headers(&self) -> Option<&Bytes> {
+        self.headers.as_ref()
+    }
+
+    /// Take in a chunk, returning the segments it completes, and if the
+    /// Clusters aren't kept in them, the Clusters themselves
+    fn take(&mut self, chunk: Chunk, keep: bool) -> (Vec<Segment>, Vec<(bool, Chunk)>) {
+        let mut finished = Vec::new();
+        let mut pieces = Vec::new();
+        match chunk {
+            Chunk::Headers { bytes } => {
+                finished.extend(self.finish());
+                self.video_tracks = video_tracks(&bytes);
+                self.headers = Some(bytes);
+            },
+            Chunk::Cluster(head, body) => {
+                if self.headers.is_none() {
+                    return (finished, pieces);
+                }
+                for (split, head, body) in self.split(head, body) {
+                    if split {
+                        if let Some(segment) = self.finish_at(head.start) {
+                            finished.push(segment);
+                        }
+                        self.current = Some(Building {
+                            start: head.start,
+                            end: head.start,
+                            clusters: Vec::new(),
+                            size: 0,
+                        });
+                    }
+                    if let Some(ref mut current) = self.current {
+                        current.end = current.end.max(head.end);
+                        let chunk = Chunk::Cluster(head, body);
+                        current.size += chunk.size();
+                        if keep {
+                            current.clusters.push(chunk);
+                        } else {
+                            pieces.push((split, chunk));
+                        }
+                    }
+                }
+            },
+            _ => {}
+        }
+        (finished, pieces)
+    }
+
+    /// Finish the current segment, if there is one, at the end of its last block
+    pub fn finish(&mut self) -> Option<Segment> {
+        let end = self.current.as_ref()?.end;
+        self.finish_at(end)
+    }
+
+    fn finish_at(&mut self, end: u64) -> Option<Segment> {
+        let current = self.current.take()?;
+        let segment = Segment {
+            sequence: self.sequence,
+            headers: self.headers.clone().unwrap_or_default(),
+            start: current.start,
+            duration: end.saturating_sub(current.start),
+            clusters: current.clusters,
+        };
+        self.sequence += 1;
+        Some(segment)
+    }
+
+    /// Whether a segment starting at `start` with `size` bytes so far should
+    /// end before a keyframe at `time`
+    fn is_due(&self, start: Option<(u64, usize)>, time: u64) -> bool {
+        match start {
+            None => true,
+            Some((start, size)) => time.saturating_sub(start) >= self.target_duration
+                || self.max_size.map_or(false, |max_size| size >= max_size),
+        }
+    }
+
+    /// Cut a Cluster at each keyframe that should start a segment, marking
+    /// the pieces that do; elements other than blocks are carried along as
+    /// they are, in the piece they fall in
+    fn split(&self, head: ClusterHead, body: Bytes) -> Vec<(bool, ClusterHead, Bytes)> {
+        // each element's offset & length, and its block if it is one
+        let elements: Vec<_> = element_offsets(&body).map(|(offset, length, element)| {
+            let block = block_timing(&element);
+            (offset, length, element, block)
+        }).collect();
+
+        let mut current = self.current.as_ref().map(|current| (current.start, current.size));
+        let mut cuts = Vec::new();
+        for (index, (_, length, _, block)) in elements.iter().enumerate() {
+            if let Some((block, keyframe)) = block {
+                let time = head.block_time(block.timecode);
+                let is_keyframe = *keyframe
+                    && (self.video_tracks.is_empty() || self.video_tracks.contains(&block.track));
+                if is_keyframe && self.is_due(current, time) {
+                    cuts.push(index);
+                    current = Some((time, 0));
+                }
+            }
+            if let Some((_, ref mut size)) = current {
+                *size += length;
+            }
+        }
+
+        match cuts.first() {
+            // the Cluster continues the current segment as it is
+            None => return vec![(false, head, body)],
+            // or starts one as it is
+            Some(0) if cuts.len() == 1 => return vec![(true, head, body)],
+            _ => {}
+        }
+        let mut pieces = Vec::new();
+        if cuts[0] > 0 {
+            // the elements before the first cut are kept as they are
+            let end = elements[cuts[0]].0;
+            let mut first = head.clone();
+            first.end = first.start;
+            for (block, _) in elements[..cuts[0]].iter().filter_map(|(_, _, _, block)| block.as_ref()) {
+                first.observe_simpleblock_timecode(block.timecode);
+            }
+            pieces.push((false, first, body.slice(..end)));
+        }
+        for (number, &cut) in cuts.iter().enumerate() {
+            let until = cuts.get(number + 1).cloned().unwrap_or(elements.len());
+            // the piece starts at its first block, at the Cluster's TimestampScale
+            let start = elements[cut].3.as_ref().map_or(0, |(block, _)| block.timecode as i64);
+            let start = (head.timecode() as i64 + start).max(0);
+            let mut cluster_head = ClusterHead::with_scale(start as u64, head.timestamp_scale());
+            cluster_head.keyframe = true;
+            let mut buffer = Cursor::new(Vec::new());
+            for (offset, length, element, block) in &elements[cut..until] {
+                // writing to memory can't fail
+                match block {
+                    Some((block, _)) => {
+                        let timecode = (head.timecode() as i64 + block.timecode as i64 - start)
+                            .max(i16::min_value() as i64)
+                            .min(i16::max_value() as i64) as i16;
+                        cluster_head.observe_simpleblock_timecode(timecode);
+                        encode_block(*element, timecode, &mut buffer).unwrap();
+                    },
+                    None => buffer.write_all(&body[*offset..offset + length]).unwrap(),
+                }
+            }
+            pieces.push((true, cluster_head, Bytes::from(buffer.into_inner())));
+        }
+        pieces
+    }
+}
+
+/// The segments of a chunk stream; see `ChunkStream::segment`
+pub struct Segments<S> {
+    stream: S,
+    segmenter: Segmenter,
+    ready: VecDeque<Segment>,
+    ended: bool,
+}
+
+impl<S> Segments<S> {
+    pub fn new(stream: S, segmenter: Segmenter) -> Segments<S> {
+        Segments {
+            stream,
+            segmenter,
+            ready: VecDeque::new(),
+            ended: false,
+        }
+    }
+}
+
+impl<S: TryStream<Ok = Chunk> + Unpin> Stream for Segments<S> {
+    type Item = Result<Segment, S::Error>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<Result<Segment, S::Error>>> {
+        loop {
+            if let Some(segment) = self.ready.pop_front() {
+                return Poll::Ready(Some(Ok(segment)));
+            }
+            if self.ended {
+                return Poll::Ready(None);
+            }
+            match self.stream.try_poll_next_unpin(cx) {
+                Poll::Ready(Some(Ok(chunk))) => {
+                    let segments = self.segmenter.push(chunk);
+                    self.ready.extend(segments);
+                },
+                Poll::Ready(Some(Err(err))) => return Poll::Ready(Some(Err(err))),
+                Poll::Ready(None) => {
+                    self.ended = true;
+                    let last = self.segmenter.finish();
+                    self.ready.extend(last);
+                },
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{executor::block_on, stream};
+
+    use crate::chunk::WebmStream;
+    use crate::error::WebmetroError;
+    use crate::fixers::ChunkStream;
+    use crate::segmenter::*;
+    use crate::stream_parser::StreamEbml;
+    use crate::tests::{block, starts, timecodes, TEST_FILE};
+
+    #[test]
+    fn split_clusters_at_keyframes() {
+        let mut segmenter = Segmenter::new(1000);
+        assert!(segmenter.push(Chunk::Headers { bytes: Bytes::new() }).is_empty());
+
+        // the delta frame before the first keyframe is dropped
+        let body: Vec<u8> = [block(1, 0, false), block(1, 500, true), block(1, 1000, false), block(1, 1500, true), block(1, 2000, false)].concat();
+        let mut head = ClusterHead::new(10_000);
+        head.end = 12_000;
+        let segments = segmenter.push(Chunk::Cluster(head, Bytes::from(body)));
+        assert_eq!(segments.len(), 1);
+        assert_eq!((segments[0].sequence, segments[0].start, segments[0].duration), (0, 10_500, 1000));
+        assert_eq!(starts(&segments[0].clusters), vec![Some(10_500)]);
+        assert_eq!(segments[0].clusters.iter().map(timecodes).collect::<Vec<_>>(), vec![vec![(1, 0), (1, 500)]]);
+
+        // a Cluster starting with a keyframe is passed on whole
+        let body: Vec<u8> = [block(1, 0, true), block(1, 400, false)].concat();
+        let mut head = ClusterHead::new(12_600);
+        head.end = 13_000;
+        let segments = segmenter.push(Chunk::Cluster(head, Bytes::from(body)));
+        assert_eq!((segments[0].start, segments[0].duration), (11_500, 1100));
+        assert_eq!(starts(&segments[0].clusters), vec![Some(11_500)]);
+        assert_eq!(segments[0].clusters.iter().map(timecodes).collect::<Vec<_>>(), vec![vec![(1, 0), (1, 500)]]);
+
+        let last = segmenter.finish().unwrap();
+        assert_eq!((last.sequence, last.start, last.duration), (2, 12_600, 400));
+        assert!(segmenter.finish().is_none());
+    }
+
+    #[test]
+    fn cut_clusters_without_keeping_them() {
+        let mut segmenter = Segmenter::new(1000);
+        assert!(segmenter.cut(Chunk::Headers { bytes: Bytes::new() }).is_empty());
+
+        // a Void element after the keyframe stays in its piece
+        let void = [0xEC, 0x81, 0x00];
+        let body: Vec<u8> = [block(1, 0, false), block(1, 500, true), void.to_vec(), block(1, 1000, false), block(1, 1500, true)].concat();
+        let pieces = segmenter.cut(Chunk::Cluster(ClusterHead::new(10_000), Bytes::from(body)));
+        assert!(pieces.iter().all(|(starts_segment, _)| *starts_segment));
+        let clusters: Vec<Chunk> = pieces.iter().map(|(_, cluster)| cluster.clone()).collect();
+        assert_eq!(starts(&clusters), vec![Some(10_500), Some(11_500)]);
+        assert_eq!(clusters.iter().map(timecodes).collect::<Vec<_>>(), vec![vec![(1, 0), (1, 500)], vec![(1, 0)]]);
+        match pieces[0].1 {
+            Chunk::Cluster(_, ref body) => assert!(body.windows(3).any(|bytes| bytes == void)),
+            _ => panic!("not a Cluster"),
+        }
+        assert!(segmenter.finish().unwrap().clusters.is_empty());
+    }
+
+    #[test]
+    fn segment_streams() {
+        let segments: Vec<Segment> = block_on(stream::iter(vec![Ok::<&[u8], WebmetroError>(TEST_FILE)])
+            .parse_ebml()
+            .chunk_webm()
+            .segment(Segmenter::new(1000))
+            .try_collect())
+            .unwrap();
+        assert!(segments.len() > 1);
+        for (segment, next) in segments.iter().zip(segments.iter().skip(1)) {
+            assert_eq!(segment.start + segment.duration, next.start);
+            assert!(segment.duration >= 1000);
+        }
+        for segment in &segments {
+            match segment.clusters[0] {
+                Chunk::Cluster(ref head, _) => assert!(head.keyframe),
+                _ => panic!("Segment doesn't start with a Cluster"),
+            }
+        }
+    }
+}
@@ -0,0 +1,2566 @@
+//! The relay's channel-hosting behavior, independent of any web framework.
+//!
+//! A `Relay` owns the set of live channels; a request handler decides what a
+//! request wants with `Action::from_method`, asks the `Relay` whether it's
+//! allowed, and then either pipes the request body into `Relay::publish` or
+//! answers with `MEDIA_HEADERS` and the stream from `Relay::listen`.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::Cursor;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hash, Hasher};
+use std::str::FromStr;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use futures::{
+    channel::oneshot,
+    future::{ready, BoxFuture, Either},
+    prelude::*,
+    stream::{iter, once, select},
+};
+use serde::{Serialize, Serializer};
+
+use crate::budget::{Charge, MemoryBudget};
+use crate::channel::{Channel, Handle, LagPolicy, Listener, ListenerPriority, ListenerStats, Transmitter, DEFAULT_QUEUE_LIMIT};
+use crate::chunk::{Chunk, ChunkerOptions, WebmStream};
+use crate::config_store::same_key;
+use crate::ebml::{FromEbml, EBML_HEAD_ID};
+use crate::error::{Limit, WebmetroError};
+use crate::events::{Event, EventHub};
+use crate::fixers::{AudioChoice, AudioTrackSelector, ChunkProcessor, ChunkStream, ChunkTimecodeFixer, DriftCorrector, GapDetector, Pipeline, SilenceFiller};
+use crate::fmp4::Fmp4Muxer;
+use crate::history::History;
+use crate::metadata::ChannelMetadata;
+use crate::muxer::{mux, Muxer};
+use crate::hls::{master_playlist, HlsOptions, HlsPackager, Variant};
+use crate::ogg::OggOpusMuxer;
+use crate::recorder::WebmFileWriter;
+use crate::stream_parser::StreamEbml;
+use crate::webm::{doc_type, TrackEntry, WebmElement, CLUSTER_ID};
+
+pub use crate::muxer::MediaFormat;
+
+/// How many chunks a channel's recorder or mirror may fall behind before skipping ahead
+const BACKGROUND_QUEUE_LIMIT: usize = 32;
+
+/// How many ms of media a source's bitrate is averaged over, to hold it to
+/// a channel's `max_bitrate`
+const BITRATE_CAP_WINDOW: u64 = 3000;
+
+/// The least media, in ms, a source's bitrate is judged on, so a single
+/// short Cluster can't trip the cap
+const BITRATE_CAP_MINIMUM: u64 = 1000;
+
+/// How many independently locked parts the channel map is split into, so
+/// requests for different channels rarely wait on each other
+const CHANNEL_SHARDS: usize = 16;
+
+type ChannelMap = HashMap<String, Weak<Mutex<Channel>>>;
+
+/// Something running for a channel (e.g. the target it's mirrored to, or the
+/// packager that serves it as HLS), and a sender that stops it when dropped
+type Stoppable<T> = (T, oneshot::Sender<()>);
+
+/// Makes a processing stage for one stream through a channel, given the
+/// channel's name; see `Relay::with_ingest_stage` & `with_egress_stage`
+pub type StageFactory = Arc<dyn Fn(&str) -> Box<dyn ChunkProcessor> + Send + Sync>;
+
+/// Told the name of each channel a listener or HLS packager starts on; see
+/// `Relay::on_demand`
+type DemandHandler = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// The buffer limit applied to ingest by default; neither a cluster nor the
+/// initialization segment may be larger than this.
+pub const DEFAULT_BUFFER_LIMIT: usize = 2 * 1024 * 1024;
+
+/// How many ms a source's timecodes may jump ahead between Clusters before
+/// the relay marks a discontinuity, by default
+pub const DEFAULT_GAP_THRESHOLD: u64 = 1000;
+
+/// How long a channel stays paused, by default, before it's resumed anyway
+pub const DEFAULT_MAX_PAUSE: Duration = Duration::from_secs(30 * 60);
+
+/// Headers to send with a listener's response (and in answer to HEAD
+/// requests), unless `RelayOptions::media_headers` says otherwise
+pub const MEDIA_HEADERS: [(&str, &str); 3] = [
+    ("Content-Type", "video/webm"),
+    // keep proxies like nginx from buffering the stream
+    ("X-Accel-Buffering", "no"),
+    ("Cache-Control", "no-cache, no-store"),
+];
+
+/// What a request to a channel's URL is asking for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    /// HEAD: just the response headers
+    Probe,
+    /// GET: watch the channel
+    Listen,
+    /// POST or PUT: send a stream to the channel
+    Publish,
+    /// switch recording the channel on or off
+    Record,
+    /// start or stop mirroring the channel to another server
+    Mirror,
+    /// follow the channel's lifecycle events (or every channel's, if the
+    /// channel name is empty)
+    Monitor,
+    /// read or change the channel's stored configuration
+    Configure,
+    /// pause or resume the channel between sources
+    Pause,
+}
+
+impl Action {
+    pub fn from_method(method: &str) -> Option<Action> {
+        match method {
+            "HEAD" => Some(Action::Probe),
+            "GET" => Some(Action::Listen),
+            "POST" | "PUT" => Some(Action::Publish),
+            _ => None
+        }
+    }
+
+    /// Whether the action changes what the relay does rather than using a
+    /// channel, so it's left to the admin Authorizer, which denies it unless
+    /// told otherwise (see `Relay::with_admin_authorizer`)
+    pub fn is_admin(&self) -> bool {
+        matches!(self, Action::Record | Action::Mirror | Action::Configure | Action::Pause)
+    }
+}
+
+/// The details of a request an `Authorizer` may consider
+#[derive(Clone, Debug)]
+pub struct AccessRequest {
+    pub action: Action,
+    pub channel: String,
+    /// the request's Authorization header or other credentials, if any
+    pub credentials: Option<String>,
+    pub remote: Option<SocketAddr>,
+    /// the names (CN & SANs) on the client's verified TLS certificate, if it
+    /// presented one; see `certificate_names`
+    pub client_names: Vec<String>,
+}
+
+/// A URL fit for logs: any `token` query parameter (a bearer token, passed
+/// that way by clients that can't set headers) has its value blanked out
+pub fn redact_token(url: &str) -> Cow<str> {
+    let is_token = |parameter: &str| parameter == "token" || parameter.starts_with("token=");
+    let query_start = match url.find('?') {
+        Some(question) => question + 1,
+        None => return Cow::Borrowed(url),
+    };
+    let (base, rest) = url.split_at(query_start);
+    let (query, fragment) = rest.split_at(rest.find('#').unwrap_or_else(|| rest.len()));
+    if !query.split('&').any(is_token) {
+        return Cow::Borrowed(url);
+    }
+    let query: Vec<&str> = query.split('&')
+        .map(|parameter| if is_token(parameter) { "token=redacted" } else { parameter })
+        .collect();
+    Cow::Owned(format!("{}{}{}", base, query.join("&"), fragment))
+}
+
+/// Who a listener is, for its stats
+#[derive(Clone, Debug, Default)]
+pub struct Viewer {
+    pub remote: Option<SocketAddr>,
+    /// a token from `Relay::new_session`, so the viewer can look up its own
+    /// stats with `Relay::session_stats`
+    pub session: Option<String>,
+    /// the one audio track to send, if the stream has several (e.g. one per
+    /// language); the others' blocks & TrackEntries are left out
+    pub audio: Option<AudioChoice>,
+}
+
+/// A hook deciding whether requests may proceed
+pub trait Authorizer: Send + Sync {
+    fn authorize(&self, request: &AccessRequest) -> BoxFuture<'static, bool>;
+}
+
+/// A shared Authorizer, e.g. one chosen at runtime
+impl<A: Authorizer + ?Sized> Authorizer for Arc<A> {
+    fn authorize(&self, request: &AccessRequest) -> BoxFuture<'static, bool> {
+        (**self).authorize(request)
+    }
+}
+
+/// Lets every request through; the default
+pub struct AllowAll;
+
+impl Authorizer for AllowAll {
+    fn authorize(&self, _request: &AccessRequest) -> BoxFuture<'static, bool> {
+        ready(true).boxed()
+    }
+}
+
+/// Turns every request away; admin actions' default
+pub struct DenyAll;
+
+impl Authorizer for DenyAll {
+    fn authorize(&self, _request: &AccessRequest) -> BoxFuture<'static, bool> {
+        ready(false).boxed()
+    }
+}
+
+/// Lets through only requests presenting this token as a bearer token (or
+/// `?token=` parameter), e.g. the operator's, for admin actions
+pub struct AdminToken {
+    token: String,
+}
+
+impl AdminToken {
+    pub fn new(token: &str) -> Self {
+        AdminToken {
+            token: token.to_string(),
+        }
+    }
+}
+
+impl Authorizer for AdminToken {
+    fn authorize(&self, request: &AccessRequest) -> BoxFuture<'static, bool> {
+        let presented = request.credentials.as_ref()
+            .filter(|credentials| credentials.starts_with("Bearer "))
+            .map_or(false, |credentials| same_key(&credentials["Bearer ".len()..], &self.token));
+        ready(presented).boxed()
+    }
+}
+
+/// Lets sources publish only if their TLS client certificate carries a name
+/// that's allowed to publish to the channel, and leaves every other request
+/// (and the sources it admits) to the next Authorizer
+pub struct CertificateAuthorizer {
+    /// certificate names, and the channel name prefixes they may publish to
+    rules: Vec<(String, String)>,
+    next: Arc<dyn Authorizer>,
+}
+
+impl CertificateAuthorizer {
+    pub fn new(next: impl Authorizer + 'static) -> Self {
+        CertificateAuthorizer {
+            rules: Vec::new(),
+            next: Arc::new(next),
+        }
+    }
+
+    /// Let a certificate with this CN or SAN publish to channels whose names
+    /// start with `prefix` (an empty prefix allows every channel)
+    pub fn allow(mut self, name: &str, prefix: &str) -> Self {
+        self.rules.push((name.to_string(), prefix.to_string()));
+        self
+    }
+
+    pub fn permits(&self, request: &AccessRequest) -> bool {
+        self.rules.iter().any(|(name, prefix)| {
+            request.channel.starts_with(prefix.as_str()) && request.client_names.contains(name)
+        })
+    }
+}
+
+impl Authorizer for CertificateAuthorizer {
+    fn authorize(&self, request: &AccessRequest) -> BoxFuture<'static, bool> {
+        if request.action == Action::Publish && !self.permits(request) {
+            return ready(false).boxed();
+        }
+        self.next.authorize(request)
+    }
+}
+
+/// Collect the names on a client certificate, as a TLS-terminating proxy
+/// describes it: the CNs from its subject (e.g. nginx's `$ssl_client_s_dn`,
+/// an RFC 2253 DN like "CN=a,O=b", or the older "/O=b/CN=a" form), and its
+/// subject alternative names, comma-separated & optionally typed (e.g.
+/// "DNS:a, email:b@c"). A subject that can't be parsed contributes no names.
+pub fn certificate_names(subject: Option<&str>, alt_names: Option<&str>) -> Vec<String> {
+    let mut names = Vec::new();
+    let attributes = match subject {
+        Some(subject) if subject.starts_with('/') => {
+            subject.split('/')
+                .filter_map(|part| {
+                    let mut parts = part.splitn(2, '=');
+                    Some((parts.next()?.trim().to_string(), parts.next()?.to_string()))
+                })
+                .collect()
+        },
+        Some(subject) => parse_dn(subject).unwrap_or_default(),
+        None => Vec::new(),
+    };
+    for (kind, value) in attributes {
+        if (kind.eq_ignore_ascii_case("CN") || kind == "2.5.4.3") && !value.is_empty() {
+            names.push(value);
+        }
+    }
+    for name in alt_names.into_iter().flat_map(|names| names.split(',')) {
+        let name = name.trim();
+        let name = match name.find(':') {
+            Some(colon) if matches!(&name[..colon], "DNS" | "email" | "URI") => &name[colon + 1..],
+            _ => name,
+        };
+        if !name.is_empty() {
+            names.push(name.to_string());
+        }
+    }
+    names
+}
+
+/// Split an RFC 2253 distinguished name into its attributes' types & values,
+/// undoing escapes (`\,`, `\2C`), quoting, and `#`-prefixed BER strings
+fn parse_dn(dn: &str) -> Option<Vec<(String, String)>> {
+    let dn = dn.as_bytes();
+    let mut attributes = Vec::new();
+    let mut at = 0;
+    let skip_spaces = |at: &mut usize| {
+        while dn.get(*at) == Some(&b' ') {
+            *at += 1;
+        }
+    };
+    skip_spaces(&mut at);
+    if at == dn.len() {
+        return Some(attributes);
+    }
+    loop {
+        let equals = at + dn[at..].iter().position(|&byte| byte == b'=')?;
+        let kind = std::str::from_utf8(&dn[at..equals]).ok()?.trim().to_string();
+        if kind.is_empty() {
+            return None;
+        }
+        at = equals + 1;
+        skip_spaces(&mut at);
+
+        let mut value = Vec::new();
+        match dn.get(at) {
+            Some(b'#') => {
+                at += 1;
+                let start = at;
+                at += dn[at..].iter().take_while(|byte| byte.is_ascii_hexdigit()).count();
+                value = ber_string(&hex_bytes(&dn[start..at])?)?.to_vec();
+            },
+            Some(b'"') => {
+                at += 1;
+                loop {
+                    match *dn.get(at)? {
+                        b'"' => break,
+                        b'\\' => {
+                            let (byte, length) = dn_escape(&dn[at + 1..])?;
+                            value.push(byte);
+                            at += length;
+                        },
+                        byte => value.push(byte),
+                    }
+                    at += 1;
+                }
+                at += 1;
+            },
+            _ => {
+                // unescaped trailing spaces aren't part of the value
+                let mut kept = 0;
+                while let Some(&byte) = dn.get(at) {
+                    match byte {
+                        b',' | b';' | b'+' => break,
+                        b'\\' => {
+                            let (byte, length) = dn_escape(&dn[at + 1..])?;
+                            value.push(byte);
+                            at += length;
+                            kept = value.len();
+                        },
+                        b' ' => value.push(byte),
+                        byte => {
+                            value.push(byte);
+                            kept = value.len();
+                        },
+                    }
+                    at += 1;
+                }
+                value.truncate(kept);
+            },
+        }
+        attributes.push((kind, String::from_utf8(value).ok()?));
+
+        skip_spaces(&mut at);
+        match dn.get(at) {
+            None => return Some(attributes),
+            Some(b',') | Some(b';') | Some(b'+') => {
+                at += 1;
+                skip_spaces(&mut at);
+            },
+            Some(_) => return None,
+        }
+    }
+}
+
+/// Decode the escape after a backslash: a special character, or a byte as two
+/// hex digits, returning the byte & how many characters the escape used
+fn dn_escape(escape: &[u8]) -> Option<(u8, usize)> {
+    match *escape.first()? {
+        byte @ b',' | byte @ b'=' | byte @ b'+' | byte @ b'<' | byte @ b'>' | byte @ b'#'
+            | byte @ b';' | byte @ b'\\' | byte @ b'"' | byte @ b' ' => Some((byte, 1)),
+        _ => Some((hex_bytes(escape.get(..2)?)?[0], 2)),
+    }
+}
+
+fn hex_bytes(hex: &[u8]) -> Option<Vec<u8>> {
+    let pairs = hex.chunks_exact(2);
+    if !pairs.remainder().is_empty() {
+        return None;
+    }
+    pairs
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+/// The contents of a BER-encoded string (UTF8String, PrintableString,
+/// T61String, or IA5String), as a `#`-prefixed attribute value holds it
+fn ber_string(ber: &[u8]) -> Option<&[u8]> {
+    match ber {
+        [0x0c, length, contents @ ..] | [0x13, length, contents @ ..]
+            | [0x14, length, contents @ ..] | [0x16, length, contents @ ..]
+            if *length < 0x80 && contents.len() == *length as usize => Some(contents),
+        _ => None,
+    }
+}
+
+/// Admits bursts of up to `burst` requests, refilling at `rate` per second
+pub struct TokenBucket {
+    rate: f64,
+    burst: f64,
+    /// the tokens left, as of when they were last counted
+    tokens: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    pub fn new(rate: f64, burst: f64) -> TokenBucket {
+        TokenBucket {
+            rate,
+            burst,
+            tokens: Mutex::new((burst, Instant::now())),
+        }
+    }
+
+    /// Spend a token if there's one to spend
+    pub fn take(&self) -> bool {
+        self.take_at(Instant::now())
+    }
+
+    fn take_at(&self, now: Instant) -> bool {
+        let mut tokens = self.tokens.lock().expect("Locking token bucket");
+        let elapsed = now.saturating_duration_since(tokens.1);
+        let refilled = tokens.0 + elapsed.as_secs_f64() * self.rate;
+        *tokens = (refilled.min(self.burst), now.max(tokens.1));
+        if tokens.0 >= 1.0 {
+            tokens.0 -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// A stream that keeps a guard (e.g. what counts a listener) until it's dropped
+pub struct Holding<S, G> {
+    stream: Pin<Box<S>>,
+    _guard: G,
+}
+
+impl<S: Stream, G> Holding<S, G> {
+    pub fn new(stream: S, guard: G) -> Self {
+        Holding {
+            stream: Box::pin(stream),
+            _guard: guard,
+        }
+    }
+}
+
+impl<S: Stream, G: Unpin> Stream for Holding<S, G> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Option<S::Item>> {
+        self.stream.as_mut().poll_next(cx)
+    }
+}
+
+/// Renditions of a simulcast channel (e.g. its 720p & 360p versions) are
+/// channels of their own, named for the channel & rendition, e.g. "main/720p"
+pub fn rendition_channel(channel: &str, rendition: &str) -> String {
+    format!("{}/{}", channel, rendition)
+}
+
+/// Split a rendition's channel name into the simulcast channel it belongs to
+/// & the rendition, e.g. "main/720p" into ("main", "720p")
+pub fn split_rendition(name: &str) -> Option<(&str, &str)> {
+    let slash = name.find('/')?;
+    Some((&name[..slash], &name[slash + 1..]))
+}
+
+/// How one rendition of a simulcast channel is doing
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RenditionStatus {
+    pub rendition: String,
+    pub live: bool,
+    pub listeners: usize,
+    /// the source's latest bitrate, while it's publishing
+    #[serde(rename = "bps")]
+    pub bits_per_second: Option<u64>,
+}
+
+/// A simulcast channel's status as JSON describes it
+#[derive(Serialize)]
+struct SimulcastJson<'a> {
+    channel: &'a str,
+    live: bool,
+    listeners: usize,
+    meta: Option<&'a ChannelMetadata>,
+    renditions: &'a [RenditionStatus],
+}
+
+/// A simulcast channel's combined status, as a JSON object: whether any
+/// rendition is live, how many listeners they have together, its metadata
+/// (or null), and each rendition's own status, e.g.
+/// `{"channel":"main","live":true,"listeners":3,"meta":null,"renditions":[{"rendition":"720p","live":true,"listeners":3,"bps":2500000}]}`
+pub fn simulcast_json(channel: &str, metadata: Option<&ChannelMetadata>, renditions: &[RenditionStatus]) -> String {
+    let status = SimulcastJson {
+        channel,
+        live: renditions.iter().any(|status| status.live),
+        listeners: renditions.iter().map(|status| status.listeners).sum(),
+        meta: metadata,
+        renditions,
+    };
+    serde_json::to_string(&status).expect("Serializing simulcast status")
+}
+
+/// A number for JSON, to at most 3 decimal places, and without a fraction
+/// if it's whole (e.g. a frame rate of `30`, rather than `30.0`)
+struct Rounded(f64);
+
+impl Serialize for Rounded {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let rounded = (self.0 * 1000.0).round() / 1000.0;
+        if rounded.fract() == 0.0 && rounded.abs() < (1u64 << 53) as f64 {
+            serializer.serialize_i64(rounded as i64)
+        } else {
+            serializer.serialize_f64(rounded)
+        }
+    }
+}
+
+/// A track as a stream's manifest describes it
+#[derive(Serialize)]
+struct TrackJson<'a> {
+    number: u64,
+    #[serde(rename = "type")]
+    kind: &'static str,
+    codec_id: &'a str,
+    codec: Option<Cow<'static, str>>,
+    #[serde(flatten)]
+    format: Option<TrackFormat>,
+}
+
+/// The picture or sound of a track, for its manifest
+#[derive(Serialize)]
+#[serde(untagged)]
+enum TrackFormat {
+    Video { width: u64, height: u64, frame_rate: Option<Rounded> },
+    Audio { channels: u64, sample_rate: Rounded },
+}
+
+/// A stream's manifest as JSON describes it
+#[derive(Serialize)]
+struct ManifestJson<'a> {
+    channel: &'a str,
+    live: bool,
+    meta: Option<&'a ChannelMetadata>,
+    mime_type: String,
+    tracks: Vec<TrackJson<'a>>,
+}
+
+/// Describes a channel's stream as a JSON object, for players to set up
+/// Media Source Extensions before fetching it: its MIME type (with the
+/// codecs browsers know), the channel's metadata (or null), and each track's
+/// codec & format, e.g.
+/// `{"channel":"main","live":true,"meta":null,"mime_type":"video/webm; codecs=\"vp9,opus\"","tracks":[{"number":1,"type":"video","codec_id":"V_VP9","codec":"vp9","width":1280,"height":720,"frame_rate":30},{"number":2,"type":"audio","codec_id":"A_OPUS","codec":"opus","channels":2,"sample_rate":48000}]}`
+pub fn manifest_json(channel: &str, live: bool, metadata: Option<&ChannelMetadata>, tracks: &[TrackEntry]) -> String {
+    let codecs: Vec<Cow<str>> = tracks.iter().filter_map(TrackEntry::mse_codec).collect();
+    let media_type = if tracks.iter().any(TrackEntry::is_video) { "video" } else { "audio" };
+    let manifest = ManifestJson {
+        channel,
+        live,
+        meta: metadata,
+        mime_type: format!("{}/webm; codecs=\"{}\"", media_type, codecs.join(",")),
+        tracks: tracks.iter().map(|track| TrackJson {
+            number: track.number,
+            kind: if track.is_video() { "video" } else if track.is_audio() { "audio" } else { "other" },
+            codec_id: &track.codec_id,
+            codec: track.mse_codec(),
+            format: if track.is_video() {
+                Some(TrackFormat::Video {
+                    width: track.pixel_width,
+                    height: track.pixel_height,
+                    frame_rate: track.frame_rate().map(Rounded),
+                })
+            } else if track.is_audio() {
+                Some(TrackFormat::Audio {
+                    channels: track.channels,
+                    sample_rate: Rounded(track.sampling_frequency),
+                })
+            } else {
+                None
+            },
+        }).collect(),
+    };
+    serde_json::to_string(&manifest).expect("Serializing a manifest")
+}
+
+/// Where a channel name in a request actually leads
+#[derive(Clone, Debug, PartialEq)]
+pub enum Route {
+    /// serve this channel
+    Channel(String),
+    /// send the client to this channel's URL instead (e.g. with a 307)
+    Redirect(String),
+}
+
+/// How a Relay treats its publishers & listeners
+#[derive(Clone, Debug)]
+pub struct RelayOptions {
+    /// how many chunks a listener may fall behind before its lag policy applies
+    pub queue_limit: usize,
+    pub lag_policy: LagPolicy,
+    /// the soft limit for the ingest parser & chunker
+    pub buffer_limit: usize,
+    /// skip past corrupt data from publishers instead of disconnecting them
+    pub resync: bool,
+    /// the most bytes all channels' retained clusters, listener queues, and
+    /// in-progress ingest may hold together before the relay sheds load
+    pub memory_limit: Option<usize>,
+    /// the most listeners one client address may have open at once
+    pub max_listeners_per_ip: Option<usize>,
+    /// headers to send with a listener's response, starting out as `MEDIA_HEADERS`
+    pub media_headers: Vec<(String, String)>,
+    /// split sources' Clusters once they span this many ms, e.g. to keep
+    /// LL-HLS parts short
+    pub max_cluster_duration: Option<u64>,
+    /// mark sources' Clusters that start more than this many ms after the
+    /// last one ended as discontinuities
+    pub gap_threshold: u64,
+    /// shift sources' audio back in step with their video when the two
+    /// drift more than this many ms apart
+    pub max_av_drift: Option<u64>,
+    /// fill gaps longer than this many ms in sources' Opus tracks with silence
+    pub fill_silence: Option<u64>,
+    pub hls: HlsOptions,
+    /// how many ms of each channel to keep for listeners that rewind
+    pub dvr_window: Option<u64>,
+    /// end sources once they've published for this many ms
+    pub max_publish_duration: Option<u64>,
+    /// end listeners' streams when their channel's source disconnects (after
+    /// what it sent), rather than leaving them waiting for the next source
+    pub end_with_source: bool,
+    /// with `end_with_source`, leave listeners waiting when a source
+    /// disconnects, in case it reconnects, until `Relay::end_listeners` is called
+    pub reconnect_grace: bool,
+    /// the most bits per second sources may publish, measured over their
+    /// last few seconds of media
+    pub max_bitrate: Option<u64>,
+    pub bitrate_action: BitrateAction,
+    /// resume channels that have been paused this long, ending their
+    /// listeners if no source has come back
+    pub max_pause: Duration,
+}
+
+impl RelayOptions {
+    /// Replace the media header with this name (ignoring case), or add it if
+    /// there isn't one; `None` removes it instead
+    pub fn set_media_header(&mut self, name: &str, value: Option<&str>) {
+        let position = self.media_headers.iter().position(|(header, _)| header.eq_ignore_ascii_case(name));
+        match (position, value) {
+            (Some(position), Some(value)) => self.media_headers[position] = (name.to_string(), value.to_string()),
+            (None, Some(value)) => self.media_headers.push((name.to_string(), value.to_string())),
+            (Some(position), None) => {
+                self.media_headers.remove(position);
+            },
+            (None, None) => {}
+        }
+    }
+}
+
+impl Default for RelayOptions {
+    fn default() -> RelayOptions {
+        RelayOptions {
+            queue_limit: DEFAULT_QUEUE_LIMIT,
+            lag_policy: LagPolicy::Disconnect,
+            buffer_limit: DEFAULT_BUFFER_LIMIT,
+            resync: false,
+            memory_limit: None,
+            max_listeners_per_ip: None,
+            media_headers: MEDIA_HEADERS.iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            max_cluster_duration: None,
+            gap_threshold: DEFAULT_GAP_THRESHOLD,
+            max_av_drift: None,
+            fill_silence: None,
+            hls: HlsOptions::default(),
+            dvr_window: None,
+            max_publish_duration: None,
+            end_with_source: false,
+            reconnect_grace: false,
+            max_bitrate: None,
+            bitrate_action: BitrateAction::End,
+            max_pause: DEFAULT_MAX_PAUSE,
+        }
+    }
+}
+
+/// What happens to a source publishing over its channel's `max_bitrate`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BitrateAction {
+    /// log a warning each time it goes over
+    Warn,
+    /// end it with `LimitExceeded`, before the Cluster that took it over
+    End,
+}
+
+impl FromStr for BitrateAction {
+    type Err = WebmetroError;
+
+    fn from_str(action: &str) -> Result<BitrateAction, WebmetroError> {
+        match action {
+            "warn" => Ok(BitrateAction::Warn),
+            "end" => Ok(BitrateAction::End),
+            _ => Err(WebmetroError::ApplicationError {
+                message: format!("Unknown bitrate action \"{}\" (expected warn or end)", action),
+            })
+        }
+    }
+}
+
+/// A source's bitrate over its last `BITRATE_CAP_WINDOW` of media, going by
+/// its Clusters' sizes & timecodes rather than when they arrive
+#[derive(Default)]
+struct RollingBitrate {
+    /// recent Clusters' start & end timecodes, and sizes
+    clusters: VecDeque<(u64, u64, usize)>,
+}
+
+impl RollingBitrate {
+    /// Count a chunk, returning the bitrate once enough media has arrived
+    fn observe(&mut self, chunk: &Chunk) -> Option<u64> {
+        let head = match chunk {
+            Chunk::Cluster(head, _) => head,
+            // a new initialization segment means a new stream, with new timecodes
+            Chunk::Headers { .. } => {
+                self.clusters.clear();
+                return None;
+            },
+            _ => return None,
+        };
+        if self.clusters.back().map_or(false, |&(start, _, _)| head.start < start) {
+            self.clusters.clear();
+        }
+        self.clusters.push_back((head.start, head.end, chunk.size()));
+        while self.clusters.len() > 1 && head.end.saturating_sub(self.clusters[0].0) > BITRATE_CAP_WINDOW {
+            self.clusters.pop_front();
+        }
+
+        let elapsed = head.end.saturating_sub(self.clusters[0].0);
+        if elapsed < BITRATE_CAP_MINIMUM {
+            return None;
+        }
+        let bytes: usize = self.clusters.iter().map(|&(_, _, size)| size).sum();
+        Some(bytes as u64 * 8 * 1000 / elapsed)
+    }
+}
+
+/// How a source publishes to a channel; see `Relay::publish_with`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct SourceOptions {
+    /// replace the channel's current source, instead of failing if it has one
+    pub takeover: bool,
+    /// pause the channel when the source disconnects (see `Relay::set_paused`)
+    pub pause_on_end: bool,
+}
+
+/// A set of named channels, each of which may have one publisher & many listeners.
+/// Channels are created on first use, and forgotten once nothing refers to them.
+/// The channel map is sharded by name, so lookups for different channels
+/// rarely contend for the same lock.
+pub struct Relay {
+    shards: Vec<Mutex<ChannelMap>>,
+    hasher: RandomState,
+    options: RelayOptions,
+    budget: Arc<MemoryBudget>,
+    /// dropping a channel's sender ends its recording
+    recordings: Mutex<HashMap<String, oneshot::Sender<()>>>,
+    /// each mirrored channel's target
+    mirrors: Mutex<HashMap<String, Stoppable<String>>>,
+    /// each channel packaged for HLS
+    hls: Mutex<HashMap<String, Stoppable<Arc<HlsPackager>>>>,
+    /// alternate names for channels; read on every request, rarely changed
+    aliases: RwLock<HashMap<String, Route>>,
+    /// channels' DVR windows where they differ from `RelayOptions::dvr_window`
+    dvr_windows: RwLock<HashMap<String, u64>>,
+    /// channels' publishing limits where they differ from
+    /// `RelayOptions::max_publish_duration`
+    max_publish_durations: RwLock<HashMap<String, u64>>,
+    /// channels' bitrate caps where they differ from `RelayOptions::max_bitrate`;
+    /// shared with sources, which check them as they go
+    max_bitrates: Arc<RwLock<HashMap<String, u64>>>,
+    /// when each paused channel is resumed regardless; kept by name, so a
+    /// pause outlasts the channel while nobody's listening
+    pauses: Arc<Mutex<HashMap<String, Instant>>>,
+    /// what publishers have said about their channels
+    metadata: RwLock<HashMap<String, ChannelMetadata>>,
+    events: Arc<EventHub>,
+    history: Arc<History>,
+    /// how many listeners each client address has open
+    listeners_by_ip: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    authorizer: Arc<dyn Authorizer>,
+    /// decides admin actions instead of `authorizer`
+    admin: Arc<dyn Authorizer>,
+    ingest_stages: Vec<StageFactory>,
+    egress_stages: Vec<StageFactory>,
+    demand: RwLock<Option<DemandHandler>>,
+}
+
+impl Relay {
+    pub fn new(options: RelayOptions) -> Relay {
+        let budget = match options.memory_limit {
+            Some(limit) => MemoryBudget::new(limit),
+            None => MemoryBudget::unlimited(),
+        };
+        Relay {
+            shards: (0..CHANNEL_SHARDS).map(|_| Mutex::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+            options,
+            budget,
+            recordings: Mutex::new(HashMap::new()),
+            mirrors: Mutex::new(HashMap::new()),
+            hls: Mutex::new(HashMap::new()),
+            aliases: RwLock::new(HashMap::new()),
+            dvr_windows: RwLock::new(HashMap::new()),
+            max_publish_durations: RwLock::new(HashMap::new()),
+            max_bitrates: Arc::new(RwLock::new(HashMap::new())),
+            pauses: Arc::new(Mutex::new(HashMap::new())),
+            metadata: RwLock::new(HashMap::new()),
+            events: EventHub::new(),
+            history: Arc::new(History::default()),
+            listeners_by_ip: Arc::new(Mutex::new(HashMap::new())),
+            authorizer: Arc::new(AllowAll),
+            admin: Arc::new(DenyAll),
+            ingest_stages: Vec::new(),
+            egress_stages: Vec::new(),
+            demand: RwLock::new(None),
+        }
+    }
+
+    pub fn with_authorizer(mut self, authorizer: impl Authorizer + 'static) -> Self {
+        self.authorizer = Arc::new(authorizer);
+        self
+    }
+
+    /// Decide admin actions (see `Action::is_admin`) with this Authorizer,
+    /// rather than denying them all
+    pub fn with_admin_authorizer(mut self, authorizer: impl Authorizer + 'static) -> Self {
+        self.admin = Arc::new(authorizer);
+        self
+    }
+
+    /// Keep channels' session history here, e.g. one backed by a file
+    pub fn with_history(mut self, history: History) -> Self {
+        self.history = Arc::new(history);
+        self
+    }
+
+    /// Run each source's chunks through a stage `stage` makes for it, after
+    /// the built-in fixers, before they reach its channel's listeners
+    pub fn with_ingest_stage<P: ChunkProcessor + 'static>(mut self, stage: impl Fn(&str) -> P + Send + Sync + 'static) -> Self {
+        self.ingest_stages.push(Arc::new(move |name: &str| Box::new(stage(name)) as Box<dyn ChunkProcessor>));
+        self
+    }
+
+    /// Run the chunks each listener, mirror, and HLS packager gets through a
+    /// stage `stage` makes for it, after timecodes are fixed
+    pub fn with_egress_stage<P: ChunkProcessor + 'static>(mut self, stage: impl Fn(&str) -> P + Send + Sync + 'static) -> Self {
+        self.egress_stages.push(Arc::new(move |name: &str| Box::new(stage(name)) as Box<dyn ChunkProcessor>));
+        self
+    }
+
+    /// Call `handler` with a channel's name whenever a listener or HLS
+    /// packager starts on it, e.g. to fetch the channel from elsewhere
+    pub fn on_demand(&self, handler: impl Fn(&str) + Send + Sync + 'static) {
+        *self.demand.write().expect("Locking demand handler") = Some(Arc::new(handler));
+    }
+
+    fn demand(&self, name: &str) {
+        let handler = self.demand.read().expect("Locking demand handler").clone();
+        if let Some(handler) = handler {
+            handler(name);
+        }
+    }
+
+    pub fn options(&self) -> &RelayOptions {
+        &self.options
+    }
+
+    /// Each channel's broadcast sessions, which recorders add their files to
+    pub fn history(&self) -> &Arc<History> {
+        &self.history
+    }
+
+    /// The budget every channel & publisher charges their buffers to
+    pub fn budget(&self) -> &Arc<MemoryBudget> {
+        &self.budget
+    }
+
+    /// Look up a channel by name, opening it if it isn't live
+    pub fn channel(&self, name: &str) -> Handle {
+        let mut channels = self.shard(name).lock().expect("Locking channel map");
+        if let Some(channel) = channels.get(name).and_then(Weak::upgrade) {
+            return channel;
+        }
+        channels.retain(|_, channel| channel.strong_count() > 0);
+        let channel = Channel::with_budget(name.to_string(), self.budget.clone());
+        {
+            let mut opened = channel.lock().expect("Locking channel");
+            opened.set_dvr_window(self.dvr_window(name));
+            if self.pause_deadline(name).is_some() {
+                opened.set_paused(true);
+            }
+        }
+        channels.insert(name.to_string(), Arc::downgrade(&channel));
+        channel
+    }
+
+    /// The names of the channels currently open
+    pub fn channel_names(&self) -> Vec<String> {
+        let mut names = Vec::new();
+        for shard in &self.shards {
+            let channels = shard.lock().expect("Locking channel map");
+            names.extend(channels.iter()
+                .filter(|(_, channel)| channel.strong_count() > 0)
+                .map(|(name, _)| name.clone()));
+        }
+        names
+    }
+
+    fn shard(&self, name: &str) -> &Mutex<ChannelMap> {
+        let mut hasher = self.hasher.build_hasher();
+        name.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    /// Keep `window` ms of a channel for listeners that rewind, rather than
+    /// `RelayOptions::dvr_window`; `None` goes back to that
+    pub fn set_dvr_window(&self, name: &str, window: Option<u64>) {
+        {
+            let mut windows = self.dvr_windows.write().expect("Locking DVR windows");
+            match window {
+                Some(window) => windows.insert(name.to_string(), window),
+                None => windows.remove(name),
+            };
+        }
+        self.channel(name).lock().expect("Locking channel").set_dvr_window(self.dvr_window(name));
+    }
+
+    /// How many ms of a channel are kept for listeners that rewind, if any
+    pub fn dvr_window(&self, name: &str) -> Option<u64> {
+        let windows = self.dvr_windows.read().expect("Locking DVR windows");
+        windows.get(name).cloned().or(self.options.dvr_window)
+    }
+
+    /// End a channel's sources after they've published for `duration` ms,
+    /// rather than `RelayOptions::max_publish_duration`; `None` goes back to
+    /// that. Applies from the next source on.
+    pub fn set_max_publish_duration(&self, name: &str, duration: Option<u64>) {
+        let mut durations = self.max_publish_durations.write().expect("Locking publishing limits");
+        match duration {
+            Some(duration) => durations.insert(name.to_string(), duration),
+            None => durations.remove(name),
+        };
+    }
+
+    /// How many ms a channel's sources may publish for, if there's a limit
+    pub fn max_publish_duration(&self, name: &str) -> Option<u64> {
+        let durations = self.max_publish_durations.read().expect("Locking publishing limits");
+        durations.get(name).cloned().or(self.options.max_publish_duration)
+    }
+
+    /// Hold a channel's sources to `bitrate` bits per second, rather than
+    /// `RelayOptions::max_bitrate`; `None` goes back to that. Applies right
+    /// away, to a source that's already publishing too.
+    pub fn set_max_bitrate(&self, name: &str, bitrate: Option<u64>) {
+        let mut bitrates = self.max_bitrates.write().expect("Locking bitrate caps");
+        match bitrate {
+            Some(bitrate) => bitrates.insert(name.to_string(), bitrate),
+            None => bitrates.remove(name),
+        };
+    }
+
+    /// The most bits per second a channel's sources may publish, if there's a cap
+    pub fn max_bitrate(&self, name: &str) -> Option<u64> {
+        let bitrates = self.max_bitrates.read().expect("Locking bitrate caps");
+        bitrates.get(name).cloned().or(self.options.max_bitrate)
+    }
+
+    /// Describe a channel with a publisher's metadata, replacing what was
+    /// there (or forgetting it, with `None`), and announce the change. It's
+    /// kept across sources, until replaced or `expire_metadata` finds the
+    /// channel closed.
+    pub fn set_metadata(&self, name: &str, metadata: Option<ChannelMetadata>) {
+        {
+            let mut channels = self.metadata.write().expect("Locking channel metadata");
+            match metadata.clone() {
+                Some(metadata) => channels.insert(name.to_string(), metadata),
+                None => channels.remove(name),
+            };
+        }
+        self.events.emit(Event::Metadata {
+            channel: name.to_string(),
+            metadata: metadata.unwrap_or_default(),
+        });
+    }
+
+    /// Forget the metadata of channels that have closed, with neither a
+    /// source nor listeners (nor, for a simulcast channel, open renditions),
+    /// announcing each as described by nothing
+    pub fn expire_metadata(&self) {
+        let open: HashSet<String> = self.channel_names().into_iter()
+            .flat_map(|name| {
+                let group = split_rendition(&name).map(|(group, _)| group.to_string());
+                std::iter::once(name).chain(group)
+            })
+            .collect();
+        let expired: Vec<String> = {
+            let mut channels = self.metadata.write().expect("Locking channel metadata");
+            let expired: Vec<String> = channels.keys().filter(|name| !open.contains(*name)).cloned().collect();
+            for name in &expired {
+                channels.remove(name);
+            }
+            expired
+        };
+        for channel in expired {
+            self.events.emit(Event::Metadata {
+                channel,
+                metadata: ChannelMetadata::default(),
+            });
+        }
+    }
+
+    /// Whether a channel has a source, or is a simulcast channel with a
+    /// rendition that has one
+    pub fn is_live(&self, name: &str) -> bool {
+        self.has_source(name) || self.rendition_status(name).iter().any(|rendition| rendition.live)
+    }
+
+    /// A channel's metadata, if its publisher gave any; a rendition without
+    /// its own shares its simulcast channel's
+    pub fn metadata(&self, name: &str) -> Option<ChannelMetadata> {
+        let channels = self.metadata.read().expect("Locking channel metadata");
+        channels.get(name)
+            .or_else(|| split_rendition(name).and_then(|(group, _)| channels.get(group)))
+            .cloned()
+    }
+
+    /// Make `alias` another name for the channel `target`, either served
+    /// directly or redirected to. Aliases aren't followed any further, so
+    /// `target` should be a real channel name.
+    pub fn set_alias(&self, alias: &str, target: &str, redirect: bool) {
+        let route = if redirect {
+            Route::Redirect(target.to_string())
+        } else {
+            Route::Channel(target.to_string())
+        };
+        self.aliases.write().expect("Locking alias table").insert(alias.to_string(), route);
+    }
+
+    pub fn remove_alias(&self, alias: &str) {
+        self.aliases.write().expect("Locking alias table").remove(alias);
+    }
+
+    /// Look a requested channel name up in the alias table; a rendition of
+    /// an alias is the same rendition of the channel it stands for
+    pub fn resolve(&self, name: &str) -> Route {
+        let aliases = self.aliases.read().expect("Locking alias table");
+        if let Some(route) = aliases.get(name) {
+            return route.clone();
+        }
+        match split_rendition(name).and_then(|(group, rendition)| Some((aliases.get(group)?, rendition))) {
+            Some((Route::Channel(channel), rendition)) => Route::Channel(rendition_channel(channel, rendition)),
+            Some((Route::Redirect(channel), rendition)) => Route::Redirect(rendition_channel(channel, rendition)),
+            None => Route::Channel(name.to_string()),
+        }
+    }
+
+    /// The renditions of a simulcast channel that are open, sorted by name
+    pub fn renditions(&self, name: &str) -> Vec<String> {
+        let mut renditions: Vec<String> = self.channel_names().iter()
+            .filter_map(|channel| match split_rendition(channel) {
+                Some((group, rendition)) if group == name => Some(rendition.to_string()),
+                _ => None,
+            })
+            .collect();
+        renditions.sort();
+        renditions
+    }
+
+    /// How each of a simulcast channel's open renditions is doing
+    pub fn rendition_status(&self, name: &str) -> Vec<RenditionStatus> {
+        self.renditions(name).into_iter().map(|rendition| {
+            let channel = rendition_channel(name, &rendition);
+            RenditionStatus {
+                live: self.has_source(&channel),
+                listeners: self.listener_count(&channel),
+                bits_per_second: self.events.bitrate(&channel),
+                rendition,
+            }
+        }).collect()
+    }
+
+    /// A master HLS playlist offering a simulcast channel's live renditions,
+    /// lowest bandwidth first, relative to `live/<channel>/hls/`; `None` if
+    /// none are live with a measured bitrate & headers MP4 can carry yet
+    pub fn master_playlist(&self, name: &str) -> Option<String> {
+        let mut variants: Vec<Variant> = self.rendition_status(name).into_iter()
+            .filter(|status| status.live)
+            .filter_map(|status| {
+                let channel = rendition_channel(name, &status.rendition);
+                let headers = self.init_segment(&channel)?;
+                let uri = format!("../{}/hls/index.m3u8", status.rendition);
+                match Variant::from_headers(uri, headers, status.bits_per_second?) {
+                    Ok(variant) => Some(variant),
+                    Err(err) => {
+                        warn!("Leaving {} out of the master playlist: {}", channel, err);
+                        None
+                    }
+                }
+            })
+            .collect();
+        if variants.is_empty() {
+            return None;
+        }
+        variants.sort_by_key(|variant| variant.bandwidth);
+        Some(master_playlist(&variants))
+    }
+
+    /// Follow sources starting & stopping, listener counts, and bitrates for
+    /// one channel, or every channel with `None`
+    pub fn subscribe(&self, channel: Option<&str>) -> impl Stream<Item = Event> + Send + Unpin {
+        self.events.subscribe(channel)
+    }
+
+    /// Events describing where channels stand, for a new subscriber to start
+    /// from before following `subscribe`: each live channel starting & its
+    /// bitrate, each watched channel's listener count, and each described
+    /// channel's metadata. Takes the same `channel` as `subscribe`.
+    pub fn snapshot(&self, channel: Option<&str>) -> Vec<Event> {
+        let mut names: Vec<String> = self.channel_names();
+        names.extend(self.metadata.read().expect("Locking channel metadata").keys().cloned());
+        let groups: Vec<String> = names.iter()
+            .filter_map(|name| split_rendition(name).map(|(group, _)| group.to_string()))
+            .collect();
+        names.extend(groups);
+        if let Some(channel) = channel {
+            names.retain(|name| name == channel || matches!(split_rendition(name), Some((group, _)) if group == channel));
+        }
+        names.sort();
+        names.dedup();
+
+        let mut events = Vec::new();
+        for name in names {
+            if self.is_live(&name) {
+                events.push(Event::PublishStart { channel: name.clone() });
+                if let Some(bits_per_second) = self.bitrate(&name) {
+                    events.push(Event::Bitrate { channel: name.clone(), bits_per_second });
+                }
+            }
+            let count = self.listener_count(&name);
+            if count > 0 {
+                events.push(Event::Listeners { channel: name.clone(), count });
+            }
+            let metadata = self.metadata.read().expect("Locking channel metadata").get(&name).cloned();
+            if let Some(metadata) = metadata {
+                events.push(Event::Metadata { channel: name, metadata });
+            }
+        }
+        events
+    }
+
+    /// Follow channel events like `subscribe`, without missing any however
+    /// far behind the subscriber falls (see `EventHub::subscribe_lossless`)
+    pub fn subscribe_lossless(&self, channel: Option<&str>) -> impl Stream<Item = Event> + Send + Unpin {
+        self.events.subscribe_lossless(channel)
+    }
+
+    /// How many listeners are watching a channel through `listen`
+    pub fn listener_count(&self, name: &str) -> usize {
+        self.events.listener_count(name)
+    }
+
+    /// A channel's source's latest bitrate, in bits per second, while it's publishing
+    pub fn bitrate(&self, name: &str) -> Option<u64> {
+        self.events.bitrate(name)
+    }
+
+    /// Take one of a client address's listener slots, to hold for as long as
+    /// it's listening; returns `None` if it already has as many as it may.
+    pub fn reserve_listener(&self, ip: IpAddr) -> Option<ListenerSlot> {
+        let mut counts = self.listeners_by_ip.lock().expect("Locking listener addresses");
+        let count = counts.entry(ip).or_insert(0);
+        if self.options.max_listeners_per_ip.map_or(false, |limit| *count >= limit) {
+            return None;
+        }
+        *count += 1;
+        Some(ListenerSlot {
+            counts: self.listeners_by_ip.clone(),
+            ip,
+        })
+    }
+
+    pub fn authorize(&self, request: &AccessRequest) -> impl Future<Output = bool> {
+        if request.action.is_admin() {
+            self.admin.authorize(request)
+        } else {
+            self.authorizer.authorize(request)
+        }
+    }
+
+    /// The WebM stream to send a new listener, starting with the initialization
+    /// segment & a keyframe, with timecodes kept monotonic across publishers.
+    /// With `RelayOptions::end_with_source`, it ends once the channel's source
+    /// disconnects, after the last chunks it sent, unless another publisher
+    /// is waiting to take over; otherwise it waits for the next publisher.
+    ///
+    /// Each Cluster's head & body are yielded as separate `Bytes`, shared with
+    /// the channel rather than copied; an HTTP server that supports vectored
+    /// writes (e.g. hyper with `http1_writev(true)`) can send them as they are.
+    pub fn listen(&self, name: &str) -> impl Stream<Item = Result<Bytes, WebmetroError>> + Send {
+        self.listen_from(name, Viewer::default(), MediaFormat::WebM)
+    }
+
+    /// Like `listen`, noting who the listener is for `listener_stats` &
+    /// `session_stats`, and labeling the stream as `format`
+    pub fn listen_from(&self, name: &str, viewer: Viewer, format: MediaFormat) -> impl Stream<Item = Result<Bytes, WebmetroError>> + Send {
+        self.listen_muxed(name, viewer, format.muxer())
+    }
+
+    /// Like `listen_from`, but in whichever container `muxer` produces
+    pub fn listen_muxed<M: Muxer + Send + 'static>(&self, name: &str, viewer: Viewer, muxer: M) -> impl Stream<Item = Result<Bytes, WebmetroError>> + Send {
+        mux(self.listener_chunks(name, viewer, None), muxer)
+    }
+
+    /// Like `listen_from`, but starting about `rewind` ms behind the live
+    /// edge, at a keyframe kept by `RelayOptions::dvr_window` (or as far
+    /// back as it goes), with timecodes kept monotonic as usual
+    pub fn listen_rewound(&self, name: &str, viewer: Viewer, format: MediaFormat, rewind: u64) -> impl Stream<Item = Result<Bytes, WebmetroError>> + Send {
+        mux(self.listener_chunks(name, viewer, Some(rewind)), format.muxer())
+    }
+
+    /// Like `listen_from`, but transmuxed to fragmented MP4: an
+    /// initialization segment followed by a fragment per Cluster
+    pub fn listen_cmaf(&self, name: &str, viewer: Viewer) -> impl Stream<Item = Result<Bytes, WebmetroError>> + Send {
+        self.listen_muxed(name, viewer, Fmp4Muxer::new())
+    }
+
+    /// Like `listen_from`, but remuxed to Ogg Opus, for channels whose only
+    /// track is Opus; fails at the first Headers otherwise
+    pub fn listen_ogg(&self, name: &str, viewer: Viewer) -> impl Stream<Item = Result<Bytes, WebmetroError>> + Send {
+        self.listen_muxed(name, viewer, OggOpusMuxer::new())
+    }
+
+    /// The tracks of the channel's latest initialization segment; `None` if
+    /// it hasn't had a source
+    pub fn tracks(&self, name: &str) -> Option<Vec<TrackEntry>> {
+        let channel = self.channel(name);
+        let channel = channel.lock().expect("Locking channel");
+        channel.headers()?;
+        Some(channel.tracks().to_vec())
+    }
+
+    /// The channel's latest initialization segment, for players that fetch
+    /// it separately from `listen_media`
+    pub fn init_segment(&self, name: &str) -> Option<Bytes> {
+        self.channel(name).lock().expect("Locking channel").headers()
+    }
+
+    /// A small, complete WebM file of the channel's headers & latest keyframe
+    /// Cluster, with a Duration, e.g. for dashboards' thumbnails; `None` if
+    /// the channel has no source, or hasn't retained a keyframe
+    pub fn preview(&self, name: &str) -> Result<Option<Bytes>, WebmetroError> {
+        let (headers, cluster) = {
+            let channel = self.channel(name);
+            let channel = channel.lock().expect("Locking channel");
+            match (channel.headers(), channel.keyframe_cluster()) {
+                (Some(headers), Some(cluster)) => (Chunk::Headers { bytes: headers }, cluster),
+                _ => return Ok(None),
+            }
+        };
+        let mut writer = WebmFileWriter::new(Cursor::new(Vec::new()), &headers)?;
+        writer.write_cluster(&cluster)?;
+        Ok(Some(Bytes::from(writer.finish()?.into_inner())))
+    }
+
+    /// Like `listen_from`, but only the Clusters (starting from a keyframe),
+    /// for players that append the initialization segment from
+    /// `init_segment` themselves and may reconnect without re-appending it.
+    /// Ends if a source with different headers takes over, so the player
+    /// can fetch the new ones & reconnect.
+    pub fn listen_media(&self, name: &str, viewer: Viewer) -> impl Stream<Item = Result<Bytes, WebmetroError>> + Send {
+        let mut headers: Option<Bytes> = None;
+        self.listener_chunks(name, viewer, None)
+            .try_take_while(move |chunk| {
+                let same_headers = match chunk {
+                    Chunk::Headers { bytes } => {
+                        let same = headers.as_ref().map_or(true, |headers| headers == bytes);
+                        headers = Some(bytes.clone());
+                        same
+                    },
+                    _ => true,
+                };
+                ready(Ok(same_headers))
+            })
+            .try_filter(|chunk| ready(match chunk {
+                Chunk::Headers { .. } => false,
+                _ => true,
+            }))
+            .map_ok(|chunk| iter(chunk).map(Result::<Bytes, WebmetroError>::Ok))
+            .try_flatten()
+    }
+
+    /// The stages a channel's chunks go through on their way out: the
+    /// timecode fixer, then any added with `with_egress_stage`
+    fn egress_pipeline(&self, name: &str) -> Pipeline {
+        let mut pipeline = Pipeline::new().with(ChunkTimecodeFixer::new());
+        for stage in &self.egress_stages {
+            pipeline.push(stage(name));
+        }
+        pipeline
+    }
+
+    /// A new listener's chunks, starting with the initialization segment & a
+    /// keyframe (`rewind` ms back, if given), with timecodes kept monotonic
+    /// across publishers, just the viewer's chosen audio track, and ending
+    /// with the source if `RelayOptions::end_with_source` says so
+    fn listener_chunks(&self, name: &str, viewer: Viewer, rewind: Option<u64>) -> impl Stream<Item = Result<Chunk, WebmetroError>> + Send {
+        self.demand(name);
+        // counted until the stream is dropped
+        let viewing = self.events.viewing(name);
+        let listener = match rewind {
+            Some(rewind) => Listener::rewound(self.channel(name), self.options.queue_limit, self.options.lag_policy, rewind),
+            None => Listener::with_policy(self.channel(name), self.options.queue_limit, self.options.lag_policy),
+        };
+        let mut pipeline = self.egress_pipeline(name);
+        if let Some(choice) = viewer.audio {
+            pipeline.push(AudioTrackSelector::new(choice));
+        }
+        let mut listener = listener
+            .with_remote(viewer.remote)
+            .with_session(viewer.session);
+        if self.options.end_with_source {
+            listener = listener.until_source_ends();
+        }
+        let chunks = listener
+            .into_chunks()
+            .map(Result::<Chunk, WebmetroError>::Ok)
+            .process_with(pipeline)
+            .find_starting_point();
+        Holding::new(chunks, viewing)
+    }
+
+    /// Switch recording a channel on or off. If this starts a new recording,
+    /// returns the chunks to write: they begin with the initialization segment
+    /// & a keyframe, and end at a Cluster boundary once recording is switched
+    /// off (or the returned stream is dropped, which also switches it off).
+    /// Its listener is critical, so it isn't skipped ahead or dropped for
+    /// lagging, short of stalling outright (see `channel::MAX_CRITICAL_QUEUE_BYTES`).
+    pub fn set_recording(&self, name: &str, enabled: bool) -> Option<impl Stream<Item = Result<Chunk, WebmetroError>> + Send> {
+        let mut recordings = self.recordings.lock().expect("Locking recording map");
+        if !enabled {
+            recordings.remove(name);
+            return None;
+        }
+        if recordings.get(name).map_or(false, |stop| !stop.is_canceled()) {
+            return None;
+        }
+
+        let (stop, stopped) = oneshot::channel::<()>();
+        recordings.insert(name.to_string(), stop);
+        let chunks = Listener::with_policy(self.channel(name), BACKGROUND_QUEUE_LIMIT, LagPolicy::DropToKeyframe)
+            .with_priority(ListenerPriority::Critical)
+            .into_chunks()
+            .map(Result::<Chunk, WebmetroError>::Ok)
+            .find_starting_point();
+        Some(until_stopped(chunks, stopped))
+    }
+
+    pub fn is_recording(&self, name: &str) -> bool {
+        let recordings = self.recordings.lock().expect("Locking recording map");
+        recordings.get(name).map_or(false, |stop| !stop.is_canceled())
+    }
+
+    /// Mirror a channel to `target` (any URL that accepts a WebM upload), or
+    /// stop mirroring it with `None`. If this starts a new mirror, returns the
+    /// chunks to send there, with timecodes kept monotonic across publishers;
+    /// pointing a live mirror at a new target ends the old mirror's stream.
+    pub fn set_mirror(&self, name: &str, target: Option<&str>) -> Option<impl Stream<Item = Result<Chunk, WebmetroError>> + Send> {
+        let mut mirrors = self.mirrors.lock().expect("Locking mirror map");
+        let target = match target {
+            Some(target) => target,
+            None => {
+                mirrors.remove(name);
+                return None;
+            }
+        };
+        if let Some((current, stop)) = mirrors.get(name) {
+            if current == target && !stop.is_canceled() {
+                return None;
+            }
+        }
+
+        let (stop, stopped) = oneshot::channel::<()>();
+        mirrors.insert(name.to_string(), (target.to_string(), stop));
+        let chunks = Listener::with_policy(self.channel(name), BACKGROUND_QUEUE_LIMIT, LagPolicy::DropToKeyframe)
+            .with_priority(ListenerPriority::Critical)
+            .into_chunks()
+            .map(Result::<Chunk, WebmetroError>::Ok)
+            .process_with(self.egress_pipeline(name))
+            .find_starting_point();
+        Some(until_stopped(chunks, stopped))
+    }
+
+    /// The chunks a channel's current source sends from here on (starting
+    /// at a keyframe), ending when it disconnects, e.g. to forward to other
+    /// relays. Its listener is best-effort: a consumer that falls behind is
+    /// skipped ahead to the next keyframe, rather than holding up the relay.
+    pub fn source_chunks(&self, name: &str) -> impl Stream<Item = Result<Chunk, WebmetroError>> + Send {
+        Listener::with_policy(self.channel(name), BACKGROUND_QUEUE_LIMIT, LagPolicy::DropToKeyframe)
+            .until_source_ends()
+            .into_chunks()
+            .map(Result::<Chunk, WebmetroError>::Ok)
+            .find_starting_point()
+    }
+
+    /// Where a channel is being mirrored to, if anywhere
+    pub fn mirror_target(&self, name: &str) -> Option<String> {
+        let mirrors = self.mirrors.lock().expect("Locking mirror map");
+        mirrors.get(name)
+            .filter(|(_, stop)| !stop.is_canceled())
+            .map(|(target, _)| target.clone())
+    }
+
+    /// A channel's HLS packager. If this starts it, also returns the future
+    /// that feeds it the channel's chunks, which runs until `expire_hls`
+    /// finds nobody has asked for the channel lately.
+    pub fn hls(&self, name: &str) -> (Arc<HlsPackager>, Option<impl Future<Output = ()> + Send>) {
+        let mut packagers = self.hls.lock().expect("Locking HLS map");
+        if let Some((packager, stop)) = packagers.get(name) {
+            if !stop.is_canceled() {
+                packager.touch();
+                return (packager.clone(), None);
+            }
+        }
+
+        let packager = Arc::new(HlsPackager::new(self.options.hls.clone()));
+        let (stop, stopped) = oneshot::channel::<()>();
+        packagers.insert(name.to_string(), (packager.clone(), stop));
+        drop(packagers);
+        self.demand(name);
+        let chunks = Listener::with_policy(self.channel(name), BACKGROUND_QUEUE_LIMIT, LagPolicy::DropToKeyframe)
+            .into_chunks()
+            .map(Result::<Chunk, WebmetroError>::Ok)
+            .process_with(self.egress_pipeline(name))
+            .find_starting_point();
+        let fed = packager.clone();
+        let packaging = until_stopped(chunks, stopped)
+            .try_for_each(move |chunk| {
+                fed.push(&chunk);
+                ready(Ok(()))
+            })
+            .map(|_| ());
+        (packager, Some(packaging))
+    }
+
+    /// Stop packaging channels for HLS that nobody has asked for in `idle`
+    pub fn expire_hls(&self, idle: Duration) {
+        let mut packagers = self.hls.lock().expect("Locking HLS map");
+        packagers.retain(|_, (packager, stop)| !stop.is_canceled() && packager.idle_time() < idle);
+    }
+
+    /// How far behind each of a channel's listeners is, including recorders &
+    /// mirrors (which have no remote address)
+    pub fn listener_stats(&self, name: &str) -> Vec<ListenerStats> {
+        self.channel(name).lock().expect("Locking channel").listener_stats()
+    }
+
+    /// A new, unguessable token for a listener to be identified by; see `Viewer`
+    pub fn new_session(&self) -> String {
+        let mut token = [0; 16];
+        getrandom::getrandom(&mut token).expect("Reading random bytes for a session token");
+        hex::encode(token)
+    }
+
+    /// The stats of the listener to a channel given this session token, if
+    /// it's still connected
+    pub fn session_stats(&self, name: &str, session: &str) -> Option<ListenerStats> {
+        self.channel(name).lock().expect("Locking channel").session_stats(session)
+    }
+
+    /// Pause a channel, so its listeners wait through a break between
+    /// sources (e.g. an ad break) rather than being ended when the source
+    /// disconnects; the next source resumes it, or else `RelayOptions::max_pause`
+    /// does. Resuming a channel without a source ends its listeners.
+    pub fn set_paused(&self, name: &str, paused: bool) {
+        {
+            let mut pauses = self.pauses.lock().expect("Locking pauses");
+            if paused {
+                pauses.insert(name.to_string(), Instant::now() + self.options.max_pause);
+            } else {
+                pauses.remove(name);
+            }
+        }
+        self.channel(name).lock().expect("Locking channel").set_paused(paused);
+    }
+
+    /// Whether a channel is paused, whether or not its source has gone yet
+    pub fn is_paused(&self, name: &str) -> bool {
+        self.expire_pauses();
+        self.channel(name).lock().expect("Locking channel").is_paused()
+    }
+
+    /// Resume the channels that have been paused for `RelayOptions::max_pause`
+    pub fn expire_pauses(&self) {
+        let now = Instant::now();
+        let expired: Vec<String> = {
+            let mut pauses = self.pauses.lock().expect("Locking pauses");
+            let expired = pauses.iter()
+                .filter(|(_, deadline)| **deadline <= now)
+                .map(|(name, _)| name.clone())
+                .collect();
+            pauses.retain(|_, deadline| *deadline > now);
+            expired
+        };
+        for name in expired {
+            info!("Channel {} has been paused too long", name);
+            self.channel(&name).lock().expect("Locking channel").set_paused(false);
+        }
+    }
+
+    /// When a channel's pause runs out, if it's paused
+    fn pause_deadline(&self, name: &str) -> Option<Instant> {
+        self.pauses.lock().expect("Locking pauses").get(name)
+            .copied()
+            .filter(|deadline| *deadline > Instant::now())
+    }
+
+    /// Whether a channel currently has a source
+    pub fn has_source(&self, name: &str) -> bool {
+        self.channel(name).lock().expect("Locking channel").has_source()
+    }
+
+    /// Parse a publisher's request body & broadcast it to the channel,
+    /// finishing when the body ends or turns out to be unusable, or once the
+    /// channel's `max_publish_duration` is up; the Cluster under way then is
+    /// sent in full before the source is cleanly ended. Input is
+    /// charged to the memory budget until it's been sent as part of a chunk.
+    /// Subscribers hear when the source starts & stops, and its bitrate.
+    ///
+    /// Fails with `ChannelBusy` if the channel already has a source, and
+    /// with `SourceReplaced` if another source takes it over.
+    pub fn publish<I: Buf, E, S>(&self, name: &str, body: S) -> impl Future<Output = Result<(), WebmetroError>>
+    where
+        S: Stream<Item = Result<I, E>> + Unpin,
+        WebmetroError: From<E>,
+    {
+        self.publish_with(name, body, SourceOptions::default())
+    }
+
+    /// Like `publish`, but replaces the channel's current source (e.g. one
+    /// whose encoder crashed without closing its connection) once this one
+    /// reaches a keyframe, so listeners switch over without a glitch
+    pub fn take_over<I: Buf, E, S>(&self, name: &str, body: S) -> impl Future<Output = Result<(), WebmetroError>>
+    where
+        S: Stream<Item = Result<I, E>> + Unpin,
+        WebmetroError: From<E>,
+    {
+        self.publish_with(name, body, SourceOptions { takeover: true, ..SourceOptions::default() })
+    }
+
+    /// Like `publish`, or `take_over`, as the options say
+    pub fn publish_with<I: Buf, E, S>(&self, name: &str, body: S, options: SourceOptions) -> impl Future<Output = Result<(), WebmetroError>>
+    where
+        S: Stream<Item = Result<I, E>> + Unpin,
+        WebmetroError: From<E>,
+    {
+        let mut transmitter = if options.takeover {
+            Transmitter::take_over(self.channel(name))
+        } else {
+            match Transmitter::claim(self.channel(name)) {
+                Ok(transmitter) => transmitter,
+                Err(err) => return Either::Right(ready(Err(err))),
+            }
+        };
+        // a new source resumes the channel
+        self.pauses.lock().expect("Locking pauses").remove(name);
+        if !options.pause_on_end {
+            return Either::Left(Either::Left(self.ingest(name, body, transmitter)));
+        }
+        transmitter.pause_on_disconnect();
+        let channel = self.channel(name);
+        let pauses = self.pauses.clone();
+        let max_pause = self.options.max_pause;
+        let name = name.to_string();
+        // the source is gone by now, so note its pause like `set_paused` does
+        Either::Left(Either::Right(self.ingest(&name, body, transmitter).map(move |result| {
+            let channel = channel.lock().expect("Locking channel");
+            if channel.is_paused() && !channel.has_source() {
+                pauses.lock().expect("Locking pauses").entry(name).or_insert_with(|| Instant::now() + max_pause);
+            }
+            result
+        })))
+    }
+
+    /// End the streams of a channel's listeners (after what's queued) if
+    /// it has no source, e.g. once `RelayOptions::reconnect_grace` is up
+    pub fn end_listeners(&self, name: &str) {
+        self.channel(name).lock().expect("Locking channel").end_listeners();
+    }
+
+    /// A count that moves on whenever a channel gets a new source; see
+    /// `end_listeners_since`
+    pub fn source_generation(&self, name: &str) -> u64 {
+        self.channel(name).lock().expect("Locking channel").source_generation()
+    }
+
+    /// Like `end_listeners`, unless a source has arrived since `generation`
+    /// (from `source_generation`), e.g. when a reconnect grace is up
+    pub fn end_listeners_since(&self, name: &str, generation: u64) {
+        self.channel(name).lock().expect("Locking channel").end_listeners_since(generation);
+    }
+
+    fn ingest<I: Buf, E, S>(&self, name: &str, body: S, mut transmitter: Transmitter) -> impl Future<Output = Result<(), WebmetroError>>
+    where
+        S: Stream<Item = Result<I, E>> + Unpin,
+        WebmetroError: From<E>,
+    {
+        if self.options.reconnect_grace {
+            transmitter.keep_listeners();
+        }
+        let in_flight = Arc::new(Charge::new(self.budget.clone()));
+        // announces the source stopping when the pipeline is dropped
+        let mut publishing = self.events.publishing(name);
+        let mut session = self.history.start(name);
+        let events = self.events.clone();
+        let channel = name.to_string();
+        let received = in_flight.clone();
+        let body = body.inspect(move |item| if let Ok(buf) = item {
+            received.add(buf.remaining());
+        });
+
+        let parser = body.parse_ebml().with_soft_limit(self.options.buffer_limit);
+        let parser = if self.options.resync { parser.with_resync() } else { parser };
+        let mut chunker_options = ChunkerOptions::new().soft_limit(self.options.buffer_limit);
+        if let Some(duration) = self.options.max_cluster_duration {
+            chunker_options = chunker_options.max_cluster_duration(duration);
+        }
+        let mut pipeline = Pipeline::new().with(GapDetector::new(self.options.gap_threshold));
+        if let Some(threshold) = self.options.max_av_drift {
+            pipeline.push(DriftCorrector::new(threshold));
+        }
+        if let Some(threshold) = self.options.fill_silence {
+            pipeline.push(SilenceFiller::new(threshold));
+        }
+        for stage in &self.ingest_stages {
+            pipeline.push(stage(name));
+        }
+        let deadline = self.max_publish_duration(name)
+            .map(|duration| Instant::now() + Duration::from_millis(duration));
+        let mut expired = false;
+        let limit_channel = name.to_string();
+        let max_bitrates = self.max_bitrates.clone();
+        let default_max_bitrate = self.options.max_bitrate;
+        let bitrate_action = self.options.bitrate_action;
+        let mut bitrate = RollingBitrate::default();
+        let mut over_bitrate = false;
+        let bitrate_channel = name.to_string();
+        parser
+            .chunk_webm_with(chunker_options)
+            .process_with(pipeline)
+            .and_then(move |chunk| {
+                let measured = bitrate.observe(&chunk);
+                let limit = max_bitrates.read().expect("Locking bitrate caps")
+                    .get(&bitrate_channel).cloned()
+                    .or(default_max_bitrate);
+                let result = match (measured, limit) {
+                    (Some(measured), Some(limit)) if measured > limit => {
+                        if bitrate_action == BitrateAction::End {
+                            warn!("Source on Channel {} is publishing {} bps, over its cap of {}, ending it", bitrate_channel, measured, limit);
+                            Err(WebmetroError::LimitExceeded { limit: Limit::Bitrate(limit) })
+                        } else {
+                            if !over_bitrate {
+                                warn!("Source on Channel {} is publishing {} bps, over its cap of {}", bitrate_channel, measured, limit);
+                            }
+                            over_bitrate = true;
+                            Ok(chunk)
+                        }
+                    },
+                    (Some(_), _) => {
+                        over_bitrate = false;
+                        Ok(chunk)
+                    },
+                    (None, _) => Ok(chunk),
+                };
+                ready(result)
+            })
+            // the chunk that completes once time's up is the last Cluster under way
+            .try_take_while(move |_| {
+                let before = !expired;
+                if before && deadline.map_or(false, |deadline| Instant::now() >= deadline) {
+                    info!("Source on Channel {} reached its maximum publishing time, ending it", limit_channel);
+                    expired = true;
+                }
+                ready(Ok(before))
+            })
+            .inspect_ok(move |chunk| {
+                in_flight.remove(chunk.size());
+                publishing.observe(chunk);
+                session.observe(chunk.size(), events.listener_count(&channel));
+            })
+            .forward(transmitter)
+    }
+}
+
+/// One of a client address's listener slots, given back when dropped
+pub struct ListenerSlot {
+    counts: Arc<Mutex<HashMap<IpAddr, usize>>>,
+    ip: IpAddr,
+}
+
+impl Drop for ListenerSlot {
+    fn drop(&mut self) {
+        let mut counts = self.counts.lock().expect("Locking listener addresses");
+        if let Some(count) = counts.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.ip);
+            }
+        }
+    }
+}
+
+/// Read the start of a publisher's body, through its headers up to the first
+/// Cluster, to check it's WebM before answering the request; a relay can then
+/// refuse a bad source with the status from `WebmetroError::status_code`,
+/// rather than cutting off a response that's already begun.
+///
+/// Fails with `UnsupportedFormat` if the body isn't WebM or Matroska, with a
+/// `ParseError` if its headers are corrupt, or with `LimitExceeded` if they
+/// don't fit in `buffer_limit` bytes. Otherwise resolves to the whole body,
+/// with what was read put back in front.
+pub async fn check_source<I: Buf, E, S>(mut body: S, buffer_limit: usize) -> Result<impl Stream<Item = Result<Bytes, WebmetroError>> + Unpin, WebmetroError>
+where
+    S: Stream<Item = Result<I, E>> + Unpin,
+    WebmetroError: From<E>,
+{
+    let mut read = BytesMut::new();
+    // where the next element to check starts
+    let mut offset = 0;
+    loop {
+        match WebmElement::peek_layout(&read[offset..]) {
+            Err(source) => return Err(WebmetroError::ParseError { offset: offset as u64, source }),
+            Ok(Some(layout)) if offset == 0 && layout.element_id != EBML_HEAD_ID => return Err(WebmetroError::UnsupportedFormat),
+            // the headers are complete
+            Ok(Some(layout)) if layout.element_id == CLUSTER_ID => break,
+            Ok(Some(layout)) if layout.element_len <= read.len() - offset => {
+                if layout.element_id == EBML_HEAD_ID {
+                    // a missing DocType means Matroska
+                    match doc_type(&read[offset..offset + layout.element_len]) {
+                        Ok(Some("webm")) | Ok(Some("matroska")) | Ok(None) => {},
+                        Ok(Some(_)) => return Err(WebmetroError::UnsupportedFormat),
+                        Err(source) => return Err(WebmetroError::ParseError { offset: offset as u64, source }),
+                    }
+                }
+                offset += layout.element_len;
+                continue;
+            },
+            // the next element hasn't all arrived yet
+            Ok(_) => {}
+        }
+
+        if read.len() > buffer_limit {
+            return Err(WebmetroError::LimitExceeded { limit: Limit::ParserBuffer(buffer_limit) });
+        }
+        match body.next().await {
+            Some(Ok(buf)) => read.put(buf),
+            Some(Err(err)) => return Err(err.into()),
+            // a body too short to reach a Cluster is left to the chunker
+            None => break,
+        }
+    }
+
+    let rest = body.map(|item| item.map(|mut buf| buf.to_bytes()).map_err(WebmetroError::from));
+    Ok(once(ready(Ok(read.freeze()))).chain(rest))
+}
+
+/// End a stream of chunks between items once `stopped`'s sender is dropped
+fn until_stopped<S>(chunks: S, stopped: oneshot::Receiver<()>) -> impl Stream<Item = S::Item> + Send
+where
+    S: Stream + Send,
+    S::Item: Send,
+{
+    // the sender is never used, so this only yields once it's dropped
+    let stopped = stopped.into_stream().map(|_| None);
+    select(chunks.map(Some), stopped).take_while(|chunk| ready(chunk.is_some())).filter_map(ready)
+}
+
+impl Default for Relay {
+    fn default() -> Relay {
+        Relay::new(RelayOptions::default())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::task::Poll;
+
+    use bytes::Bytes;
+    use futures::{executor::block_on, future::{join, select}, stream::{self, iter}};
+    use matches::assert_matches;
+
+    use crate::chunk::ClusterHead;
+    use crate::server::*;
+    use crate::tests::{ENCODE_WEBM_TEST_FILE, TEST_FILE};
+    use crate::webm::set_doc_type;
+
+    #[test]
+    fn listen_as_cmaf() {
+        let relay = Relay::default();
+        let listener = relay.listen_cmaf("main", Viewer::default());
+        let body = iter(vec![Ok::<_, WebmetroError>(Bytes::from_static(TEST_FILE))]);
+
+        let (published, received) = block_on(join(
+            relay.publish("main", body),
+            listener.take(2).try_collect::<Vec<Bytes>>(),
+        ));
+        published.unwrap();
+        let segments = received.unwrap();
+        assert_eq!(&segments[0][4..8], b"ftyp");
+        assert_eq!(&segments[1][4..8], b"moof");
+    }
+
+    #[test]
+    fn split_init_and_media() {
+        let relay = Relay::default();
+        assert_eq!(relay.init_segment("main"), None);
+        let listener = relay.listen_media("main", Viewer::default());
+        // the source stays connected, so its headers are kept
+        let body = iter(vec![Ok::<_, WebmetroError>(Bytes::from_static(TEST_FILE))]).chain(stream::pending());
+
+        let received = match block_on(select(Box::pin(relay.publish("main", body)), listener.take(1).try_collect::<Vec<Bytes>>())) {
+            Either::Right((received, _publishing)) => {
+                assert_eq!(&relay.init_segment("main").unwrap()[0..4], &[0x1A, 0x45, 0xDF, 0xA3]);
+                received.unwrap()
+            },
+            Either::Left((published, _)) => panic!("Source stopped early: {:?}", published),
+        };
+        // a Cluster head, not the EBML header
+        assert_eq!(&received[0][0..4], &[0x1F, 0x43, 0xB6, 0x75]);
+    }
+
+    #[test]
+    fn look_up_sessions() {
+        let relay = Relay::default();
+        let session = relay.new_session();
+        assert_eq!(session.len(), 32);
+        assert_ne!(session, relay.new_session());
+
+        let mut listener = Box::pin(relay.listen_from("main", Viewer { session: Some(session.clone()), ..Viewer::default() }, MediaFormat::WebM));
+        let _other = relay.listen("main");
+        assert_eq!(relay.session_stats("main", &session).unwrap().sent_bytes, 0);
+        assert_eq!(relay.session_stats("other", &session), None);
+
+        let body = iter(vec![Ok::<_, WebmetroError>(Bytes::from_static(TEST_FILE))]).chain(stream::pending());
+        match block_on(select(Box::pin(relay.publish("main", body)), listener.next())) {
+            Either::Right((Some(Ok(_)), _publishing)) => {},
+            _ => panic!("Listener got nothing"),
+        };
+        block_on(listener.next()).unwrap().unwrap();
+        let stats = relay.session_stats("main", &session).unwrap();
+        assert!(stats.sent_bytes > 0);
+        assert!(stats.start_timecode.is_some());
+
+        drop(listener);
+        assert_eq!(relay.session_stats("main", &session), None);
+    }
+
+    #[test]
+    fn limit_publishing_time() {
+        let relay = Relay::new(RelayOptions {
+            max_publish_duration: Some(60_000),
+            end_with_source: true,
+            ..RelayOptions::default()
+        });
+        relay.set_max_publish_duration("main", Some(0));
+        assert_eq!(relay.max_publish_duration("main"), Some(0));
+        assert_eq!(relay.max_publish_duration("other"), Some(60_000));
+
+        // the source is ended cleanly after its first chunk, though it never stops sending
+        let listener = relay.listen("main");
+        let body = iter(vec![Ok::<_, WebmetroError>(Bytes::from_static(TEST_FILE))]).chain(stream::pending());
+        block_on(relay.publish("main", body)).unwrap();
+        let received: Vec<Bytes> = block_on(listener.try_collect()).unwrap();
+        assert_eq!(&received[0][0..4], &[0x1A, 0x45, 0xDF, 0xA3]);
+        assert!(!relay.has_source("main"));
+
+        relay.set_max_publish_duration("main", None);
+        assert_eq!(relay.max_publish_duration("main"), Some(60_000));
+    }
+
+    #[test]
+    fn cap_bitrates() {
+        let cluster = |start: u64, end: u64, size: usize| {
+            let mut head = ClusterHead::new(start);
+            head.end = end;
+            Chunk::Cluster(head, Bytes::from(vec![0; size]))
+        };
+        let mut bitrate = RollingBitrate::default();
+        assert_eq!(bitrate.observe(&cluster(0, 500, 1000)), None);
+        let size = cluster(0, 500, 1000).size() as u64;
+        assert_eq!(bitrate.observe(&cluster(500, 1000, 1000)), Some(size * 2 * 8));
+        // only the last few seconds count
+        for second in 1..10 {
+            bitrate.observe(&cluster(second * 1000, second * 1000 + 1000, 1000));
+        }
+        assert_eq!(bitrate.observe(&cluster(10_000, 11_000, 1000)), Some(size * 8));
+        // timecodes going back mean a new stream
+        assert_eq!(bitrate.observe(&cluster(0, 500, 1000)), None);
+        assert!("end".parse::<BitrateAction>().is_ok());
+        assert!("throttle".parse::<BitrateAction>().is_err());
+
+        let relay = Relay::new(RelayOptions { max_bitrate: Some(1), ..RelayOptions::default() });
+        relay.set_max_bitrate("roomy", Some(u64::max_value()));
+        assert_eq!(relay.max_bitrate("main"), Some(1));
+        let body = || iter(vec![Ok::<_, WebmetroError>(Bytes::from_static(TEST_FILE))]);
+        assert_matches!(block_on(relay.publish("main", body().chain(stream::pending()))),
+            Err(WebmetroError::LimitExceeded { limit: Limit::Bitrate(1) }));
+        assert!(!relay.has_source("main"));
+        block_on(relay.publish("roomy", body())).unwrap();
+
+        let lenient = Relay::new(RelayOptions { max_bitrate: Some(1), bitrate_action: BitrateAction::Warn, ..RelayOptions::default() });
+        block_on(lenient.publish("main", body())).unwrap();
+    }
+
+    #[test]
+    fn describe_tracks() {
+        let relay = Relay::default();
+        assert_eq!(relay.tracks("main"), None);
+        let listener = relay.listen("main");
+        let body = iter(vec![Ok::<_, WebmetroError>(Bytes::from_static(TEST_FILE))]).chain(stream::pending());
+
+        let tracks = match block_on(select(Box::pin(relay.publish("main", body)), listener.take(1).try_collect::<Vec<Bytes>>())) {
+            Either::Right((_, _publishing)) => relay.tracks("main").expect("Tracks should be known"),
+            Either::Left((published, _)) => panic!("Source stopped early: {:?}", published),
+        };
+        let metadata = ChannelMetadata { title: Some("Main".into()), ..ChannelMetadata::default() };
+        assert_eq!(manifest_json("main", true, Some(&metadata), &tracks), concat!(
+            r#"{"channel":"main","live":true,"meta":{"title":"Main","description":null,"tags":[]},"mime_type":"video/webm; codecs=\"vp9\"","tracks":["#,
+            r#"{"number":1,"type":"video","codec_id":"V_VP9","codec":"vp9","width":320,"height":240,"frame_rate":30}]}"#,
+        ));
+
+        let audio = TrackEntry {
+            number: 2,
+            track_type: crate::webm::TRACK_TYPE_AUDIO,
+            codec_id: "A_OPUS".into(),
+            default_duration: 20_000_000,
+            sampling_frequency: 48000.0,
+            channels: 2,
+            ..TrackEntry::default()
+        };
+        assert_eq!(manifest_json("radio", false, None, &[audio]), concat!(
+            r#"{"channel":"radio","live":false,"meta":null,"mime_type":"audio/webm; codecs=\"opus\"","tracks":["#,
+            r#"{"number":2,"type":"audio","codec_id":"A_OPUS","codec":"opus","channels":2,"sample_rate":48000}]}"#,
+        ));
+    }
+
+    #[test]
+    fn preview_latest_keyframe() {
+        use crate::webm::{parse_webm, WebmElement};
+
+        let relay = Relay::default();
+        assert_eq!(relay.preview("main").unwrap(), None);
+        let listener = relay.listen("main");
+        let body = iter(vec![Ok::<_, WebmetroError>(Bytes::from_static(TEST_FILE))]).chain(stream::pending());
+
+        let preview = match block_on(select(Box::pin(relay.publish("main", body)), listener.take(2).try_collect::<Vec<Bytes>>())) {
+            Either::Right((_, _publishing)) => relay.preview("main").unwrap().expect("Preview should be ready"),
+            Either::Left((published, _)) => panic!("Source stopped early: {:?}", published),
+        };
+        let elements: Vec<WebmElement> = parse_webm(&preview).collect();
+        assert_eq!(elements[0], WebmElement::EbmlHead);
+        assert_eq!(elements.iter().filter(|element| **element == WebmElement::Cluster).count(), 1);
+        match elements.iter().find(|element| match element { WebmElement::SimpleBlock(_) => true, _ => false }) {
+            Some(WebmElement::SimpleBlock(block)) => assert!(block.flags & 0x80 != 0),
+            _ => panic!("Preview has no blocks"),
+        }
+    }
+
+    #[test]
+    fn refuse_ogg_for_video() {
+        let relay = Relay::default();
+        let listener = relay.listen_ogg("main", Viewer::default());
+        let body = iter(vec![Ok::<_, WebmetroError>(Bytes::from_static(TEST_FILE))]);
+
+        let (published, received) = block_on(join(
+            relay.publish("main", body),
+            listener.into_future(),
+        ));
+        published.unwrap();
+        assert!(received.0.unwrap().is_err());
+    }
+
+    #[test]
+    fn package_for_hls() {
+        let relay = Relay::default();
+        let (packager, packaging) = relay.hls("main");
+        let packaging = packaging.expect("Packaging should have started");
+        assert!(relay.hls("main").1.is_none());
+
+        let changed = packager.changed();
+        let body = iter(vec![Ok::<_, WebmetroError>(Bytes::from_static(TEST_FILE))]);
+        block_on(select(Box::pin(packaging), Box::pin(join(relay.publish("main", body), changed))));
+        assert!(packager.playlist().unwrap().contains("#EXT-X-PART:"));
+
+        relay.expire_hls(Duration::from_secs(0));
+        assert!(relay.hls("main").1.is_some());
+    }
+
+    #[test]
+    fn override_media_headers() {
+        let mut options = RelayOptions::default();
+        options.set_media_header("cache-control", Some("public, max-age=1"));
+        options.set_media_header("X-Accel-Buffering", None);
+        options.set_media_header("Surrogate-Control", Some("no-store"));
+        assert_eq!(options.media_headers, vec![
+            ("Content-Type".to_string(), "video/webm".to_string()),
+            ("cache-control".to_string(), "public, max-age=1".to_string()),
+            ("Surrogate-Control".to_string(), "no-store".to_string()),
+        ]);
+    }
+
+    #[test]
+    fn reuse_live_channels() {
+        let relay = Relay::default();
+        let channel = relay.channel("main");
+        assert!(Arc::ptr_eq(&channel, &relay.channel("main")));
+        assert_eq!(relay.channel_names(), vec!["main".to_string()]);
+
+        drop(channel);
+        assert!(relay.channel_names().is_empty());
+    }
+
+    #[test]
+    fn list_channels_across_shards() {
+        let relay = Relay::default();
+        let channels: Vec<_> = (0..100).map(|n| relay.channel(&n.to_string())).collect();
+        let mut names = relay.channel_names();
+        names.sort_by_key(|name| name.parse::<usize>().unwrap());
+        assert_eq!(names, (0..100).map(|n| n.to_string()).collect::<Vec<_>>());
+        drop(channels);
+        assert!(relay.channel_names().is_empty());
+    }
+
+    #[test]
+    fn publish_to_listeners() {
+        let relay = Relay::default();
+        let listener = relay.listen("main");
+        let body = iter(vec![Ok::<_, WebmetroError>(Bytes::from_static(ENCODE_WEBM_TEST_FILE))]);
+
+        let (published, received) = block_on(join(
+            relay.publish("main", body),
+            listener.take(1).try_collect::<Vec<Bytes>>(),
+        ));
+        published.unwrap();
+        // the initialization segment comes first
+        assert!(received.unwrap()[0].starts_with(&[0x1A, 0x45, 0xDF, 0xA3]));
+    }
+
+    #[test]
+    fn end_listeners_with_source() {
+        let relay = Relay::new(RelayOptions { end_with_source: true, ..RelayOptions::default() });
+        let listener = relay.listen("main");
+        let body = iter(vec![Ok::<_, WebmetroError>(Bytes::from_static(TEST_FILE))]);
+
+        // the listener's stream ends with the publisher's, final Cluster included
+        let (published, received) = block_on(join(
+            relay.publish("main", body),
+            listener.try_collect::<Vec<Bytes>>(),
+        ));
+        published.unwrap();
+        let chunks: Vec<Chunk> = block_on(iter(vec![Ok::<_, WebmetroError>(TEST_FILE)]).parse_ebml().chunk_webm().try_collect()).unwrap();
+        let sent: Vec<u8> = chunks.into_iter().flatten().flatten().collect();
+        assert_eq!(received.unwrap().concat(), sent);
+    }
+
+    /// Counts the chunks passing through, passing them on as they are
+    struct Counter(Arc<AtomicUsize>);
+
+    impl ChunkProcessor for Counter {
+        fn process_chunk(&mut self, chunk: Chunk, output: &mut Vec<Chunk>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            output.push(chunk);
+        }
+    }
+
+    #[test]
+    fn run_custom_stages() {
+        let ingested = Arc::new(AtomicUsize::new(0));
+        let egressed = Arc::new(AtomicUsize::new(0));
+        let (ingest_count, egress_count) = (ingested.clone(), egressed.clone());
+        let relay = Relay::new(RelayOptions { end_with_source: true, ..RelayOptions::default() })
+            .with_ingest_stage(move |_| Counter(ingest_count.clone()))
+            .with_egress_stage(move |name| {
+                assert_eq!(name, "main");
+                Counter(egress_count.clone())
+            });
+        let listener = relay.listen("main");
+        let body = iter(vec![Ok::<_, WebmetroError>(Bytes::from_static(TEST_FILE))]);
+
+        let (published, received) = block_on(join(
+            relay.publish("main", body),
+            listener.try_collect::<Vec<Bytes>>(),
+        ));
+        published.unwrap();
+        received.unwrap();
+        let chunks: Vec<Chunk> = block_on(iter(vec![Ok::<_, WebmetroError>(TEST_FILE)]).parse_ebml().chunk_webm().try_collect()).unwrap();
+        assert_eq!(ingested.load(Ordering::SeqCst), chunks.len());
+        assert_eq!(egressed.load(Ordering::SeqCst), chunks.len());
+    }
+
+    #[test]
+    fn check_sources_up_front() {
+        // delivered a few bytes at a time, to check elements split across reads
+        let pieces = || iter(TEST_FILE.chunks(7).map(|piece| Ok::<_, WebmetroError>(Bytes::from_static(piece))).collect::<Vec<_>>());
+        let body = block_on(check_source(pieces(), 1 << 16)).unwrap();
+        let bytes: Vec<Bytes> = block_on(body.try_collect()).unwrap();
+        assert_eq!(bytes.concat(), TEST_FILE);
+
+        assert_matches!(block_on(check_source(pieces(), 16)).err(), Some(WebmetroError::LimitExceeded { limit: Limit::ParserBuffer(16) }));
+
+        let ogg = set_doc_type(ENCODE_WEBM_TEST_FILE, "ogg").unwrap();
+        let body = iter(vec![Ok::<_, WebmetroError>(Bytes::from(ogg))]);
+        assert_matches!(block_on(check_source(body, 1 << 16)).err(), Some(WebmetroError::UnsupportedFormat));
+
+        let body = iter(vec![Ok::<_, WebmetroError>(Bytes::from_static(b"\x1a\x45\xdf\xa3\xff"))]);
+        assert_matches!(block_on(check_source(body, 1 << 16)).err(), Some(WebmetroError::ParseError { .. }));
+    }
+
+    #[test]
+    fn account_for_buffers() {
+        let relay = Relay::new(RelayOptions {
+            memory_limit: Some(1 << 20),
+            ..RelayOptions::default()
+        });
+        let body = iter(vec![Ok::<_, WebmetroError>(Bytes::from_static(ENCODE_WEBM_TEST_FILE))]);
+        let channel = relay.channel("main");
+
+        block_on(relay.publish("main", body)).unwrap();
+        // ingest has finished, and the publisher's departure clears what was retained
+        assert_eq!(relay.budget().used(), 0);
+
+        let listener = relay.listen("main");
+        Transmitter::new(channel.clone()).send(Chunk::Headers { bytes: Bytes::from_static(b"headers") });
+        // still queued for the listener
+        assert_eq!(relay.budget().used(), 7);
+        drop(listener);
+        drop(channel);
+        assert_eq!(relay.budget().used(), 0);
+    }
+
+    #[test]
+    fn separate_cluster_frames() {
+        let relay = Relay::default();
+        let listener = relay.listen("main");
+
+        let transmitter = Transmitter::new(relay.channel("main"));
+        let mut head = ClusterHead::new(0);
+        head.keyframe = true;
+        transmitter.send(Chunk::Headers { bytes: Bytes::from_static(b"headers") });
+        transmitter.send(Chunk::Cluster(head, Bytes::from_static(b"body")));
+
+        let frames = block_on(listener.take(3).try_collect::<Vec<Bytes>>()).unwrap();
+        assert_eq!(frames[0], "headers");
+        // the Cluster's head, then its body
+        assert!(frames[1].starts_with(&[0x1F, 0x43, 0xB6, 0x75]));
+        assert_eq!(frames[2], "body");
+    }
+
+    #[test]
+    fn share_frames_across_listeners() {
+        let relay = Relay::default();
+        let listeners = vec![relay.listen("main"), relay.listen("main")];
+
+        let transmitter = Transmitter::new(relay.channel("main"));
+        let mut head = ClusterHead::new(0);
+        head.keyframe = true;
+        transmitter.send(Chunk::Headers { bytes: Bytes::from_static(b"headers") });
+        transmitter.send(Chunk::Cluster(head, Bytes::from(vec![0; 64])));
+
+        let frames: Vec<Vec<Bytes>> = listeners.into_iter()
+            .map(|listener| block_on(listener.take(3).try_collect()).unwrap())
+            .collect();
+        // both listeners were handed the same allocations, head included
+        for (first, second) in frames[0].iter().zip(&frames[1]) {
+            assert_eq!(first.as_ptr(), second.as_ptr());
+        }
+    }
+
+    #[test]
+    fn toggle_recording() {
+        let relay = Relay::default();
+        let recording = relay.set_recording("main", true).expect("Recording should start");
+        assert!(relay.set_recording("main", true).is_none());
+        assert!(relay.is_recording("main"));
+
+        let transmitter = Transmitter::new(relay.channel("main"));
+        let mut head = ClusterHead::new(0);
+        head.keyframe = true;
+        transmitter.send(Chunk::Headers { bytes: Bytes::from_static(b"headers") });
+        transmitter.send(Chunk::Cluster(head, Bytes::from_static(b"body")));
+
+        let mut recording = Box::pin(recording);
+        let started: Vec<Chunk> = block_on(recording.as_mut().take(2).try_collect()).unwrap();
+        assert_eq!(started.len(), 2);
+
+        transmitter.send(Chunk::Cluster(ClusterHead::new(1), Bytes::from_static(b"body")));
+        assert!(relay.set_recording("main", false).is_none());
+        assert!(!relay.is_recording("main"));
+        // the recording ends between Clusters once switched off, though
+        // one that was already queued might make it in
+        let rest: Vec<Chunk> = block_on(recording.try_collect()).unwrap();
+        assert!(rest.len() <= 1);
+    }
+
+    #[test]
+    fn retarget_mirrors() {
+        let relay = Relay::default();
+        let first = relay.set_mirror("main", Some("http://a.example/live/main")).expect("Mirror should start");
+        assert!(relay.set_mirror("main", Some("http://a.example/live/main")).is_none());
+        assert_eq!(relay.mirror_target("main").as_deref(), Some("http://a.example/live/main"));
+
+        // a new target replaces the old mirror
+        let second = relay.set_mirror("main", Some("http://b.example/live/main")).expect("Mirror should restart");
+        assert_eq!(relay.mirror_target("main").as_deref(), Some("http://b.example/live/main"));
+        let rest: Vec<Chunk> = block_on(first.try_collect()).unwrap();
+        assert!(rest.is_empty());
+
+        assert!(relay.set_mirror("main", None).is_none());
+        assert_eq!(relay.mirror_target("main"), None);
+        let rest: Vec<Chunk> = block_on(second.try_collect()).unwrap();
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn announce_events() {
+        let relay = Relay::default();
+        let events = relay.subscribe(Some("main"));
+        let listener = relay.listen("main");
+        assert_eq!(relay.listener_count("main"), 1);
+        drop(listener);
+        assert_eq!(relay.listener_count("main"), 0);
+
+        let body = iter(vec![Ok::<_, WebmetroError>(Bytes::from_static(crate::tests::TEST_FILE))]);
+        block_on(relay.publish("main", body)).unwrap();
+
+        // dropping the relay ends the subscription
+        drop(relay);
+        let events: Vec<Event> = block_on(events.collect());
+        assert_eq!(events[0], Event::Listeners { channel: "main".into(), count: 1 });
+        assert_eq!(events[1], Event::Listeners { channel: "main".into(), count: 0 });
+        assert_eq!(events[2], Event::PublishStart { channel: "main".into() });
+        assert_eq!(events.last(), Some(&Event::PublishStop { channel: "main".into() }));
+        // in between, the bitrate is measured as the test file arrives
+        assert!(events[3..events.len() - 1].iter().all(|event| matches!(event, Event::Bitrate { .. })));
+    }
+
+    #[test]
+    fn limit_listeners_per_ip() {
+        let relay = Relay::new(RelayOptions {
+            max_listeners_per_ip: Some(2),
+            ..RelayOptions::default()
+        });
+        let ip: IpAddr = "192.0.2.1".parse().unwrap();
+        let first = relay.reserve_listener(ip).expect("First slot should be free");
+        let _second = relay.reserve_listener(ip).expect("Second slot should be free");
+        assert!(relay.reserve_listener(ip).is_none());
+        // other addresses have their own slots
+        assert!(relay.reserve_listener("192.0.2.2".parse().unwrap()).is_some());
+
+        drop(first);
+        assert!(relay.reserve_listener(ip).is_some());
+    }
+
+    fn publish_request(channel: &str, client_names: Vec<String>) -> AccessRequest {
+        AccessRequest {
+            action: Action::Publish,
+            channel: channel.into(),
+            credentials: None,
+            remote: None,
+            client_names,
+        }
+    }
+
+    #[test]
+    fn authorize_certificates() {
+        let authorizer = CertificateAuthorizer::new(AllowAll)
+            .allow("encoder-1.example", "studio-")
+            .allow("backup@example.com", "");
+
+        let names = certificate_names(Some("CN=encoder-1.example,O=Example"), None);
+        assert!(block_on(authorizer.authorize(&publish_request("studio-a", names.clone()))));
+        assert!(!block_on(authorizer.authorize(&publish_request("main", names))));
+        assert!(!block_on(authorizer.authorize(&publish_request("studio-a", Vec::new()))));
+
+        let names = certificate_names(Some("/O=Example/CN=other"), Some("DNS:other.example, email:backup@example.com"));
+        assert_eq!(names, vec!["other", "other.example", "backup@example.com"]);
+        assert!(block_on(authorizer.authorize(&publish_request("main", names))));
+
+        // listeners don't need certificates
+        let mut request = publish_request("main", Vec::new());
+        request.action = Action::Listen;
+        assert!(block_on(authorizer.authorize(&request)));
+    }
+
+    #[test]
+    fn parse_escaped_subjects() {
+        let names = |subject| certificate_names(Some(subject), None);
+        assert_eq!(names(r"CN=Encoder\, Studio A,O=Example"), vec!["Encoder, Studio A"]);
+        assert_eq!(names(r"O=Example\,CN=fake,CN=real"), vec!["real"]);
+        assert_eq!(names(r"cn=a\2Cb + UID=1; OU=x"), vec!["a,b"]);
+        assert_eq!(names(r#"CN="quoted, name",O=Example"#), vec!["quoted, name"]);
+        assert_eq!(names(r"CN=trailing\ ,2.5.4.3=#0c0474776f6f"), vec!["trailing ", "twoo"]);
+        assert_eq!(names(r"CN=caf\C3\A9"), vec!["café"]);
+        // malformed names don't yield anything
+        assert!(names(r"CN=bad\").is_empty());
+        assert!(names(r"CN=a,junk").is_empty());
+        assert!(names(r#"CN="unterminated"#).is_empty());
+    }
+
+    #[test]
+    fn redact_tokens_for_logs() {
+        assert_eq!(redact_token("http://a/live/main"), "http://a/live/main");
+        assert_eq!(redact_token("http://a/live/main?tokens=1"), "http://a/live/main?tokens=1");
+        assert_eq!(redact_token("http://a/live/main?token=secret"), "http://a/live/main?token=redacted");
+        assert_eq!(
+            redact_token("http://a/live/main?x=1&token=secret&y=2#token=z"),
+            "http://a/live/main?x=1&token=redacted&y=2#token=z"
+        );
+    }
+
+    #[test]
+    fn deny_admin_actions_by_default() {
+        let mut request = publish_request("main", Vec::new());
+        request.action = Action::Record;
+        assert!(!block_on(Relay::default().authorize(&request)));
+
+        let relay = Relay::default().with_admin_authorizer(AdminToken::new("s3cret"));
+        assert!(!block_on(relay.authorize(&request)));
+        request.credentials = Some("Bearer guess".into());
+        assert!(!block_on(relay.authorize(&request)));
+        request.credentials = Some("Bearer s3cret".into());
+        assert!(block_on(relay.authorize(&request)));
+
+        request.action = Action::Mirror;
+        assert!(block_on(relay.authorize(&request)));
+        request.action = Action::Configure;
+        assert!(block_on(relay.authorize(&request)));
+        request.action = Action::Pause;
+        assert!(block_on(relay.authorize(&request)));
+
+        // everything else is up to the main Authorizer
+        request.action = Action::Publish;
+        request.credentials = None;
+        assert!(block_on(relay.authorize(&request)));
+    }
+
+    #[test]
+    fn keep_pauses_without_listeners() {
+        let relay = Relay::default();
+        relay.set_paused("main", true);
+        // nothing holds the channel open, but it's still paused once reopened
+        assert!(relay.channel_names().is_empty());
+        assert!(relay.is_paused("main"));
+        let listener = relay.listen("main");
+        assert!(relay.is_paused("main"));
+        drop(listener);
+
+        relay.set_paused("main", false);
+        assert!(!relay.is_paused("main"));
+
+        // a pause that's run out resumes the channel
+        let relay = Relay::new(RelayOptions { max_pause: Duration::from_millis(0), ..RelayOptions::default() });
+        let _listener = relay.listen("main");
+        relay.set_paused("main", true);
+        relay.expire_pauses();
+        assert!(!relay.is_paused("main"));
+    }
+
+    #[test]
+    fn take_over_busy_channel() {
+        let relay = Relay::default();
+        let mut listener = Listener::with_policy(relay.channel("main"), 1000, LagPolicy::DropToKeyframe).into_chunks();
+        // a source that's stalled without closing its connection
+        let (stalled, stalled_body) = futures::channel::mpsc::unbounded::<Result<Bytes, WebmetroError>>();
+        stalled.unbounded_send(Ok(Bytes::from_static(&crate::tests::TEST_FILE[..2000]))).unwrap();
+        let mut first = Box::pin(relay.publish("main", stalled_body));
+        assert!(block_on(futures::future::poll_fn(|cx| Poll::Ready(first.as_mut().poll(cx).is_pending()))));
+        assert!(relay.has_source("main"));
+
+        let body = iter(vec![Ok::<_, WebmetroError>(Bytes::from_static(crate::tests::TEST_FILE))]);
+        assert_matches!(block_on(relay.publish("main", body)), Err(WebmetroError::ChannelBusy { .. }));
+
+        let body = iter(vec![Ok::<_, WebmetroError>(Bytes::from_static(crate::tests::TEST_FILE))]);
+        block_on(relay.take_over("main", body)).unwrap();
+        // the stalled source finds it's been replaced once it sends again
+        stalled.unbounded_send(Ok(Bytes::from_static(&crate::tests::TEST_FILE[2000..]))).unwrap();
+        assert_matches!(block_on(first), Err(WebmetroError::SourceReplaced { .. }));
+
+        // listeners got the new source's headers, then its first keyframe
+        let received: Vec<Chunk> = block_on(futures::future::poll_fn(|cx| {
+            let mut received = Vec::new();
+            while let Poll::Ready(Some(chunk)) = Pin::new(&mut listener).poll_next(cx) {
+                received.push(chunk);
+            }
+            Poll::Ready(received)
+        }));
+        let last_headers = received.iter().rposition(|chunk| matches!(chunk, Chunk::Headers { .. })).unwrap();
+        assert!(received[..last_headers].iter().all(|chunk| matches!(chunk, Chunk::Headers { .. })));
+        assert_matches!(received[last_headers + 1], Chunk::Cluster(ref head, _) if head.keyframe);
+    }
+
+    #[test]
+    fn resolve_aliases() {
+        let relay = Relay::default();
+        relay.set_alias("public", "internal-3", false);
+        relay.set_alias("old", "public", true);
+
+        assert_eq!(relay.resolve("public"), Route::Channel("internal-3".into()));
+        // aliases aren't followed through redirects
+        assert_eq!(relay.resolve("old"), Route::Redirect("public".into()));
+        assert_eq!(relay.resolve("internal-3"), Route::Channel("internal-3".into()));
+
+        // renditions of an alias are renditions of its channel
+        assert_eq!(relay.resolve("public/720p"), Route::Channel("internal-3/720p".into()));
+        assert_eq!(relay.resolve("old/720p"), Route::Redirect("public/720p".into()));
+
+        relay.remove_alias("public");
+        assert_eq!(relay.resolve("public"), Route::Channel("public".into()));
+    }
+
+    #[test]
+    fn group_renditions() {
+        let relay = Relay::default();
+        let _source = Transmitter::new(relay.channel(&rendition_channel("main", "720p")));
+        let _low = relay.channel("main/360p");
+        let _elsewhere = relay.channel("mainly/720p");
+        assert_eq!(split_rendition("main/720p"), Some(("main", "720p")));
+        assert_eq!(split_rendition("main"), None);
+
+        assert_eq!(relay.renditions("main"), vec!["360p", "720p"]);
+        assert_eq!(simulcast_json("main", None, &relay.rendition_status("main")), concat!(
+            r#"{"channel":"main","live":true,"listeners":0,"meta":null,"renditions":["#,
+            r#"{"rendition":"360p","live":false,"listeners":0,"bps":null},"#,
+            r#"{"rendition":"720p","live":true,"listeners":0,"bps":null}]}"#,
+        ));
+        // nothing's measured a bitrate to advertise yet
+        assert_eq!(relay.master_playlist("main"), None);
+    }
+
+    #[test]
+    fn describe_channels() {
+        let relay = Relay::default();
+        let events = relay.subscribe(Some("main"));
+        let metadata = ChannelMetadata { title: Some("Main".into()), tags: vec!["live".into()], ..ChannelMetadata::default() };
+        relay.set_metadata("main", Some(metadata.clone()));
+        assert_eq!(relay.metadata("main"), Some(metadata.clone()));
+        // renditions share their simulcast channel's, unless they have their own
+        assert_eq!(relay.metadata("main/720p"), Some(metadata.clone()));
+        assert_eq!(relay.metadata("mainly"), None);
+        relay.set_metadata("main", None);
+        assert_eq!(relay.metadata("main"), None);
+
+        // a closed channel's is forgotten, but an open rendition keeps its
+        // simulcast channel's
+        let listener = relay.listen("main/720p");
+        relay.set_metadata("main", Some(metadata.clone()));
+        relay.set_metadata("other", Some(metadata.clone()));
+        relay.expire_metadata();
+        assert_eq!(relay.metadata("main"), Some(metadata.clone()));
+        assert_eq!(relay.metadata("other"), None);
+        drop(listener);
+        relay.expire_metadata();
+        assert_eq!(relay.metadata("main"), None);
+        drop(relay);
+
+        // the rendition's listener is announced too
+        let events: Vec<Event> = block_on(events.filter(|event| ready(matches!(event, Event::Metadata { .. }))).collect());
+        assert_eq!(events, vec![
+            Event::Metadata { channel: "main".into(), metadata: metadata.clone() },
+            Event::Metadata { channel: "main".into(), metadata: ChannelMetadata::default() },
+            Event::Metadata { channel: "main".into(), metadata },
+            Event::Metadata { channel: "main".into(), metadata: ChannelMetadata::default() },
+        ]);
+    }
+
+    #[test]
+    fn snapshot_channels() {
+        let relay = Relay::default();
+        let metadata = ChannelMetadata { title: Some("Main".into()), ..ChannelMetadata::default() };
+        let _listener = relay.listen("main/720p");
+        relay.set_metadata("main", Some(metadata.clone()));
+        relay.set_metadata("other", Some(metadata.clone()));
+        assert_eq!(relay.snapshot(Some("main")), vec![
+            Event::Metadata { channel: "main".into(), metadata },
+            Event::Listeners { channel: "main/720p".into(), count: 1 },
+        ]);
+        assert_eq!(relay.snapshot(None).len(), 3);
+    }
+
+    #[test]
+    fn route_methods() {
+        assert_eq!(Action::from_method("HEAD"), Some(Action::Probe));
+        assert_eq!(Action::from_method("PUT"), Some(Action::Publish));
+        assert_eq!(Action::from_method("DELETE"), None);
+    }
+
+    #[test]
+    fn refill_token_bucket() {
+        let bucket = TokenBucket::new(2.0, 1.0);
+        let start = bucket.tokens.lock().unwrap().1;
+        assert!(bucket.take_at(start));
+        assert!(!bucket.take_at(start));
+        // half a second buys one more token at 2 per second
+        assert!(!bucket.take_at(start + Duration::from_millis(250)));
+        assert!(bucket.take_at(start + Duration::from_millis(500)));
+        assert!(!bucket.take_at(start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn limit_token_bucket_bursts() {
+        let bucket = TokenBucket::new(1.0, 3.0);
+        let start = bucket.tokens.lock().unwrap().1;
+        assert_eq!((0..5).filter(|_| bucket.take_at(start)).count(), 3);
+
+        // idling refills no more than the burst
+        let later = start + Duration::from_secs(60);
+        assert_eq!((0..5).filter(|_| bucket.take_at(later)).count(), 3);
+
+        // a clock that goes backwards neither refills nor panics
+        assert!(!bucket.take_at(start));
+        assert!(bucket.take_at(later + Duration::from_secs(1)));
+    }
+}
@@ -0,0 +1,211 @@
+//! Pushes the relay's gauges to a StatsD collector over UDP, for
+//! environments that collect metrics that way rather than by polling:
+//!
+//! * `<prefix>.channels`, how many channels have a source
+//! * `<prefix>.listeners`, across every channel
+//! * `<prefix>.memory`, the bytes charged to the relay's memory budget
+//!
+//! and for each open channel, `listeners`, `live` (1 or 0), and `bitrate`
+//! while it's publishing. With plain StatsD these are named
+//! `<prefix>.channel.<name>.<gauge>`; with DogStatsD (Datadog's dialect) they're
+//! `<prefix>.channel.<gauge>`, tagged `channel:<name>` along with any tags
+//! configured for every metric.
+
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::Future;
+use sha2::{Digest, Sha256};
+use tokio::time::interval;
+
+use crate::error::WebmetroError;
+use crate::server::Relay;
+
+/// Datagrams are kept under this many bytes, to fit an Ethernet frame
+const MAX_PACKET: usize = 1432;
+
+/// How metrics are named & sent
+#[derive(Clone, Debug, PartialEq)]
+pub struct StatsdOptions {
+    /// put in front of every metric's name, e.g. `webmetro`
+    pub prefix: String,
+    /// send DogStatsD tags, with these added to every metric (e.g.
+    /// `env:prod`); `None` sends plain StatsD, naming channels in the metrics
+    pub tags: Option<Vec<String>>,
+    /// how often to send
+    pub interval: Duration,
+}
+
+impl Default for StatsdOptions {
+    fn default() -> StatsdOptions {
+        StatsdOptions {
+            prefix: "webmetro".to_string(),
+            tags: None,
+            interval: Duration::from_secs(10),
+        }
+    }
+}
+
+/// One gauge's reading, which belongs to a channel or the whole relay
+#[derive(Clone, Debug, PartialEq)]
+pub struct Gauge {
+    pub name: &'static str,
+    pub channel: Option<String>,
+    pub value: u64,
+}
+
+impl Gauge {
+    fn new(name: &'static str, channel: Option<&str>, value: u64) -> Gauge {
+        Gauge {
+            name,
+            channel: channel.map(str::to_string),
+            value,
+        }
+    }
+}
+
+/// Read the relay's gauges
+pub fn relay_gauges(relay: &Relay) -> Vec<Gauge> {
+    let mut names = relay.channel_names();
+    names.sort();
+    let mut gauges = Vec::new();
+    let mut live = 0;
+    let mut listeners = 0;
+    for name in &names {
+        let channel = Some(name.as_str());
+        let has_source = relay.has_source(name);
+        let listener_count = relay.listener_count(name) as u64;
+        live += has_source as u64;
+        listeners += listener_count;
+        gauges.push(Gauge::new("listeners", channel, listener_count));
+        gauges.push(Gauge::new("live", channel, has_source as u64));
+        if let Some(bitrate) = relay.bitrate(name).filter(|_| has_source) {
+            gauges.push(Gauge::new("bitrate", channel, bitrate));
+        }
+    }
+    gauges.push(Gauge::new("channels", None, live));
+    gauges.push(Gauge::new("listeners", None, listeners));
+    gauges.push(Gauge::new("memory", None, relay.budget().used() as u64));
+    gauges
+}
+
+/// Keep only characters that can't be mistaken for StatsD syntax; a name
+/// that had others replaced gets a hash of the original added, so e.g.
+/// `main/720p` and `main.720p` don't share gauges
+fn sanitize(name: &str) -> String {
+    let is_safe = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_';
+    if name.chars().all(is_safe) {
+        return name.to_string();
+    }
+    let replaced: String = name.chars().map(|c| if is_safe(c) { c } else { '_' }).collect();
+    format!("{}_{}", replaced, hex::encode(&Sha256::digest(name.as_bytes())[..4]))
+}
+
+/// A gauge as a StatsD line, e.g. `webmetro.channel.main.listeners:3|g`
+pub fn format_gauge(options: &StatsdOptions, gauge: &Gauge) -> String {
+    match (&options.tags, &gauge.channel) {
+        (None, None) => format!("{}.{}:{}|g", options.prefix, gauge.name, gauge.value),
+        (None, Some(channel)) => format!("{}.channel.{}.{}:{}|g", options.prefix, sanitize(channel), gauge.name, gauge.value),
+        (Some(tags), channel) => {
+            let mut tags = tags.clone();
+            let name = match channel {
+                Some(channel) => {
+                    tags.push(format!("channel:{}", sanitize(channel)));
+                    format!("{}.channel.{}", options.prefix, gauge.name)
+                },
+                None => format!("{}.{}", options.prefix, gauge.name),
+            };
+            if tags.is_empty() {
+                format!("{}:{}|g", name, gauge.value)
+            } else {
+                format!("{}:{}|g|#{}", name, gauge.value, tags.join(","))
+            }
+        }
+    }
+}
+
+/// Pack lines into as few datagrams as fit them
+pub fn packets(lines: &[String]) -> Vec<String> {
+    let mut packets: Vec<String> = Vec::new();
+    for line in lines {
+        match packets.last_mut() {
+            Some(packet) if packet.len() + 1 + line.len() <= MAX_PACKET => {
+                packet.push('\n');
+                packet.push_str(line);
+            },
+            _ => packets.push(line.clone()),
+        }
+    }
+    packets
+}
+
+/// Send the relay's gauges to the collector at `address` every
+/// `options.interval`, forever; sending failures are only logged, since
+/// a collector may come & go
+pub fn statsd(relay: Arc<Relay>, address: SocketAddr, options: StatsdOptions) -> Result<impl Future<Output = ()>, WebmetroError> {
+    let bind: SocketAddr = if address.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+    let socket = UdpSocket::bind(bind)?;
+    // a full send buffer drops metrics rather than stalling the relay
+    socket.set_nonblocking(true)?;
+    socket.connect(address)?;
+
+    Ok(async move {
+        let mut ticks = interval(options.interval);
+        loop {
+            ticks.tick().await;
+            let lines: Vec<String> = relay_gauges(&relay).iter()
+                .map(|gauge| format_gauge(&options, gauge))
+                .collect();
+            for packet in packets(&lines) {
+                if let Err(err) = socket.send(packet.as_bytes()) {
+                    debug!("Couldn't send metrics to {}: {}", address, err);
+                }
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::server::RelayOptions;
+    use crate::statsd::*;
+
+    #[test]
+    fn format_gauges() {
+        let listeners = Gauge::new("listeners", Some("main/720p"), 3);
+        let memory = Gauge::new("memory", None, 1024);
+
+        let plain = StatsdOptions::default();
+        assert_eq!(format_gauge(&plain, &listeners), "webmetro.channel.main_720p_bcc9bbeb.listeners:3|g");
+        assert_eq!(format_gauge(&plain, &memory), "webmetro.memory:1024|g");
+        // names that needed changes can't collide with each other, or with ones that didn't
+        assert_eq!(sanitize("main-720p_2"), "main-720p_2");
+        assert_ne!(sanitize("main.720p"), sanitize("main/720p"));
+
+        let tagged = StatsdOptions { tags: Some(vec!["env:prod".into()]), ..StatsdOptions::default() };
+        assert_eq!(format_gauge(&tagged, &listeners), "webmetro.channel.listeners:3|g|#env:prod,channel:main_720p_bcc9bbeb");
+        assert_eq!(format_gauge(&tagged, &memory), "webmetro.memory:1024|g|#env:prod");
+        let untagged = StatsdOptions { tags: Some(Vec::new()), ..StatsdOptions::default() };
+        assert_eq!(format_gauge(&untagged, &memory), "webmetro.memory:1024|g");
+    }
+
+    #[test]
+    fn pack_datagrams() {
+        let lines: Vec<String> = (0..100).map(|n| format!("webmetro.channel.channel-{}.listeners:0|g", n)).collect();
+        let packets = packets(&lines);
+        assert!(packets.len() > 1);
+        assert!(packets.iter().all(|packet| packet.len() <= MAX_PACKET));
+        assert_eq!(packets.join("\n"), lines.join("\n"));
+    }
+
+    #[test]
+    fn read_relay_gauges() {
+        let relay = Relay::new(RelayOptions::default());
+        let _channel = relay.channel("main");
+        let gauges = relay_gauges(&relay);
+        assert!(gauges.contains(&Gauge::new("live", Some("main"), 0)));
+        assert!(gauges.contains(&Gauge::new("channels", None, 0)));
+        assert!(gauges.contains(&Gauge::new("memory", None, 0)));
+    }
+}
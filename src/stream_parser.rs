@@ -1,15 +1,40 @@
 use bytes::{Buf, BufMut, Bytes, BytesMut};
-use futures::{TryStreamExt, stream::{Stream, StreamExt}};
+use futures::{future::poll_fn, stream::{Stream, StreamExt}};
 use std::task::{Context, Poll};
+use tracing::Span;
 
-use crate::ebml::FromEbml;
-use crate::error::WebmetroError;
+use crate::ebml::{encode_varint, EbmlLayout, FromEbml, Varint, EBML_HEAD_ID, VOID_ID};
+use crate::error::{Limit, WebmetroError};
+
+/// Longest possible tag header: an 8-byte ID varint & an 8-byte size varint
+const MAX_HEADER_LEN: usize = 16;
 
 pub struct EbmlStreamingParser<S> {
     stream: S,
+    /// an element spanning several reads, copied together
     buffer: BytesMut,
+    /// input following `buffer` as it was received; elements lying entirely
+    /// within it are shared with the source's buffers rather than copied
+    input: Bytes,
     buffer_size_limit: Option<usize>,
     borrowed: Bytes,
+    offset: u64,
+    resync: bool,
+    /// where corrupt input began, while scanning for a place to resume
+    skip_start: Option<u64>,
+    /// scanning for the first place to start, having joined the input partway
+    joining: bool,
+    /// IDs of elements passed over without buffering their payloads
+    ignored: Vec<u64>,
+    /// how much of an ignored element is still to be dropped as it arrives
+    discarding: u64,
+    span: Span,
+}
+
+/// What the parser found next in its input
+enum Parsed {
+    Element { offset: u64, info: EbmlLayout },
+    Skipped { offset: u64, length: u64 },
 }
 
 impl<S> EbmlStreamingParser<S> {
@@ -20,6 +45,199 @@ impl<S> EbmlStreamingParser<S> {
         self.buffer_size_limit = Some(limit);
         self
     }
+
+    /// on corrupt input (including hitting the soft limit), skip ahead to the
+    /// next element with the ID given by `FromEbml::resync_id` and report what
+    /// was lost with a `FromEbml::skipped` event, instead of erroring the stream
+    pub fn with_resync(mut self) -> Self {
+        self.resync = true;
+        self
+    }
+
+    /// tolerate input that starts partway through a stream, without its EBML
+    /// header: skip ahead to the first EBML header or element with the ID
+    /// given by `FromEbml::resync_id` (for WebM, a Cluster), reporting what
+    /// was passed over with a `FromEbml::skipped` event. The chunker gives a
+    /// stream starting at a Cluster an empty Headers chunk, which
+    /// `ChunkStream::fill_headers` can fill in from elsewhere. Since an ID
+    /// can turn up inside other elements' payloads by chance, pair this with
+    /// `with_resync` to recover from a false start.
+    pub fn joining_mid_stream(mut self) -> Self {
+        self.joining = true;
+        self.skip_start = Some(self.offset);
+        self
+    }
+
+    /// also pass over elements with this ID without buffering them: like
+    /// Voids, one that hasn't arrived whole is reported with an empty payload,
+    /// and the rest dropped as it comes in, so padding or an index of any
+    /// size takes no memory & doesn't count towards the soft limit. Only for
+    /// elements whose `FromEbml::decode` can do without their payload.
+    pub fn ignoring(mut self, element_id: u64) -> Self {
+        self.ignored.push(element_id);
+        self
+    }
+
+    /// The number of bytes of input consumed so far; this is the stream offset
+    /// of the next element (or parse error) to be returned.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    fn buffered(&self) -> usize {
+        self.buffer.len() + self.input.len()
+    }
+
+    /// Find the next element, if all of it is available. Elements arriving in
+    /// a single read are left in `input`, while the start of one that isn't
+    /// complete is moved to `buffer` to collect the rest as it arrives.
+    fn find_element<'a, T: FromEbml<'a>>(&mut self) -> Result<Option<EbmlLayout>, crate::ebml::EbmlError> {
+        if self.buffer.is_empty() {
+            let info = T::check_space(&self.input)?;
+            if info.is_none() {
+                self.buffer.extend_from_slice(&self.input);
+                self.input = Bytes::new();
+            }
+            return Ok(info);
+        }
+
+        loop {
+            let wanted = match T::peek_layout(&self.buffer)? {
+                Some(info) if info.element_len <= self.buffer.len() => return Ok(Some(info)),
+                Some(info) => info.element_len - self.buffer.len(),
+                None => MAX_HEADER_LEN,
+            };
+            if self.input.is_empty() {
+                return Ok(None);
+            }
+            let moved = self.input.split_to(wanted.min(self.input.len()));
+            self.buffer.extend_from_slice(&moved);
+        }
+    }
+
+    fn take_element(&mut self, info: &EbmlLayout) {
+        self.offset += info.element_len as u64;
+        let mut bytes = if self.buffer.is_empty() {
+            self.input.split_to(info.element_len)
+        } else {
+            self.buffer.split_to(info.element_len).freeze()
+        };
+        bytes.advance(info.body_offset);
+        self.borrowed = bytes;
+    }
+
+    /// The layout of the incomplete element starting the buffer, if it's one
+    /// to pass over rather than collect
+    fn ignorable<'a, T: FromEbml<'a>>(&self) -> Option<EbmlLayout> {
+        T::peek_layout(&self.buffer).ok()?.filter(|info| {
+            self.ignored.contains(&info.element_id) && !T::should_unwrap(info.element_id)
+        })
+    }
+
+    /// Report an ignored element without its payload, and start dropping it
+    fn ignore_element(&mut self, info: &EbmlLayout) {
+        self.offset += info.element_len as u64;
+        self.discarding = info.element_len as u64;
+        self.borrowed = Bytes::new();
+        self.discard_ignored();
+    }
+
+    /// Drop as much of an ignored element as has arrived
+    fn discard_ignored(&mut self) {
+        let from_buffer = self.discarding.min(self.buffer.len() as u64) as usize;
+        self.buffer.advance(from_buffer);
+        self.discarding -= from_buffer as u64;
+        let from_input = self.discarding.min(self.input.len() as u64) as usize;
+        self.input.advance(from_input);
+        self.discarding -= from_input as u64;
+    }
+
+    fn refill(&mut self, mut buf: impl Buf) {
+        if self.skip_start.is_none() && self.input.is_empty() {
+            // for Bytes input, this shares the buffer instead of copying it
+            self.input = buf.to_bytes();
+        } else {
+            self.buffer.reserve(buf.remaining());
+            self.buffer.put(buf);
+        }
+    }
+
+    fn over_limit(&self) -> Option<WebmetroError> {
+        match self.buffer_size_limit {
+            Some(limit) if limit <= self.buffered() => Some(WebmetroError::LimitExceeded {
+                limit: Limit::ParserBuffer(limit),
+            }),
+            _ => None
+        }
+    }
+
+    /// Begin skipping corrupt input if resyncing is possible,
+    /// otherwise pass back the error that ends the stream.
+    fn corrupted<'a, T: FromEbml<'a>>(&mut self, error: WebmetroError) -> Result<(), WebmetroError> {
+        if !self.resync || T::resync_id().is_none() {
+            return Err(error);
+        }
+        warn!(offset = self.offset, "{}; skipping ahead", error);
+        self.skip_start = Some(self.offset);
+        // gather everything in one place to scan
+        self.buffer.extend_from_slice(&self.input);
+        self.input = Bytes::new();
+        Ok(())
+    }
+
+    /// Discard input up to the next resync element (or, when joining a
+    /// stream partway, the next EBML header); once one is found, returns the
+    /// offset & length of the input skipped over.
+    fn skip_to_resync_point<'a, T: FromEbml<'a>>(&mut self) -> Option<(u64, u64)> {
+        let start = self.skip_start?;
+        let ids = T::resync_id().into_iter().chain(if self.joining { Some(EBML_HEAD_ID) } else { None });
+        let markers: Vec<Vec<u8>> = ids.filter_map(|id| {
+            let mut marker = Vec::new();
+            encode_varint(Varint::Value(id), &mut marker).ok()?;
+            Some(marker)
+        }).collect();
+        let longest = markers.iter().map(Vec::len).max()?;
+
+        // don't resume at the same place that just failed; nothing has when joining
+        let search_from = if start == self.offset && !self.joining { 1 } else { 0 };
+        let found = markers.iter().filter_map(|marker| {
+            self.buffer.windows(marker.len())
+                .skip(search_from)
+                .position(|window| window == &marker[..])
+                .map(|position| position + search_from)
+        }).min();
+        let discard = match found {
+            Some(position) => position,
+            // keep what might be the start of a marker split across reads
+            None => self.buffer.len().saturating_sub(longest - 1),
+        };
+        self.buffer.advance(discard);
+        self.offset += discard as u64;
+
+        found?;
+        self.skip_start = None;
+        self.joining = false;
+        Some((start, self.offset - start))
+    }
+
+    /// Construct the event for what was found; a corrupt element is
+    /// reported as skipped when resyncing, since its extent is known.
+    fn decode<'a, T: FromEbml<'a>>(&'a self, parsed: Parsed) -> Result<T, WebmetroError> {
+        match parsed {
+            Parsed::Element { offset, info } => T::decode(info.element_id, &self.borrowed).or_else(|source| {
+                let error = WebmetroError::ParseError { offset, source };
+                match T::skipped(offset, info.element_len as u64) {
+                    Some(skipped) if self.resync => {
+                        warn!(offset, "{}; skipping element", error);
+                        Ok(skipped)
+                    },
+                    _ => Err(error),
+                }
+            }),
+            Parsed::Skipped { offset, length } => T::skipped(offset, length)
+                .ok_or_else(|| "Skipped input can't be reported".into()),
+        }
+    }
 }
 
 pub trait StreamEbml: Sized {
@@ -27,8 +245,16 @@ pub trait StreamEbml: Sized {
         EbmlStreamingParser {
             stream: self,
             buffer: BytesMut::new(),
+            input: Bytes::new(),
             buffer_size_limit: None,
             borrowed: Bytes::new(),
+            offset: 0,
+            resync: false,
+            skip_start: None,
+            joining: false,
+            ignored: vec![VOID_ID],
+            discarding: 0,
+            span: debug_span!("parse"),
         }
     }
 }
@@ -40,35 +266,54 @@ impl<I: Buf, E, S: Stream<Item = Result<I, E>> + Unpin> EbmlStreamingParser<S>
 where
     WebmetroError: From<E>,
 {
-    pub fn poll_event<'a, T: FromEbml<'a>>(
-        &'a mut self,
-        cx: &mut Context,
-    ) -> Poll<Option<Result<T, WebmetroError>>> {
+    fn poll_parsed<'a, T: FromEbml<'a>>(&mut self, cx: &mut Context) -> Poll<Option<Result<Parsed, WebmetroError>>> {
+        let span = self.span.clone();
+        let _enter = span.enter();
         loop {
-            match T::check_space(&self.buffer)? {
-                None => {
-                    // need to refill buffer, below
-                }
-                Some(info) => {
-                    let mut bytes = self.buffer.split_to(info.element_len).freeze();
-                    bytes.advance(info.body_offset);
-                    self.borrowed = bytes;
-                    return Poll::Ready(Some(
-                        T::decode(info.element_id, &self.borrowed).map_err(Into::into),
-                    ));
+            let offset = self.offset;
+            let parse_error = move |source| WebmetroError::ParseError { offset, source };
+
+            if self.discarding > 0 {
+                self.discard_ignored();
+            }
+
+            if self.discarding > 0 {
+                // the rest of an ignored element is still to come
+            } else if self.skip_start.is_none() {
+                match self.find_element::<T>() {
+                    Ok(None) => if let Some(info) = self.ignorable::<T>() {
+                        self.ignore_element(&info);
+                        return Poll::Ready(Some(Ok(Parsed::Element { offset, info })));
+                    },
+                    Ok(Some(info)) => {
+                        self.take_element(&info);
+                        return Poll::Ready(Some(Ok(Parsed::Element { offset, info })));
+                    }
+                    Err(source) => if let Err(err) = self.corrupted::<T>(parse_error(source)) {
+                        return Poll::Ready(Some(Err(err)));
+                    }
                 }
             }
 
-            if let Some(limit) = self.buffer_size_limit {
-                if limit <= self.buffer.len() {
-                    return Poll::Ready(Some(Err(WebmetroError::ResourcesExceeded)));
+            if self.skip_start.is_some() {
+                if let Some((offset, length)) = self.skip_to_resync_point::<T>() {
+                    // joining right at the start of an element skips nothing
+                    if length > 0 && T::skipped(offset, length).is_some() {
+                        return Poll::Ready(Some(Ok(Parsed::Skipped { offset, length })));
+                    }
+                    continue;
                 }
+            } else if let Some(err) = self.over_limit() {
+                // hit our buffer limit and still nothing parsed
+                if let Err(err) = self.corrupted::<T>(err) {
+                    return Poll::Ready(Some(Err(err)));
+                }
+                continue;
             }
 
             match self.stream.poll_next_unpin(cx)? {
                 Poll::Ready(Some(buf)) => {
-                    self.buffer.reserve(buf.remaining());
-                    self.buffer.put(buf);
+                    self.refill(buf);
                     // ok can retry decoding now
                 }
                 Poll::Ready(None) => return Poll::Ready(None),
@@ -77,32 +322,34 @@ where
         }
     }
 
-    pub async fn next<'a, T: FromEbml<'a>>(&'a mut self) -> Result<Option<T>, WebmetroError> {
-        loop {
-            if let Some(info) = T::check_space(&self.buffer)? {
-                let mut bytes = self.buffer.split_to(info.element_len).freeze();
-                bytes.advance(info.body_offset);
-                self.borrowed = bytes;
-                return Ok(Some(T::decode(info.element_id, &self.borrowed)?));
-            }
+    pub fn poll_event<'a, T: FromEbml<'a>>(
+        &'a mut self,
+        cx: &mut Context,
+    ) -> Poll<Option<Result<T, WebmetroError>>> {
+        self.poll_parsed::<T>(cx).map(|parsed| parsed.map(|parsed| parsed.and_then(move |parsed| self.decode(parsed))))
+    }
 
-            if let Some(limit) = self.buffer_size_limit {
-                if limit <= self.buffer.len() {
-                    // hit our buffer limit and still nothing parsed
-                    return Err(WebmetroError::ResourcesExceeded);
-                }
-            }
+    /// Like `poll_event`, but also hands back the element's body as shared Bytes,
+    /// so a borrowed payload (such as `SimpleBlock::data`) can be kept without
+    /// copying it, via `Bytes::slice_ref`.
+    pub fn poll_event_with_body<'a, T: FromEbml<'a>>(
+        &'a mut self,
+        cx: &mut Context,
+    ) -> Poll<Option<Result<(T, Bytes), WebmetroError>>> {
+        self.poll_parsed::<T>(cx).map(|parsed| parsed.map(|parsed| parsed.and_then(move |parsed| {
+            let body = match parsed {
+                Parsed::Element {..} => self.borrowed.clone(),
+                Parsed::Skipped {..} => Bytes::new(),
+            };
+            Ok((self.decode(parsed)?, body))
+        })))
+    }
 
-            match self.stream.try_next().await? {
-                Some(refill) => {
-                    self.buffer.reserve(refill.remaining());
-                    self.buffer.put(refill);
-                }
-                None => {
-                    // Nothing left, we're done
-                    return Ok(None);
-                }
-            }
+    pub async fn next<'a, T: FromEbml<'a>>(&'a mut self) -> Result<Option<T>, WebmetroError> {
+        match poll_fn(|cx| self.poll_parsed::<T>(cx)).await {
+            Some(parsed) => self.decode(parsed?).map(Some),
+            // Nothing left, we're done
+            None => Ok(None),
         }
     }
 }
@@ -182,15 +429,190 @@ mod tests {
                 .map(|bytes| Ok::<&[u8], WebmetroError>(&bytes[..]))
                 .parse_ebml();
 
+            assert_matches!(parser.next().await?, Some(WebmElement::EbmlHead));
+            assert_eq!(parser.offset(), 15);
+            assert_matches!(parser.next().await?, Some(WebmElement::Segment));
+            assert_matches!(parser.next().await?, Some(WebmElement::Tracks(_)));
+            assert_matches!(parser.next().await?, Some(WebmElement::Cluster));
+            assert_matches!(parser.next().await?, Some(WebmElement::Timecode(0)));
+            assert_matches!(parser.next().await?, Some(WebmElement::SimpleBlock(_)));
+            assert_matches!(parser.next().await?, Some(WebmElement::Cluster));
+            assert_matches!(parser.next().await?, Some(WebmElement::Timecode(1000)));
+
+            Result::<(), WebmetroError>::Ok(())
+        }
+            .now_or_never()
+            .expect("Test tried to block on I/O")
+            .expect("Parse failed");
+    }
+
+    #[test]
+    fn share_input_buffers() {
+        let input = Bytes::from_static(ENCODE_WEBM_TEST_FILE);
+        let input_range = input.as_ptr() as usize..input.as_ptr() as usize + input.len();
+        // split mid-Tracks, so at least one element must be reassembled
+        let pieces = vec![input.slice(0..40), input.slice(40..)];
+
+        poll_fn(|cx| {
+            let mut parser = futures::stream::iter(pieces.clone())
+                .map(Ok::<Bytes, WebmetroError>)
+                .parse_ebml();
+
+            loop {
+                match parser.poll_event_with_body(cx) {
+                    Ready(Some(Ok((WebmElement::SimpleBlock(block), body)))) => {
+                        let payload = body.slice_ref(block.data);
+                        assert!(input_range.contains(&(payload.as_ptr() as usize)));
+                        break;
+                    },
+                    Ready(Some(Ok(_))) => {},
+                    _ => panic!("no SimpleBlock found"),
+                }
+            }
+
+            std::task::Poll::Ready(())
+        })
+        .now_or_never()
+        .expect("Test tried to block on I/O");
+    }
+
+    /// the test file with garbage inserted just before its second Cluster
+    fn corrupted_file(garbage: &[u8]) -> (Vec<u8>, usize) {
+        let cluster_id = [0x1F, 0x43, 0xB6, 0x75];
+        let second_cluster = ENCODE_WEBM_TEST_FILE.windows(4)
+            .enumerate()
+            .filter(|(_, window)| window == &cluster_id)
+            .nth(1)
+            .unwrap().0;
+        let mut file = ENCODE_WEBM_TEST_FILE[..second_cluster].to_vec();
+        file.extend_from_slice(garbage);
+        file.extend_from_slice(&ENCODE_WEBM_TEST_FILE[second_cluster..]);
+        (file, second_cluster)
+    }
+
+    #[test]
+    fn resync_after_corruption() {
+        let (file, second_cluster) = corrupted_file(&[0; 10]);
+        let pieces: Vec<&[u8]> = file.chunks(7).collect();
+
+        async {
+            let mut parser = futures::stream::iter(pieces.iter())
+                .map(|bytes| Ok::<&[u8], WebmetroError>(&bytes[..]))
+                .parse_ebml()
+                .with_resync();
+
             assert_matches!(parser.next().await?, Some(WebmElement::EbmlHead));
             assert_matches!(parser.next().await?, Some(WebmElement::Segment));
             assert_matches!(parser.next().await?, Some(WebmElement::Tracks(_)));
             assert_matches!(parser.next().await?, Some(WebmElement::Cluster));
             assert_matches!(parser.next().await?, Some(WebmElement::Timecode(0)));
             assert_matches!(parser.next().await?, Some(WebmElement::SimpleBlock(_)));
+            assert_eq!(parser.next().await?, Some(WebmElement::Skipped {
+                offset: second_cluster as u64,
+                length: 10
+            }));
+            assert_matches!(parser.next().await?, Some(WebmElement::Cluster));
+            assert_matches!(parser.next().await?, Some(WebmElement::Timecode(1000)));
+
+            Result::<(), WebmetroError>::Ok(())
+        }
+            .now_or_never()
+            .expect("Test tried to block on I/O")
+            .expect("Parse failed");
+    }
+
+    #[test]
+    fn join_mid_stream() {
+        let (_, second_cluster) = corrupted_file(&[]);
+        let partway = &ENCODE_WEBM_TEST_FILE[second_cluster - 5..];
+        let pieces: Vec<&[u8]> = partway.chunks(7).collect();
+
+        async {
+            // partway through the first Cluster, what's passed over is reported
+            let mut parser = futures::stream::iter(pieces.iter())
+                .map(|bytes| Ok::<&[u8], WebmetroError>(&bytes[..]))
+                .parse_ebml()
+                .joining_mid_stream();
+            assert_eq!(parser.next().await?, Some(WebmElement::Skipped {
+                offset: 0,
+                length: 5
+            }));
+            assert_matches!(parser.next().await?, Some(WebmElement::Cluster));
+            assert_matches!(parser.next().await?, Some(WebmElement::Timecode(1000)));
+
+            // right at a Cluster, nothing is
+            let mut parser = futures::stream::iter(vec![Ok::<&[u8], WebmetroError>(&ENCODE_WEBM_TEST_FILE[second_cluster..])])
+                .parse_ebml()
+                .joining_mid_stream();
             assert_matches!(parser.next().await?, Some(WebmElement::Cluster));
             assert_matches!(parser.next().await?, Some(WebmElement::Timecode(1000)));
 
+            // and a whole stream starts at its EBML header as usual
+            let mut parser = futures::stream::iter(vec![Ok::<&[u8], WebmetroError>(ENCODE_WEBM_TEST_FILE)])
+                .parse_ebml()
+                .joining_mid_stream();
+            assert_matches!(parser.next().await?, Some(WebmElement::EbmlHead));
+            assert_matches!(parser.next().await?, Some(WebmElement::Segment));
+
+            Result::<(), WebmetroError>::Ok(())
+        }
+            .now_or_never()
+            .expect("Test tried to block on I/O")
+            .expect("Parse failed");
+    }
+
+    #[test]
+    fn pass_over_large_elements() {
+        let mut file = std::io::Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::EbmlHead, &mut file).unwrap();
+        encode_webm_element(WebmElement::Segment, &mut file).unwrap();
+        crate::ebml::encode_bytes(VOID_ID, &vec![0; 1_000_000], &mut file).unwrap();
+        crate::ebml::encode_bytes(CUES_ID, &vec![0; 200_000], &mut file).unwrap();
+        encode_webm_element(WebmElement::Cluster, &mut file).unwrap();
+        encode_webm_element(WebmElement::Timecode(0), &mut file).unwrap();
+        let file = file.into_inner();
+        let pieces = || futures::stream::iter(file.chunks(4096).map(|piece| Ok::<_, WebmetroError>(Bytes::copy_from_slice(piece))).collect::<Vec<_>>());
+
+        async {
+            let mut parser = pieces().parse_ebml().with_soft_limit(65536).ignoring(CUES_ID);
+            assert_matches!(parser.next().await?, Some(WebmElement::EbmlHead));
+            assert_matches!(parser.next().await?, Some(WebmElement::Segment));
+            assert_matches!(parser.next().await?, Some(WebmElement::Void));
+            assert_matches!(parser.next().await?, Some(WebmElement::Cues));
+            assert_matches!(parser.next().await?, Some(WebmElement::Cluster));
+            assert_matches!(parser.next().await?, Some(WebmElement::Timecode(0)));
+            assert_eq!(parser.offset(), file.len() as u64);
+
+            // Cues are otherwise collected, and that's too much
+            let mut parser = pieces().parse_ebml().with_soft_limit(65536);
+            assert_matches!(parser.next().await?, Some(WebmElement::EbmlHead));
+            assert_matches!(parser.next().await?, Some(WebmElement::Segment));
+            assert_matches!(parser.next().await?, Some(WebmElement::Void));
+            assert_matches!(parser.next::<WebmElement>().await, Err(WebmetroError::LimitExceeded {..}));
+
+            Result::<(), WebmetroError>::Ok(())
+        }
+            .now_or_never()
+            .expect("Test tried to block on I/O")
+            .expect("Parse failed");
+    }
+
+    #[test]
+    fn fail_on_corruption() {
+        let (file, second_cluster) = corrupted_file(&[0; 10]);
+
+        async {
+            let mut parser = futures::stream::iter(vec![Ok::<&[u8], WebmetroError>(&file[..])])
+                .parse_ebml();
+
+            for _ in 0..6 {
+                parser.next::<WebmElement>().await?;
+            }
+            match parser.next::<WebmElement>().await {
+                Err(WebmetroError::ParseError { offset, .. }) => assert_eq!(offset, second_cluster as u64),
+                _ => panic!("Corruption should have been an error"),
+            }
+
             Result::<(), WebmetroError>::Ok(())
         }
             .now_or_never()
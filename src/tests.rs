@@ -0,0 +1,66 @@
+//! Test data & fixtures shared by the modules' tests
+
+use bytes::Bytes;
+
+use crate::chunk::{Chunk, ClusterHead};
+use crate::ebml::{encode_bytes, encode_integer};
+use crate::webm::{block_timing, encode_simple_block, parse_webm, SimpleBlock, BLOCK_GROUP_ID, BLOCK_ID, REFERENCE_BLOCK_ID, KEYFRAME};
+
+pub const TEST_FILE: &'static [u8] = include_bytes!("data/test1.webm");
+pub const ENCODE_WEBM_TEST_FILE: &'static [u8] = include_bytes!("data/encode_webm_test.webm");
+
+/// An encoded SimpleBlock with a few bytes of frame data
+pub fn block(track: u64, timecode: i16, keyframe: bool) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    let flags = if keyframe { KEYFRAME } else { 0 };
+    encode_simple_block(SimpleBlock { track, timecode, flags, data: &[0; 4] }, &mut buffer).unwrap();
+    buffer
+}
+
+/// An encoded BlockGroup like `block`'s, referring to an earlier frame
+pub fn block_group(track: u64, timecode: i16) -> Vec<u8> {
+    let mut payload = Vec::new();
+    let mut block = Vec::new();
+    encode_simple_block(SimpleBlock { track, timecode, flags: 0, data: &[0; 4] }, &mut block).unwrap();
+    // a SimpleBlock's payload is a Block's
+    encode_bytes(BLOCK_ID, &block[2..], &mut payload).unwrap();
+    encode_integer(REFERENCE_BLOCK_ID, -20i64 as u64, &mut payload).unwrap();
+    let mut buffer = Vec::new();
+    encode_bytes(BLOCK_GROUP_ID, &payload, &mut buffer).unwrap();
+    buffer
+}
+
+/// A Cluster of delta frames, given as (track, timecode)
+pub fn cluster(start: u64, blocks: &[(u64, i16)]) -> Chunk {
+    let mut head = ClusterHead::new(start);
+    let body: Vec<u8> = blocks.iter().flat_map(|&(track, timecode)| {
+        head.observe_simpleblock_timecode(timecode);
+        block(track, timecode, false)
+    }).collect();
+    Chunk::Cluster(head, Bytes::from(body))
+}
+
+/// A Cluster with no blocks, for tests that only look at its head
+pub fn empty_cluster(start: u64, keyframe: bool) -> Chunk {
+    let mut head = ClusterHead::new(start);
+    head.keyframe = keyframe;
+    Chunk::Cluster(head, Bytes::new())
+}
+
+/// The track & timecode of each block in a Cluster
+pub fn timecodes(chunk: &Chunk) -> Vec<(u64, i16)> {
+    match chunk {
+        Chunk::Cluster(_, body) => parse_webm(body).filter_map(|element| {
+            block_timing(&element).map(|(block, _)| (block.track, block.timecode))
+        }).collect(),
+        _ => panic!("not a Cluster"),
+    }
+}
+
+/// Where each Cluster starts, with `None` for other chunks
+pub fn starts(chunks: &[Chunk]) -> Vec<Option<u64>> {
+    chunks.iter().map(|chunk| match chunk {
+        Chunk::Cluster(head, _) => Some(head.start),
+        _ => None,
+    }).collect()
+}
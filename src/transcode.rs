@@ -0,0 +1,269 @@
+//! Pipes channels' sources through external commands, like an ffmpeg
+//! invocation, publishing what each command writes as a channel of its own.
+//! A transcode is given as `<suffix>=<command>`: while a channel (say
+//! `main`) has a source, the command is run, reading the source's WebM on
+//! stdin, and its stdout is published as `main_<suffix>`, unless that
+//! channel has a publisher of its own. The command is run directly rather
+//! than by a shell, split into words like a hook's (see
+//! `hooks::command_words`), and gets `WEBMETRO_CHANNEL` in its environment.
+//!
+//! The source is fed to the command only as fast as it reads; one that falls
+//! behind loses whole Clusters up to the next keyframe, rather than holding
+//! up the relay. A command that exits while its source is still publishing
+//! is restarted after a delay, which doubles (up to a limit) each time it
+//! exits again without having run for that long.
+
+use std::collections::HashSet;
+use std::process::Stdio;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{future::Either, prelude::*};
+use tokio::{
+    io::AsyncWriteExt,
+    process::Command,
+    time::{delay_for, timeout},
+};
+use tokio_util::codec::{BytesCodec, FramedRead};
+use tracing_futures::Instrument;
+
+use crate::error::WebmetroError;
+use crate::events::Event;
+use crate::hooks::command_words;
+use crate::server::Relay;
+
+/// How long a command may take to exit after its output ends, before it's killed
+const EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a command's output may go on being published after its source
+/// ends, before the command is stopped
+const DRAIN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// A command to run on every channel's source
+#[derive(Clone, Debug, PartialEq)]
+pub struct Transcode {
+    /// added to the source channel's name to name the derived channel
+    pub suffix: String,
+    pub command: String,
+}
+
+/// Reads a transcode as `<suffix>=<command>`
+impl FromStr for Transcode {
+    type Err = WebmetroError;
+
+    fn from_str(spec: &str) -> Result<Transcode, WebmetroError> {
+        let mut parts = spec.splitn(2, '=');
+        match (parts.next(), parts.next()) {
+            (Some(suffix), Some(command))
+            if !suffix.is_empty()
+                && suffix.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                && !command.trim().is_empty() => {
+                command_words(command, &[])?;
+                Ok(Transcode {
+                    suffix: suffix.to_string(),
+                    command: command.to_string(),
+                })
+            },
+            _ => Err(WebmetroError::ApplicationError {
+                message: format!("Transcode {} should look like suffix=command, with a suffix of letters, digits, - or _", spec),
+            })
+        }
+    }
+}
+
+impl Transcode {
+    /// The channel a source channel's transcoded output is published to
+    pub fn derived_channel(&self, channel: &str) -> String {
+        format!("{}_{}", channel, self.suffix)
+    }
+}
+
+/// When commands are restarted
+#[derive(Clone, Debug, PartialEq)]
+pub struct TranscodeOptions {
+    /// how long to wait before restarting a command the first time
+    pub restart_delay: Duration,
+    /// the longest the wait grows to, and how long a command must run for
+    /// the wait to start over
+    pub max_restart_delay: Duration,
+}
+
+impl Default for TranscodeOptions {
+    fn default() -> TranscodeOptions {
+        TranscodeOptions {
+            restart_delay: Duration::from_secs(1),
+            max_restart_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Run a transcode's command once, until its output ends
+async fn run_command(relay: &Relay, channel: &str, transcode: &Transcode) -> Result<(), WebmetroError> {
+    let environment = [("WEBMETRO_CHANNEL", channel.to_string())];
+    let words = command_words(&transcode.command, &environment)?;
+    let mut child = Command::new(&words[0])
+        .args(&words[1..])
+        .envs(environment.iter().cloned())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .kill_on_drop(true)
+        .spawn()?;
+    let mut stdin = child.stdin.take().ok_or("Command has no stdin")?;
+    let stdout = child.stdout.take().ok_or("Command has no stdout")?;
+
+    let mut chunks = Box::pin(relay.source_chunks(channel));
+    let feed = async move {
+        while let Some(Ok(chunk)) = chunks.next().await {
+            for bytes in chunk {
+                if let Err(err) = stdin.write_all(&bytes).await {
+                    debug!("Stopped feeding the command: {}", err);
+                    return;
+                }
+            }
+        }
+        // dropping stdin closes it, so the command can finish up
+    };
+    let output = FramedRead::new(stdout, BytesCodec::new()).map_ok(|bytes| bytes.freeze());
+    // a channel with a publisher of its own is left alone
+    let publish = relay.publish(&transcode.derived_channel(channel), output);
+
+    let published = match future::select(Box::pin(feed), Box::pin(publish)).await {
+        // the source ended; publish whatever the command has left, for a while
+        Either::Left(((), publish)) => match timeout(DRAIN_TIMEOUT, publish).await {
+            Ok(published) => published,
+            Err(_) => {
+                warn!("Transcode \"{}\" went on too long after its source ended, stopped it", transcode.command);
+                Ok(())
+            }
+        },
+        // the output ended (or was unusable), so stop feeding the command
+        Either::Right((published, _)) => published,
+    };
+    match timeout(EXIT_TIMEOUT, child).await {
+        Ok(Ok(status)) if !status.success() => warn!("Transcode \"{}\" failed: {}", transcode.command, status),
+        Ok(Ok(_)) => {},
+        Ok(Err(err)) => return Err(err.into()),
+        Err(_) => warn!("Transcode \"{}\" didn't exit after its output ended, killed it", transcode.command),
+    }
+    published
+}
+
+/// Run a transcode's command on a channel's source, restarting it as need
+/// be, until the source stops publishing
+async fn run_transcode(relay: Arc<Relay>, channel: String, transcode: Transcode, options: TranscodeOptions) {
+    let mut delay = options.restart_delay;
+    loop {
+        let started = Instant::now();
+        match run_command(&relay, &channel, &transcode).await {
+            Ok(()) => debug!("Transcode \"{}\" finished", transcode.command),
+            Err(WebmetroError::ChannelBusy { .. }) => {
+                warn!("Channel {} has a source of its own, not transcoding to it", transcode.derived_channel(&channel));
+                return;
+            },
+            Err(err) => warn!("Transcode \"{}\" failed: {}", transcode.command, err),
+        }
+        if !relay.has_source(&channel) {
+            return;
+        }
+
+        if started.elapsed() >= options.max_restart_delay {
+            delay = options.restart_delay;
+        }
+        info!("Restarting transcode \"{}\" in {} ms", transcode.command, delay.as_millis());
+        delay_for(delay).await;
+        delay = (delay * 2).min(options.max_restart_delay);
+        if !relay.has_source(&channel) {
+            return;
+        }
+    }
+}
+
+/// Run every transcode on each channel as its source starts, until the
+/// returned future is dropped. Derived channels aren't transcoded again.
+pub fn transcoder(relay: Arc<Relay>, transcodes: Vec<Transcode>, options: TranscodeOptions) -> impl Future<Output = ()> {
+    // derived channels being published, by whichever transcode is running
+    let derived = Arc::new(Mutex::new(HashSet::new()));
+    // a missed start would leave a channel untranscoded
+    let mut events = relay.subscribe_lossless(None);
+    async move {
+        while let Some(event) = events.next().await {
+            let channel = match event {
+                Event::PublishStart { channel } => channel,
+                _ => continue,
+            };
+            // simulcast groups have no source of their own
+            if derived.lock().expect("Locking derived channels").contains(&channel) || !relay.has_source(&channel) {
+                continue;
+            }
+            for transcode in &transcodes {
+                let name = transcode.derived_channel(&channel);
+                if !derived.lock().expect("Locking derived channels").insert(name.clone()) {
+                    // still running from the last source
+                    continue;
+                }
+                info!("Transcoding channel {} to {}", channel, name);
+                let running = run_transcode(relay.clone(), channel.clone(), transcode.clone(), options.clone());
+                let derived = derived.clone();
+                tokio::spawn(async move {
+                    running.await;
+                    derived.lock().expect("Locking derived channels").remove(&name);
+                    info!("Stopped transcoding to {}", name);
+                }.instrument(info_span!("transcode", channel = %channel)));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use futures::stream;
+
+    use crate::server::RelayOptions;
+    use crate::tests::TEST_FILE;
+    use crate::transcode::*;
+
+    #[test]
+    fn parse_transcodes() {
+        let transcode: Transcode = "480p=ffmpeg -i - -s 854x480 -f webm -".parse().unwrap();
+        assert_eq!(transcode.suffix, "480p");
+        assert_eq!(transcode.command, "ffmpeg -i - -s 854x480 -f webm -");
+        assert_eq!(transcode.derived_channel("main"), "main_480p");
+        assert_eq!("a=b=c".parse::<Transcode>().unwrap().command, "b=c");
+        assert!("480p=".parse::<Transcode>().is_err());
+        assert!("=cat".parse::<Transcode>().is_err());
+        assert!("480/p=cat".parse::<Transcode>().is_err());
+        assert!("cat".parse::<Transcode>().is_err());
+        assert!("480p=ffmpeg -metadata 'title".parse::<Transcode>().is_err());
+    }
+
+    #[test]
+    fn transcode_channels() {
+        let mut runtime = tokio::runtime::Builder::new().basic_scheduler().enable_all().build().unwrap();
+        let relay = Arc::new(Relay::new(RelayOptions::default()));
+        let transcodes = vec!["copy=cat".parse().unwrap()];
+        runtime.spawn(transcoder(relay.clone(), transcodes, TranscodeOptions::default()));
+
+        runtime.block_on(async {
+            let mut copied = relay.subscribe(Some("main_copy"));
+            let source = relay.clone();
+            tokio::spawn(async move {
+                // let the transcoder subscribe first
+                delay_for(Duration::from_millis(50)).await;
+                let body = stream::once(future::ok::<_, WebmetroError>(Bytes::from_static(TEST_FILE)))
+                    .chain(stream::pending());
+                source.publish("main", body).await.ok();
+            });
+            let started = timeout(Duration::from_secs(5), async {
+                while let Some(event) = copied.next().await {
+                    if let Event::PublishStart { .. } = event {
+                        return;
+                    }
+                }
+            }).await;
+            assert!(started.is_ok());
+            assert!(relay.has_source("main_copy"));
+        });
+    }
+}
@@ -0,0 +1,111 @@
+//! A JavaScript interface to the chunker, for splitting WebM (such as from
+//! `MediaRecorder`) into chunks in the browser before uploading it.
+//!
+//! ```js
+//! const chunker = new Chunker(2 * 1024 * 1024);
+//! recorder.ondataavailable = async (event) => {
+//!     chunker.push(new Uint8Array(await event.data.arrayBuffer()));
+//!     let chunk;
+//!     while ((chunk = chunker.next())) {
+//!         upload(chunk.data, chunk.start, chunk.keyframe);
+//!     }
+//! };
+//! ```
+
+use bytes::Bytes;
+use wasm_bindgen::prelude::*;
+
+use crate::chunk::{Chunk as WebmChunk, ChunkerOptions, PushChunker};
+
+#[wasm_bindgen]
+pub struct Chunker {
+    chunker: PushChunker,
+}
+
+#[wasm_bindgen]
+impl Chunker {
+    /// `soft_limit` bounds the size of a chunk in bytes; 0 for no limit
+    #[wasm_bindgen(constructor)]
+    pub fn new(soft_limit: usize) -> Chunker {
+        let mut options = ChunkerOptions::new();
+        if soft_limit > 0 {
+            options = options.soft_limit(soft_limit);
+        }
+        Chunker {
+            chunker: PushChunker::new(options),
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) {
+        self.chunker.push(Bytes::copy_from_slice(data));
+    }
+
+    /// Signal the end of the input, so the final Cluster can be taken
+    pub fn finish(&mut self) {
+        self.chunker.finish();
+    }
+
+    /// The next completed chunk, or undefined if more input is needed;
+    /// throws if the input isn't usable WebM
+    pub fn next(&mut self) -> Result<Option<Chunk>, JsValue> {
+        while let Some(result) = self.chunker.next_chunk() {
+            let chunk = result.map_err(|err| JsValue::from_str(&err.to_string()))?;
+            let (is_headers, keyframe, start, end) = match chunk {
+                WebmChunk::Headers {..} => (true, false, 0, 0),
+                WebmChunk::Cluster(ref head, _) => (false, head.keyframe, head.start, head.end),
+                // the chunker only produces the above
+                _ => continue,
+            };
+            return Ok(Some(Chunk {
+                is_headers,
+                keyframe,
+                start,
+                end,
+                data: chunk.flatten().collect(),
+            }));
+        }
+        Ok(None)
+    }
+}
+
+/// An initialization segment or Cluster, ready to upload
+#[wasm_bindgen]
+pub struct Chunk {
+    is_headers: bool,
+    keyframe: bool,
+    start: u64,
+    end: u64,
+    data: Vec<u8>,
+}
+
+#[wasm_bindgen]
+impl Chunk {
+    /// true for the initialization segment, false for a Cluster
+    #[wasm_bindgen(getter, js_name = isHeaders)]
+    pub fn is_headers(&self) -> bool {
+        self.is_headers
+    }
+
+    #[wasm_bindgen(getter)]
+    pub fn keyframe(&self) -> bool {
+        self.keyframe
+    }
+
+    /// a Cluster's first timecode, in milliseconds
+    #[wasm_bindgen(getter)]
+    pub fn start(&self) -> f64 {
+        self.start as f64
+    }
+
+    /// a Cluster's last timecode, in milliseconds
+    #[wasm_bindgen(getter)]
+    pub fn end(&self) -> f64 {
+        self.end as f64
+    }
+
+    /// the chunk's bytes, as a Uint8Array
+    #[wasm_bindgen(getter)]
+    pub fn data(&self) -> Vec<u8> {
+        self.data.clone()
+    }
+}
@@ -0,0 +1,299 @@
+//! Authorizes requests by asking an external service, so the relay needn't
+//! know every auth scheme itself. Each request is described to a configured
+//! URL in a JSON POST body:
+//!
+//! ```json
+//! {"channel":"main","role":"listen","token":"abc123","ip":"192.0.2.1"}
+//! ```
+//!
+//! where `token` is the bearer token or `?token=` parameter (or `null`), and
+//! `role` is what's being asked for, as in the access log. A 2xx answer
+//! allows it and a 4xx denies it. Allowing answers, and denials with 401
+//! or 403, are cached for a while; other 4xx denials (e.g. 429 Too Many
+//! Requests) are asked again next time. Anything else (another status, a connection error, or no answer in time) is a
+//! failure, which allows or denies the request depending on `FailurePolicy`.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use futures::{future::BoxFuture, prelude::*};
+use hyper::{client::HttpConnector, Body, Client, Request, StatusCode, Uri};
+use hyper_tls::HttpsConnector;
+use serde::Serialize;
+use tokio::time::timeout;
+
+use crate::access_log::role;
+use crate::error::WebmetroError;
+use crate::jwt::bearer_token;
+use crate::server::{AccessRequest, Authorizer};
+
+/// The most answers the cache holds; when it's full, expired answers are
+/// swept out before another is added, and then the oldest if need be
+const CACHE_SIZE: usize = 10_000;
+
+/// Whether to allow requests when the service can't give an answer
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailurePolicy {
+    Open,
+    Closed,
+}
+
+impl FromStr for FailurePolicy {
+    type Err = WebmetroError;
+
+    fn from_str(policy: &str) -> Result<FailurePolicy, WebmetroError> {
+        match policy {
+            "open" => Ok(FailurePolicy::Open),
+            "closed" => Ok(FailurePolicy::Closed),
+            _ => Err(WebmetroError::ApplicationError {
+                message: format!("Unknown failure policy \"{}\" (expected open or closed)", policy),
+            })
+        }
+    }
+}
+
+/// How the service is asked
+#[derive(Clone, Debug, PartialEq)]
+pub struct WebhookOptions {
+    /// how long an answer is reused for the same request
+    pub cache_ttl: Duration,
+    /// how long to wait for an answer
+    pub timeout: Duration,
+    pub on_failure: FailurePolicy,
+}
+
+impl Default for WebhookOptions {
+    fn default() -> WebhookOptions {
+        WebhookOptions {
+            cache_ttl: Duration::from_secs(30),
+            timeout: Duration::from_secs(5),
+            on_failure: FailurePolicy::Closed,
+        }
+    }
+}
+
+/// What an answer applies to: the role, channel, token & address asked about
+type CacheKey = (&'static str, String, Option<String>, Option<IpAddr>);
+
+/// Asks a URL whether each request may proceed; see the module docs
+pub struct WebhookAuthorizer {
+    url: Uri,
+    options: WebhookOptions,
+    client: Client<HttpsConnector<HttpConnector>>,
+    /// answers, and when they were given
+    cache: Arc<Mutex<HashMap<CacheKey, (bool, Instant)>>>,
+}
+
+impl WebhookAuthorizer {
+    pub fn new(url: &str, options: WebhookOptions) -> Result<WebhookAuthorizer, WebmetroError> {
+        let url: Uri = url.parse().map_err(|_| WebmetroError::ApplicationError {
+            message: format!("Invalid auth webhook URL {}", url),
+        })?;
+        Ok(WebhookAuthorizer {
+            url,
+            options,
+            client: Client::builder().build::<_, Body>(HttpsConnector::new()),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    fn cached(&self, key: &CacheKey) -> Option<bool> {
+        let cache = self.cache.lock().expect("Locking webhook cache");
+        cache.get(key)
+            .filter(|(_, time)| time.elapsed() < self.options.cache_ttl)
+            .map(|(allowed, _)| *allowed)
+    }
+}
+
+/// A request as the service is told about it
+#[derive(Serialize)]
+struct RequestJson<'a> {
+    channel: &'a str,
+    role: &'static str,
+    token: Option<&'a str>,
+    ip: Option<IpAddr>,
+}
+
+/// A request's description, as sent to the service
+pub fn request_json(request: &AccessRequest) -> String {
+    let description = RequestJson {
+        channel: &request.channel,
+        role: role(request.action),
+        token: request.credentials.as_deref().map(bearer_token),
+        ip: request.remote.map(|remote| remote.ip()),
+    };
+    serde_json::to_string(&description).expect("Serializing a request")
+}
+
+/// A clear answer from the service
+struct Answer {
+    allowed: bool,
+    /// whether it holds for the same request for a while; only 2xx, 401 &
+    /// 403 answers do
+    cacheable: bool,
+}
+
+/// Whether the service allows a request, if it gave a clear answer
+async fn ask(client: &Client<HttpsConnector<HttpConnector>>, url: Uri, body: String) -> Result<Answer, WebmetroError> {
+    let request = Request::post(url)
+        .header("Content-Type", "application/json")
+        .body(Body::from(body))?;
+    let response = client.request(request).await?;
+    let status = response.status();
+    if status.is_success() {
+        Ok(Answer { allowed: true, cacheable: true })
+    } else if status.is_client_error() {
+        let cacheable = status == StatusCode::UNAUTHORIZED || status == StatusCode::FORBIDDEN;
+        Ok(Answer { allowed: false, cacheable })
+    } else {
+        Err(WebmetroError::from_response(&response))
+    }
+}
+
+impl Authorizer for WebhookAuthorizer {
+    fn authorize(&self, request: &AccessRequest) -> BoxFuture<'static, bool> {
+        let key = (
+            role(request.action),
+            request.channel.clone(),
+            request.credentials.as_ref().map(|credentials| bearer_token(credentials).to_string()),
+            request.remote.map(|remote| remote.ip()),
+        );
+        if let Some(allowed) = self.cached(&key) {
+            return future::ready(allowed).boxed();
+        }
+
+        let client = self.client.clone();
+        let url = self.url.clone();
+        let body = request_json(request);
+        let options = self.options.clone();
+        let cache = self.cache.clone();
+        async move {
+            let answer = timeout(options.timeout, ask(&client, url, body)).await
+                .unwrap_or_else(|elapsed| Err(elapsed.into()));
+            match answer {
+                Ok(Answer { allowed, cacheable: false }) => allowed,
+                Ok(Answer { allowed, cacheable: true }) => {
+                    let mut cache = cache.lock().expect("Locking webhook cache");
+                    if cache.len() >= CACHE_SIZE && !cache.contains_key(&key) {
+                        cache.retain(|_, (_, time)| time.elapsed() < options.cache_ttl);
+                        if cache.len() >= CACHE_SIZE {
+                            let oldest = cache.iter()
+                                .min_by_key(|(_, (_, time))| *time)
+                                .map(|(oldest, _)| oldest.clone());
+                            if let Some(oldest) = oldest {
+                                cache.remove(&oldest);
+                            }
+                        }
+                    }
+                    cache.insert(key, (allowed, Instant::now()));
+                    allowed
+                },
+                Err(err) => {
+                    let allowed = options.on_failure == FailurePolicy::Open;
+                    warn!("Auth webhook failed ({}), {} request", err, if allowed { "allowing" } else { "denying" });
+                    allowed
+                }
+            }
+        }.boxed()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::channel::oneshot;
+    use hyper::{body::to_bytes, service::{make_service_fn, service_fn}, Response, Server};
+
+    use crate::server::Action;
+    use crate::webhook::*;
+
+    fn request(channel: &str, credentials: Option<&str>) -> AccessRequest {
+        AccessRequest {
+            action: Action::Listen,
+            channel: channel.into(),
+            credentials: credentials.map(str::to_string),
+            remote: Some(([192, 0, 2, 1], 5000).into()),
+            client_names: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn describe_requests() {
+        assert_eq!(request_json(&request("main", Some("Bearer abc123"))), r#"{"channel":"main","role":"listen","token":"abc123","ip":"192.0.2.1"}"#);
+        let mut anonymous = request("main", None);
+        anonymous.remote = None;
+        assert_eq!(request_json(&anonymous), r#"{"channel":"main","role":"listen","token":null,"ip":null}"#);
+        assert_eq!("open".parse::<FailurePolicy>().unwrap(), FailurePolicy::Open);
+        assert!("ajar".parse::<FailurePolicy>().is_err());
+    }
+
+    #[test]
+    fn ask_service() {
+        let mut runtime = tokio::runtime::Builder::new().basic_scheduler().enable_all().build().unwrap();
+        let asked = Arc::new(AtomicUsize::new(0));
+        let counter = asked.clone();
+        let (stop, stopped) = oneshot::channel::<()>();
+        let address = runtime.block_on(async move {
+            let service = make_service_fn(move |_| {
+                let counter = counter.clone();
+                async move {
+                    Ok::<_, Infallible>(service_fn(move |request: Request<Body>| {
+                        counter.fetch_add(1, Ordering::SeqCst);
+                        async move {
+                            let body = to_bytes(request.into_body()).await?;
+                            let body = String::from_utf8_lossy(&body);
+                            let status = if body.contains(r#""token":"good""#) {
+                                StatusCode::OK
+                            } else if body.contains(r#""token":"broken""#) {
+                                StatusCode::INTERNAL_SERVER_ERROR
+                            } else if body.contains(r#""token":"busy""#) {
+                                StatusCode::TOO_MANY_REQUESTS
+                            } else {
+                                StatusCode::FORBIDDEN
+                            };
+                            Response::builder().status(status).body(Body::empty())
+                                .map_err(|err| -> Box<dyn std::error::Error + Send + Sync> { err.into() })
+                        }
+                    }))
+                }
+            });
+            let server = Server::bind(&SocketAddr::from(([127, 0, 0, 1], 0))).serve(service);
+            let address = server.local_addr();
+            tokio::spawn(server.with_graceful_shutdown(stopped.map(|_| ())));
+            address
+        });
+        let url = format!("http://{}/auth", address);
+
+        let closed = WebhookAuthorizer::new(&url, WebhookOptions::default()).unwrap();
+        runtime.block_on(async {
+            assert!(closed.authorize(&request("main", Some("Bearer good"))).await);
+            assert!(!closed.authorize(&request("main", Some("bad"))).await);
+            assert!(!closed.authorize(&request("main", None)).await);
+            // answered from the cache
+            assert!(closed.authorize(&request("main", Some("good"))).await);
+            assert_eq!(asked.load(Ordering::SeqCst), 3);
+            // failures aren't cached
+            assert!(!closed.authorize(&request("main", Some("broken"))).await);
+            assert!(!closed.authorize(&request("main", Some("broken"))).await);
+            assert_eq!(asked.load(Ordering::SeqCst), 5);
+            // nor are denials that aren't about the credentials
+            assert!(!closed.authorize(&request("main", Some("busy"))).await);
+            assert!(!closed.authorize(&request("main", Some("busy"))).await);
+            assert_eq!(asked.load(Ordering::SeqCst), 7);
+        });
+
+        let open = WebhookAuthorizer::new(&url, WebhookOptions { on_failure: FailurePolicy::Open, ..WebhookOptions::default() }).unwrap();
+        runtime.block_on(async {
+            assert!(open.authorize(&request("main", Some("broken"))).await);
+            assert!(!open.authorize(&request("main", Some("bad"))).await);
+            stop.send(()).unwrap();
+        });
+        assert!(WebhookAuthorizer::new("not a url", WebhookOptions::default()).is_err());
+    }
+}
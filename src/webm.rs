@@ -1,3 +1,4 @@
+use std::borrow::Cow;
 use std::io::{Error as IoError, ErrorKind, Result as IoResult, Write, Seek};
 use byteorder::{BigEndian, ByteOrder};
 use bytes::BufMut;
@@ -5,19 +6,110 @@ use crate::ebml::*;
 use crate::iterator::ebml_iter;
 use crate::iterator::EbmlIterator;
 
-const SEGMENT_ID: u64 = 0x08538067;
-const SEEK_HEAD_ID: u64 = 0x014D9B74;
-const SEGMENT_INFO_ID: u64 = 0x0549A966;
-const CUES_ID: u64 = 0x0C53BB6B;
-const TRACKS_ID: u64 = 0x0654AE6B;
-const CLUSTER_ID: u64 = 0x0F43B675;
-const TIMECODE_ID: u64 = 0x67;
-const SIMPLE_BLOCK_ID: u64 = 0x23;
+pub const SEGMENT_ID: u64 = 0x08538067;
+pub const SEEK_HEAD_ID: u64 = 0x014D9B74;
+pub const SEEK_ID: u64 = 0x0DBB;
+pub const SEEK_ID_ID: u64 = 0x13AB;
+pub const SEEK_POSITION_ID: u64 = 0x13AC;
+pub const SEGMENT_INFO_ID: u64 = 0x0549A966;
+pub const TIMECODE_SCALE_ID: u64 = 0x0AD7B1;
+pub const DURATION_ID: u64 = 0x0489;
+pub const MUXING_APP_ID: u64 = 0x0D80;
+pub const WRITING_APP_ID: u64 = 0x1741;
+pub const CUES_ID: u64 = 0x0C53BB6B;
+pub const CUE_POINT_ID: u64 = 0x3B;
+pub const CUE_TIME_ID: u64 = 0x33;
+pub const CUE_TRACK_POSITIONS_ID: u64 = 0x37;
+pub const CUE_TRACK_ID: u64 = 0x77;
+pub const CUE_CLUSTER_POSITION_ID: u64 = 0x71;
+pub const TRACKS_ID: u64 = 0x0654AE6B;
+pub const TRACK_ENTRY_ID: u64 = 0x2E;
+pub const TRACK_NUMBER_ID: u64 = 0x57;
+pub const TRACK_TYPE_ID: u64 = 0x03;
+pub const CODEC_ID_ID: u64 = 0x06;
+pub const CODEC_PRIVATE_ID: u64 = 0x23A2;
+pub const DEFAULT_DURATION_ID: u64 = 0x03E383;
+pub const LANGUAGE_ID: u64 = 0x02B59C;
+pub const LANGUAGE_BCP47_ID: u64 = 0x02B59D;
+pub const MAX_BLOCK_ADDITION_ID_ID: u64 = 0x15EE;
+pub const BLOCK_ADDITION_MAPPING_ID: u64 = 0x01E4;
+pub const BLOCK_ADD_ID_VALUE_ID: u64 = 0x01F0;
+pub const BLOCK_ADD_ID_NAME_ID: u64 = 0x01A4;
+pub const BLOCK_ADD_ID_TYPE_ID: u64 = 0x01E7;
+pub const BLOCK_ADD_ID_EXTRA_DATA_ID: u64 = 0x01ED;
+pub const VIDEO_ID: u64 = 0x60;
+pub const ALPHA_MODE_ID: u64 = 0x13C0;
+pub const PIXEL_WIDTH_ID: u64 = 0x30;
+pub const PIXEL_HEIGHT_ID: u64 = 0x3A;
+pub const AUDIO_ID: u64 = 0x61;
+pub const SAMPLING_FREQUENCY_ID: u64 = 0x35;
+pub const CHANNELS_ID: u64 = 0x1F;
+pub const CLUSTER_ID: u64 = 0x0F43B675;
+pub const TIMECODE_ID: u64 = 0x67;
+pub const SIMPLE_BLOCK_ID: u64 = 0x23;
+pub const BLOCK_GROUP_ID: u64 = 0x20;
+pub const BLOCK_ID: u64 = 0x21;
+pub const BLOCK_DURATION_ID: u64 = 0x1B;
+pub const REFERENCE_BLOCK_ID: u64 = 0x7B;
+pub const BLOCK_ADDITIONS_ID: u64 = 0x35A1;
+pub const BLOCK_MORE_ID: u64 = 0x26;
+pub const BLOCK_ADD_ID_ID: u64 = 0x6E;
+pub const BLOCK_ADDITIONAL_ID: u64 = 0x25;
+
+pub const TRACK_TYPE_VIDEO: u64 = 1;
+pub const TRACK_TYPE_AUDIO: u64 = 2;
+
+/// Nanoseconds per timecode tick, unless a Segment's Info says otherwise
+pub const DEFAULT_TIMESTAMP_SCALE: u64 = 1_000_000;
 
 pub fn parse_webm<'a, T: AsRef<[u8]> + ?Sized>(source: &'a T) -> EbmlIterator<'a, WebmElement> {
     ebml_iter(source.as_ref())
 }
 
+/// The flag marking a SimpleBlock as a keyframe
+pub const KEYFRAME: u8 = 0b1000_0000;
+
+/// Each element in a run of them, like a Cluster's body, with its offset &
+/// encoded length; stops at the first one that can't be decoded
+pub fn element_offsets(body: &[u8]) -> impl Iterator<Item = (usize, usize, WebmElement)> {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let (element, length) = WebmElement::decode_element(&body[offset..]).ok()??;
+        let start = offset;
+        offset += length;
+        Some((start, length, element))
+    })
+}
+
+/// The SimpleBlocks & BlockGroups in a Cluster's body, with their offsets;
+/// see `block_timing`
+pub fn cluster_blocks(body: &[u8]) -> Vec<(usize, SimpleBlock, bool)> {
+    element_offsets(body).filter_map(|(offset, _, element)| {
+        block_timing(&element).map(|(block, keyframe)| (offset, block, keyframe))
+    }).collect()
+}
+
+/// A SimpleBlock's or BlockGroup's Block, and whether it's a keyframe (which
+/// a BlockGroup's Block has no flag for); None for other elements
+pub fn block_timing<'b>(element: &WebmElement<'b>) -> Option<(SimpleBlock<'b>, bool)> {
+    match element {
+        WebmElement::SimpleBlock(block) => Some((*block, block.flags & KEYFRAME != 0)),
+        WebmElement::BlockGroup(group) => Some((group.block, group.keyframe)),
+        _ => None
+    }
+}
+
+/// Convert a timecode between TimestampScales, rounding to the nearest tick
+pub fn rescale(timecode: i64, from: u64, to: u64) -> i64 {
+    if from == to {
+        return timecode;
+    }
+    let nanoseconds = timecode as i128 * from as i128;
+    let half = to as i128 / 2;
+    let rounded = if nanoseconds < 0 { nanoseconds - half } else { nanoseconds + half };
+    (rounded / to as i128) as i64
+}
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub struct SimpleBlock<'b> {
     pub track: u64,
@@ -26,19 +118,108 @@ pub struct SimpleBlock<'b> {
     pub data: &'b[u8]
 }
 
+/// A Block along with what a SimpleBlock can't carry: its duration, the
+/// frames it refers to, and BlockAdditions (like a VP9 or AV1 alpha channel,
+/// or HDR metadata)
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct BlockGroup<'b> {
+    /// the group's Block; its flags have no keyframe bit, see `keyframe`
+    pub block: SimpleBlock<'b>,
+    /// the Block refers to no other frames (there's no ReferenceBlock)
+    pub keyframe: bool,
+    /// how long the Block lasts, in timecode ticks, if the group says
+    pub duration: Option<u64>,
+    /// the group's payload, which is written back as it is apart from the Block's timecode
+    payload: &'b[u8],
+    /// where in the payload the Block's timecode is
+    timecode_offset: usize,
+    /// the TimestampScales its BlockDuration & ReferenceBlock are to be
+    /// converted between when it's written, if they are
+    scale: Option<(u64, u64)>,
+}
+
+/// Extra data for a Block, told apart by its BlockAddID; what each ID
+/// carries is given by the track's `BlockAdditionMapping`s, or for VP9 &
+/// AV1, ID 1 is the alpha channel when the track's `alpha_mode` is set
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct BlockAddition<'b> {
+    pub id: u64,
+    pub data: &'b[u8],
+}
+
+impl<'b> BlockGroup<'b> {
+    /// Write the group's BlockDuration & ReferenceBlock at another
+    /// TimestampScale; the Block's own timecode, being relative to its
+    /// Cluster's, is left to the caller
+    pub fn rescale(&mut self, from: u64, to: u64) {
+        // the payload stays at its original scale, while `duration` is at the latest
+        let (original, current) = self.scale.unwrap_or((from, from));
+        self.duration = self.duration.map(|duration| rescale(duration as i64, current, to) as u64);
+        self.scale = Some((original, to));
+    }
+
+    pub fn additions(&self) -> Vec<BlockAddition<'b>> {
+        ebml_iter::<BlockGroupElement>(self.payload).filter_map(|element| match element {
+            BlockGroupElement::Additions(additions) => Some(additions),
+            _ => None,
+        }).flat_map(ebml_iter::<BlockGroupElement>).filter_map(|element| match element {
+            BlockGroupElement::More(more) => Some(more),
+            _ => None,
+        }).map(|more| {
+            // BlockAddID defaults to 1
+            let mut addition = BlockAddition { id: 1, data: &[] };
+            for element in ebml_iter::<BlockGroupElement>(more) {
+                match element {
+                    BlockGroupElement::AddId(id) => addition.id = id,
+                    BlockGroupElement::Additional(data) => addition.data = data,
+                    _ => {}
+                }
+            }
+            addition
+        }).collect()
+    }
+}
+
+enum BlockGroupElement<'b> {
+    Additions(&'b[u8]),
+    More(&'b[u8]),
+    AddId(u64),
+    Additional(&'b[u8]),
+    Other
+}
+
+impl<'b> FromEbml<'b> for BlockGroupElement<'b> {
+    fn should_unwrap(_element_id: u64) -> bool {
+        false
+    }
+
+    fn decode(element_id: u64, bytes: &'b[u8]) -> Result<BlockGroupElement<'b>, EbmlError> {
+        match element_id {
+            BLOCK_ADDITIONS_ID => Ok(BlockGroupElement::Additions(bytes)),
+            BLOCK_MORE_ID => Ok(BlockGroupElement::More(bytes)),
+            BLOCK_ADD_ID_ID => decode_uint(bytes).map(BlockGroupElement::AddId),
+            BLOCK_ADDITIONAL_ID => Ok(BlockGroupElement::Additional(bytes)),
+            _ => Ok(BlockGroupElement::Other)
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Copy, Clone)]
 pub enum WebmElement<'b> {
     EbmlHead,
     Void,
     Segment,
     SeekHead,
-    Info,
+    Info(&'b[u8]),
     Cues,
     Tracks(&'b[u8]),
     Cluster,
     Timecode(u64),
     SimpleBlock(SimpleBlock<'b>),
-    Unknown(u64)
+    BlockGroup(BlockGroup<'b>),
+    Unknown(u64),
+    /// Corrupt input that was skipped over while resyncing
+    Skipped { offset: u64, length: u64 },
 }
 
 impl<'b> FromEbml<'b> for WebmElement<'b> {
@@ -57,18 +238,325 @@ impl<'b> FromEbml<'b> for WebmElement<'b> {
             VOID_ID => Ok(WebmElement::Void),
             SEGMENT_ID => Ok(WebmElement::Segment),
             SEEK_HEAD_ID => Ok(WebmElement::SeekHead),
-            SEGMENT_INFO_ID => Ok(WebmElement::Info),
+            SEGMENT_INFO_ID => Ok(WebmElement::Info(bytes)),
             CUES_ID => Ok(WebmElement::Cues),
             TRACKS_ID => Ok(WebmElement::Tracks(bytes)),
             CLUSTER_ID => Ok(WebmElement::Cluster),
             TIMECODE_ID => decode_uint(bytes).map(WebmElement::Timecode),
             SIMPLE_BLOCK_ID => decode_simple_block(bytes),
+            BLOCK_GROUP_ID => decode_block_group(bytes),
             _ => Ok(WebmElement::Unknown(element_id))
         }
     }
+
+    fn resync_id() -> Option<u64> {
+        Some(CLUSTER_ID)
+    }
+
+    fn skipped(offset: u64, length: u64) -> Option<WebmElement<'b>> {
+        Some(WebmElement::Skipped { offset, length })
+    }
 }
 
-fn decode_simple_block(bytes: &[u8]) -> Result<WebmElement, EbmlError> {
+/// The subset of a TrackEntry's metadata webmetro pays attention to
+#[derive(Debug, PartialEq, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct TrackEntry {
+    pub number: u64,
+    pub track_type: u64,
+    pub codec_id: String,
+    pub codec_private: Vec<u8>,
+    /// how long each frame lasts, in ns, if the track says; 0 if not
+    pub default_duration: u64,
+    /// its language's ISO 639-2 code, if the track says; see `language_code`
+    pub language: String,
+    /// its language as a BCP 47 tag (e.g. "pt-BR"), which takes precedence
+    /// over `language`, if the track gives one
+    pub language_bcp47: String,
+    /// video only
+    pub pixel_width: u64,
+    /// video only
+    pub pixel_height: u64,
+    /// audio only
+    pub sampling_frequency: f64,
+    /// audio only
+    pub channels: u64,
+    /// video only; 1 if BlockAdditions with BlockAddID 1 carry an alpha channel
+    pub alpha_mode: u64,
+    /// the highest BlockAddID its blocks use; 0 if they have no BlockAdditions
+    pub max_block_addition_id: u64,
+    /// what its BlockAdditions carry, where the track says
+    pub block_addition_mappings: Vec<BlockAdditionMapping>,
+}
+
+/// What the BlockAdditions with a given BlockAddID carry, e.g. HDR metadata
+#[derive(Debug, PartialEq, Clone, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub struct BlockAdditionMapping {
+    /// the BlockAddID it describes
+    pub value: u64,
+    pub name: String,
+    /// registered in the Matroska Block Additional Mapping registry
+    pub add_id_type: u64,
+    pub extra_data: Vec<u8>,
+}
+
+impl TrackEntry {
+    pub fn is_video(&self) -> bool {
+        self.track_type == TRACK_TYPE_VIDEO
+    }
+
+    pub fn is_audio(&self) -> bool {
+        self.track_type == TRACK_TYPE_AUDIO
+    }
+
+    /// Frames per second, going by the default frame duration
+    pub fn frame_rate(&self) -> Option<f64> {
+        match self.default_duration {
+            0 => None,
+            duration => Some(1_000_000_000.0 / duration as f64),
+        }
+    }
+
+    /// The ISO 639-2 code of the track's language, which Matroska takes to
+    /// be English ("eng") unless it says otherwise
+    pub fn language_code(&self) -> &str {
+        if self.language.is_empty() { "eng" } else { &self.language }
+    }
+
+    /// Whether the track is in `language`, given as a BCP 47 tag (e.g. "en"
+    /// or "pt-BR") or an ISO 639-2 code (e.g. "eng" or "por"). A plain
+    /// language matches any regional variant of it.
+    pub fn in_language(&self, language: &str) -> bool {
+        let wanted = language.to_ascii_lowercase();
+        let tag = if self.language_bcp47.is_empty() { self.language_code() } else { &self.language_bcp47 }.to_ascii_lowercase();
+        if tag == wanted || tag.starts_with(&format!("{}-", wanted)) {
+            return true;
+        }
+        // otherwise compare the languages, however each is written
+        let primary = tag.split('-').next().unwrap_or_default();
+        !wanted.contains('-') && language_codes(primary).iter().any(|code| language_codes(&wanted).contains(code))
+    }
+
+    /// The codec as named in a WebM MIME type's `codecs` parameter, e.g.
+    /// `vp9` in `video/webm; codecs="vp9,opus"`, for codecs browsers play.
+    /// AV1 has to be named in full (see `av1_codec`), so it's left out if
+    /// its CodecPrivate is missing.
+    pub fn mse_codec(&self) -> Option<Cow<'static, str>> {
+        match self.codec_id.as_str() {
+            "V_VP8" => Some("vp8".into()),
+            "V_VP9" => Some("vp9".into()),
+            "V_AV1" => self.av1_codec().map(Cow::from),
+            "A_OPUS" => Some("opus".into()),
+            "A_VORBIS" => Some("vorbis".into()),
+            _ => None,
+        }
+    }
+
+    /// An AV1 track's codec as RFC 6381 names it, `av01.P.LLT.DD` (profile,
+    /// level, tier & bit depth), from the AV1CodecConfigurationRecord in its
+    /// CodecPrivate; `None` if there isn't one
+    pub fn av1_codec(&self) -> Option<String> {
+        match self.codec_private.get(0..3) {
+            // the record's marker bit & version 1
+            Some(&[0x81, profile_level, tier_depth]) => {
+                let depth = match (tier_depth & 0x40 != 0, tier_depth & 0x20 != 0) {
+                    (true, true) => 12,
+                    (true, false) => 10,
+                    _ => 8,
+                };
+                let tier = if tier_depth & 0x80 != 0 { 'H' } else { 'M' };
+                Some(format!("av01.{}.{:02}{}.{:02}", profile_level >> 5, profile_level & 0x1F, tier, depth))
+            },
+            _ => None,
+        }
+    }
+}
+
+/// ISO 639-1 codes for widely spoken languages, and the ISO 639-2 codes
+/// Matroska's Language element would use for them (bibliographic, then
+/// terminologic, where those differ)
+const LANGUAGE_CODES: &[(&str, &[&str])] = &[
+    ("ar", &["ara"]), ("bn", &["ben"]), ("cs", &["cze", "ces"]), ("da", &["dan"]),
+    ("de", &["ger", "deu"]), ("el", &["gre", "ell"]), ("en", &["eng"]), ("es", &["spa"]),
+    ("fa", &["per", "fas"]), ("fi", &["fin"]), ("fr", &["fre", "fra"]), ("he", &["heb"]),
+    ("hi", &["hin"]), ("hu", &["hun"]), ("id", &["ind"]), ("it", &["ita"]),
+    ("ja", &["jpn"]), ("ko", &["kor"]), ("nl", &["dut", "nld"]), ("no", &["nor"]),
+    ("pl", &["pol"]), ("pt", &["por"]), ("ro", &["rum", "ron"]), ("ru", &["rus"]),
+    ("sv", &["swe"]), ("th", &["tha"]), ("tr", &["tur"]), ("uk", &["ukr"]),
+    ("vi", &["vie"]), ("zh", &["chi", "zho"]),
+];
+
+/// The ISO 639-2 codes for a (lowercase) language code of either kind
+fn language_codes(language: &str) -> Vec<&str> {
+    match LANGUAGE_CODES.iter().find(|(short, long)| *short == language || long.contains(&language)) {
+        Some((_, long)) => long.to_vec(),
+        None => vec![language],
+    }
+}
+
+enum TrackElement<'b> {
+    Entry(&'b[u8]),
+    Number(u64),
+    Type(u64),
+    CodecId(&'b[u8]),
+    CodecPrivate(&'b[u8]),
+    DefaultDuration(u64),
+    Language(&'b[u8]),
+    LanguageBcp47(&'b[u8]),
+    Video(&'b[u8]),
+    PixelWidth(u64),
+    PixelHeight(u64),
+    Audio(&'b[u8]),
+    SamplingFrequency(f64),
+    Channels(u64),
+    AlphaMode(u64),
+    MaxBlockAdditionId(u64),
+    BlockAdditionMapping(&'b[u8]),
+    BlockAddIdValue(u64),
+    BlockAddIdName(&'b[u8]),
+    BlockAddIdType(u64),
+    BlockAddIdExtraData(&'b[u8]),
+    Other
+}
+
+impl<'b> FromEbml<'b> for TrackElement<'b> {
+    fn should_unwrap(_element_id: u64) -> bool {
+        false
+    }
+
+    fn decode(element_id: u64, bytes: &'b[u8]) -> Result<TrackElement<'b>, EbmlError> {
+        match element_id {
+            TRACK_ENTRY_ID => Ok(TrackElement::Entry(bytes)),
+            TRACK_NUMBER_ID => decode_uint(bytes).map(TrackElement::Number),
+            TRACK_TYPE_ID => decode_uint(bytes).map(TrackElement::Type),
+            CODEC_ID_ID => Ok(TrackElement::CodecId(bytes)),
+            CODEC_PRIVATE_ID => Ok(TrackElement::CodecPrivate(bytes)),
+            DEFAULT_DURATION_ID => decode_uint(bytes).map(TrackElement::DefaultDuration),
+            LANGUAGE_ID => Ok(TrackElement::Language(bytes)),
+            LANGUAGE_BCP47_ID => Ok(TrackElement::LanguageBcp47(bytes)),
+            VIDEO_ID => Ok(TrackElement::Video(bytes)),
+            PIXEL_WIDTH_ID => decode_uint(bytes).map(TrackElement::PixelWidth),
+            PIXEL_HEIGHT_ID => decode_uint(bytes).map(TrackElement::PixelHeight),
+            AUDIO_ID => Ok(TrackElement::Audio(bytes)),
+            SAMPLING_FREQUENCY_ID => decode_float(bytes).map(TrackElement::SamplingFrequency),
+            CHANNELS_ID => decode_uint(bytes).map(TrackElement::Channels),
+            ALPHA_MODE_ID => decode_uint(bytes).map(TrackElement::AlphaMode),
+            MAX_BLOCK_ADDITION_ID_ID => decode_uint(bytes).map(TrackElement::MaxBlockAdditionId),
+            BLOCK_ADDITION_MAPPING_ID => Ok(TrackElement::BlockAdditionMapping(bytes)),
+            BLOCK_ADD_ID_VALUE_ID => decode_uint(bytes).map(TrackElement::BlockAddIdValue),
+            BLOCK_ADD_ID_NAME_ID => Ok(TrackElement::BlockAddIdName(bytes)),
+            BLOCK_ADD_ID_TYPE_ID => decode_uint(bytes).map(TrackElement::BlockAddIdType),
+            BLOCK_ADD_ID_EXTRA_DATA_ID => Ok(TrackElement::BlockAddIdExtraData(bytes)),
+            _ => Ok(TrackElement::Other)
+        }
+    }
+}
+
+fn read_track_fields(track: &mut TrackEntry, bytes: &[u8]) {
+    for element in ebml_iter::<TrackElement>(bytes) {
+        match element {
+            TrackElement::Number(number) => track.number = number,
+            TrackElement::Type(track_type) => track.track_type = track_type,
+            TrackElement::CodecId(codec_id) => track.codec_id = String::from_utf8_lossy(codec_id).into_owned(),
+            TrackElement::CodecPrivate(codec_private) => track.codec_private = codec_private.to_vec(),
+            TrackElement::DefaultDuration(duration) => track.default_duration = duration,
+            TrackElement::Language(language) => track.language = String::from_utf8_lossy(language).into_owned(),
+            TrackElement::LanguageBcp47(language) => track.language_bcp47 = String::from_utf8_lossy(language).into_owned(),
+            TrackElement::Video(video) => read_track_fields(track, video),
+            TrackElement::PixelWidth(width) => track.pixel_width = width,
+            TrackElement::PixelHeight(height) => track.pixel_height = height,
+            TrackElement::Audio(audio) => read_track_fields(track, audio),
+            TrackElement::SamplingFrequency(frequency) => track.sampling_frequency = frequency,
+            TrackElement::Channels(channels) => track.channels = channels,
+            TrackElement::AlphaMode(mode) => track.alpha_mode = mode,
+            TrackElement::MaxBlockAdditionId(id) => track.max_block_addition_id = id,
+            TrackElement::BlockAdditionMapping(mapping) => track.block_addition_mappings.push(read_block_addition_mapping(mapping)),
+            _ => {}
+        }
+    }
+}
+
+fn read_block_addition_mapping(bytes: &[u8]) -> BlockAdditionMapping {
+    let mut mapping = BlockAdditionMapping::default();
+    for element in ebml_iter::<TrackElement>(bytes) {
+        match element {
+            TrackElement::BlockAddIdValue(value) => mapping.value = value,
+            TrackElement::BlockAddIdName(name) => mapping.name = String::from_utf8_lossy(name).into_owned(),
+            TrackElement::BlockAddIdType(add_id_type) => mapping.add_id_type = add_id_type,
+            TrackElement::BlockAddIdExtraData(extra_data) => mapping.extra_data = extra_data.to_vec(),
+            _ => {}
+        }
+    }
+    mapping
+}
+
+/// Extract track metadata from the payload of a Tracks element
+pub fn parse_tracks(tracks: &[u8]) -> Vec<TrackEntry> {
+    ebml_iter::<TrackElement>(tracks).filter_map(|element| match element {
+        TrackElement::Entry(entry) => Some(entry),
+        _ => None
+    }).map(|entry| {
+        let mut track = TrackEntry::default();
+        read_track_fields(&mut track, entry);
+        track
+    }).collect()
+}
+
+/// The tracks an initialization segment describes, if it has any
+pub fn header_tracks(headers: &[u8]) -> Vec<TrackEntry> {
+    parse_webm(headers).find_map(|element| match element {
+        WebmElement::Tracks(tracks) => Some(parse_tracks(tracks)),
+        _ => None,
+    }).unwrap_or_default()
+}
+
+/// The numbers of the video tracks an initialization segment describes,
+/// whose keyframes are where playback can start
+pub fn video_tracks(headers: &[u8]) -> Vec<u64> {
+    header_tracks(headers)
+        .iter()
+        .filter(|track| track.is_video())
+        .map(|track| track.number)
+        .collect()
+}
+
+enum InfoElement {
+    TimestampScale(u64),
+    Other
+}
+
+impl<'b> FromEbml<'b> for InfoElement {
+    fn should_unwrap(_element_id: u64) -> bool {
+        false
+    }
+
+    fn decode(element_id: u64, bytes: &'b[u8]) -> Result<InfoElement, EbmlError> {
+        match element_id {
+            TIMECODE_SCALE_ID => decode_uint(bytes).map(InfoElement::TimestampScale),
+            _ => Ok(InfoElement::Other)
+        }
+    }
+}
+
+/// The nanoseconds per timecode tick given by the payload of an Info element
+pub fn info_timestamp_scale(info: &[u8]) -> u64 {
+    ebml_iter::<InfoElement>(info).find_map(|element| match element {
+        InfoElement::TimestampScale(scale) if scale > 0 => Some(scale),
+        _ => None,
+    }).unwrap_or(DEFAULT_TIMESTAMP_SCALE)
+}
+
+/// The nanoseconds per timecode tick of the stream an initialization segment begins
+pub fn timestamp_scale(headers: &[u8]) -> u64 {
+    parse_webm(headers).find_map(|element| match element {
+        WebmElement::Info(info) => Some(info_timestamp_scale(info)),
+        _ => None,
+    }).unwrap_or(DEFAULT_TIMESTAMP_SCALE)
+}
+
+/// Decode a SimpleBlock's (or Block's) payload, along with where its timecode is
+fn decode_block(bytes: &[u8]) -> Result<(SimpleBlock, usize), EbmlError> {
     if let Ok(Some((Varint::Value(track), track_field_len))) = decode_varint(bytes) {
         let header_len = track_field_len + 2 + 1;
         if bytes.len() < header_len {
@@ -76,17 +564,60 @@ fn decode_simple_block(bytes: &[u8]) -> Result<WebmElement, EbmlError> {
         }
         let timecode = BigEndian::read_i16(&bytes[track_field_len..]);
         let flags = bytes[track_field_len + 2];
-        return Ok(WebmElement::SimpleBlock(SimpleBlock {
+        return Ok((SimpleBlock {
             track: track,
             timecode: timecode,
             flags: flags,
             data: &bytes[header_len..],
-        }))
+        }, track_field_len))
     } else {
         return Err(EbmlError::CorruptPayload);
     }
 }
 
+fn decode_simple_block(bytes: &[u8]) -> Result<WebmElement, EbmlError> {
+    decode_block(bytes).map(|(block, _)| WebmElement::SimpleBlock(block))
+}
+
+fn decode_block_group(bytes: &[u8]) -> Result<WebmElement, EbmlError> {
+    let mut block = None;
+    let mut keyframe = true;
+    let mut duration = None;
+    let mut children = bytes;
+    while !children.is_empty() {
+        let (child_id, child_size, child_header_length) = decode_tag(children)?.ok_or(EbmlError::CorruptPayload)?;
+        let child_length = match child_size {
+            Varint::Value(size) => element_length(child_header_length, size)?,
+            Varint::Unknown => return Err(EbmlError::UnknownElementLength),
+        };
+        if children.len() < child_length {
+            return Err(EbmlError::CorruptPayload);
+        }
+        let payload = &children[child_header_length..child_length];
+        match child_id {
+            BLOCK_ID => {
+                let (parsed, track_field_len) = decode_block(payload)?;
+                let offset = bytes.len() - children.len() + child_header_length + track_field_len;
+                block = Some((parsed, offset));
+            },
+            BLOCK_DURATION_ID => duration = Some(decode_uint(payload)?),
+            REFERENCE_BLOCK_ID => keyframe = false,
+            _ => {}
+        }
+        children = &children[child_length..];
+    }
+
+    let (block, timecode_offset) = block.ok_or(EbmlError::CorruptPayload)?;
+    Ok(WebmElement::BlockGroup(BlockGroup {
+        block,
+        keyframe,
+        duration,
+        payload: bytes,
+        timecode_offset,
+        scale: None,
+    }))
+}
+
 pub fn encode_simple_block<T: Write>(block: SimpleBlock, output: &mut T) -> IoResult<()> {
     let SimpleBlock {
         track,
@@ -95,22 +626,77 @@ pub fn encode_simple_block<T: Write>(block: SimpleBlock, output: &mut T) -> IoRe
         data
     } = block;
 
-    // limiting number of tracks for now
-    if track > 31 {
-        return Err(IoError::new(ErrorKind::InvalidInput, WriteError::OutOfRange));
-    }
-    let header_len = 1 + 2 + 1;
+    // the track number is a varint of up to 8 bytes, then the timecode & flags
+    let mut buffer = [0; 8 + 2 + 1];
+    let header_len = {
+        let mut cursor = buffer.as_mut();
+        encode_varint(Varint::Value(track), &mut cursor)?;
+        cursor.put_i16(timecode);
+        cursor.put_u8(flags);
+        let unused = cursor.len();
+        buffer.len() - unused
+    };
+
     encode_tag_header(SIMPLE_BLOCK_ID, Varint::Value((header_len + data.len()) as u64), output)?;
+    output.write_all(&buffer[..header_len])?;
+    output.write_all(data)
+}
 
-    encode_varint(Varint::Value(track), output)?;
+/// Write a BlockGroup as it was read, except for its Block's timecode
+pub fn encode_block_group<T: Write>(group: BlockGroup, output: &mut T) -> IoResult<()> {
+    let BlockGroup { block, duration, payload, timecode_offset, scale, .. } = group;
+    let (from, to) = match scale {
+        Some((from, to)) if from != to => (from, to),
+        _ => {
+            encode_tag_header(BLOCK_GROUP_ID, Varint::Value(payload.len() as u64), output)?;
+            output.write_all(&payload[..timecode_offset])?;
+            output.write_all(&block.timecode.to_be_bytes())?;
+            return output.write_all(&payload[timecode_offset + 2..]);
+        }
+    };
 
-    let mut buffer = [0; 3];
-    let mut cursor = buffer.as_mut();
-    cursor.put_i16(timecode);
-    cursor.put_u8(flags);
+    // the children were checked when the group was decoded
+    let corrupt = || IoError::new(ErrorKind::InvalidData, EbmlError::CorruptPayload);
+    let mut rescaled = Vec::with_capacity(payload.len());
+    let mut children = payload;
+    while !children.is_empty() {
+        let start = payload.len() - children.len();
+        let (child_id, child_size, header_length) = decode_tag(children).ok().flatten().ok_or_else(corrupt)?;
+        let length = match child_size {
+            Varint::Value(size) => element_length(header_length, size).map_err(|_| corrupt())?,
+            Varint::Unknown => return Err(corrupt()),
+        };
+        let child = children.get(..length).ok_or_else(corrupt)?;
+        match child_id {
+            BLOCK_ID => {
+                let offset = timecode_offset - start;
+                rescaled.extend_from_slice(&child[..offset]);
+                rescaled.extend_from_slice(&block.timecode.to_be_bytes());
+                rescaled.extend_from_slice(&child[offset + 2..]);
+            },
+            BLOCK_DURATION_ID => encode_integer(BLOCK_DURATION_ID, duration.unwrap_or_default(), &mut rescaled)?,
+            REFERENCE_BLOCK_ID => {
+                let reference = decode_int(&child[header_length..]).map_err(|_| corrupt())?;
+                encode_integer(REFERENCE_BLOCK_ID, rescale(reference, from, to) as u64, &mut rescaled)?;
+            },
+            _ => rescaled.extend_from_slice(child),
+        }
+        children = &children[length..];
+    }
+    encode_bytes(BLOCK_GROUP_ID, &rescaled, output)
+}
 
-    output.write_all(&buffer)?;
-    output.write_all(data)
+/// Write a SimpleBlock or BlockGroup again with its Block's timecode changed;
+/// other elements are refused
+pub fn encode_block<T: Write>(element: WebmElement, timecode: i16, output: &mut T) -> IoResult<()> {
+    match element {
+        WebmElement::SimpleBlock(block) => encode_simple_block(SimpleBlock { timecode, ..block }, output),
+        WebmElement::BlockGroup(mut group) => {
+            group.block.timecode = timecode;
+            encode_block_group(group, output)
+        },
+        _ => Err(IoError::new(ErrorKind::InvalidInput, WriteError::OutOfRange))
+    }
 }
 
 pub fn encode_webm_element<T: Write + Seek>(element: WebmElement, output: &mut T) -> IoResult<()> {
@@ -125,21 +711,238 @@ pub fn encode_webm_element<T: Write + Seek>(element: WebmElement, output: &mut T
         WebmElement::Cluster => encode_tag_header(CLUSTER_ID, Varint::Unknown, output),
         WebmElement::Timecode(time) => encode_integer(TIMECODE_ID, time, output),
         WebmElement::SimpleBlock(block) => encode_simple_block(block, output),
+        WebmElement::BlockGroup(group) => encode_block_group(group, output),
         WebmElement::Void => Err(IoError::new(ErrorKind::InvalidInput, WriteError::OutOfRange)),
-        WebmElement::Info => Err(IoError::new(ErrorKind::InvalidInput, WriteError::OutOfRange)),
-        WebmElement::Unknown(_) => Err(IoError::new(ErrorKind::InvalidInput, WriteError::OutOfRange))
+        WebmElement::Info(data) => encode_bytes(SEGMENT_INFO_ID, data, output),
+        WebmElement::Unknown(_) => Err(IoError::new(ErrorKind::InvalidInput, WriteError::OutOfRange)),
+        WebmElement::Skipped {..} => Err(IoError::new(ErrorKind::InvalidInput, WriteError::OutOfRange))
+    }
+}
+
+/// Copy a stream's initialization segment with its EBML header's DocType
+/// changed (e.g. to "matroska" for players that insist on it), leaving the
+/// rest of it untouched
+pub fn set_doc_type(headers: &[u8], doc_type: &str) -> Result<Vec<u8>, EbmlError> {
+    let (id, size, header_length) = decode_tag(headers)?.ok_or(EbmlError::CorruptPayload)?;
+    let end = match size {
+        Varint::Value(size) => element_length(header_length, size)?,
+        Varint::Unknown => return Err(EbmlError::UnknownElementLength),
+    };
+    if id != EBML_HEAD_ID || headers.len() < end {
+        return Err(EbmlError::CorruptPayload);
+    }
+
+    // writing to a Vec can only fail for sizes too large to encode, which a
+    // header that was just decoded can't have
+    let mut body = Vec::new();
+    let mut children = &headers[header_length..end];
+    while !children.is_empty() {
+        let (child_id, child_size, child_header_length) = decode_tag(children)?.ok_or(EbmlError::CorruptPayload)?;
+        let child_length = match child_size {
+            Varint::Value(size) => element_length(child_header_length, size)?,
+            Varint::Unknown => return Err(EbmlError::UnknownElementLength),
+        };
+        if children.len() < child_length {
+            return Err(EbmlError::CorruptPayload);
+        }
+        if child_id == DOC_TYPE_ID {
+            encode_bytes(DOC_TYPE_ID, doc_type.as_bytes(), &mut body).map_err(|_| EbmlError::CorruptPayload)?;
+        } else {
+            body.extend_from_slice(&children[..child_length]);
+        }
+        children = &children[child_length..];
+    }
+
+    let mut output = Vec::with_capacity(headers.len() + doc_type.len());
+    encode_bytes(EBML_HEAD_ID, &body, &mut output).map_err(|_| EbmlError::CorruptPayload)?;
+    output.extend_from_slice(&headers[end..]);
+    Ok(output)
+}
+
+/// Copy a stream's initialization segment without the TrackEntries of the
+/// given tracks, e.g. to send a listener just one of several audio tracks.
+/// The Segment is left with an unknown size, as a live stream's would be.
+pub fn remove_tracks(headers: &[u8], numbers: &[u64]) -> Result<Vec<u8>, EbmlError> {
+    // as with set_doc_type, writing to a Vec can't fail for sizes just decoded
+    let mut output = Vec::with_capacity(headers.len());
+    let mut rest = headers;
+    while !rest.is_empty() {
+        let (id, size, header_length) = decode_tag(rest)?.ok_or(EbmlError::CorruptPayload)?;
+        let length = match (id, size) {
+            // carry on with what's in the Segment
+            (SEGMENT_ID, _) => header_length,
+            (_, Varint::Value(size)) => element_length(header_length, size)?,
+            (_, Varint::Unknown) => return Err(EbmlError::UnknownElementLength),
+        };
+        if rest.len() < length {
+            return Err(EbmlError::CorruptPayload);
+        }
+        match id {
+            SEGMENT_ID => encode_tag_header(SEGMENT_ID, Varint::Unknown, &mut output).map_err(|_| EbmlError::CorruptPayload)?,
+            TRACKS_ID => {
+                let mut entries = Vec::new();
+                let mut children = &rest[header_length..length];
+                while !children.is_empty() {
+                    let (child_id, child_size, child_header_length) = decode_tag(children)?.ok_or(EbmlError::CorruptPayload)?;
+                    let child_length = match child_size {
+                        Varint::Value(size) => element_length(child_header_length, size)?,
+                        Varint::Unknown => return Err(EbmlError::UnknownElementLength),
+                    };
+                    if children.len() < child_length {
+                        return Err(EbmlError::CorruptPayload);
+                    }
+                    let removed = child_id == TRACK_ENTRY_ID && parse_tracks(&children[..child_length]).iter()
+                        .any(|track| numbers.contains(&track.number));
+                    if !removed {
+                        entries.extend_from_slice(&children[..child_length]);
+                    }
+                    children = &children[child_length..];
+                }
+                encode_bytes(TRACKS_ID, &entries, &mut output).map_err(|_| EbmlError::CorruptPayload)?;
+            },
+            _ => output.extend_from_slice(&rest[..length]),
+        }
+        rest = &rest[length..];
+    }
+    Ok(output)
+}
+
+/// Read the DocType (e.g. "webm") from the EBML header element at the start of
+/// `head`, if it declares one
+pub fn doc_type(head: &[u8]) -> Result<Option<&str>, EbmlError> {
+    let (id, size, header_length) = decode_tag(head)?.ok_or(EbmlError::CorruptPayload)?;
+    let end = match size {
+        Varint::Value(size) => element_length(header_length, size)?,
+        Varint::Unknown => return Err(EbmlError::UnknownElementLength),
+    };
+    if id != EBML_HEAD_ID || head.len() < end {
+        return Err(EbmlError::CorruptPayload);
+    }
+
+    let mut children = &head[header_length..end];
+    while !children.is_empty() {
+        let (child_id, child_size, child_header_length) = decode_tag(children)?.ok_or(EbmlError::CorruptPayload)?;
+        let child_length = match child_size {
+            Varint::Value(size) => element_length(child_header_length, size)?,
+            Varint::Unknown => return Err(EbmlError::UnknownElementLength),
+        };
+        if children.len() < child_length {
+            return Err(EbmlError::CorruptPayload);
+        }
+        if child_id == DOC_TYPE_ID {
+            return std::str::from_utf8(&children[child_header_length..child_length])
+                .map(Some)
+                .map_err(|_| EbmlError::CorruptPayload);
+        }
+        children = &children[child_length..];
+    }
+    Ok(None)
+}
+
+#[cfg(feature = "serde")]
+mod serialize {
+    use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+    use crate::webm::{SimpleBlock, WebmElement};
+
+    // payloads are summarized by size; tooling wants the structure, not megabytes of arrays
+    impl Serialize for SimpleBlock<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let mut block = serializer.serialize_struct("SimpleBlock", 5)?;
+            block.serialize_field("track", &self.track)?;
+            block.serialize_field("timecode", &self.timecode)?;
+            block.serialize_field("flags", &self.flags)?;
+            block.serialize_field("keyframe", &(self.flags & 0b10000000 != 0))?;
+            block.serialize_field("size", &self.data.len())?;
+            block.end()
+        }
+    }
+
+    /// Elements serialize as a struct tagged with an "element" field
+    impl Serialize for WebmElement<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+            let name = match self {
+                WebmElement::EbmlHead => "EbmlHead",
+                WebmElement::Void => "Void",
+                WebmElement::Segment => "Segment",
+                WebmElement::SeekHead => "SeekHead",
+                WebmElement::Info(_) => "Info",
+                WebmElement::Cues => "Cues",
+                WebmElement::Tracks(_) => "Tracks",
+                WebmElement::Cluster => "Cluster",
+                WebmElement::Timecode(_) => "Timecode",
+                WebmElement::SimpleBlock(_) => "SimpleBlock",
+                WebmElement::BlockGroup(_) => "BlockGroup",
+                WebmElement::Unknown(_) => "Unknown",
+                WebmElement::Skipped {..} => "Skipped",
+            };
+            let fields = match self {
+                WebmElement::SimpleBlock(_) | WebmElement::BlockGroup(_) => 6,
+                WebmElement::Skipped {..} => 3,
+                WebmElement::Info(_) | WebmElement::Tracks(_) | WebmElement::Timecode(_) | WebmElement::Unknown(_) => 2,
+                _ => 1,
+            };
+            let mut element = serializer.serialize_struct("WebmElement", fields)?;
+            element.serialize_field("element", name)?;
+            match self {
+                WebmElement::Info(data) | WebmElement::Tracks(data) => element.serialize_field("size", &data.len())?,
+                WebmElement::Timecode(timecode) => element.serialize_field("timecode", timecode)?,
+                WebmElement::SimpleBlock(block) => {
+                    element.serialize_field("track", &block.track)?;
+                    element.serialize_field("timecode", &block.timecode)?;
+                    element.serialize_field("flags", &block.flags)?;
+                    element.serialize_field("keyframe", &(block.flags & 0b10000000 != 0))?;
+                    element.serialize_field("size", &block.data.len())?;
+                },
+                WebmElement::BlockGroup(group) => {
+                    element.serialize_field("track", &group.block.track)?;
+                    element.serialize_field("timecode", &group.block.timecode)?;
+                    element.serialize_field("keyframe", &group.keyframe)?;
+                    element.serialize_field("additions", &group.additions().len())?;
+                    element.serialize_field("size", &group.block.data.len())?;
+                },
+                WebmElement::Unknown(id) => element.serialize_field("id", id)?,
+                WebmElement::Skipped { offset, length } => {
+                    element.serialize_field("offset", offset)?;
+                    element.serialize_field("length", length)?;
+                },
+                _ => {}
+            }
+            element.end()
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
+    use proptest::{collection::vec, prelude::*};
     use crate::tests::{
         TEST_FILE,
         ENCODE_WEBM_TEST_FILE
     };
     use crate::webm::*;
 
+    #[test]
+    fn change_doc_type() {
+        let matroska = set_doc_type(ENCODE_WEBM_TEST_FILE, "matroska").unwrap();
+        let webm = set_doc_type(ENCODE_WEBM_TEST_FILE, "webm").unwrap();
+        assert_eq!(matroska.len(), webm.len() + 4);
+
+        let mut head = Vec::new();
+        encode_bytes(DOC_TYPE_ID, b"matroska", &mut head).unwrap();
+        assert!(matroska.windows(head.len()).any(|window| window == &head[..]));
+        // everything after the EBML header (a 4-byte ID, 4-byte size, and 7-byte body) is the same
+        assert!(matroska.ends_with(&ENCODE_WEBM_TEST_FILE[4 + 4 + 7..]));
+        assert_eq!(set_doc_type(&matroska, "webm").unwrap(), webm);
+
+        assert!(set_doc_type(b"not webm", "matroska").is_err());
+
+        assert_eq!(doc_type(&matroska).unwrap(), Some("matroska"));
+        assert_eq!(doc_type(&webm).unwrap(), Some("webm"));
+        assert!(doc_type(b"not webm").is_err());
+    }
+
     #[test]
     fn decode_webm_test1() {
         let mut iter = parse_webm(TEST_FILE);
@@ -149,7 +952,7 @@ mod tests {
         assert_eq!(iter.next(), Some(WebmElement::Segment));
         assert_eq!(iter.next(), Some(WebmElement::SeekHead));
         assert_eq!(iter.next(), Some(WebmElement::Void));
-        assert_eq!(iter.next(), Some(WebmElement::Info));
+        assert_eq!(iter.next(), Some(WebmElement::Info(&TEST_FILE[296..346])));
         assert_eq!(iter.next(), Some(WebmElement::Tracks(&TEST_FILE[358..421])));
 
         assert_eq!(iter.next(), Some(WebmElement::Cluster));
@@ -195,6 +998,171 @@ mod tests {
         assert_eq!(iter.next(), None);
     }
 
+    #[test]
+    fn parse_tracks_test() {
+        assert_eq!(parse_tracks(&TEST_FILE[358..421]), vec![TrackEntry {
+            number: 1,
+            track_type: TRACK_TYPE_VIDEO,
+            codec_id: String::from("V_VP9"),
+            default_duration: 33_333_333,
+            language: String::from("und"),
+            pixel_width: 320,
+            pixel_height: 240,
+            ..TrackEntry::default()
+        }]);
+    }
+
+    #[test]
+    fn parse_block_addition_mappings() {
+        let mut mapping = Vec::new();
+        encode_integer(BLOCK_ADD_ID_VALUE_ID, 4, &mut mapping).unwrap();
+        encode_bytes(BLOCK_ADD_ID_NAME_ID, b"HDR10+", &mut mapping).unwrap();
+        encode_integer(BLOCK_ADD_ID_TYPE_ID, 4, &mut mapping).unwrap();
+        let mut video = Vec::new();
+        encode_integer(ALPHA_MODE_ID, 1, &mut video).unwrap();
+        let mut entry = Vec::new();
+        encode_integer(TRACK_NUMBER_ID, 1, &mut entry).unwrap();
+        encode_integer(MAX_BLOCK_ADDITION_ID_ID, 4, &mut entry).unwrap();
+        encode_bytes(BLOCK_ADDITION_MAPPING_ID, &mapping, &mut entry).unwrap();
+        encode_bytes(VIDEO_ID, &video, &mut entry).unwrap();
+        let mut tracks = Vec::new();
+        encode_bytes(TRACK_ENTRY_ID, &entry, &mut tracks).unwrap();
+
+        assert_eq!(parse_tracks(&tracks), vec![TrackEntry {
+            number: 1,
+            alpha_mode: 1,
+            max_block_addition_id: 4,
+            block_addition_mappings: vec![BlockAdditionMapping {
+                value: 4,
+                name: String::from("HDR10+"),
+                add_id_type: 4,
+                extra_data: Vec::new(),
+            }],
+            ..TrackEntry::default()
+        }]);
+    }
+
+    #[test]
+    fn match_languages() {
+        let track = |language: &str, language_bcp47: &str| TrackEntry {
+            language: language.to_string(),
+            language_bcp47: language_bcp47.to_string(),
+            ..TrackEntry::default()
+        };
+        // English unless it says otherwise
+        assert!(track("", "").in_language("en"));
+        assert!(track("", "").in_language("ENG"));
+        assert!(track("ger", "").in_language("de"));
+        assert!(track("ger", "").in_language("deu"));
+        assert!(!track("ger", "").in_language("en"));
+        // a BCP 47 tag takes precedence
+        assert!(track("eng", "pt-BR").in_language("pt"));
+        assert!(track("eng", "pt-BR").in_language("pt-br"));
+        assert!(track("eng", "pt-BR").in_language("por"));
+        assert!(!track("eng", "pt-BR").in_language("pt-PT"));
+        assert!(!track("eng", "pt-BR").in_language("en"));
+    }
+
+    #[test]
+    fn name_mse_codecs() {
+        let track = |codec_id: &str, codec_private: &[u8]| TrackEntry {
+            codec_id: codec_id.to_string(),
+            codec_private: codec_private.to_vec(),
+            ..TrackEntry::default()
+        };
+        assert_eq!(track("V_VP9", &[]).mse_codec().as_deref(), Some("vp9"));
+        assert_eq!(track("A_AAC", &[]).mse_codec(), None);
+        // Main profile, level 4.0 (8), Main tier, 10 bits
+        assert_eq!(track("V_AV1", &[0x81, 0x08, 0x4C, 0x00]).mse_codec().as_deref(), Some("av01.0.08M.10"));
+        // High tier, 12 bits
+        assert_eq!(track("V_AV1", &[0x81, 0x4D, 0xE0, 0x00]).av1_codec().as_deref(), Some("av01.2.13H.12"));
+        assert_eq!(track("V_AV1", &[]).mse_codec(), None);
+    }
+
+    #[test]
+    fn block_groups() {
+        let mut more = Vec::new();
+        encode_integer(BLOCK_ADD_ID_ID, 1, &mut more).unwrap();
+        encode_bytes(BLOCK_ADDITIONAL_ID, b"alpha", &mut more).unwrap();
+        let mut additions = Vec::new();
+        encode_bytes(BLOCK_MORE_ID, &more, &mut additions).unwrap();
+        let mut payload = Vec::new();
+        encode_bytes(BLOCK_ID, &[0x81, 0x00, 0x21, 0x00, 0xAA, 0xBB], &mut payload).unwrap();
+        encode_integer(BLOCK_DURATION_ID, 33, &mut payload).unwrap();
+        encode_bytes(BLOCK_ADDITIONS_ID, &additions, &mut payload).unwrap();
+        let mut input = Vec::new();
+        encode_bytes(BLOCK_GROUP_ID, &payload, &mut input).unwrap();
+
+        let mut group = match parse_webm(&input).next() {
+            Some(WebmElement::BlockGroup(group)) => group,
+            other => panic!("not a BlockGroup: {:?}", other)
+        };
+        assert_eq!(group.block, SimpleBlock { track: 1, timecode: 33, flags: 0, data: &[0xAA, 0xBB] });
+        assert!(group.keyframe);
+        assert_eq!(group.duration, Some(33));
+        assert_eq!(group.additions(), vec![BlockAddition { id: 1, data: b"alpha" }]);
+
+        // written back as it was read, apart from the timecode
+        group.block.timecode = -5;
+        let mut output = Cursor::new(Vec::new());
+        encode_webm_element(WebmElement::BlockGroup(group), &mut output).unwrap();
+        let output = output.into_inner();
+        assert_eq!(output.len(), input.len());
+        match parse_webm(&output).next() {
+            Some(WebmElement::BlockGroup(rewritten)) => {
+                assert_eq!(rewritten.block.timecode, -5);
+                assert_eq!(rewritten.duration, Some(33));
+                assert_eq!(rewritten.additions(), group.additions());
+            },
+            other => panic!("not a BlockGroup: {:?}", other)
+        }
+    }
+
+    #[test]
+    fn rescale_block_groups() {
+        let mut payload = Vec::new();
+        encode_bytes(BLOCK_ID, &[0x81, 0x00, 0x21, 0x00, 0xAA], &mut payload).unwrap();
+        encode_integer(BLOCK_DURATION_ID, 33, &mut payload).unwrap();
+        encode_integer(REFERENCE_BLOCK_ID, -40i64 as u64, &mut payload).unwrap();
+        let mut input = Vec::new();
+        encode_bytes(BLOCK_GROUP_ID, &payload, &mut input).unwrap();
+
+        let mut group = match parse_webm(&input).next() {
+            Some(WebmElement::BlockGroup(group)) => group,
+            other => panic!("not a BlockGroup: {:?}", other)
+        };
+        assert!(!group.keyframe);
+        // from milliseconds to microseconds
+        group.rescale(DEFAULT_TIMESTAMP_SCALE, 1000);
+        assert_eq!(group.duration, Some(33_000));
+        let mut output = Vec::new();
+        encode_block(WebmElement::BlockGroup(group), 500, &mut output).unwrap();
+
+        let rewritten = match parse_webm(&output).next() {
+            Some(WebmElement::BlockGroup(rewritten)) => rewritten,
+            other => panic!("not a BlockGroup: {:?}", other)
+        };
+        assert_eq!(rewritten.block.timecode, 500);
+        assert_eq!(rewritten.duration, Some(33_000));
+        let mut reference = Vec::new();
+        encode_integer(REFERENCE_BLOCK_ID, -40_000i64 as u64, &mut reference).unwrap();
+        assert!(output.windows(reference.len()).any(|window| window == &reference[..]));
+        assert_eq!(cluster_blocks(&output), vec![(0, rewritten.block, false)]);
+
+        // rescaling again goes on from microseconds, to 100µs
+        group.rescale(1000, 100_000);
+        assert_eq!(group.duration, Some(330));
+        let mut output = Vec::new();
+        encode_block(WebmElement::BlockGroup(group), 500, &mut output).unwrap();
+        match parse_webm(&output).next() {
+            Some(WebmElement::BlockGroup(rewritten)) => assert_eq!(rewritten.duration, Some(330)),
+            other => panic!("not a BlockGroup: {:?}", other)
+        }
+        let mut reference = Vec::new();
+        encode_integer(REFERENCE_BLOCK_ID, -400i64 as u64, &mut reference).unwrap();
+        assert!(output.windows(reference.len()).any(|window| window == &reference[..]));
+    }
+
     #[test]
     fn encode_webm_test() {
         let mut cursor = Cursor::new(Vec::new());
@@ -220,4 +1188,61 @@ mod tests {
         assert_eq!(cursor.get_ref(), &ENCODE_WEBM_TEST_FILE);
     }
 
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize_elements() {
+        let mut iter = parse_webm(TEST_FILE);
+        let json: Vec<String> = (0..8).map(|_| serde_json::to_string(&iter.next().unwrap()).unwrap()).collect();
+        assert_eq!(json[0], r#"{"element":"EbmlHead"}"#);
+        assert_eq!(json[5], r#"{"element":"Tracks","size":63}"#);
+        assert_eq!(json[7], r#"{"element":"Timecode","timecode":0}"#);
+
+        let block = serde_json::to_string(&iter.next().unwrap()).unwrap();
+        assert_eq!(block, r#"{"element":"SimpleBlock","track":1,"timecode":0,"flags":128,"keyframe":true,"size":3240}"#);
+    }
+
+    /// encode, parse, & encode the result again, checking each step agrees
+    fn round_trip(element: WebmElement) {
+        let mut encoded = Cursor::new(Vec::new());
+        encode_webm_element(element, &mut encoded).unwrap();
+        let encoded = encoded.into_inner();
+
+        let parsed: Vec<WebmElement> = parse_webm(&encoded).collect();
+        assert_eq!(parsed, vec![element]);
+
+        let mut reencoded = Cursor::new(Vec::new());
+        encode_webm_element(parsed[0], &mut reencoded).unwrap();
+        assert_eq!(reencoded.into_inner(), encoded);
+    }
+
+    #[test]
+    fn round_trip_markers() {
+        round_trip(WebmElement::EbmlHead);
+        round_trip(WebmElement::Segment);
+        round_trip(WebmElement::Cluster);
+    }
+
+    proptest! {
+        #[test]
+        fn round_trip_timecodes(timecode in any::<u64>()) {
+            round_trip(WebmElement::Timecode(timecode));
+        }
+
+        #[test]
+        fn round_trip_tracks(data in vec(any::<u8>(), 0..300)) {
+            round_trip(WebmElement::Tracks(&data));
+        }
+
+        #[test]
+        fn round_trip_blocks(
+            // any track number a varint can hold
+            track in 0..(1u64 << 56) - 1,
+            timecode in any::<i16>(),
+            flags in any::<u8>(),
+            data in vec(any::<u8>(), 0..300),
+        ) {
+            round_trip(WebmElement::SimpleBlock(SimpleBlock { track, timecode, flags, data: &data }));
+        }
+    }
 }